@@ -0,0 +1,78 @@
+//! Decrypts a synthetic 256 MiB image to a file without an output digest and with each
+//! HashAlgo, see DecryptOptions::output_digest, to compare what hashing the output costs:
+//!
+//! cargo bench --bench hash_algos --features test-fixtures,blake3
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use libcryptocam::{fixtures::*, prelude::*};
+use std::{
+    env,
+    error::Error,
+    fs,
+    io::Cursor,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+const IMAGE_LEN: usize = 256 << 20;
+
+struct NoProgress;
+
+impl ProgressCallback for NoProgress {
+    fn set_total_file_size(&mut self, _n: u64) {}
+    fn on_progress(&mut self, _processed_bytes: u64) {}
+    fn on_complete(&mut self) {}
+    fn on_error(&mut self, error: Box<dyn Error>) {
+        panic!("Decryption failed: {}", error);
+    }
+}
+
+fn hash_algos(c: &mut Criterion) {
+    // a PNG signature, so the extension is kept without sniffing warnings
+    let mut image = b"\x89PNG\r\n\x1a\n".to_vec();
+    image.resize(IMAGE_LEN, 0x5a);
+    let file = FixtureFile::image(
+        r#"{"timestamp":"2021-06-01T12:00:00","format":"png"}"#,
+        image,
+    )
+    .build();
+    let mut keyring = test_keyring();
+    let out_dir = env::temp_dir().join("cryptocam-bench-hash");
+
+    let algos = [
+        ("none", None),
+        ("sha256", Some(HashAlgo::Sha256)),
+        ("sha512", Some(HashAlgo::Sha512)),
+        #[cfg(feature = "blake3")]
+        ("blake3", Some(HashAlgo::Blake3)),
+    ];
+
+    let mut group = c.benchmark_group("decrypt_image_digest");
+    group
+        .sample_size(10)
+        .throughput(Throughput::Bytes(file.len() as u64));
+    for &(name, algo) in &algos {
+        group.bench_function(name, |b| {
+            // copying the encrypted file isn't measured
+            b.iter_batched(
+                || Cursor::new(file.clone()),
+                |input| {
+                    fs::create_dir_all(&out_dir).unwrap();
+                    let mut options = DecryptOptions::new();
+                    if let Some(algo) = algo {
+                        options = options.output_digest(algo);
+                    }
+                    let mut job =
+                        decrypt_from_reader(input, None, &mut keyring, out_dir.clone(), options)
+                            .unwrap();
+                    job.run(Box::new(&mut NoProgress), Arc::new(AtomicBool::new(false)));
+                    fs::remove_dir_all(&out_dir).unwrap();
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, hash_algos);
+criterion_main!(benches);
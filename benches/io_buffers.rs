@@ -0,0 +1,71 @@
+//! Decrypts a synthetic 256 MiB image to a file with 8 KiB and with 1 MiB buffers, see
+//! DecryptOptions::input_buffer_size, read_buffer_size and write_buffer_size. The output goes
+//! to the temporary directory, point TMPDIR at the disk to measure:
+//!
+//! TMPDIR=/mnt/nas cargo bench --bench io_buffers --features test-fixtures
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use libcryptocam::{fixtures::*, prelude::*};
+use std::{
+    env,
+    error::Error,
+    fs,
+    io::Cursor,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+const IMAGE_LEN: usize = 256 << 20;
+
+struct NoProgress;
+
+impl ProgressCallback for NoProgress {
+    fn set_total_file_size(&mut self, _n: u64) {}
+    fn on_progress(&mut self, _processed_bytes: u64) {}
+    fn on_complete(&mut self) {}
+    fn on_error(&mut self, error: Box<dyn Error>) {
+        panic!("Decryption failed: {}", error);
+    }
+}
+
+fn io_buffers(c: &mut Criterion) {
+    // a PNG signature, so the extension is kept without sniffing warnings
+    let mut image = b"\x89PNG\r\n\x1a\n".to_vec();
+    image.resize(IMAGE_LEN, 0x5a);
+    let file = FixtureFile::image(
+        r#"{"timestamp":"2021-06-01T12:00:00","format":"png"}"#,
+        image,
+    )
+    .build();
+    let mut keyring = test_keyring();
+    let out_dir = env::temp_dir().join("cryptocam-bench-io");
+
+    let mut group = c.benchmark_group("decrypt_image_io");
+    group
+        .sample_size(10)
+        .throughput(Throughput::Bytes(file.len() as u64));
+    for &(name, buffer_size) in &[("8_KiB", 8 << 10), ("1_MiB", 1 << 20)] {
+        group.bench_function(name, |b| {
+            // copying the encrypted file isn't measured
+            b.iter_batched(
+                || Cursor::new(file.clone()),
+                |input| {
+                    fs::create_dir_all(&out_dir).unwrap();
+                    let options = DecryptOptions::new()
+                        .input_buffer_size(buffer_size)
+                        .read_buffer_size(buffer_size)
+                        .write_buffer_size(buffer_size);
+                    let mut job =
+                        decrypt_from_reader(input, None, &mut keyring, out_dir.clone(), options)
+                            .unwrap();
+                    job.run(Box::new(&mut NoProgress), Arc::new(AtomicBool::new(false)));
+                    fs::remove_dir_all(&out_dir).unwrap();
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, io_buffers);
+criterion_main!(benches);
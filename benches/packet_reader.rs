@@ -0,0 +1,101 @@
+//! Reads a synthetic 1 GiB packet stream, once allocating a Vec per packet through the Iterator
+//! and once reading every packet into the same buffer through next_header() and read_data(),
+//! which is how the muxer reads straight into ffmpeg's packet buffers.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use libcryptocam::packet::PacketReader;
+use std::io::{self, Read};
+
+const STREAM_LEN: u64 = 1 << 30;
+
+/// Alternating video and audio packets of typical sizes, generated on the fly.
+struct SyntheticStream {
+    remaining: u64,
+    packet: Vec<u8>,
+    offset: usize,
+    count: u64,
+}
+
+impl SyntheticStream {
+    fn new(len: u64) -> Self {
+        SyntheticStream {
+            remaining: len,
+            packet: Vec::new(),
+            offset: 0,
+            count: 0,
+        }
+    }
+
+    fn next_packet(&mut self) {
+        let (kind, length) = if self.count & 1 == 0 {
+            (1u8, 60_000 + (self.count % 7) as u32 * 10_000)
+        } else {
+            (2u8, 400)
+        };
+        self.packet.clear();
+        self.packet.push(kind);
+        self.packet
+            .extend_from_slice(&(self.count * 16_666).to_le_bytes());
+        self.packet.extend_from_slice(&length.to_le_bytes());
+        self.packet.resize(13 + length as usize, 0xa5);
+        self.offset = 0;
+        self.count += 1;
+    }
+}
+
+impl Read for SyntheticStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        if self.offset == self.packet.len() {
+            self.next_packet();
+        }
+        let n = buf
+            .len()
+            .min(self.packet.len() - self.offset)
+            .min(self.remaining as usize);
+        buf[..n].copy_from_slice(&self.packet[self.offset..self.offset + n]);
+        self.offset += n;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+fn packet_reader(c: &mut Criterion) {
+    let mut group = c.benchmark_group("packet_reader");
+    group
+        .sample_size(10)
+        .throughput(Throughput::Bytes(STREAM_LEN));
+    group.bench_function("vec_per_packet", |b| {
+        b.iter(|| {
+            let mut total = 0;
+            for packet in PacketReader::new(SyntheticStream::new(STREAM_LEN)) {
+                match packet {
+                    Ok(packet) => total += packet.data.len(),
+                    Err(_) => break,
+                }
+            }
+            total
+        })
+    });
+    group.bench_function("reused_buffer", |b| {
+        let mut buf = Vec::new();
+        b.iter(|| {
+            let mut total = 0;
+            let mut packets = PacketReader::new(SyntheticStream::new(STREAM_LEN));
+            while let Some(Ok(header)) = packets.next_header() {
+                buf.resize(header.length, 0);
+                if packets.read_data(&mut buf).is_err() {
+                    break;
+                }
+                total += buf.len();
+            }
+            total
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, packet_reader);
+criterion_main!(benches);
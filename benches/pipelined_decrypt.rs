@@ -0,0 +1,70 @@
+//! Decrypts a real recording single-threaded and pipelined. There is no synthetic fixture since
+//! the muxer needs valid H.264 and AAC, so point the benchmark at a large video and the keyring
+//! holding its key:
+//!
+//! CRYPTOCAM_BENCH_KEYRING=<keyring dir> CRYPTOCAM_BENCH_FILE=<video> cargo bench --bench pipelined_decrypt
+//!
+//! The key must not be passphrase protected.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use libcryptocam::prelude::*;
+use std::{
+    env,
+    error::Error,
+    fs::{self, File},
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+struct NoProgress;
+
+impl ProgressCallback for NoProgress {
+    fn set_total_file_size(&mut self, _n: u64) {}
+    fn on_progress(&mut self, _processed_bytes: u64) {}
+    fn on_complete(&mut self) {}
+    fn on_error(&mut self, error: Box<dyn Error>) {
+        panic!("Decryption failed: {}", error);
+    }
+}
+
+fn pipelined_decrypt(c: &mut Criterion) {
+    let (keyring_dir, file) = match (
+        env::var_os("CRYPTOCAM_BENCH_KEYRING"),
+        env::var_os("CRYPTOCAM_BENCH_FILE"),
+    ) {
+        (Some(keyring_dir), Some(file)) => (PathBuf::from(keyring_dir), PathBuf::from(file)),
+        _ => {
+            eprintln!("Set CRYPTOCAM_BENCH_KEYRING and CRYPTOCAM_BENCH_FILE to run this benchmark");
+            return;
+        }
+    };
+    let mut keyring = Keyring::load_from_directory(keyring_dir).unwrap();
+    let out_dir = env::temp_dir().join("cryptocam-bench");
+    let file_size = fs::metadata(&file).unwrap().len();
+
+    let mut group = c.benchmark_group("decrypt_video");
+    group
+        .sample_size(10)
+        .throughput(Throughput::Bytes(file_size));
+    for &(name, pipelined) in &[("single_threaded", false), ("pipelined", true)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                fs::create_dir_all(&out_dir).unwrap();
+                let options = DecryptOptions::new().pipelined(pipelined);
+                let mut job = decrypt_with_options(
+                    File::open(&file).unwrap(),
+                    &mut keyring,
+                    out_dir.clone(),
+                    options,
+                )
+                .unwrap();
+                job.run(Box::new(&mut NoProgress), Arc::new(AtomicBool::new(false)));
+                fs::remove_dir_all(&out_dir).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pipelined_decrypt);
+criterion_main!(benches);
@@ -0,0 +1,371 @@
+//! A small command line tool built only on the public API of libcryptocam.
+//!
+//! cargo run --example cryptocam-cli -- <keyring dir> keys
+//! cargo run --example cryptocam-cli -- <keyring dir> create-key <name> [--passphrase]
+//! cargo run --example cryptocam-cli -- <keyring dir> import <age identity file>
+//! cargo run --example cryptocam-cli -- <keyring dir> qr <key name>
+//! cargo run --example cryptocam-cli -- <keyring dir> qr-import <scanned texts...>
+//! cargo run --example cryptocam-cli -- <keyring dir> decrypt <out dir> [--faststart] <files...>
+//! cargo run --example cryptocam-cli -- <keyring dir> batch <out dir> [--dry-run]
+//!     [--manifest <file>] <files...>
+//! cargo run --example cryptocam-cli -- <keyring dir> identify <files...>
+//! cargo run --example cryptocam-cli -- <keyring dir> lint <dir>
+//! cargo run --example cryptocam-cli -- <keyring dir> report <files...>
+//!
+//! Exits with 1 if the command failed for any of the files.
+
+use anyhow::{anyhow, bail, Result};
+use dialoguer::Password;
+use indicatif::{ProgressBar, ProgressStyle};
+use libcryptocam::{prelude::*, qrcode::render::unicode};
+use secrecy::ExposeSecret;
+use std::{
+    env,
+    error::Error,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    process,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let (keyring_dir, command, rest) = match args {
+        [keyring_dir, command, rest @ ..] => (PathBuf::from(keyring_dir), command.as_str(), rest),
+        _ => bail!(
+            "Usage: cryptocam-cli <keyring dir> <keys|create-key|import|qr|qr-import|decrypt|batch|identify|lint|report> ..."
+        ),
+    };
+    fs::create_dir_all(&keyring_dir)?;
+    let mut keyring = Keyring::load_from_directory(keyring_dir)?;
+    match command {
+        "keys" => list_keys(&keyring),
+        "create-key" => create_key(&mut keyring, rest),
+        "import" => import_identities(&mut keyring, rest),
+        "qr" => show_qr_code(&keyring, rest),
+        "qr-import" => import_qr_code(&mut keyring, rest),
+        "decrypt" => decrypt_files(&mut keyring, rest),
+        "batch" => decrypt_batch(&mut keyring, rest),
+        "identify" => identify_files(&keyring, rest),
+        "lint" => lint_dir(rest),
+        "report" => report_files(&mut keyring, rest),
+        other => bail!("Unknown command {}", other),
+    }
+}
+
+fn list_keys(keyring: &Keyring) -> Result<()> {
+    for identity in keyring.display_identities() {
+        match keyring.unlock_estimate(&identity.public_key_digest) {
+            None => println!("{}\t{}", identity.name, identity.public_key),
+            Some(estimate) => println!(
+                "{}\t{}\t(passphrase protected, unlocking takes ~{} ms)",
+                identity.name,
+                identity.public_key,
+                estimate.estimated.as_millis()
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn create_key(keyring: &mut Keyring, args: &[String]) -> Result<()> {
+    let name = args.first().ok_or_else(|| anyhow!("Missing key name"))?;
+    let passphrase = if args.iter().any(|a| a == "--passphrase") {
+        Some(
+            Password::new()
+                .with_prompt("Passphrase")
+                .with_confirmation("Repeat passphrase", "Passphrases don't match")
+                .interact()?,
+        )
+    } else {
+        None
+    };
+    let identity = keyring
+        .create_key(name, passphrase.as_deref())
+        .map_err(|e| anyhow!("{}", e))?;
+    println!("Created {} in {}", identity.name, identity.path.display());
+    Ok(())
+}
+
+fn import_identities(keyring: &mut Keyring, args: &[String]) -> Result<()> {
+    let path = args
+        .first()
+        .ok_or_else(|| anyhow!("Missing identity file"))?;
+    keyring.set_passphrase_provider(Some(Box::new(TerminalPassphrase)));
+    for digest in keyring.import_age_identities(File::open(path)?)? {
+        println!("Imported {}", digest);
+    }
+    Ok(())
+}
+
+fn show_qr_code(keyring: &Keyring, args: &[String]) -> Result<()> {
+    let name = args.first().ok_or_else(|| anyhow!("Missing key name"))?;
+    let identity = keyring
+        .display_identities()
+        .into_iter()
+        .find(|i| &i.name == name)
+        .ok_or_else(|| anyhow!("No key named {}", name))?;
+    let qr_code = make_qr_code(&identity)?;
+    println!("{}", qr_code.render::<unicode::Dense1x2>().build());
+    Ok(())
+}
+
+/// Takes the texts of scanned QR codes: a key, the parts of a split key or the parts of a
+/// keyring backup.
+fn import_qr_code(keyring: &mut Keyring, args: &[String]) -> Result<()> {
+    let texts: Vec<&str> = args.iter().map(String::as_str).collect();
+    match texts.first() {
+        None => bail!("Missing QR code text"),
+        Some(text) if text.starts_with("cryptocam-keyring:") => {
+            for (label, result) in import_keyring_parts(&texts, keyring)? {
+                let label = label.unwrap_or_default();
+                match result {
+                    KeyringImportResult::Imported(digest) => {
+                        println!("Imported {}\t{}", label, digest)
+                    }
+                    KeyringImportResult::AlreadyPresent(digest) => {
+                        println!("Already present {}\t{}", label, digest)
+                    }
+                    KeyringImportResult::Failed(e) => bail!("Error importing {}: {}", label, e),
+                }
+            }
+            return Ok(());
+        }
+        Some(_) => {}
+    }
+    let payload = match texts.as_slice() {
+        [text] => parse_payload(text)?,
+        parts => assemble_parts(parts)?,
+    };
+    if !payload.is_secret_key() {
+        bail!(
+            "The QR code holds the public key {}, not a secret key",
+            payload.key.expose_secret()
+        );
+    }
+    let digest = keyring.import_key(payload.key.expose_secret(), payload.label.clone())?;
+    println!("Imported {}", digest);
+    Ok(())
+}
+
+fn decrypt_files(keyring: &mut Keyring, args: &[String]) -> Result<()> {
+    let (out_dir, rest) = args
+        .split_first()
+        .ok_or_else(|| anyhow!("Missing output directory"))?;
+    let options = DecryptOptions::new().faststart(rest.iter().any(|a| a == "--faststart"));
+    let files: Vec<&String> = rest.iter().filter(|a| !a.starts_with("--")).collect();
+    if files.is_empty() {
+        bail!("No files to decrypt");
+    }
+    keyring.set_passphrase_provider(Some(Box::new(TerminalPassphrase)));
+    for path in files {
+        let file = File::open(path)?;
+        let mut job = decrypt_with_options(file, keyring, PathBuf::from(out_dir), options.clone())?;
+        let mut progress = BarProgress::new(path);
+        job.run(Box::new(&mut progress), Arc::new(AtomicBool::new(false)));
+        if let Some(e) = progress.error {
+            bail!("Error decrypting {}: {}", path, e);
+        }
+    }
+    Ok(())
+}
+
+fn decrypt_batch(keyring: &mut Keyring, args: &[String]) -> Result<()> {
+    let (out_dir, rest) = args
+        .split_first()
+        .ok_or_else(|| anyhow!("Missing output directory"))?;
+    let (mut dry_run, mut manifest, mut files) = (false, None, vec![]);
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--manifest" => {
+                manifest = Some(
+                    rest.next()
+                        .ok_or_else(|| anyhow!("Missing manifest path"))?,
+                )
+            }
+            _ => files.push(PathBuf::from(arg)),
+        }
+    }
+    if files.is_empty() {
+        bail!("No files to decrypt");
+    }
+    keyring.set_passphrase_provider(Some(Box::new(TerminalPassphrase)));
+    let options = BatchOptions::new();
+    let planned = plan(&files, keyring, Path::new(out_dir), &options)?;
+    if dry_run {
+        println!("{}", serde_json::to_string_pretty(&planned)?);
+        return Ok(());
+    }
+    let mut failed = 0;
+    for file in &planned.files {
+        match &file.action {
+            PlannedAction::MissingKey { .. } => {
+                eprintln!("{}: no matching key", file.input.display());
+                failed += 1;
+            }
+            PlannedAction::Skip { reason } => {
+                eprintln!("{}: {}", file.input.display(), reason);
+                failed += 1;
+            }
+            _ => {}
+        }
+    }
+    let execution = execute(&planned, keyring, &options)?;
+    for drift in &execution.drift {
+        eprintln!("Warning: {}", drift);
+    }
+    let mut report = BatchReport::new();
+    for entry in execution.skipped {
+        println!("{}: already decrypted", entry.input.display());
+        report.push(entry);
+    }
+    for planned_job in execution.jobs {
+        let (header, _) = parse_header(&mut File::open(&planned_job.input)?)?;
+        let mut entry = BatchEntry::new(&planned_job.input, &header);
+        match planned_job.job {
+            Ok(mut job) => {
+                job.run_with_token(Box::new(&mut entry), &CancellationToken::new());
+            }
+            Err(e) => entry.error = Some(e.to_string()),
+        }
+        match (&entry.output, &entry.error) {
+            (_, Some(e)) => {
+                eprintln!("{}: {}", entry.input.display(), e);
+                failed += 1;
+            }
+            (Some(output), None) => {
+                println!("{} -> {}", entry.input.display(), output.display())
+            }
+            (None, None) => println!("{}", entry.input.display()),
+        }
+        report.push(entry);
+    }
+    if let Some(path) = manifest {
+        fs::write(path, report.to_json())?;
+    }
+    if failed > 0 {
+        bail!("{} of {} files failed", failed, planned.files.len());
+    }
+    Ok(())
+}
+
+fn identify_files(keyring: &Keyring, args: &[String]) -> Result<()> {
+    if args.is_empty() {
+        bail!("No files to identify");
+    }
+    for path in args {
+        let (header, _) =
+            parse_header(&mut File::open(path)?).map_err(|e| anyhow!("{}: {}", path, e))?;
+        let recipients: Vec<String> = header
+            .recipient_digests
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        let key = match keyring.can_decrypt(&header.recipient_digests) {
+            MatchResult::Matched(identity) => identity.label.unwrap_or_default(),
+            MatchResult::NoMatch(_) => "no matching key".to_owned(),
+        };
+        println!(
+            "{}\tversion {}\t{}\t{}\t{}",
+            path,
+            header.version,
+            header.device_label().unwrap_or_default(),
+            recipients.join(", "),
+            key
+        );
+    }
+    Ok(())
+}
+
+/// Lists the files under `dir` that aren't valid Cryptocam files.
+fn lint_dir(args: &[String]) -> Result<()> {
+    let dir = args.first().ok_or_else(|| anyhow!("Missing directory"))?;
+    let entries = scan_dir_all(Path::new(dir), true)?;
+    let mut problems = 0;
+    for entry in &entries {
+        if let Err(e) = &entry.header {
+            let kind = serde_json::to_value(entry.error_kind)?;
+            println!(
+                "{}\t{}\t{}",
+                entry.path.display(),
+                kind.as_str().unwrap_or(""),
+                e
+            );
+            problems += 1;
+        }
+    }
+    if problems > 0 {
+        bail!("{} of {} files have problems", problems, entries.len());
+    }
+    Ok(())
+}
+
+/// Verifies `args` without writing outputs, printing the progress as JSON lines.
+fn report_files(keyring: &mut Keyring, args: &[String]) -> Result<()> {
+    if args.is_empty() {
+        bail!("No files to verify");
+    }
+    keyring.set_passphrase_provider(Some(Box::new(TerminalPassphrase)));
+    let options = DecryptOptions::new().verify_only(true);
+    let mut failed = 0;
+    for path in args {
+        let file = File::open(path)?;
+        let mut job = decrypt_with_options(file, keyring, env::temp_dir(), options.clone())?;
+        let mut progress = JsonlProgress::new(io::stdout());
+        let result = job.run_with_token(Box::new(&mut progress), &CancellationToken::new());
+        progress.finish()?;
+        if !matches!(result, JobResult::Complete { .. }) {
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} files failed verification", failed, args.len());
+    }
+    Ok(())
+}
+
+struct BarProgress {
+    bar: ProgressBar,
+    error: Option<String>,
+}
+
+impl BarProgress {
+    fn new(name: &str) -> Self {
+        let bar = ProgressBar::new(0).with_message(name.to_owned());
+        bar.set_style(
+            ProgressStyle::with_template("{msg} [{bar:40}] {bytes}/{total_bytes}")
+                .unwrap()
+                .progress_chars("=> "),
+        );
+        BarProgress { bar, error: None }
+    }
+}
+
+impl ProgressCallback for BarProgress {
+    fn set_total_file_size(&mut self, n: u64) {
+        self.bar.set_length(n);
+    }
+
+    fn on_progress(&mut self, processed_bytes: u64) {
+        self.bar.set_position(processed_bytes);
+    }
+
+    fn on_complete(&mut self) {
+        self.bar.finish();
+    }
+
+    fn on_error(&mut self, error: Box<dyn Error>) {
+        self.bar.abandon();
+        self.error = Some(error.to_string());
+    }
+}
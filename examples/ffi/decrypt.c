@@ -0,0 +1,81 @@
+/*
+Decrypts a file through the C API, as a test of the whole FFI layer, built and run by
+ffi/tests/c_example.rs:
+
+    cargo build --release -p cryptocam-ffi
+    cc -Iinclude examples/ffi/decrypt.c -Ltarget/release -lcryptocam -o decrypt
+    LD_LIBRARY_PATH=target/release ./decrypt <keyring dir> <secret key or -> <file> <output dir>
+
+Pass - instead of a secret key to use the keys already in the keyring.
+*/
+
+#include <inttypes.h>
+#include <stdio.h>
+#include <string.h>
+
+#include "cryptocam.h"
+
+static void on_progress(void *user_data, uint64_t processed, uint64_t total) {
+    (void)user_data;
+    fprintf(stderr, "\r%" PRIu64 " / %" PRIu64, processed, total);
+}
+
+static void on_warning(void *user_data, const char *message) {
+    (void)user_data;
+    fprintf(stderr, "\nwarning: %s\n", message);
+}
+
+static void on_output_created(void *user_data, const char *path) {
+    int *outputs = user_data;
+    *outputs += 1;
+    printf("writing %s\n", path);
+}
+
+static int fail(const char *what) {
+    char *error = cryptocam_last_error();
+    fprintf(stderr, "%s: %s\n", what, error ? error : "unknown error");
+    cryptocam_string_free(error);
+    return 1;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 5) {
+        fprintf(stderr, "usage: %s <keyring dir> <secret key or -> <file> <output dir>\n", argv[0]);
+        return 2;
+    }
+    CryptocamKeyring *keyring = cryptocam_keyring_new(argv[1]);
+    if (!keyring) {
+        return fail("loading keyring");
+    }
+    if (strcmp(argv[2], "-") != 0) {
+        char *digest = NULL;
+        if (cryptocam_keyring_import_key(keyring, argv[2], "ffi test", &digest) != CRYPTOCAM_STATUS_OK) {
+            cryptocam_keyring_free(keyring);
+            return fail("importing key");
+        }
+        printf("imported key %s\n", digest);
+        cryptocam_string_free(digest);
+    }
+
+    int outputs = 0;
+    CryptocamCallbacks callbacks = {
+        .user_data = &outputs,
+        .on_progress = on_progress,
+        .on_warning = on_warning,
+        .on_error = NULL,
+        .on_output_created = on_output_created,
+    };
+    CryptocamCancelToken *cancel = cryptocam_cancel_token_new();
+    CryptocamStatus status = cryptocam_decrypt_file(keyring, argv[3], argv[4], &callbacks, cancel);
+    fprintf(stderr, "\n");
+    cryptocam_cancel_token_free(cancel);
+    cryptocam_keyring_free(keyring);
+    if (status != CRYPTOCAM_STATUS_OK) {
+        return fail("decrypting");
+    }
+    if (outputs != 1) {
+        fprintf(stderr, "expected one output file, got %d\n", outputs);
+        return 1;
+    }
+    return 0;
+}
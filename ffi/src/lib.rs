@@ -0,0 +1,9 @@
+//! The C API of libcryptocam, see src/ffi.rs there. The functions are only re-exported, this
+//! crate exists to build them into a C library:
+//!
+//! ```text
+//! cargo build --release -p cryptocam-ffi
+//! cc -Iinclude examples/ffi/decrypt.c -Ltarget/release -lcryptocam -o decrypt
+//! ```
+
+pub use libcryptocam::ffi::*;
@@ -0,0 +1,76 @@
+//! Builds examples/ffi/decrypt.c against the library and decrypts a fixture file with it. Needs
+//! a C compiler, `cc` or the one in $CC.
+#![cfg(unix)]
+
+use libcryptocam::fixtures::{FixtureFile, TEST_SECRET_KEY};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Where cargo put libcryptocam.so, next to the deps directory the test runs from.
+fn library_dir() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    let dir = exe.parent().and_then(Path::parent).unwrap().to_path_buf();
+    let library = format!(
+        "{}cryptocam{}",
+        env::consts::DLL_PREFIX,
+        env::consts::DLL_SUFFIX
+    );
+    assert!(
+        dir.join(&library).is_file(),
+        "{} isn't in {}",
+        library,
+        dir.display()
+    );
+    dir
+}
+
+#[test]
+fn c_example_decrypts_a_file() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
+    let library_dir = library_dir();
+    let dir = tempfile::tempdir().unwrap();
+    let example = dir.path().join("decrypt");
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_owned());
+    let status = Command::new(&compiler)
+        .arg("-I")
+        .arg(root.join("include"))
+        .arg(root.join("examples/ffi/decrypt.c"))
+        .arg("-L")
+        .arg(&library_dir)
+        .arg("-lcryptocam")
+        .arg("-o")
+        .arg(&example)
+        .status()
+        .unwrap_or_else(|e| panic!("Error running {}: {}", compiler, e));
+    assert!(status.success(), "Building the C example failed");
+
+    let input = dir.path().join("image.cryptocam");
+    let metadata = r#"{"timestamp":"2021-06-01T12:00:00Z","format":"png"}"#;
+    fs::write(
+        &input,
+        FixtureFile::image(metadata, &b"\x89PNG\r\n\x1a\nimage data"[..]).build(),
+    )
+    .unwrap();
+    let (keyring_dir, out_dir) = (dir.path().join("keyring"), dir.path().join("out"));
+    fs::create_dir(&keyring_dir).unwrap();
+    fs::create_dir(&out_dir).unwrap();
+    let output = Command::new(&example)
+        .arg(&keyring_dir)
+        .arg(TEST_SECRET_KEY)
+        .arg(&input)
+        .arg(&out_dir)
+        .env("LD_LIBRARY_PATH", &library_dir)
+        .env("DYLD_LIBRARY_PATH", &library_dir)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "The C example failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let outputs = fs::read_dir(&out_dir).unwrap().count();
+    assert_eq!(outputs, 1);
+}
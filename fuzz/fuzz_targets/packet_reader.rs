@@ -0,0 +1,29 @@
+//! cargo +nightly fuzz run packet_reader ../tests/corpus/packets
+
+#![no_main]
+use libcryptocam::packet::PacketReader;
+use libfuzzer_sys::fuzz_target;
+
+// small enough that a corrupt length can't make the reader allocate much
+const MAX_PACKET_LEN: usize = 1 << 16;
+
+fuzz_target!(|data: &[u8]| {
+    // the first byte picks the options, the rest is the decrypted payload
+    let (resync, payload) = match data.split_first() {
+        Some((options, payload)) => (options & 1 == 1, payload),
+        None => return,
+    };
+    let mut packets = PacketReader::new(payload)
+        .resync_on_error(resync)
+        .max_packet_len(MAX_PACKET_LEN);
+    let mut last_position = 0;
+    while let Some(packet) = packets.next() {
+        assert!(packets.position() >= last_position);
+        assert!(packets.position() <= payload.len() as u64);
+        last_position = packets.position();
+        match packet {
+            Ok(packet) => assert!(packet.data.len() <= MAX_PACKET_LEN),
+            Err(_) => assert!(packets.next().is_none()),
+        }
+    }
+});
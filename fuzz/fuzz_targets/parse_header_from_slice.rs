@@ -0,0 +1,12 @@
+//! cargo +nightly fuzz run parse_header_from_slice ../tests/corpus/header
+
+#![no_main]
+use libcryptocam::parser::parse_header_from_slice;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((header, len)) = parse_header_from_slice(data) {
+        assert!(len <= data.len());
+        assert_eq!(header.header_len, len as u64);
+    }
+});
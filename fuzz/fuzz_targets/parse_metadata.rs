@@ -0,0 +1,11 @@
+//! cargo +nightly fuzz run parse_metadata ../tests/corpus/metadata
+
+#![no_main]
+use libcryptocam::fuzzing::parse_any_metadata;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(json) = std::str::from_utf8(data) {
+        parse_any_metadata(json);
+    }
+});
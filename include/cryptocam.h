@@ -0,0 +1,106 @@
+#ifndef CRYPTOCAM_H
+#define CRYPTOCAM_H
+
+/* Generated by cbindgen from src/ffi.rs, don't edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum CryptocamStatus {
+  CRYPTOCAM_STATUS_OK = 0,
+  CRYPTOCAM_STATUS_ERROR = 1,
+  CRYPTOCAM_STATUS_INVALID_ARGUMENT = 2,
+  CRYPTOCAM_STATUS_CANCELLED = 3,
+  CRYPTOCAM_STATUS_PANIC = 4,
+} CryptocamStatus;
+
+// Cancels a running cryptocam_decrypt_file() from another thread.
+typedef struct CryptocamCancelToken CryptocamCancelToken;
+
+// A keyring, see Keyring.
+typedef struct CryptocamKeyring CryptocamKeyring;
+
+// Receives the events of cryptocam_decrypt_file(). Every function pointer may be NULL.
+// They are called on the thread running the decryption, with `user_data` as it is set here.
+typedef struct CryptocamCallbacks {
+  void *user_data;
+  // Bytes of the input processed so far and its total size, 0 if unknown.
+  void (*on_progress)(void *user_data, uint64_t processed, uint64_t total);
+  void (*on_warning)(void *user_data, const char *message);
+  // Also reported through cryptocam_last_error() after cryptocam_decrypt_file() returns.
+  void (*on_error)(void *user_data, const char *message);
+  // Path of the output file, before anything is written to it.
+  void (*on_output_created)(void *user_data, const char *path);
+} CryptocamCallbacks;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// The message of the last error on this thread, or NULL if there was none. Free it with
+// cryptocam_string_free().
+char *cryptocam_last_error(void);
+
+// Frees a string returned by the library. NULL is ignored.
+//
+// # Safety
+// `s` must be NULL or a string returned by the library that wasn't freed yet.
+void cryptocam_string_free(char *s);
+
+// Loads the keyring stored in the directory `path`, or NULL on failure.
+// Free it with cryptocam_keyring_free().
+//
+// # Safety
+// `path` must be a NUL terminated string.
+CryptocamKeyring *cryptocam_keyring_new(const char *path);
+
+// # Safety
+// `keyring` must be NULL or returned by cryptocam_keyring_new() and not freed yet.
+void cryptocam_keyring_free(CryptocamKeyring *keyring);
+
+// Imports an age secret key (AGE-SECRET-KEY-1...) into the keyring. `label` may be NULL.
+// If `digest_out` isn't NULL, it receives the hex encoded digest of the key, to be freed
+// with cryptocam_string_free().
+//
+// # Safety
+// `keyring` must be returned by cryptocam_keyring_new(), `key` and `label` NUL terminated
+// strings, and `digest_out` NULL or valid for writing a pointer.
+CryptocamStatus cryptocam_keyring_import_key(CryptocamKeyring *keyring,
+                                             const char *key,
+                                             const char *label,
+                                             char **digest_out);
+
+// Free it with cryptocam_cancel_token_free().
+CryptocamCancelToken *cryptocam_cancel_token_new(void);
+
+// Makes decryptions using the token stop as soon as possible. May be called from any thread.
+//
+// # Safety
+// `token` must be returned by cryptocam_cancel_token_new() and not freed yet.
+void cryptocam_cancel_token_cancel(const CryptocamCancelToken *token);
+
+// # Safety
+// `token` must be NULL or returned by cryptocam_cancel_token_new(), and not be in use by a
+// running decryption.
+void cryptocam_cancel_token_free(CryptocamCancelToken *token);
+
+// Decrypts the file at `path` into the directory `out_dir` with the default options, blocking
+// until done. `callbacks` and `cancel_token` may be NULL.
+//
+// # Safety
+// `keyring` must be returned by cryptocam_keyring_new(), `path` and `out_dir` NUL terminated
+// strings, `callbacks` NULL or valid for the whole call, and `cancel_token` NULL or returned by
+// cryptocam_cancel_token_new().
+CryptocamStatus cryptocam_decrypt_file(CryptocamKeyring *keyring,
+                                       const char *path,
+                                       const char *out_dir,
+                                       const CryptocamCallbacks *callbacks,
+                                       const CryptocamCancelToken *cancel_token);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* CRYPTOCAM_H */
@@ -0,0 +1,677 @@
+//! Batch decryption: a manifest of what was decrypted to what, see BatchReport, and a dry run
+//! showing what a batch would do before running it, see plan().
+
+use crate::{
+    budget::ResourceBudget,
+    decrypt::{
+        decrypt_with_options, probe, BatchNames, DecryptOptions, DecryptStats, DecryptingJob,
+        OutputNaming, Overwrite, Probe, ProgressCallback, SubdirectoryStrategy, FILE_TYPE_AUDIO,
+        FILE_TYPE_IMAGE, FILE_TYPE_VIDEO,
+    },
+    decrypt_image,
+    error::Error as CryptocamError,
+    estimate::estimate_data_len,
+    hash::HashDigest,
+    jsonl::stats_fields,
+    keyring::{sign_manifest, DecryptionError, KeyDigest, Keyring, Signature},
+    output_path::{
+        absolutize_output_dir, batch_name_warning, numbered_path, output_file_name, MediaInfo,
+    },
+    parser::Header,
+    registry::Registry,
+    sidecar::recorded_digest,
+};
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+use crate::{decrypt_audio, decrypt_video};
+use anyhow::{anyhow, Result};
+use chrono::{SecondsFormat, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::HashSet,
+    error::Error,
+    fmt,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// The `manifest_version` of BatchReport::to_json(). Only changes when fields are removed or
+/// change their meaning.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// The files of a batch decryption with their outputs, written out as JSON by to_json():
+///
+/// ```text
+/// {
+///   "manifest_version": 1,
+///   "libcryptocam_version": "0.1.3",
+///   "created": "2021-06-01T12:00:00Z",
+///   "files": [
+///     {
+///       "input": "/in/VID_1.mp4",
+///       "recipient_digests": ["3f2a9c10e4b70d5c81a26f33c09e7b14"],
+///       "output": "/out/2021-06-01T11:58:03.mp4",
+///       "output_digest": { "algo": "sha256", "hex": "..." },
+///       "stats": { "type": "video", "duration_us": 12000000, ... },
+///       "error": null,
+///       "skipped": null
+///     }
+///   ]
+/// }
+/// ```
+///
+/// `output_digest` is only there with DecryptOptions::output_digest, set it to
+/// HashAlgo::Sha256 for the SHA-256 of every output. `skipped` is a SkipReason, like
+/// "already_decrypted", for files that weren't decrypted.
+#[derive(Debug, Clone, Default)]
+pub struct BatchReport {
+    pub entries: Vec<BatchEntry>,
+}
+
+/// One file of a BatchReport. Pass it as the ProgressCallback of the file's job, or forward the
+/// events to it, to fill in the output, its digest, the stats and the error.
+#[derive(Debug, Clone)]
+pub struct BatchEntry {
+    pub input: PathBuf,
+    /// From the file's header.
+    pub recipient_digests: Vec<KeyDigest>,
+    pub output: Option<PathBuf>,
+    pub output_digest: Option<HashDigest>,
+    pub stats: Option<DecryptStats>,
+    /// The message of the error the job failed with.
+    pub error: Option<String>,
+    /// Set if the file wasn't decrypted, `output` is the existing output then.
+    pub skipped: Option<SkipReason>,
+}
+
+/// Why a file of a batch wasn't decrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// Its output exists already, see BatchOptions::skip_existing_by_hash.
+    AlreadyDecrypted,
+}
+
+impl BatchEntry {
+    pub fn new(input: impl Into<PathBuf>, header: &Header) -> Self {
+        BatchEntry {
+            input: input.into(),
+            recipient_digests: header.recipient_digests.clone(),
+            output: None,
+            output_digest: None,
+            stats: None,
+            error: None,
+            skipped: None,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "input": self.input.to_string_lossy(),
+            "recipient_digests": self
+                .recipient_digests
+                .iter()
+                .map(KeyDigest::to_hex)
+                .collect::<Vec<_>>(),
+            "output": self.output.as_deref().map(Path::to_string_lossy),
+            "output_digest": self
+                .output_digest
+                .as_ref()
+                .map(|digest| json!({ "algo": digest.algo().name(), "hex": digest.to_hex() })),
+            "stats": self.stats.as_ref().map(stats_fields),
+            "error": self.error,
+            "skipped": self.skipped,
+        })
+    }
+}
+
+impl BatchReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, entry: BatchEntry) {
+        self.entries.push(entry);
+    }
+
+    /// The report as pretty-printed JSON, see BatchReport. Paths that aren't valid UTF-8 have
+    /// the invalid parts replaced by U+FFFD.
+    pub fn to_json(&self) -> Vec<u8> {
+        let report = json!({
+            "manifest_version": MANIFEST_VERSION,
+            "libcryptocam_version": env!("CARGO_PKG_VERSION"),
+            "created": Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            "files": self.entries.iter().map(BatchEntry::to_json).collect::<Vec<_>>(),
+        });
+        let mut json = serde_json::to_vec_pretty(&report).unwrap();
+        json.push(b'\n');
+        json
+    }
+
+    /// to_json(), signed with the identity `digest` of `keyring`, see keyring::sign_manifest().
+    /// Keep the JSON exactly as returned, the signature covers its bytes, not its content.
+    pub fn to_signed_json(
+        &self,
+        keyring: &Keyring,
+        digest: &KeyDigest,
+    ) -> Result<(Vec<u8>, Signature)> {
+        let json = self.to_json();
+        let signature = sign_manifest(keyring, digest, &json)?;
+        Ok((json, signature))
+    }
+}
+
+impl ProgressCallback for BatchEntry {
+    fn set_total_file_size(&mut self, _n: u64) {}
+
+    fn on_progress(&mut self, _processed_bytes: u64) {}
+
+    fn on_complete(&mut self) {}
+
+    fn on_error(&mut self, error: Box<dyn Error>) {
+        self.error = Some(error.to_string());
+    }
+
+    fn on_output_created(&mut self, path: &Path) {
+        self.output = Some(path.to_path_buf());
+    }
+
+    fn on_output_digest(&mut self, digest: &HashDigest) {
+        self.output_digest = Some(digest.clone());
+    }
+
+    fn on_stats(&mut self, stats: &DecryptStats) {
+        self.stats = Some(stats.clone());
+    }
+}
+
+/// How plan() and execute() handle a batch. Built from BatchOptions::new() or default() with
+/// the setters below, new options may be added in any release.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct BatchOptions {
+    /// The options of the jobs. Naming, subdirectories and batch names only apply to plan(),
+    /// the jobs write to the planned outputs.
+    pub decrypt_options: DecryptOptions,
+    /// Files whose output exists already are planned as PlannedAction::AlreadyDecrypted
+    /// instead of being decrypted again under another name, if the output still has the
+    /// digest recorded in its sidecar by DecryptOptions::output_digest and
+    /// write_metadata_sidecar. Outputs without a recorded digest are decrypted again. Off by
+    /// default.
+    pub skip_existing_by_hash: bool,
+    /// The bytes of packets and frames all jobs of the batch may hold in memory together, see
+    /// ResourceBudget::max_buffer_bytes(). Applied to DecryptOptions::resource_budget, or to a
+    /// default budget if there is none. Unlimited by default.
+    pub memory_budget_bytes: Option<u64>,
+}
+
+// setters for the fields above, see there
+impl BatchOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn decrypt_options(mut self, decrypt_options: DecryptOptions) -> Self {
+        self.decrypt_options = decrypt_options;
+        self
+    }
+
+    pub fn skip_existing_by_hash(mut self, skip_existing_by_hash: bool) -> Self {
+        self.skip_existing_by_hash = skip_existing_by_hash;
+        self
+    }
+
+    pub fn memory_budget_bytes(mut self, memory_budget_bytes: u64) -> Self {
+        self.memory_budget_bytes = Some(memory_budget_bytes);
+        self
+    }
+}
+
+/// What plan() found out about the files of a batch. It can be serialized to show it, and
+/// deserialized again to hand it to execute().
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchPlan {
+    /// The output directory, made absolute.
+    pub out_dir: PathBuf,
+    pub files: Vec<PlannedFile>,
+    /// The estimated output size of all files that will be decrypted, like
+    /// DecryptingJob::estimated_output_size().
+    pub total_estimated_size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlannedFile {
+    pub input: PathBuf,
+    /// The size of the input when it was planned, execute() checks it is still the same.
+    pub size: u64,
+    #[serde(flatten)]
+    pub action: PlannedAction,
+}
+
+/// What happens to a file of a BatchPlan. Digests are written as RecipientDigest::to_hex().
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum PlannedAction {
+    Decrypt {
+        /// The identity that decrypts the file, None if it is encrypted with a passphrase.
+        identity: Option<String>,
+        /// "video", "image" or "audio"
+        media_type: String,
+        timestamp: String,
+        output: PathBuf,
+        /// Set if the output name is taken already.
+        collision: Option<Collision>,
+        /// Whether the output name has the milliseconds of the timestamp, because other files
+        /// of the batch were recorded in the same second, see BatchNames.
+        #[serde(default)]
+        milliseconds_appended: bool,
+        estimated_size: u64,
+    },
+    /// None of the file's recipients are in the keyring.
+    MissingKey { recipients: Vec<String> },
+    /// The file can't be decrypted, e.g. because it isn't a Cryptocam file or its metadata is
+    /// invalid, `reason` says why.
+    Skip { reason: String },
+    /// The output exists already, see BatchOptions::skip_existing_by_hash.
+    AlreadyDecrypted {
+        /// All recipients of the file.
+        recipients: Vec<String>,
+        output: PathBuf,
+    },
+}
+
+/// An output name that is taken, by an existing file or by the output of an earlier file of the
+/// batch. What happens depends on DecryptOptions::overwrite.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Collision {
+    /// Overwrite::Rename: the output goes to a name with a number instead of `wanted`.
+    Renamed { wanted: PathBuf },
+    /// Overwrite::Replace: the other file is replaced.
+    Replaces,
+    /// Overwrite::Fail: the file's job will fail.
+    Fails,
+}
+
+/// What changed between plan() and execute().
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PlanDrift {
+    /// The input is gone or its size is different.
+    InputChanged { input: PathBuf },
+    /// The identity the plan matched is no longer in the keyring.
+    KeyMissing { input: PathBuf },
+    /// The planned output exists now, DecryptOptions::overwrite decides what happens.
+    OutputExists { output: PathBuf },
+}
+
+impl fmt::Display for PlanDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanDrift::InputChanged { input } => {
+                write!(f, "{} changed since it was planned", input.display())
+            }
+            PlanDrift::KeyMissing { input } => write!(
+                f,
+                "The key planned for {} is no longer in the keyring",
+                input.display()
+            ),
+            PlanDrift::OutputExists { output } => {
+                write!(f, "{} exists now", output.display())
+            }
+        }
+    }
+}
+
+/// The jobs execute() built.
+pub struct BatchExecution {
+    pub jobs: Vec<PlannedJob>,
+    /// Also logged as warnings.
+    pub drift: Vec<PlanDrift>,
+    /// The report entries of the files planned as PlannedAction::AlreadyDecrypted, with
+    /// BatchEntry::skipped set. Push them to the BatchReport with the entries of the jobs.
+    pub skipped: Vec<BatchEntry>,
+    /// The budget all jobs share, e.g. to check its peak() once they ran. None if neither
+    /// BatchOptions::memory_budget_bytes nor DecryptOptions::resource_budget is set.
+    pub resource_budget: Option<ResourceBudget>,
+}
+
+pub struct PlannedJob {
+    pub input: PathBuf,
+    /// The output as planned. The job writes there unless the name was taken since and
+    /// DecryptOptions::overwrite is Overwrite::Rename.
+    pub output: PathBuf,
+    pub job: Result<Box<dyn DecryptingJob + Send>>,
+}
+
+/// A dry run of decrypting `files` into `out_dir` with `options`, nothing is written: the
+/// headers are read and matched against `keyring`, the metadata is decrypted to name the
+/// outputs the way the jobs would, including the names BatchNames and Overwrite::Rename give
+/// to colliding files, and the output sizes are estimated. Locked identities are unlocked
+/// through the keyring's PassphraseProvider, like for decrypt(). The stem of each input is
+/// the {source_stem} of its output name.
+///
+/// Image outputs are named after the format in their metadata, the contents aren't checked
+/// like DecryptOptions::image_format_mismatch does. Files that can't be read are planned as
+/// PlannedAction::Skip, only invalid options or an invalid output directory fail the plan.
+/// With BatchOptions::skip_existing_by_hash, existing outputs are read to compare their
+/// digest.
+pub fn plan(
+    files: &[PathBuf],
+    keyring: &mut Keyring,
+    out_dir: &Path,
+    options: &BatchOptions,
+) -> Result<BatchPlan> {
+    let skip_existing_by_hash = options.skip_existing_by_hash;
+    let options = &options.decrypt_options;
+    options.validate()?;
+    let out_dir = absolutize_output_dir(out_dir.to_path_buf())?;
+    // names in a batch depend on all of its timestamps, so every file is probed first
+    let batch_names = options.batch_names.as_ref().map(|_| BatchNames::new());
+    let mut probed = vec![];
+    for input in files {
+        let (size, result) = probe_file(input, keyring, options);
+        if let (Some(batch_names), Ok(file)) = (&batch_names, &result) {
+            batch_names.register(&file.info.timestamp);
+        }
+        probed.push((input.clone(), size, result));
+    }
+
+    let mut planned_outputs = HashSet::new();
+    let mut planned = BatchPlan {
+        out_dir,
+        files: vec![],
+        total_estimated_size: 0,
+    };
+    for (input, size, result) in probed {
+        let action = match result {
+            Err(action) => action,
+            Ok(file) => {
+                let file_name = output_file_name(&file.info, &options.naming, batch_names.as_ref());
+                let milliseconds_appended =
+                    batch_name_warning(&file.info, &options.naming, batch_names.as_ref()).is_some();
+                let subdirectory = options
+                    .subdirectory_strategy
+                    .subdirectory(&file.info.timestamp);
+                let wanted = planned.out_dir.join(subdirectory).join(file_name);
+                if skip_existing_by_hash
+                    && !planned_outputs.contains(&wanted)
+                    && existing_output(&wanted)
+                {
+                    planned_outputs.insert(wanted.clone());
+                    planned.files.push(PlannedFile {
+                        input,
+                        size,
+                        action: PlannedAction::AlreadyDecrypted {
+                            recipients: file.recipients.iter().map(KeyDigest::to_hex).collect(),
+                            output: wanted,
+                        },
+                    });
+                    continue;
+                }
+                let (output, collision) =
+                    resolve_collision(wanted, options.overwrite, &planned_outputs);
+                planned_outputs.insert(output.clone());
+                planned.total_estimated_size += file.estimated_size;
+                PlannedAction::Decrypt {
+                    identity: file.identity.map(|digest| digest.to_hex()),
+                    media_type: file.info.media_type.to_owned(),
+                    timestamp: file.info.timestamp,
+                    output,
+                    collision,
+                    milliseconds_appended,
+                    estimated_size: file.estimated_size,
+                }
+            }
+        };
+        planned.files.push(PlannedFile {
+            input,
+            size,
+            action,
+        });
+    }
+    Ok(planned)
+}
+
+/// Builds the jobs for the files `plan` decrypts, writing to the outputs it planned: naming,
+/// subdirectories and batch names are replaced by the planned paths, the other `options`
+/// apply as given and should be the ones the plan was made with. The jobs share one
+/// ResourceBudget, limited to BatchOptions::memory_budget_bytes, so they can run in parallel. What changed since the plan
+/// was made ends up in BatchExecution::drift, the jobs are built anyway. Jobs that can't be
+/// built, e.g. because their input is gone, get the error instead.
+pub fn execute(
+    plan: &BatchPlan,
+    keyring: &mut Keyring,
+    options: &BatchOptions,
+) -> Result<BatchExecution> {
+    let mut job_options = options.decrypt_options.clone();
+    job_options.validate()?;
+    if let Some(max_bytes) = options.memory_budget_bytes {
+        let budget = job_options.resource_budget.take().unwrap_or_default();
+        job_options.resource_budget = Some(budget.max_buffer_bytes(max_bytes));
+    }
+    let mut execution = BatchExecution {
+        jobs: vec![],
+        drift: vec![],
+        skipped: vec![],
+        resource_budget: job_options.resource_budget.clone(),
+    };
+    for file in &plan.files {
+        let (identity, output, collision) = match &file.action {
+            PlannedAction::Decrypt {
+                identity,
+                output,
+                collision,
+                ..
+            } => (identity, output, collision),
+            PlannedAction::AlreadyDecrypted {
+                recipients, output, ..
+            } => {
+                execution.skipped.push(BatchEntry {
+                    input: file.input.clone(),
+                    recipient_digests: recipients
+                        .iter()
+                        .map(|hex| hex.parse())
+                        .collect::<Result<_>>()?,
+                    output: Some(output.clone()),
+                    output_digest: None,
+                    stats: None,
+                    error: None,
+                    skipped: Some(SkipReason::AlreadyDecrypted),
+                });
+                continue;
+            }
+            _ => continue,
+        };
+        let mut drift = vec![];
+        match fs::metadata(&file.input) {
+            Ok(metadata) if metadata.len() == file.size => {}
+            _ => drift.push(PlanDrift::InputChanged {
+                input: file.input.clone(),
+            }),
+        }
+        let identity_missing = identity
+            .as_deref()
+            .map(|hex| hex.parse::<KeyDigest>())
+            .transpose()?
+            .map_or(false, |digest| !keyring.contains(&digest));
+        if identity_missing {
+            drift.push(PlanDrift::KeyMissing {
+                input: file.input.clone(),
+            });
+        }
+        if collision.is_none() && output.exists() {
+            drift.push(PlanDrift::OutputExists {
+                output: output.clone(),
+            });
+        }
+        for drift in &drift {
+            warn!("{}", drift);
+        }
+        execution.drift.extend(drift);
+        execution.jobs.push(PlannedJob {
+            input: file.input.clone(),
+            output: output.clone(),
+            job: build_planned_job(&file.input, output, keyring, job_options.clone()),
+        });
+    }
+    Ok(execution)
+}
+
+/// What plan() needs of a file that can be decrypted.
+struct ProbedFile {
+    identity: Option<KeyDigest>,
+    recipients: Vec<KeyDigest>,
+    info: MediaInfo,
+    estimated_size: u64,
+}
+
+/// The size of `input` and what is known about it, or why it won't be decrypted.
+fn probe_file(
+    input: &Path,
+    keyring: &mut Keyring,
+    options: &DecryptOptions,
+) -> (u64, Result<ProbedFile, PlannedAction>) {
+    let skip = |e: anyhow::Error| PlannedAction::Skip {
+        reason: e.to_string(),
+    };
+    let file = match File::open(input) {
+        Ok(file) => file,
+        Err(e) => return (0, Err(skip(e.into()))),
+    };
+    let size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+    let probe = match probe(file, keyring, options) {
+        Ok(probe) => probe,
+        Err(e) => {
+            let action = match e.downcast_ref::<DecryptionError>() {
+                Some(DecryptionError::NoSuchKey { recipients }) => PlannedAction::MissingKey {
+                    recipients: recipients.iter().map(KeyDigest::to_hex).collect(),
+                },
+                _ => skip(e),
+            };
+            return (size, Err(action));
+        }
+    };
+    let mut options = options.clone();
+    options.source_stem = source_stem(input);
+    let info = match planned_media_info(&probe, &options) {
+        Ok(info) => info,
+        Err(e) => return (size, Err(skip(e))),
+    };
+    let file = ProbedFile {
+        identity: probe.identity.map(|identity| identity.digest),
+        recipients: probe.header.recipient_digests.clone(),
+        info,
+        estimated_size: estimate_data_len(&probe.header, size, probe.offset_to_data),
+    };
+    (size, Ok(file))
+}
+
+fn planned_media_info(probe: &Probe, options: &DecryptOptions) -> Result<MediaInfo> {
+    let device_label = probe.header.device_label();
+    match probe.file_type {
+        FILE_TYPE_IMAGE => {
+            decrypt_image::planned_media_info(&probe.metadata, device_label, options)
+        }
+        #[cfg(any(feature = "video", feature = "rust-mp4"))]
+        FILE_TYPE_VIDEO => {
+            decrypt_video::planned_media_info(&probe.metadata, device_label, options)
+        }
+        #[cfg(any(feature = "video", feature = "rust-mp4"))]
+        FILE_TYPE_AUDIO => {
+            decrypt_audio::planned_media_info(&probe.metadata, device_label, options)
+        }
+        #[cfg(not(any(feature = "video", feature = "rust-mp4")))]
+        FILE_TYPE_VIDEO => Err(CryptocamError::VideoSupportNotCompiled.into()),
+        #[cfg(not(any(feature = "video", feature = "rust-mp4")))]
+        FILE_TYPE_AUDIO => Err(CryptocamError::AudioSupportNotCompiled.into()),
+        file_type => Err(CryptocamError::UnknownFileType {
+            file_type,
+            registered: Registry::default().file_types(),
+        }
+        .into()),
+    }
+}
+
+/// Where the output of a file goes if `wanted` is taken, on disk or by an earlier file of the
+/// plan, following what create_output_file() does.
+fn resolve_collision(
+    wanted: PathBuf,
+    overwrite: Overwrite,
+    planned: &HashSet<PathBuf>,
+) -> (PathBuf, Option<Collision>) {
+    let taken = |path: &Path| planned.contains(path) || path.exists();
+    if !taken(&wanted) {
+        return (wanted, None);
+    }
+    match overwrite {
+        Overwrite::Replace => (wanted, Some(Collision::Replaces)),
+        Overwrite::Fail => (wanted, Some(Collision::Fails)),
+        Overwrite::Rename => {
+            let output = (1..)
+                .map(|n| numbered_path(&wanted, n))
+                .find(|candidate| !taken(candidate))
+                .unwrap();
+            (output, Some(Collision::Renamed { wanted }))
+        }
+    }
+}
+
+/// Whether `output` is what an earlier run decrypted to, i.e. it has the digest recorded in
+/// its sidecar.
+fn existing_output(output: &Path) -> bool {
+    if !output.is_file() {
+        return false;
+    }
+    let digest = match recorded_digest(output) {
+        Some(digest) => digest,
+        None => {
+            warn!(
+                "{} has no recorded digest, decrypting again",
+                output.display()
+            );
+            return false;
+        }
+    };
+    let matches = File::open(output)
+        .map_err(anyhow::Error::from)
+        .and_then(|file| digest.verify(file));
+    match matches {
+        Ok(matches) => matches,
+        Err(e) => {
+            warn!("Error hashing {}: {}", output.display(), e);
+            false
+        }
+    }
+}
+
+fn build_planned_job(
+    input: &Path,
+    output: &Path,
+    keyring: &mut Keyring,
+    mut options: DecryptOptions,
+) -> Result<Box<dyn DecryptingJob + Send>> {
+    let out_dir = output
+        .parent()
+        .ok_or_else(|| anyhow!("Invalid output {}", output.display()))?;
+    let stem = output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    options.naming = OutputNaming::Callback(Arc::new(move |_: &MediaInfo| stem.clone()));
+    options.subdirectory_strategy = SubdirectoryStrategy::Flat;
+    options.batch_names = None;
+    options.source_stem = source_stem(input);
+    fs::create_dir_all(out_dir).map_err(|e| anyhow!("Error creating output directory: {}", e))?;
+    let file =
+        File::open(input).map_err(|e| anyhow!("Error opening {}: {}", input.display(), e))?;
+    decrypt_with_options(file, keyring, out_dir.to_path_buf(), options)
+}
+
+fn source_stem(input: &Path) -> Option<String> {
+    input
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+}
@@ -0,0 +1,172 @@
+use crate::error::Error;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::Duration,
+};
+
+/// Limits on the memory a batch of files decrypted in parallel may use for headers, metadata,
+/// payload buffers and buffered packets together. A file that would exceed a limit waits until
+/// other files release enough, a file that exceeds a limit on its own fails with
+/// Error::ResourceBudgetExceeded.
+/// Clones share the same counters, so one budget is created per batch and handed to every job
+/// through DecryptOptions::resource_budget.
+#[derive(Debug, Clone)]
+pub struct ResourceBudget {
+    limits: ResourceUsage,
+    state: Arc<(Mutex<BudgetState>, Condvar)>,
+}
+
+/// Amounts of the resources tracked by a ResourceBudget.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    pub metadata_bytes: u64,
+    pub header_bytes: u64,
+    pub payload_buffers: u64,
+    /// Video and audio packets read but not written yet, including those queued for the muxer
+    /// in pipelined mode, and the frames thumbnails are made from.
+    pub buffer_bytes: u64,
+}
+
+#[derive(Debug, Default)]
+struct BudgetState {
+    used: ResourceUsage,
+    peak: ResourceUsage,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Resource {
+    MetadataBytes,
+    HeaderBytes,
+    PayloadBuffers,
+    BufferBytes,
+}
+
+impl Resource {
+    fn name(&self) -> &'static str {
+        match self {
+            Resource::MetadataBytes => "metadata bytes",
+            Resource::HeaderBytes => "header bytes",
+            Resource::PayloadBuffers => "concurrent payload buffers",
+            Resource::BufferBytes => "buffered bytes",
+        }
+    }
+
+    fn of(self, usage: &mut ResourceUsage) -> &mut u64 {
+        match self {
+            Resource::MetadataBytes => &mut usage.metadata_bytes,
+            Resource::HeaderBytes => &mut usage.header_bytes,
+            Resource::PayloadBuffers => &mut usage.payload_buffers,
+            Resource::BufferBytes => &mut usage.buffer_bytes,
+        }
+    }
+}
+
+impl Default for ResourceBudget {
+    /// Far more than any real recording needs: metadata is a few hundred bytes and
+    /// headers a few kilobytes per file.
+    fn default() -> Self {
+        ResourceBudget::new(64 << 20, 16 << 20, 16)
+    }
+}
+
+impl ResourceBudget {
+    /// Buffered bytes are unlimited, see max_buffer_bytes().
+    pub fn new(
+        max_total_metadata_bytes: u64,
+        max_total_header_bytes: u64,
+        max_concurrent_payload_buffers: u64,
+    ) -> Self {
+        ResourceBudget {
+            limits: ResourceUsage {
+                metadata_bytes: max_total_metadata_bytes,
+                header_bytes: max_total_header_bytes,
+                payload_buffers: max_concurrent_payload_buffers,
+                buffer_bytes: u64::MAX,
+            },
+            state: Arc::new((Mutex::new(BudgetState::default()), Condvar::new())),
+        }
+    }
+
+    /// Limits the bytes of packets and frames all jobs together hold in memory. Jobs wait for
+    /// others to write theirs out when it is reached, a pipelined job's queue shrinks to what
+    /// fits. It has to be larger than the largest packet, usually a keyframe of a few hundred
+    /// kilobytes.
+    pub fn max_buffer_bytes(mut self, max_buffer_bytes: u64) -> Self {
+        self.limits.buffer_bytes = max_buffer_bytes;
+        self
+    }
+
+    pub fn limits(&self) -> ResourceUsage {
+        self.limits
+    }
+
+    /// What is reserved right now.
+    pub fn usage(&self) -> ResourceUsage {
+        self.state.0.lock().unwrap().used
+    }
+
+    /// The most that was reserved at any one time.
+    pub fn peak(&self) -> ResourceUsage {
+        self.state.0.lock().unwrap().peak
+    }
+
+    /// Reserves `amount` of `resource`, waiting for other reservations to be released if needed.
+    /// Gives up with None if `cancel` is set while waiting.
+    pub(crate) fn reserve(
+        &self,
+        resource: Resource,
+        amount: u64,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<Option<Reservation>, Error> {
+        let mut limits = self.limits;
+        let limit = *resource.of(&mut limits);
+        if amount > limit {
+            return Err(Error::ResourceBudgetExceeded {
+                resource: resource.name(),
+                requested: amount,
+                limit,
+            });
+        }
+        let (lock, released) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        while *resource.of(&mut state.used) + amount > limit {
+            if cancel.is_some_and(|c| c.load(Ordering::Relaxed)) {
+                return Ok(None);
+            }
+            state = released
+                .wait_timeout(state, Duration::from_millis(100))
+                .unwrap()
+                .0;
+        }
+        let BudgetState { used, peak } = &mut *state;
+        *resource.of(used) += amount;
+        let now = *resource.of(used);
+        let peak = resource.of(peak);
+        *peak = (*peak).max(now);
+        Ok(Some(Reservation {
+            budget: self.clone(),
+            resource,
+            amount,
+        }))
+    }
+}
+
+/// A part of a ResourceBudget that is given back when dropped.
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    budget: ResourceBudget,
+    resource: Resource,
+    amount: u64,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let (lock, released) = &*self.budget.state;
+        let mut state = lock.lock().unwrap();
+        *self.resource.of(&mut state.used) -= self.amount;
+        released.notify_all();
+    }
+}
@@ -0,0 +1,168 @@
+//! A FrameProcessor stamping the time of recording into every frame, see TimestampBurnIn.
+
+use crate::{timestamp::parse_timestamp, transcode::FrameProcessor};
+use ac_ffmpeg::codec::video::{frame::get_pixel_format, VideoFrameMut};
+use chrono::{DateTime, Duration, FixedOffset};
+use log::warn;
+
+/// 5x7 glyphs, one byte per row with the leftmost pixel in bit 4.
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+const DIGITS: [[u8; GLYPH_HEIGHT]; 10] = [
+    [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+    [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+    [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+    [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+    [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+    [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+    [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+    [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+    [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+    [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+];
+const DASH: [u8; GLYPH_HEIGHT] = [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00];
+const COLON: [u8; GLYPH_HEIGHT] = [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x0c, 0x00];
+const SPACE: [u8; GLYPH_HEIGHT] = [0; GLYPH_HEIGHT];
+
+/// Video range luma of the text and the box behind it, and neutral chroma.
+const TEXT_LUMA: u8 = 235;
+const BOX_LUMA: u8 = 16;
+const NEUTRAL_CHROMA: u8 = 128;
+
+/// Where TimestampBurnIn puts the time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+/// Burns the wall-clock time of each frame, the metadata timestamp plus the frame's PTS, into
+/// a corner as `YYYY-MM-DD HH:MM:SS`, white on a black box. The time is in the timezone of
+/// the timestamp, or the local one of the machine if it has none. Videos whose timestamp
+/// can't be parsed are left as they are, with a warning in the log. Set it with
+/// `TranscodeSpec::new("libx264").frame_processor(|| Box::new(TimestampBurnIn::new()))`.
+#[derive(Debug, Clone)]
+pub struct TimestampBurnIn {
+    /// Bottom left by default.
+    pub corner: Corner,
+    /// How many pixels each pixel of the font takes, by default a 270th of the frame height.
+    pub scale: Option<usize>,
+    start: Option<DateTime<FixedOffset>>,
+}
+
+impl Default for TimestampBurnIn {
+    fn default() -> Self {
+        TimestampBurnIn {
+            corner: Corner::BottomLeft,
+            scale: None,
+            start: None,
+        }
+    }
+}
+
+// setters for the fields above, see there
+impl TimestampBurnIn {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn corner(mut self, corner: Corner) -> Self {
+        self.corner = corner;
+        self
+    }
+
+    pub fn scale(mut self, scale: usize) -> Self {
+        self.scale = Some(scale.max(1));
+        self
+    }
+}
+
+impl FrameProcessor for TimestampBurnIn {
+    fn start(&mut self, timestamp: &str) {
+        match parse_timestamp(timestamp) {
+            Ok(start) => self.start = Some(start),
+            Err(e) => warn!("Not burning in the time: {}", e),
+        }
+    }
+
+    fn wants(&mut self, _pts_us: i64) -> bool {
+        self.start.is_some()
+    }
+
+    fn process(&mut self, frame: &mut VideoFrameMut, pts_us: i64) {
+        let start = match self.start {
+            Some(start) => start,
+            None => return,
+        };
+        if frame.pixel_format() != get_pixel_format("yuv420p") {
+            return;
+        }
+        let time = start + Duration::microseconds(pts_us);
+        let text = time.format("%Y-%m-%d %H:%M:%S").to_string();
+        let (width, height) = (frame.width(), frame.height());
+        let scale = self.scale.unwrap_or(height / 270).max(1);
+        // a glyph and a pixel of space after it, a pixel of box around the text
+        let box_width = (text.len() * (GLYPH_WIDTH + 1) + 1) * scale;
+        let box_height = (GLYPH_HEIGHT + 2) * scale;
+        let margin = 4 * scale;
+        if box_width + 2 * margin > width || box_height + 2 * margin > height {
+            return;
+        }
+        // even, so the box covers whole chroma samples
+        let left = match self.corner {
+            Corner::TopLeft | Corner::BottomLeft => margin,
+            Corner::TopRight | Corner::BottomRight => width - margin - box_width,
+        } & !1;
+        let top = match self.corner {
+            Corner::TopLeft | Corner::TopRight => margin,
+            Corner::BottomLeft | Corner::BottomRight => height - margin - box_height,
+        } & !1;
+
+        let mut planes = frame.planes_mut();
+        for y in 0..box_height {
+            let line = match planes[0].line_mut(top + y) {
+                Some(line) => line,
+                None => return,
+            };
+            for x in 0..box_width {
+                line[left + x] = if lit(&text, x / scale, y / scale) {
+                    TEXT_LUMA
+                } else {
+                    BOX_LUMA
+                };
+            }
+        }
+        for plane in 1..3 {
+            for y in top / 2..(top + box_height + 1) / 2 {
+                if let Some(line) = planes[plane].line_mut(y) {
+                    let end = ((left + box_width + 1) / 2).min(line.len());
+                    line[left / 2..end].fill(NEUTRAL_CHROMA);
+                }
+            }
+        }
+    }
+}
+
+/// Whether the font pixel at `x`, `y` of the box around `text` is part of a glyph.
+fn lit(text: &str, x: usize, y: usize) -> bool {
+    if x == 0 || y == 0 || y > GLYPH_HEIGHT {
+        return false;
+    }
+    let (index, column) = ((x - 1) / (GLYPH_WIDTH + 1), (x - 1) % (GLYPH_WIDTH + 1));
+    let rows = match text.as_bytes().get(index) {
+        Some(&c) => glyph(c),
+        None => return false,
+    };
+    column < GLYPH_WIDTH && rows[y - 1] & (0x10 >> column) != 0
+}
+
+fn glyph(c: u8) -> &'static [u8; GLYPH_HEIGHT] {
+    match c {
+        b'0'..=b'9' => &DIGITS[(c - b'0') as usize],
+        b'-' => &DASH,
+        b':' => &COLON,
+        _ => &SPACE,
+    }
+}
@@ -0,0 +1,329 @@
+//! Stopping a running job, see CancellationToken.
+
+#[cfg(feature = "thumbnail")]
+use crate::thumbnail::ThumbnailFormat;
+use crate::{
+    decrypt::{DecryptStats, ProgressCallback},
+    error::Error,
+    hash::HashDigest,
+    keyring::IdentityInfo,
+    packet::PacketKind,
+    verify::VerificationReport,
+    warning::DecryptWarning,
+};
+use std::{
+    error::Error as StdError,
+    io::{self, Read},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+/// Jobs read the decrypted data in pieces of at most this size and check for cancellation
+/// before each, so a cancelled job stops within one age chunk of reading even in the middle of
+/// a large packet.
+const CANCEL_CHECK_LEN: usize = 64 << 10;
+
+/// Stops a job run with DecryptingJob::run_with_token() or started by decrypt_async(), and
+/// tells when it has stopped. Clones stop the same job. Wraps the `Arc<AtomicBool>` that
+/// DecryptingJob::run() takes, see flag().
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    ack: CancelAck,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Set once the job has returned, see CancelAck.
+    pub fn cancelled_ack(&self) -> CancelAck {
+        self.ack.clone()
+    }
+
+    /// The flag cancel() sets, for passing to DecryptingJob::run(). Jobs run that way don't
+    /// set cancelled_ack().
+    pub fn flag(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+}
+
+impl From<Arc<AtomicBool>> for CancellationToken {
+    /// A token setting `flag` when cancelled.
+    fn from(flag: Arc<AtomicBool>) -> Self {
+        CancellationToken {
+            cancelled: flag,
+            ack: CancelAck::default(),
+        }
+    }
+}
+
+/// Set by DecryptingJob::run_with_token() once the job has returned, telling whether it
+/// stopped because it was cancelled. By then the job has removed its partial output, unless
+/// it was resumable, so the output directory can be cleaned up.
+#[derive(Debug, Clone, Default)]
+pub struct CancelAck(Arc<(Mutex<Option<bool>>, Condvar)>);
+
+impl CancelAck {
+    /// Whether the job has stopped because it was cancelled. False while it is still running
+    /// and if it completed or failed before noticing the cancellation.
+    pub fn is_acknowledged(&self) -> bool {
+        *self.state() == Some(true)
+    }
+
+    /// Waits for the job to return, true if it stopped because it was cancelled. Blocks
+    /// forever if the job isn't run with DecryptingJob::run_with_token().
+    pub fn wait(&self) -> bool {
+        let (_, returned) = &*self.0;
+        let mut state = self.state();
+        loop {
+            match *state {
+                Some(cancelled) => return cancelled,
+                None => state = returned.wait(state).unwrap_or_else(|e| e.into_inner()),
+            }
+        }
+    }
+
+    /// Like wait(), None if the job is still running after `timeout`.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<bool> {
+        let (_, returned) = &*self.0;
+        let state = self.state();
+        let (state, _) = returned
+            .wait_timeout_while(state, timeout, |state| state.is_none())
+            .unwrap_or_else(|e| e.into_inner());
+        *state
+    }
+
+    pub(crate) fn set(&self, cancelled: bool) {
+        *self.state() = Some(cancelled);
+        self.0 .1.notify_all();
+    }
+
+    fn state(&self) -> MutexGuard<'_, Option<bool>> {
+        self.0 .0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Fails reads with error::Error::Cancelled once `cancel` is set, and reads at most
+/// CANCEL_CHECK_LEN bytes at a time so it is checked often.
+pub(crate) struct CancellableReader<'a, R> {
+    inner: R,
+    cancel: &'a AtomicBool,
+}
+
+impl<'a, R> CancellableReader<'a, R> {
+    pub(crate) fn new(inner: R, cancel: &'a AtomicBool) -> Self {
+        CancellableReader { inner, cancel }
+    }
+}
+
+impl<R: Read> Read for CancellableReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.cancel.load(Ordering::Relaxed) {
+            return Err(io::Error::other(Error::Cancelled));
+        }
+        let len = buf.len().min(CANCEL_CHECK_LEN);
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+/// Passes the events on to `inner` and remembers how the job ended, for run_with_token().
+pub(crate) struct OutcomeRecorder<'a> {
+    inner: &'a mut dyn ProgressCallback,
+    pub(crate) error: Option<String>,
+    pub(crate) complete: bool,
+}
+
+impl<'a> OutcomeRecorder<'a> {
+    pub(crate) fn new(inner: &'a mut dyn ProgressCallback) -> Self {
+        OutcomeRecorder {
+            inner,
+            error: None,
+            complete: false,
+        }
+    }
+}
+
+impl ProgressCallback for OutcomeRecorder<'_> {
+    fn set_total_file_size(&mut self, n: u64) {
+        self.inner.set_total_file_size(n);
+    }
+
+    fn on_identity_matched(&mut self, identity: &IdentityInfo) {
+        self.inner.on_identity_matched(identity);
+    }
+
+    fn on_progress(&mut self, processed_bytes: u64) {
+        self.inner.on_progress(processed_bytes);
+    }
+
+    fn on_stream_progress(
+        &mut self,
+        stream: PacketKind,
+        packets: u64,
+        bytes: u64,
+        last_pts_us: u64,
+    ) {
+        self.inner
+            .on_stream_progress(stream, packets, bytes, last_pts_us);
+    }
+
+    fn on_complete(&mut self) {
+        self.complete = true;
+        self.inner.on_complete();
+    }
+
+    fn on_error(&mut self, error: Box<dyn StdError>) {
+        self.error = Some(error.to_string());
+        self.inner.on_error(error);
+    }
+
+    fn on_warning(&mut self, warning: DecryptWarning) {
+        self.inner.on_warning(warning);
+    }
+
+    fn on_truncated(&mut self, processed_bytes: u64) {
+        self.inner.on_truncated(processed_bytes);
+    }
+
+    fn on_output_created(&mut self, path: &Path) {
+        self.inner.on_output_created(path);
+    }
+
+    fn on_output_digest(&mut self, digest: &HashDigest) {
+        self.inner.on_output_digest(digest);
+    }
+
+    fn on_stats(&mut self, stats: &DecryptStats) {
+        self.inner.on_stats(stats);
+    }
+
+    fn on_verified(&mut self, report: &VerificationReport) {
+        self.inner.on_verified(report);
+    }
+
+    #[cfg(feature = "thumbnail")]
+    fn on_thumbnail(&mut self, data: &[u8], format: ThumbnailFormat) {
+        self.inner.on_thumbnail(data, format);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        decrypt::{decrypt_stream, DecryptOptions, JobResult},
+        fixtures::{test_keyring, FixtureFile},
+    };
+    use std::{
+        io::Cursor,
+        path::PathBuf,
+        sync::mpsc::{channel, Receiver, Sender},
+        thread,
+    };
+
+    /// Stops once `gate_at` bytes are read, says so on `reached` and waits for `resume`.
+    struct GatedReader {
+        data: Cursor<Vec<u8>>,
+        gate_at: u64,
+        gate: Option<(Sender<()>, Receiver<()>)>,
+    }
+
+    impl Read for GatedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data.position() >= self.gate_at {
+                if let Some((reached, resume)) = self.gate.take() {
+                    reached.send(()).unwrap();
+                    resume.recv().unwrap();
+                }
+            }
+            self.data.read(buf)
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        output: Option<PathBuf>,
+        completed: bool,
+    }
+
+    impl ProgressCallback for Recorder {
+        fn set_total_file_size(&mut self, _: u64) {}
+        fn on_progress(&mut self, _: u64) {}
+        fn on_complete(&mut self) {
+            self.completed = true;
+        }
+        fn on_error(&mut self, error: Box<dyn StdError>) {
+            panic!("{}", error);
+        }
+        fn on_output_created(&mut self, path: &Path) {
+            self.output = Some(path.to_owned());
+        }
+    }
+
+    #[test]
+    fn cancelling_during_a_slow_read_removes_the_output() {
+        // large enough that building the job doesn't read up to the gate
+        let mut image = b"\x89PNG\r\n\x1a\n".to_vec();
+        image.resize(1 << 20, 0);
+        let file = FixtureFile::image(
+            r#"{"timestamp":"2021-06-01T12:00:00Z","format":"png"}"#,
+            image,
+        )
+        .build();
+        let (reached_tx, reached) = channel();
+        let (resume, resume_rx) = channel();
+        let reader = GatedReader {
+            data: Cursor::new(file),
+            gate_at: 600_000,
+            gate: Some((reached_tx, resume_rx)),
+        };
+        let out_dir = tempfile::tempdir().unwrap();
+        let mut job = decrypt_stream(
+            reader,
+            &mut test_keyring(),
+            out_dir.path().to_path_buf(),
+            DecryptOptions::default(),
+        )
+        .unwrap();
+        let token = CancellationToken::new();
+        let ack = token.cancelled_ack();
+        let running = thread::spawn({
+            let token = token.clone();
+            move || {
+                let mut recorder = Recorder::default();
+                let result = job.run_with_token(Box::new(&mut recorder), &token);
+                (result, recorder)
+            }
+        });
+
+        reached.recv().unwrap();
+        assert_eq!(ack.wait_timeout(Duration::from_millis(10)), None);
+        token.cancel();
+        resume.send(()).unwrap();
+        assert_eq!(ack.wait_timeout(Duration::from_secs(10)), Some(true));
+        assert!(ack.is_acknowledged());
+
+        let (result, recorder) = running.join().unwrap();
+        assert_eq!(result, JobResult::Cancelled);
+        assert!(!recorder.completed);
+        let output = recorder
+            .output
+            .expect("the output was created before cancelling");
+        assert!(!output.exists());
+        assert_eq!(std::fs::read_dir(out_dir.path()).unwrap().count(), 0);
+    }
+}
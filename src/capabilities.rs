@@ -0,0 +1,238 @@
+//! What this build of the crate can do, see capabilities().
+
+use crate::{
+    decrypt::{FILE_TYPE_AUDIO, FILE_TYPE_IMAGE, FILE_TYPE_VIDEO},
+    parser::HeaderVersion,
+};
+use serde::Serialize;
+
+/// The version and supported formats of this build, from capabilities(). Serializes to JSON
+/// like this, for attaching to bug reports:
+///
+/// ```text
+/// {
+///   "version": "0.1.3",
+///   "header_versions": [1, 2],
+///   "file_types": [
+///     { "file_type": 1, "media_type": "video", "supported": true },
+///     ...
+///   ],
+///   "video_backends": ["ffmpeg"],
+///   "output_containers": ["mp4", "mkv", "raw_packets"],
+///   "features": ["video"],
+///   "ffmpeg_version": "4.4.2"
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Capabilities {
+    /// The version of libcryptocam.
+    pub version: &'static str,
+    /// The header versions parse_header() reads.
+    pub header_versions: Vec<u16>,
+    /// The file types the default Registry has builders for.
+    pub file_types: Vec<FileTypeSupport>,
+    /// The VideoBackends this build has, "ffmpeg" and "rust_mp4".
+    pub video_backends: Vec<&'static str>,
+    /// The VideoContainers videos and audio recordings can be written as, "mp4", "mkv" and
+    /// "raw_packets". Empty without a video backend.
+    pub output_containers: Vec<&'static str>,
+    /// The cargo features the crate was built with.
+    pub features: Vec<&'static str>,
+    /// The version of the linked FFmpeg libraries, only with the video feature.
+    pub ffmpeg_version: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileTypeSupport {
+    /// The file type byte in the encrypted header.
+    pub file_type: u8,
+    /// "video", "image" or "audio"
+    pub media_type: &'static str,
+    /// False if decrypting it fails with error::Error::VideoSupportNotCompiled or
+    /// AudioSupportNotCompiled.
+    pub supported: bool,
+}
+
+/// The version of the crate and what the features it was built with support.
+pub fn capabilities() -> Capabilities {
+    let ffmpeg = cfg!(feature = "video");
+    let rust_mp4 = cfg!(feature = "rust-mp4");
+    let muxing = ffmpeg || rust_mp4;
+
+    let mut video_backends = vec![];
+    if ffmpeg {
+        video_backends.push("ffmpeg");
+    }
+    if rust_mp4 {
+        video_backends.push("rust_mp4");
+    }
+    let mut output_containers = vec![];
+    if muxing {
+        output_containers.push("mp4");
+    }
+    if ffmpeg {
+        output_containers.push("mkv");
+    }
+    // written without muxing, but still by the video and audio jobs
+    if muxing {
+        output_containers.push("raw_packets");
+    }
+
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        header_versions: HeaderVersion::ALL
+            .iter()
+            .map(|version| version.number())
+            .collect(),
+        file_types: vec![
+            FileTypeSupport {
+                file_type: FILE_TYPE_VIDEO,
+                media_type: "video",
+                supported: muxing,
+            },
+            FileTypeSupport {
+                file_type: FILE_TYPE_IMAGE,
+                media_type: "image",
+                supported: true,
+            },
+            FileTypeSupport {
+                file_type: FILE_TYPE_AUDIO,
+                media_type: "audio",
+                supported: muxing,
+            },
+        ],
+        video_backends,
+        output_containers,
+        features: features(),
+        ffmpeg_version: ffmpeg_version(),
+    }
+}
+
+/// The features from Cargo.toml that are enabled, in the order they are listed there.
+fn features() -> Vec<&'static str> {
+    [
+        ("video", cfg!(feature = "video")),
+        ("rust-mp4", cfg!(feature = "rust-mp4")),
+        ("image", cfg!(feature = "image")),
+        ("qr-decode", cfg!(feature = "qr-decode")),
+        ("shamir", cfg!(feature = "shamir")),
+        ("thumbnail", cfg!(feature = "thumbnail")),
+        ("transcode", cfg!(feature = "transcode")),
+        ("ffi", cfg!(feature = "ffi")),
+        ("async", cfg!(feature = "async")),
+        ("watch", cfg!(feature = "watch")),
+        ("test-fixtures", cfg!(feature = "test-fixtures")),
+        ("fuzzing", cfg!(feature = "fuzzing")),
+    ]
+    .iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(feature, _)| *feature)
+    .collect()
+}
+
+#[cfg(feature = "video")]
+fn ffmpeg_version() -> Option<String> {
+    use std::{ffi::CStr, os::raw::c_char};
+
+    // libavutil is linked by ac-ffmpeg, which doesn't wrap this
+    extern "C" {
+        fn av_version_info() -> *const c_char;
+    }
+    let version = unsafe { av_version_info() };
+    if version.is_null() {
+        return None;
+    }
+    let version = unsafe { CStr::from_ptr(version) };
+    Some(version.to_string_lossy().into_owned())
+}
+
+#[cfg(not(feature = "video"))]
+fn ffmpeg_version() -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supported(capabilities: &Capabilities, media_type: &str) -> bool {
+        capabilities
+            .file_types
+            .iter()
+            .find(|support| support.media_type == media_type)
+            .unwrap()
+            .supported
+    }
+
+    #[test]
+    fn images_are_always_supported() {
+        let capabilities = capabilities();
+        assert!(supported(&capabilities, "image"));
+        assert_eq!(capabilities.header_versions, [1, 2]);
+        assert_eq!(capabilities.version, env!("CARGO_PKG_VERSION"));
+    }
+
+    #[cfg(feature = "video")]
+    #[test]
+    fn video_feature_adds_ffmpeg() {
+        let capabilities = capabilities();
+        assert!(capabilities.video_backends.contains(&"ffmpeg"));
+        assert_eq!(
+            capabilities.output_containers,
+            ["mp4", "mkv", "raw_packets"]
+        );
+        assert!(capabilities.ffmpeg_version.is_some());
+        assert!(capabilities.features.contains(&"video"));
+    }
+
+    #[cfg(not(feature = "video"))]
+    #[test]
+    fn without_video_feature_there_is_no_ffmpeg() {
+        let capabilities = capabilities();
+        assert!(!capabilities.video_backends.contains(&"ffmpeg"));
+        assert!(!capabilities.output_containers.contains(&"mkv"));
+        assert_eq!(capabilities.ffmpeg_version, None);
+        assert!(!capabilities.features.contains(&"video"));
+    }
+
+    #[cfg(feature = "rust-mp4")]
+    #[test]
+    fn rust_mp4_feature_adds_its_backend() {
+        let capabilities = capabilities();
+        assert!(capabilities.video_backends.contains(&"rust_mp4"));
+        assert!(capabilities.output_containers.contains(&"mp4"));
+        assert!(capabilities.features.contains(&"rust-mp4"));
+    }
+
+    #[cfg(any(feature = "video", feature = "rust-mp4"))]
+    #[test]
+    fn videos_and_audio_are_supported_with_a_backend() {
+        let capabilities = capabilities();
+        assert!(supported(&capabilities, "video"));
+        assert!(supported(&capabilities, "audio"));
+    }
+
+    #[cfg(not(any(feature = "video", feature = "rust-mp4")))]
+    #[test]
+    fn videos_and_audio_need_a_backend() {
+        let capabilities = capabilities();
+        assert!(!supported(&capabilities, "video"));
+        assert!(!supported(&capabilities, "audio"));
+        assert!(capabilities.video_backends.is_empty());
+        assert!(capabilities.output_containers.is_empty());
+    }
+
+    #[test]
+    fn listed_features_are_those_enabled() {
+        let features = capabilities().features;
+        assert_eq!(features.contains(&"shamir"), cfg!(feature = "shamir"));
+        assert_eq!(features.contains(&"thumbnail"), cfg!(feature = "thumbnail"));
+        assert_eq!(features.contains(&"transcode"), cfg!(feature = "transcode"));
+        assert_eq!(features.contains(&"async"), cfg!(feature = "async"));
+        assert_eq!(features.contains(&"watch"), cfg!(feature = "watch"));
+        assert_eq!(
+            features.contains(&"test-fixtures"),
+            cfg!(feature = "test-fixtures")
+        );
+    }
+}
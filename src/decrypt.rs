@@ -1,68 +1,1402 @@
+#[cfg(feature = "transcode")]
+pub use crate::burn_in::{Corner, TimestampBurnIn};
+pub use crate::cancel::{CancelAck, CancellationToken};
+#[cfg(feature = "async")]
+pub use crate::decrypt_async::{decrypt_async, JobEvent, ProgressStream};
+use crate::decrypt_image::write_image;
+pub use crate::decrypt_image::DecryptedImage;
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+use crate::decrypt_video::{build_stitch_job, StitchSegment};
+pub use crate::estimate::estimate_output_size;
+pub use crate::jsonl::{JsonlProgress, JSONL_SCHEMA_VERSION};
+pub use crate::output_path::{
+    BatchNames, MediaInfo, NameTemplate, OutputNaming, Overwrite, SubdirectoryStrategy,
+};
+pub use crate::progress::InputPosition;
+pub use crate::reencrypt::reencrypt;
+pub use crate::registry::{JobBuilder, JobContext, Registry};
+pub use crate::scan::{
+    scan_dir, scan_dir_all, total_estimated_output_size, ScanEntry, ScanErrorKind,
+};
+#[cfg(feature = "thumbnail")]
+pub use crate::thumbnail::{ThumbnailFormat, ThumbnailSpec};
+#[cfg(feature = "transcode")]
+pub use crate::transcode::FrameProcessor;
+pub use crate::transcode::TranscodeSpec;
+pub use crate::verify::VerificationReport;
+pub use crate::warning::DecryptWarning;
+#[cfg(feature = "watch")]
+pub use crate::watch::{watch_dir, WatchEvent, WatchHandle, WatchOptions};
 use crate::{
-    decrypt_image::build_image_decryption_job, decrypt_video::build_video_decryption_job,
-    keyring::Keyring, parser::parse_header,
+    budget::{Reservation, Resource, ResourceBudget},
+    cancel::OutcomeRecorder,
+    error::read_error_anyhow,
+    estimate::estimate_data_len,
+    hash::{HashAlgo, HashDigest},
+    keyring::{DecryptionError, IdentityInfo, Keyring},
+    output_path::absolutize_output_dir,
+    packet::{PacketKind, MAX_PACKET_LEN},
+    parser::{parse_header_within_budget, Header},
+    progress::CountingReader,
+    space::{available_space, check_free_space, needed_space},
 };
 use anyhow::{bail, Result};
 use bytes::ByteOrder;
+#[cfg(unix)]
+use std::os::unix::io::OwnedFd;
 use std::{
-    error::Error, fs::File, io::BufReader, io::Read, path::PathBuf, sync::atomic::AtomicBool,
+    error::Error,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::atomic::AtomicBool,
     sync::Arc,
 };
 
 /// Decrypts a Cryptocam output file, taking keys from the provided keyring.
-/// passphrase_input is used to ask the user for a passphrase through e.g. pinentry or the terminal.
+/// If the file's key is passphrase protected and locked, the keyring's PassphraseProvider is asked
+/// for the passphrase, see Keyring::set_passphrase_provider().
 /// progress_callback(process, total) receives the number of processed bytes and the total length of the file.
+/// A relative out_path is resolved against the current working directory when the job is built,
+/// not when it is run.
+/// Without the video or rust-mp4 feature, videos fail with error::Error::VideoSupportNotCompiled
+/// and audio recordings with error::Error::AudioSupportNotCompiled.
 pub fn decrypt(
     file: File,
     keyring: &mut Keyring,
     out_path: PathBuf,
 ) -> Result<Box<dyn DecryptingJob + Send>> {
-    let total_file_size = file.metadata().map_or(0, |md| md.len());
-    let mut buf_reader = BufReader::new(file);
-    let (header, header_len) = parse_header(&mut buf_reader)?;
-    if header.version != 1 {
-        bail!("Bad Version in file header")
-    }
-    let mut decrypted =
-        BufReader::new(keyring.decrypt(Box::new(buf_reader), &header.recipient_digests)?);
+    decrypt_with_options(file, keyring, out_path, DecryptOptions::default())
+}
+
+/// Like decrypt(), with control over how the output is written.
+pub fn decrypt_with_options(
+    file: File,
+    keyring: &mut Keyring,
+    out_path: PathBuf,
+    options: DecryptOptions,
+) -> Result<Box<dyn DecryptingJob + Send>> {
+    let file_size = file.metadata().map(|md| md.len()).ok();
+    decrypt_from_reader(file, file_size, keyring, out_path, options)
+}
+
+/// Like decrypt_with_options(), building the job with the JobBuilder `registry` has for the
+/// file's type. Types without one fail with error::Error::UnknownFileType.
+pub fn decrypt_with_registry(
+    mut file: File,
+    keyring: &mut Keyring,
+    out_path: PathBuf,
+    options: DecryptOptions,
+    registry: &Registry,
+) -> Result<Box<dyn DecryptingJob + Send>> {
+    let total_file_size = match file.metadata() {
+        Ok(md) => md.len(),
+        Err(_) => remaining_len(&mut file)?,
+    };
+    decrypt_reader(file, total_file_size, keyring, out_path, options, registry)
+}
+
+/// Like decrypt_with_options(), reading from anything seekable, e.g. a Cursor over a file in
+/// memory. `known_size` is the number of bytes from the reader's position to the end, used as
+/// the total for progress. If it is None, the reader is seeked to the end and back to find out.
+pub fn decrypt_from_reader<R: Read + Seek + Send + 'static>(
+    mut reader: R,
+    known_size: Option<u64>,
+    keyring: &mut Keyring,
+    out_path: PathBuf,
+    options: DecryptOptions,
+) -> Result<Box<dyn DecryptingJob + Send>> {
+    let total_file_size = match known_size {
+        Some(size) => size,
+        None => remaining_len(&mut reader)?,
+    };
+    decrypt_reader(
+        reader,
+        total_file_size,
+        keyring,
+        out_path,
+        options,
+        &Registry::default(),
+    )
+}
+
+/// Like decrypt_from_reader(), for input that can't be seeked, e.g. stdin or a network stream.
+/// The total size is reported as 0 since it isn't known. Combined with
+/// DecryptOptions::fragmented, neither input nor output are seeked.
+pub fn decrypt_stream<R: Read + Send + 'static>(
+    reader: R,
+    keyring: &mut Keyring,
+    out_path: PathBuf,
+    options: DecryptOptions,
+) -> Result<Box<dyn DecryptingJob + Send>> {
+    decrypt_reader(reader, 0, keyring, out_path, options, &Registry::default())
+}
+
+/// Like decrypt_with_options(), for a file descriptor of the input without a path, like the
+/// ones Android's Storage Access Framework hands out. The descriptor is closed with the job.
+/// It is only read from, never seeked, so pipes and content provider descriptors work too.
+/// `size_hint` is the total for progress when fstat() doesn't give a size, as for those
+/// descriptors, which often report 0. Without either, the total is reported as 0.
+#[cfg(unix)]
+pub fn decrypt_from_fd(
+    fd: OwnedFd,
+    size_hint: Option<u64>,
+    keyring: &mut Keyring,
+    out_path: PathBuf,
+    options: DecryptOptions,
+) -> Result<Box<dyn DecryptingJob + Send>> {
+    let file = File::from(fd);
+    let total_file_size = fd_size(&file).or(size_hint).unwrap_or(0);
+    decrypt_reader(
+        file,
+        total_file_size,
+        keyring,
+        out_path,
+        options,
+        &Registry::default(),
+    )
+}
+
+/// Like decrypt_image_to_writer(), reading from and writing to file descriptors, e.g. two
+/// from the Storage Access Framework. Both are closed when done.
+#[cfg(unix)]
+pub fn decrypt_image_from_fd(
+    fd: OwnedFd,
+    keyring: &mut Keyring,
+    out: OwnedFd,
+    options: DecryptOptions,
+) -> Result<DecryptedImage> {
+    let mut out = io::BufWriter::new(File::from(out));
+    let image = decrypt_image_to_writer(File::from(fd), keyring, &mut out, options)?;
+    io::Write::flush(&mut out)?;
+    Ok(image)
+}
+
+/// The size of a regular file behind a descriptor, None for anything else or a size of 0.
+#[cfg(unix)]
+fn fd_size(file: &File) -> Option<u64> {
+    file.metadata()
+        .ok()
+        .filter(|md| md.is_file())
+        .map(|md| md.len())
+        .filter(|&len| len > 0)
+}
+
+fn decrypt_reader<R: Read + Send + 'static>(
+    reader: R,
+    total_file_size: u64,
+    keyring: &mut Keyring,
+    out_path: PathBuf,
+    options: DecryptOptions,
+    registry: &Registry,
+) -> Result<Box<dyn DecryptingJob + Send>> {
+    options.validate()?;
+    let out_path = absolutize_output_dir(out_path)?;
+    let Payload {
+        header,
+        header_len,
+        file_type,
+        offset_to_data,
+        metadata,
+        data,
+        identity,
+        input_position,
+        _reservations,
+    } = open_payload(reader, keyring, &options)?;
+    let bytes_before_data = header_len + offset_to_data as u64;
+    let estimated_output_size = match total_file_size {
+        0 => None,
+        _ if options.verify_only => Some(0),
+        _ => Some(estimate_data_len(&header, total_file_size, offset_to_data)),
+    };
+    let free_space_check = match estimated_output_size {
+        Some(estimate) if options.check_free_space && !options.verify_only => {
+            Some((out_path.clone(), needed_space(estimate, options.faststart)))
+        }
+        _ => None,
+    };
+    let builder = registry.builder(file_type)?;
+    let job = builder.build(
+        Box::new(data),
+        &metadata,
+        out_path,
+        total_file_size,
+        bytes_before_data,
+        JobContext {
+            header,
+            options,
+            input_position,
+        },
+    )?;
+    Ok(Box::new(MatchedJob {
+        job,
+        identity,
+        estimated_output_size,
+        free_space_check,
+    }))
+}
+
+/// Decrypts the segments of a recording the app split into several files into one video in
+/// `out_path`, named after the first segment. `files` have to be in recording order. Each
+/// segment's timestamps continue one frame interval after the last packet of the segment
+/// before it. The segments have to be videos with the same codec, size and audio format, or
+/// this fails with error::Error::IncompatibleSegments. Progress counts the sizes of all files
+/// together, the job is cancelled like any other.
+///
+/// Stitched videos can't be resumed or verified, and need a container other than
+/// VideoContainer::RawPackets. DecryptOptions::write_metadata_sidecar is ignored.
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+pub fn stitch(
+    files: Vec<File>,
+    keyring: &mut Keyring,
+    out_path: PathBuf,
+    options: DecryptOptions,
+) -> Result<Box<dyn DecryptingJob + Send>> {
+    options.validate()?;
+    if options.verify_only || options.resumable || options.resume_from.is_some() {
+        bail!("Stitched videos can't be verified or resumed");
+    }
+    if options.container == VideoContainer::RawPackets {
+        bail!("Stitched videos can't be written as raw packets");
+    }
+    let out_path = absolutize_output_dir(out_path)?;
+    let mut segments = vec![];
+    let mut first = None;
+    let mut estimated_output_size = 0;
+    for (index, file) in files.into_iter().enumerate() {
+        let total_file_size = file.metadata().map(|md| md.len()).unwrap_or(0);
+        let payload = open_payload(file, keyring, &options)?;
+        if payload.file_type != FILE_TYPE_VIDEO {
+            bail!(
+                "Segment {} is not a video, file type {}",
+                index,
+                payload.file_type
+            );
+        }
+        estimated_output_size +=
+            estimate_data_len(&payload.header, total_file_size, payload.offset_to_data);
+        if first.is_none() {
+            first = Some((payload.identity.clone(), payload.header.device_label()));
+        }
+        segments.push(StitchSegment {
+            data: Box::new(payload.data),
+            metadata: payload.metadata,
+            total_file_size,
+            input_position: payload.input_position,
+        });
+    }
+    let (identity, device_label) = match first {
+        Some(first) => first,
+        None => bail!("No segments to stitch"),
+    };
+    let free_space_check = if options.check_free_space {
+        Some((
+            out_path.clone(),
+            needed_space(estimated_output_size, options.faststart),
+        ))
+    } else {
+        None
+    };
+    let job = build_stitch_job(segments, out_path, device_label, options)?;
+    Ok(Box::new(MatchedJob {
+        job,
+        identity,
+        estimated_output_size: Some(estimated_output_size),
+        free_space_check,
+    }))
+}
+
+/// Decrypts an image from `reader` and writes it to `out`, without using the filesystem.
+/// Of the options, those about naming and writing output files don't apply. Fails for videos.
+pub fn decrypt_image_to_writer(
+    reader: impl Read,
+    keyring: &mut Keyring,
+    out: impl io::Write,
+    options: DecryptOptions,
+) -> Result<DecryptedImage> {
+    options.validate()?;
+    let payload = open_payload(reader, keyring, &options)?;
+    if payload.file_type != FILE_TYPE_IMAGE {
+        bail!("Not an image, file type {}", payload.file_type);
+    }
+    write_image(payload.data, &payload.metadata, out, &options)
+}
+
+/// Decrypts an image into memory, e.g. for a preview, without using the filesystem. The image
+/// is written like by decrypt_image_to_writer() with the default options. Fails with
+/// error::Error::TooLargeForMemory as soon as it grows past `limit` bytes, and with
+/// error::Error::UseStreamingApi for videos and audio recordings.
+pub fn decrypt_to_vec(
+    reader: impl Read,
+    keyring: &mut Keyring,
+    limit: usize,
+) -> Result<(MediaInfo, Vec<u8>)> {
+    let options = DecryptOptions::default();
+    let payload = open_payload(reader, keyring, &options)?;
+    match payload.file_type {
+        FILE_TYPE_IMAGE => {}
+        FILE_TYPE_VIDEO | FILE_TYPE_AUDIO => {
+            return Err(crate::error::Error::UseStreamingApi.into());
+        }
+        file_type => {
+            return Err(crate::error::Error::UnknownFileType {
+                file_type,
+                registered: Registry::default().file_types(),
+            }
+            .into())
+        }
+    }
+    let mut out = LimitedVec {
+        data: Vec::new(),
+        limit,
+        exceeded: false,
+    };
+    let written = write_image(payload.data, &payload.metadata, &mut out, &options);
+    if out.exceeded {
+        return Err(crate::error::Error::TooLargeForMemory { limit }.into());
+    }
+    let image = written?;
+    let info = MediaInfo {
+        timestamp: image.metadata.timestamp,
+        media_type: "image",
+        codec: image.extension.clone(),
+        width: None,
+        height: None,
+        source_stem: None,
+        device_label: payload.header.device_label(),
+        extra: image.metadata.extra,
+        extension: image.extension,
+    };
+    Ok((info, out.data))
+}
+
+/// A file's header and metadata, decrypted without reading any of its data, see batch::plan().
+pub(crate) struct Probe {
+    pub(crate) header: Header,
+    /// None for files encrypted with a passphrase.
+    pub(crate) identity: Option<IdentityInfo>,
+    pub(crate) file_type: u8,
+    pub(crate) offset_to_data: u32,
+    pub(crate) metadata: Vec<u8>,
+}
+
+pub(crate) fn probe(
+    reader: impl Read,
+    keyring: &mut Keyring,
+    options: &DecryptOptions,
+) -> Result<Probe> {
+    let payload = open_payload(reader, keyring, options)?;
+    Ok(Probe {
+        header: payload.header,
+        identity: payload.identity,
+        file_type: payload.file_type,
+        offset_to_data: payload.offset_to_data,
+        metadata: payload.metadata,
+    })
+}
+
+/// What decrypt_to_vec() writes to, failing once `limit` would be exceeded.
+struct LimitedVec {
+    data: Vec<u8>,
+    limit: usize,
+    exceeded: bool,
+}
+
+impl io::Write for LimitedVec {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.data.len() + buf.len() > self.limit {
+            self.exceeded = true;
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                "Size limit exceeded",
+            ));
+        }
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A decrypted file up to the start of its data. The budget reservations for the header and
+/// metadata are held until it is dropped.
+struct Payload<D> {
+    header: Header,
+    header_len: u64,
+    file_type: u8,
+    offset_to_data: u32,
+    metadata: Vec<u8>,
+    data: BufReader<D>,
+    /// The keyring identity the file was decrypted with, None if it was a passphrase.
+    identity: Option<IdentityInfo>,
+    input_position: InputPosition,
+    _reservations: (Vec<Reservation>, Option<Reservation>),
+}
+
+/// The decrypted payload of a Cryptocam file. Turns DecryptionError::PayloadCorrupted into
+/// Error::IntegrityError, with the offset counted from the start of the file.
+struct IntegrityReader<R> {
+    inner: R,
+    header_len: u64,
+}
+
+impl<R: Read> Read for IntegrityReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).map_err(|e| {
+            match e
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<DecryptionError>())
+            {
+                Some(&DecryptionError::PayloadCorrupted {
+                    decrypted_bytes,
+                    encrypted_offset,
+                }) => io::Error::new(
+                    e.kind(),
+                    crate::error::Error::IntegrityError {
+                        approximate_offset: self.header_len + encrypted_offset,
+                        processed_bytes: decrypted_bytes,
+                    },
+                ),
+                _ => e,
+            }
+        })
+    }
+}
+
+/// The most metadata open_payload() reads, whatever the budget allows. The app writes a few
+/// hundred bytes of JSON.
+const MAX_METADATA_LEN: usize = 4 << 20;
+
+/// Reads the header and decrypts the file type and metadata.
+fn open_payload<R: Read>(
+    reader: R,
+    keyring: &mut Keyring,
+    options: &DecryptOptions,
+) -> Result<Payload<impl Read>> {
+    let (reader, input_position) = CountingReader::new(reader);
+    let mut buf_reader = BufReader::with_capacity(options.input_buffer_size, reader);
+    let (header, header_len, header_reservation) =
+        parse_header_within_budget(&mut buf_reader, options.resource_budget.as_ref())?;
+    let (decrypted, identity) = keyring.decrypt(buf_reader, &header.recipient_digests)?;
+    let decrypted = IntegrityReader {
+        inner: decrypted,
+        header_len,
+    };
+    let mut decrypted = BufReader::with_capacity(options.read_buffer_size, decrypted);
     let mut encrypted_header: [u8; 5] = [0; 5];
-    decrypted.read_exact(&mut encrypted_header)?;
+    decrypted
+        .read_exact(&mut encrypted_header)
+        .map_err(read_error_anyhow)?;
     let file_type = encrypted_header[0];
     let offset_to_data = bytes::LittleEndian::read_u32(&encrypted_header[1..5]);
     let bytes_before_metadata: usize = encrypted_header.len();
-    let metadata_len: usize = offset_to_data as usize - bytes_before_metadata;
-    let mut metadata_bytes = vec![0; metadata_len];
-    decrypted.read_exact(&mut metadata_bytes)?;
-    match file_type {
-        1 => build_video_decryption_job(
-            Box::new(decrypted),
-            metadata_bytes.as_slice(),
-            out_path,
-            total_file_size,
-            header_len + offset_to_data as u64,
-        ),
-        2 => build_image_decryption_job(
-            Box::new(decrypted),
-            metadata_bytes.as_slice(),
-            out_path,
-            total_file_size,
-            header_len + offset_to_data as u64,
-        ),
-        other => {
-            bail!("Unknown file type {}", other);
+    let metadata_len: usize = match (offset_to_data as usize).checked_sub(bytes_before_metadata) {
+        Some(len) => len,
+        None => bail!("Invalid offset to data {}", offset_to_data),
+    };
+    if metadata_len > MAX_METADATA_LEN {
+        return Err(crate::error::Error::MetadataTooLarge {
+            len: metadata_len as u64,
+            limit: MAX_METADATA_LEN as u64,
+        }
+        .into());
+    }
+    let metadata_reservation = match &options.resource_budget {
+        None => None,
+        Some(budget) => budget.reserve(Resource::MetadataBytes, metadata_len as u64, None)?,
+    };
+    let mut metadata = vec![0; metadata_len];
+    decrypted
+        .read_exact(&mut metadata)
+        .map_err(read_error_anyhow)?;
+    Ok(Payload {
+        header,
+        header_len,
+        file_type,
+        offset_to_data,
+        metadata,
+        data: decrypted,
+        identity,
+        input_position,
+        _reservations: (header_reservation, metadata_reservation),
+    })
+}
+
+/// File types in the encrypted header.
+pub(crate) const FILE_TYPE_VIDEO: u8 = 1;
+pub(crate) const FILE_TYPE_IMAGE: u8 = 2;
+pub(crate) const FILE_TYPE_AUDIO: u8 = 3;
+
+/// How decrypt_with_options() writes its output. Built from DecryptOptions::new() or default()
+/// with the setters below, new options may be added in any release.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct DecryptOptions {
+    /// Move the moov box of decrypted videos in front of the media data so playback can start
+    /// before the whole file has been read. This rewrites the output once more after muxing,
+    /// which is reported as the last 5% of progress. Only applies to MP4 output, building a job
+    /// fails if the container is set to Mkv.
+    pub faststart: bool,
+    /// Set the modification time of outputs to the recording timestamp. On by default.
+    pub preserve_timestamps: bool,
+    /// Write the recording time and orientation as EXIF into decrypted JPEGs. Existing EXIF
+    /// data is kept and only missing tags are added. On by default.
+    pub write_exif: bool,
+    /// Leave the GPS location in the metadata out of the output: videos get no location tag,
+    /// JPEGs no GPS tags from the metadata and the sidecar no coordinates. The location is
+    /// kept by default. GPS tags the camera wrote into a JPEG itself are left alone.
+    pub strip_location: bool,
+    /// Names of the other files decrypted in the same batch, see BatchNames.
+    pub batch_names: Option<BatchNames>,
+    /// Memory limits shared by all files of a batch, see ResourceBudget. Nothing is accounted if None.
+    pub resource_budget: Option<ResourceBudget>,
+    /// What to do when the contents of an image don't match the format in its metadata.
+    /// Either way, on_warning() is called.
+    pub image_format_mismatch: ImageFormatMismatch,
+    /// When a video ends in the middle of a packet, e.g. because the phone died while recording,
+    /// finish the MP4 with the packets read so far instead of failing. on_warning() and
+    /// on_truncated() are called before on_complete(). On by default.
+    pub finalize_on_truncation: bool,
+    /// When a video packet header is implausible (unknown type, huge length, PTS far away from
+    /// the previous packet), skip ahead to the next plausible header instead of failing, and
+    /// report the skipped bytes through on_warning(). Meant for recovering damaged files,
+    /// off by default.
+    pub resync_on_error: bool,
+    /// When the metadata of an image or video can't be parsed, e.g. because the app wrote it
+    /// truncated, write the payload as it is to `<source stem>.bin` instead of failing: the
+    /// image file, or the packet stream of a video. The metadata goes to
+    /// `<source stem>.metadata.raw` next to it and on_warning() is called. Off by default.
+    pub recover_raw_on_bad_metadata: bool,
+    /// What to do with video or audio packets whose timestamp is not after the previous one in
+    /// the same stream. Steps back by more than a few seconds are treated as a clock change
+    /// and shift all following timestamps instead.
+    pub non_monotonic_pts: NonMonotonicPts,
+    /// Size of the buffer in front of the decrypted stream. Larger reads let the decryption
+    /// work on whole chunks. 256 KiB by default.
+    pub read_buffer_size: usize,
+    /// Size of the buffer the encrypted file is read through. 8 KiB by default.
+    pub input_buffer_size: usize,
+    /// Size of the buffer in front of output files. The muxers and the image copy write in
+    /// small pieces, a buffer of a MiB or so turns them into fewer large writes, which helps on
+    /// spinning disks and network shares. 0 by default, every write goes to the file.
+    pub write_buffer_size: usize,
+    /// Flush outputs to disk before the job completes, so they survive a crash or power loss
+    /// once on_complete() was called. Off by default.
+    pub fsync_on_complete: bool,
+    /// Read and decrypt video packets on a separate thread while the muxer writes, so reading
+    /// and writing overlap. Up to 64 packets and 16 MiB of them are queued between the two, or
+    /// what resource_budget allows for buffered bytes. Off by default.
+    pub pipelined: bool,
+    /// The container decrypted videos are written to.
+    pub container: VideoContainer,
+    /// With VideoContainer::RawPackets, also write `<output basename>.index.json`, listing the
+    /// type byte, PTS, data length and offset in the output of every packet. Off by default.
+    pub raw_packet_index: bool,
+    /// What writes decrypted videos, FFmpeg if the video feature is enabled.
+    pub video_backend: VideoBackend,
+    /// Write videos as fragmented MP4, or Matroska without cues, so the output is written front
+    /// to back and never seeked. Can't be combined with faststart.
+    pub fragmented: bool,
+    /// Decrypt and check the file without writing anything: video packets are parsed but not
+    /// muxed, images are read to the end. on_verified() gets what was found.
+    pub verify_only: bool,
+    /// Hash every output file while it is written and report the digest through
+    /// on_output_digest(). Videos written as plain MP4 are read once more for this, since the
+    /// muxer goes back to finish the file; fragmented output is hashed on the way.
+    pub output_digest: Option<HashAlgo>,
+    /// Read the box structure of MP4 and M4A outputs back once they are written: an ftyp box
+    /// first, a moov box with a track for every stream and a duration, and media data. Catches
+    /// outputs the muxer broke without reporting an error, which only show when they don't
+    /// play. The job fails with error::Error::CorruptOutput if the check fails, the output is
+    /// left as it is. On by default.
+    pub check_output: bool,
+    /// Report what check_output finds through on_warning() instead of failing the job.
+    pub lenient: bool,
+    /// Write the file's metadata to `<output basename>.json` next to the output, together with
+    /// the file type, header version and recipients, and the digest of the output with
+    /// output_digest. The sidecar only appears once the output is complete.
+    pub write_metadata_sidecar: bool,
+    /// Re-encode the video stream instead of copying it, see TranscodeSpec. Needs the transcode
+    /// feature, decrypting fails without it. Progress is still reported in input bytes.
+    pub transcode: Option<TranscodeSpec>,
+    /// Decode the first frame of videos into a thumbnail, see ThumbnailSpec.
+    #[cfg(feature = "thumbnail")]
+    pub extract_thumbnail: Option<ThumbnailSpec>,
+    /// What to do when the output file already exists. Replaced by default.
+    pub overwrite: Overwrite,
+    /// Subdirectories of the output directory to sort files into, none by default.
+    pub subdirectory_strategy: SubdirectoryStrategy,
+    /// How output files are named, after their timestamp by default.
+    pub naming: OutputNaming,
+    /// Stem of the encrypted file's name for the {source_stem} placeholder of name templates.
+    /// decrypt() only gets the open file and can't know it.
+    pub source_stem: Option<String>,
+    /// Video packets longer than this are considered corrupt, see PacketReader::max_packet_len().
+    pub max_packet_len: usize,
+    /// Write a job manifest to `<output name>.resume` next to the output while it is written,
+    /// so an interrupted job can be continued with resume_from. It is removed once the output
+    /// is complete. Only images and fragmented videos and audio get one.
+    pub resumable: bool,
+    /// Before a job creates its output, make sure the output directory has room for the
+    /// estimated output size, see estimate_output_size(), plus a margin, twice that with
+    /// faststart. Fails with error::Error::InsufficientSpace otherwise. The check happens when
+    /// the job runs, so each job of a batch sees the space the ones before it used up. Only
+    /// done on Unix and when the input size is known, off by default.
+    pub check_free_space: bool,
+    /// Continue the partial output a resumable job left behind at this path instead of creating
+    /// a new file, naming and overwrite options don't apply then. The file is decrypted from the
+    /// start again, but the part that was already written is only read and compared, not
+    /// written again. Fails with error::Error::PartialOutputMismatch if the manifest is missing
+    /// or the partial output belongs to another file or other options.
+    pub resume_from: Option<PathBuf>,
+}
+
+impl Default for DecryptOptions {
+    fn default() -> Self {
+        DecryptOptions {
+            faststart: false,
+            preserve_timestamps: true,
+            write_exif: true,
+            strip_location: false,
+            batch_names: None,
+            resource_budget: None,
+            image_format_mismatch: ImageFormatMismatch::CorrectExtension,
+            finalize_on_truncation: true,
+            resync_on_error: false,
+            recover_raw_on_bad_metadata: false,
+            non_monotonic_pts: NonMonotonicPts::Bump,
+            read_buffer_size: 256 << 10,
+            input_buffer_size: 8 << 10,
+            write_buffer_size: 0,
+            fsync_on_complete: false,
+            pipelined: false,
+            container: VideoContainer::Auto,
+            raw_packet_index: false,
+            video_backend: VideoBackend::default(),
+            fragmented: false,
+            verify_only: false,
+            output_digest: None,
+            check_output: true,
+            lenient: false,
+            write_metadata_sidecar: false,
+            transcode: None,
+            #[cfg(feature = "thumbnail")]
+            extract_thumbnail: None,
+            overwrite: Overwrite::Replace,
+            subdirectory_strategy: SubdirectoryStrategy::Flat,
+            naming: OutputNaming::Timestamp,
+            source_stem: None,
+            max_packet_len: MAX_PACKET_LEN,
+            resumable: false,
+            check_free_space: false,
+            resume_from: None,
+        }
+    }
+}
+
+// setters for the fields above, see there
+impl DecryptOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn faststart(mut self, faststart: bool) -> Self {
+        self.faststart = faststart;
+        self
+    }
+
+    pub fn preserve_timestamps(mut self, preserve_timestamps: bool) -> Self {
+        self.preserve_timestamps = preserve_timestamps;
+        self
+    }
+
+    pub fn write_exif(mut self, write_exif: bool) -> Self {
+        self.write_exif = write_exif;
+        self
+    }
+
+    pub fn strip_location(mut self, strip_location: bool) -> Self {
+        self.strip_location = strip_location;
+        self
+    }
+
+    pub fn batch_names(mut self, batch_names: BatchNames) -> Self {
+        self.batch_names = Some(batch_names);
+        self
+    }
+
+    pub fn resource_budget(mut self, resource_budget: ResourceBudget) -> Self {
+        self.resource_budget = Some(resource_budget);
+        self
+    }
+
+    pub fn image_format_mismatch(mut self, image_format_mismatch: ImageFormatMismatch) -> Self {
+        self.image_format_mismatch = image_format_mismatch;
+        self
+    }
+
+    pub fn finalize_on_truncation(mut self, finalize_on_truncation: bool) -> Self {
+        self.finalize_on_truncation = finalize_on_truncation;
+        self
+    }
+
+    pub fn resync_on_error(mut self, resync_on_error: bool) -> Self {
+        self.resync_on_error = resync_on_error;
+        self
+    }
+
+    pub fn recover_raw_on_bad_metadata(mut self, recover_raw_on_bad_metadata: bool) -> Self {
+        self.recover_raw_on_bad_metadata = recover_raw_on_bad_metadata;
+        self
+    }
+
+    pub fn non_monotonic_pts(mut self, non_monotonic_pts: NonMonotonicPts) -> Self {
+        self.non_monotonic_pts = non_monotonic_pts;
+        self
+    }
+
+    pub fn read_buffer_size(mut self, read_buffer_size: usize) -> Self {
+        self.read_buffer_size = read_buffer_size;
+        self
+    }
+
+    pub fn input_buffer_size(mut self, input_buffer_size: usize) -> Self {
+        self.input_buffer_size = input_buffer_size;
+        self
+    }
+
+    pub fn write_buffer_size(mut self, write_buffer_size: usize) -> Self {
+        self.write_buffer_size = write_buffer_size;
+        self
+    }
+
+    pub fn fsync_on_complete(mut self, fsync_on_complete: bool) -> Self {
+        self.fsync_on_complete = fsync_on_complete;
+        self
+    }
+
+    pub fn pipelined(mut self, pipelined: bool) -> Self {
+        self.pipelined = pipelined;
+        self
+    }
+
+    pub fn container(mut self, container: VideoContainer) -> Self {
+        self.container = container;
+        self
+    }
+
+    pub fn raw_packet_index(mut self, raw_packet_index: bool) -> Self {
+        self.raw_packet_index = raw_packet_index;
+        self
+    }
+
+    pub fn video_backend(mut self, video_backend: VideoBackend) -> Self {
+        self.video_backend = video_backend;
+        self
+    }
+
+    pub fn fragmented(mut self, fragmented: bool) -> Self {
+        self.fragmented = fragmented;
+        self
+    }
+
+    pub fn verify_only(mut self, verify_only: bool) -> Self {
+        self.verify_only = verify_only;
+        self
+    }
+
+    pub fn output_digest(mut self, algo: HashAlgo) -> Self {
+        self.output_digest = Some(algo);
+        self
+    }
+
+    pub fn check_output(mut self, check_output: bool) -> Self {
+        self.check_output = check_output;
+        self
+    }
+
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    pub fn write_metadata_sidecar(mut self, write_metadata_sidecar: bool) -> Self {
+        self.write_metadata_sidecar = write_metadata_sidecar;
+        self
+    }
+
+    pub fn transcode(mut self, spec: TranscodeSpec) -> Self {
+        self.transcode = Some(spec);
+        self
+    }
+
+    #[cfg(feature = "thumbnail")]
+    pub fn extract_thumbnail(mut self, spec: ThumbnailSpec) -> Self {
+        self.extract_thumbnail = Some(spec);
+        self
+    }
+
+    pub fn overwrite(mut self, overwrite: Overwrite) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    pub fn subdirectory_strategy(mut self, subdirectory_strategy: SubdirectoryStrategy) -> Self {
+        self.subdirectory_strategy = subdirectory_strategy;
+        self
+    }
+
+    pub fn naming(mut self, naming: OutputNaming) -> Self {
+        self.naming = naming;
+        self
+    }
+
+    pub fn source_stem(mut self, source_stem: impl Into<String>) -> Self {
+        self.source_stem = Some(source_stem.into());
+        self
+    }
+
+    pub fn max_packet_len(mut self, max_packet_len: usize) -> Self {
+        self.max_packet_len = max_packet_len;
+        self
+    }
+
+    pub fn resumable(mut self, resumable: bool) -> Self {
+        self.resumable = resumable;
+        self
+    }
+
+    pub fn check_free_space(mut self, check_free_space: bool) -> Self {
+        self.check_free_space = check_free_space;
+        self
+    }
+
+    /// Also makes the continued job resumable, in case it is interrupted again.
+    pub fn resume_from(mut self, existing_partial_output: PathBuf) -> Self {
+        self.resume_from = Some(existing_partial_output);
+        self.resumable = true;
+        self
+    }
+
+    /// Rejects combinations that can't work, before anything is decrypted.
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.faststart && self.container == VideoContainer::Mkv {
+            bail!("faststart only applies to MP4, not to Matroska output");
+        }
+        if self.faststart && self.container == VideoContainer::RawPackets {
+            bail!("faststart only applies to MP4, not to raw packet output");
+        }
+        if self.raw_packet_index && self.container != VideoContainer::RawPackets {
+            bail!("The packet index is only written with VideoContainer::RawPackets");
+        }
+        if self.faststart && self.fragmented {
+            bail!("faststart can't be combined with fragmented output");
+        }
+        if (self.resumable || self.resume_from.is_some()) && self.faststart {
+            bail!("faststart rewrites the whole output at the end, it can't be resumed");
+        }
+        if self.resume_from.is_some() && self.verify_only {
+            bail!("Verifying doesn't write anything that could be resumed");
+        }
+        self.subdirectory_strategy.validate()?;
+        if let OutputNaming::Template(template) = &self.naming {
+            if template.uses_source_stem() && self.source_stem.is_none() {
+                bail!("The output name template uses {{source_stem}}, but no source stem is set");
+            }
         }
+        if self.read_buffer_size == 0 || self.input_buffer_size == 0 {
+            bail!("The read buffer sizes must not be 0");
+        }
+        if self.max_packet_len == 0 {
+            bail!("The maximum packet length must not be 0");
+        }
+        if cfg!(not(feature = "transcode")) && self.transcode.is_some() {
+            bail!("Transcoding needs libcryptocam to be built with the transcode feature");
+        }
+        // without either backend, videos fail with VideoSupportNotCompiled instead
+        let any_backend = cfg!(any(feature = "video", feature = "rust-mp4"));
+        match self.video_backend {
+            VideoBackend::FFmpeg if any_backend && cfg!(not(feature = "video")) => {
+                bail!("The FFmpeg backend needs libcryptocam to be built with the video feature")
+            }
+            VideoBackend::RustMp4 if any_backend && cfg!(not(feature = "rust-mp4")) => {
+                bail!(
+                    "The rust-mp4 backend needs libcryptocam to be built with the rust-mp4 feature"
+                )
+            }
+            VideoBackend::RustMp4 => {
+                if self.container == VideoContainer::Mkv {
+                    bail!("The rust-mp4 backend only writes MP4");
+                }
+                if self.fragmented {
+                    bail!("The rust-mp4 backend can't write fragmented output");
+                }
+                if self.transcode.is_some() {
+                    bail!("Transcoding needs the FFmpeg backend");
+                }
+                #[cfg(feature = "thumbnail")]
+                if self.extract_thumbnail.is_some() {
+                    bail!("Thumbnails need the FFmpeg backend");
+                }
+            }
+            VideoBackend::FFmpeg => {}
+        }
+        Ok(())
     }
 }
 
+fn remaining_len(reader: &mut dyn Seek) -> io::Result<u64> {
+    let position = reader.stream_position()?;
+    let end = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(position))?;
+    Ok(end.saturating_sub(position))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormatMismatch {
+    /// Name the output after the format the contents were recognized as.
+    CorrectExtension,
+    /// Keep the declared format as the extension.
+    Warn,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoContainer {
+    /// MP4 for AAC audio, Matroska for Opus.
+    Auto,
+    /// Fails for Opus audio.
+    Mp4,
+    Mkv,
+    /// The decrypted packet stream as it is, 13 byte headers included, see packet.rs. Nothing
+    /// is muxed, so this works with either backend, e.g. for debugging a recording that doesn't
+    /// mux. See DecryptOptions::raw_packet_index. Audio recordings are written as with Auto.
+    RawPackets,
+}
+
+/// The muxer that writes decrypted videos.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoBackend {
+    /// Needs the video feature.
+    FFmpeg,
+    /// A pure Rust MP4 muxer, needs the rust-mp4 feature. Only writes plain MP4 with H.264 video
+    /// and AAC audio, and doesn't transcode or extract thumbnails.
+    RustMp4,
+}
+
+impl Default for VideoBackend {
+    fn default() -> Self {
+        if cfg!(feature = "video") {
+            VideoBackend::FFmpeg
+        } else {
+            VideoBackend::RustMp4
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonMonotonicPts {
+    /// Move the packet to just after the previous one.
+    Bump,
+    /// Leave the packet out.
+    Drop,
+}
+
+/// What was written by a job, passed to ProgressCallback::on_stats().
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecryptStats {
+    Video(VideoStats),
+    Image(ImageStats),
+    Audio(AudioStats),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VideoStats {
+    /// From the first to the last video packet's PTS, after fixing up non-monotonic PTS.
+    pub duration_us: u64,
+    pub video_packets: u64,
+    /// 0 for videos without audio.
+    pub audio_packets: u64,
+    pub video_bytes: u64,
+    pub audio_bytes: u64,
+    /// Length of the largest video packet.
+    pub max_video_packet: u64,
+    /// From the metadata.
+    pub width: usize,
+    pub height: usize,
+    /// From the metadata, or estimated from the PTS of the first video packets if it has none.
+    /// None if there weren't enough video packets for an estimate.
+    pub frame_rate: Option<FrameRate>,
+}
+
+/// A frame rate as a fraction, `num` frames per `den` seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameRate {
+    pub num: u32,
+    pub den: u32,
+}
+
+impl FrameRate {
+    /// The fraction closest to `rate` in thousandths, None unless it is above 0 and at most
+    /// 1000 frames per second.
+    pub fn from_f64(rate: f64) -> Option<FrameRate> {
+        if !rate.is_finite() || rate <= 0.0 || rate > 1000.0 {
+            return None;
+        }
+        let num = (rate * 1000.0).round() as u32;
+        if num == 0 {
+            return None;
+        }
+        let mut gcd = (num, 1000);
+        while gcd.1 != 0 {
+            gcd = (gcd.1, gcd.0 % gcd.1);
+        }
+        Some(FrameRate {
+            num: num / gcd.0,
+            den: 1000 / gcd.0,
+        })
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        f64::from(self.num) / f64::from(self.den)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageStats {
+    /// Size of the written image, including EXIF data added to it.
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AudioStats {
+    /// From the first to the last audio packet's PTS, after fixing up non-monotonic PTS.
+    pub duration_us: u64,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// How a job ended, see DecryptingJob::run_with_token().
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobResult {
+    Complete { output: Option<PathBuf> },
+    Failed(String),
+    Cancelled,
+}
+
 pub trait DecryptingJob {
+    /// Stops soon after `cancel` is set, without calling on_complete() or on_error(). A
+    /// cancelled job removes its partial output unless DecryptOptions::resumable is set.
     fn run(&mut self, progress_callback: Box<&mut dyn ProgressCallback>, cancel: Arc<AtomicBool>);
+    /// Like run(), returning how the job ended. Sets the token's cancelled_ack() once the job
+    /// has returned.
+    #[allow(clippy::boxed_local)]
+    fn run_with_token(
+        &mut self,
+        progress_callback: Box<&mut dyn ProgressCallback>,
+        cancel: &CancellationToken,
+    ) -> JobResult {
+        let mut outcome = OutcomeRecorder::new(*progress_callback);
+        self.run(Box::new(&mut outcome), cancel.flag());
+        let result = match outcome.error {
+            Some(e) => JobResult::Failed(e),
+            None if outcome.complete => JobResult::Complete {
+                output: self.output_path().map(Path::to_path_buf),
+            },
+            None if cancel.is_cancelled() => JobResult::Cancelled,
+            None => JobResult::Failed("Decryption stopped without an error".to_owned()),
+        };
+        cancel.cancelled_ack().set(result == JobResult::Cancelled);
+        result
+    }
+    /// The file written by run(), once it completed.
+    fn output_path(&self) -> Option<&Path> {
+        None
+    }
+    /// The keyring identity that decrypted the input, known as soon as the job is built.
+    /// None for files encrypted with a passphrase.
+    fn identity(&self) -> Option<&IdentityInfo> {
+        None
+    }
+    /// How large the output will be, see estimate_output_size(). Jobs from decrypt() and the
+    /// like know it as soon as they are built, unless the input size isn't known, as with
+    /// decrypt_stream(). 0 with DecryptOptions::verify_only.
+    fn estimated_output_size(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// A job along with the identity its input was decrypted with. Reports the identity to
+/// on_identity_matched() before running the job.
+pub(crate) struct MatchedJob {
+    pub job: Box<dyn DecryptingJob + Send>,
+    pub identity: Option<IdentityInfo>,
+    /// Unless the job has its own estimate.
+    pub estimated_output_size: Option<u64>,
+    /// The output directory and the bytes that need to be free there, see
+    /// DecryptOptions::check_free_space.
+    pub free_space_check: Option<(PathBuf, u64)>,
+}
+
+impl DecryptingJob for MatchedJob {
+    fn run(&mut self, progress_callback: Box<&mut dyn ProgressCallback>, cancel: Arc<AtomicBool>) {
+        if let Some(identity) = &self.identity {
+            progress_callback.on_identity_matched(identity);
+        }
+        if let Some((out_dir, needed)) = &self.free_space_check {
+            if let Err(e) = check_free_space(out_dir, *needed, available_space) {
+                progress_callback.on_error(e.into());
+                return;
+            }
+        }
+        self.job.run(progress_callback, cancel)
+    }
+
+    fn output_path(&self) -> Option<&Path> {
+        self.job.output_path()
+    }
+
+    fn identity(&self) -> Option<&IdentityInfo> {
+        self.identity.as_ref()
+    }
+
+    fn estimated_output_size(&self) -> Option<u64> {
+        self.job
+            .estimated_output_size()
+            .or(self.estimated_output_size)
+    }
 }
 
 pub trait ProgressCallback {
+    /// 0 if the size isn't known, see decrypt_stream().
     fn set_total_file_size(&mut self, n: u64);
-    // bytes in the headers before actual data, these have to be added to processed_bytes to calculate progress
-    fn set_offset(&mut self, offset: u64);
+    /// No longer called, on_progress() counts the headers itself.
+    #[deprecated(note = "on_progress() counts from the start of the file")]
+    fn set_offset(&mut self, _offset: u64) {}
+    /// The keyring identity that decrypted the file, called before anything else. Not called
+    /// for files encrypted with a passphrase.
+    fn on_identity_matched(&mut self, _identity: &IdentityInfo) {}
+    /// Bytes of the encrypted file read so far, see InputPosition. The last call reports the
+    /// total file size, unless the file is truncated or failed to decrypt.
     fn on_progress(&mut self, processed_bytes: u64);
+    /// Packets and bytes of `stream` written so far and the PTS of its last packet, counted
+    /// from the first packet of the recording. Called along with on_progress() for each packet
+    /// of videos and audio recordings, so audio that stops while the video goes on shows up
+    /// as a `last_pts_us` falling behind.
+    fn on_stream_progress(
+        &mut self,
+        _stream: PacketKind,
+        _packets: u64,
+        _bytes: u64,
+        _last_pts_us: u64,
+    ) {
+    }
     fn on_complete(&mut self);
     fn on_error(&mut self, error: Box<dyn Error>);
+    /// Something is off with the file, but decryption goes on. Warnings are logged as well.
+    fn on_warning(&mut self, _warning: DecryptWarning) {}
+    /// The input ended early and the output only contains what was read before, up to
+    /// `processed_bytes` of the file, counted like on_progress(). Called before on_complete(),
+    /// see DecryptOptions::finalize_on_truncation.
+    fn on_truncated(&mut self, _processed_bytes: u64) {}
+    /// The output file was created at `path`, which already accounts for
+    /// DecryptOptions::overwrite. Called before any data is written.
+    fn on_output_created(&mut self, _path: &Path) {}
+    /// Digest of the finished output file, see DecryptOptions::output_digest. Called before
+    /// on_complete().
+    fn on_output_digest(&mut self, _digest: &HashDigest) {}
+    /// What was written, called before on_complete(). Not called when verifying.
+    fn on_stats(&mut self, _stats: &DecryptStats) {}
+    /// Called before on_complete() when verifying, see DecryptOptions::verify_only.
+    fn on_verified(&mut self, _report: &VerificationReport) {}
+    /// The encoded thumbnail of a video, see DecryptOptions::extract_thumbnail.
+    #[cfg(feature = "thumbnail")]
+    fn on_thumbnail(&mut self, _data: &[u8], _format: ThumbnailFormat) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        error::Error,
+        fixtures::{encrypt_raw, test_keyring, FixtureFile, FixtureVideo},
+    };
+    use std::io::Cursor;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n not really a png";
+
+    fn image_metadata() -> Vec<u8> {
+        br#"{"timestamp":"2021-06-01T12:00:00Z","format":"png"}"#.to_vec()
+    }
+
+    /// The decrypted payload of an image whose encrypted header has `offset_to_data`,
+    /// followed by `rest`.
+    fn hostile_image(offset_to_data: u32, rest: &[u8]) -> Vec<u8> {
+        let mut decrypted = vec![FILE_TYPE_IMAGE];
+        decrypted.extend_from_slice(&offset_to_data.to_le_bytes());
+        decrypted.extend_from_slice(rest);
+        encrypt_raw(&decrypted)
+    }
+
+    fn typed_error(result: Result<(MediaInfo, Vec<u8>)>) -> Error {
+        result
+            .err()
+            .expect("decrypting fails")
+            .downcast::<Error>()
+            .expect("the error is typed")
+    }
+
+    #[test]
+    fn decrypts_an_image_to_memory() {
+        let file = FixtureFile::image(image_metadata(), PNG).build();
+        let (info, image) =
+            decrypt_to_vec(Cursor::new(file), &mut test_keyring(), 1 << 20).unwrap();
+        assert_eq!(image, PNG);
+        assert_eq!(info.media_type, "image");
+    }
+
+    #[test]
+    fn images_over_the_limit_are_too_large_for_memory() {
+        let mut png = PNG.to_vec();
+        png.resize(200_000, 0);
+        let file = FixtureFile::image(image_metadata(), png.clone()).build();
+        let (_, image) =
+            decrypt_to_vec(Cursor::new(file.clone()), &mut test_keyring(), png.len()).unwrap();
+        assert_eq!(image, png);
+
+        let limit = png.len() - 1;
+        let error = typed_error(decrypt_to_vec(
+            Cursor::new(file),
+            &mut test_keyring(),
+            limit,
+        ));
+        assert!(
+            matches!(error, Error::TooLargeForMemory { limit: l } if l == limit),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn videos_and_audio_need_the_streaming_api() {
+        let video = FixtureFile::video(
+            r#"{"width":640,"height":480,"video_bitrate":1000000,"audio_sample_rate":44100,"audio_channel_count":1,"audio_bitrate":64000,"timestamp":"2021-06-01T12:00:00Z"}"#,
+            FixtureVideo::new().h264_frames(2, 64),
+        );
+        let audio = FixtureFile::audio(
+            r#"{"audio_sample_rate":44100,"audio_channel_count":1,"audio_bitrate":64000,"timestamp":"2021-06-01T12:00:00Z"}"#,
+            FixtureVideo::new().audio_packet(0, b"\xff\xf1"),
+        );
+        for file in [video, audio] {
+            let error = typed_error(decrypt_to_vec(
+                Cursor::new(file.build()),
+                &mut test_keyring(),
+                1 << 20,
+            ));
+            assert!(matches!(error, Error::UseStreamingApi), "{:?}", error);
+        }
+    }
+
+    /// A reader that is neither Seek nor a File, like a stream in the browser.
+    struct ChunkedReader<'a>(&'a [u8]);
+
+    impl Read for ChunkedReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(1000);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn decrypts_images_from_readers_to_writers() {
+        let file = FixtureFile::image(image_metadata(), PNG).build();
+        let mut out = Vec::new();
+        let image = decrypt_image_to_writer(
+            ChunkedReader(&file),
+            &mut test_keyring(),
+            &mut out,
+            DecryptOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(out, PNG);
+        assert_eq!(image.extension, "png");
+        assert_eq!(image.metadata.timestamp, "2021-06-01T12:00:00Z");
+        assert!(image.warnings.is_empty(), "{:?}", image.warnings);
+
+        let video = FixtureFile::video(image_metadata(), FixtureVideo::new()).build();
+        let mut out = Vec::new();
+        assert!(decrypt_image_to_writer(
+            ChunkedReader(&video),
+            &mut test_keyring(),
+            &mut out,
+            DecryptOptions::default(),
+        )
+        .is_err());
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn huge_metadata_length_fails_without_allocating() {
+        let file = hostile_image(u32::MAX, b"{}");
+        let error = typed_error(decrypt_to_vec(
+            Cursor::new(file),
+            &mut test_keyring(),
+            1 << 20,
+        ));
+        assert!(
+            matches!(error, Error::MetadataTooLarge { len, limit }
+                if len == u32::MAX as u64 - 5 && limit == MAX_METADATA_LEN as u64),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn metadata_just_over_the_limit_fails() {
+        let file = hostile_image(MAX_METADATA_LEN as u32 + 6, b"{}");
+        let error = typed_error(decrypt_to_vec(
+            Cursor::new(file),
+            &mut test_keyring(),
+            1 << 20,
+        ));
+        assert!(
+            matches!(error, Error::MetadataTooLarge { .. }),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn metadata_length_beyond_the_payload_fails() {
+        let file = hostile_image(5 + 1000, b"{}");
+        assert!(decrypt_to_vec(Cursor::new(file), &mut test_keyring(), 1 << 20).is_err());
+    }
+
+    #[test]
+    fn offset_to_data_inside_the_encrypted_header_fails() {
+        let file = hostile_image(3, b"{}");
+        assert!(decrypt_to_vec(Cursor::new(file), &mut test_keyring(), 1 << 20).is_err());
+    }
+
+    #[test]
+    fn metadata_larger_than_the_budget_fails() {
+        let file = FixtureFile::image(image_metadata(), PNG).build();
+        let options =
+            DecryptOptions::default().resource_budget(ResourceBudget::new(10, 1 << 20, 4));
+        let error =
+            decrypt_image_to_writer(Cursor::new(file), &mut test_keyring(), Vec::new(), options)
+                .err()
+                .expect("decrypting fails")
+                .downcast::<Error>()
+                .expect("the error is typed");
+        assert!(
+            matches!(error, Error::ResourceBudgetExceeded { requested, limit: 10, .. }
+                if requested == image_metadata().len() as u64),
+            "{:?}",
+            error
+        );
+    }
 }
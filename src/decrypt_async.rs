@@ -0,0 +1,340 @@
+//! Running decryption jobs from async code, built with the async feature.
+
+use crate::{
+    decrypt::{
+        decrypt_with_options, CancellationToken, DecryptOptions, DecryptStats, DecryptWarning,
+        JobResult, ProgressCallback,
+    },
+    hash::HashDigest,
+    keyring::{IdentityInfo, Keyring},
+};
+use anyhow::Result;
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fs::File,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    runtime::Handle,
+    sync::mpsc::{self, error::TrySendError},
+    task::{self, JoinHandle},
+};
+
+/// Events that don't fit into the channel wait for room. Progress events are merged while
+/// waiting, so a slow consumer never holds up the job.
+const EVENT_CHANNEL_CAPACITY: usize = 64;
+/// Warnings waiting for room beyond this are dropped, and counted in JobEvent::WarningsDropped.
+const MAX_PENDING_WARNINGS: usize = 256;
+
+/// What a ProgressCallback is told while a job runs, see decrypt_async().
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobEvent {
+    /// Bytes of the input processed so far, including the headers, and its total size,
+    /// 0 if unknown.
+    Progress {
+        processed: u64,
+        total: u64,
+    },
+    /// The keyring identity that decrypted the file, before any other event.
+    IdentityMatched(IdentityInfo),
+    Warning(DecryptWarning),
+    /// `count` warnings were left out here because the stream wasn't read fast enough.
+    WarningsDropped {
+        count: u64,
+    },
+    Truncated {
+        processed: u64,
+    },
+    OutputCreated(PathBuf),
+    OutputDigest(HashDigest),
+    Stats(DecryptStats),
+    Error(String),
+    Complete,
+}
+
+/// The events of a job started by decrypt_async(). Dropping the stream cancels the job.
+pub struct ProgressStream {
+    events: mpsc::Receiver<JobEvent>,
+    cancel: CancellationToken,
+}
+
+impl ProgressStream {
+    /// The next event, None once the job has ended and all events were received.
+    pub async fn next(&mut self) -> Option<JobEvent> {
+        self.events.recv().await
+    }
+
+    /// For cancelling the job while the stream is used elsewhere.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+}
+
+impl Drop for ProgressStream {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+/// Like decrypt_with_options(), running the job on tokio's blocking thread pool. The header is
+/// read and the key unlocked before this returns, so the keyring's PassphraseProvider may be
+/// asked on the calling thread. Must be called from within a tokio runtime.
+pub fn decrypt_async(
+    file: File,
+    keyring: &mut Keyring,
+    out_path: PathBuf,
+    options: DecryptOptions,
+) -> Result<(JoinHandle<JobResult>, ProgressStream)> {
+    let mut job = decrypt_with_options(file, keyring, out_path, options)?;
+    let (sender, receiver) = mpsc::channel(EVENT_CHANNEL_CAPACITY);
+    let cancel = CancellationToken::new();
+    let job_cancel = cancel.clone();
+    let runtime = Handle::current();
+    let handle = task::spawn_blocking(move || {
+        let mut events = EventSender::new(sender);
+        let result = job.run_with_token(Box::new(&mut events), &job_cancel);
+        // the job is done, whether or not anyone reads the rest
+        runtime.spawn(events.flush());
+        result
+    });
+    Ok((
+        handle,
+        ProgressStream {
+            events: receiver,
+            cancel,
+        },
+    ))
+}
+
+struct EventSender {
+    sender: mpsc::Sender<JobEvent>,
+    /// Events other than progress waiting for room in the channel, in order.
+    pending: VecDeque<JobEvent>,
+    /// The latest progress, sent once `pending` is through.
+    progress: Option<JobEvent>,
+    /// Warnings dropped since the last WarningsDropped.
+    dropped_warnings: u64,
+    total: u64,
+}
+
+impl EventSender {
+    fn new(sender: mpsc::Sender<JobEvent>) -> Self {
+        EventSender {
+            sender,
+            pending: VecDeque::new(),
+            progress: None,
+            dropped_warnings: 0,
+            total: 0,
+        }
+    }
+
+    fn send(&mut self, event: JobEvent) {
+        match event {
+            JobEvent::Progress { .. } => self.progress = Some(event),
+            JobEvent::Warning(_) if self.pending_warnings() >= MAX_PENDING_WARNINGS => {
+                self.dropped_warnings += 1
+            }
+            event => {
+                self.queue_dropped_warnings();
+                if matches!(event, JobEvent::Complete | JobEvent::Error(_)) {
+                    // the last progress goes before the end of the job
+                    self.queue_progress();
+                }
+                self.pending.push_back(event);
+            }
+        }
+        self.try_flush();
+    }
+
+    fn pending_warnings(&self) -> usize {
+        self.pending
+            .iter()
+            .filter(|event| matches!(event, JobEvent::Warning(_)))
+            .count()
+    }
+
+    fn queue_progress(&mut self) {
+        if let Some(progress) = self.progress.take() {
+            self.pending.push_back(progress);
+        }
+    }
+
+    fn queue_dropped_warnings(&mut self) {
+        if self.dropped_warnings > 0 {
+            self.pending.push_back(JobEvent::WarningsDropped {
+                count: self.dropped_warnings,
+            });
+            self.dropped_warnings = 0;
+        }
+    }
+
+    /// Sends what fits into the channel without waiting.
+    fn try_flush(&mut self) {
+        loop {
+            let from_pending = !self.pending.is_empty();
+            let event = match self.pending.pop_front().or_else(|| self.progress.take()) {
+                Some(event) => event,
+                None => return,
+            };
+            match self.sender.try_send(event) {
+                Ok(()) => {}
+                Err(TrySendError::Full(event)) => {
+                    if from_pending {
+                        self.pending.push_front(event);
+                    } else {
+                        self.progress = Some(event);
+                    }
+                    return;
+                }
+                // the stream was dropped, which also cancels the job
+                Err(TrySendError::Closed(_)) => {
+                    self.pending.clear();
+                    self.progress = None;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Sends the remaining events once the job is done, waiting for room.
+    async fn flush(mut self) {
+        self.queue_dropped_warnings();
+        self.queue_progress();
+        for event in self.pending.drain(..) {
+            if self.sender.send(event).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl ProgressCallback for EventSender {
+    fn set_total_file_size(&mut self, n: u64) {
+        self.total = n;
+    }
+
+    fn on_identity_matched(&mut self, identity: &IdentityInfo) {
+        self.send(JobEvent::IdentityMatched(identity.clone()));
+    }
+
+    fn on_progress(&mut self, processed_bytes: u64) {
+        self.send(JobEvent::Progress {
+            processed: processed_bytes,
+            total: self.total,
+        });
+    }
+
+    fn on_complete(&mut self) {
+        self.send(JobEvent::Complete);
+    }
+
+    fn on_error(&mut self, error: Box<dyn Error>) {
+        self.send(JobEvent::Error(error.to_string()));
+    }
+
+    fn on_warning(&mut self, warning: DecryptWarning) {
+        self.send(JobEvent::Warning(warning));
+    }
+
+    fn on_truncated(&mut self, processed_bytes: u64) {
+        self.send(JobEvent::Truncated {
+            processed: processed_bytes,
+        });
+    }
+
+    fn on_output_created(&mut self, path: &Path) {
+        self.send(JobEvent::OutputCreated(path.to_path_buf()));
+    }
+
+    fn on_output_digest(&mut self, digest: &HashDigest) {
+        self.send(JobEvent::OutputDigest(digest.clone()));
+    }
+
+    fn on_stats(&mut self, stats: &DecryptStats) {
+        self.send(JobEvent::Stats(stats.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{test_keyring, FixtureFile};
+
+    fn warning(n: u8) -> DecryptWarning {
+        DecryptWarning::UnknownPacketTypeSkipped(n)
+    }
+
+    #[tokio::test]
+    async fn events_stay_bounded_while_nothing_is_read() {
+        let (sender, mut receiver) = mpsc::channel(EVENT_CHANNEL_CAPACITY);
+        let mut events = EventSender::new(sender);
+        events.set_total_file_size(100_000);
+        for processed in 0..100_000 {
+            events.on_progress(processed);
+            events.on_warning(warning(processed as u8));
+        }
+        assert!(events.pending.len() <= MAX_PENDING_WARNINGS);
+        events.on_complete();
+        assert!(events.pending.len() <= MAX_PENDING_WARNINGS + 3);
+        tokio::spawn(events.flush());
+
+        let mut received = vec![];
+        while let Some(event) = receiver.recv().await {
+            received.push(event);
+        }
+        let warnings = received
+            .iter()
+            .filter(|event| matches!(event, JobEvent::Warning(_)))
+            .count() as u64;
+        let dropped: u64 = received
+            .iter()
+            .map(|event| match event {
+                JobEvent::WarningsDropped { count } => *count,
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(warnings + dropped, 100_000);
+        assert_eq!(
+            &received[received.len() - 2..],
+            [
+                JobEvent::Progress {
+                    processed: 99_999,
+                    total: 100_000
+                },
+                JobEvent::Complete
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn job_finishes_without_a_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("image");
+        let metadata = r#"{"timestamp":"2021-06-01T12:00:00Z","format":"png"}"#;
+        std::fs::write(
+            &input,
+            FixtureFile::image(metadata, &b"\x89PNG\r\n\x1a\n"[..]).build(),
+        )
+        .unwrap();
+        let (handle, mut stream) = decrypt_async(
+            File::open(&input).unwrap(),
+            &mut test_keyring(),
+            dir.path().to_path_buf(),
+            DecryptOptions::default(),
+        )
+        .unwrap();
+        let result = handle.await.unwrap();
+        assert!(matches!(result, JobResult::Complete { .. }));
+
+        let mut last = None;
+        while let Some(event) = stream.next().await {
+            last = Some(event);
+        }
+        assert_eq!(last, Some(JobEvent::Complete));
+    }
+}
@@ -0,0 +1,371 @@
+//! Audio recordings, file type 3. The payload is framed like a video's but only has audio
+//! packets, which go through the same packers with the video stream left out.
+
+#[cfg(feature = "video")]
+use crate::decrypt_video::FfmpegPacker;
+#[cfg(feature = "rust-mp4")]
+use crate::rust_mp4::RustMp4Packer;
+use crate::{
+    budget::Resource,
+    cancel::CancellableReader,
+    decrypt::{
+        DecryptOptions, DecryptStats, DecryptingJob, ProgressCallback, VideoBackend, VideoContainer,
+    },
+    decrypt_video::{check_output, pack, AudioCodec, StatsCollector, VideoMetadata},
+    error::{job_error, Error},
+    output_path::{
+        batch_name_warning, output_file_name, sync_output, MediaInfo, RemoveIfCancelled,
+    },
+    packet::PacketReader,
+    progress::InputPosition,
+    resume::{open_output, ResumeManifest},
+    sidecar::Sidecar,
+    timestamp,
+    verify::VideoVerifyJob,
+    warning,
+};
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    str,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn build_audio_decryption_job(
+    data: Box<dyn Read + Send>,
+    metadata: &[u8],
+    out_path: PathBuf,
+    total_file_size: u64,
+    input_position: InputPosition,
+    sidecar: Option<Sidecar>,
+    resume_manifest: Option<ResumeManifest>,
+    device_label: Option<String>,
+    mut options: DecryptOptions,
+) -> Result<Box<dyn DecryptingJob + Send>> {
+    let metadata = parse_audio_metadata(str::from_utf8(metadata)?)?;
+    if options.verify_only {
+        return Ok(Box::new(VideoVerifyJob::new(
+            data,
+            total_file_size,
+            input_position,
+            options,
+        )));
+    }
+    // fail before anything is written, the job only sees Mp4 or Mkv
+    let (container, extension) = output_container(metadata.audio_codec, options.container)?;
+    options.container = container;
+    if options.video_backend == VideoBackend::RustMp4 && container != VideoContainer::Mp4 {
+        bail!("The rust-mp4 backend only writes M4A, Opus audio needs the FFmpeg backend");
+    }
+    if let Some(batch_names) = &options.batch_names {
+        batch_names.register(&metadata.timestamp);
+    }
+    Ok(Box::new(AudioMuxingJob {
+        params: AudioMuxingJobParams {
+            data,
+            metadata,
+            extension,
+            out_path,
+            total_file_size,
+            input_position,
+            sidecar,
+            resume_manifest,
+            device_label,
+            options,
+        },
+        output: None,
+    }))
+}
+
+/// The MediaInfo the job will name the output after, for batch::plan().
+pub(crate) fn planned_media_info(
+    metadata: &[u8],
+    device_label: Option<String>,
+    options: &DecryptOptions,
+) -> Result<MediaInfo> {
+    let metadata = parse_audio_metadata(str::from_utf8(metadata)?)?;
+    let (_, extension) = output_container(metadata.audio_codec, options.container)?;
+    Ok(MediaInfo {
+        timestamp: metadata.timestamp,
+        media_type: "audio",
+        codec: match metadata.audio_codec {
+            AudioCodec::Aac => "aac",
+            AudioCodec::Opus => "opus",
+        }
+        .to_owned(),
+        width: None,
+        height: None,
+        source_stem: options.source_stem.clone(),
+        device_label,
+        extra: metadata.extra,
+        extension: extension.to_owned(),
+    })
+}
+
+/// The metadata the app stores with an audio recording.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct AudioMetadata {
+    pub audio_sample_rate: u32,
+    pub audio_channel_count: u32,
+    /// FFmpeg channel layout name, e.g. "stereo" or "5.1". Without it, the usual layout for
+    /// the channel count is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_channel_layout: Option<String>,
+    pub audio_bitrate: u64,
+    #[serde(default)]
+    pub audio_codec: AudioCodec,
+    /// local time of the recording
+    pub timestamp: String,
+    /// Fields this version doesn't know, like those added by newer versions of the app.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl AudioMetadata {
+    /// The packers write videos, an audio recording is a video without the video stream.
+    fn as_video_metadata(&self) -> VideoMetadata {
+        let mut metadata = VideoMetadata::new(self.timestamp.clone(), 0, 0)
+            .audio_sample_rate(self.audio_sample_rate)
+            .audio_channel_count(self.audio_channel_count)
+            .audio_bitrate(self.audio_bitrate)
+            .audio_codec(self.audio_codec);
+        metadata.audio_channel_layout = self.audio_channel_layout.clone();
+        metadata
+    }
+}
+
+pub(crate) fn parse_audio_metadata(json: &str) -> Result<AudioMetadata> {
+    match serde_json::from_str(json) {
+        Ok(metadata) => Ok(metadata),
+        Err(e) => Err(Error::metadata_parse(e).into()),
+    }
+}
+
+/// The container and file extension for the audio codec. AAC goes to M4A, Opus to Ogg, or
+/// Matroska audio if Mkv is asked for.
+fn output_container(
+    audio_codec: AudioCodec,
+    requested: VideoContainer,
+) -> Result<(VideoContainer, &'static str)> {
+    Ok(match (requested, audio_codec) {
+        (VideoContainer::Mkv, _) => (VideoContainer::Mkv, "mka"),
+        (VideoContainer::Mp4, AudioCodec::Opus) => {
+            bail!("Opus audio can't be written as M4A, use VideoContainer::Mkv")
+        }
+        (_, AudioCodec::Aac) => (VideoContainer::Mp4, "m4a"),
+        (_, AudioCodec::Opus) => (VideoContainer::Mkv, "ogg"),
+    })
+}
+
+struct AudioMuxingJobParams {
+    data: Box<dyn Read + Send>,
+    metadata: AudioMetadata,
+    extension: &'static str,
+    out_path: PathBuf,
+    total_file_size: u64,
+    input_position: InputPosition,
+    sidecar: Option<Sidecar>,
+    resume_manifest: Option<ResumeManifest>,
+    device_label: Option<String>,
+    options: DecryptOptions,
+}
+
+struct AudioMuxingJob {
+    params: AudioMuxingJobParams,
+    output: Option<PathBuf>,
+}
+
+impl DecryptingJob for AudioMuxingJob {
+    fn run(&mut self, progress_callback: Box<&mut dyn ProgressCallback>, cancel: Arc<AtomicBool>) {
+        progress_callback.set_total_file_size(self.params.total_file_size);
+        let _payload_reservation = match &self.params.options.resource_budget {
+            None => None,
+            Some(budget) => match budget.reserve(Resource::PayloadBuffers, 1, Some(&cancel)) {
+                Ok(Some(reservation)) => Some(reservation),
+                Ok(None) => return,
+                Err(e) => {
+                    progress_callback.on_error(e.into());
+                    return;
+                }
+            },
+        };
+        mux_audio(
+            &mut self.params,
+            &mut self.output,
+            *progress_callback,
+            cancel,
+        )
+    }
+
+    fn output_path(&self) -> Option<&Path> {
+        self.output.as_deref()
+    }
+}
+
+fn mux_audio(
+    params: &mut AudioMuxingJobParams,
+    output: &mut Option<PathBuf>,
+    progress_callback: &mut dyn ProgressCallback,
+    cancel: Arc<AtomicBool>,
+) {
+    let metadata = &params.metadata;
+    let options = &params.options;
+    let out_path = &mut params.out_path;
+    let codec_name = match metadata.audio_codec {
+        AudioCodec::Aac => "aac",
+        AudioCodec::Opus => "opus",
+    };
+    let info = MediaInfo {
+        timestamp: metadata.timestamp.clone(),
+        media_type: "audio",
+        codec: codec_name.to_owned(),
+        width: None,
+        height: None,
+        source_stem: options.source_stem.clone(),
+        device_label: params.device_label.clone(),
+        extra: metadata.extra.clone(),
+        extension: params.extension.to_owned(),
+    };
+    let file_name = output_file_name(&info, &options.naming, options.batch_names.as_ref());
+    if let Some(warning) = batch_name_warning(&info, &options.naming, options.batch_names.as_ref())
+    {
+        warning::report(&mut *progress_callback, warning);
+    }
+    let (out, pending_manifest) = match open_output(
+        out_path,
+        &file_name,
+        &metadata.timestamp,
+        options,
+        params.resume_manifest.as_ref(),
+        options.fragmented,
+    ) {
+        Err(e) => {
+            progress_callback.on_error(job_error(e));
+            return;
+        }
+        Ok(opened) => opened,
+    };
+    let cleanup = RemoveIfCancelled::new(out_path, &cancel, options.resumable);
+    progress_callback.on_output_created(out_path);
+    let pending_sidecar = match params
+        .sidecar
+        .as_ref()
+        .map(|s| s.write_pending(out_path))
+        .transpose()
+    {
+        Ok(pending_sidecar) => pending_sidecar,
+        Err(e) => {
+            progress_callback.on_error(anyhow!("Error writing metadata sidecar: {}", e).into());
+            return;
+        }
+    };
+
+    let video_metadata = metadata.as_video_metadata();
+    let mut data = CancellableReader::new(&mut params.data, &cancel);
+    let packets = PacketReader::new(&mut data as &mut (dyn Read + Send))
+        .resync_on_error(options.resync_on_error)
+        .max_packet_len(options.max_packet_len);
+    let mut stats = StatsCollector::default();
+    let no_share = |progress: u64| progress;
+    let packed = match options.video_backend {
+        #[cfg(feature = "video")]
+        VideoBackend::FFmpeg => match FfmpegPacker::new(
+            out,
+            &video_metadata,
+            None,
+            &file_name,
+            options,
+            progress_callback,
+            &cancel,
+        ) {
+            Ok(packer) => pack(
+                packer,
+                packets,
+                options,
+                &params.input_position,
+                &no_share,
+                &mut stats,
+                progress_callback,
+                &cancel,
+            ),
+            Err(e) => {
+                progress_callback.on_error(e.into());
+                return;
+            }
+        },
+        #[cfg(feature = "rust-mp4")]
+        VideoBackend::RustMp4 => match RustMp4Packer::new(out, &video_metadata, true) {
+            Ok(packer) => pack(
+                packer,
+                packets,
+                options,
+                &params.input_position,
+                &no_share,
+                &mut stats,
+                progress_callback,
+                &cancel,
+            ),
+            Err(e) => {
+                progress_callback.on_error(e.into());
+                return;
+            }
+        },
+        // validate() rejects backends that aren't built
+        #[allow(unreachable_patterns)]
+        backend => {
+            progress_callback.on_error(anyhow!("{:?} backend not built", backend).into());
+            return;
+        }
+    };
+    let out = match packed {
+        Some(out) => out,
+        None => return,
+    };
+
+    let digest = match out.digest(out_path) {
+        Ok(digest) => digest,
+        Err(e) => {
+            progress_callback.on_error(e.into());
+            return;
+        }
+    };
+    if let Some(digest) = &digest {
+        progress_callback.on_output_digest(digest);
+    }
+    if options.check_output
+        && params.extension == "m4a"
+        && !check_output(out_path, 1, options, progress_callback)
+    {
+        return;
+    }
+    if options.preserve_timestamps {
+        timestamp::set_mtime(out_path, &metadata.timestamp);
+    }
+    if options.fsync_on_complete {
+        if let Err(e) = sync_output(out_path) {
+            progress_callback.on_error(e.into());
+            return;
+        }
+    }
+    if let Some(pending_sidecar) = pending_sidecar {
+        if let Err(e) = pending_sidecar.commit(digest.as_ref()) {
+            progress_callback.on_error(anyhow!("Error writing metadata sidecar: {}", e).into());
+            return;
+        }
+    }
+    if let Some(pending_manifest) = pending_manifest {
+        if let Err(e) = pending_manifest.complete() {
+            progress_callback.on_error(anyhow!("Error removing job manifest: {}", e).into());
+            return;
+        }
+    }
+    cleanup.keep();
+    *output = Some(out_path.clone());
+    progress_callback.on_stats(&DecryptStats::Audio(stats.finish_audio()));
+    progress_callback.on_complete();
+}
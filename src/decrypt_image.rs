@@ -1,35 +1,106 @@
-use crate::decrypt::{DecryptingJob, ProgressCallback};
-use anyhow::{bail, Result};
-use serde::Deserialize;
+use crate::{
+    budget::Resource,
+    cancel::CancellableReader,
+    decrypt::{
+        DecryptOptions, DecryptStats, DecryptingJob, ImageFormatMismatch, ImageStats,
+        ProgressCallback,
+    },
+    error::{job_error, read_error, read_error_anyhow, typed_read_error, Error},
+    exif::{self, ExifTags},
+    location::{self, Location},
+    output_path::{
+        batch_name_warning, output_file_name, sanitize_extension, sync_output, MediaInfo,
+        RemoveIfCancelled,
+    },
+    progress::InputPosition,
+    resume::{open_output, ResumeManifest},
+    sidecar::Sidecar,
+    timestamp,
+    verify::ImageVerifyJob,
+    warning::{self, DecryptWarning},
+};
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::{
-    fs::File,
-    io::{copy, Read},
-    path::PathBuf,
+    io::{copy, BufWriter, Cursor, Read, Write},
+    path::{Path, PathBuf},
     str,
-    sync::{atomic::AtomicBool, Arc},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
+#[allow(clippy::too_many_arguments)]
 pub fn build_image_decryption_job(
     data: Box<dyn Read>,
     metadata: &[u8],
     out_path: PathBuf,
     total_file_size: u64,
-    bytes_before_data: u64,
+    input_position: InputPosition,
+    sidecar: Option<Sidecar>,
+    resume_manifest: Option<ResumeManifest>,
+    device_label: Option<String>,
+    options: DecryptOptions,
 ) -> Result<Box<dyn DecryptingJob + Send>> {
     let metadata = parse_metadata(str::from_utf8(metadata)?)?;
+    if options.verify_only {
+        return Ok(Box::new(ImageVerifyJob::new(
+            data,
+            metadata.format,
+            total_file_size,
+            input_position,
+        )));
+    }
+    if let Some(batch_names) = &options.batch_names {
+        batch_names.register(&metadata.timestamp);
+    }
     Ok(Box::new(ImageDecryptionJob {
         params: ImageDecryptionJobParams {
             data,
             metadata,
             out_path,
             total_file_size,
-            bytes_before_data,
+            input_position,
+            sidecar,
+            resume_manifest,
+            device_label,
+            options,
         },
+        output: None,
     }))
 }
 
+/// The MediaInfo the job will name the output after, for batch::plan(). The extension is the
+/// declared format's, the contents can't be checked without decrypting them.
+pub(crate) fn planned_media_info(
+    metadata: &[u8],
+    device_label: Option<String>,
+    options: &DecryptOptions,
+) -> Result<MediaInfo> {
+    let metadata = parse_metadata(str::from_utf8(metadata)?)?;
+    let extension = match normalize_format(&metadata.format) {
+        Some(_) => metadata.format.clone(),
+        None => "bin".to_owned(),
+    };
+    Ok(MediaInfo {
+        timestamp: metadata.timestamp,
+        media_type: "image",
+        codec: extension.clone(),
+        width: None,
+        height: None,
+        source_stem: options.source_stem.clone(),
+        device_label,
+        extra: metadata.extra,
+        extension,
+    })
+}
+
 struct ImageDecryptionJob {
     params: ImageDecryptionJobParams,
+    output: Option<PathBuf>,
 }
 
 struct ImageDecryptionJobParams {
@@ -37,54 +108,362 @@ struct ImageDecryptionJobParams {
     metadata: ImageMetadata,
     out_path: PathBuf,
     total_file_size: u64,
-    bytes_before_data: u64,
+    input_position: InputPosition,
+    sidecar: Option<Sidecar>,
+    resume_manifest: Option<ResumeManifest>,
+    device_label: Option<String>,
+    options: DecryptOptions,
 }
 
 unsafe impl Send for ImageDecryptionJob {}
 
 impl DecryptingJob for ImageDecryptionJob {
-    fn run(&mut self, progress_callback: Box<&mut dyn ProgressCallback>, _cancel: Arc<AtomicBool>) {
-        let bytes_before_data = self.params.bytes_before_data;
-        let total_file_size = self.params.total_file_size;
-        progress_callback.set_total_file_size(total_file_size);
-        progress_callback.set_offset(bytes_before_data);
+    fn run(&mut self, progress_callback: Box<&mut dyn ProgressCallback>, cancel: Arc<AtomicBool>) {
+        progress_callback.set_total_file_size(self.params.total_file_size);
+        let _payload_reservation = match &self.params.options.resource_budget {
+            None => None,
+            Some(budget) => match budget.reserve(Resource::PayloadBuffers, 1, Some(&cancel)) {
+                Ok(Some(reservation)) => Some(reservation),
+                Ok(None) => return,
+                Err(e) => {
+                    progress_callback.on_error(e.into());
+                    return;
+                }
+            },
+        };
 
+        // look at the start of the payload to make sure the extension matches the contents
+        let mut head = Vec::with_capacity(16);
+        if let Err(e) = (&mut self.params.data).take(16).read_to_end(&mut head) {
+            progress_callback.on_error(read_error(e));
+            return;
+        }
+        let strip_location = self.params.options.strip_location;
+        if let Some(warning) = self.params.metadata.normalize_location(strip_location) {
+            warning::report(&mut **progress_callback, warning);
+        }
         let metadata = &self.params.metadata;
-        let filename = format!(
-            "{}.{}",
-            metadata.timestamp.replace(":", "-"),
-            metadata.format
-        ); // try not tripping up windows with scary filenames
+        let extension = match choose_extension(
+            &metadata.format,
+            &head,
+            self.params.options.image_format_mismatch,
+        ) {
+            (extension, Some(warning)) => {
+                warning::report(&mut **progress_callback, warning);
+                extension
+            }
+            (extension, None) => extension,
+        };
+        let mut data =
+            CancellableReader::new(Cursor::new(head).chain(&mut self.params.data), &cancel);
+        let options = &self.params.options;
+        let info = MediaInfo {
+            timestamp: metadata.timestamp.clone(),
+            media_type: "image",
+            codec: extension.to_owned(),
+            width: None,
+            height: None,
+            source_stem: options.source_stem.clone(),
+            device_label: self.params.device_label.clone(),
+            extra: metadata.extra.clone(),
+            extension: extension.to_owned(),
+        };
+        let filename = output_file_name(&info, &options.naming, options.batch_names.as_ref());
+        if let Some(warning) =
+            batch_name_warning(&info, &options.naming, options.batch_names.as_ref())
+        {
+            warning::report(&mut **progress_callback, warning);
+        }
         let out_path = &mut self.params.out_path;
-        out_path.push(filename);
-        let mut out = match File::create(&out_path) {
+        let (mut out, pending_manifest) = match open_output(
+            out_path,
+            &filename,
+            &metadata.timestamp,
+            options,
+            self.params.resume_manifest.as_ref(),
+            true,
+        ) {
             Err(e) => {
-                progress_callback.on_error(e.into());
+                progress_callback.on_error(job_error(e));
                 return;
             }
-            Ok(f) => f,
+            Ok(opened) => opened,
         };
-        match copy(&mut self.params.data, &mut out) {
-            Ok(_) => {}
+        let cleanup = RemoveIfCancelled::new(out_path, &cancel, options.resumable);
+        progress_callback.on_output_created(out_path);
+        let pending_sidecar = match self
+            .params
+            .sidecar
+            .as_ref()
+            .map(|s| s.write_pending(out_path))
+            .transpose()
+        {
+            Ok(pending_sidecar) => pending_sidecar,
             Err(e) => {
-                progress_callback.on_error(Box::new(e));
+                progress_callback.on_error(anyhow!("Error writing metadata sidecar: {}", e).into());
                 return;
             }
         };
+        if let Err(e) = copy_image(&mut data, &mut out, metadata, extension, options.write_exif) {
+            if !cancel.load(Ordering::Relaxed) {
+                progress_callback.on_error(job_error(e));
+            }
+            return;
+        }
+        progress_callback.on_progress(self.params.input_position.get());
+        let stats = DecryptStats::Image(ImageStats {
+            bytes: out.position(),
+        });
+        let digest = match out.digest(out_path) {
+            Ok(digest) => digest,
+            Err(e) => {
+                progress_callback.on_error(e.into());
+                return;
+            }
+        };
+        if let Some(digest) = &digest {
+            progress_callback.on_output_digest(digest);
+        }
+        if options.preserve_timestamps {
+            timestamp::set_mtime(out_path, &metadata.timestamp);
+        }
+        if options.fsync_on_complete {
+            if let Err(e) = sync_output(out_path) {
+                progress_callback.on_error(e.into());
+                return;
+            }
+        }
+        if let Some(pending_sidecar) = pending_sidecar {
+            if let Err(e) = pending_sidecar.commit(digest.as_ref()) {
+                progress_callback.on_error(anyhow!("Error writing metadata sidecar: {}", e).into());
+                return;
+            }
+        }
+        if let Some(pending_manifest) = pending_manifest {
+            if let Err(e) = pending_manifest.complete() {
+                progress_callback.on_error(anyhow!("Error removing job manifest: {}", e).into());
+                return;
+            }
+        }
+        cleanup.keep();
+        self.output = Some(out_path.clone());
+        progress_callback.on_stats(&stats);
         progress_callback.on_complete();
     }
+
+    fn output_path(&self) -> Option<&Path> {
+        self.output.as_deref()
+    }
 }
 
-fn parse_metadata(json: &str) -> Result<ImageMetadata> {
+/// The image written by decrypt_image_to_writer().
+#[derive(Debug, Clone)]
+pub struct DecryptedImage {
+    pub metadata: ImageMetadata,
+    /// What to save the image as, without the dot. Picked like for the name of output files,
+    /// see DecryptOptions::image_format_mismatch.
+    pub extension: String,
+    pub warnings: Vec<DecryptWarning>,
+}
+
+/// Writes the decrypted image that follows the metadata to `out`, see decrypt_image_to_writer().
+pub(crate) fn write_image(
+    mut data: impl Read,
+    metadata: &[u8],
+    mut out: impl Write,
+    options: &DecryptOptions,
+) -> Result<DecryptedImage> {
+    let mut metadata = parse_metadata(str::from_utf8(metadata)?)?;
+    let location_warning = metadata.normalize_location(options.strip_location);
+    let mut head = Vec::with_capacity(16);
+    (&mut data)
+        .take(16)
+        .read_to_end(&mut head)
+        .map_err(read_error_anyhow)?;
+    let (extension, format_warning) =
+        choose_extension(&metadata.format, &head, options.image_format_mismatch);
+    let extension = sanitize_extension(extension);
+    let warnings: Vec<DecryptWarning> =
+        location_warning.into_iter().chain(format_warning).collect();
+    for warning in &warnings {
+        warn!("{}", warning);
+    }
+    let mut data = Cursor::new(head).chain(data);
+    copy_image(
+        &mut data,
+        &mut out,
+        &metadata,
+        &extension,
+        options.write_exif,
+    )
+    .map_err(typed_read_error)?;
+    out.flush()?;
+    Ok(DecryptedImage {
+        metadata,
+        extension,
+        warnings,
+    })
+}
+
+/// Copies the image to `out`, adding the timestamp, rotation and location from the metadata as
+/// EXIF tags to JPEGs if `write_exif` is set.
+fn copy_image(
+    data: &mut dyn Read,
+    out: &mut dyn Write,
+    metadata: &ImageMetadata,
+    extension: &str,
+    write_exif: bool,
+) -> Result<()> {
+    let is_jpeg = normalize_format(extension) == Some("jpg");
+    let tags = ExifTags {
+        date_time_original: timestamp::parse_timestamp(&metadata.timestamp).ok(),
+        orientation: metadata.rotation.and_then(exif::orientation_from_rotation),
+        location: Location::from_metadata(metadata.latitude, metadata.longitude, metadata.altitude)
+            .ok()
+            .flatten(),
+    };
+    if write_exif && is_jpeg && !tags.is_empty() {
+        let mut out = BufWriter::new(out);
+        exif::copy_jpeg_with_exif(data, &mut out, &tags)?;
+        out.flush()?;
+    } else {
+        copy(data, out)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn parse_metadata(json: &str) -> Result<ImageMetadata> {
     let metadata: ImageMetadata = match serde_json::from_str(json) {
         Ok(m) => m,
-        Err(e) => bail!("Error parsing metadata: {}", e),
+        Err(e) => return Err(Error::metadata_parse(e).into()),
     };
     Ok(metadata)
 }
 
-#[derive(Debug, Deserialize)]
-struct ImageMetadata {
-    timestamp: String,
-    format: String,
+/// The metadata the app stores with an image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageMetadata {
+    /// ISO 8601, with or without a timezone.
+    pub timestamp: String,
+    /// File extension of the image format, e.g. "jpg".
+    pub format: String,
+    /// clockwise, in degrees
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation: Option<u16>,
+    /// Where the recording was made, in degrees, north and east are positive. Only in files
+    /// from newer versions of the app, see DecryptOptions::strip_location.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<f64>,
+    /// Meters above sea level.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub altitude: Option<f64>,
+    /// Fields this version doesn't know, like those added by newer versions of the app.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl ImageMetadata {
+    pub fn new(timestamp: impl Into<String>, format: impl Into<String>) -> Self {
+        ImageMetadata {
+            timestamp: timestamp.into(),
+            format: format.into(),
+            rotation: None,
+            latitude: None,
+            longitude: None,
+            altitude: None,
+            extra: Map::new(),
+        }
+    }
+
+    pub fn rotation(mut self, rotation: u16) -> Self {
+        self.rotation = Some(rotation);
+        self
+    }
+
+    pub fn location(mut self, latitude: f64, longitude: f64) -> Self {
+        self.latitude = Some(latitude);
+        self.longitude = Some(longitude);
+        self
+    }
+
+    pub fn altitude(mut self, altitude: f64) -> Self {
+        self.altitude = Some(altitude);
+        self
+    }
+
+    /// Drops the location if `strip` is set or it isn't valid, returns the warning about an
+    /// invalid one.
+    fn normalize_location(&mut self, strip: bool) -> Option<DecryptWarning> {
+        location::normalize(
+            &mut self.latitude,
+            &mut self.longitude,
+            &mut self.altitude,
+            strip,
+        )
+    }
+}
+
+/// Recognizes the image formats cameras write by their first bytes.
+pub(crate) fn sniff_format(head: &[u8]) -> Option<&'static str> {
+    if head.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("jpg")
+    } else if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("png")
+    } else if head.starts_with(b"RIFF") && head.get(8..12) == Some(b"WEBP") {
+        Some("webp")
+    } else if head.get(4..8) == Some(b"ftyp") {
+        match head.get(8..12) {
+            Some(b"heic") | Some(b"heix") | Some(b"hevc") | Some(b"heim") | Some(b"heis")
+            | Some(b"mif1") | Some(b"msf1") => Some("heic"),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+pub(crate) fn normalize_format(format: &str) -> Option<&'static str> {
+    match format.to_ascii_lowercase().as_str() {
+        "jpg" | "jpeg" => Some("jpg"),
+        "png" => Some("png"),
+        "webp" => Some("webp"),
+        "heic" | "heif" => Some("heic"),
+        _ => None,
+    }
+}
+
+/// Picks the extension of the output file from the declared format and the first bytes
+/// of the payload, along with a warning if the two disagree.
+fn choose_extension<'a>(
+    declared: &'a str,
+    head: &[u8],
+    on_mismatch: ImageFormatMismatch,
+) -> (&'a str, Option<DecryptWarning>) {
+    match (normalize_format(declared), sniff_format(head)) {
+        (Some(d), Some(s)) if d == s => (declared, None),
+        (None, None) => (
+            "bin",
+            Some(DecryptWarning::UnknownImageFormat {
+                declared: declared.to_owned(),
+            }),
+        ),
+        (_, None) => (
+            declared,
+            Some(DecryptWarning::UnrecognizedImage {
+                declared: declared.to_owned(),
+            }),
+        ),
+        (_, Some(sniffed)) => {
+            let warning = DecryptWarning::FormatMismatch {
+                declared: declared.to_owned(),
+                detected: sniffed.to_owned(),
+            };
+            match on_mismatch {
+                ImageFormatMismatch::CorrectExtension => (sniffed, Some(warning)),
+                ImageFormatMismatch::Warn => (declared, Some(warning)),
+            }
+        }
+    }
 }
@@ -1,4 +1,33 @@
-use crate::decrypt::{DecryptingJob, ProgressCallback};
+pub use crate::packet::{CryptocamPacket, PacketKind, PacketReader};
+#[cfg(feature = "rust-mp4")]
+use crate::rust_mp4::RustMp4Packer;
+#[cfg(feature = "thumbnail")]
+use crate::thumbnail::Thumbnailer;
+#[cfg(feature = "transcode")]
+use crate::transcode::Transcoder;
+use crate::{
+    budget::{Reservation, Resource, ResourceBudget},
+    cancel::CancellableReader,
+    decrypt::{
+        AudioStats, DecryptOptions, DecryptStats, DecryptingJob, FrameRate, NonMonotonicPts,
+        ProgressCallback, VideoBackend, VideoContainer, VideoStats,
+    },
+    error::{job_error, Error},
+    location::{self, Location},
+    mp4,
+    output_path::{
+        batch_name_warning, hash_file, output_file_name, sync_output, MediaInfo, OutputFile,
+        RemoveIfCancelled,
+    },
+    packet::PacketError,
+    progress::InputPosition,
+    resume::{open_output, ResumeManifest},
+    sidecar::Sidecar,
+    timestamp,
+    verify::VideoVerifyJob,
+    warning::{self, DecryptWarning},
+};
+#[cfg(feature = "video")]
 use ac_ffmpeg::{
     codec::{
         audio::ChannelLayout, bsf::BitstreamFilter, AudioCodecParameters, CodecParameters,
@@ -8,276 +37,2285 @@ use ac_ffmpeg::{
         io::IO,
         muxer::{Muxer, OutputFormat},
     },
-    packet::{Packet, PacketMut},
-    time::Timestamp,
+    packet::PacketMut,
+    time::{TimeBase, Timestamp},
 };
 use anyhow::{anyhow, bail, Result};
-use bytes::{ByteOrder, LittleEndian};
-use log::warn;
-use serde::Deserialize;
-use std::{fs::File, io::Read, path::PathBuf, str, sync::atomic::AtomicBool, sync::Arc};
+use log::debug;
+#[cfg(feature = "video")]
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    fs::{self, File},
+    io::{BufReader, Read, Write},
+    iter,
+    path::{Path, PathBuf},
+    str,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::{mpsc, Arc},
+    thread,
+};
 
-pub fn build_video_decryption_job(
-    data: Box<dyn Read>,
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn build_video_decryption_job(
+    data: Box<dyn Read + Send>,
     metadata: &[u8],
     out_path: PathBuf,
     total_file_size: u64,
-    bytes_before_data: u64,
+    input_position: InputPosition,
+    sidecar: Option<Sidecar>,
+    resume_manifest: Option<ResumeManifest>,
+    device_label: Option<String>,
+    mut options: DecryptOptions,
 ) -> Result<Box<dyn DecryptingJob + Send>> {
     let metadata = parse_video_metadata(str::from_utf8(metadata)?)?;
+    if options.verify_only {
+        return Ok(Box::new(VideoVerifyJob::new(
+            data,
+            total_file_size,
+            input_position,
+            options,
+        )));
+    }
+    if options.container == VideoContainer::RawPackets {
+        if let Some(batch_names) = &options.batch_names {
+            batch_names.register(&metadata.timestamp);
+        }
+        return Ok(Box::new(RawPacketsJob {
+            params: VideoMuxingJobParams {
+                data,
+                metadata,
+                out_path,
+                total_file_size,
+                input_position,
+                sidecar,
+                resume_manifest,
+                device_label,
+                options,
+            },
+            output: None,
+        }));
+    }
+    choose_container(&metadata, &mut options)?;
+    if let Some(batch_names) = &options.batch_names {
+        batch_names.register(&metadata.timestamp);
+    }
     Ok(Box::new(VideoMuxingJob {
         params: VideoMuxingJobParams {
             data,
             metadata,
             out_path,
             total_file_size,
-            bytes_before_data,
+            input_position,
+            sidecar,
+            resume_manifest,
+            device_label,
+            options,
         },
+        output: None,
+    }))
+}
+
+/// Sets the container the output is written as, failing before anything is written if it
+/// can't be. The job only sees Mp4 or Mkv.
+fn choose_container(metadata: &VideoMetadata, options: &mut DecryptOptions) -> Result<()> {
+    options.container = output_container(metadata.audio_codec, options.container)?;
+    if options.video_backend == VideoBackend::RustMp4 {
+        if options.container != VideoContainer::Mp4 {
+            bail!("The rust-mp4 backend only writes MP4, Opus audio needs the FFmpeg backend");
+        }
+        if is_hevc(metadata.codec.as_deref()) {
+            bail!("The rust-mp4 backend can't write HEVC video, use the FFmpeg backend");
+        }
+    }
+    Ok(())
+}
+
+/// A segment of a recording for decrypt::stitch(), decrypted up to its packets.
+pub(crate) struct StitchSegment {
+    pub(crate) data: Box<dyn Read + Send>,
+    pub(crate) metadata: Vec<u8>,
+    pub(crate) total_file_size: u64,
+    pub(crate) input_position: InputPosition,
+}
+
+/// The job for decrypt::stitch(), named after the first segment.
+pub(crate) fn build_stitch_job(
+    segments: Vec<StitchSegment>,
+    out_path: PathBuf,
+    device_label: Option<String>,
+    mut options: DecryptOptions,
+) -> Result<Box<dyn DecryptingJob + Send>> {
+    let mut metadata: Option<VideoMetadata> = None;
+    let mut inputs = vec![];
+    for (index, segment) in segments.into_iter().enumerate() {
+        let segment_metadata = parse_video_metadata(str::from_utf8(&segment.metadata)?)?;
+        match &metadata {
+            None => metadata = Some(segment_metadata),
+            Some(first) => check_segment(first, &segment_metadata, index)?,
+        }
+        inputs.push(StitchInput {
+            data: segment.data,
+            total_file_size: segment.total_file_size,
+            input_position: segment.input_position,
+        });
+    }
+    let metadata = metadata.ok_or_else(|| anyhow!("No segments to stitch"))?;
+    choose_container(&metadata, &mut options)?;
+    if let Some(batch_names) = &options.batch_names {
+        batch_names.register(&metadata.timestamp);
+    }
+    Ok(Box::new(StitchJob {
+        inputs,
+        metadata,
+        out_path,
+        device_label,
+        options,
+        output: None,
     }))
 }
 
-#[derive(Debug, Deserialize)]
-struct VideoMetadata {
-    width: usize,
-    height: usize,
-    rotation: u16,
-    video_bitrate: u64,
-    audio_sample_rate: u32,
-    audio_channel_count: u32,
-    audio_bitrate: u64,
-    timestamp: String,
+/// Fails with Error::IncompatibleSegments unless the segment at `index` can be muxed into the
+/// streams set up for the `first` one.
+fn check_segment(first: &VideoMetadata, segment: &VideoMetadata, index: usize) -> Result<()> {
+    let codec = |metadata: &VideoMetadata| {
+        if is_hevc(metadata.codec.as_deref()) {
+            "hevc"
+        } else {
+            "h264"
+        }
+    };
+    let reason = if codec(segment) != codec(first) {
+        format!("{} video, not {}", codec(segment), codec(first))
+    } else if (segment.width, segment.height) != (first.width, first.height) {
+        format!(
+            "{}x{} video, not {}x{}",
+            segment.width, segment.height, first.width, first.height
+        )
+    } else if segment.audio_codec != first.audio_codec {
+        format!(
+            "{:?} audio, not {:?}",
+            segment.audio_codec, first.audio_codec
+        )
+    } else if segment.audio_sample_rate != first.audio_sample_rate
+        || segment.audio_channel_count != first.audio_channel_count
+    {
+        format!(
+            "{} Hz audio with {} channels, not {} Hz with {}",
+            segment.audio_sample_rate,
+            segment.audio_channel_count,
+            first.audio_sample_rate,
+            first.audio_channel_count
+        )
+    } else {
+        return Ok(());
+    };
+    Err(Error::IncompatibleSegments { index, reason }.into())
+}
+
+/// The MediaInfo the job will name the output after, for batch::plan().
+pub(crate) fn planned_media_info(
+    metadata: &[u8],
+    device_label: Option<String>,
+    options: &DecryptOptions,
+) -> Result<MediaInfo> {
+    let metadata = parse_video_metadata(str::from_utf8(metadata)?)?;
+    let extension = match options.container {
+        VideoContainer::RawPackets => RAW_PACKETS_EXTENSION,
+        requested => match output_container(metadata.audio_codec, requested)? {
+            VideoContainer::Mkv => "mkv",
+            _ => "mp4",
+        },
+    };
+    let codec = if is_hevc(metadata.codec.as_deref()) {
+        "hevc"
+    } else {
+        "h264"
+    };
+    Ok(MediaInfo {
+        timestamp: metadata.timestamp,
+        media_type: "video",
+        codec: codec.to_owned(),
+        width: Some(metadata.width),
+        height: Some(metadata.height),
+        source_stem: options.source_stem.clone(),
+        device_label,
+        extra: metadata.extra,
+        extension: extension.to_owned(),
+    })
+}
+
+/// The extension of VideoContainer::RawPackets output.
+const RAW_PACKETS_EXTENSION: &str = "packets";
+
+/// Videos wider or higher than this are rejected, their metadata is assumed to be corrupt.
+const MAX_VIDEO_DIMENSION: usize = 16384;
+
+/// The metadata the app stores with a video.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct VideoMetadata {
+    pub width: usize,
+    pub height: usize,
+    /// Clockwise, in degrees. Missing in files from some modified versions of the app.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation: Option<u16>,
+    /// Where the recording was made, in degrees, north and east are positive. Only in files
+    /// from newer versions of the app, see DecryptOptions::strip_location.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<f64>,
+    /// Meters above sea level.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub altitude: Option<f64>,
+    pub video_bitrate: u64,
+    pub audio_sample_rate: u32,
+    pub audio_channel_count: u32,
+    /// Name of the layout as understood by ffmpeg, e.g. "5.1" or "quad". Only some files have it,
+    /// otherwise the layout is guessed from the channel count.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_channel_layout: Option<String>,
+    pub audio_bitrate: u64,
+    /// ISO 8601, with or without a timezone.
+    pub timestamp: String,
+    /// "h264" (the default) or "hevc".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec: Option<String>,
+    /// Frames per second the camera was set to. Only in files from newer versions of the app,
+    /// the recording may still have a variable frame rate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame_rate: Option<f64>,
+    /// Missing in files from versions of the app that only recorded AAC.
     #[serde(default)]
-    codec: Option<String>,
+    pub audio_codec: AudioCodec,
+    /// The video encoder's codec specific data buffers (csd-0, csd-1) in base64, the SPS and PPS
+    /// (and VPS for HEVC) with start codes. Only in files from newer versions of the app,
+    /// otherwise the parameter sets are taken from the video stream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub video_csd: Option<Vec<String>>,
+    /// The audio encoder's csd-0 in base64, the AudioSpecificConfig for AAC.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_csd: Option<String>,
+    /// Fields this version doesn't know, like those added by newer versions of the app.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl VideoMetadata {
+    /// Metadata of an H.264 video with stereo AAC audio at 48 kHz, change the rest with the
+    /// setters below.
+    pub fn new(timestamp: impl Into<String>, width: usize, height: usize) -> Self {
+        VideoMetadata {
+            width,
+            height,
+            rotation: Some(0),
+            latitude: None,
+            longitude: None,
+            altitude: None,
+            video_bitrate: 0,
+            audio_sample_rate: 48000,
+            audio_channel_count: 2,
+            audio_channel_layout: None,
+            audio_bitrate: 0,
+            timestamp: timestamp.into(),
+            codec: None,
+            frame_rate: None,
+            audio_codec: AudioCodec::Aac,
+            video_csd: None,
+            audio_csd: None,
+            extra: Map::new(),
+        }
+    }
+
+    // setters for the fields above, see there
+
+    pub fn rotation(mut self, rotation: u16) -> Self {
+        self.rotation = Some(rotation);
+        self
+    }
+
+    pub fn location(mut self, latitude: f64, longitude: f64) -> Self {
+        self.latitude = Some(latitude);
+        self.longitude = Some(longitude);
+        self
+    }
+
+    pub fn altitude(mut self, altitude: f64) -> Self {
+        self.altitude = Some(altitude);
+        self
+    }
+
+    pub fn video_bitrate(mut self, video_bitrate: u64) -> Self {
+        self.video_bitrate = video_bitrate;
+        self
+    }
+
+    pub fn audio_sample_rate(mut self, audio_sample_rate: u32) -> Self {
+        self.audio_sample_rate = audio_sample_rate;
+        self
+    }
+
+    pub fn audio_channel_count(mut self, audio_channel_count: u32) -> Self {
+        self.audio_channel_count = audio_channel_count;
+        self
+    }
+
+    pub fn audio_channel_layout(mut self, audio_channel_layout: impl Into<String>) -> Self {
+        self.audio_channel_layout = Some(audio_channel_layout.into());
+        self
+    }
+
+    pub fn audio_bitrate(mut self, audio_bitrate: u64) -> Self {
+        self.audio_bitrate = audio_bitrate;
+        self
+    }
+
+    pub fn codec(mut self, codec: impl Into<String>) -> Self {
+        self.codec = Some(codec.into());
+        self
+    }
+
+    pub fn frame_rate(mut self, frame_rate: f64) -> Self {
+        self.frame_rate = Some(frame_rate);
+        self
+    }
+
+    pub fn audio_codec(mut self, audio_codec: AudioCodec) -> Self {
+        self.audio_codec = audio_codec;
+        self
+    }
+
+    /// The buffers are base64 encoded.
+    pub fn video_csd(mut self, buffers: &[&[u8]]) -> Self {
+        self.video_csd = Some(buffers.iter().map(base64::encode).collect());
+        self
+    }
+
+    /// The buffer is base64 encoded.
+    pub fn audio_csd(mut self, buffer: &[u8]) -> Self {
+        self.audio_csd = Some(base64::encode(buffer));
+        self
+    }
+
+    /// Replaces a rotation other than 0, 90, 180 or 270 degrees with 0, returns the warning
+    /// about it.
+    fn normalize_rotation(&mut self) -> Option<DecryptWarning> {
+        match self.rotation {
+            None | Some(0 | 90 | 180 | 270) => None,
+            Some(rotation) => {
+                self.rotation = Some(0);
+                Some(DecryptWarning::InvalidRotation(rotation))
+            }
+        }
+    }
+
+    /// Drops codec specific data that isn't valid base64, so the parameter sets are taken from
+    /// the stream instead, returns the warnings about it.
+    fn normalize_csd(&mut self) -> Vec<DecryptWarning> {
+        let mut warnings = vec![];
+        if let Some(Err(e)) = self.video_csd.as_ref().map(|csd| decode_video_csd(csd)) {
+            self.video_csd = None;
+            warnings.push(DecryptWarning::InvalidCodecConfig {
+                stream: PacketKind::Video,
+                reason: e.to_string(),
+            });
+        }
+        if let Some(Err(e)) = self.audio_csd.as_ref().map(base64::decode) {
+            self.audio_csd = None;
+            warnings.push(DecryptWarning::InvalidCodecConfig {
+                stream: PacketKind::Audio,
+                reason: e.to_string(),
+            });
+        }
+        warnings
+    }
+
+    /// The video parameter sets from the metadata in Annex B format, after normalize_csd().
+    pub(crate) fn video_extradata(&self) -> Option<Vec<u8>> {
+        self.video_csd
+            .as_ref()
+            .and_then(|csd| decode_video_csd(csd).ok())
+            .filter(|extradata| !extradata.is_empty())
+    }
+
+    /// The AudioSpecificConfig from the metadata, after normalize_csd().
+    pub(crate) fn audio_extradata(&self) -> Option<Vec<u8>> {
+        self.audio_csd
+            .as_ref()
+            .and_then(|csd| base64::decode(csd).ok())
+            .filter(|extradata| !extradata.is_empty())
+    }
+
+    /// The location to write into the output, after normalize_location().
+    pub(crate) fn gps_location(&self) -> Option<Location> {
+        Location::from_metadata(self.latitude, self.longitude, self.altitude)
+            .ok()
+            .flatten()
+    }
+
+    /// Drops the location if `strip` is set or it isn't valid, returns the warning about an
+    /// invalid one.
+    fn normalize_location(&mut self, strip: bool) -> Option<DecryptWarning> {
+        location::normalize(
+            &mut self.latitude,
+            &mut self.longitude,
+            &mut self.altitude,
+            strip,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioCodec {
+    #[default]
+    Aac,
+    Opus,
+}
+
+/// Joins the csd buffers into one Annex B stream, adding start codes to buffers without one.
+fn decode_video_csd(csd: &[String]) -> Result<Vec<u8>, base64::DecodeError> {
+    let mut extradata = vec![];
+    for buffer in csd {
+        let buffer = base64::decode(buffer)?;
+        if buffer.is_empty() {
+            continue;
+        }
+        if !buffer.starts_with(&[0, 0, 1]) && !buffer.starts_with(&[0, 0, 0, 1]) {
+            extradata.extend_from_slice(&[0, 0, 0, 1]);
+        }
+        extradata.extend_from_slice(&buffer);
+    }
+    Ok(extradata)
+}
+
+fn is_hevc(codec: Option<&str>) -> bool {
+    matches!(codec, Some(c) if c.eq_ignore_ascii_case("hevc") || c.eq_ignore_ascii_case("h265"))
+}
+
+fn output_container(audio_codec: AudioCodec, requested: VideoContainer) -> Result<VideoContainer> {
+    Ok(match (requested, audio_codec) {
+        (VideoContainer::Auto, AudioCodec::Aac) => VideoContainer::Mp4,
+        (VideoContainer::Auto, AudioCodec::Opus) => VideoContainer::Mkv,
+        (VideoContainer::Mp4, AudioCodec::Opus) => {
+            bail!("Videos with Opus audio can't be written as MP4, use VideoContainer::Mkv")
+        }
+        (container, _) => container,
+    })
+}
+
+/// The layout named in the metadata, or the usual one for the channel count. If neither is known,
+/// stereo along with a warning, since a wrong layout is better than failing the whole video.
+#[cfg(feature = "video")]
+fn channel_layout(
+    name: Option<&str>,
+    channel_count: u32,
+) -> (ChannelLayout, Option<DecryptWarning>) {
+    if let Some(layout) = name.and_then(|name| name.parse::<ChannelLayout>().ok()) {
+        return (layout, None);
+    }
+    match ChannelLayout::from_channels(channel_count) {
+        Some(layout) => {
+            let warning = name.map(|name| DecryptWarning::ChannelLayoutGuessed {
+                layout: Some(name.to_owned()),
+                channel_count,
+                stereo: false,
+            });
+            (layout, warning)
+        }
+        None => {
+            let warning = DecryptWarning::ChannelLayoutGuessed {
+                layout: name.map(str::to_owned),
+                channel_count,
+                stereo: true,
+            };
+            (ChannelLayout::from_channels(2).unwrap(), Some(warning))
+        }
+    }
+}
+
+/// The OpusHead structure Matroska expects as CodecPrivate, see RFC 7845 section 5.1.
+#[cfg(feature = "video")]
+fn opus_head(channel_count: u8, sample_rate: u32) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1); // version
+    head.push(channel_count);
+    head.extend_from_slice(&0u16.to_le_bytes()); // pre-skip
+    head.extend_from_slice(&sample_rate.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+    head.push(0); // channel mapping family: mono or stereo
+    head
 }
 
-fn parse_video_metadata(json: &str) -> Result<VideoMetadata> {
+pub(crate) fn parse_video_metadata(json: &str) -> Result<VideoMetadata> {
     let metadata: VideoMetadata = match serde_json::from_str(json) {
         Ok(m) => m,
-        Err(e) => bail!("Error parsing metadata: {}", e),
+        Err(e) => return Err(Error::metadata_parse(e).into()),
     };
+    let valid_dimension = 1..=MAX_VIDEO_DIMENSION;
+    if !valid_dimension.contains(&metadata.width) || !valid_dimension.contains(&metadata.height) {
+        bail!(
+            "Invalid video size {}x{} in metadata, width and height must be 1 to {}",
+            metadata.width,
+            metadata.height,
+            MAX_VIDEO_DIMENSION
+        );
+    }
     Ok(metadata)
 }
 
-#[derive(Debug, PartialEq)]
-enum PacketType {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum PacketType {
     Video,
     Audio,
 }
 
 struct VideoMuxingJobParams {
-    data: Box<dyn Read>,
+    data: Box<dyn Read + Send>,
     metadata: VideoMetadata,
     out_path: PathBuf,
     total_file_size: u64,
-    bytes_before_data: u64,
+    input_position: InputPosition,
+    sidecar: Option<Sidecar>,
+    resume_manifest: Option<ResumeManifest>,
+    device_label: Option<String>,
+    options: DecryptOptions,
 }
 
 struct VideoMuxingJob {
     params: VideoMuxingJobParams,
+    output: Option<PathBuf>,
 }
 
 unsafe impl Send for VideoMuxingJob {}
 
 impl DecryptingJob for VideoMuxingJob {
     fn run(&mut self, progress_callback: Box<&mut dyn ProgressCallback>, cancel: Arc<AtomicBool>) {
-        let bytes_before_data = self.params.bytes_before_data;
-        let total_file_size = self.params.total_file_size;
-        progress_callback.set_total_file_size(total_file_size);
-        progress_callback.set_offset(bytes_before_data);
+        progress_callback.set_total_file_size(self.params.total_file_size);
+        let _payload_reservation = match &self.params.options.resource_budget {
+            None => None,
+            Some(budget) => match budget.reserve(Resource::PayloadBuffers, 1, Some(&cancel)) {
+                Ok(Some(reservation)) => Some(reservation),
+                Ok(None) => return,
+                Err(e) => {
+                    progress_callback.on_error(e.into());
+                    return;
+                }
+            },
+        };
+        let metadata = &mut self.params.metadata;
+        let warnings = vec![
+            metadata.normalize_rotation(),
+            metadata.normalize_location(self.params.options.strip_location),
+        ];
+        let csd_warnings = metadata.normalize_csd();
+        for warning in warnings.into_iter().flatten().chain(csd_warnings) {
+            warning::report(&mut **progress_callback, warning);
+        }
+        let mut segments = [Segment {
+            data: &mut self.params.data,
+            total_file_size: self.params.total_file_size,
+            input_position: &self.params.input_position,
+        }];
         mux_video(
-            &mut self.params.data,
+            &mut segments,
             &self.params.metadata,
             &mut self.params.out_path,
-            progress_callback,
+            &mut self.output,
+            &self.params.options,
+            self.params.sidecar.as_ref(),
+            self.params.resume_manifest.as_ref(),
+            self.params.device_label.as_deref(),
+            *progress_callback,
             cancel,
         )
     }
+
+    fn output_path(&self) -> Option<&Path> {
+        self.output.as_deref()
+    }
 }
 
-fn mux_video(
-    data: &mut dyn Read,
-    metadata: &VideoMetadata,
-    out_path: &mut PathBuf,
-    progress_callback: Box<&mut dyn ProgressCallback>,
-    cancel: Arc<AtomicBool>,
-) {
-    // 1. Определение кодека (HEVC или AVC)
-    let codec_name = match metadata.codec.as_deref() {
-        Some(c) if c.eq_ignore_ascii_case("hevc") || c.eq_ignore_ascii_case("h265") => "hevc",
-        _ => "h264",
-    };
+/// A segment of StitchJob, after its metadata was checked.
+struct StitchInput {
+    data: Box<dyn Read + Send>,
+    total_file_size: u64,
+    input_position: InputPosition,
+}
 
-    let video_params = VideoCodecParameters::builder(codec_name)
-        .unwrap()
-        .width(metadata.width)
-        .height(metadata.height)
-        .bit_rate(metadata.video_bitrate)
-        .build();
+/// Muxes the segments of a recording into one output, see decrypt::stitch().
+struct StitchJob {
+    inputs: Vec<StitchInput>,
+    /// Of the first segment.
+    metadata: VideoMetadata,
+    out_path: PathBuf,
+    device_label: Option<String>,
+    options: DecryptOptions,
+    output: Option<PathBuf>,
+}
 
-    let channel_layout = match ChannelLayout::from_channels(metadata.audio_channel_count as u32) {
-        None => {
-            progress_callback.on_error(anyhow!("Error getting channel layout").into());
-            return;
+impl DecryptingJob for StitchJob {
+    fn run(&mut self, progress_callback: Box<&mut dyn ProgressCallback>, cancel: Arc<AtomicBool>) {
+        let total_file_size = self.inputs.iter().map(|input| input.total_file_size).sum();
+        progress_callback.set_total_file_size(total_file_size);
+        let _payload_reservation = match &self.options.resource_budget {
+            None => None,
+            Some(budget) => match budget.reserve(Resource::PayloadBuffers, 1, Some(&cancel)) {
+                Ok(Some(reservation)) => Some(reservation),
+                Ok(None) => return,
+                Err(e) => {
+                    progress_callback.on_error(e.into());
+                    return;
+                }
+            },
+        };
+        let metadata = &mut self.metadata;
+        let warnings = vec![
+            metadata.normalize_rotation(),
+            metadata.normalize_location(self.options.strip_location),
+        ];
+        let csd_warnings = metadata.normalize_csd();
+        for warning in warnings.into_iter().flatten().chain(csd_warnings) {
+            warning::report(&mut **progress_callback, warning);
         }
-        Some(c) => c,
-    };
+        let mut segments: Vec<Segment<'_>> = self
+            .inputs
+            .iter_mut()
+            .map(|input| Segment {
+                data: &mut input.data,
+                total_file_size: input.total_file_size,
+                input_position: &input.input_position,
+            })
+            .collect();
+        mux_video(
+            &mut segments,
+            &self.metadata,
+            &mut self.out_path,
+            &mut self.output,
+            &self.options,
+            None,
+            None,
+            self.device_label.as_deref(),
+            *progress_callback,
+            cancel,
+        )
+    }
 
-    let audio_params = AudioCodecParameters::builder("aac")
-        .unwrap()
-        .channel_layout(&channel_layout)
-        .bit_rate(metadata.audio_bitrate)
-        .sample_rate(metadata.audio_sample_rate)
-        .build();
+    fn output_path(&self) -> Option<&Path> {
+        self.output.as_deref()
+    }
+}
 
-    // 2. Создаем фильтр для исправления аудио (FIX ДЛЯ WINDOWS)
-    let mut audio_bsf = match BitstreamFilter::from_name("aac_adtstoasc") {
-        Ok(bsf) => bsf,
-        Err(e) => {
-            progress_callback.on_error(anyhow!("Error creating audio filter: {}", e).into());
-            return;
+/// Writes the decrypted packet stream as it is, see VideoContainer::RawPackets.
+struct RawPacketsJob {
+    params: VideoMuxingJobParams,
+    output: Option<PathBuf>,
+}
+
+/// A packet in `<output basename>.index.json`, see DecryptOptions::raw_packet_index.
+#[derive(Serialize)]
+struct PacketIndexEntry {
+    /// The type byte of the packet header, 1 for video and 2 for audio.
+    #[serde(rename = "type")]
+    packet_type: u8,
+    pts_us: u64,
+    /// Of the packet data, without the header.
+    length: usize,
+    /// Where the packet's header starts in the output.
+    offset: u64,
+}
+
+impl DecryptingJob for RawPacketsJob {
+    fn run(&mut self, progress_callback: Box<&mut dyn ProgressCallback>, cancel: Arc<AtomicBool>) {
+        let params = &mut self.params;
+        progress_callback.set_total_file_size(params.total_file_size);
+        let _payload_reservation = match &params.options.resource_budget {
+            None => None,
+            Some(budget) => match budget.reserve(Resource::PayloadBuffers, 1, Some(&cancel)) {
+                Ok(Some(reservation)) => Some(reservation),
+                Ok(None) => return,
+                Err(e) => {
+                    progress_callback.on_error(e.into());
+                    return;
+                }
+            },
+        };
+        let metadata = &params.metadata;
+        let options = &params.options;
+        let info = MediaInfo {
+            timestamp: metadata.timestamp.clone(),
+            media_type: "video",
+            codec: if is_hevc(metadata.codec.as_deref()) {
+                "hevc"
+            } else {
+                "h264"
+            }
+            .to_owned(),
+            width: Some(metadata.width),
+            height: Some(metadata.height),
+            source_stem: options.source_stem.clone(),
+            device_label: params.device_label.clone(),
+            extra: metadata.extra.clone(),
+            extension: RAW_PACKETS_EXTENSION.to_owned(),
+        };
+        let file_name = output_file_name(&info, &options.naming, options.batch_names.as_ref());
+        if let Some(warning) =
+            batch_name_warning(&info, &options.naming, options.batch_names.as_ref())
+        {
+            warning::report(&mut **progress_callback, warning);
         }
-    };
-    // Фильтру нужно знать параметры входящего аудио
-    if let Err(e) = audio_bsf.set_parameters(CodecParameters::from(audio_params.clone())) {
-        progress_callback.on_error(anyhow!("Error setting audio filter params: {}", e).into());
-        return;
-    }
+        let out_path = &mut params.out_path;
+        let (mut out, pending_manifest) = match open_output(
+            out_path,
+            &file_name,
+            &metadata.timestamp,
+            options,
+            params.resume_manifest.as_ref(),
+            true,
+        ) {
+            Err(e) => {
+                progress_callback.on_error(job_error(e));
+                return;
+            }
+            Ok(opened) => opened,
+        };
+        let cleanup = RemoveIfCancelled::new(out_path, &cancel, options.resumable);
+        progress_callback.on_output_created(out_path);
+        let pending_sidecar = match params
+            .sidecar
+            .as_ref()
+            .map(|s| s.write_pending(out_path))
+            .transpose()
+        {
+            Ok(pending_sidecar) => pending_sidecar,
+            Err(e) => {
+                progress_callback.on_error(anyhow!("Error writing metadata sidecar: {}", e).into());
+                return;
+            }
+        };
 
-    let file_name = format!("{}.mp4", metadata.timestamp.replace(":", "-"));
-    let output_format = match OutputFormat::guess_from_file_name(&file_name) {
-        None => {
-            progress_callback.on_error(
-                anyhow!("Could not find output format for filename {}", file_name).into(),
-            );
+        let mut data = CancellableReader::new(&mut params.data, &cancel);
+        let mut packets = PacketReader::new(&mut data as &mut (dyn Read + Send))
+            .resync_on_error(options.resync_on_error)
+            .max_packet_len(options.max_packet_len);
+        let mut stats = StatsCollector::default();
+        let mut index = vec![];
+        let mut buf = vec![];
+        let truncation = loop {
+            let next = packets.next_header();
+            if let Some(skipped) = packets.take_skipped() {
+                let warning = DecryptWarning::CorruptDataSkipped {
+                    offset: skipped.offset,
+                    len: skipped.len,
+                };
+                warning::report(&mut **progress_callback, warning);
+            }
+            let header = match next {
+                None => break None,
+                Some(Ok(header)) => header,
+                Some(Err(e)) => break Some(e),
+            };
+            buf.resize(header.length, 0);
+            if let Err(e) = packets.read_data(&mut buf) {
+                break Some(e);
+            }
+            let offset = out.position();
+            let header_bytes = header.to_bytes();
+            if let Err(e) = out
+                .write_all(&header_bytes)
+                .and_then(|()| out.write_all(&buf))
+            {
+                progress_callback.on_error(e.into());
+                return;
+            }
+            if options.raw_packet_index {
+                index.push(PacketIndexEntry {
+                    packet_type: header_bytes[0],
+                    pts_us: header.pts_us,
+                    length: header.length,
+                    offset,
+                });
+            }
+            progress_callback.on_progress(params.input_position.get());
+            let packet_type = match header.kind {
+                PacketKind::Video => PacketType::Video,
+                PacketKind::Audio => PacketType::Audio,
+                PacketKind::Unknown(_) => continue,
+            };
+            if let Ok(pts_us) = i64::try_from(header.pts_us) {
+                stats.add(packet_type, pts_us, header.length);
+                let (stream, count, bytes) = stats.stream_totals(packet_type);
+                progress_callback.on_stream_progress(stream, count, bytes, header.pts_us);
+            }
+        };
+        if cancel.load(Ordering::Relaxed) {
             return;
         }
-        Some(o) => o,
-    };
-    out_path.push(file_name);
-    let out = match File::create(&out_path) {
-        Err(e) => {
-            progress_callback.on_error(e.into());
-            return;
+        if let Some(e) = truncation {
+            if !(options.finalize_on_truncation && e.is_truncation()) {
+                progress_callback.on_error(e.into_job_error());
+                return;
+            }
+            warning::report(
+                &mut **progress_callback,
+                DecryptWarning::TruncatedStream {
+                    processed: packets.position(),
+                    reason: e.to_string(),
+                },
+            );
+            progress_callback.on_truncated(params.input_position.get());
         }
-        Ok(f) => f,
-    };
-    let io = IO::from_seekable_write_stream(out);
-    let mut muxer_builder = Muxer::builder().interleaved(true);
+        progress_callback.on_progress(params.input_position.get());
 
-    let video_stream_index = match muxer_builder.add_stream(&CodecParameters::from(video_params)) {
-        Ok(i) => i,
-        Err(e) => {
-             progress_callback.on_error(anyhow!("Error adding video stream: {}", e).into());
-             return;
+        if options.raw_packet_index {
+            if let Err(e) = write_packet_index(&out_path.with_extension("index.json"), &index) {
+                progress_callback.on_error(anyhow!("Error writing packet index: {}", e).into());
+                return;
+            }
+        }
+        let digest = match out.digest(out_path) {
+            Ok(digest) => digest,
+            Err(e) => {
+                progress_callback.on_error(e.into());
+                return;
+            }
+        };
+        if let Some(digest) = &digest {
+            progress_callback.on_output_digest(digest);
+        }
+        if options.preserve_timestamps {
+            timestamp::set_mtime(out_path, &metadata.timestamp);
+        }
+        if options.fsync_on_complete {
+            if let Err(e) = sync_output(out_path) {
+                progress_callback.on_error(e.into());
+                return;
+            }
+        }
+        if let Some(pending_sidecar) = pending_sidecar {
+            if let Err(e) = pending_sidecar.commit(digest.as_ref()) {
+                progress_callback.on_error(anyhow!("Error writing metadata sidecar: {}", e).into());
+                return;
+            }
+        }
+        if let Some(pending_manifest) = pending_manifest {
+            if let Err(e) = pending_manifest.complete() {
+                progress_callback.on_error(anyhow!("Error removing job manifest: {}", e).into());
+                return;
+            }
+        }
+        cleanup.keep();
+        self.output = Some(out_path.clone());
+        progress_callback.on_stats(&DecryptStats::Video(stats.finish(metadata)));
+        progress_callback.on_complete();
+    }
+
+    fn output_path(&self) -> Option<&Path> {
+        self.output.as_deref()
+    }
+}
+
+fn write_packet_index(path: &Path, index: &[PacketIndexEntry]) -> Result<()> {
+    let mut json = serde_json::to_vec(index)?;
+    json.push(b'\n');
+    fs::write(path, json)?;
+    Ok(())
+}
+
+/// One of the files mux_video() reads packets from, in order.
+struct Segment<'a> {
+    data: &'a mut (dyn Read + Send),
+    total_file_size: u64,
+    input_position: &'a InputPosition,
+}
+
+/// Muxes the packets of `segments` into one output, named and written as described by
+/// `metadata`, the metadata of the first segment.
+#[allow(clippy::too_many_arguments)]
+fn mux_video(
+    segments: &mut [Segment<'_>],
+    metadata: &VideoMetadata,
+    out_path: &mut PathBuf,
+    output: &mut Option<PathBuf>,
+    options: &DecryptOptions,
+    sidecar: Option<&Sidecar>,
+    resume_manifest: Option<&ResumeManifest>,
+    device_label: Option<&str>,
+    progress_callback: &mut dyn ProgressCallback,
+    cancel: Arc<AtomicBool>,
+) {
+    let total_file_size: u64 = segments.iter().map(|segment| segment.total_file_size).sum();
+    let faststart = options.faststart && options.container == VideoContainer::Mp4;
+    // with faststart, the last 5% of progress are reserved for rewriting the file
+    let mux_share = |progress: u64| {
+        if faststart {
+            progress * 95 / 100
+        } else {
+            progress
         }
     };
+    let codec_name = if is_hevc(metadata.codec.as_deref()) {
+        "hevc"
+    } else {
+        "h264"
+    };
 
-    let audio_stream_index = match muxer_builder.add_stream(&CodecParameters::from(audio_params)) {
-        Ok(i) => i,
+    let extension = match options.container {
+        VideoContainer::Mkv => "mkv",
+        _ => "mp4",
+    };
+    let info = MediaInfo {
+        timestamp: metadata.timestamp.clone(),
+        media_type: "video",
+        codec: codec_name.to_owned(),
+        width: Some(metadata.width),
+        height: Some(metadata.height),
+        source_stem: options.source_stem.clone(),
+        device_label: device_label.map(str::to_owned),
+        extra: metadata.extra.clone(),
+        extension: extension.to_owned(),
+    };
+    let file_name = output_file_name(&info, &options.naming, options.batch_names.as_ref());
+    if let Some(warning) = batch_name_warning(&info, &options.naming, options.batch_names.as_ref())
+    {
+        warning::report(&mut *progress_callback, warning);
+    }
+    let (out, pending_manifest) = match open_output(
+        out_path,
+        &file_name,
+        &metadata.timestamp,
+        options,
+        resume_manifest,
+        options.fragmented,
+    ) {
         Err(e) => {
-             progress_callback.on_error(anyhow!("Error adding audio stream: {}", e).into());
-             return;
+            progress_callback.on_error(job_error(e));
+            return;
         }
+        Ok(opened) => opened,
     };
-
-    // 3. Исправление поворота (FIX ДЛЯ ORIENTATION)
-    // Преобразуем число в строку явно
-    muxer_builder.streams_mut()[video_stream_index]
-        .set_metadata("rotate", &metadata.rotation.to_string());
-
-    let mut muxer = match muxer_builder.build(io, output_format) {
+    let cleanup = RemoveIfCancelled::new(out_path, &cancel, options.resumable);
+    progress_callback.on_output_created(out_path);
+    let pending_sidecar = match sidecar.map(|s| s.write_pending(out_path)).transpose() {
+        Ok(pending_sidecar) => pending_sidecar,
         Err(e) => {
-            progress_callback.on_error(e.into());
+            progress_callback.on_error(anyhow!("Error writing metadata sidecar: {}", e).into());
             return;
         }
-        Ok(m) => m,
     };
 
-    let mut packet_header: [u8; 13] = [0; 13];
-    let mut first_pts: Option<i64> = None;
-    let mut progress: u64 = 0;
-
-    while let Ok(()) = data.read_exact(&mut packet_header) {
-        if cancel.load(std::sync::atomic::Ordering::Relaxed) {
-            return;
+    #[cfg(feature = "thumbnail")]
+    let mut thumbnailer = options
+        .extract_thumbnail
+        .clone()
+        .map(|spec| Thumbnailer::new(codec_name, spec, metadata.rotation.unwrap_or(0)));
+    // the decoded frame the thumbnail is made from, yuv420p
+    #[cfg(feature = "thumbnail")]
+    let _thumbnail_reservation = match (&options.resource_budget, &thumbnailer) {
+        (Some(budget), Some(_)) => {
+            let frame_bytes = (metadata.width * metadata.height * 3 / 2) as u64;
+            match budget.reserve(Resource::BufferBytes, frame_bytes, Some(&cancel)) {
+                Ok(Some(reservation)) => Some(reservation),
+                Ok(None) => return,
+                Err(e) => {
+                    progress_callback.on_error(e.into());
+                    return;
+                }
+            }
         }
-        let packet_type = match packet_header[0] {
-            1 => PacketType::Video,
-            2 => PacketType::Audio,
-            e => {
-                warn!("Unknown packet type {}", e);
-                continue;
+        _ => None,
+    };
+    let mut stats = StatsCollector::default();
+    let packed = match options.video_backend {
+        #[cfg(feature = "video")]
+        VideoBackend::FFmpeg => match FfmpegPacker::new(
+            out,
+            metadata,
+            Some(codec_name),
+            &file_name,
+            options,
+            progress_callback,
+            &cancel,
+        ) {
+            #[allow(unused_mut)]
+            Ok(mut packer) => {
+                #[cfg(feature = "thumbnail")]
+                {
+                    packer.thumbnailer = thumbnailer.as_mut();
+                }
+                pack_segments(
+                    packer,
+                    segments,
+                    metadata,
+                    options,
+                    &mux_share,
+                    &mut stats,
+                    progress_callback,
+                    &cancel,
+                )
             }
-        };
-        let pts = LittleEndian::read_u64(&packet_header[1..9]);
-        let packet_length = LittleEndian::read_u32(&packet_header[9..13]) as usize;
-        let mut packet_data = vec![0; packet_length];
-        match data.read_exact(&mut packet_data) {
             Err(e) => {
                 progress_callback.on_error(e.into());
                 return;
             }
-            Ok(()) => {}
+        },
+        #[cfg(feature = "rust-mp4")]
+        VideoBackend::RustMp4 => match RustMp4Packer::new(out, metadata, false) {
+            Ok(packer) => pack_segments(
+                packer,
+                segments,
+                metadata,
+                options,
+                &mux_share,
+                &mut stats,
+                progress_callback,
+                &cancel,
+            ),
+            Err(e) => {
+                progress_callback.on_error(e.into());
+                return;
+            }
+        },
+        // validate() rejects backends that aren't built
+        #[allow(unreachable_patterns)]
+        backend => {
+            progress_callback.on_error(anyhow!("{:?} backend not built", backend).into());
+            return;
+        }
+    };
+    let mut out = match packed {
+        Some(out) => out,
+        None => return,
+    };
+
+    let digest = if faststart {
+        // the rewritten file is hashed from scratch, and it can't be replaced while still open
+        if let Err(e) = out.flush() {
+            progress_callback.on_error(e.into());
+            return;
+        }
+        drop(out);
+        let read: u64 = segments
+            .iter()
+            .map(|segment| segment.input_position.get())
+            .sum();
+        let reserved_from = mux_share(read);
+        let mut on_rewrite_progress = |done: u64, total: u64| {
+            let rewrite_share = total_file_size.saturating_sub(reserved_from);
+            progress_callback.on_progress(reserved_from + rewrite_share * done / total.max(1));
         };
-        if first_pts.is_none() {
-            first_pts = Some(pts as i64);
+        match mp4::faststart(out_path, &mut on_rewrite_progress, &cancel) {
+            Err(e) => {
+                progress_callback.on_error(anyhow!("Error moving moov box: {}", e).into());
+                return;
+            }
+            Ok(_) if cancel.load(Ordering::Relaxed) => return,
+            Ok(_) => {}
         }
+        options
+            .output_digest
+            .map(|algo| hash_file(out_path, algo))
+            .transpose()
+    } else {
+        out.digest(out_path)
+    };
+    let digest = match digest {
+        Ok(digest) => digest,
+        Err(e) => {
+            progress_callback.on_error(e.into());
+            return;
+        }
+    };
+    if let Some(digest) = &digest {
+        progress_callback.on_output_digest(digest);
+    }
+    let stats = stats.finish(metadata);
+    if options.check_output && options.container == VideoContainer::Mp4 {
+        let min_tracks = if stats.audio_packets > 0 { 2 } else { 1 };
+        if !check_output(out_path, min_tracks, options, progress_callback) {
+            return;
+        }
+    }
+    #[cfg(feature = "thumbnail")]
+    if let Some(thumbnailer) = thumbnailer {
+        thumbnailer.finish(out_path, progress_callback);
+    }
+    if options.preserve_timestamps {
+        timestamp::set_mtime(out_path, &metadata.timestamp);
+    }
+    if options.fsync_on_complete {
+        if let Err(e) = sync_output(out_path) {
+            progress_callback.on_error(e.into());
+            return;
+        }
+    }
+    if let Some(pending_sidecar) = pending_sidecar {
+        if let Err(e) = pending_sidecar.commit(digest.as_ref()) {
+            progress_callback.on_error(anyhow!("Error writing metadata sidecar: {}", e).into());
+            return;
+        }
+    }
+    if let Some(pending_manifest) = pending_manifest {
+        if let Err(e) = pending_manifest.complete() {
+            progress_callback.on_error(anyhow!("Error removing job manifest: {}", e).into());
+            return;
+        }
+    }
+    cleanup.keep();
+    *output = Some(out_path.clone());
+    progress_callback.on_stats(&DecryptStats::Video(stats));
+    progress_callback.on_complete();
+}
 
-        let packet = PacketMut::from(packet_data)
-            .with_pts(Timestamp::from_micros(pts as i64 - first_pts.unwrap()))
-            .with_stream_index(match packet_type {
-                PacketType::Video => video_stream_index as usize,
-                PacketType::Audio => audio_stream_index as usize,
-            })
-            .freeze();
+/// DecryptOptions::check_output of a finished MP4 or M4A. False if the job has to fail, the
+/// error has been reported then.
+pub(crate) fn check_output(
+    out_path: &Path,
+    min_tracks: usize,
+    options: &DecryptOptions,
+    progress_callback: &mut dyn ProgressCallback,
+) -> bool {
+    let checked = File::open(out_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|file| mp4::check_structure(&mut BufReader::new(file), min_tracks));
+    let reason = match checked {
+        Ok(()) => return true,
+        Err(e) => e.to_string(),
+    };
+    if options.lenient {
+        warning::report(progress_callback, DecryptWarning::CorruptOutput { reason });
+        return true;
+    }
+    progress_callback.on_error(
+        Error::CorruptOutput {
+            path: out_path.to_path_buf(),
+            reason,
+        }
+        .into(),
+    );
+    false
+}
+
+/// Writes the packets of a video into the output file, see VideoBackend.
+pub(crate) trait PackerBackend {
+    /// What packets are read into, so they can be handed over without a copy.
+    type Buffer: Send;
+
+    fn alloc(len: usize) -> Self::Buffer;
+    fn buffer_mut(buffer: &mut Self::Buffer) -> &mut [u8];
+    /// Adds a packet. `pts_us` counts from the first packet of the recording and increases
+    /// within each stream.
+    fn push(&mut self, packet_type: PacketType, pts_us: i64, data: Self::Buffer) -> Result<()>;
+    /// Writes what is left and the end of the file, returns the file for hashing.
+    fn finish(self) -> Result<OutputFile>;
+}
+
+/// Reads the packets and pushes them to `packer` until the stream ends, then finishes the file.
+/// Progress is `mux_share` of `input_position`. Returns the output file, or None if the job
+/// failed or was cancelled.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn pack<P: PackerBackend>(
+    mut packer: P,
+    packets: PacketReader<&mut (dyn Read + Send)>,
+    options: &DecryptOptions,
+    input_position: &InputPosition,
+    mux_share: &dyn Fn(u64) -> u64,
+    stats: &mut StatsCollector,
+    progress_callback: &mut dyn ProgressCallback,
+    cancel: &AtomicBool,
+) -> Option<OutputFile> {
+    let segment = SegmentPosition {
+        input_position,
+        progress_offset: 0,
+        pts_offset: 0,
+    };
+    feed(
+        &mut packer,
+        packets,
+        &segment,
+        options,
+        mux_share,
+        stats,
+        progress_callback,
+        cancel,
+    )?;
+    finish_packer(packer, progress_callback, cancel)
+}
+
+/// Like pack(), reading the segments one after the other into the same output. Each segment's
+/// PTS continue one frame interval after the last packet of the segment before it, the frame
+/// interval is from `metadata` or estimated from the packets. Progress counts the segments'
+/// sizes together.
+#[allow(clippy::too_many_arguments)]
+fn pack_segments<P: PackerBackend>(
+    mut packer: P,
+    segments: &mut [Segment<'_>],
+    metadata: &VideoMetadata,
+    options: &DecryptOptions,
+    mux_share: &dyn Fn(u64) -> u64,
+    stats: &mut StatsCollector,
+    progress_callback: &mut dyn ProgressCallback,
+    cancel: &AtomicBool,
+) -> Option<OutputFile> {
+    let mut progress_offset = 0;
+    for (index, segment) in segments.iter_mut().enumerate() {
+        let pts_offset = match index {
+            0 => 0,
+            _ => stats.next_segment_start(metadata),
+        };
+        let mut data = CancellableReader::new(&mut *segment.data, cancel);
+        let packets = PacketReader::new(&mut data as &mut (dyn Read + Send))
+            .resync_on_error(options.resync_on_error)
+            .max_packet_len(options.max_packet_len);
+        let position = SegmentPosition {
+            input_position: segment.input_position,
+            progress_offset,
+            pts_offset,
+        };
+        feed(
+            &mut packer,
+            packets,
+            &position,
+            options,
+            mux_share,
+            stats,
+            progress_callback,
+            cancel,
+        )?;
+        progress_offset += segment.total_file_size;
+    }
+    finish_packer(packer, progress_callback, cancel)
+}
+
+/// Where the packets fed to the packer are within the whole output.
+struct SegmentPosition<'a> {
+    input_position: &'a InputPosition,
+    /// The sizes of the segments before this one, added to `input_position` for progress.
+    progress_offset: u64,
+    /// Added to the PTS of the segment's packets.
+    pts_offset: i64,
+}
+
+impl SegmentPosition<'_> {
+    fn progress(&self) -> u64 {
+        self.progress_offset + self.input_position.get()
+    }
+}
+
+/// Pushes the packets to `packer` until the stream ends, reporting a truncated stream. None if
+/// the job failed or was cancelled.
+#[allow(clippy::too_many_arguments)]
+fn feed<P: PackerBackend>(
+    packer: &mut P,
+    packets: PacketReader<&mut (dyn Read + Send)>,
+    segment: &SegmentPosition<'_>,
+    options: &DecryptOptions,
+    mux_share: &dyn Fn(u64) -> u64,
+    stats: &mut StatsCollector,
+    progress_callback: &mut dyn ProgressCallback,
+    cancel: &AtomicBool,
+) -> Option<()> {
+    let mut source = PacketSource::<_, P>::new(packets, options, cancel);
+    let end = if options.pipelined {
+        if source.resource_budget.is_none() {
+            let max_bytes = PIPELINE_BUFFER_BYTES.max(options.max_packet_len as u64);
+            source.resource_budget = Some(ResourceBudget::default().max_buffer_bytes(max_bytes));
+        }
+        thread::scope(|scope| {
+            let (sender, receiver) = mpsc::sync_channel(PIPELINE_DEPTH);
+            let source = &mut source;
+            scope.spawn(move || {
+                while let Some(event) = source.next_event() {
+                    if cancel.load(Ordering::Relaxed) || sender.send(event).is_err() {
+                        break;
+                    }
+                }
+            });
+            // returning drops the receiver, which stops the reader thread if it is still running
+            mux_packets(
+                receiver.into_iter(),
+                packer,
+                segment,
+                mux_share,
+                stats,
+                progress_callback,
+                cancel,
+            )
+        })
+    } else {
+        mux_packets(
+            iter::from_fn(|| source.next_event()),
+            packer,
+            segment,
+            mux_share,
+            stats,
+            progress_callback,
+            cancel,
+        )
+    };
+    let (truncation, position) = end?;
+    if let Some(e) = truncation {
+        warning::report(
+            progress_callback,
+            DecryptWarning::TruncatedStream {
+                processed: position,
+                reason: e.to_string(),
+            },
+        );
+        progress_callback.on_truncated(segment.progress());
+    }
+    Some(())
+}
 
-        // 4. Обработка пакетов с учетом фильтра для Аудио
+/// Finishes the file once every packet was pushed, None if that failed or the job was
+/// cancelled.
+fn finish_packer<P: PackerBackend>(
+    packer: P,
+    progress_callback: &mut dyn ProgressCallback,
+    cancel: &AtomicBool,
+) -> Option<OutputFile> {
+    match packer.finish() {
+        Ok(_) if cancel.load(Ordering::Relaxed) => None,
+        Ok(out) => Some(out),
+        Err(e) => {
+            if !cancel.load(Ordering::Relaxed) {
+                progress_callback.on_error(e.into());
+            }
+            None
+        }
+    }
+}
+
+/// Pushes the packets to the packer until the stream ends. Returns the truncation error, if the
+/// stream was truncated, and the number of bytes of the stream read, or None if the job failed
+/// or was cancelled.
+fn mux_packets<P: PackerBackend>(
+    events: impl Iterator<Item = ReadEvent<P::Buffer>>,
+    packer: &mut P,
+    segment: &SegmentPosition<'_>,
+    mux_share: &dyn Fn(u64) -> u64,
+    stats: &mut StatsCollector,
+    progress_callback: &mut dyn ProgressCallback,
+    cancel: &AtomicBool,
+) -> Option<(Option<PacketError>, u64)> {
+    for event in events {
+        if cancel.load(Ordering::Relaxed) {
+            return None;
+        }
+        match event {
+            ReadEvent::Packet {
+                mut data,
+                packet_type,
+                pts_us,
+                reservation,
+            } => {
+                let pts_us = pts_us + segment.pts_offset;
+                stats.add(packet_type, pts_us, P::buffer_mut(&mut data).len());
+                if let Err(e) = packer.push(packet_type, pts_us, data) {
+                    progress_callback.on_error(e.into());
+                    return None;
+                }
+                drop(reservation);
+                progress_callback.on_progress(mux_share(segment.progress()));
+                let (stream, packets, bytes) = stats.stream_totals(packet_type);
+                progress_callback.on_stream_progress(stream, packets, bytes, pts_us.max(0) as u64);
+            }
+            ReadEvent::Warning(warning) => warning::report(progress_callback, warning),
+            ReadEvent::Skipped => progress_callback.on_progress(mux_share(segment.progress())),
+            ReadEvent::End {
+                truncation,
+                position,
+            } => {
+                progress_callback.on_progress(mux_share(segment.progress()));
+                return Some((truncation, position));
+            }
+            ReadEvent::Error(e) => {
+                progress_callback.on_error(e.into_job_error());
+                return None;
+            }
+            ReadEvent::BudgetExceeded(e) => {
+                progress_callback.on_error(e.into());
+                return None;
+            }
+        }
+    }
+    // only a reader thread stopped by cancellation ends without End or Error
+    None
+}
+
+/// Video packets whose PTS the frame rate is estimated from, when the metadata has none.
+const FRAME_RATE_ESTIMATE_PACKETS: u64 = 120;
+
+/// Between stitched segments if the frame rate is unknown, 30 fps.
+const DEFAULT_FRAME_INTERVAL_US: i64 = 33_333;
+
+/// Adds up the VideoStats of the packets pushed to the packer.
+#[derive(Default)]
+pub(crate) struct StatsCollector {
+    stats: VideoStats,
+    first_video_pts: Option<i64>,
+    last_video_pts: i64,
+    /// PTS of the last of the first FRAME_RATE_ESTIMATE_PACKETS video packets.
+    estimate_end_pts: i64,
+    first_audio_pts: Option<i64>,
+    last_audio_pts: i64,
+}
+
+impl StatsCollector {
+    fn add(&mut self, packet_type: PacketType, pts_us: i64, len: usize) {
+        let stats = &mut self.stats;
         match packet_type {
-            PacketType::Audio => {
-                // Прогоняем аудио через фильтр aac_adtstoasc
-                if let Err(e) = audio_bsf.push(packet) {
-                     progress_callback.on_error(anyhow!("Error pushing to audio filter: {}", e).into());
-                     return;
+            PacketType::Video => {
+                stats.video_packets += 1;
+                stats.video_bytes += len as u64;
+                stats.max_video_packet = stats.max_video_packet.max(len as u64);
+                self.first_video_pts.get_or_insert(pts_us);
+                self.last_video_pts = pts_us;
+                if stats.video_packets <= FRAME_RATE_ESTIMATE_PACKETS {
+                    self.estimate_end_pts = pts_us;
                 }
-                // Забираем отфильтрованные пакеты (их может быть несколько или 0)
+            }
+            PacketType::Audio => {
+                stats.audio_packets += 1;
+                stats.audio_bytes += len as u64;
+                self.first_audio_pts.get_or_insert(pts_us);
+                self.last_audio_pts = pts_us;
+            }
+        }
+    }
+
+    /// The stream of `packet_type` and its packets and bytes so far.
+    fn stream_totals(&self, packet_type: PacketType) -> (PacketKind, u64, u64) {
+        match packet_type {
+            PacketType::Video => (
+                PacketKind::Video,
+                self.stats.video_packets,
+                self.stats.video_bytes,
+            ),
+            PacketType::Audio => (
+                PacketKind::Audio,
+                self.stats.audio_packets,
+                self.stats.audio_bytes,
+            ),
+        }
+    }
+
+    /// The stats of an audio recording, see decrypt_audio.
+    pub(crate) fn finish_audio(self) -> AudioStats {
+        AudioStats {
+            duration_us: self
+                .first_audio_pts
+                .map_or(0, |first| (self.last_audio_pts - first) as u64),
+            packets: self.stats.audio_packets,
+            bytes: self.stats.audio_bytes,
+        }
+    }
+
+    fn finish(mut self, metadata: &VideoMetadata) -> VideoStats {
+        if let Some(first) = self.first_video_pts {
+            self.stats.duration_us = (self.last_video_pts - first) as u64;
+        }
+        self.stats.width = metadata.width;
+        self.stats.height = metadata.height;
+        self.stats.frame_rate = match metadata.frame_rate.and_then(FrameRate::from_f64) {
+            Some(rate) => Some(rate),
+            None => self.estimate_frame_rate(),
+        };
+        self.stats
+    }
+
+    /// The PTS the next segment of a stitched recording starts at: one frame interval after the
+    /// last packet so far.
+    fn next_segment_start(&self, metadata: &VideoMetadata) -> i64 {
+        let frame_rate = metadata
+            .frame_rate
+            .and_then(FrameRate::from_f64)
+            .or_else(|| self.estimate_frame_rate());
+        let interval = frame_rate.map_or(DEFAULT_FRAME_INTERVAL_US, |rate| {
+            rate.den as i64 * 1_000_000 / rate.num as i64
+        });
+        self.last_video_pts.max(self.last_audio_pts) + interval.max(MIN_PTS_INCREMENT_US)
+    }
+
+    /// The average frame rate of the first FRAME_RATE_ESTIMATE_PACKETS video packets.
+    fn estimate_frame_rate(&self) -> Option<FrameRate> {
+        let span_us = self.estimate_end_pts - self.first_video_pts?;
+        let intervals = self.stats.video_packets.min(FRAME_RATE_ESTIMATE_PACKETS) - 1;
+        if intervals == 0 || span_us <= 0 {
+            return None;
+        }
+        FrameRate::from_f64(intervals as f64 * 1_000_000.0 / span_us as f64)
+    }
+}
+
+/// The FFmpeg backend, for MP4 and Matroska.
+#[cfg(feature = "video")]
+pub(crate) struct FfmpegPacker<'a> {
+    muxer: Muxer<OutputFile>,
+    audio_filter: AudioFilter,
+    /// None for audio recordings, whose video packets are skipped.
+    video_stream_index: Option<usize>,
+    audio_stream_index: usize,
+    #[cfg(feature = "thumbnail")]
+    thumbnailer: Option<&'a mut Thumbnailer>,
+    #[cfg(feature = "transcode")]
+    transcoder: Option<Transcoder>,
+    cancel: &'a AtomicBool,
+}
+
+#[cfg(feature = "video")]
+impl<'a> FfmpegPacker<'a> {
+    /// Without `video_codec`, the output only has the audio stream.
+    pub(crate) fn new(
+        out: OutputFile,
+        metadata: &VideoMetadata,
+        video_codec: Option<&str>,
+        file_name: &str,
+        options: &DecryptOptions,
+        progress_callback: &mut dyn ProgressCallback,
+        cancel: &'a AtomicBool,
+    ) -> Result<Self> {
+        let channel_layout = match channel_layout(
+            metadata.audio_channel_layout.as_deref(),
+            metadata.audio_channel_count,
+        ) {
+            (layout, None) => layout,
+            (layout, Some(warning)) => {
+                warning::report(progress_callback, warning);
+                layout
+            }
+        };
+
+        let audio_params = match metadata.audio_codec {
+            // without it, aac_adtstoasc takes the AudioSpecificConfig from the first ADTS header
+            AudioCodec::Aac => AudioCodecParameters::builder("aac")
+                .unwrap()
+                .extradata(metadata.audio_extradata()),
+            AudioCodec::Opus => {
+                let channel_count = match metadata.audio_channel_count {
+                    n @ 1..=2 => n as u8,
+                    n => bail!("Opus audio with {} channels is not supported", n),
+                };
+                AudioCodecParameters::builder("opus")
+                    .unwrap()
+                    .extradata(Some(opus_head(channel_count, metadata.audio_sample_rate)))
+            }
+        }
+        .channel_layout(&channel_layout)
+        .bit_rate(metadata.audio_bitrate)
+        .sample_rate(metadata.audio_sample_rate)
+        .build();
+
+        let audio_filter = match metadata.audio_codec {
+            AudioCodec::Aac => AudioFilter::Undecided(CodecParameters::from(audio_params.clone())),
+            AudioCodec::Opus => AudioFilter::Passthrough,
+        };
+
+        let output_format = match OutputFormat::guess_from_file_name(file_name) {
+            None => bail!("Could not find output format for filename {}", file_name),
+            Some(o) => o,
+        };
+        let io = if options.fragmented {
+            IO::from_write_stream(out)
+        } else {
+            IO::from_seekable_write_stream(out)
+        };
+        let creation_time = match timestamp::parse_timestamp(&metadata.timestamp) {
+            Ok(t) => Some(timestamp::to_creation_time(&t)),
+            Err(e) => {
+                warning::report(
+                    progress_callback,
+                    DecryptWarning::InvalidTimestamp {
+                        timestamp: metadata.timestamp.clone(),
+                        reason: e.to_string(),
+                    },
+                );
+                None
+            }
+        };
+        let mut muxer_builder = Muxer::builder().interleaved(true).set_metadata(
+            "encoder",
+            concat!("libcryptocam ", env!("CARGO_PKG_VERSION")),
+        );
+        if let Some(creation_time) = &creation_time {
+            muxer_builder = muxer_builder.set_metadata("creation_time", creation_time);
+        }
+        if let Some(location) = metadata.gps_location() {
+            // the first is what FFmpeg writes for MP4 as ©xyz, the second for MOV players
+            let location = location.to_iso6709();
+            muxer_builder = muxer_builder
+                .set_metadata("location", &location)
+                .set_metadata("com.apple.quicktime.location.ISO6709", &location);
+        }
+        if options.fragmented && options.container == VideoContainer::Mp4 {
+            // the moov box goes first without any samples, which follow in moof boxes
+            muxer_builder =
+                muxer_builder.set_option("movflags", "frag_keyframe+empty_moov+default_base_moof");
+        }
+
+        #[cfg(feature = "transcode")]
+        let mut transcoder = match (&options.transcode, video_codec) {
+            (Some(spec), Some(codec_name)) => Some(Transcoder::new(
+                codec_name,
+                spec,
+                metadata.width,
+                metadata.height,
+                metadata.video_bitrate,
+                &metadata.timestamp,
+            )?),
+            _ => None,
+        };
+        let video_stream_index = match video_codec {
+            Some(codec_name) => {
+                // without it, the muxer takes the parameter sets from the first keyframe
+                let video_params = VideoCodecParameters::builder(codec_name)
+                    .unwrap()
+                    .width(metadata.width)
+                    .height(metadata.height)
+                    .bit_rate(metadata.video_bitrate)
+                    .extradata(metadata.video_extradata())
+                    .build();
+                #[cfg(feature = "transcode")]
+                let video_params = match &transcoder {
+                    Some(transcoder) => transcoder.codec_parameters(),
+                    None => CodecParameters::from(video_params),
+                };
+                #[cfg(not(feature = "transcode"))]
+                let video_params = CodecParameters::from(video_params);
+                let index = muxer_builder
+                    .add_stream(&video_params)
+                    .map_err(|e| anyhow!("Error adding video stream: {}", e))?;
+                Some(index)
+            }
+            None => None,
+        };
+        let audio_stream_index = muxer_builder
+            .add_stream(&CodecParameters::from(audio_params))
+            .map_err(|e| anyhow!("Error adding audio stream: {}", e))?;
+
+        #[cfg(feature = "transcode")]
+        if let (Some(transcoder), Some(index)) = (&mut transcoder, video_stream_index) {
+            transcoder.set_stream_index(index);
+        }
+        if let Some(index) = video_stream_index {
+            let stream = &mut muxer_builder.streams_mut()[index];
+            // the packets' PTS are in microseconds, MP4 takes the track timescale from this
+            stream.set_time_base(TimeBase::MICROSECONDS);
+            // the average only, variable frame rate recordings aren't made constant
+            if let Some(rate) = metadata.frame_rate.and_then(FrameRate::from_f64) {
+                stream.set_avg_frame_rate(TimeBase::new(rate.num as i32, rate.den as i32));
+            }
+            if let Some(rotation) = metadata.rotation {
+                stream.set_metadata("rotate", rotation.to_string());
+            }
+        }
+        if let Some(creation_time) = &creation_time {
+            for stream in muxer_builder.streams_mut() {
+                stream.set_metadata("creation_time", creation_time);
+            }
+        }
+
+        Ok(FfmpegPacker {
+            muxer: muxer_builder.build(io, output_format)?,
+            audio_filter,
+            video_stream_index,
+            audio_stream_index,
+            #[cfg(feature = "thumbnail")]
+            thumbnailer: None,
+            #[cfg(feature = "transcode")]
+            transcoder,
+            cancel,
+        })
+    }
+}
+
+#[cfg(feature = "video")]
+impl PackerBackend for FfmpegPacker<'_> {
+    type Buffer = PacketMut;
+
+    // read straight into memory owned by ffmpeg, saving an allocation and a copy per packet
+    fn alloc(len: usize) -> PacketMut {
+        PacketMut::new(len)
+    }
+
+    fn buffer_mut(buffer: &mut PacketMut) -> &mut [u8] {
+        buffer.data_mut()
+    }
+
+    fn push(&mut self, packet_type: PacketType, pts_us: i64, data: PacketMut) -> Result<()> {
+        let stream_index = match (packet_type, self.video_stream_index) {
+            (PacketType::Video, Some(index)) => index,
+            (PacketType::Video, None) => {
+                debug!("Skipping video packet of an audio recording");
+                return Ok(());
+            }
+            (PacketType::Audio, _) => self.audio_stream_index,
+        };
+        let packet = data
+            .with_pts(Timestamp::from_micros(pts_us))
+            .with_stream_index(stream_index)
+            .freeze();
+        if packet_type == PacketType::Video {
+            #[cfg(feature = "thumbnail")]
+            if let Some(thumbnailer) = &mut self.thumbnailer {
+                thumbnailer.push(&packet);
+            }
+            #[cfg(feature = "transcode")]
+            if let Some(transcoder) = &mut self.transcoder {
+                return transcoder.push(Some(packet), &mut self.muxer, self.cancel);
+            }
+            self.muxer.push(packet)?;
+            return Ok(());
+        }
+        if let AudioFilter::Undecided(params) = &self.audio_filter {
+            self.audio_filter = AudioFilter::for_first_packet(params, packet.data())?;
+        }
+        match &mut self.audio_filter {
+            AudioFilter::Adts(audio_bsf) => {
+                audio_bsf
+                    .push(packet)
+                    .map_err(|e| anyhow!("Error pushing to audio filter: {}", e))?;
                 while let Ok(Some(filtered_packet)) = audio_bsf.take() {
-                    if let Err(e) = muxer.push(filtered_packet) {
-                        progress_callback.on_error(e.into());
+                    self.muxer.push(filtered_packet)?;
+                }
+            }
+            _ => self.muxer.push(packet)?,
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<OutputFile> {
+        #[cfg(feature = "transcode")]
+        if let Some(transcoder) = &mut self.transcoder {
+            transcoder.push(None, &mut self.muxer, self.cancel)?;
+        }
+        if self.cancel.load(Ordering::Relaxed) {
+            return Err(crate::error::Error::Cancelled.into());
+        }
+        if let AudioFilter::Adts(audio_bsf) = &mut self.audio_filter {
+            audio_bsf
+                .flush()
+                .map_err(|e| anyhow!("Error flushing audio filter: {}", e))?;
+            while let Ok(Some(filtered_packet)) = audio_bsf.take() {
+                self.muxer.push(filtered_packet)?;
+            }
+        }
+        self.muxer.flush()?;
+        // closing writes the trailer, after which the file is complete
+        Ok(self.muxer.close()?.into_stream())
+    }
+}
+
+/// How AAC audio packets get to the muxer.
+#[cfg(feature = "video")]
+enum AudioFilter {
+    /// No audio packet has been read yet.
+    Undecided(CodecParameters),
+    /// The phone writes ADTS framed AAC, MP4 wants the AudioSpecificConfig instead.
+    Adts(BitstreamFilter),
+    /// Opus, or AAC without ADTS headers as written by some beta versions of the app.
+    Passthrough,
+}
+
+#[cfg(feature = "video")]
+impl AudioFilter {
+    /// Decides by the first audio packet whether the file's AAC is ADTS framed.
+    fn for_first_packet(params: &CodecParameters, data: &[u8]) -> Result<AudioFilter> {
+        let is_adts = data.len() >= 2 && data[0] == 0xff && data[1] & 0xf0 == 0xf0;
+        if !is_adts {
+            info!("Audio packets have no ADTS header, muxing them as they are");
+            return Ok(AudioFilter::Passthrough);
+        }
+        info!("Audio packets are ADTS framed, converting with aac_adtstoasc");
+        match BitstreamFilter::builder("aac_adtstoasc")
+            .and_then(|builder| builder.input_codec_parameters(params).build())
+        {
+            Ok(bsf) => Ok(AudioFilter::Adts(bsf)),
+            Err(e) => bail!("Error creating audio filter: {}", e),
+        }
+    }
+}
+
+/// Packets at most, queued between the reader thread and the muxer in pipelined mode.
+const PIPELINE_DEPTH: usize = 64;
+/// Bytes of packet data at most in that queue, unless DecryptOptions::resource_budget limits
+/// them. Raised to DecryptOptions::max_packet_len if that is larger, so every packet fits.
+const PIPELINE_BUFFER_BYTES: u64 = 16 << 20;
+
+/// What the muxer gets from PacketSource.
+enum ReadEvent<B> {
+    Packet {
+        data: B,
+        packet_type: PacketType,
+        pts_us: i64,
+        /// The packet's share of DecryptOptions::resource_budget, released once it is written.
+        reservation: Option<Reservation>,
+    },
+    Warning(DecryptWarning),
+    /// A packet was read but has nothing to mux, only the bytes read are reported as progress.
+    Skipped,
+    /// The stream ended, possibly early. Always the last event.
+    End {
+        truncation: Option<PacketError>,
+        position: u64,
+    },
+    /// Always the last event.
+    Error(PacketError),
+    /// A packet is larger than the whole budget for buffered bytes. Always the last event.
+    BudgetExceeded(Error),
+}
+
+/// The reading half of the muxing job: reads packets and fixes their timestamps, so the muxer
+/// only has to write them. Runs on its own thread in pipelined mode.
+struct PacketSource<'a, R: Read, P: PackerBackend> {
+    packets: PacketReader<R>,
+    resource_budget: Option<ResourceBudget>,
+    /// Stops waiting for the budget.
+    cancel: &'a AtomicBool,
+    finalize_on_truncation: bool,
+    non_monotonic_pts: NonMonotonicPts,
+    first_pts: Option<i64>,
+    video_pts: PtsConditioner,
+    audio_pts: PtsConditioner,
+    queued: VecDeque<ReadEvent<P::Buffer>>,
+    done: bool,
+}
+
+impl<'a, R: Read, P: PackerBackend> PacketSource<'a, R, P> {
+    fn new(packets: PacketReader<R>, options: &DecryptOptions, cancel: &'a AtomicBool) -> Self {
+        PacketSource {
+            packets,
+            resource_budget: options.resource_budget.clone(),
+            cancel,
+            finalize_on_truncation: options.finalize_on_truncation,
+            non_monotonic_pts: options.non_monotonic_pts,
+            first_pts: None,
+            video_pts: PtsConditioner::default(),
+            audio_pts: PtsConditioner::default(),
+            queued: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    fn next_event(&mut self) -> Option<ReadEvent<P::Buffer>> {
+        while self.queued.is_empty() && !self.done {
+            self.read_packet();
+        }
+        self.queued.pop_front()
+    }
+
+    fn read_packet(&mut self) {
+        let next = self.packets.next_header();
+        if let Some(skipped) = self.packets.take_skipped() {
+            self.queued
+                .push_back(ReadEvent::Warning(DecryptWarning::CorruptDataSkipped {
+                    offset: skipped.offset,
+                    len: skipped.len,
+                }));
+        }
+        let header = match next {
+            None => return self.end(None),
+            Some(Ok(header)) => header,
+            Some(Err(e)) => return self.end(Some(e)),
+        };
+        let packet_type = match header.kind {
+            PacketKind::Video => PacketType::Video,
+            PacketKind::Audio => PacketType::Audio,
+            PacketKind::Unknown(packet_type) => {
+                self.queued.push_back(ReadEvent::Warning(
+                    DecryptWarning::UnknownPacketTypeSkipped(packet_type),
+                ));
+                return;
+            }
+        };
+        if header.length == 0 {
+            // left behind when the phone's encoder is flushed, ffmpeg doesn't take empty packets
+            debug!("Skipping empty {:?} packet", packet_type);
+            self.queued.push_back(ReadEvent::Skipped);
+            return;
+        }
+        let pts = match i64::try_from(header.pts_us) {
+            Ok(pts) => pts,
+            Err(_) => {
+                self.queued
+                    .push_back(ReadEvent::Warning(DecryptWarning::InvalidPtsSkipped {
+                        stream: header.kind,
+                        pts: header.pts_us,
+                    }));
+                return;
+            }
+        };
+        let first_pts = *self.first_pts.get_or_insert(pts);
+        let conditioner = match packet_type {
+            PacketType::Video => &mut self.video_pts,
+            PacketType::Audio => &mut self.audio_pts,
+        };
+        let (pts, warning) =
+            conditioner.condition(header.kind, pts - first_pts, self.non_monotonic_pts);
+        if let Some(warning) = warning {
+            self.queued.push_back(ReadEvent::Warning(warning));
+        }
+        let pts = match pts {
+            Some(pts) => pts,
+            None => return,
+        };
+
+        let reservation = match &self.resource_budget {
+            None => None,
+            Some(budget) => {
+                match budget.reserve(
+                    Resource::BufferBytes,
+                    header.length as u64,
+                    Some(self.cancel),
+                ) {
+                    Ok(Some(reservation)) => Some(reservation),
+                    // cancelled, the muxer stops without an End
+                    Ok(None) => {
+                        self.done = true;
+                        return;
+                    }
+                    Err(e) => {
+                        self.done = true;
+                        self.queued.push_back(ReadEvent::BudgetExceeded(e));
                         return;
                     }
                 }
+            }
+        };
+        let mut data = P::alloc(header.length);
+        if let Err(e) = self.packets.read_data(P::buffer_mut(&mut data)) {
+            return self.end(Some(e));
+        }
+        self.queued.push_back(ReadEvent::Packet {
+            data,
+            packet_type,
+            pts_us: pts,
+            reservation,
+        });
+    }
+
+    fn end(&mut self, error: Option<PacketError>) {
+        self.done = true;
+        let position = self.packets.position();
+        self.queued.push_back(match error {
+            None => ReadEvent::End {
+                truncation: None,
+                position,
             },
-            PacketType::Video => {
-                // Видео пишем как есть
-                if let Err(e) = muxer.push(packet) {
-                    progress_callback.on_error(e.into());
-                    return;
-                }
+            Some(e) if self.finalize_on_truncation && e.is_truncation() => ReadEvent::End {
+                truncation: Some(e),
+                position,
+            },
+            Some(e) => ReadEvent::Error(e),
+        });
+    }
+}
+
+/// The smallest step between two packets of a stream, coarse enough to survive the
+/// conversion to the stream's time base.
+const MIN_PTS_INCREMENT_US: i64 = 1000;
+/// A step back in PTS larger than this is a clock change, not a reordered packet.
+const PTS_DISCONTINUITY_US: i64 = 5_000_000;
+
+/// Keeps the PTS of a stream increasing, which the MP4 muxer insists on.
+#[derive(Default)]
+struct PtsConditioner {
+    last: Option<i64>,
+    offset: i64,
+}
+
+impl PtsConditioner {
+    /// Returns the PTS to use for the packet, None if it is to be dropped,
+    /// and a warning if the PTS was changed.
+    fn condition(
+        &mut self,
+        stream: PacketKind,
+        pts: i64,
+        mode: NonMonotonicPts,
+    ) -> (Option<i64>, Option<DecryptWarning>) {
+        let shifted = pts + self.offset;
+        let last = match self.last {
+            Some(last) if shifted <= last => last,
+            _ => {
+                self.last = Some(shifted);
+                return (Some(shifted), None);
+            }
+        };
+        let adjusted = last + MIN_PTS_INCREMENT_US;
+        let warning = if last - shifted > PTS_DISCONTINUITY_US {
+            // everything after a clock change is moved along with this packet
+            self.offset += adjusted - shifted;
+            DecryptWarning::TimestampJump {
+                stream,
+                pts,
+                back_us: last - shifted,
+                adjusted,
+            }
+        } else if mode == NonMonotonicPts::Drop {
+            return (
+                None,
+                Some(DecryptWarning::PacketDropped {
+                    stream,
+                    pts: shifted,
+                    previous: last,
+                }),
+            );
+        } else {
+            DecryptWarning::TimestampAdjusted {
+                stream,
+                pts: shifted,
+                previous: last,
+                adjusted,
             }
+        };
+        self.last = Some(adjusted);
+        (Some(adjusted), Some(warning))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the PTS through a fresh PtsConditioner.
+    fn condition(pts: &[i64], mode: NonMonotonicPts) -> Vec<(Option<i64>, Option<DecryptWarning>)> {
+        let mut conditioner = PtsConditioner::default();
+        pts.iter()
+            .map(|&pts| conditioner.condition(PacketKind::Video, pts, mode))
+            .collect()
+    }
+
+    #[test]
+    fn repeated_and_earlier_pts_are_bumped() {
+        let conditioned = condition(&[0, 33_333, 33_333, 30_000, 66_666], NonMonotonicPts::Bump);
+        assert_eq!(
+            conditioned,
+            vec![
+                (Some(0), None),
+                (Some(33_333), None),
+                (
+                    Some(34_333),
+                    Some(DecryptWarning::TimestampAdjusted {
+                        stream: PacketKind::Video,
+                        pts: 33_333,
+                        previous: 33_333,
+                        adjusted: 34_333,
+                    })
+                ),
+                (
+                    Some(35_333),
+                    Some(DecryptWarning::TimestampAdjusted {
+                        stream: PacketKind::Video,
+                        pts: 30_000,
+                        previous: 34_333,
+                        adjusted: 35_333,
+                    })
+                ),
+                (Some(66_666), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn repeated_and_earlier_pts_are_dropped() {
+        let conditioned = condition(&[0, 33_333, 33_333, 30_000, 66_666], NonMonotonicPts::Drop);
+        assert_eq!(
+            conditioned,
+            vec![
+                (Some(0), None),
+                (Some(33_333), None),
+                (
+                    None,
+                    Some(DecryptWarning::PacketDropped {
+                        stream: PacketKind::Video,
+                        pts: 33_333,
+                        previous: 33_333,
+                    })
+                ),
+                (
+                    None,
+                    Some(DecryptWarning::PacketDropped {
+                        stream: PacketKind::Video,
+                        pts: 30_000,
+                        previous: 33_333,
+                    })
+                ),
+                (Some(66_666), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn clock_changes_move_the_following_packets() {
+        for mode in [NonMonotonicPts::Bump, NonMonotonicPts::Drop] {
+            let conditioned = condition(&[0, 10_000_000, 1_000_000, 1_033_333], mode);
+            assert_eq!(
+                conditioned,
+                vec![
+                    (Some(0), None),
+                    (Some(10_000_000), None),
+                    (
+                        Some(10_001_000),
+                        Some(DecryptWarning::TimestampJump {
+                            stream: PacketKind::Video,
+                            pts: 1_000_000,
+                            back_us: 9_000_000,
+                            adjusted: 10_001_000,
+                        })
+                    ),
+                    (Some(10_034_333), None),
+                ],
+                "{:?}",
+                mode
+            );
         }
+    }
 
-        progress += packet_header.len() as u64 + packet_length as u64;
-        progress_callback.on_progress(progress);
+    #[cfg(feature = "video")]
+    #[test]
+    fn channel_layouts_come_from_the_metadata() {
+        for (name, channel_count, channels) in [
+            ("mono", 1, 1),
+            ("5.1", 6, 6),
+            ("7.1", 8, 8),
+            // the named layout wins over the channel count
+            ("quad", 2, 4),
+        ] {
+            let (layout, warning) = channel_layout(Some(name), channel_count);
+            assert_eq!(layout.channels(), channels, "{}", name);
+            assert_eq!(warning, None, "{}", name);
+        }
+        // without a name, the usual layout for the count
+        let (layout, warning) = channel_layout(None, 6);
+        assert_eq!((layout.channels(), warning), (6, None));
     }
 
-    // Сбрасываем остатки фильтра
-    if let Err(e) = audio_bsf.flush() {
-         progress_callback.on_error(anyhow!("Error flushing audio filter: {}", e).into());
-         return;
+    #[cfg(feature = "video")]
+    #[test]
+    fn unknown_channel_layouts_fall_back() {
+        let (layout, warning) = channel_layout(Some("surround-ish"), 6);
+        assert_eq!(layout.channels(), 6);
+        assert_eq!(
+            warning,
+            Some(DecryptWarning::ChannelLayoutGuessed {
+                layout: Some("surround-ish".to_owned()),
+                channel_count: 6,
+                stereo: false,
+            })
+        );
+        // no usual layout for the count either, stereo it is
+        for name in [Some("surround-ish"), None] {
+            let (layout, warning) = channel_layout(name, 0);
+            assert_eq!(layout.channels(), 2);
+            assert_eq!(
+                warning,
+                Some(DecryptWarning::ChannelLayoutGuessed {
+                    layout: name.map(str::to_owned),
+                    channel_count: 0,
+                    stereo: true,
+                })
+            );
+        }
     }
-    while let Ok(Some(filtered_packet)) = audio_bsf.take() {
-        if let Err(e) = muxer.push(filtered_packet) {
-            progress_callback.on_error(e.into());
-            return;
+
+    /// Video metadata with `fields` in place of the size and rotation.
+    fn metadata_with(fields: &str) -> Result<VideoMetadata> {
+        parse_video_metadata(&format!(
+            r#"{{{},"video_bitrate":1000000,"audio_sample_rate":44100,"audio_channel_count":1,"audio_bitrate":64000,"timestamp":"2021-06-01T12:00:00Z"}}"#,
+            fields
+        ))
+    }
+
+    fn rotated(rotation: impl std::fmt::Display) -> Result<VideoMetadata> {
+        metadata_with(&format!(
+            r#""width":640,"height":480,"rotation":{}"#,
+            rotation
+        ))
+    }
+
+    #[test]
+    fn rotations_other_than_right_angles_become_0() {
+        for rotation in [0, 90, 180, 270] {
+            let mut metadata = rotated(rotation).unwrap();
+            assert_eq!(metadata.normalize_rotation(), None);
+            assert_eq!(metadata.rotation, Some(rotation));
+        }
+        for rotation in [45, 360, 65535] {
+            let mut metadata = rotated(rotation).unwrap();
+            assert_eq!(
+                metadata.normalize_rotation(),
+                Some(DecryptWarning::InvalidRotation(rotation))
+            );
+            assert_eq!(metadata.rotation, Some(0));
+        }
+
+        // files without one get no rotate tag at all
+        let mut metadata = metadata_with(r#""width":640,"height":480"#).unwrap();
+        assert_eq!(metadata.normalize_rotation(), None);
+        assert_eq!(metadata.rotation, None);
+    }
+
+    #[test]
+    fn malformed_rotations_are_metadata_errors() {
+        for rotation in [r#""90""#, "-90", "90.5", "65536"] {
+            let error = rotated(rotation).unwrap_err();
+            assert!(
+                matches!(
+                    error.downcast_ref::<Error>(),
+                    Some(Error::MetadataParse {
+                        missing_field: None,
+                        ..
+                    })
+                ),
+                "{}: {}",
+                rotation,
+                error
+            );
         }
     }
 
-    match muxer.flush() {
-        Err(e) => {
-            progress_callback.on_error(e.into());
-            return;
+    #[test]
+    fn implausible_sizes_are_rejected() {
+        for size in [r#""width":1,"height":1"#, r#""width":16384,"height":16384"#] {
+            assert!(metadata_with(size).is_ok(), "{}", size);
         }
-        Ok(()) => {}
-    };
-    progress_callback.on_complete();
-}
\ No newline at end of file
+        for (size, reported) in [
+            (r#""width":0,"height":480"#, "0x480"),
+            (r#""width":640,"height":0"#, "640x0"),
+            (r#""width":16385,"height":480"#, "16385x480"),
+            (r#""width":640,"height":1000000"#, "640x1000000"),
+        ] {
+            let error = metadata_with(size).unwrap_err().to_string();
+            assert_eq!(
+                error,
+                format!(
+                    "Invalid video size {} in metadata, width and height must be 1 to 16384",
+                    reported
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn missing_and_malformed_sizes_are_metadata_errors() {
+        for (size, missing) in [
+            (r#""height":480"#, Some("width")),
+            (r#""width":640"#, Some("height")),
+            (r#""width":-640,"height":480"#, None),
+            (r#""width":"640","height":480"#, None),
+            (r#""width":640.5,"height":480"#, None),
+        ] {
+            let error = metadata_with(size).unwrap_err();
+            match error.downcast_ref::<Error>() {
+                Some(Error::MetadataParse { missing_field, .. }) => {
+                    assert_eq!(missing_field.as_deref(), missing, "{}", size)
+                }
+                _ => panic!("{}: {}", size, error),
+            }
+        }
+    }
+}
@@ -0,0 +1,260 @@
+//! Writing Cryptocam files, the way the app does. Files written here decrypt with decrypt().
+
+pub use crate::decrypt_image::ImageMetadata;
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+pub use crate::decrypt_video::{AudioCodec, VideoMetadata};
+#[cfg(feature = "video")]
+pub use crate::import::{import_mp4, ImportOptions};
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+use crate::packet::{PacketHeader, PacketKind, MAX_PACKET_LEN};
+use crate::{
+    keyring::{compute_digest, KeyDigest},
+    parser::write_header,
+};
+use age::stream::StreamWriter;
+use anyhow::{anyhow, bail, Result};
+use std::io::{self, Read};
+use std::{convert::TryFrom, fmt, io::Write, str::FromStr};
+
+/// File types in the encrypted header.
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+const FILE_TYPE_VIDEO: u8 = 1;
+const FILE_TYPE_IMAGE: u8 = 2;
+
+/// Someone a file is encrypted to, by their age public key.
+#[derive(Clone)]
+pub struct Recipient {
+    public_key: String,
+    recipient: age::x25519::Recipient,
+}
+
+impl Recipient {
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    /// The digest stored in the header of files encrypted to this recipient.
+    pub fn digest(&self) -> KeyDigest {
+        compute_digest(&self.public_key)
+    }
+}
+
+impl FromStr for Recipient {
+    type Err = anyhow::Error;
+
+    fn from_str(public_key: &str) -> Result<Self> {
+        let public_key = public_key.trim();
+        let recipient = age::x25519::Recipient::from_str(public_key)
+            .map_err(|e| anyhow!("Invalid public key {}: {}", public_key, e))?;
+        Ok(Recipient {
+            public_key: public_key.to_owned(),
+            recipient,
+        })
+    }
+}
+
+impl fmt::Debug for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Recipient").field(&self.public_key).finish()
+    }
+}
+
+/// Encrypts an image to `recipients` and writes it to `out` as a Cryptocam file.
+/// `input` is stored as it is, `metadata.format` should match its contents.
+pub fn encrypt_image(
+    mut input: impl Read,
+    recipients: &[Recipient],
+    metadata: ImageMetadata,
+    out: impl Write,
+) -> Result<()> {
+    let mut encrypted = start_file(
+        recipients,
+        FILE_TYPE_IMAGE,
+        &serde_json::to_vec(&metadata)?,
+        out,
+    )?;
+    io::copy(&mut input, &mut encrypted)?;
+    encrypted.finish()?.flush()?;
+    Ok(())
+}
+
+/// Writes a video as a Cryptocam file from its encoded packets, in the order they are pushed.
+/// The packets are what decrypting passes to the muxer, e.g. H.264 in Annex B format and ADTS
+/// framed AAC as the app writes them.
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+pub struct VideoEncryptor<W: Write> {
+    encrypted: StreamWriter<W>,
+}
+
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+impl<W: Write> VideoEncryptor<W> {
+    /// Writes the headers and metadata to `out`, the packets follow.
+    pub fn new(recipients: &[Recipient], metadata: &VideoMetadata, out: W) -> Result<Self> {
+        let encrypted = start_file(
+            recipients,
+            FILE_TYPE_VIDEO,
+            &serde_json::to_vec(metadata)?,
+            out,
+        )?;
+        Ok(VideoEncryptor { encrypted })
+    }
+
+    pub fn push_video(&mut self, pts_us: u64, data: &[u8]) -> Result<()> {
+        self.push(PacketKind::Video, pts_us, data)
+    }
+
+    pub fn push_audio(&mut self, pts_us: u64, data: &[u8]) -> Result<()> {
+        self.push(PacketKind::Audio, pts_us, data)
+    }
+
+    /// Writes the end of the encrypted stream, returns `out`.
+    pub fn finish(self) -> Result<W> {
+        let mut out = self.encrypted.finish()?;
+        out.flush()?;
+        Ok(out)
+    }
+
+    fn push(&mut self, kind: PacketKind, pts_us: u64, data: &[u8]) -> Result<()> {
+        if data.len() > MAX_PACKET_LEN {
+            bail!("Packet of {} bytes is too long", data.len());
+        }
+        let header = PacketHeader {
+            kind,
+            pts_us,
+            length: data.len(),
+        };
+        self.encrypted.write_all(&header.to_bytes())?;
+        self.encrypted.write_all(data)?;
+        Ok(())
+    }
+}
+
+/// Writes the header, then the encrypted file type and metadata.
+/// Returns the writer for the rest of the encrypted data.
+pub(crate) fn start_file<W: Write>(
+    recipients: &[Recipient],
+    file_type: u8,
+    metadata: &[u8],
+    out: W,
+) -> Result<StreamWriter<W>> {
+    let offset_to_data = match u32::try_from(metadata.len() + 5) {
+        Ok(offset) => offset,
+        Err(_) => bail!("Metadata of {} bytes is too long", metadata.len()),
+    };
+    let mut encrypted = encrypt_to(recipients, out)?;
+    encrypted.write_all(&[file_type])?;
+    encrypted.write_all(&offset_to_data.to_le_bytes())?;
+    encrypted.write_all(metadata)?;
+    Ok(encrypted)
+}
+
+/// Writes the header for `recipients`, returns the writer for the encrypted data that follows.
+pub(crate) fn encrypt_to<W: Write>(
+    recipients: &[Recipient],
+    mut out: W,
+) -> Result<StreamWriter<W>> {
+    let digests: Vec<KeyDigest> = recipients.iter().map(Recipient::digest).collect();
+    write_header(&mut out, &digests)?;
+    let age_recipients: Vec<Box<dyn age::Recipient>> = recipients
+        .iter()
+        .map(|r| Box::new(r.recipient.clone()) as Box<dyn age::Recipient>)
+        .collect();
+    match age::Encryptor::with_recipients(age_recipients).wrap_output(out) {
+        Ok(writer) => Ok(writer),
+        Err(e) => bail!("Error encrypting: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        decrypt::decrypt_to_vec,
+        fixtures::{test_keyring, test_recipient, TEST_SECRET_KEY},
+        keyring::Keyring,
+        parser::parse_header_from_slice,
+    };
+    use secrecy::ExposeSecret;
+    use std::iter;
+
+    const JPEG: &[u8] = b"\xff\xd8\xff\xe0\x00\x10JFIF\x00 jpeg data \xff\xd9";
+
+    fn encrypted_image(recipients: &[Recipient]) -> Vec<u8> {
+        let metadata = ImageMetadata::new("2021-06-01T12:00:00+02:00", "jpg");
+        let mut file = vec![];
+        encrypt_image(JPEG, recipients, metadata, &mut file).unwrap();
+        file
+    }
+
+    #[test]
+    fn encrypted_images_decrypt_to_the_same_bytes() {
+        let file = encrypted_image(&[test_recipient()]);
+        let (info, image) = decrypt_to_vec(&file[..], &mut test_keyring(), 1 << 20).unwrap();
+        assert_eq!(image, JPEG);
+        assert_eq!(info.timestamp, "2021-06-01T12:00:00+02:00");
+        assert_eq!(info.extension, "jpg");
+    }
+
+    #[test]
+    fn encrypted_images_have_the_app_layout() {
+        let file = encrypted_image(&[test_recipient()]);
+        let (header, header_len) = parse_header_from_slice(&file).unwrap();
+        assert_eq!(header.version, 1);
+        assert_eq!(header.recipient_digests, [test_recipient().digest()]);
+
+        let identity: age::x25519::Identity = TEST_SECRET_KEY.parse().unwrap();
+        let decryptor = match age::Decryptor::new(&file[header_len..]).unwrap() {
+            age::Decryptor::Recipients(decryptor) => decryptor,
+            _ => panic!("Not encrypted to recipients"),
+        };
+        let mut decrypted = vec![];
+        decryptor
+            .decrypt(iter::once(Box::new(identity) as Box<dyn age::Identity>))
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        let metadata = &decrypted[5..decrypted.len() - JPEG.len()];
+        assert_eq!(decrypted[0], FILE_TYPE_IMAGE);
+        assert_eq!(
+            u32::from_le_bytes([decrypted[1], decrypted[2], decrypted[3], decrypted[4]]) as usize,
+            5 + metadata.len()
+        );
+        let metadata: serde_json::Value = serde_json::from_slice(metadata).unwrap();
+        assert_eq!(
+            metadata,
+            serde_json::json!({"timestamp": "2021-06-01T12:00:00+02:00", "format": "jpg"})
+        );
+        assert!(decrypted.ends_with(JPEG));
+    }
+
+    #[test]
+    fn every_recipient_can_decrypt() {
+        let other = age::x25519::Identity::generate();
+        let mut other_keyring = Keyring::in_memory();
+        other_keyring
+            .import_key(other.to_string().expose_secret(), None)
+            .unwrap();
+        let other_recipient: Recipient = other.to_public().to_string().parse().unwrap();
+        let file = encrypted_image(&[test_recipient(), other_recipient.clone()]);
+
+        let (header, _) = parse_header_from_slice(&file).unwrap();
+        assert_eq!(
+            header.recipient_digests,
+            [test_recipient().digest(), other_recipient.digest()]
+        );
+        for mut keyring in [test_keyring(), other_keyring] {
+            let (_, image) = decrypt_to_vec(&file[..], &mut keyring, 1 << 20).unwrap();
+            assert_eq!(image, JPEG);
+        }
+    }
+
+    #[test]
+    fn images_need_distinct_recipients() {
+        let metadata = ImageMetadata::new("2021-06-01T12:00:00Z", "jpg");
+        for recipients in [vec![], vec![test_recipient(), test_recipient()]] {
+            let result = encrypt_image(JPEG, &recipients, metadata.clone(), Vec::new());
+            assert!(result.is_err(), "{:?}", recipients);
+        }
+        assert!("age1notakey".parse::<Recipient>().is_err());
+    }
+}
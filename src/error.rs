@@ -0,0 +1,187 @@
+use crate::keyring::DecryptionError;
+use std::{error::Error as StdError, io, path::PathBuf};
+use thiserror::Error;
+
+/// Errors callers may want to tell apart. Functions returning anyhow::Result wrap them,
+/// use anyhow::Error::downcast_ref::<libcryptocam::error::Error>() to get them back.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Resource budget exceeded: {requested} {resource} needed, the limit is {limit}")]
+    ResourceBudgetExceeded {
+        resource: &'static str,
+        requested: u64,
+        limit: u64,
+    },
+    /// The version as written in the header. The field is a u16 there, see parser.rs, so a
+    /// u8 would report e.g. version 256 as 0.
+    #[error("Unsupported file format version {0}, a newer version of this program may be needed")]
+    UnsupportedVersion(u16),
+    /// The input doesn't start with the magic bytes of a Cryptocam file. `found_magic` are its
+    /// first four bytes, 0 past the end of shorter input.
+    #[error("Not a Cryptocam file, it starts with {}", hex(.found_magic))]
+    NotACryptocamFile { found_magic: [u8; 4] },
+    /// The input ends inside the header, e.g. because the file is still being synced. Both are
+    /// in bytes from the start of the file, `expected` is how long the header is as far as it
+    /// could be read.
+    #[error("Truncated header: {got} of {expected} bytes")]
+    TruncatedHeader { expected: u64, got: u64 },
+    /// The recipient digests in the header can't be right, `reason` says why.
+    #[error("Malformed recipient list: {reason}")]
+    MalformedRecipientList { reason: String },
+    /// The encrypted header declares more metadata than any file has, `len` bytes where at
+    /// most `limit` are read. Only crafted or corrupt files get here.
+    #[error("Metadata of {len} bytes is larger than the limit of {limit}")]
+    MetadataTooLarge { len: u64, limit: u64 },
+    #[error("Cancelled")]
+    Cancelled,
+    #[error(
+        "The file is a video, but libcryptocam was built without the video or rust-mp4 feature"
+    )]
+    VideoSupportNotCompiled,
+    #[error("The file is audio, but libcryptocam was built without the video or rust-mp4 feature")]
+    AudioSupportNotCompiled,
+    /// The decrypted file is larger than the limit given to decrypt::decrypt_to_vec().
+    #[error("The decrypted file is larger than the limit of {limit} bytes")]
+    TooLargeForMemory { limit: usize },
+    /// decrypt::decrypt_to_vec() only takes images, videos and audio have to be decrypted to
+    /// a file.
+    #[error("Videos and audio recordings can't be decrypted to memory, decrypt them to a file")]
+    UseStreamingApi,
+    /// No JobBuilder for the file type, see decrypt::Registry.
+    #[error("Unknown file type {file_type}, known types are {}", type_list(.registered))]
+    UnknownFileType { file_type: u8, registered: Vec<u8> },
+    /// The partial output given to decrypt::DecryptOptions::resume_from doesn't continue the
+    /// file being decrypted, `reason` says why.
+    #[error("{} can't be resumed: {reason}", .path.display())]
+    PartialOutputMismatch { path: PathBuf, reason: String },
+    /// The output file for a recording would end up outside of the output directory, which
+    /// only happens with crafted metadata.
+    #[error("Refusing to write {}, it is outside of the output directory", .path.display())]
+    UnsafeOutputPath { path: PathBuf },
+    /// The metadata isn't valid JSON or lacks a field, `missing_field` names it.
+    #[error("Error parsing metadata: {message}")]
+    MetadataParse {
+        missing_field: Option<String>,
+        message: String,
+    },
+    /// Part of the encrypted data failed authentication: the file was damaged or tampered
+    /// with, or it was cut off in the middle of the chunk the encrypted data is stored in.
+    /// `approximate_offset` is where that chunk starts in the file, `processed_bytes` how many
+    /// decrypted bytes were read before it, including the file type and metadata.
+    #[error(
+        "The file is corrupted or was tampered with around byte {approximate_offset}, \
+         {processed_bytes} bytes were decrypted before"
+    )]
+    IntegrityError {
+        approximate_offset: u64,
+        processed_bytes: u64,
+    },
+    /// encrypt::import_mp4() only takes H.264 or H.265 video and AAC audio. `streams` are the
+    /// audio and video streams with other codecs, as their index in the input and codec name.
+    #[error("Can't import {}", stream_list(.streams))]
+    UnsupportedStreams { streams: Vec<(usize, String)> },
+    /// The output directory has less free space than the output is estimated to need, see
+    /// decrypt::DecryptOptions::check_free_space. Both are in bytes.
+    #[error("Not enough free space: {needed} bytes needed, {available} available")]
+    InsufficientSpace { needed: u64, available: u64 },
+    /// The box structure of a finished MP4 is broken, see
+    /// decrypt::DecryptOptions::check_output. `reason` says what was found.
+    #[error("The output {} is corrupted: {reason}", .path.display())]
+    CorruptOutput { path: PathBuf, reason: String },
+    /// The output path, or one of the names in it, is longer than the platform allows, even
+    /// with the `\\?\` prefix on Windows. `limit` is in bytes, or UTF-16 units on Windows.
+    #[error("The output path {} is longer than the limit of {limit}", .path.display())]
+    PathTooLong { path: PathBuf, limit: usize },
+    /// A segment given to decrypt::stitch() can't be muxed into the same output as the first
+    /// one. `index` is its position in the files, counting from 0, `reason` says what differs.
+    #[error("Segment {index} can't be stitched to the first one: {reason}")]
+    IncompatibleSegments { index: usize, reason: String },
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn type_list(file_types: &[u8]) -> String {
+    let file_types: Vec<String> = file_types.iter().map(u8::to_string).collect();
+    file_types.join(", ")
+}
+
+fn stream_list(streams: &[(usize, String)]) -> String {
+    let streams: Vec<String> = streams
+        .iter()
+        .map(|(index, codec)| format!("stream {} ({})", index, codec))
+        .collect();
+    streams.join(", ")
+}
+
+impl Error {
+    pub(crate) fn metadata_parse(e: serde_json::Error) -> Self {
+        let message = e.to_string();
+        let missing_field = message
+            .strip_prefix("missing field `")
+            .and_then(|rest| rest.split('`').next())
+            .map(str::to_owned);
+        Error::MetadataParse {
+            missing_field,
+            message,
+        }
+    }
+}
+
+/// Whether `e` holds an error reading the decrypted payload may fail with that callers can
+/// downcast to.
+fn holds_typed(e: &io::Error) -> bool {
+    e.get_ref()
+        .is_some_and(|inner| inner.is::<Error>() || inner.is::<DecryptionError>())
+}
+
+/// The Error or DecryptionError inside an io::Error from reading the decrypted payload, or the
+/// io::Error itself. For ProgressCallback::on_error(), which gets the typed error boxed as it
+/// is so it can be downcast.
+pub(crate) fn read_error(e: io::Error) -> Box<dyn StdError + Send + Sync> {
+    if !holds_typed(&e) {
+        return Box::new(e);
+    }
+    e.into_inner().expect("holds_typed() found an inner error")
+}
+
+/// Like read_error(), for functions returning anyhow::Result. The typed error is moved out of
+/// its box, anyhow can't downcast to the contents of a boxed error.
+pub(crate) fn read_error_anyhow(e: io::Error) -> anyhow::Error {
+    let inner = match read_error(e).downcast::<Error>() {
+        Ok(e) => return (*e).into(),
+        Err(inner) => inner,
+    };
+    let inner = match inner.downcast::<DecryptionError>() {
+        Ok(e) => return (*e).into(),
+        Err(inner) => inner,
+    };
+    match inner.downcast::<io::Error>() {
+        Ok(e) => (*e).into(),
+        Err(inner) => anyhow::anyhow!(inner),
+    }
+}
+
+/// read_error() for an anyhow::Error a job passes to ProgressCallback::on_error(), which may be
+/// an io::Error from reading the decrypted payload or an Error without context. Other errors
+/// keep their context.
+pub(crate) fn job_error(e: anyhow::Error) -> Box<dyn StdError + Send + Sync> {
+    if e.downcast_ref::<io::Error>().is_some_and(holds_typed) {
+        return read_error(e.downcast().expect("downcast_ref() found an io::Error"));
+    }
+    // is() also looks below context, which downcasting would drop
+    if e.chain().count() > 1 || !e.is::<Error>() {
+        return e.into();
+    }
+    Box::new(e.downcast::<Error>().expect("is() found an Error"))
+}
+
+/// read_error_anyhow() for an anyhow::Error that may be an io::Error from reading the
+/// decrypted payload. Other errors are returned as they are.
+pub(crate) fn typed_read_error(e: anyhow::Error) -> anyhow::Error {
+    match e.downcast_ref::<io::Error>().is_some_and(holds_typed) {
+        true => read_error_anyhow(e.downcast().expect("downcast_ref() found an io::Error")),
+        false => e,
+    }
+}
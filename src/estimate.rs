@@ -0,0 +1,102 @@
+//! Predicting the size of decrypted files from their header and size alone, see
+//! estimate_output_size().
+
+use crate::parser::Header;
+
+/// age encrypts the payload in chunks of this many bytes, each followed by a tag.
+const AGE_CHUNK_LEN: u64 = 64 << 10;
+const AGE_TAG_LEN: u64 = 16;
+/// In front of the first chunk.
+const AGE_NONCE_LEN: u64 = 16;
+/// The "age-encryption.org/v1" line and the MAC line of the age header.
+const AGE_HEADER_FIXED_LEN: u64 = 22 + 48;
+/// An X25519 stanza: the line with the ephemeral share and the wrapped file key, 43 base64
+/// characters each.
+const AGE_X25519_STANZA_LEN: u64 = 54 + 44;
+/// The stanza of a file encrypted with a passphrase, with a two digit work factor.
+const AGE_SCRYPT_STANZA_LEN: u64 = 36 + 44;
+/// The file type and offset to data in front of the metadata.
+const INNER_HEADER_LEN: u64 = 5;
+
+/// How large the output of a file of `total_file_size` bytes with this header will be, without
+/// reading anything past the header. This is the decrypted payload without the age overhead,
+/// so the metadata, usually a few hundred bytes, is still counted.
+/// DecryptingJob::estimated_output_size() leaves it out.
+///
+/// Images come out that large, plus the EXIF tags added with DecryptOptions::write_exif.
+/// For videos, it is a slight overestimate: the MP4 or Matroska overhead is smaller than the
+/// packet headers it replaces.
+pub fn estimate_output_size(header: &Header, total_file_size: u64) -> u64 {
+    payload_len(header, total_file_size).saturating_sub(INNER_HEADER_LEN)
+}
+
+/// Like estimate_output_size(), without the metadata. `offset_to_data` is from the inner
+/// header.
+pub(crate) fn estimate_data_len(header: &Header, total_file_size: u64, offset_to_data: u32) -> u64 {
+    payload_len(header, total_file_size).saturating_sub(offset_to_data as u64)
+}
+
+/// The decrypted length of the age payload.
+fn payload_len(header: &Header, total_file_size: u64) -> u64 {
+    let stanzas = match header.recipient_digests.len() {
+        0 => AGE_SCRYPT_STANZA_LEN,
+        recipients => recipients as u64 * AGE_X25519_STANZA_LEN,
+    };
+    let encrypted = total_file_size
+        .saturating_sub(header.header_len + AGE_HEADER_FIXED_LEN + stanzas + AGE_NONCE_LEN);
+    let chunks = (encrypted + AGE_CHUNK_LEN + AGE_TAG_LEN - 1) / (AGE_CHUNK_LEN + AGE_TAG_LEN);
+    encrypted.saturating_sub(chunks * AGE_TAG_LEN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fixtures::{test_recipient, FixtureFile},
+        parser::parse_header_from_slice,
+    };
+
+    const METADATA: &str = r#"{"timestamp":"2021-06-01T12:00:00Z","format":"png"}"#;
+
+    #[test]
+    fn estimates_are_the_decrypted_sizes() {
+        let other: crate::encrypt::Recipient = age::x25519::Identity::generate()
+            .to_public()
+            .to_string()
+            .parse()
+            .unwrap();
+        // around the chunk boundaries, where the number of tags changes
+        for len in [
+            0,
+            1,
+            1000,
+            (64 << 10) - 56,
+            64 << 10,
+            (64 << 10) + 1,
+            200_000,
+            1 << 20,
+        ] {
+            for recipients in [
+                vec![test_recipient()],
+                vec![test_recipient(), other.clone()],
+            ] {
+                let file = FixtureFile::image(METADATA, vec![0; len])
+                    .recipients(recipients.clone())
+                    .build();
+                let (header, _) = parse_header_from_slice(&file).unwrap();
+                let offset_to_data = INNER_HEADER_LEN as u32 + METADATA.len() as u32;
+                assert_eq!(
+                    estimate_output_size(&header, file.len() as u64),
+                    (METADATA.len() + len) as u64,
+                    "{} bytes to {} recipients",
+                    len,
+                    recipients.len()
+                );
+                assert_eq!(
+                    estimate_data_len(&header, file.len() as u64, offset_to_data),
+                    len as u64
+                );
+            }
+        }
+    }
+}
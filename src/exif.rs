@@ -0,0 +1,739 @@
+use crate::location::Location;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, FixedOffset};
+use log::warn;
+use std::{
+    convert::TryInto,
+    io::{copy, Read, Write},
+};
+
+const EXIF_HEADER: &[u8; 6] = b"Exif\0\0";
+
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_GPS_IFD: u16 = 0x8825;
+const TAG_INTEROP_IFD: u16 = 0xa005;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_OFFSET_TIME_ORIGINAL: u16 = 0x9011;
+const TAG_GPS_VERSION_ID: u16 = 0x0000;
+const TAG_GPS_LATITUDE_REF: u16 = 0x0001;
+const TAG_GPS_LATITUDE: u16 = 0x0002;
+const TAG_GPS_LONGITUDE_REF: u16 = 0x0003;
+const TAG_GPS_LONGITUDE: u16 = 0x0004;
+const TAG_GPS_ALTITUDE_REF: u16 = 0x0005;
+const TAG_GPS_ALTITUDE: u16 = 0x0006;
+
+const TYPE_BYTE: u16 = 1;
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+
+/// Tags written into decrypted JPEGs.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ExifTags {
+    pub date_time_original: Option<DateTime<FixedOffset>>,
+    /// EXIF orientation, 1-8
+    pub orientation: Option<u16>,
+    pub location: Option<Location>,
+}
+
+impl ExifTags {
+    pub fn is_empty(&self) -> bool {
+        self.date_time_original.is_none() && self.orientation.is_none() && self.location.is_none()
+    }
+}
+
+/// Maps a clockwise rotation in degrees as stored in the metadata to an EXIF orientation.
+pub(crate) fn orientation_from_rotation(rotation: u16) -> Option<u16> {
+    match rotation % 360 {
+        0 => Some(1),
+        90 => Some(6),
+        180 => Some(3),
+        270 => Some(8),
+        _ => None,
+    }
+}
+
+/// Copies a JPEG from `input` to `out`, adding `tags` as EXIF. If the JPEG already has an EXIF
+/// segment, tags missing from it are merged in and existing values are kept. Everything from the
+/// start of scan on is copied unchanged, the image is never re-encoded.
+pub(crate) fn copy_jpeg_with_exif(
+    input: &mut dyn Read,
+    out: &mut dyn Write,
+    tags: &ExifTags,
+) -> Result<()> {
+    let mut soi = [0u8; 2];
+    input.read_exact(&mut soi)?;
+    out.write_all(&soi)?;
+    if soi != [0xff, 0xd8] {
+        warn!("Image is not a JPEG, not adding EXIF");
+        copy(input, out)?;
+        return Ok(());
+    }
+
+    // read all segments before the image data
+    let mut segments: Vec<(u8, Vec<u8>)> = Vec::new();
+    let start_of_scan = loop {
+        let mut marker = [0u8; 2];
+        input.read_exact(&mut marker).context("Truncated JPEG")?;
+        if marker[0] != 0xff {
+            bail!("Invalid JPEG marker {:02x}{:02x}", marker[0], marker[1]);
+        }
+        if marker[1] == 0xda || (0xd0..=0xd9).contains(&marker[1]) || marker[1] == 0x01 {
+            break marker;
+        }
+        let mut len = [0u8; 2];
+        input.read_exact(&mut len).context("Truncated JPEG")?;
+        let len = u16::from_be_bytes(len) as usize;
+        if len < 2 {
+            bail!("Invalid JPEG segment length {}", len);
+        }
+        let mut payload = vec![0; len - 2];
+        input.read_exact(&mut payload).context("Truncated JPEG")?;
+        segments.push((marker[1], payload));
+    };
+
+    let existing = segments
+        .iter()
+        .position(|(marker, payload)| *marker == 0xe1 && payload.starts_with(EXIF_HEADER));
+    match existing {
+        Some(i) => match merge_exif(&segments[i].1[EXIF_HEADER.len()..], tags) {
+            Ok(merged) => segments[i].1 = merged,
+            Err(e) => warn!("Keeping unreadable EXIF data as is: {}", e),
+        },
+        None => {
+            let mut exif = Exif::new();
+            exif.add_missing(tags);
+            // JFIF wants its APP0 segment to come first
+            let insert_at = segments.iter().take_while(|(m, _)| *m == 0xe0).count();
+            segments.insert(insert_at, (0xe1, exif.to_app1_payload()?));
+        }
+    }
+
+    for (marker, payload) in &segments {
+        out.write_all(&[0xff, *marker])?;
+        out.write_all(&(payload.len() as u16 + 2).to_be_bytes())?;
+        out.write_all(payload)?;
+    }
+    out.write_all(&start_of_scan)?;
+    copy(input, out)?;
+    Ok(())
+}
+
+fn merge_exif(tiff: &[u8], tags: &ExifTags) -> Result<Vec<u8>> {
+    let mut exif = Exif::parse(tiff)?;
+    exif.add_missing(tags);
+    exif.to_app1_payload()
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    /// value in the byte order of the containing Exif
+    data: Vec<u8>,
+}
+
+/// The IFDs of an EXIF block that are kept when rewriting it. The thumbnail IFD and
+/// the interoperability IFD are dropped.
+struct Exif {
+    little_endian: bool,
+    ifd0: Vec<Entry>,
+    exif_ifd: Vec<Entry>,
+    gps_ifd: Vec<Entry>,
+}
+
+impl Exif {
+    fn new() -> Self {
+        Exif {
+            little_endian: false,
+            ifd0: Vec::new(),
+            exif_ifd: Vec::new(),
+            gps_ifd: Vec::new(),
+        }
+    }
+
+    fn parse(tiff: &[u8]) -> Result<Exif> {
+        let little_endian = match tiff.get(0..2) {
+            Some(b"II") => true,
+            Some(b"MM") => false,
+            _ => bail!("Invalid TIFF header"),
+        };
+        let reader = TiffReader {
+            data: tiff,
+            little_endian,
+        };
+        let ifd0 = reader.read_ifd(reader.u32(4)?)?;
+        let sub_ifd = |tag: u16| -> Result<Vec<Entry>> {
+            match ifd0.iter().find(|e| e.tag == tag) {
+                None => Ok(Vec::new()),
+                Some(e) => reader.read_ifd(reader.u32_from(&e.data)?),
+            }
+        };
+        let exif_ifd = sub_ifd(TAG_EXIF_IFD)?
+            .into_iter()
+            .filter(|e| e.tag != TAG_INTEROP_IFD)
+            .collect();
+        let gps_ifd = sub_ifd(TAG_GPS_IFD)?;
+        Ok(Exif {
+            little_endian,
+            ifd0: ifd0
+                .into_iter()
+                .filter(|e| e.tag != TAG_EXIF_IFD && e.tag != TAG_GPS_IFD)
+                .collect(),
+            exif_ifd,
+            gps_ifd,
+        })
+    }
+
+    fn add_missing(&mut self, tags: &ExifTags) {
+        if let Some(orientation) = tags.orientation {
+            let data = self.short(orientation);
+            add_if_missing(&mut self.ifd0, TAG_ORIENTATION, TYPE_SHORT, 1, data);
+        }
+        if let Some(time) = &tags.date_time_original {
+            let date_time = ascii(&time.format("%Y:%m:%d %H:%M:%S").to_string());
+            let offset = ascii(&time.format("%:z").to_string());
+            add_if_missing(
+                &mut self.exif_ifd,
+                TAG_DATE_TIME_ORIGINAL,
+                TYPE_ASCII,
+                date_time.len() as u32,
+                date_time,
+            );
+            add_if_missing(
+                &mut self.exif_ifd,
+                TAG_OFFSET_TIME_ORIGINAL,
+                TYPE_ASCII,
+                offset.len() as u32,
+                offset,
+            );
+        }
+        // a location is only added as a whole, never mixed with one that is already there
+        if let Some(location) = &tags.location {
+            if !self.gps_ifd.iter().any(|e| e.tag == TAG_GPS_LATITUDE) {
+                self.add_location(location);
+            }
+        }
+    }
+
+    fn add_location(&mut self, location: &Location) {
+        let latitude_ref = if location.latitude < 0.0 { "S" } else { "N" };
+        let longitude_ref = if location.longitude < 0.0 { "W" } else { "E" };
+        let mut entries = vec![
+            (TAG_GPS_VERSION_ID, TYPE_BYTE, 4, vec![2, 3, 0, 0]),
+            (TAG_GPS_LATITUDE_REF, TYPE_ASCII, 2, ascii(latitude_ref)),
+            (
+                TAG_GPS_LATITUDE,
+                TYPE_RATIONAL,
+                3,
+                self.degrees(location.latitude.abs()),
+            ),
+            (TAG_GPS_LONGITUDE_REF, TYPE_ASCII, 2, ascii(longitude_ref)),
+            (
+                TAG_GPS_LONGITUDE,
+                TYPE_RATIONAL,
+                3,
+                self.degrees(location.longitude.abs()),
+            ),
+        ];
+        if let Some(altitude) = location.altitude {
+            // in centimeters, 0 for above sea level and 1 for below
+            let centimeters = (altitude.abs() * 100.0).round() as u32;
+            entries.push((
+                TAG_GPS_ALTITUDE_REF,
+                TYPE_BYTE,
+                1,
+                vec![(altitude < 0.0) as u8],
+            ));
+            entries.push((
+                TAG_GPS_ALTITUDE,
+                TYPE_RATIONAL,
+                1,
+                self.rational(centimeters, 100),
+            ));
+        }
+        for (tag, kind, count, data) in entries {
+            add_if_missing(&mut self.gps_ifd, tag, kind, count, data);
+        }
+    }
+
+    /// Degrees as the rationals for degrees, minutes and seconds, to a millisecond of arc.
+    fn degrees(&self, degrees: f64) -> Vec<u8> {
+        let millis = (degrees * 3_600_000.0).round() as u32;
+        let mut data = self.rational(millis / 3_600_000, 1);
+        data.extend(self.rational(millis / 60_000 % 60, 1));
+        data.extend(self.rational(millis % 60_000, 1000));
+        data
+    }
+
+    fn rational(&self, numerator: u32, denominator: u32) -> Vec<u8> {
+        let mut data = self.long(numerator);
+        data.extend(self.long(denominator));
+        data
+    }
+
+    fn short(&self, value: u16) -> Vec<u8> {
+        if self.little_endian {
+            value.to_le_bytes().to_vec()
+        } else {
+            value.to_be_bytes().to_vec()
+        }
+    }
+
+    fn long(&self, value: u32) -> Vec<u8> {
+        if self.little_endian {
+            value.to_le_bytes().to_vec()
+        } else {
+            value.to_be_bytes().to_vec()
+        }
+    }
+
+    fn to_app1_payload(&self) -> Result<Vec<u8>> {
+        let mut ifd0 = self.ifd0.clone();
+        // placeholders, the offsets are known once the size of IFD0 is
+        if !self.exif_ifd.is_empty() {
+            add_if_missing(&mut ifd0, TAG_EXIF_IFD, TYPE_LONG, 1, vec![0; 4]);
+        }
+        if !self.gps_ifd.is_empty() {
+            add_if_missing(&mut ifd0, TAG_GPS_IFD, TYPE_LONG, 1, vec![0; 4]);
+        }
+        let exif_offset = 8 + ifd_size(&ifd0);
+        let gps_offset = exif_offset + ifd_size(&self.exif_ifd);
+        for entry in ifd0.iter_mut() {
+            match entry.tag {
+                TAG_EXIF_IFD => entry.data = self.long(exif_offset),
+                TAG_GPS_IFD => entry.data = self.long(gps_offset),
+                _ => {}
+            }
+        }
+
+        let mut tiff = Vec::new();
+        tiff.extend_from_slice(if self.little_endian { b"II" } else { b"MM" });
+        tiff.extend_from_slice(&self.short(42));
+        tiff.extend_from_slice(&self.long(8));
+        self.write_ifd(&mut tiff, &ifd0);
+        if !self.exif_ifd.is_empty() {
+            self.write_ifd(&mut tiff, &self.exif_ifd);
+        }
+        if !self.gps_ifd.is_empty() {
+            self.write_ifd(&mut tiff, &self.gps_ifd);
+        }
+
+        let mut payload = EXIF_HEADER.to_vec();
+        payload.extend_from_slice(&tiff);
+        if payload.len() + 2 > u16::MAX as usize {
+            bail!("EXIF data too large for a JPEG segment");
+        }
+        Ok(payload)
+    }
+
+    fn write_ifd(&self, tiff: &mut Vec<u8>, entries: &[Entry]) {
+        let start = tiff.len() as u32;
+        let mut data_offset = start + 2 + 12 * entries.len() as u32 + 4;
+        let mut data_area = Vec::new();
+        tiff.extend_from_slice(&self.short(entries.len() as u16));
+        for entry in entries {
+            tiff.extend_from_slice(&self.short(entry.tag));
+            tiff.extend_from_slice(&self.short(entry.kind));
+            tiff.extend_from_slice(&self.long(entry.count));
+            if entry.data.len() <= 4 {
+                let mut inline = entry.data.clone();
+                inline.resize(4, 0);
+                tiff.extend_from_slice(&inline);
+            } else {
+                tiff.extend_from_slice(&self.long(data_offset));
+                data_area.extend_from_slice(&entry.data);
+                if entry.data.len() % 2 == 1 {
+                    data_area.push(0);
+                }
+                data_offset = start + 2 + 12 * entries.len() as u32 + 4 + data_area.len() as u32;
+            }
+        }
+        // no further IFDs
+        tiff.extend_from_slice(&[0; 4]);
+        tiff.extend_from_slice(&data_area);
+    }
+}
+
+fn ifd_size(entries: &[Entry]) -> u32 {
+    if entries.is_empty() {
+        return 0;
+    }
+    let data_len: usize = entries
+        .iter()
+        .filter(|e| e.data.len() > 4)
+        .map(|e| e.data.len() + e.data.len() % 2)
+        .sum();
+    (2 + 12 * entries.len() + 4 + data_len) as u32
+}
+
+fn add_if_missing(entries: &mut Vec<Entry>, tag: u16, kind: u16, count: u32, data: Vec<u8>) {
+    if let Err(i) = entries.binary_search_by_key(&tag, |e| e.tag) {
+        entries.insert(
+            i,
+            Entry {
+                tag,
+                kind,
+                count,
+                data,
+            },
+        );
+    }
+}
+
+fn ascii(s: &str) -> Vec<u8> {
+    let mut bytes = s.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+struct TiffReader<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl TiffReader<'_> {
+    fn bytes(&self, offset: u32, len: usize) -> Result<&[u8]> {
+        let offset = offset as usize;
+        self.data
+            .get(
+                offset
+                    ..offset
+                        .checked_add(len)
+                        .ok_or_else(|| anyhow!("Invalid offset"))?,
+            )
+            .ok_or_else(|| anyhow!("Offset {} out of range in EXIF data", offset))
+    }
+
+    fn u16(&self, offset: u32) -> Result<u16> {
+        let b = self.bytes(offset, 2)?;
+        Ok(if self.little_endian {
+            u16::from_le_bytes([b[0], b[1]])
+        } else {
+            u16::from_be_bytes([b[0], b[1]])
+        })
+    }
+
+    fn u32(&self, offset: u32) -> Result<u32> {
+        self.u32_from(self.bytes(offset, 4)?)
+    }
+
+    fn u32_from(&self, b: &[u8]) -> Result<u32> {
+        let b: [u8; 4] = b
+            .get(0..4)
+            .and_then(|b| b.try_into().ok())
+            .ok_or_else(|| anyhow!("Invalid IFD pointer"))?;
+        Ok(if self.little_endian {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        })
+    }
+
+    fn read_ifd(&self, offset: u32) -> Result<Vec<Entry>> {
+        let count = self.u16(offset)? as u32;
+        let mut entries = Vec::with_capacity(count as usize);
+        for i in 0..count {
+            // the value comes last in an entry, so the other fields can't overflow if it doesn't
+            let pos = offset
+                .checked_add(2 + 12 * i)
+                .filter(|pos| pos.checked_add(8).is_some())
+                .ok_or_else(|| anyhow!("IFD offset {} out of range in EXIF data", offset))?;
+            let tag = self.u16(pos)?;
+            let kind = self.u16(pos + 2)?;
+            let count = self.u32(pos + 4)?;
+            let size: usize = match kind {
+                1 | 2 | 6 | 7 => 1,
+                3 | 8 => 2,
+                4 | 9 | 11 => 4,
+                5 | 10 | 12 => 8,
+                // unknown types can't be relocated
+                _ => continue,
+            };
+            let len = size
+                .checked_mul(count as usize)
+                .ok_or_else(|| anyhow!("Invalid count {} in EXIF data", count))?;
+            let data = if len <= 4 {
+                self.bytes(pos + 8, len)?
+            } else {
+                self.bytes(self.u32(pos + 8)?, len)?
+            };
+            entries.push(Entry {
+                tag,
+                kind,
+                count,
+                data: data.to_vec(),
+            });
+        }
+        entries.sort_by_key(|e| e.tag);
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JFIF: &[u8] = b"\xff\xe0\x00\x10JFIF\x00\x01\x01\x00\x00\x01\x00\x01\x00\x00";
+    /// A start of scan segment, the entropy coded data and the end of image.
+    const SCAN: &[u8] = b"\xff\xda\x00\x08\x01\x01\x00\x00\x3f\x00scan data\xff\xd9";
+
+    fn jpeg(segments: &[&[u8]]) -> Vec<u8> {
+        let mut jpeg = b"\xff\xd8".to_vec();
+        for segment in segments {
+            jpeg.extend_from_slice(segment);
+        }
+        jpeg.extend_from_slice(SCAN);
+        jpeg
+    }
+
+    fn app1(payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xff, 0xe1];
+        segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    fn tags() -> ExifTags {
+        ExifTags {
+            date_time_original: Some(
+                DateTime::parse_from_rfc3339("2021-06-01T12:00:00+02:00").unwrap(),
+            ),
+            orientation: Some(6),
+            location: None,
+        }
+    }
+
+    fn with_exif(input: &[u8], tags: &ExifTags) -> Vec<u8> {
+        let mut out = Vec::new();
+        copy_jpeg_with_exif(&mut &input[..], &mut out, tags).unwrap();
+        out
+    }
+
+    /// The markers and payloads of the segments before the start of scan.
+    fn segments(jpeg: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut segments = Vec::new();
+        let mut pos = 2;
+        while jpeg[pos + 1] != 0xda {
+            let len = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+            segments.push((jpeg[pos + 1], &jpeg[pos + 4..pos + 2 + len]));
+            pos += 2 + len;
+        }
+        segments
+    }
+
+    /// The EXIF of the only APP1 segment of `jpeg`.
+    fn exif(jpeg: &[u8]) -> Exif {
+        let app1: Vec<_> = segments(jpeg)
+            .into_iter()
+            .filter(|(marker, _)| *marker == 0xe1)
+            .collect();
+        assert_eq!(app1.len(), 1);
+        Exif::parse(&app1[0].1[EXIF_HEADER.len()..]).unwrap()
+    }
+
+    fn value<'a>(exif: &Exif, entries: &'a [Entry], tag: u16) -> Option<TiffReader<'a>> {
+        entries.iter().find(|e| e.tag == tag).map(|e| TiffReader {
+            data: &e.data,
+            little_endian: exif.little_endian,
+        })
+    }
+
+    fn orientation(exif: &Exif) -> Option<u16> {
+        value(exif, &exif.ifd0, TAG_ORIENTATION).map(|v| v.u16(0).unwrap())
+    }
+
+    fn date_time_original(exif: &Exif) -> Option<&[u8]> {
+        value(exif, &exif.exif_ifd, TAG_DATE_TIME_ORIGINAL).map(|v| v.data)
+    }
+
+    #[test]
+    fn exif_is_added_to_bare_jpegs() {
+        let input = jpeg(&[JFIF]);
+        let out = with_exif(&input, &tags());
+        let segments = segments(&out);
+        assert_eq!(segments[0].0, 0xe0);
+        assert_eq!(segments[1].0, 0xe1);
+        assert!(out.ends_with(SCAN));
+
+        let exif = exif(&out);
+        assert_eq!(orientation(&exif), Some(6));
+        assert_eq!(
+            date_time_original(&exif),
+            Some(&b"2021:06:01 12:00:00\0"[..])
+        );
+        let offset = value(&exif, &exif.exif_ifd, TAG_OFFSET_TIME_ORIGINAL).unwrap();
+        assert_eq!(offset.data, b"+02:00\0");
+    }
+
+    #[test]
+    fn existing_exif_is_merged_into() {
+        for little_endian in [false, true] {
+            let mut existing = Exif::new();
+            existing.little_endian = little_endian;
+            existing.add_missing(&ExifTags {
+                orientation: Some(3),
+                ..ExifTags::default()
+            });
+            let input = jpeg(&[JFIF, &app1(&existing.to_app1_payload().unwrap())[..]]);
+            let out = with_exif(&input, &tags());
+            assert!(out.ends_with(SCAN));
+
+            let exif = exif(&out);
+            assert_eq!(exif.little_endian, little_endian);
+            // values already there are kept
+            assert_eq!(orientation(&exif), Some(3));
+            assert_eq!(
+                date_time_original(&exif),
+                Some(&b"2021:06:01 12:00:00\0"[..])
+            );
+        }
+    }
+
+    #[test]
+    fn jpegs_with_all_tags_are_copied_unchanged() {
+        let once = with_exif(&jpeg(&[JFIF]), &tags());
+        assert_eq!(with_exif(&once, &tags()), once);
+    }
+
+    #[test]
+    fn other_images_are_copied_unchanged() {
+        let png = b"\x89PNG\r\n\x1a\n not really a png";
+        assert_eq!(with_exif(png, &tags()), png);
+    }
+
+    /// The rationals of the GPS tag `tag` as numerator and denominator.
+    fn gps_rationals(exif: &Exif, tag: u16) -> Vec<(u32, u32)> {
+        let value = value(exif, &exif.gps_ifd, tag).unwrap();
+        (0..value.data.len() as u32 / 8)
+            .map(|i| (value.u32(i * 8).unwrap(), value.u32(i * 8 + 4).unwrap()))
+            .collect()
+    }
+
+    fn gps_bytes(exif: &Exif, tag: u16) -> Option<&[u8]> {
+        value(exif, &exif.gps_ifd, tag).map(|v| v.data)
+    }
+
+    #[test]
+    fn locations_are_added_as_gps_tags() {
+        let location = Location {
+            latitude: -33.8568,
+            longitude: 151.2153,
+            altitude: Some(-12.34),
+        };
+        let tags = ExifTags {
+            location: Some(location),
+            ..tags()
+        };
+        let exif = exif(&with_exif(&jpeg(&[JFIF]), &tags));
+        assert_eq!(
+            gps_bytes(&exif, TAG_GPS_VERSION_ID),
+            Some(&[2, 3, 0, 0][..])
+        );
+        assert_eq!(gps_bytes(&exif, TAG_GPS_LATITUDE_REF), Some(&b"S\0"[..]));
+        // 33° 51' 24.48"
+        assert_eq!(
+            gps_rationals(&exif, TAG_GPS_LATITUDE),
+            [(33, 1), (51, 1), (24480, 1000)]
+        );
+        assert_eq!(gps_bytes(&exif, TAG_GPS_LONGITUDE_REF), Some(&b"E\0"[..]));
+        // 151° 12' 55.08"
+        assert_eq!(
+            gps_rationals(&exif, TAG_GPS_LONGITUDE),
+            [(151, 1), (12, 1), (55080, 1000)]
+        );
+        // below sea level
+        assert_eq!(gps_bytes(&exif, TAG_GPS_ALTITUDE_REF), Some(&[1][..]));
+        assert_eq!(gps_rationals(&exif, TAG_GPS_ALTITUDE), [(1234, 100)]);
+        // the other tags are still there
+        assert_eq!(orientation(&exif), Some(6));
+
+        // no altitude, no altitude tags
+        let tags = ExifTags {
+            location: Some(Location {
+                altitude: None,
+                ..location
+            }),
+            ..tags
+        };
+        let exif = exif(&with_exif(&jpeg(&[JFIF]), &tags));
+        assert!(gps_bytes(&exif, TAG_GPS_LATITUDE).is_some());
+        assert_eq!(gps_bytes(&exif, TAG_GPS_ALTITUDE_REF), None);
+        assert_eq!(gps_bytes(&exif, TAG_GPS_ALTITUDE), None);
+    }
+
+    #[test]
+    fn existing_locations_are_kept_whole() {
+        let mut existing = Exif::new();
+        existing.add_missing(&ExifTags {
+            location: Some(Location {
+                latitude: 48.8584,
+                longitude: 2.2945,
+                altitude: None,
+            }),
+            ..ExifTags::default()
+        });
+        let input = jpeg(&[JFIF, &app1(&existing.to_app1_payload().unwrap())[..]]);
+        let tags = ExifTags {
+            location: Some(Location {
+                latitude: -33.8568,
+                longitude: 151.2153,
+                altitude: Some(58.0),
+            }),
+            ..tags()
+        };
+        let exif = exif(&with_exif(&input, &tags));
+        assert_eq!(gps_bytes(&exif, TAG_GPS_LATITUDE_REF), Some(&b"N\0"[..]));
+        assert_eq!(gps_rationals(&exif, TAG_GPS_LATITUDE)[0], (48, 1));
+        assert_eq!(gps_rationals(&exif, TAG_GPS_LONGITUDE)[0], (2, 1));
+        // not even the missing altitude is taken from the other location
+        assert_eq!(gps_bytes(&exif, TAG_GPS_ALTITUDE), None);
+    }
+
+    /// A TIFF header pointing to IFD0 at `ifd0`, followed by `rest`.
+    fn tiff(ifd0: u32, rest: &[u8]) -> Vec<u8> {
+        let mut tiff = b"II\x2a\x00".to_vec();
+        tiff.extend_from_slice(&ifd0.to_le_bytes());
+        tiff.extend_from_slice(rest);
+        tiff
+    }
+
+    #[test]
+    fn hostile_offsets_are_errors() {
+        // an IFD with one entry pointing to an EXIF IFD at `exif_ifd`
+        let sub_ifd = |exif_ifd: u32| {
+            let mut ifd = 1u16.to_le_bytes().to_vec();
+            ifd.extend_from_slice(&TAG_EXIF_IFD.to_le_bytes());
+            ifd.extend_from_slice(&TYPE_LONG.to_le_bytes());
+            ifd.extend_from_slice(&1u32.to_le_bytes());
+            ifd.extend_from_slice(&exif_ifd.to_le_bytes());
+            ifd.extend_from_slice(&[0; 4]);
+            tiff(8, &ifd)
+        };
+        // a value of u32::MAX bytes
+        let mut huge_value = 1u16.to_le_bytes().to_vec();
+        huge_value.extend_from_slice(&TAG_ORIENTATION.to_le_bytes());
+        huge_value.extend_from_slice(&5u16.to_le_bytes());
+        huge_value.extend_from_slice(&u32::MAX.to_le_bytes());
+        huge_value.extend_from_slice(&8u32.to_le_bytes());
+        for hostile in [
+            tiff(u32::MAX, &[]),
+            tiff(u32::MAX - 1, &[]),
+            sub_ifd(u32::MAX - 3),
+            sub_ifd(u32::MAX),
+            tiff(8, &huge_value),
+            tiff(8, &[0xff, 0xff]),
+        ] {
+            assert!(Exif::parse(&hostile).is_err(), "{:?}", hostile);
+        }
+
+        // unreadable EXIF is kept as it is
+        let mut payload = EXIF_HEADER.to_vec();
+        payload.extend_from_slice(&sub_ifd(u32::MAX - 3));
+        let input = jpeg(&[&app1(&payload)[..]]);
+        assert_eq!(with_exif(&input, &tags()), input);
+    }
+}
@@ -0,0 +1,319 @@
+//! A C API for using the library from other languages, built with the ffi feature. The
+//! cryptocam-ffi crate in ffi/ builds it into libcryptocam.so and libcryptocam.a,
+//! include/cryptocam.h is generated from this file with cbindgen, see cbindgen.toml.
+//!
+//! Strings passed in and out are NUL terminated UTF-8. Strings returned by the library are
+//! owned by the caller and freed with cryptocam_string_free(). Functions that fail return
+//! CRYPTOCAM_STATUS_ERROR or NULL and leave a message for cryptocam_last_error(), panics are
+//! caught and reported as CRYPTOCAM_STATUS_PANIC.
+
+use crate::{
+    decrypt::{decrypt_with_options, DecryptOptions, DecryptWarning, ProgressCallback},
+    keyring::Keyring,
+};
+use anyhow::{anyhow, Result};
+use std::{
+    cell::RefCell,
+    error::Error,
+    ffi::{CStr, CString},
+    fs::File,
+    os::raw::{c_char, c_void},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptocamStatus {
+    Ok = 0,
+    Error = 1,
+    InvalidArgument = 2,
+    Cancelled = 3,
+    Panic = 4,
+}
+
+/// A keyring, see Keyring.
+pub struct CryptocamKeyring(Keyring);
+
+/// Cancels a running cryptocam_decrypt_file() from another thread.
+pub struct CryptocamCancelToken(Arc<AtomicBool>);
+
+/// Receives the events of cryptocam_decrypt_file(). Every function pointer may be NULL.
+/// They are called on the thread running the decryption, with `user_data` as it is set here.
+#[repr(C)]
+pub struct CryptocamCallbacks {
+    pub user_data: *mut c_void,
+    /// Bytes of the input processed so far and its total size, 0 if unknown.
+    pub on_progress: Option<extern "C" fn(user_data: *mut c_void, processed: u64, total: u64)>,
+    pub on_warning: Option<extern "C" fn(user_data: *mut c_void, message: *const c_char)>,
+    /// Also reported through cryptocam_last_error() after cryptocam_decrypt_file() returns.
+    pub on_error: Option<extern "C" fn(user_data: *mut c_void, message: *const c_char)>,
+    /// Path of the output file, before anything is written to it.
+    pub on_output_created: Option<extern "C" fn(user_data: *mut c_void, path: *const c_char)>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/// Runs `f`, turning errors and panics into a status code and the last error.
+fn guard(f: impl FnOnce() -> Result<CryptocamStatus>) -> CryptocamStatus {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(status)) => status,
+        Ok(Err(e)) => {
+            set_last_error(format!("{:#}", e));
+            CryptocamStatus::Error
+        }
+        Err(_) => {
+            set_last_error("Panic in libcryptocam".to_owned());
+            CryptocamStatus::Panic
+        }
+    }
+}
+
+/// Like guard(), for functions that return a pointer, NULL on failure.
+fn guard_ptr<T>(f: impl FnOnce() -> Result<*mut T>) -> *mut T {
+    let mut result = ptr::null_mut();
+    guard(|| {
+        result = f()?;
+        Ok(CryptocamStatus::Ok)
+    });
+    result
+}
+
+/// # Safety
+/// `s` must be NULL or a NUL terminated string.
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str> {
+    if s.is_null() {
+        return Err(anyhow!("{} is NULL", name));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| anyhow!("{} is not valid UTF-8", name))
+}
+
+fn to_c_string(s: &str) -> CString {
+    CString::new(s.replace('\0', "")).unwrap()
+}
+
+/// The message of the last error on this thread, or NULL if there was none. Free it with
+/// cryptocam_string_free().
+#[no_mangle]
+pub extern "C" fn cryptocam_last_error() -> *mut c_char {
+    LAST_ERROR.with(|e| match &*e.borrow() {
+        Some(message) => message.clone().into_raw(),
+        None => ptr::null_mut(),
+    })
+}
+
+/// Frees a string returned by the library. NULL is ignored.
+///
+/// # Safety
+/// `s` must be NULL or a string returned by the library that wasn't freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn cryptocam_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Loads the keyring stored in the directory `path`, or NULL on failure.
+/// Free it with cryptocam_keyring_free().
+///
+/// # Safety
+/// `path` must be a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn cryptocam_keyring_new(path: *const c_char) -> *mut CryptocamKeyring {
+    guard_ptr(|| {
+        let path = str_arg(path, "path")?;
+        let keyring = Keyring::load(Path::new(path))?;
+        Ok(Box::into_raw(Box::new(CryptocamKeyring(keyring))))
+    })
+}
+
+/// # Safety
+/// `keyring` must be NULL or returned by cryptocam_keyring_new() and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn cryptocam_keyring_free(keyring: *mut CryptocamKeyring) {
+    if !keyring.is_null() {
+        drop(Box::from_raw(keyring));
+    }
+}
+
+/// Imports an age secret key (AGE-SECRET-KEY-1...) into the keyring. `label` may be NULL.
+/// If `digest_out` isn't NULL, it receives the hex encoded digest of the key, to be freed
+/// with cryptocam_string_free().
+///
+/// # Safety
+/// `keyring` must be returned by cryptocam_keyring_new(), `key` and `label` NUL terminated
+/// strings, and `digest_out` NULL or valid for writing a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn cryptocam_keyring_import_key(
+    keyring: *mut CryptocamKeyring,
+    key: *const c_char,
+    label: *const c_char,
+    digest_out: *mut *mut c_char,
+) -> CryptocamStatus {
+    guard(|| {
+        let keyring = match keyring.as_mut() {
+            Some(keyring) => keyring,
+            None => return invalid_argument("keyring is NULL"),
+        };
+        let key = str_arg(key, "key")?;
+        let label = if label.is_null() {
+            None
+        } else {
+            Some(str_arg(label, "label")?.to_owned())
+        };
+        let digest = keyring.0.import_key(key, label)?;
+        if !digest_out.is_null() {
+            *digest_out = to_c_string(&digest.to_hex()).into_raw();
+        }
+        Ok(CryptocamStatus::Ok)
+    })
+}
+
+/// Free it with cryptocam_cancel_token_free().
+#[no_mangle]
+pub extern "C" fn cryptocam_cancel_token_new() -> *mut CryptocamCancelToken {
+    Box::into_raw(Box::new(CryptocamCancelToken(Arc::new(AtomicBool::new(
+        false,
+    )))))
+}
+
+/// Makes decryptions using the token stop as soon as possible. May be called from any thread.
+///
+/// # Safety
+/// `token` must be returned by cryptocam_cancel_token_new() and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn cryptocam_cancel_token_cancel(token: *const CryptocamCancelToken) {
+    if let Some(token) = token.as_ref() {
+        token.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// # Safety
+/// `token` must be NULL or returned by cryptocam_cancel_token_new(), and not be in use by a
+/// running decryption.
+#[no_mangle]
+pub unsafe extern "C" fn cryptocam_cancel_token_free(token: *mut CryptocamCancelToken) {
+    if !token.is_null() {
+        drop(Box::from_raw(token));
+    }
+}
+
+/// Decrypts the file at `path` into the directory `out_dir` with the default options, blocking
+/// until done. `callbacks` and `cancel_token` may be NULL.
+///
+/// # Safety
+/// `keyring` must be returned by cryptocam_keyring_new(), `path` and `out_dir` NUL terminated
+/// strings, `callbacks` NULL or valid for the whole call, and `cancel_token` NULL or returned by
+/// cryptocam_cancel_token_new().
+#[no_mangle]
+pub unsafe extern "C" fn cryptocam_decrypt_file(
+    keyring: *mut CryptocamKeyring,
+    path: *const c_char,
+    out_dir: *const c_char,
+    callbacks: *const CryptocamCallbacks,
+    cancel_token: *const CryptocamCancelToken,
+) -> CryptocamStatus {
+    guard(|| {
+        let keyring = match keyring.as_mut() {
+            Some(keyring) => keyring,
+            None => return invalid_argument("keyring is NULL"),
+        };
+        let path = str_arg(path, "path")?;
+        let out_dir = str_arg(out_dir, "out_dir")?;
+        let cancel = match cancel_token.as_ref() {
+            Some(token) => token.0.clone(),
+            None => Arc::new(AtomicBool::new(false)),
+        };
+        let mut job = decrypt_with_options(
+            File::open(path)?,
+            &mut keyring.0,
+            PathBuf::from(out_dir),
+            DecryptOptions::new(),
+        )?;
+        let mut callback = CallbackAdapter {
+            callbacks: callbacks.as_ref(),
+            total: 0,
+            error: None,
+            complete: false,
+        };
+        job.run(Box::new(&mut callback), cancel.clone());
+        match callback.error {
+            Some(e) => Err(anyhow!(e)),
+            None if callback.complete => Ok(CryptocamStatus::Ok),
+            None if cancel.load(Ordering::Relaxed) => Ok(CryptocamStatus::Cancelled),
+            None => Err(anyhow!("Decryption stopped without an error")),
+        }
+    })
+}
+
+fn invalid_argument(message: &str) -> Result<CryptocamStatus> {
+    set_last_error(message.to_owned());
+    Ok(CryptocamStatus::InvalidArgument)
+}
+
+struct CallbackAdapter<'a> {
+    callbacks: Option<&'a CryptocamCallbacks>,
+    total: u64,
+    error: Option<String>,
+    complete: bool,
+}
+
+impl CallbackAdapter<'_> {
+    fn call_with_str(
+        &self,
+        f: impl Fn(&CryptocamCallbacks) -> Option<extern "C" fn(*mut c_void, *const c_char)>,
+        s: &str,
+    ) {
+        if let Some(callbacks) = self.callbacks {
+            if let Some(f) = f(callbacks) {
+                f(callbacks.user_data, to_c_string(s).as_ptr());
+            }
+        }
+    }
+}
+
+impl ProgressCallback for CallbackAdapter<'_> {
+    fn set_total_file_size(&mut self, n: u64) {
+        self.total = n;
+    }
+
+    fn on_progress(&mut self, processed_bytes: u64) {
+        if let Some(callbacks) = self.callbacks {
+            if let Some(on_progress) = callbacks.on_progress {
+                on_progress(callbacks.user_data, processed_bytes, self.total);
+            }
+        }
+    }
+
+    fn on_complete(&mut self) {
+        self.complete = true;
+    }
+
+    fn on_error(&mut self, error: Box<dyn Error>) {
+        let message = error.to_string();
+        self.call_with_str(|c| c.on_error, &message);
+        self.error = Some(message);
+    }
+
+    fn on_warning(&mut self, warning: DecryptWarning) {
+        self.call_with_str(|c| c.on_warning, &warning.to_string());
+    }
+
+    fn on_output_created(&mut self, path: &Path) {
+        self.call_with_str(|c| c.on_output_created, &path.to_string_lossy());
+    }
+}
@@ -0,0 +1,202 @@
+/*
+The digest a file's header stores for each of its recipients, the last 16 bytes of the SHA-256
+of the recipient's age public key. Shown to users as the key's fingerprint, in uppercase hex
+grouped by 4 digits:
+
+3F2A 9C10 E4B7 0D5C 81A2 6F33 C09E 7B14
+
+so it can be read out and compared between the phone and the desktop.
+*/
+
+use anyhow::{bail, Result};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::{
+    convert::TryInto,
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+
+/// The digest of a recipient's public key, see parser::Header::recipient_digests.
+/// Compared in constant time, so comparing against a secret digest doesn't leak it.
+#[derive(Clone, Copy)]
+pub struct RecipientDigest([u8; 16]);
+
+impl RecipientDigest {
+    pub const LEN: usize = 16;
+
+    /// The digest of an age public key (age1...).
+    pub fn of_public_key(public_key: &str) -> Self {
+        let hash = Sha256::digest(public_key.as_bytes());
+        RecipientDigest(hash[16..32].try_into().unwrap())
+    }
+
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        RecipientDigest(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    /// Lowercase hex without groups, as in metadata sidecars and the C API.
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// The first 8 digits of the fingerprint, e.g. "3F2A9C10", for places with little room.
+    /// Too short to tell keys apart reliably, compare the full fingerprint for that.
+    pub fn short(&self) -> String {
+        self.0[..4].iter().map(|b| format!("{:02X}", b)).collect()
+    }
+}
+
+impl From<[u8; 16]> for RecipientDigest {
+    fn from(bytes: [u8; 16]) -> Self {
+        RecipientDigest(bytes)
+    }
+}
+
+impl PartialEq for RecipientDigest {
+    fn eq(&self, other: &Self) -> bool {
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+    }
+}
+
+impl Eq for RecipientDigest {}
+
+impl Hash for RecipientDigest {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl fmt::Display for RecipientDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, group) in self.0.chunks(2).enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{:02X}{:02X}", group[0], group[1])?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for RecipientDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RecipientDigest({})", self.to_hex())
+    }
+}
+
+/// Accepts the grouped form Display writes as well as 32 hex digits without spaces, in either
+/// case.
+impl FromStr for RecipientDigest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let hex: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+        if hex.len() != 2 * Self::LEN {
+            bail!("A fingerprint has 32 hex digits, not {:?}", s.trim());
+        }
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("Invalid hex in fingerprint {:?}", s.trim());
+        }
+        let mut bytes = [0; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)?;
+        }
+        Ok(RecipientDigest(bytes))
+    }
+}
+
+/// As to_hex(), the way sidecars and batch plans write digests.
+impl Serialize for RecipientDigest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+/// Accepts what FromStr does.
+impl<'de> Deserialize<'de> for RecipientDigest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Digests spread over the whole range, the same on every run.
+    fn digests() -> impl Iterator<Item = RecipientDigest> {
+        (0..1000)
+            .map(|i| RecipientDigest::of_public_key(&format!("age1{}", i)))
+            .chain([RecipientDigest([0; 16]), RecipientDigest([0xff; 16])])
+    }
+
+    #[test]
+    fn formatted_digests_parse_back() {
+        for digest in digests() {
+            let display = digest.to_string();
+            assert_eq!(display.len(), 39);
+            assert_eq!(display.split(' ').count(), 8);
+            assert_eq!(display.parse::<RecipientDigest>().unwrap(), digest);
+            assert_eq!(
+                display.to_lowercase().parse::<RecipientDigest>().unwrap(),
+                digest
+            );
+            let hex = digest.to_hex();
+            assert_eq!(hex.len(), 32);
+            assert_eq!(hex.parse::<RecipientDigest>().unwrap(), digest);
+            assert_eq!(
+                hex.to_uppercase().parse::<RecipientDigest>().unwrap(),
+                digest
+            );
+            assert_eq!(display.replace(' ', "").to_lowercase(), hex);
+            assert!(display.replace(' ', "").starts_with(&digest.short()));
+        }
+    }
+
+    #[test]
+    fn digests_serialize_as_hex() {
+        for digest in digests() {
+            let json = serde_json::to_string(&digest).unwrap();
+            assert_eq!(json, format!("\"{}\"", digest.to_hex()));
+            assert_eq!(
+                serde_json::from_str::<RecipientDigest>(&json).unwrap(),
+                digest
+            );
+            let display = serde_json::to_string(&digest.to_string()).unwrap();
+            assert_eq!(
+                serde_json::from_str::<RecipientDigest>(&display).unwrap(),
+                digest
+            );
+        }
+        assert!(serde_json::from_str::<RecipientDigest>("[1, 2, 3]").is_err());
+    }
+
+    #[test]
+    fn malformed_fingerprints_are_refused() {
+        let hex = RecipientDigest::from_bytes([0xab; 16]).to_hex();
+        for malformed in [
+            "",
+            &hex[..30],
+            format!("{}ab", hex).as_str(),
+            format!("{}g", &hex[..31]).as_str(),
+            format!("{}+", &hex[..31]).as_str(),
+        ] {
+            assert!(
+                malformed.parse::<RecipientDigest>().is_err(),
+                "{:?}",
+                malformed
+            );
+        }
+    }
+}
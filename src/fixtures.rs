@@ -0,0 +1,179 @@
+//! Cryptocam files built in memory, for tests of this crate and of programs using it. Built
+//! with the test-fixtures feature, e.g. as a dev-dependency:
+//!
+//! ```toml
+//! libcryptocam = { version = "0.1", features = ["test-fixtures"] }
+//! ```
+//!
+//! Files are encrypted to the fixed test key, test_keyring() decrypts them:
+//!
+//! ```ignore
+//! let video = FixtureVideo::new()
+//!     .video_packet(0, b"\x00\x00\x00\x01\x67...")
+//!     .audio_packet(0, b"\xff\xf1...");
+//! let file = FixtureFile::video(metadata_json, video).build();
+//! let job = decrypt_from_reader(Cursor::new(file), None, &mut test_keyring(), out_dir, options)?;
+//! ```
+//!
+//! The decrypted contents are always the same, the encrypted bytes are not: age picks a new file
+//! key and ephemeral key every time. Cut the built file short to test truncated recordings.
+
+use crate::{
+    decrypt::{FILE_TYPE_AUDIO, FILE_TYPE_IMAGE, FILE_TYPE_VIDEO},
+    encrypt::{encrypt_to, start_file, Recipient},
+    keyring::Keyring,
+    packet::{PacketHeader, PacketKind},
+};
+use std::io::Write;
+
+/// The secret key of the test recipient. Only for tests, anyone can decrypt files encrypted
+/// to it.
+pub const TEST_SECRET_KEY: &str =
+    "AGE-SECRET-KEY-17RH45R7JUVAH28PKWTGEJHG07CRAKGQ3PE6VAM7QVFESCN0UWF0QP4QGWV";
+/// The public key belonging to TEST_SECRET_KEY.
+pub const TEST_PUBLIC_KEY: &str = "age19dsycucfhwf8pcmwrhpzms8mglstwt4n63xtsg0zmqp50s54aseqz6r5pe";
+
+/// The recipient fixtures are encrypted to unless others are given.
+pub fn test_recipient() -> Recipient {
+    TEST_PUBLIC_KEY
+        .parse()
+        .expect("the test public key is valid")
+}
+
+/// An in-memory keyring holding TEST_SECRET_KEY, labeled "test".
+pub fn test_keyring() -> Keyring {
+    let mut keyring = Keyring::in_memory();
+    keyring
+        .import_key(TEST_SECRET_KEY, Some("test".to_owned()))
+        .expect("the test secret key is valid");
+    keyring
+}
+
+/// A Cryptocam file to build: a version 1 header, then the age encrypted file type, metadata
+/// and payload.
+#[derive(Debug, Clone)]
+pub struct FixtureFile {
+    recipients: Vec<Recipient>,
+    file_type: u8,
+    metadata: Vec<u8>,
+    payload: Vec<u8>,
+}
+
+impl FixtureFile {
+    /// An image, `metadata` is the JSON the app writes, e.g. from encrypt::ImageMetadata, and
+    /// is stored as it is, so it may as well be invalid.
+    pub fn image(metadata: impl Into<Vec<u8>>, image: impl Into<Vec<u8>>) -> Self {
+        Self::raw(FILE_TYPE_IMAGE, metadata, image)
+    }
+
+    /// A video with the packets of `video`.
+    pub fn video(metadata: impl Into<Vec<u8>>, video: FixtureVideo) -> Self {
+        Self::raw(FILE_TYPE_VIDEO, metadata, video.payload)
+    }
+
+    /// An audio recording, the packets of `audio` should all be audio packets.
+    pub fn audio(metadata: impl Into<Vec<u8>>, audio: FixtureVideo) -> Self {
+        Self::raw(FILE_TYPE_AUDIO, metadata, audio.payload)
+    }
+
+    /// Any file type with any payload, e.g. for types only a custom JobBuilder knows.
+    pub fn raw(file_type: u8, metadata: impl Into<Vec<u8>>, payload: impl Into<Vec<u8>>) -> Self {
+        FixtureFile {
+            recipients: vec![test_recipient()],
+            file_type,
+            metadata: metadata.into(),
+            payload: payload.into(),
+        }
+    }
+
+    /// Encrypts to `recipients` instead of the test recipient.
+    pub fn recipients(mut self, recipients: Vec<Recipient>) -> Self {
+        self.recipients = recipients;
+        self
+    }
+
+    /// The encrypted file.
+    pub fn build(&self) -> Vec<u8> {
+        let mut encrypted =
+            start_file(&self.recipients, self.file_type, &self.metadata, Vec::new())
+                .expect("encrypting to a Vec doesn't fail");
+        encrypted
+            .write_all(&self.payload)
+            .expect("encrypting to a Vec doesn't fail");
+        encrypted
+            .finish()
+            .expect("encrypting to a Vec doesn't fail")
+    }
+}
+
+/// Encrypts `decrypted` to the test recipient as it is, without a file type and metadata in
+/// front, so that they can be anything, e.g. an offset to data past the end of the file.
+pub fn encrypt_raw(decrypted: &[u8]) -> Vec<u8> {
+    let mut encrypted =
+        encrypt_to(&[test_recipient()], Vec::new()).expect("encrypting to a Vec doesn't fail");
+    encrypted
+        .write_all(decrypted)
+        .expect("encrypting to a Vec doesn't fail");
+    encrypted
+        .finish()
+        .expect("encrypting to a Vec doesn't fail")
+}
+
+/// The packet stream of a video or audio recording, see FixtureFile::video(). Unlike
+/// encrypt::VideoEncryptor, packets aren't checked, so they may be empty, out of order or
+/// longer than any muxer takes.
+#[derive(Debug, Clone, Default)]
+pub struct FixtureVideo {
+    payload: Vec<u8>,
+}
+
+impl FixtureVideo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn video_packet(self, pts_us: u64, data: &[u8]) -> Self {
+        self.packet(PacketKind::Video, pts_us, data)
+    }
+
+    pub fn audio_packet(self, pts_us: u64, data: &[u8]) -> Self {
+        self.packet(PacketKind::Audio, pts_us, data)
+    }
+
+    /// A packet of any kind, e.g. PacketKind::Unknown like newer versions of the app write.
+    pub fn packet(mut self, kind: PacketKind, pts_us: u64, data: &[u8]) -> Self {
+        let header = PacketHeader {
+            kind,
+            pts_us,
+            length: data.len(),
+        };
+        self.payload.extend_from_slice(&header.to_bytes());
+        self.payload.extend_from_slice(data);
+        self
+    }
+
+    /// `frames` video packets of `frame_len` bytes at 30 frames per second that pass for H.264
+    /// with the muxers: the first holds an SPS, a PPS and an IDR slice, the others a slice
+    /// each. The slices are filler, they don't decode to pictures.
+    pub fn h264_frames(mut self, frames: u64, frame_len: usize) -> Self {
+        const SPS_PPS_IDR: &[u8] = b"\x00\x00\x00\x01\x67\x42\x00\x1e\xab\x00\x00\x00\x01\x68\xce\x3c\x80\x00\x00\x00\x01\x65";
+        const SLICE: &[u8] = b"\x00\x00\x00\x01\x41";
+        for i in 0..frames {
+            let mut frame = if i == 0 { SPS_PPS_IDR } else { SLICE }.to_vec();
+            frame.resize(frame_len.max(frame.len()), 0x88);
+            self = self.video_packet(i * 33_333, &frame);
+        }
+        self
+    }
+
+    /// Appends `bytes` as they are, e.g. half a packet header or garbage between packets.
+    pub fn raw_bytes(mut self, bytes: &[u8]) -> Self {
+        self.payload.extend_from_slice(bytes);
+        self
+    }
+
+    /// The decrypted payload as the packet reader sees it.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
@@ -0,0 +1,88 @@
+//! Entry points for the fuzz targets in fuzz/, built with the fuzzing feature. They reach
+//! parsers that aren't public otherwise and are not a stable API.
+
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+use crate::{decrypt_audio::parse_audio_metadata, decrypt_video::parse_video_metadata};
+use crate::{
+    decrypt_image::parse_metadata,
+    output_path::{
+        output_file_name, BatchNames, MediaInfo, NameTemplate, OutputNaming, SubdirectoryStrategy,
+    },
+    timestamp::parse_timestamp,
+};
+use std::path::{Component, Path};
+
+/// Parses `json` as the metadata of an image, and of a video and an audio recording if those
+/// are built, then names an output file after each that parses.
+pub fn parse_any_metadata(json: &str) {
+    if let Ok(metadata) = parse_metadata(json) {
+        name_output(MediaInfo {
+            timestamp: metadata.timestamp,
+            media_type: "image",
+            codec: metadata.format.clone(),
+            width: None,
+            height: None,
+            source_stem: None,
+            device_label: None,
+            extra: metadata.extra,
+            extension: metadata.format,
+        });
+    }
+    #[cfg(any(feature = "video", feature = "rust-mp4"))]
+    if let Ok(metadata) = parse_video_metadata(json) {
+        name_output(MediaInfo {
+            timestamp: metadata.timestamp,
+            media_type: "video",
+            codec: metadata.codec.unwrap_or_default(),
+            width: Some(metadata.width),
+            height: Some(metadata.height),
+            source_stem: None,
+            device_label: None,
+            extra: metadata.extra,
+            extension: "mp4".to_owned(),
+        });
+    }
+    #[cfg(any(feature = "video", feature = "rust-mp4"))]
+    if let Ok(metadata) = parse_audio_metadata(json) {
+        name_output(MediaInfo {
+            timestamp: metadata.timestamp,
+            media_type: "audio",
+            codec: format!("{:?}", metadata.audio_codec),
+            width: None,
+            height: None,
+            source_stem: None,
+            device_label: None,
+            extra: metadata.extra,
+            extension: "m4a".to_owned(),
+        });
+    }
+}
+
+/// Goes through the ways output files are named and sorted into subdirectories, none of them
+/// may panic whatever the metadata says. Nothing is created on disk.
+fn name_output(info: MediaInfo) {
+    let _ = parse_timestamp(&info.timestamp);
+    let batch_names = BatchNames::new();
+    batch_names.register(&info.timestamp);
+    batch_names.register(&format!("{}.5", info.timestamp));
+    let template = NameTemplate::parse(
+        "{timestamp}_{type}_{codec}_{width}x{height}_{device}_{extra.label}_{extra.n}",
+    )
+    .expect("the template is valid");
+    for naming in [OutputNaming::Timestamp, OutputNaming::Template(template)] {
+        for name in [
+            output_file_name(&info, &naming, None),
+            output_file_name(&info, &naming, Some(&batch_names)),
+        ] {
+            let mut components = Path::new(&name).components();
+            assert!(matches!(components.next(), Some(Component::Normal(_))));
+            assert!(components.next().is_none());
+        }
+    }
+    for format in ["%Y-%m-%d", "%Y/%m"] {
+        let strategy = SubdirectoryStrategy::ByDate {
+            format: format.to_owned(),
+        };
+        assert!(strategy.subdirectory(&info.timestamp).is_relative());
+    }
+}
@@ -0,0 +1,314 @@
+/*
+Every artifact that stores a hash records it as "<algo>:<hex>", e.g.
+sha256:9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08
+so that verification can recompute with the algorithm the hash was created with,
+regardless of which algorithm is currently configured.
+*/
+
+use anyhow::{anyhow, bail, Result};
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    fmt,
+    io::{Read, Write},
+    str::FromStr,
+};
+
+/// Hash algorithm used for output digests and integrity checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum HashAlgo {
+    #[default]
+    Sha256,
+    Sha512,
+    #[cfg(feature = "blake3")]
+    Blake3,
+}
+
+impl HashAlgo {
+    /// The name recorded next to every stored hash.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Sha512 => "sha512",
+            #[cfg(feature = "blake3")]
+            HashAlgo::Blake3 => "blake3",
+        }
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        let state = match self {
+            HashAlgo::Sha256 => HasherState::Sha256(Sha256::new()),
+            HashAlgo::Sha512 => HasherState::Sha512(Sha512::new()),
+            #[cfg(feature = "blake3")]
+            HashAlgo::Blake3 => HasherState::Blake3(Box::new(blake3::Hasher::new())),
+        };
+        Hasher { algo: *self, state }
+    }
+}
+
+impl fmt::Display for HashAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgo {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().replace('-', "").as_str() {
+            "sha256" => Ok(HashAlgo::Sha256),
+            "sha512" => Ok(HashAlgo::Sha512),
+            #[cfg(feature = "blake3")]
+            "blake3" => Ok(HashAlgo::Blake3),
+            #[cfg(not(feature = "blake3"))]
+            "blake3" => bail!("Hash algorithm blake3 requires the blake3 feature"),
+            other => bail!("Unknown hash algorithm {}", other),
+        }
+    }
+}
+
+enum HasherState {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+/// Incremental hasher for any of the supported algorithms.
+pub struct Hasher {
+    algo: HashAlgo,
+    state: HasherState,
+}
+
+impl Hasher {
+    pub fn algo(&self) -> HashAlgo {
+        self.algo
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.state {
+            HasherState::Sha256(h) => h.update(data),
+            HasherState::Sha512(h) => h.update(data),
+            #[cfg(feature = "blake3")]
+            HasherState::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    pub fn finalize(self) -> HashDigest {
+        let bytes = match self.state {
+            HasherState::Sha256(h) => h.finalize().to_vec(),
+            HasherState::Sha512(h) => h.finalize().to_vec(),
+            #[cfg(feature = "blake3")]
+            HasherState::Blake3(h) => h.finalize().as_bytes().to_vec(),
+        };
+        HashDigest {
+            algo: self.algo,
+            bytes,
+        }
+    }
+}
+
+/// A finished hash together with the algorithm that produced it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HashDigest {
+    algo: HashAlgo,
+    bytes: Vec<u8>,
+}
+
+impl HashDigest {
+    pub fn algo(&self) -> HashAlgo {
+        self.algo
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Hashes `reader` with this digest's algorithm and compares the result.
+    pub fn verify(&self, reader: impl Read) -> Result<bool> {
+        let mut reader = HashingReader::new(reader, self.algo);
+        std::io::copy(&mut reader, &mut std::io::sink())?;
+        Ok(reader.finalize().1 == *self)
+    }
+}
+
+impl fmt::Display for HashDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algo, self.to_hex())
+    }
+}
+
+impl FromStr for HashDigest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (algo, hex) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Hash {} does not record its algorithm", s))?;
+        let algo: HashAlgo = algo.parse()?;
+        if hex.len() % 2 != 0 || !hex.is_ascii() {
+            bail!("Invalid hex in hash {}", s);
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| anyhow!("Invalid hex in hash {}", s))?;
+        if bytes.len() != algo.hasher().finalize().bytes.len() {
+            bail!("Hash {} has the wrong length for {}", s, algo);
+        }
+        Ok(HashDigest { algo, bytes })
+    }
+}
+
+/// Writer that hashes and counts everything written through it.
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Hasher,
+    bytes: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W, algo: HashAlgo) -> Self {
+        HashingWriter {
+            inner,
+            hasher: algo.hasher(),
+            bytes: 0,
+        }
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn finalize(self) -> (W, HashDigest) {
+        (self.inner, self.hasher.finalize())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader that hashes and counts everything read through it.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Hasher,
+    bytes: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R, algo: HashAlgo) -> Self {
+        HashingReader {
+            inner,
+            hasher: algo.hasher(),
+            bytes: 0,
+        }
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn finalize(self) -> (R, HashDigest) {
+        (self.inner, self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.bytes += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest_of(algo: HashAlgo, data: &[u8]) -> HashDigest {
+        let mut hasher = algo.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    fn algos() -> Vec<HashAlgo> {
+        vec![
+            HashAlgo::Sha256,
+            HashAlgo::Sha512,
+            #[cfg(feature = "blake3")]
+            HashAlgo::Blake3,
+        ]
+    }
+
+    #[test]
+    fn digests_are_those_of_the_algorithm() {
+        assert_eq!(
+            digest_of(HashAlgo::Sha256, b"abc").to_string(),
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            digest_of(HashAlgo::Sha512, b"abc").to_string(),
+            "sha512:ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+        );
+        #[cfg(feature = "blake3")]
+        assert_eq!(
+            digest_of(HashAlgo::Blake3, b"abc").as_bytes(),
+            blake3::hash(b"abc").as_bytes()
+        );
+    }
+
+    #[test]
+    fn digests_parse_back_with_their_algorithm() {
+        for algo in algos() {
+            let digest = digest_of(algo, b"abc");
+            let parsed: HashDigest = digest.to_string().parse().unwrap();
+            assert_eq!(parsed, digest);
+            assert_eq!(parsed.algo(), algo);
+        }
+        let sha256 = digest_of(HashAlgo::Sha256, b"abc").to_hex();
+        // the length has to be that of the algorithm
+        assert!(format!("sha512:{}", sha256).parse::<HashDigest>().is_err());
+        // and the algorithm has to be recorded and known
+        assert!(sha256.parse::<HashDigest>().is_err());
+        assert!("md5:900150983cd24fb0d6963f7d28e17f72"
+            .parse::<HashDigest>()
+            .is_err());
+    }
+
+    #[test]
+    fn verify_recomputes_with_the_recorded_algorithm() {
+        for algo in algos() {
+            let digest: HashDigest = digest_of(algo, b"abc").to_string().parse().unwrap();
+            assert!(digest.verify(&b"abc"[..]).unwrap(), "{}", algo);
+            assert!(!digest.verify(&b"abd"[..]).unwrap(), "{}", algo);
+        }
+        let mut writer = HashingWriter::new(vec![], HashAlgo::Sha512);
+        writer.write_all(b"abc").unwrap();
+        assert_eq!(writer.bytes_written(), 3);
+        let (written, digest) = writer.finalize();
+        assert_eq!(written, b"abc");
+        assert_eq!(digest, digest_of(HashAlgo::Sha512, b"abc"));
+    }
+}
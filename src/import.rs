@@ -0,0 +1,319 @@
+//! Importing MP4 and MOV files recorded elsewhere as Cryptocam videos, see
+//! encrypt::import_mp4().
+
+use crate::{
+    decrypt_video::{AudioCodec, VideoMetadata},
+    encrypt::{Recipient, VideoEncryptor},
+    error::Error,
+    mp4,
+};
+use ac_ffmpeg::{
+    codec::{bsf::BitstreamFilter, CodecParameters},
+    format::{demuxer::Demuxer, io::IO},
+    packet::Packet,
+    time::TimeBase,
+};
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{info, warn};
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+/// Options for encrypt::import_mp4().
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    /// The recording's timestamp in the metadata, ISO 8601. By default the creation time of
+    /// the input, or its modification time if it has none.
+    pub timestamp: Option<String>,
+    /// Import the audio stream, if there is one. On by default.
+    pub include_audio: bool,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        ImportOptions {
+            timestamp: None,
+            include_audio: true,
+        }
+    }
+}
+
+// setters for the fields above, see there
+impl ImportOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn timestamp(mut self, timestamp: impl Into<String>) -> Self {
+        self.timestamp = Some(timestamp.into());
+        self
+    }
+
+    pub fn include_audio(mut self, include_audio: bool) -> Self {
+        self.include_audio = include_audio;
+        self
+    }
+}
+
+/// Encrypts the MP4 or MOV file at `input` to `recipients` as a Cryptocam video at `out`, like
+/// the app would have recorded it: the first video stream, H.264 or H.265, goes in Annex B
+/// format and the first audio stream, AAC, with ADTS headers. Fails with
+/// error::Error::UnsupportedStreams if an audio or video stream has another codec. Nothing is
+/// left at `out` if importing fails.
+///
+/// The PTS are kept as they are, so videos with B-frames, which phones don't record, get their
+/// out of order timestamps fixed up when decrypting, see DecryptOptions::non_monotonic_pts.
+pub fn import_mp4(
+    input: &Path,
+    recipients: &[Recipient],
+    out: &Path,
+    options: ImportOptions,
+) -> Result<()> {
+    let file = File::create(out).map_err(|e| anyhow!("Error creating {}: {}", out.display(), e))?;
+    let result = import_to(input, recipients, BufWriter::new(file), &options);
+    if result.is_err() {
+        let _ = fs::remove_file(out);
+    }
+    result
+}
+
+/// The streams import_mp4() takes, by their index in the input.
+struct SelectedStreams {
+    video: usize,
+    audio: Option<usize>,
+}
+
+fn import_to(
+    input: &Path,
+    recipients: &[Recipient],
+    out: impl Write,
+    options: &ImportOptions,
+) -> Result<()> {
+    let mut file =
+        File::open(input).map_err(|e| anyhow!("Error opening {}: {}", input.display(), e))?;
+    let movie = mp4::movie_info(&mut file)?;
+    let io = IO::from_seekable_read_stream(File::open(input)?);
+    let mut demuxer = Demuxer::builder()
+        .build(io)?
+        .find_stream_info(None)
+        .map_err(|(_, e)| anyhow!("Error reading {}: {}", input.display(), e))?;
+
+    let streams = select_streams(
+        demuxer
+            .streams()
+            .iter()
+            .map(|stream| stream.codec_parameters())
+            .collect(),
+        options.include_audio,
+    )?;
+    let video_params = demuxer.streams()[streams.video].codec_parameters();
+    let video = video_params.as_video_codec_parameters().unwrap();
+    let timestamp = match &options.timestamp {
+        Some(timestamp) => timestamp.clone(),
+        None => movie
+            .creation_time
+            .map_or_else(|| modification_time(&file), Ok)?
+            .to_rfc3339_opts(SecondsFormat::Millis, true),
+    };
+    let mut metadata = VideoMetadata::new(timestamp, video.width(), video.height())
+        .rotation(movie.rotation.unwrap_or(0))
+        .video_bitrate(video.bit_rate());
+    if video_params.decoder_name() == Some("hevc") {
+        metadata = metadata.codec("hevc");
+    }
+    let mut video_filter = BitstreamFilter::builder(match metadata.codec.as_deref() {
+        Some("hevc") => "hevc_mp4toannexb",
+        _ => "h264_mp4toannexb",
+    })
+    .and_then(|builder| builder.input_codec_parameters(&video_params).build())
+    .map_err(|e| anyhow!("Error creating video filter: {}", e))?;
+
+    let mut adts = None;
+    if let Some(index) = streams.audio {
+        let params = demuxer.streams()[index].codec_parameters();
+        let audio = params.as_audio_codec_parameters().unwrap();
+        let config = match audio.extradata() {
+            Some(asc) => AdtsConfig::from_asc(asc)?,
+            None => AdtsConfig::aac_lc(audio.sample_rate(), audio.channel_layout().channels())?,
+        };
+        metadata = metadata
+            .audio_codec(AudioCodec::Aac)
+            .audio_sample_rate(audio.sample_rate())
+            .audio_channel_count(audio.channel_layout().channels())
+            .audio_bitrate(audio.bit_rate());
+        if let Some(asc) = audio.extradata() {
+            metadata = metadata.audio_csd(asc);
+        }
+        adts = Some(config);
+    }
+
+    let mut encryptor = VideoEncryptor::new(recipients, &metadata, out)?;
+    while let Some(packet) = demuxer.take()? {
+        if packet.stream_index() == streams.video {
+            video_filter
+                .push(packet)
+                .map_err(|e| anyhow!("Error pushing to video filter: {}", e))?;
+            while let Some(packet) = video_filter.take()? {
+                encryptor.push_video(pts_us(&packet), packet.data())?;
+            }
+        } else if Some(packet.stream_index()) == streams.audio {
+            let config = adts.as_ref().unwrap();
+            let data = packet.data();
+            if is_adts(data) {
+                encryptor.push_audio(pts_us(&packet), data)?;
+            } else {
+                let mut framed = config.header(data.len())?.to_vec();
+                framed.extend_from_slice(data);
+                encryptor.push_audio(pts_us(&packet), &framed)?;
+            }
+        }
+    }
+    video_filter
+        .flush()
+        .map_err(|e| anyhow!("Error flushing video filter: {}", e))?;
+    while let Some(packet) = video_filter.take()? {
+        encryptor.push_video(pts_us(&packet), packet.data())?;
+    }
+    encryptor.finish()?;
+    Ok(())
+}
+
+/// The first video stream and, with `include_audio`, the first audio stream, after making sure
+/// every audio and video stream has a codec the app records.
+fn select_streams(params: Vec<CodecParameters>, include_audio: bool) -> Result<SelectedStreams> {
+    let mut unsupported = vec![];
+    let mut video = None;
+    let mut audio = None;
+    for (index, params) in params.iter().enumerate() {
+        let codec = params.decoder_name().unwrap_or("unknown");
+        if params.is_video_codec() {
+            match codec {
+                "h264" | "hevc" => {
+                    video.get_or_insert(index);
+                }
+                _ => unsupported.push((index, codec.to_owned())),
+            }
+        } else if params.is_audio_codec() {
+            match codec {
+                "aac" => {
+                    audio.get_or_insert(index);
+                }
+                _ => unsupported.push((index, codec.to_owned())),
+            }
+        }
+    }
+    if !unsupported.is_empty() {
+        return Err(Error::UnsupportedStreams {
+            streams: unsupported,
+        }
+        .into());
+    }
+    let video = video.ok_or_else(|| anyhow!("No video stream found"))?;
+    if audio.is_some() && !include_audio {
+        info!("Leaving out the audio stream");
+    }
+    Ok(SelectedStreams {
+        video,
+        audio: audio.filter(|_| include_audio),
+    })
+}
+
+/// The packet's PTS in microseconds, or its DTS if it has none. Negative timestamps, left by
+/// edit lists, become 0.
+fn pts_us(packet: &Packet) -> u64 {
+    let timestamp = if packet.pts().is_null() {
+        packet.dts()
+    } else {
+        packet.pts()
+    };
+    let us = timestamp
+        .with_time_base(TimeBase::MICROSECONDS)
+        .timestamp()
+        .max(0);
+    us as u64
+}
+
+fn modification_time(file: &File) -> Result<DateTime<Utc>> {
+    let modified = file.metadata()?.modified()?;
+    warn!("The input has no creation time, using its modification time");
+    Ok(modified.into())
+}
+
+fn is_adts(data: &[u8]) -> bool {
+    data.len() >= 2 && data[0] == 0xff && data[1] & 0xf0 == 0xf0
+}
+
+/// Sample rates by their index in the AudioSpecificConfig and ADTS header.
+const SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// What the ADTS header of every AAC frame repeats.
+struct AdtsConfig {
+    /// The audio object type minus 1.
+    profile: u8,
+    sample_rate_index: u8,
+    channel_config: u8,
+}
+
+impl AdtsConfig {
+    /// From the AudioSpecificConfig in the MP4, see ISO 14496-3 section 1.6.2.1.
+    fn from_asc(asc: &[u8]) -> Result<Self> {
+        if asc.len() < 2 {
+            bail!("AudioSpecificConfig is too short");
+        }
+        let object_type = asc[0] >> 3;
+        let sample_rate_index = ((asc[0] & 0x07) << 1) | (asc[1] >> 7);
+        let channel_config = (asc[1] >> 3) & 0x0f;
+        if !(1..=4).contains(&object_type) {
+            bail!(
+                "AAC object type {} can't be written with ADTS headers",
+                object_type
+            );
+        }
+        if sample_rate_index as usize >= SAMPLE_RATES.len() {
+            bail!("Unsupported AAC sample rate index {}", sample_rate_index);
+        }
+        Ok(AdtsConfig {
+            profile: object_type - 1,
+            sample_rate_index,
+            channel_config,
+        })
+    }
+
+    /// AAC LC, for streams without an AudioSpecificConfig.
+    fn aac_lc(sample_rate: u32, channel_count: u32) -> Result<Self> {
+        let sample_rate_index = SAMPLE_RATES
+            .iter()
+            .position(|&rate| rate == sample_rate)
+            .ok_or_else(|| anyhow!("Unsupported AAC sample rate {}", sample_rate))?;
+        if !(1..=7).contains(&channel_count) {
+            bail!("Unsupported AAC channel count {}", channel_count);
+        }
+        Ok(AdtsConfig {
+            profile: 1,
+            sample_rate_index: sample_rate_index as u8,
+            channel_config: channel_count as u8,
+        })
+    }
+
+    /// The header for a frame of `payload_len` bytes, without CRC.
+    fn header(&self, payload_len: usize) -> Result<[u8; 7]> {
+        let frame_len = payload_len + 7;
+        if frame_len > 0x1fff {
+            bail!("AAC frame of {} bytes is too long for ADTS", payload_len);
+        }
+        Ok([
+            0xff,
+            0xf1, // MPEG-4, no CRC
+            (self.profile << 6) | (self.sample_rate_index << 2) | (self.channel_config >> 2),
+            ((self.channel_config & 0x03) << 6) | (frame_len >> 11) as u8,
+            (frame_len >> 3) as u8,
+            ((frame_len & 0x07) << 5) as u8 | 0x1f,
+            0xfc, // buffer fullness 0x7ff: variable bitrate, one raw data block
+        ])
+    }
+}
@@ -0,0 +1,331 @@
+//! Job events as JSON lines, see JsonlProgress.
+
+#[cfg(feature = "thumbnail")]
+use crate::thumbnail::ThumbnailFormat;
+use crate::{
+    decrypt::{DecryptStats, ProgressCallback},
+    hash::HashDigest,
+    keyring::IdentityInfo,
+    packet::PacketKind,
+    verify::VerificationReport,
+    warning::DecryptWarning,
+};
+use serde_json::{json, Value};
+use std::{
+    error::Error,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// The `schema_version` of every line. Only changes when fields are removed or change their
+/// meaning, new events and fields may be added without it.
+pub const JSONL_SCHEMA_VERSION: u32 = 1;
+
+/// A ProgressCallback writing every event as one JSON object per line to `W`, for programs
+/// driving a decryption from another language. Every object has `schema_version` and `event`,
+/// the other fields depend on the event:
+///
+/// | `event` | fields |
+/// |---|---|
+/// | `identity_matched` | `digest` (hex), `label` (string or null) |
+/// | `progress` | `processed`, `total` (bytes, `total` is 0 if unknown) |
+/// | `stream_progress` | `stream` (`video` or `audio`), `packets`, `bytes`, `last_pts_us` |
+/// | `warning` | `kind` (DecryptWarning::kind()), `message` |
+/// | `truncated` | `processed` (bytes) |
+/// | `output_created` | `path` |
+/// | `output_digest` | `algo`, `hex` |
+/// | `stats` | `type` (`video`, `image` or `audio`) and the fields of its stats struct |
+/// | `verified` | `type` (`video` or `image`) and the fields of VerificationReport |
+/// | `thumbnail` | `format` (extension), `len` (bytes), only with the thumbnail feature |
+/// | `complete` | `path` (null when verifying) |
+/// | `error` | `message` |
+///
+/// ```text
+/// {"schema_version":1,"event":"progress","processed":1234,"total":5678}
+/// {"schema_version":1,"event":"warning","kind":"truncated_stream","message":"..."}
+/// {"schema_version":1,"event":"complete","path":"/out/2021-06-01T12:00:00.mp4"}
+/// ```
+///
+/// Paths are written as strings with invalid UTF-8 replaced by U+FFFD. On Unix, paths that
+/// aren't valid UTF-8 also get a `path_bytes` array with the raw bytes of the path. Every line
+/// is flushed as it is written.
+pub struct JsonlProgress<W: Write> {
+    out: W,
+    total: u64,
+    output: Option<PathBuf>,
+    write_error: Option<io::Error>,
+}
+
+impl<W: Write> JsonlProgress<W> {
+    pub fn new(out: W) -> Self {
+        JsonlProgress {
+            out,
+            total: 0,
+            output: None,
+            write_error: None,
+        }
+    }
+
+    /// The writer, or the first error writing to it. Events after a failed write are dropped.
+    pub fn finish(self) -> io::Result<W> {
+        match self.write_error {
+            Some(e) => Err(e),
+            None => Ok(self.out),
+        }
+    }
+
+    fn emit(&mut self, event: &str, fields: Value) {
+        if self.write_error.is_some() {
+            return;
+        }
+        // schema_version and event come first, serde_json would sort them in with the rest
+        let mut line = format!(
+            "{{\"schema_version\":{},\"event\":{}",
+            JSONL_SCHEMA_VERSION,
+            Value::from(event)
+        );
+        if let Value::Object(fields) = fields {
+            for (name, value) in fields {
+                line.push_str(&format!(",{}:{}", Value::from(name), value));
+            }
+        }
+        line.push_str("}\n");
+        if let Err(e) = self
+            .out
+            .write_all(line.as_bytes())
+            .and_then(|()| self.out.flush())
+        {
+            self.write_error = Some(e);
+        }
+    }
+}
+
+/// `path` and, for paths that aren't UTF-8, `path_bytes`.
+fn path_fields(path: Option<&Path>) -> Value {
+    let path = match path {
+        None => return json!({ "path": null }),
+        Some(path) => path,
+    };
+    let mut fields = json!({ "path": path.to_string_lossy() });
+    #[cfg(unix)]
+    if path.to_str().is_none() {
+        use std::os::unix::ffi::OsStrExt;
+        fields["path_bytes"] = path.as_os_str().as_bytes().into();
+    }
+    fields
+}
+
+/// `type` and the fields of the stats struct, also used by batch::BatchReport.
+pub(crate) fn stats_fields(stats: &DecryptStats) -> Value {
+    match stats {
+        DecryptStats::Video(stats) => json!({
+            "type": "video",
+            "duration_us": stats.duration_us,
+            "video_packets": stats.video_packets,
+            "audio_packets": stats.audio_packets,
+            "video_bytes": stats.video_bytes,
+            "audio_bytes": stats.audio_bytes,
+            "max_video_packet": stats.max_video_packet,
+            "width": stats.width,
+            "height": stats.height,
+            "frame_rate": stats.frame_rate.map(|rate| rate.as_f64()),
+        }),
+        DecryptStats::Image(stats) => json!({ "type": "image", "bytes": stats.bytes }),
+        DecryptStats::Audio(stats) => json!({
+            "type": "audio",
+            "duration_us": stats.duration_us,
+            "packets": stats.packets,
+            "bytes": stats.bytes,
+        }),
+    }
+}
+
+impl<W: Write> ProgressCallback for JsonlProgress<W> {
+    fn set_total_file_size(&mut self, n: u64) {
+        self.total = n;
+    }
+
+    fn on_identity_matched(&mut self, identity: &IdentityInfo) {
+        self.emit(
+            "identity_matched",
+            json!({ "digest": identity.digest.to_hex(), "label": identity.label }),
+        );
+    }
+
+    fn on_progress(&mut self, processed_bytes: u64) {
+        let total = self.total;
+        self.emit(
+            "progress",
+            json!({ "processed": processed_bytes, "total": total }),
+        );
+    }
+
+    fn on_stream_progress(
+        &mut self,
+        stream: PacketKind,
+        packets: u64,
+        bytes: u64,
+        last_pts_us: u64,
+    ) {
+        let stream = match stream {
+            PacketKind::Video => "video",
+            PacketKind::Audio => "audio",
+            PacketKind::Unknown(_) => "unknown",
+        };
+        self.emit(
+            "stream_progress",
+            json!({
+                "stream": stream,
+                "packets": packets,
+                "bytes": bytes,
+                "last_pts_us": last_pts_us,
+            }),
+        );
+    }
+
+    fn on_complete(&mut self) {
+        let fields = path_fields(self.output.as_deref());
+        self.emit("complete", fields);
+    }
+
+    fn on_error(&mut self, error: Box<dyn Error>) {
+        self.emit("error", json!({ "message": error.to_string() }));
+    }
+
+    fn on_warning(&mut self, warning: DecryptWarning) {
+        self.emit(
+            "warning",
+            json!({ "kind": warning.kind(), "message": warning.to_string() }),
+        );
+    }
+
+    fn on_truncated(&mut self, processed_bytes: u64) {
+        self.emit("truncated", json!({ "processed": processed_bytes }));
+    }
+
+    fn on_output_created(&mut self, path: &Path) {
+        self.output = Some(path.to_path_buf());
+        self.emit("output_created", path_fields(Some(path)));
+    }
+
+    fn on_output_digest(&mut self, digest: &HashDigest) {
+        self.emit(
+            "output_digest",
+            json!({ "algo": digest.algo().name(), "hex": digest.to_hex() }),
+        );
+    }
+
+    fn on_stats(&mut self, stats: &DecryptStats) {
+        self.emit("stats", stats_fields(stats));
+    }
+
+    fn on_verified(&mut self, report: &VerificationReport) {
+        let fields = match report {
+            VerificationReport::Video {
+                packets_video,
+                packets_audio,
+                bytes_video,
+                bytes_audio,
+                duration_us,
+                truncated,
+            } => json!({
+                "type": "video",
+                "packets_video": packets_video,
+                "packets_audio": packets_audio,
+                "bytes_video": bytes_video,
+                "bytes_audio": bytes_audio,
+                "duration_us": duration_us,
+                "truncated": truncated,
+            }),
+            VerificationReport::Image {
+                bytes,
+                format_matches_magic,
+            } => json!({
+                "type": "image",
+                "bytes": bytes,
+                "format_matches_magic": format_matches_magic,
+            }),
+        };
+        self.emit("verified", fields);
+    }
+
+    #[cfg(feature = "thumbnail")]
+    fn on_thumbnail(&mut self, data: &[u8], format: ThumbnailFormat) {
+        self.emit(
+            "thumbnail",
+            json!({ "format": format.extension(), "len": data.len() }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Asserts that `progress` wrote the lines of tests/golden/jsonl/`name`.jsonl.
+    fn assert_golden(progress: JsonlProgress<Vec<u8>>, name: &str) {
+        let golden = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/golden/jsonl")
+            .join(name)
+            .with_extension("jsonl");
+        let written = progress.finish().unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            fs::read_to_string(golden).unwrap()
+        );
+    }
+
+    #[test]
+    fn progress() {
+        let mut progress = JsonlProgress::new(Vec::new());
+        progress.set_total_file_size(5678);
+        progress.on_progress(1234);
+        assert_golden(progress, "progress");
+    }
+
+    #[test]
+    fn warning() {
+        let mut progress = JsonlProgress::new(Vec::new());
+        progress.on_warning(DecryptWarning::TruncatedStream {
+            processed: 1234,
+            reason: "Unexpected end of stream".to_owned(),
+        });
+        assert_golden(progress, "warning");
+    }
+
+    #[test]
+    fn output_created() {
+        let mut progress = JsonlProgress::new(Vec::new());
+        progress.on_output_created(Path::new("/out/2021-06-01T12:00:00.mp4"));
+        assert_golden(progress, "output_created");
+    }
+
+    #[test]
+    fn complete() {
+        let mut progress = JsonlProgress::new(Vec::new());
+        progress.on_output_created(Path::new("/out/2021-06-01T12:00:00.mp4"));
+        progress.on_complete();
+        assert_golden(progress, "complete");
+
+        let mut progress = JsonlProgress::new(Vec::new());
+        progress.on_complete();
+        assert_golden(progress, "complete_without_output");
+    }
+
+    #[test]
+    fn error() {
+        let mut progress = JsonlProgress::new(Vec::new());
+        progress.on_error(anyhow::anyhow!("No space left on device").into());
+        assert_golden(progress, "error");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths_have_their_bytes() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+        let mut progress = JsonlProgress::new(Vec::new());
+        progress.on_output_created(Path::new(OsStr::from_bytes(b"/out/caf\xe9.jpg")));
+        assert_golden(progress, "output_created_non_utf8");
+    }
+}
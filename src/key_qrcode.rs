@@ -1,13 +1,1240 @@
-use crate::keyring::DisplayIdentity;
-use anyhow::{Context, Result};
-use qrcode::QrCode;
+use crate::{
+    fingerprint::RecipientDigest,
+    hash::HashAlgo,
+    keyring::{DisplayIdentity, IdentityInfo, Keyring},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use qrcode::{Color, EcLevel, QrCode};
+use secrecy::{ExposeSecret, SecretString};
+#[cfg(feature = "qr-decode")]
+use std::path::Path;
+use std::{convert::TryFrom, fmt::Write, str::FromStr};
+#[cfg(feature = "qr-decode")]
+use thiserror::Error;
 use urlencoding;
 
 pub fn make_qr_code(identity: &DisplayIdentity) -> Result<QrCode> {
-    let intent_uri = format!(
-        "cryptocam://import_key?key_name={}&public_key={}",
+    QrCode::new(import_uri(identity)).context("Could not create qr code")
+}
+
+/// The contents of the QR code the Cryptocam app scans to import a public key.
+pub fn import_uri(identity: &DisplayIdentity) -> String {
+    format!(
+        "{}key_name={}&public_key={}",
+        IMPORT_URI_PREFIX,
         urlencoding::encode(&identity.name),
         identity.public_key
+    )
+}
+
+const IMPORT_URI_PREFIX: &str = "cryptocam://import_key?";
+const PART_PREFIX: &str = "cryptocam-part:";
+const KEYRING_PREFIX: &str = "cryptocam-keyring:";
+/// Version of the keyring backup payload written by export_keyring().
+const KEYRING_VERSION: u8 = 1;
+const SHARE_PREFIX: &str = "cryptocam-share:";
+
+/// The key material read from a scanned key QR code, see parse_payload().
+pub struct KeyQrPayload {
+    /// An age recipient (age1...) for codes made by make_qr_code(), or an age secret key
+    /// (AGE-SECRET-KEY-1...) that can be passed to Keyring::import_key().
+    pub key: SecretString,
+    /// 1 for cryptocam://import_key URIs, 0 for a bare key.
+    pub version: u8,
+    pub label: Option<String>,
+}
+
+impl KeyQrPayload {
+    pub fn is_secret_key(&self) -> bool {
+        self.key.expose_secret().starts_with("AGE-SECRET-KEY-1")
+    }
+
+    /// The age public key (age1...), derived from the secret key if the code holds one.
+    pub fn public_key(&self) -> Result<String> {
+        let key = self.key.expose_secret();
+        if !self.is_secret_key() {
+            return Ok(key.clone());
+        }
+        Ok(age::x25519::Identity::from_str(key)
+            .map_err(|e| anyhow!("Invalid secret key: {}", e))?
+            .to_public()
+            .to_string())
+    }
+
+    /// The key's fingerprint, to compare with the one the app shows.
+    pub fn fingerprint(&self) -> Result<RecipientDigest> {
+        Ok(RecipientDigest::of_public_key(&self.public_key()?))
+    }
+}
+
+/// Parses the text a QR scanner read from a key QR code. Accepts the import URIs made by
+/// import_uri() as well as bare age recipients and secret keys. The key's Bech32 checksum is
+/// verified, so a misread code is rejected rather than imported.
+pub fn parse_payload(s: &str) -> Result<KeyQrPayload> {
+    let s = s.trim();
+    if let Some(query) = s.strip_prefix(IMPORT_URI_PREFIX) {
+        let mut key = None;
+        let mut label = None;
+        for pair in query.split('&') {
+            let (name, value) = match pair.find('=') {
+                Some(i) => (&pair[..i], &pair[i + 1..]),
+                None => (pair, ""),
+            };
+            let value = urlencoding::decode(value)
+                .map_err(|_| anyhow!("Invalid {} in key QR code", name))?;
+            match name {
+                "public_key" => key = Some(value),
+                "key_name" => label = Some(value).filter(|l| !l.is_empty()),
+                _ => {}
+            }
+        }
+        let key = key.ok_or_else(|| anyhow!("Key QR code contains no public key"))?;
+        if !key.starts_with("age1") {
+            bail!("Key QR code contains no age public key");
+        }
+        return Ok(KeyQrPayload {
+            key: validate_key(key)?,
+            version: 1,
+            label,
+        });
+    }
+    if s.starts_with("age1") || s.starts_with("AGE-SECRET-KEY-1") {
+        return Ok(KeyQrPayload {
+            key: validate_key(s.to_owned())?,
+            version: 0,
+            label: None,
+        });
+    }
+    if let Some(part) = s.strip_prefix(PART_PREFIX) {
+        let (index, count, _, _) = parse_part(part)?;
+        bail!(
+            "This is part {} of {} of a key QR code, scan all parts and use assemble_parts()",
+            index,
+            count
+        );
+    }
+    if s.starts_with(KEYRING_PREFIX) {
+        bail!("This is a backup of a whole keyring, use import_keyring_parts()");
+    }
+    if s.starts_with(SHARE_PREFIX) {
+        bail!("This is a share of a key backup, use recover_shamir() with enough shares");
+    }
+    if s.starts_with("cryptocam://") {
+        bail!("Unsupported Cryptocam QR code");
+    }
+    bail!("Not a Cryptocam key QR code")
+}
+
+#[cfg(feature = "qr-decode")]
+#[derive(Debug, Error)]
+pub enum QrDecodeError {
+    #[error("No QR code found in the image")]
+    NoQrCode,
+    /// Holds the error from parse_payload() for the first QR code found.
+    #[error("The QR code is not a Cryptocam key: {0}")]
+    NotCryptocamKey(anyhow::Error),
+    #[error("Error reading image: {0:?}")]
+    Other(anyhow::Error),
+}
+
+/// Finds the QR codes in a photo or screenshot and returns the first one that parse_payload()
+/// accepts. Rotated and moderately skewed codes are found as well.
+#[cfg(feature = "qr-decode")]
+pub fn decode_from_image(path: &Path) -> std::result::Result<KeyQrPayload, QrDecodeError> {
+    let image = image::open(path)
+        .with_context(|| format!("Could not open {}", path.display()))
+        .map_err(QrDecodeError::Other)?
+        .to_luma8();
+    let mut prepared = rqrr::PreparedImage::prepare(image);
+    let mut first_error = None;
+    for grid in prepared.detect_grids() {
+        let content = match grid.decode() {
+            Ok((_, content)) => content,
+            Err(e) => {
+                first_error.get_or_insert_with(|| anyhow!("Unreadable QR code: {}", e));
+                continue;
+            }
+        };
+        match parse_payload(&content) {
+            Ok(payload) => return Ok(payload),
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    Err(match first_error {
+        None => QrDecodeError::NoQrCode,
+        Some(e) => QrDecodeError::NotCryptocamKey(e),
+    })
+}
+
+/// Splits a key QR payload, e.g. the result of import_uri(), into `parts` smaller payloads
+/// that can be shown as separate, easier to scan QR codes. The format of each part is
+///
+/// cryptocam-part:<i>/<n>:<checksum>:<chunk>
+///
+/// where i is the 1-based number of the part, n the number of parts, checksum the first
+/// 8 hex digits of the hash of the whole payload with `algo` (the same in all parts) and
+/// chunk the i-th of n consecutive pieces of the payload, of as equal length as possible. The
+/// checksum is prefixed with the algorithm's name unless it is SHA-256, e.g.
+/// `sha512-1a2b3c4d`, so assemble_parts() knows what to recompute.
+pub fn split_payload(key: &str, parts: u8, algo: HashAlgo) -> Result<Vec<String>> {
+    split_text(key, parts, algo)
+}
+
+fn split_text(key: &str, parts: u8, algo: HashAlgo) -> Result<Vec<String>> {
+    let chars: Vec<char> = key.trim().chars().collect();
+    let count = parts as usize;
+    if count == 0 || count > chars.len() {
+        bail!(
+            "Cannot split a {} character payload into {} parts",
+            chars.len(),
+            parts
+        );
+    }
+    let checksum = payload_checksum(key.trim(), algo);
+    let mut start = 0;
+    Ok((0..count)
+        .map(|i| {
+            // the first parts get one character more when the length isn't divisible
+            let len = chars.len() / count + usize::from(i < chars.len() % count);
+            let chunk: String = chars[start..start + len].iter().collect();
+            start += len;
+            format!("{}{}/{}:{}:{}", PART_PREFIX, i + 1, count, checksum, chunk)
+        })
+        .collect())
+}
+
+/// Joins payloads made by split_payload(), given in any order, and parses the result with
+/// parse_payload(). Fails if a part is missing, given twice or doesn't match the checksum.
+pub fn assemble_parts(parts: &[&str]) -> Result<KeyQrPayload> {
+    parse_payload(&assemble_text(parts)?)
+}
+
+/// Joins the parts made by split_text() without parsing the result.
+fn assemble_text(parts: &[&str]) -> Result<String> {
+    let mut chunks: Vec<Option<&str>> = vec![];
+    let mut expected: Option<(usize, &str)> = None;
+    for part in parts {
+        let part = part
+            .trim()
+            .strip_prefix(PART_PREFIX)
+            .ok_or_else(|| anyhow!("Not a part of a multi-part key QR code"))?;
+        let (index, count, checksum, chunk) = parse_part(part)?;
+        match expected {
+            None => {
+                expected = Some((count, checksum));
+                chunks = vec![None; count];
+            }
+            Some((c, _)) if c != count => bail!("Parts of different key QR codes"),
+            Some((_, sum)) if sum != checksum => bail!("Parts of different key QR codes"),
+            Some(_) => {}
+        }
+        if chunks[index - 1].replace(chunk).is_some() {
+            bail!("Part {} was given twice", index);
+        }
+    }
+    let (_, checksum) = expected.ok_or_else(|| anyhow!("No parts given"))?;
+    let missing: Vec<String> = (1..=chunks.len())
+        .filter(|i| chunks[i - 1].is_none())
+        .map(|i| i.to_string())
+        .collect();
+    if !missing.is_empty() {
+        bail!("Missing part(s) {} of {}", missing.join(", "), chunks.len());
+    }
+    let payload: String = chunks.into_iter().flatten().collect();
+    if !checksum_matches(checksum, &payload) {
+        bail!("Checksum mismatch, a part was misread");
+    }
+    Ok(payload)
+}
+
+/// One of the QR codes of a keyring backup, see export_keyring(). Render `text` with
+/// render_svg() or render_png().
+#[derive(Debug, Clone)]
+pub struct QrPart {
+    /// 1-based
+    pub index: usize,
+    pub count: usize,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeyringExportOptions {
+    /// Characters of the payload at most in each part. Each key takes up about 120.
+    pub max_part_len: usize,
+    /// The hash the checksum of the parts is taken from, see split_payload(). SHA-256 by
+    /// default.
+    pub checksum_algo: HashAlgo,
+}
+
+impl Default for KeyringExportOptions {
+    fn default() -> Self {
+        KeyringExportOptions {
+            max_part_len: 400,
+            checksum_algo: HashAlgo::default(),
+        }
+    }
+}
+
+/// The QR codes of a keyring backup and the identities that were left out of it.
+#[derive(Debug, Clone)]
+pub struct KeyringExport {
+    pub parts: Vec<QrPart>,
+    /// Identities whose secret key can't be exported, along with the reason. Passphrase
+    /// protected identities have to be unlocked with Keyring::decrypt_identity() first.
+    pub skipped: Vec<(IdentityInfo, String)>,
+}
+
+/// Backs up the secret keys of all identities in the keyring, with their labels and creation
+/// times, as a set of QR codes to be scanned with import_keyring_parts(). The payload is
+///
+/// cryptocam-keyring:<version>:<entry>;<entry>;...
+///
+/// where each entry is key=<age secret key>&label=<label>&created=<RFC 3339>, the label and
+/// creation time URL encoded and left out if unknown. It is split into parts like by
+/// split_payload(). The parts hold the secret keys unencrypted, they have to be kept safe.
+pub fn export_keyring(keyring: &Keyring, options: KeyringExportOptions) -> Result<KeyringExport> {
+    let mut entries = vec![];
+    let mut skipped = vec![];
+    for identity in keyring.identities() {
+        let key = match keyring.export_secret_key(&identity.digest) {
+            Ok(key) => key,
+            Err(e) => {
+                skipped.push((identity, e.to_string()));
+                continue;
+            }
+        };
+        let mut entry = format!("key={}", key.expose_secret());
+        if let Some(label) = &identity.label {
+            write!(entry, "&label={}", urlencoding::encode(label))?;
+        }
+        if let Some(created) = identity.created {
+            let created = created.to_rfc3339_opts(SecondsFormat::Secs, true);
+            write!(entry, "&created={}", urlencoding::encode(&created))?;
+        }
+        entries.push(entry);
+    }
+    if entries.is_empty() {
+        bail!("The keyring has no keys that can be exported");
+    }
+    let payload = format!(
+        "{}{}:{}",
+        KEYRING_PREFIX,
+        KEYRING_VERSION,
+        entries.join(";")
     );
-    QrCode::new(intent_uri).context("Could not create qr code")
+    let max_part_len = options.max_part_len.max(1);
+    let count = u8::try_from((payload.len() + max_part_len - 1) / max_part_len)
+        .map_err(|_| anyhow!("The keyring is too large for {} QR codes", u8::MAX))?;
+    let parts = split_text(&payload, count, options.checksum_algo)?
+        .into_iter()
+        .enumerate()
+        .map(|(i, text)| QrPart {
+            index: i + 1,
+            count: count as usize,
+            text,
+        })
+        .collect();
+    Ok(KeyringExport { parts, skipped })
+}
+
+/// What import_keyring_parts() did with an identity of the backup.
+#[derive(Debug)]
+pub enum KeyringImportResult {
+    Imported(RecipientDigest),
+    /// The key was in the keyring already, its label there is kept.
+    AlreadyPresent(RecipientDigest),
+    /// The entry couldn't be imported, e.g. because its key is damaged.
+    Failed(anyhow::Error),
+}
+
+/// Restores a keyring backup made by export_keyring() from its parts, given in any order.
+/// Returns the result and label of each identity in the backup, in the backup's order. Fails
+/// without importing anything if a part is missing or the backup can't be read.
+pub fn import_keyring_parts(
+    parts: &[&str],
+    keyring: &mut Keyring,
+) -> Result<Vec<(Option<String>, KeyringImportResult)>> {
+    let payload = SecretString::new(assemble_text(parts)?);
+    let rest = payload
+        .expose_secret()
+        .strip_prefix(KEYRING_PREFIX)
+        .ok_or_else(|| anyhow!("Not a keyring backup"))?;
+    let (version, entries) = match rest.find(':') {
+        Some(i) => (&rest[..i], &rest[i + 1..]),
+        None => bail!("Malformed keyring backup"),
+    };
+    if version != KEYRING_VERSION.to_string() {
+        bail!("Unsupported keyring backup version {}", version);
+    }
+    let mut results = vec![];
+    for entry in entries.split(';') {
+        let (label, result) = match parse_keyring_entry(entry) {
+            Ok((key, label, created)) => {
+                let result = match age::x25519::Identity::from_str(key.expose_secret()) {
+                    Ok(identity) => {
+                        let digest =
+                            RecipientDigest::of_public_key(&identity.to_public().to_string());
+                        if keyring.contains(&digest) {
+                            KeyringImportResult::AlreadyPresent(digest)
+                        } else {
+                            let key = key.expose_secret();
+                            match keyring.import_key_created(key, label.clone(), created) {
+                                Ok(digest) => KeyringImportResult::Imported(digest),
+                                Err(e) => KeyringImportResult::Failed(e),
+                            }
+                        }
+                    }
+                    Err(_) => KeyringImportResult::Failed(anyhow!(
+                        "The key is damaged (bad checksum or encoding)"
+                    )),
+                };
+                (label, result)
+            }
+            Err(e) => (None, KeyringImportResult::Failed(e)),
+        };
+        results.push((label, result));
+    }
+    Ok(results)
+}
+
+/// Returns (key, label, creation time) of an entry of a keyring backup.
+fn parse_keyring_entry(
+    entry: &str,
+) -> Result<(SecretString, Option<String>, Option<DateTime<Utc>>)> {
+    let mut key = None;
+    let mut label = None;
+    let mut created = None;
+    for pair in entry.split('&') {
+        let (name, value) = match pair.find('=') {
+            Some(i) => (&pair[..i], &pair[i + 1..]),
+            None => (pair, ""),
+        };
+        match name {
+            "key" => key = Some(SecretString::new(value.to_owned())),
+            "label" => {
+                let value = urlencoding::decode(value)
+                    .map_err(|_| anyhow!("Invalid label in keyring backup"))?;
+                label = Some(value).filter(|l| !l.is_empty());
+            }
+            "created" => {
+                let value = urlencoding::decode(value)
+                    .map_err(|_| anyhow!("Invalid creation time in keyring backup"))?;
+                created = DateTime::parse_from_rfc3339(&value)
+                    .ok()
+                    .map(|created| created.with_timezone(&Utc));
+            }
+            _ => {}
+        }
+    }
+    let key = key.ok_or_else(|| anyhow!("Keyring backup entry without a key"))?;
+    Ok((key, label, created))
+}
+
+/// Splits a key, e.g. an age secret key for a backup, into `n` shares any `k` of which
+/// recover it with recover_shamir(), while fewer reveal nothing about it. The format of each
+/// share is
+///
+/// cryptocam-share:<x>/<k>/<n>:<split id>:<fingerprint>:<share>
+///
+/// where x is the 1-based number of the share, split id 8 random hex digits shared by all
+/// shares from one split, fingerprint the first 8 hex digits of the hash of the key with
+/// `algo`, prefixed like the checksum of split_payload(), and share the Shamir share of the
+/// key's UTF-8 bytes over GF(256), in unpadded URL-safe base64.
+#[cfg(feature = "shamir")]
+pub fn split_shamir(key: &str, k: u8, n: u8, algo: HashAlgo) -> Result<Vec<String>> {
+    let key = key.trim();
+    parse_payload(key)?;
+    let split_id = format!("{:08x}", rand::random::<u32>());
+    let fingerprint = payload_checksum(key, algo);
+    Ok(crate::shamir::split(key.as_bytes(), k, n)?
+        .into_iter()
+        .map(|(x, share)| {
+            format!(
+                "{}{}/{}/{}:{}:{}:{}",
+                SHARE_PREFIX,
+                x,
+                k,
+                n,
+                split_id,
+                fingerprint,
+                base64::encode_config(share, base64::URL_SAFE_NO_PAD)
+            )
+        })
+        .collect())
+}
+
+/// Recovers a key from shares made by split_shamir(), given in any order. Fails if there are
+/// fewer than k shares or the shares come from different splits.
+#[cfg(feature = "shamir")]
+pub fn recover_shamir(shares: &[&str]) -> Result<KeyQrPayload> {
+    let mut parsed: Vec<(u8, Vec<u8>)> = vec![];
+    let mut expected: Option<(u8, u8, &str, &str)> = None;
+    for share in shares {
+        let share = share
+            .trim()
+            .strip_prefix(SHARE_PREFIX)
+            .ok_or_else(|| anyhow!("Not a key backup share"))?;
+        let mut fields = share.splitn(4, ':');
+        let (position, split_id, fingerprint, data) =
+            match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                (Some(p), Some(i), Some(f), Some(d)) => (p, i, f, d),
+                _ => bail!("Malformed key backup share"),
+            };
+        let numbers: Vec<u8> = position
+            .split('/')
+            .map(|n| n.parse::<u8>())
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|_| anyhow!("Malformed key backup share"))?;
+        let (x, k, n) = match numbers[..] {
+            [x, k, n] if x >= 1 && k >= 2 && x <= n && k <= n => (x, k, n),
+            _ => bail!("Malformed key backup share"),
+        };
+        let data = base64::decode_config(data, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| anyhow!("Share {} is damaged", x))?;
+        match expected {
+            None => expected = Some((k, n, split_id, fingerprint)),
+            Some((_, _, _, f)) if f != fingerprint => {
+                bail!("The shares belong to different keys")
+            }
+            Some(e) if e != (k, n, split_id, fingerprint) => {
+                bail!("The shares come from different backups of the key, they can't be combined")
+            }
+            Some(_) => {}
+        }
+        if parsed.iter().any(|(other, _)| *other == x) {
+            bail!("Share {} was given twice", x);
+        }
+        if parsed.first().is_some_and(|(_, d)| d.len() != data.len()) {
+            bail!("Share {} is damaged", x);
+        }
+        parsed.push((x, data));
+    }
+    let (k, fingerprint) = match expected {
+        None => bail!("No shares given"),
+        Some((k, _, _, fingerprint)) => (k, fingerprint),
+    };
+    if parsed.len() < k as usize {
+        bail!("{} of the {} shares needed were given", parsed.len(), k);
+    }
+    let shares: Vec<(u8, &[u8])> = parsed
+        .iter()
+        .take(k as usize)
+        .map(|(x, data)| (*x, data.as_slice()))
+        .collect();
+    let key = String::from_utf8(crate::shamir::combine(&shares))
+        .ok()
+        .filter(|key| checksum_matches(fingerprint, key))
+        .ok_or_else(|| {
+            anyhow!("Recovered key doesn't match its fingerprint, a share is damaged")
+        })?;
+    parse_payload(&key)
+}
+
+/// Returns (index, count, checksum, chunk) of a part without the prefix.
+fn parse_part(part: &str) -> Result<(usize, usize, &str, &str)> {
+    let mut fields = part.splitn(3, ':');
+    let (position, checksum, chunk) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(p), Some(s), Some(c)) => (p, s, c),
+        _ => bail!("Malformed key QR code part"),
+    };
+    let (index, count) = match position.find('/') {
+        Some(i) => (
+            position[..i].parse::<usize>(),
+            position[i + 1..].parse::<usize>(),
+        ),
+        None => bail!("Malformed key QR code part"),
+    };
+    match (index, count) {
+        (Ok(index), Ok(count)) if index >= 1 && index <= count && count <= u8::MAX as usize => {
+            Ok((index, count, checksum, chunk))
+        }
+        _ => bail!("Malformed key QR code part"),
+    }
+}
+
+/// The first 8 hex digits of the hash of `payload`, prefixed with the algorithm's name unless
+/// it is SHA-256, which older parts and shares were always checked with.
+fn payload_checksum(payload: &str, algo: HashAlgo) -> String {
+    let mut hasher = algo.hasher();
+    hasher.update(payload.as_bytes());
+    let hex = &hasher.finalize().to_hex()[..8];
+    match algo {
+        HashAlgo::Sha256 => hex.to_owned(),
+        algo => format!("{}-{}", algo, hex),
+    }
+}
+
+/// Whether `checksum` from payload_checksum() belongs to `payload`, with the algorithm it
+/// names.
+fn checksum_matches(checksum: &str, payload: &str) -> bool {
+    let algo = match checksum.split_once('-') {
+        None => HashAlgo::Sha256,
+        Some((name, _)) => match name.parse() {
+            Ok(algo) => algo,
+            Err(_) => return false,
+        },
+    };
+    payload_checksum(payload, algo) == checksum
+}
+
+fn validate_key(key: String) -> Result<SecretString> {
+    let valid = if key.starts_with("AGE-SECRET-KEY-1") {
+        age::x25519::Identity::from_str(&key).is_ok()
+    } else {
+        age::x25519::Recipient::from_str(&key).is_ok()
+    };
+    if !valid {
+        bail!("The key in the QR code is damaged (bad checksum or encoding)");
+    }
+    Ok(SecretString::new(key))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QrRenderOptions {
+    /// Width and height of one module in pixels (PNG) or user units (SVG).
+    pub module_size: u32,
+    /// Width of the light border around the code, in modules. The QR spec asks for 4.
+    pub quiet_zone: u32,
+    pub error_correction: EcLevel,
+    /// RGB
+    pub dark_color: [u8; 3],
+    pub light_color: [u8; 3],
+}
+
+impl Default for QrRenderOptions {
+    fn default() -> Self {
+        QrRenderOptions {
+            module_size: 8,
+            quiet_zone: 4,
+            error_correction: EcLevel::M,
+            dark_color: [0, 0, 0],
+            light_color: [255, 255, 255],
+        }
+    }
+}
+
+/// Renders `key`, e.g. the result of import_uri(), into a QR code PNG image.
+#[cfg(feature = "png")]
+pub fn render_png(key: &str, options: QrRenderOptions) -> Result<Vec<u8>> {
+    let modules = Modules::new(key, &options)?;
+    let module_size = options.module_size.max(1) as usize;
+    let size = modules.size * module_size;
+    let mut pixels = Vec::with_capacity(size * size * 3);
+    for y in 0..size {
+        for x in 0..size {
+            let color = if modules.is_dark(x / module_size, y / module_size) {
+                options.dark_color
+            } else {
+                options.light_color
+            };
+            pixels.extend_from_slice(&color);
+        }
+    }
+    let mut png_bytes = vec![];
+    let mut encoder = png::Encoder::new(&mut png_bytes, size as u32, size as u32);
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder
+        .write_header()
+        .context("Could not write QR code image")?;
+    writer
+        .write_image_data(&pixels)
+        .context("Could not write QR code image")?;
+    drop(writer);
+    Ok(png_bytes)
+}
+
+/// Renders `key`, e.g. the result of import_uri(), into a QR code SVG document.
+pub fn render_svg(key: &str, options: QrRenderOptions) -> Result<String> {
+    let modules = Modules::new(key, &options)?;
+    let module_size = options.module_size.max(1) as usize;
+    let size = modules.size * module_size;
+    let mut svg = String::new();
+    write!(
+        svg,
+        r#"<?xml version="1.0" standalone="yes"?><svg xmlns="http://www.w3.org/2000/svg" version="1.1" width="{size}" height="{size}" viewBox="0 0 {size} {size}" shape-rendering="crispEdges"><rect x="0" y="0" width="{size}" height="{size}" fill="{light}"/><path fill="{dark}" d=""#,
+        size = size,
+        light = hex_color(options.light_color),
+        dark = hex_color(options.dark_color),
+    )?;
+    for y in 0..modules.size {
+        for x in 0..modules.size {
+            if modules.is_dark(x, y) {
+                write!(
+                    svg,
+                    "M{} {}h{}v{}h-{}z",
+                    x * module_size,
+                    y * module_size,
+                    module_size,
+                    module_size,
+                    module_size
+                )?;
+            }
+        }
+    }
+    svg.push_str(r#""/></svg>"#);
+    Ok(svg)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaperSize {
+    A4,
+    Letter,
+}
+
+impl PaperSize {
+    /// Width and height in millimeters.
+    fn size_mm(self) -> (f64, f64) {
+        match self {
+            PaperSize::A4 => (210.0, 297.0),
+            PaperSize::Letter => (215.9, 279.4),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BackupSheetOptions {
+    pub paper: PaperSize,
+    /// Printed on the sheet if known, see IdentityInfo::created.
+    pub created: Option<DateTime<Utc>>,
+    pub error_correction: EcLevel,
+}
+
+impl Default for BackupSheetOptions {
+    fn default() -> Self {
+        BackupSheetOptions {
+            paper: PaperSize::A4,
+            created: None,
+            // a printed code has to survive creases and stains
+            error_correction: EcLevel::Q,
+        }
+    }
+}
+
+/// Renders a page to print and keep as a paper backup of the key: its QR code, fingerprint,
+/// label, creation date and how to restore it. Returns an SVG document measured in millimeters
+/// like render_svg(),
+/// the text is set in the generic sans-serif and monospace fonts every SVG renderer has.
+/// Secret keys are encoded as the bare key, public keys as an import_uri().
+pub fn render_backup_sheet(payload: &KeyQrPayload, options: BackupSheetOptions) -> Result<String> {
+    let key = payload.key.expose_secret();
+    let public_key = payload.public_key()?;
+    let qr_text = SecretString::new(if payload.is_secret_key() {
+        key.clone()
+    } else {
+        format!(
+            "{}key_name={}&public_key={}",
+            IMPORT_URI_PREFIX,
+            urlencoding::encode(payload.label.as_deref().unwrap_or("")),
+            key
+        )
+    });
+    let modules = Modules::new(
+        qr_text.expose_secret(),
+        &QrRenderOptions {
+            quiet_zone: 4,
+            error_correction: options.error_correction,
+            ..QrRenderOptions::default()
+        },
+    )?;
+
+    let (width, height) = options.paper.size_mm();
+    let margin = 20.0;
+    let qr_size = 120.0_f64.min(width - 2.0 * margin);
+    let module = qr_size / modules.size as f64;
+    let qr_x = (width - qr_size) / 2.0;
+    let qr_y = 45.0;
+    let mut svg = String::new();
+    write!(
+        svg,
+        r##"<?xml version="1.0" standalone="yes"?><svg xmlns="http://www.w3.org/2000/svg" version="1.1" width="{w}mm" height="{h}mm" viewBox="0 0 {w} {h}"><rect x="0" y="0" width="{w}" height="{h}" fill="#ffffff"/>"##,
+        w = width,
+        h = height,
+    )?;
+    let title = if payload.is_secret_key() {
+        "Cryptocam secret key backup"
+    } else {
+        "Cryptocam public key"
+    };
+    write!(
+        svg,
+        r#"<text x="{x}" y="25" font-family="sans-serif" font-size="8" font-weight="bold">{}</text>"#,
+        title,
+        x = margin,
+    )?;
+    if let Some(label) = &payload.label {
+        write!(
+            svg,
+            r#"<text x="{x}" y="35" font-family="sans-serif" font-size="6">{}</text>"#,
+            xml_escape(label),
+            x = margin,
+        )?;
+    }
+    svg.push_str(r##"<path fill="#000000" shape-rendering="crispEdges" d=""##);
+    for y in 0..modules.size {
+        for x in 0..modules.size {
+            if modules.is_dark(x, y) {
+                write!(
+                    svg,
+                    "M{:.3} {:.3}h{m:.3}v{m:.3}h-{m:.3}z",
+                    qr_x + x as f64 * module,
+                    qr_y + y as f64 * module,
+                    m = module,
+                )?;
+            }
+        }
+    }
+    svg.push_str(r#""/>"#);
+
+    let mut y = qr_y + qr_size + 15.0;
+    let mut lines = vec![
+        (
+            "Fingerprint".to_owned(),
+            payload.fingerprint()?.to_string(),
+            "monospace",
+        ),
+        ("Public key".to_owned(), public_key, "monospace"),
+    ];
+    if let Some(created) = options.created {
+        lines.push((
+            "Created".to_owned(),
+            created.format("%Y-%m-%d").to_string(),
+            "sans-serif",
+        ));
+    }
+    for (name, value, font) in lines {
+        write!(
+            svg,
+            r#"<text x="{x}" y="{y:.1}" font-family="sans-serif" font-size="4">{}:</text><text x="{vx}" y="{y:.1}" font-family="{}" font-size="3.5">{}</text>"#,
+            name,
+            font,
+            xml_escape(&value),
+            x = margin,
+            vx = margin + 25.0,
+            y = y,
+        )?;
+        y += 8.0;
+    }
+
+    y += 6.0;
+    let restore: &[&str] = if payload.is_secret_key() {
+        &[
+            "To restore: scan the code with the Cryptocam desktop app, or type the key",
+            "into Keyring::import_key(). Check that the fingerprint matches afterwards.",
+            "Anyone with this sheet can decrypt your recordings, keep it safe.",
+        ]
+    } else {
+        &[
+            "To use: scan the code with the Cryptocam app on the phone, then check that",
+            "the fingerprint the app shows matches the one above.",
+        ]
+    };
+    for line in restore {
+        write!(
+            svg,
+            r#"<text x="{x}" y="{y:.1}" font-family="sans-serif" font-size="3.5">{}</text>"#,
+            line,
+            x = margin,
+            y = y,
+        )?;
+        y += 5.5;
+    }
+    svg.push_str("</svg>");
+    Ok(svg)
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The modules of a QR code including the quiet zone.
+struct Modules {
+    colors: Vec<Color>,
+    width: usize,
+    quiet_zone: usize,
+    /// width + 2 * quiet_zone
+    size: usize,
+}
+
+impl Modules {
+    fn new(key: &str, options: &QrRenderOptions) -> Result<Modules> {
+        let code = QrCode::with_error_correction_level(key, options.error_correction)
+            .context("Could not create qr code")?;
+        let width = code.width();
+        let quiet_zone = options.quiet_zone as usize;
+        Ok(Modules {
+            colors: code.to_colors(),
+            width,
+            quiet_zone,
+            size: width + 2 * quiet_zone,
+        })
+    }
+
+    fn is_dark(&self, x: usize, y: usize) -> bool {
+        let (x, y) = match (
+            x.checked_sub(self.quiet_zone),
+            y.checked_sub(self.quiet_zone),
+        ) {
+            (Some(x), Some(y)) if x < self.width && y < self.width => (x, y),
+            _ => return false,
+        };
+        self.colors[y * self.width + x] == Color::Dark
+    }
+}
+
+fn hex_color([r, g, b]: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{TEST_PUBLIC_KEY, TEST_SECRET_KEY};
+
+    fn key_of(payload: KeyQrPayload) -> String {
+        payload.key.expose_secret().clone()
+    }
+
+    /// The dark modules of the code of `key`, without a quiet zone.
+    fn dark_modules(key: &str, error_correction: EcLevel) -> usize {
+        QrCode::with_error_correction_level(key, error_correction)
+            .unwrap()
+            .to_colors()
+            .into_iter()
+            .filter(|&color| color == Color::Dark)
+            .count()
+    }
+
+    /// The squares drawn by the path of a backup sheet's QR code.
+    fn qr_squares(svg: &str) -> usize {
+        let path = svg.split(r#" d=""#).nth(1).unwrap();
+        path[..path.find('"').unwrap()].matches('z').count()
+    }
+
+    #[test]
+    fn svg_has_a_square_per_dark_module() {
+        let options = QrRenderOptions {
+            module_size: 3,
+            dark_color: [0x12, 0x34, 0x56],
+            ..QrRenderOptions::default()
+        };
+        let svg = render_svg(TEST_PUBLIC_KEY, options).unwrap();
+        let code =
+            QrCode::with_error_correction_level(TEST_PUBLIC_KEY, options.error_correction).unwrap();
+        let size = (code.width() + 8) * 3;
+        assert!(svg.starts_with("<?xml"));
+        assert!(svg.contains(&format!(r#"width="{0}" height="{0}""#, size)));
+        assert!(svg.contains(r##"fill="#123456""##));
+        assert!(svg.ends_with("</svg>"));
+        let squares = svg.matches("h3v3h-3z").count();
+        assert_eq!(
+            squares,
+            dark_modules(TEST_PUBLIC_KEY, options.error_correction)
+        );
+    }
+
+    fn parse_error(s: &str) -> String {
+        match parse_payload(s) {
+            Ok(payload) => panic!("{} parsed to {}", s, key_of(payload)),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn mangled_keys_are_rejected() {
+        const DAMAGED: &str = "The key in the QR code is damaged";
+        for key in [TEST_PUBLIC_KEY, TEST_SECRET_KEY] {
+            let uri = format!("{}key_name=test&public_key=", IMPORT_URI_PREFIX);
+            let data_start = key.rfind('1').unwrap() + 1;
+            // secret keys are written in upper case
+            let (q, p) = if key == TEST_SECRET_KEY {
+                ('Q', 'P')
+            } else {
+                ('q', 'p')
+            };
+            // any single misread character breaks the checksum
+            for i in data_start..key.len() {
+                let mut chars: Vec<char> = key.chars().collect();
+                chars[i] = if chars[i] == q { p } else { q };
+                let mangled: String = chars.into_iter().collect();
+                assert!(parse_error(&mangled).contains(DAMAGED), "{}", mangled);
+                if key == TEST_PUBLIC_KEY {
+                    let error = parse_error(&format!("{}{}", uri, mangled));
+                    assert!(error.contains(DAMAGED), "{}", error);
+                }
+            }
+            // as does a truncated key, wherever the scan stopped
+            for len in 0..key.len() {
+                let error = parse_error(&key[..len]);
+                let expected = if len < data_start {
+                    "Not a Cryptocam key QR code"
+                } else {
+                    DAMAGED
+                };
+                assert!(error.contains(expected), "{}: {}", &key[..len], error);
+            }
+        }
+
+        // the checksum covers the human readable part, so a key with the other key's prefix
+        // doesn't pass for one
+        let data = |key: &str| key[key.rfind('1').unwrap() + 1..].to_owned();
+        let as_secret = format!("AGE-SECRET-KEY-1{}", data(TEST_PUBLIC_KEY).to_uppercase());
+        assert!(parse_error(&as_secret).contains(DAMAGED));
+        let as_public = format!("age1{}", data(TEST_SECRET_KEY).to_lowercase());
+        assert!(parse_error(&as_public).contains(DAMAGED));
+        // other prefixes aren't taken for keys at all
+        let other_hrp = TEST_PUBLIC_KEY.replacen("age1", "agf1", 1);
+        assert_eq!(parse_error(&other_hrp), "Not a Cryptocam key QR code");
+        let uri = format!("{}public_key={}", IMPORT_URI_PREFIX, TEST_SECRET_KEY);
+        assert_eq!(parse_error(&uri), "Key QR code contains no age public key");
+    }
+
+    #[test]
+    fn split_parts_assemble_in_any_order() {
+        for algo in [HashAlgo::Sha256, HashAlgo::Sha512] {
+            let parts = split_payload(TEST_SECRET_KEY, 3, algo).unwrap();
+            assert_eq!(parts.len(), 3);
+            for (i, part) in parts.iter().enumerate() {
+                assert!(part.starts_with(&format!("cryptocam-part:{}/3:", i + 1)));
+            }
+            let shuffled = [parts[2].as_str(), parts[0].as_str(), parts[1].as_str()];
+            assert_eq!(key_of(assemble_parts(&shuffled).unwrap()), TEST_SECRET_KEY);
+        }
+    }
+
+    #[test]
+    fn assembling_fails_with_a_missing_duplicate_or_corrupted_part() {
+        let parts = split_payload(TEST_SECRET_KEY, 3, HashAlgo::Sha256).unwrap();
+        let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
+        let error = assemble_parts(&[parts[0], parts[2]]).err().unwrap();
+        assert!(
+            error.to_string().contains("Missing part(s) 2 of 3"),
+            "{}",
+            error
+        );
+
+        let error = assemble_parts(&[parts[0], parts[1], parts[1]])
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("given twice"), "{}", error);
+
+        // one misread character in the chunk of the second part
+        let mut corrupted = parts[1].to_owned();
+        let last = corrupted.pop().unwrap();
+        corrupted.push(if last == 'q' { 'p' } else { 'q' });
+        let error = assemble_parts(&[parts[0], corrupted.as_str(), parts[2]])
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("Checksum mismatch"), "{}", error);
+    }
+
+    #[test]
+    fn keyring_backup_round_trips() {
+        let mut keyring = Keyring::in_memory();
+        for label in ["phone", "tablet", "old & lost"] {
+            let key = age::x25519::Identity::generate().to_string();
+            keyring
+                .import_key(key.expose_secret(), Some(label.to_owned()))
+                .unwrap();
+        }
+        let locked = age::x25519::Identity::generate().to_string();
+        let locked = keyring
+            .import_key_encrypted(locked.expose_secret(), "passphrase", None)
+            .unwrap();
+        let export = export_keyring(
+            &keyring,
+            KeyringExportOptions {
+                max_part_len: 100,
+                ..KeyringExportOptions::default()
+            },
+        )
+        .unwrap();
+        assert!(export.parts.len() > 1);
+        assert_eq!(export.skipped.len(), 1);
+        assert_eq!(export.skipped[0].0.digest, locked);
+
+        let parts: Vec<&str> = export.parts.iter().rev().map(|p| p.text.as_str()).collect();
+        let mut restored = Keyring::in_memory();
+        let results = import_keyring_parts(&parts, &mut restored).unwrap();
+        assert_eq!(results.len(), 3);
+        assert!(results
+            .iter()
+            .all(|(_, result)| matches!(result, KeyringImportResult::Imported(_))));
+        let exported: Vec<_> = keyring
+            .identities()
+            .into_iter()
+            .filter(|identity| identity.digest != locked)
+            .map(|identity| (identity.digest, identity.label))
+            .collect();
+        let imported: Vec<_> = restored
+            .identities()
+            .into_iter()
+            .map(|identity| (identity.digest, identity.label))
+            .collect();
+        assert_eq!(imported, exported);
+
+        let again = import_keyring_parts(&parts, &mut restored).unwrap();
+        assert!(again
+            .iter()
+            .all(|(_, result)| matches!(result, KeyringImportResult::AlreadyPresent(_))));
+    }
+
+    #[test]
+    fn backup_sheet_of_a_secret_key() {
+        let payload = KeyQrPayload {
+            label: Some("Phone <Pixel>".to_owned()),
+            ..parse_payload(TEST_SECRET_KEY).unwrap()
+        };
+        let created = DateTime::parse_from_rfc3339("2021-06-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let options = BackupSheetOptions {
+            created: Some(created),
+            ..BackupSheetOptions::default()
+        };
+        let svg = render_backup_sheet(&payload, options).unwrap();
+        assert!(svg.starts_with("<?xml"));
+        assert!(svg.contains(r#"width="210mm" height="297mm""#));
+        assert!(svg.contains(">Cryptocam secret key backup</text>"));
+        assert!(svg.contains(">Phone &lt;Pixel&gt;</text>"));
+        assert!(svg.contains(&format!(">{}</text>", payload.fingerprint().unwrap())));
+        assert!(svg.contains(&format!(">{}</text>", TEST_PUBLIC_KEY)));
+        assert!(svg.contains(">2021-06-01</text>"));
+        assert!(svg.contains("To restore:"));
+        // the secret key itself is only in the QR code
+        assert!(!svg.contains(TEST_SECRET_KEY));
+        assert_eq!(qr_squares(&svg), dark_modules(TEST_SECRET_KEY, EcLevel::Q));
+        assert!(svg.ends_with("</svg>"));
+    }
+
+    #[test]
+    fn backup_sheet_of_a_public_key() {
+        let payload = parse_payload(TEST_PUBLIC_KEY).unwrap();
+        let options = BackupSheetOptions {
+            paper: PaperSize::Letter,
+            ..BackupSheetOptions::default()
+        };
+        let svg = render_backup_sheet(&payload, options).unwrap();
+        assert!(svg.contains(r#"width="215.9mm" height="279.4mm""#));
+        assert!(svg.contains(">Cryptocam public key</text>"));
+        assert!(svg.contains("To use:"));
+        assert!(!svg.contains(">Created:</text>"));
+        let uri = format!(
+            "{}key_name=&public_key={}",
+            IMPORT_URI_PREFIX, TEST_PUBLIC_KEY
+        );
+        assert_eq!(qr_squares(&svg), dark_modules(&uri, EcLevel::Q));
+    }
+
+    #[cfg(all(feature = "png", feature = "qr-decode"))]
+    #[test]
+    fn png_decodes_to_the_key() {
+        let dir = tempfile::tempdir().unwrap();
+        for key in [TEST_SECRET_KEY, TEST_PUBLIC_KEY] {
+            let path = dir.path().join("key.png");
+            std::fs::write(&path, render_png(key, QrRenderOptions::default()).unwrap()).unwrap();
+            assert_eq!(key_of(decode_from_image(&path).unwrap()), key);
+        }
+    }
+
+    #[cfg(all(feature = "png", feature = "qr-decode"))]
+    #[test]
+    fn rotated_code_is_decoded() {
+        let dir = tempfile::tempdir().unwrap();
+        let png = render_png(TEST_PUBLIC_KEY, QrRenderOptions::default()).unwrap();
+        let rotated = image::load_from_memory(&png).unwrap().rotate90();
+        let path = dir.path().join("rotated.png");
+        rotated.save(&path).unwrap();
+        let payload = decode_from_image(&path).unwrap();
+        assert_eq!(payload.key.expose_secret(), TEST_PUBLIC_KEY);
+    }
+
+    #[cfg(all(feature = "png", feature = "qr-decode"))]
+    #[test]
+    fn other_codes_and_blank_images_are_told_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("other.png");
+        let png = render_png("https://example.com", QrRenderOptions::default()).unwrap();
+        std::fs::write(&path, png).unwrap();
+        assert!(matches!(
+            decode_from_image(&path),
+            Err(QrDecodeError::NotCryptocamKey(_))
+        ));
+
+        let path = dir.path().join("blank.png");
+        image::GrayImage::from_pixel(200, 200, image::Luma([255]))
+            .save(&path)
+            .unwrap();
+        assert!(matches!(
+            decode_from_image(&path),
+            Err(QrDecodeError::NoQrCode)
+        ));
+    }
+}
+
+#[cfg(all(test, feature = "shamir"))]
+mod shamir_tests {
+    use super::*;
+    use crate::fixtures::TEST_SECRET_KEY;
+
+    fn key_of(payload: KeyQrPayload) -> String {
+        payload.key.expose_secret().clone()
+    }
+
+    fn recover(shares: &[String]) -> Result<KeyQrPayload> {
+        let shares: Vec<&str> = shares.iter().map(String::as_str).collect();
+        recover_shamir(&shares)
+    }
+
+    #[test]
+    fn shamir_recovers_from_exactly_k_shares() {
+        let shares = split_shamir(TEST_SECRET_KEY, 3, 5, HashAlgo::Sha256).unwrap();
+        assert_eq!(shares.len(), 5);
+        for chosen in [[0, 1, 2], [2, 3, 4], [4, 0, 2]] {
+            let chosen: Vec<String> = chosen.iter().map(|&i| shares[i].clone()).collect();
+            assert_eq!(key_of(recover(&chosen).unwrap()), TEST_SECRET_KEY);
+        }
+    }
+
+    #[test]
+    fn shamir_recovers_from_more_than_k_shares() {
+        let mut shares = split_shamir(TEST_SECRET_KEY, 2, 4, HashAlgo::Sha512).unwrap();
+        shares.reverse();
+        assert_eq!(key_of(recover(&shares).unwrap()), TEST_SECRET_KEY);
+        assert_eq!(key_of(recover(&shares[1..]).unwrap()), TEST_SECRET_KEY);
+    }
+
+    #[test]
+    fn shamir_fails_with_fewer_than_k_shares() {
+        let shares = split_shamir(TEST_SECRET_KEY, 3, 5, HashAlgo::Sha256).unwrap();
+        let error = recover(&shares[..2]).err().unwrap();
+        assert!(error.to_string().contains("2 of the 3 shares"), "{}", error);
+    }
+
+    #[test]
+    fn shamir_rejects_shares_of_different_splits() {
+        let first = split_shamir(TEST_SECRET_KEY, 2, 3, HashAlgo::Sha256).unwrap();
+        let second = split_shamir(TEST_SECRET_KEY, 2, 3, HashAlgo::Sha256).unwrap();
+        let mixed = vec![first[0].clone(), second[1].clone()];
+        let error = recover(&mixed).err().unwrap();
+        assert!(error.to_string().contains("different backups"), "{}", error);
+
+        let other_key = age::x25519::Identity::generate();
+        let other = split_shamir(
+            other_key.to_string().expose_secret(),
+            2,
+            3,
+            HashAlgo::Sha256,
+        )
+        .unwrap();
+        let mixed = vec![first[0].clone(), other[1].clone()];
+        let error = recover(&mixed).err().unwrap();
+        assert!(error.to_string().contains("different keys"), "{}", error);
+    }
 }
@@ -7,34 +7,59 @@ File contents:
 "public_key": string,
 "identity_type": "unencrypted"/"scrypt_encrypted",
 "encrypted_identity": string, //base64 encoded
-"identity": string
+"identity": string,
+"created": string, //RFC 3339, optional
 }
 */
 
-use age;
+pub use crate::fingerprint::RecipientDigest;
+pub use crate::signing::{sign_manifest, verify_manifest, ManifestKey, Signature};
+use crate::{error, passphrase::PassphraseProvider};
+use age::{self, armor::ArmoredReader};
 use anyhow::{anyhow, bail, Context, Result};
 use base64;
+use chrono::{DateTime, SecondsFormat, Utc};
 use ini::Ini;
 use log::warn;
-use secrecy::{ExposeSecret, Secret};
-use sha2::{Digest, Sha256};
+use secrecy::{ExposeSecret, Secret, SecretString};
 use std::{
     collections::HashMap,
-    convert::TryInto,
     error::Error,
     format,
-    io::{Read, Write},
+    io::{self, Cursor, Read, Write},
     iter,
     path::{Path, PathBuf},
     str::FromStr,
+    sync::{mpsc, Arc, Mutex, OnceLock},
+    thread,
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 
-pub type KeyDigest = [u8; 16];
+/// See RecipientDigest, the fingerprint of a key.
+pub type KeyDigest = RecipientDigest;
+
+/// How often the PassphraseProvider is asked for the passphrase of a passphrase encrypted file.
+pub const MAX_PASSPHRASE_ATTEMPTS: usize = 3;
 
 pub struct Keyring {
-    path: PathBuf,
+    /// None for keyrings that only live in memory, see in_memory().
+    path: Option<PathBuf>,
     identities: HashMap<KeyDigest, Identity>,
+    unlock_timeout: Option<Duration>,
+    /// How long identities stay unlocked, see set_unlock_ttl().
+    unlock_ttl: Option<Duration>,
+    passphrase_provider: Option<Box<dyn PassphraseProvider + Send>>,
+    phase_callback: Option<Box<dyn FnMut(KeyringPhase) + Send>>,
+}
+
+/// What the keyring is busy with, see Keyring::set_phase_callback().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum KeyringPhase {
+    /// A passphrase protected identity or file is being unlocked, which is estimated to take
+    /// `estimated_ms` from its scrypt work factor, like Keyring::unlock_estimate().
+    UnlockingKey { estimated_ms: u64 },
 }
 
 #[derive(Debug, Clone)]
@@ -45,12 +70,55 @@ pub struct DisplayIdentity {
     pub public_key_digest: KeyDigest,
 }
 
+/// What is known about an identity in the keyring, without unlocking it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentityInfo {
+    /// Same as the recipient digests in the file header, see parser::parse_header().
+    pub digest: KeyDigest,
+    pub label: Option<String>,
+    /// When the key was created, unknown for keyfiles written by older versions.
+    pub created: Option<DateTime<Utc>>,
+    /// Whether the secret key is protected by a passphrase and still locked.
+    pub encrypted: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MatchResult {
+    /// The identity that can decrypt the file. It may still have to be unlocked first.
+    Matched(IdentityInfo),
+    /// None of the file's recipients are in the keyring.
+    NoMatch(Vec<KeyDigest>),
+}
+
 #[derive(Debug, Error)]
 pub enum DecryptionError {
     #[error("Identity {0:?} is encrypted")]
     IdentityEncrypted(DisplayIdentity),
-    #[error("No key found to decrypt file")]
-    NoSuchKey,
+    /// None of the file's recipients are in the keyring.
+    #[error("No key found to decrypt file, it is encrypted to {}", digest_list(.recipients))]
+    NoSuchKey { recipients: Vec<KeyDigest> },
+    /// The identity for one of the file's recipients is in the keyring, but it can't decrypt
+    /// the file key, so either the key or the header of the file is corrupted.
+    #[error(
+        "The key {} matches the file, but can't decrypt it, the key or the file is corrupted",
+        digest_list(&[.identity.digest])
+    )]
+    UnwrapFailed { identity: IdentityInfo },
+    /// The file or the identity's keyfile is encrypted with a passphrase, and the
+    /// PassphraseProvider didn't give the right one in MAX_PASSPHRASE_ATTEMPTS tries.
+    #[error("Wrong passphrase")]
+    BadPassphrase,
+    /// A chunk of the payload failed authentication after `decrypted_bytes` were decrypted,
+    /// the file was damaged or tampered with. `encrypted_offset` is where the chunk starts,
+    /// counted from the start of the age stream. Only happens while reading the decrypted
+    /// payload, the reader returned by Keyring::decrypt() fails with an io::Error holding this.
+    #[error("The file is corrupted or was tampered with after {decrypted_bytes} decrypted bytes")]
+    PayloadCorrupted {
+        decrypted_bytes: u64,
+        encrypted_offset: u64,
+    },
+    #[error("Cancelled")]
+    Cancelled,
     #[error("Decrytion error: {0:?}")]
     Other(anyhow::Error),
 }
@@ -61,10 +129,18 @@ impl From<anyhow::Error> for DecryptionError {
     }
 }
 
+fn digest_list(digests: &[KeyDigest]) -> String {
+    let digests: Vec<String> = digests.iter().map(ToString::to_string).collect();
+    digests.join(", ")
+}
+
 #[derive(Debug, Error)]
 pub enum DecryptIdentityError {
+    /// Becomes DecryptionError::BadPassphrase when unlocking for Keyring::decrypt().
     #[error("Wrong passphrase")]
-    WrongPassphrase,
+    BadPassphrase,
+    #[error("Unlocking the secret key took too long")]
+    UnlockTimedOut,
     #[error("Error decrypting secret key: {0:?}")]
     Other(anyhow::Error),
 }
@@ -75,6 +151,15 @@ impl From<anyhow::Error> for DecryptIdentityError {
     }
 }
 
+impl From<DecryptIdentityError> for DecryptionError {
+    fn from(e: DecryptIdentityError) -> Self {
+        match e {
+            DecryptIdentityError::BadPassphrase => DecryptionError::BadPassphrase,
+            e => DecryptionError::Other(e.into()),
+        }
+    }
+}
+
 impl Keyring {
     pub fn load_from_directory(keyring_path: PathBuf) -> Result<Keyring> {
         let entries = std::fs::read_dir(&keyring_path)?;
@@ -107,11 +192,102 @@ impl Keyring {
             };
         }
         Ok(Keyring {
-            path: keyring_path,
+            path: Some(keyring_path),
             identities,
+            unlock_timeout: None,
+            unlock_ttl: None,
+            passphrase_provider: None,
+            phase_callback: None,
         })
     }
 
+    /// A keyring without a directory. Keys that are created or imported are kept in memory only,
+    /// no keyfiles are written. Use save() to write them out.
+    pub fn in_memory() -> Keyring {
+        Keyring {
+            path: None,
+            identities: HashMap::new(),
+            unlock_timeout: None,
+            unlock_ttl: None,
+            passphrase_provider: None,
+            phase_callback: None,
+        }
+    }
+
+    /// Limits how long decrypt_identity() may spend deriving the key from a passphrase, it
+    /// fails with DecryptIdentityError::UnlockTimedOut after that. Identities whose scrypt work
+    /// factor is estimated to exceed the limit are rejected without starting the derivation.
+    /// A derivation can't be interrupted, so when the estimate was too low, the call returns
+    /// once it has finished anyway.
+    pub fn set_unlock_timeout(&mut self, timeout: Option<Duration>) {
+        self.unlock_timeout = timeout;
+    }
+
+    /// Locks identities again once they have been unlocked for `ttl`, so the next decrypt()
+    /// has to unlock them again. Without a TTL, identities stay unlocked for all further
+    /// decryptions until lock() is called or the keyring is dropped.
+    pub fn set_unlock_ttl(&mut self, ttl: Option<Duration>) {
+        self.unlock_ttl = ttl;
+    }
+
+    /// Forgets the secret keys of all unlocked identities, the keys are zeroized. Passphrase
+    /// protected identities have to be unlocked again before decrypting.
+    pub fn lock(&mut self) {
+        for identity in self.identities.values_mut() {
+            identity.lock();
+        }
+    }
+
+    /// Locks the identities that have been unlocked for longer than the TTL.
+    fn lock_expired(&mut self) {
+        let ttl = match self.unlock_ttl {
+            None => return,
+            Some(ttl) => ttl,
+        };
+        for identity in self.identities.values_mut() {
+            let expired = match identity.secret_key {
+                SecretKey::Unlocked(_, _, Some(unlocked_at)) => unlocked_at.elapsed() >= ttl,
+                _ => false,
+            };
+            if expired {
+                identity.lock();
+            }
+        }
+    }
+
+    /// Lets decrypt() unlock passphrase protected identities by asking `provider`.
+    /// Without a provider, decrypt() fails with DecryptionError::IdentityEncrypted instead
+    /// and the identity has to be unlocked with decrypt_identity().
+    pub fn set_passphrase_provider(
+        &mut self,
+        provider: Option<Box<dyn PassphraseProvider + Send>>,
+    ) {
+        self.passphrase_provider = provider;
+    }
+
+    /// Calls `callback` with KeyringPhase::UnlockingKey whenever a passphrase is about to be
+    /// tried on a passphrase protected identity or file, so a UI can tell how long it will
+    /// take. The first estimate runs a short calibration of scrypt on this device.
+    pub fn set_phase_callback(&mut self, callback: Option<Box<dyn FnMut(KeyringPhase) + Send>>) {
+        self.phase_callback = callback;
+    }
+
+    /// Estimates how long unlocking the identity with decrypt_identity() will take, based on
+    /// the scrypt work factor stored with it. Returns None for unencrypted or already unlocked
+    /// identities.
+    pub fn unlock_estimate(&self, digest: &KeyDigest) -> Option<UnlockEstimate> {
+        match &self.identities.get(digest)?.secret_key {
+            SecretKey::Unencrypted(_) | SecretKey::Unlocked(..) => None,
+            SecretKey::ScryptEncrypted(encrypted) => {
+                let work_factor = scrypt_work_factor(encrypted)?;
+                Some(UnlockEstimate {
+                    work_factor,
+                    estimated: estimate_scrypt_duration(work_factor),
+                })
+            }
+        }
+    }
+
     pub fn create_key(
         &mut self,
         name: &str,
@@ -119,6 +295,7 @@ impl Keyring {
     ) -> Result<DisplayIdentity, Box<dyn Error>> {
         let age_identity = age::x25519::Identity::generate();
         let public_key = age_identity.to_public().to_string();
+        let created = Utc::now();
         let secret_key = match passphrase {
             None => SecretKey::Unencrypted(age_identity),
             Some(passphrase) => {
@@ -130,48 +307,288 @@ impl Keyring {
             }
         };
 
-        let ini_secret_key: String = match &secret_key {
-            SecretKey::Unencrypted(k) => k.to_string().expose_secret().to_string(),
-            SecretKey::ScryptEncrypted(k) => base64::encode(&k),
-        };
-        let identity_type = match passphrase {
-            None => "unencrypted",
-            Some(_) => "scrypt_encrypted",
+        let identity = Identity {
+            name: name.to_owned(),
+            path: self.new_keyfile_path(name),
+            public_key_digest: compute_digest(&public_key),
+            public_key,
+            secret_key,
+            created: Some(created),
         };
-        let mut ini_file = Ini::new();
-        ini_file
-            .with_section::<String>(None)
-            .set("name", name)
-            .set("public_key", &public_key)
-            .set("identity_type", identity_type)
-            .set("secret_key", ini_secret_key);
-        let mut keyfile_path = PathBuf::from(&self.path);
-        let filename: String = name
-            .chars()
-            .map(|c| match c {
-                ' ' | '/' | '.' => '_',
-                other => other,
-            })
-            .collect();
-        keyfile_path.push(Path::new(&format!("{}.ini", &filename)));
-        ini_file.write_to_file(&keyfile_path)?;
+        self.write_keyfile(&identity)?;
+        let display_identity = identity.to_display_identity();
+        self.identities.insert(identity.public_key_digest, identity);
+        Ok(display_identity)
+    }
+
+    /// Adds an age secret key (AGE-SECRET-KEY-1...) to the keyring and writes its keyfile.
+    /// If the key is already in the keyring, nothing is added and the existing digest is returned.
+    pub fn import_key(&mut self, key_material: &str, label: Option<String>) -> Result<KeyDigest> {
+        self.import(key_material, label, None, None)
+    }
+
+    /// Like import_key(), with the secret key stored encrypted with `passphrase`.
+    /// The key has to be unlocked with decrypt_identity() before it can decrypt files.
+    pub fn import_key_encrypted(
+        &mut self,
+        key_material: &str,
+        passphrase: &str,
+        label: Option<String>,
+    ) -> Result<KeyDigest> {
+        self.import(key_material, label, Some(passphrase), None)
+    }
+
+    /// Like import_key(), keeping the creation time of the key from a backup.
+    pub(crate) fn import_key_created(
+        &mut self,
+        key_material: &str,
+        label: Option<String>,
+        created: Option<DateTime<Utc>>,
+    ) -> Result<KeyDigest> {
+        self.import(key_material, label, None, created)
+    }
+
+    /// `created` is now if not given.
+    fn import(
+        &mut self,
+        key_material: &str,
+        label: Option<String>,
+        passphrase: Option<&str>,
+        created: Option<DateTime<Utc>>,
+    ) -> Result<KeyDigest> {
+        let age_identity = age::x25519::Identity::from_str(key_material.trim())
+            .map_err(|e| anyhow!("Invalid secret key: {}", e))?;
+        let public_key = age_identity.to_public().to_string();
         let digest = compute_digest(&public_key);
-        self.identities.insert(
-            digest,
-            Identity {
-                name: name.to_owned(),
-                path: keyfile_path.clone(),
-                public_key: public_key.clone(),
-                public_key_digest: digest,
-                secret_key,
-            },
-        );
-        Ok(DisplayIdentity {
-            name: name.to_owned(),
-            path: keyfile_path,
-            public_key: public_key,
+        if self.identities.contains_key(&digest) {
+            return Ok(digest);
+        }
+        let secret_key = match passphrase {
+            None => SecretKey::Unencrypted(age_identity),
+            Some(passphrase) => SecretKey::ScryptEncrypted(encrypt_identity(
+                age_identity.to_string().expose_secret(),
+                passphrase.to_owned(),
+            )?),
+        };
+        let name = label.unwrap_or_else(|| format!("Imported key {}", &public_key[4..12]));
+        let identity = Identity {
+            path: self.new_keyfile_path(&name),
+            name,
+            public_key,
             public_key_digest: digest,
-        })
+            secret_key,
+            created: Some(created.unwrap_or_else(Utc::now)),
+        };
+        self.write_keyfile(&identity)?;
+        self.identities.insert(digest, identity);
+        Ok(digest)
+    }
+
+    /// Imports the secret keys of an age identity file, as written by age-keygen or rage-keygen:
+    /// one AGE-SECRET-KEY-1... per line, along with # comments and empty lines. Files encrypted
+    /// with a passphrase (age -p, armored or not) are decrypted with the PassphraseProvider.
+    /// Nothing is imported if any line is invalid. Returns the digests of all keys in the file,
+    /// including those that were in the keyring already.
+    pub fn import_age_identities(&mut self, mut reader: impl Read) -> Result<Vec<KeyDigest>> {
+        let mut contents = vec![];
+        reader.read_to_end(&mut contents)?;
+        let contents = if contents.starts_with(b"age-encryption.org/")
+            || contents.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----")
+        {
+            self.decrypt_identity_file(&contents)?
+        } else {
+            SecretString::new(
+                String::from_utf8(contents).context("Invalid UTF-8 in identity file")?,
+            )
+        };
+        let keys: Vec<&str> = contents
+            .expose_secret()
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+        for (n, key) in keys.iter().enumerate() {
+            if age::x25519::Identity::from_str(key).is_err() {
+                bail!(
+                    "Identity {} in the file is not an age X25519 secret key",
+                    n + 1
+                );
+            }
+        }
+        keys.iter()
+            .map(|key| self.import(key, None, None, None))
+            .collect()
+    }
+
+    fn decrypt_identity_file(&mut self, encrypted: &[u8]) -> Result<SecretString> {
+        // taken out while asking like in unlock_with_provider()
+        let mut provider = self.passphrase_provider.take().ok_or_else(|| {
+            anyhow!("The identity file is encrypted, but there is no passphrase provider")
+        })?;
+        let mut retry = false;
+        let result = loop {
+            let passphrase =
+                match provider.get_passphrase("Passphrase for the identity file", retry) {
+                    Ok(p) => p,
+                    Err(e) => break Err(e),
+                };
+            let decryptor = match age::Decryptor::new(ArmoredReader::new(encrypted)) {
+                Ok(age::Decryptor::Passphrase(d)) => d,
+                Ok(_) => {
+                    break Err(anyhow!(
+                        "The identity file is not encrypted with a passphrase"
+                    ))
+                }
+                Err(e) => break Err(anyhow!("Invalid encrypted identity file: {}", e)),
+            };
+            let mut reader = match decryptor.decrypt(&passphrase, None) {
+                Ok(reader) => reader,
+                Err(age::DecryptError::DecryptionFailed) => {
+                    retry = true;
+                    continue;
+                }
+                Err(e) => break Err(anyhow!("Error decrypting identity file: {}", e)),
+            };
+            let mut contents = String::new();
+            break reader
+                .read_to_string(&mut contents)
+                .map(|_| SecretString::new(contents))
+                .context("Error decrypting identity file");
+        };
+        self.passphrase_provider = Some(provider);
+        result
+    }
+
+    /// The secret key of an identity as an age identity file in the format age-keygen writes,
+    /// for use with age or rage. Passphrase protected identities have to be unlocked first.
+    pub fn export_age_identity(&self, digest: &KeyDigest) -> Result<String> {
+        let identity = self
+            .identities
+            .get(digest)
+            .ok_or_else(|| anyhow!("Key not found"))?;
+        let secret_key = self.export_secret_key(digest)?;
+        let mut file = String::new();
+        if let Some(created) = identity.created {
+            file += &format!(
+                "# created: {}\n",
+                created.to_rfc3339_opts(SecondsFormat::Secs, true)
+            );
+        }
+        file += &format!("# public key: {}\n", identity.public_key);
+        file += secret_key.expose_secret();
+        file.push('\n');
+        Ok(file)
+    }
+
+    /// The age secret key (AGE-SECRET-KEY-1...) of an identity. Passphrase protected identities
+    /// have to be unlocked first.
+    pub(crate) fn export_secret_key(&self, digest: &KeyDigest) -> Result<SecretString> {
+        let identity = self
+            .identities
+            .get(digest)
+            .ok_or_else(|| anyhow!("Key not found"))?;
+        match &identity.secret_key {
+            SecretKey::ScryptEncrypted(_) => {
+                bail!(
+                    "Identity {} is locked, unlock it with decrypt_identity() first",
+                    identity.name
+                )
+            }
+            SecretKey::Unencrypted(age_identity) | SecretKey::Unlocked(age_identity, ..) => {
+                Ok(age_identity.to_string())
+            }
+        }
+    }
+
+    /// Re-encrypts a secret key with a new passphrase and rewrites its keyfile.
+    /// For keys that are not passphrase protected yet, `old` has to be empty.
+    pub fn change_passphrase(
+        &mut self,
+        digest: &KeyDigest,
+        old: String,
+        new: String,
+    ) -> Result<(), DecryptIdentityError> {
+        let identity = match self.identities.get_mut(digest) {
+            None => return Err(DecryptIdentityError::Other(anyhow!("Key not found"))),
+            Some(i) => i,
+        };
+        let age_identity = match &identity.secret_key {
+            SecretKey::Unencrypted(_) if !old.is_empty() => {
+                return Err(DecryptIdentityError::BadPassphrase)
+            }
+            SecretKey::Unencrypted(age_identity) => age_identity.clone(),
+            SecretKey::ScryptEncrypted(encrypted) | SecretKey::Unlocked(_, encrypted, _) => {
+                report_unlock(&mut self.phase_callback, encrypted);
+                try_decrypt_identity(encrypted, &SecretString::new(old), self.unlock_timeout)?
+            }
+        };
+        let encrypted = encrypt_identity(age_identity.to_string().expose_secret(), new)?;
+        let secret_key = match identity.secret_key {
+            // a locked key stays locked
+            SecretKey::ScryptEncrypted(_) => SecretKey::ScryptEncrypted(encrypted),
+            _ => SecretKey::Unlocked(age_identity, encrypted, unlock_time()),
+        };
+        let previous = std::mem::replace(&mut identity.secret_key, secret_key);
+        if self.path.is_none() {
+            return Ok(());
+        }
+        if let Err(e) = write_keyfile(&identity.path, identity) {
+            identity.secret_key = previous;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Removes an identity from the keyring and deletes its keyfile.
+    /// Returns false if there was no such identity.
+    pub fn remove_key(&mut self, digest: &KeyDigest) -> Result<bool> {
+        let identity = match self.identities.remove(digest) {
+            None => return Ok(false),
+            Some(i) => i,
+        };
+        if self.path.is_none() {
+            return Ok(true);
+        }
+        if let Err(e) = std::fs::remove_file(&identity.path) {
+            let path = identity.path.clone();
+            self.identities.insert(*digest, identity);
+            return Err(e).with_context(|| format!("Could not delete {}", path.display()));
+        }
+        Ok(true)
+    }
+
+    /// Writes the keyfiles of all identities into the directory at `path`. Identities that are
+    /// locked are written still encrypted with their passphrase.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::create_dir_all(path)?;
+        for identity in self.identities.values() {
+            let keyfile_path = match identity.path.file_name() {
+                Some(file_name) => path.join(file_name),
+                None => new_keyfile_path(path, &identity.name),
+            };
+            write_keyfile(&keyfile_path, identity)?;
+        }
+        Ok(())
+    }
+
+    /// Where keyfiles of new identities go, nowhere for in memory keyrings.
+    fn new_keyfile_path(&self, name: &str) -> PathBuf {
+        match &self.path {
+            Some(path) => new_keyfile_path(path, name),
+            None => PathBuf::new(),
+        }
+    }
+
+    fn write_keyfile(&self, identity: &Identity) -> Result<()> {
+        match &self.path {
+            Some(_) => write_keyfile(&identity.path, identity),
+            None => Ok(()),
+        }
+    }
+
+    /// Same as load_from_directory().
+    pub fn load(path: &Path) -> Result<Keyring> {
+        Keyring::load_from_directory(path.to_path_buf())
     }
 
     pub fn display_identities(&self) -> Vec<DisplayIdentity> {
@@ -184,6 +601,30 @@ impl Keyring {
         display_identities
     }
 
+    /// Lists the loaded identities, sorted by label.
+    pub fn identities(&self) -> Vec<IdentityInfo> {
+        let mut identities: Vec<IdentityInfo> = self
+            .identities
+            .values()
+            .map(|identity| identity.to_identity_info())
+            .collect();
+        identities.sort_by(|i1, i2| i1.label.cmp(&i2.label));
+        identities
+    }
+
+    pub fn contains(&self, digest: &KeyDigest) -> bool {
+        self.identities.contains_key(digest)
+    }
+
+    /// Finds the identity decrypt() tries first for a file with these recipients, without
+    /// unlocking it or touching the disk.
+    pub fn can_decrypt(&self, recipient_digests: &[KeyDigest]) -> MatchResult {
+        match self.matching_identities(recipient_digests).first() {
+            Some(digest) => MatchResult::Matched(self.identities[digest].to_identity_info()),
+            None => MatchResult::NoMatch(recipient_digests.to_vec()),
+        }
+    }
+
     pub fn get_identity(&self, digest: &KeyDigest) -> Result<DisplayIdentity> {
         self.identities
             .get(digest)
@@ -191,40 +632,219 @@ impl Keyring {
             .ok_or_else(|| anyhow!("Key not found"))
     }
 
+    /// Decrypts the age stream of a file, along with the identity that was used. Every identity
+    /// for one of `recipient_digests` is tried until one decrypts the file, those that don't
+    /// have to be unlocked first, then the passphrase protected ones, each in the order of the
+    /// digests. Files encrypted with a passphrase instead of keys are decrypted by asking the
+    /// PassphraseProvider, the identity is None for them.
+    /// Fails with DecryptionError::NoSuchKey if no identity matches, and otherwise with the
+    /// error of the last identity tried: IdentityEncrypted, BadPassphrase or UnwrapFailed. Damage to the payload only shows
+    /// while reading it, as DecryptionError::PayloadCorrupted inside the io::Error.
     pub fn decrypt(
         &mut self,
         encrypted: impl Read,
         recipient_digests: &Vec<KeyDigest>,
-    ) -> std::result::Result<impl Read, DecryptionError> {
-        if let Some(digest) = recipient_digests
-            .iter()
-            .find(|&d| self.identities.contains_key(d))
-        {
-            let identity = self.identities.get(digest).unwrap();
-            let age_identity = match &identity.secret_key {
-                SecretKey::ScryptEncrypted(_) => {
-                    return Err(DecryptionError::IdentityEncrypted(
-                        identity.to_display_identity(),
-                    ));
+    ) -> std::result::Result<(impl Read, Option<IdentityInfo>), DecryptionError> {
+        self.lock_expired();
+        let input = SharedReader(Arc::new(Mutex::new(encrypted)));
+        // age reads exactly the header, so the header can be replayed in front of the rest
+        let mut header = vec![];
+        let header_reader = TeeReader {
+            inner: input.clone(),
+            copy: &mut header,
+        };
+        let is_passphrase = match age::Decryptor::new(header_reader) {
+            Ok(age::Decryptor::Recipients(_)) => false,
+            Ok(age::Decryptor::Passphrase(_)) => true,
+            Err(e) => {
+                return Err(DecryptionError::Other(anyhow!(
+                    "Failed to decrypt: invalid age header: {}",
+                    e
+                )))
+            }
+        };
+        let reopen = || age::Decryptor::new(Cursor::new(header.clone()).chain(input.clone()));
+        if is_passphrase {
+            let decrypted = self.decrypt_with_passphrase(&header, reopen)?;
+            return Ok((PayloadReader::new(decrypted, header.len()), None));
+        }
+        let candidates = self.matching_identities(recipient_digests);
+        let mut error = DecryptionError::NoSuchKey {
+            recipients: recipient_digests.clone(),
+        };
+        for digest in &candidates {
+            match self.decrypt_with_identity(digest, &reopen) {
+                Ok(decrypted) => {
+                    return Ok((
+                        PayloadReader::new(decrypted, header.len()),
+                        Some(self.identities[digest].to_identity_info()),
+                    ))
+                }
+                Err(
+                    e @ (DecryptionError::IdentityEncrypted(_)
+                    | DecryptionError::BadPassphrase
+                    | DecryptionError::UnwrapFailed { .. }),
+                ) => error = e,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(error)
+    }
+
+    /// The identities for `recipient_digests`, first those that don't have to be unlocked, each
+    /// in the order of the digests.
+    fn matching_identities(&self, recipient_digests: &[KeyDigest]) -> Vec<KeyDigest> {
+        let mut digests: Vec<KeyDigest> = vec![];
+        for digest in recipient_digests {
+            if self.identities.contains_key(digest) && !digests.contains(digest) {
+                digests.push(*digest);
+            }
+        }
+        // stable, so the order of the header is kept among locked and unlocked identities
+        digests.sort_by_key(|digest| {
+            matches!(
+                self.identities[digest].secret_key,
+                SecretKey::ScryptEncrypted(_)
+            )
+        });
+        digests
+    }
+
+    /// Decrypts with the identity of `digest`, unlocking it with the PassphraseProvider first
+    /// if there is one.
+    fn decrypt_with_identity<R: Read>(
+        &mut self,
+        digest: &KeyDigest,
+        reopen: impl Fn() -> std::result::Result<age::Decryptor<R>, age::DecryptError>,
+    ) -> std::result::Result<age::stream::StreamReader<R>, DecryptionError> {
+        if self.passphrase_provider.is_some() {
+            self.unlock_with_provider(digest)?;
+        }
+        let identity = &self.identities[digest];
+        let age_identity = match &identity.secret_key {
+            SecretKey::ScryptEncrypted(_) => {
+                return Err(DecryptionError::IdentityEncrypted(
+                    identity.to_display_identity(),
+                ));
+            }
+            SecretKey::Unencrypted(identity) | SecretKey::Unlocked(identity, ..) => identity,
+        };
+        let decryptor = match reopen() {
+            Ok(age::Decryptor::Recipients(d)) => d,
+            _ => {
+                return Err(DecryptionError::Other(anyhow!(
+                    "Failed to decrypt: not an X25519 Recipient"
+                )))
+            }
+        };
+        decryptor
+            .decrypt(iter::once(
+                Box::new(age_identity.clone()) as Box<dyn age::Identity>
+            ))
+            .map_err(|e| match e {
+                age::DecryptError::NoMatchingKeys
+                | age::DecryptError::DecryptionFailed
+                | age::DecryptError::KeyDecryptionFailed => DecryptionError::UnwrapFailed {
+                    identity: identity.to_identity_info(),
+                },
+                e => DecryptionError::Other(anyhow!("Failed to decrypt ciphertext: {}", e)),
+            })
+    }
+
+    /// Asks the PassphraseProvider for the passphrase of a file with an scrypt recipient, up to
+    /// MAX_PASSPHRASE_ATTEMPTS times. `reopen` gives a new decryptor for every attempt,
+    /// `header` is the age header of the file.
+    fn decrypt_with_passphrase<R: Read>(
+        &mut self,
+        header: &[u8],
+        reopen: impl Fn() -> std::result::Result<age::Decryptor<R>, age::DecryptError>,
+    ) -> std::result::Result<age::stream::StreamReader<R>, DecryptionError> {
+        let mut provider = match self.passphrase_provider.take() {
+            None => {
+                return Err(DecryptionError::Other(anyhow!(
+                    "The file is encrypted with a passphrase, but there is no passphrase provider"
+                )))
+            }
+            Some(p) => p,
+        };
+        let mut result = Err(DecryptionError::BadPassphrase);
+        for attempt in 0..MAX_PASSPHRASE_ATTEMPTS {
+            let passphrase = match provider.get_passphrase("Passphrase for the file", attempt > 0) {
+                Ok(p) => p,
+                Err(e) => {
+                    result = match e.downcast_ref::<error::Error>() {
+                        Some(error::Error::Cancelled) => Err(DecryptionError::Cancelled),
+                        _ => Err(DecryptionError::Other(e)),
+                    };
+                    break;
                 }
-                SecretKey::Unencrypted(identity) => identity,
             };
-            let decryptor = match age::Decryptor::new(encrypted) {
-                Ok(age::Decryptor::Recipients(d)) => d,
+            report_unlock(&mut self.phase_callback, header);
+            let decryptor = match reopen() {
+                Ok(age::Decryptor::Passphrase(d)) => d,
                 _ => {
-                    return Err(DecryptionError::Other(anyhow!(
-                        "Failed to decrypt: not an X25519 Recipient"
-                    )))
+                    result = Err(DecryptionError::Other(anyhow!(
+                        "Failed to decrypt: not a passphrase encrypted file"
+                    )));
+                    break;
                 }
             };
-            decryptor
-                .decrypt(iter::once(
-                    Box::new(age_identity.clone()) as Box<dyn age::Identity>
-                ))
-                .map_err(|e| DecryptionError::Other(anyhow!("Failed to decrypt ciphertext: {}", e)))
-        } else {
-            Err(DecryptionError::NoSuchKey)
+            match decryptor.decrypt(&passphrase, None) {
+                Ok(decrypted) => {
+                    result = Ok(decrypted);
+                    break;
+                }
+                Err(age::DecryptError::DecryptionFailed) => {}
+                Err(e) => {
+                    result = Err(DecryptionError::Other(anyhow!(
+                        "Failed to decrypt ciphertext: {}",
+                        e
+                    )));
+                    break;
+                }
+            }
         }
+        self.passphrase_provider = Some(provider);
+        result
+    }
+
+    fn unlock_with_provider(&mut self, digest: &KeyDigest) -> Result<(), DecryptionError> {
+        let identity = &self.identities[digest];
+        if !matches!(identity.secret_key, SecretKey::ScryptEncrypted(_)) {
+            return Ok(());
+        }
+        let prompt = format!("Passphrase for {}", identity.name);
+        // taken out while asking so decrypt_identity() can borrow the keyring
+        let mut provider = match self.passphrase_provider.take() {
+            None => return Ok(()),
+            Some(p) => p,
+        };
+        let mut result = Err(DecryptionError::BadPassphrase);
+        for attempt in 0..MAX_PASSPHRASE_ATTEMPTS {
+            let passphrase = match provider.get_passphrase(&prompt, attempt > 0) {
+                Ok(p) => p,
+                Err(e) => {
+                    result = match e.downcast_ref::<error::Error>() {
+                        Some(error::Error::Cancelled) => Err(DecryptionError::Cancelled),
+                        _ => Err(DecryptionError::Other(e)),
+                    };
+                    break;
+                }
+            };
+            match self.unlock_identity(digest, &passphrase) {
+                Ok(()) => {
+                    result = Ok(());
+                    break;
+                }
+                Err(DecryptIdentityError::BadPassphrase) => {}
+                Err(e) => {
+                    result = Err(e.into());
+                    break;
+                }
+            }
+        }
+        self.passphrase_provider = Some(provider);
+        result
     }
 
     pub fn decrypt_identity(
@@ -232,34 +852,68 @@ impl Keyring {
         key_digest: &KeyDigest,
         passphrase: String,
     ) -> Result<(), DecryptIdentityError> {
-        let identity = self.identities.remove(key_digest).unwrap();
-        let encrypted = match &identity.secret_key {
-            SecretKey::Unencrypted(_) => {
-                self.identities.insert(*key_digest, identity);
-                return Ok(());
-            }
-            SecretKey::ScryptEncrypted(encrypted) => encrypted,
-        };
-        let age_identity = match try_decrypt_identity(&encrypted, passphrase) {
-            Err(e) => {
-                self.identities.insert(key_digest.clone(), identity);
-                return Err(e);
-            }
-            Ok(i) => i,
+        self.unlock_identity(key_digest, &SecretString::new(passphrase))
+    }
+
+    /// decrypt_identity() with the passphrase kept in a SecretString, which is zeroized when
+    /// dropped.
+    fn unlock_identity(
+        &mut self,
+        key_digest: &KeyDigest,
+        passphrase: &SecretString,
+    ) -> Result<(), DecryptIdentityError> {
+        let identity = match self.identities.get_mut(key_digest) {
+            None => return Err(DecryptIdentityError::Other(anyhow!("Key not found"))),
+            Some(i) => i,
         };
-        let identity = Identity {
-            secret_key: SecretKey::Unencrypted(age_identity),
-            ..identity
+        let encrypted = match &identity.secret_key {
+            SecretKey::Unencrypted(_) | SecretKey::Unlocked(..) => return Ok(()),
+            SecretKey::ScryptEncrypted(encrypted) => encrypted.clone(),
         };
-
-        self.identities.insert(*key_digest, identity);
+        report_unlock(&mut self.phase_callback, &encrypted);
+        let age_identity = try_decrypt_identity(&encrypted, passphrase, self.unlock_timeout)?;
+        identity.secret_key = SecretKey::Unlocked(age_identity, encrypted, unlock_time());
         Ok(())
     }
 }
 
+/// A new key pair from generate_identity().
+pub struct GeneratedIdentity {
+    pub label: Option<String>,
+    /// The age recipient (age1...), as put into the key QR code for the Cryptocam app.
+    pub recipient: String,
+    /// The age secret key (AGE-SECRET-KEY-1...), for Keyring::import_key().
+    pub identity: SecretString,
+    pub digest: KeyDigest,
+}
+
+/// Generates an X25519 key pair in the format the Cryptocam app uses, without storing it.
+/// Keyring::create_key() generates a key and adds it to a keyring in one step.
+pub fn generate_identity(label: Option<String>) -> Result<GeneratedIdentity> {
+    let age_identity = age::x25519::Identity::generate();
+    let recipient = age_identity.to_public().to_string();
+    Ok(GeneratedIdentity {
+        label,
+        digest: compute_digest(&recipient),
+        recipient,
+        identity: age_identity.to_string(),
+    })
+}
+
+/// How expensive it is to unlock a passphrase protected identity.
+#[derive(Debug, Clone, Copy)]
+pub struct UnlockEstimate {
+    /// log2 of the scrypt cost parameter N
+    pub work_factor: u8,
+    pub estimated: Duration,
+}
+
 enum SecretKey {
     Unencrypted(age::x25519::Identity),
     ScryptEncrypted(Vec<u8>),
+    /// Unlocked with decrypt_identity() at the given time, still stored encrypted on disk.
+    /// The age identity zeroizes the key when dropped.
+    Unlocked(age::x25519::Identity, Vec<u8>, Option<Instant>),
 }
 
 struct Identity {
@@ -268,14 +922,31 @@ struct Identity {
     pub public_key: String,
     pub public_key_digest: KeyDigest,
     pub secret_key: SecretKey,
+    pub created: Option<DateTime<Utc>>,
 }
 
 impl Identity {
+    fn lock(&mut self) {
+        if let SecretKey::Unlocked(_, encrypted, _) = &mut self.secret_key {
+            let encrypted = std::mem::take(encrypted);
+            self.secret_key = SecretKey::ScryptEncrypted(encrypted);
+        }
+    }
+
+    fn to_identity_info(&self) -> IdentityInfo {
+        IdentityInfo {
+            digest: self.public_key_digest,
+            label: Some(self.name.clone()).filter(|name| !name.is_empty()),
+            created: self.created,
+            encrypted: matches!(self.secret_key, SecretKey::ScryptEncrypted(_)),
+        }
+    }
+
     fn to_display_identity(&self) -> DisplayIdentity {
         DisplayIdentity {
             name: self.name.clone(),
             public_key: self.public_key.clone(),
-            public_key_digest: self.public_key_digest.clone(),
+            public_key_digest: self.public_key_digest,
             path: self.path.clone(),
         }
     }
@@ -294,33 +965,82 @@ fn parse_keyring_file(dir_entry: &std::fs::DirEntry) -> Result<Identity> {
     let public_key = section
         .get("public_key")
         .ok_or(anyhow!("Missing field public_key"))?;
-    if let Err(_) = age::x25519::Recipient::from_str(public_key) {
+    if age::x25519::Recipient::from_str(public_key).is_err() {
         bail!("Invalid public key {}", public_key);
     };
     let secret_key = section
         .get("secret_key")
         .ok_or(anyhow!("Missing field secret_key"))?;
     let secret_key = match identity_type {
-        "unencrypted" => match age::x25519::Identity::from_str(&secret_key) {
+        "unencrypted" => match age::x25519::Identity::from_str(secret_key) {
             Err(e) => bail!("Error parsing secret key: {}", e),
             Ok(age_identity) => SecretKey::Unencrypted(age_identity),
         },
-        "scrypt_encrypted" => match base64::decode(&secret_key) {
+        "scrypt_encrypted" => match base64::decode(secret_key) {
             Err(_) => bail!("Invalid base64 encoded encrypted identity"),
             Ok(bytes) => SecretKey::ScryptEncrypted(bytes),
         },
         other => bail!("Invalid identity type {}", other),
     };
-    let public_key_digest: KeyDigest = compute_digest(&public_key);
+    let created = match section.get("created").map(DateTime::parse_from_rfc3339) {
+        None => None,
+        Some(Ok(created)) => Some(created.with_timezone(&Utc)),
+        Some(Err(e)) => {
+            warn!("Invalid creation time in {}: {}", path.display(), e);
+            None
+        }
+    };
+    let public_key_digest: KeyDigest = compute_digest(public_key);
     Ok(Identity {
         path,
         name: name.to_string(),
         secret_key,
         public_key_digest,
         public_key: public_key.to_string(),
+        created,
     })
 }
 
+/// A path in `dir` for a new keyfile, named after the key if that file doesn't exist yet.
+fn new_keyfile_path(dir: &Path, name: &str) -> PathBuf {
+    let filename: String = name
+        .chars()
+        .map(|c| match c {
+            ' ' | '/' | '\\' | '.' => '_',
+            other => other,
+        })
+        .collect();
+    iter::once(dir.join(format!("{}.ini", filename)))
+        .chain((2..).map(|n| dir.join(format!("{}_{}.ini", filename, n))))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+fn write_keyfile(path: &Path, identity: &Identity) -> Result<()> {
+    let (identity_type, ini_secret_key) = match &identity.secret_key {
+        SecretKey::Unencrypted(k) => ("unencrypted", k.to_string().expose_secret().to_string()),
+        SecretKey::ScryptEncrypted(k) | SecretKey::Unlocked(_, k, _) => {
+            ("scrypt_encrypted", base64::encode(k))
+        }
+    };
+    let mut ini_file = Ini::new();
+    ini_file
+        .with_section::<String>(None)
+        .set("name", identity.name.as_str())
+        .set("public_key", identity.public_key.as_str())
+        .set("identity_type", identity_type)
+        .set("secret_key", ini_secret_key);
+    if let Some(created) = identity.created {
+        ini_file.with_section::<String>(None).set(
+            "created",
+            created.to_rfc3339_opts(SecondsFormat::Secs, true),
+        );
+    }
+    ini_file
+        .write_to_file(path)
+        .with_context(|| format!("Could not write keyfile {}", path.display()))
+}
+
 fn encrypt_identity(secret_key: &str, passphrase: String) -> Result<Vec<u8>> {
     let encryptor = age::Encryptor::with_user_passphrase(Secret::new(passphrase));
     let mut encrypted = Vec::<u8>::new();
@@ -335,11 +1055,140 @@ fn encrypt_identity(secret_key: &str, passphrase: String) -> Result<Vec<u8>> {
     Ok(encrypted)
 }
 
+/// The input of Keyring::decrypt(), shared by the decryptors of several attempts. Only the one
+/// that succeeds reads past the header.
+struct SharedReader<R>(Arc<Mutex<R>>);
+
+impl<R> Clone for SharedReader<R> {
+    fn clone(&self) -> Self {
+        SharedReader(self.0.clone())
+    }
+}
+
+impl<R: Read> Read for SharedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+/// Plaintext bytes in a chunk of the age stream, all but the last chunk are full.
+const AGE_CHUNK_SIZE: u64 = 64 << 10;
+/// The authentication tag after every chunk.
+const AGE_TAG_SIZE: u64 = 16;
+
+/// The decrypted payload returned by Keyring::decrypt(). age fails reads of chunks that don't
+/// authenticate with InvalidData, those become DecryptionError::PayloadCorrupted.
+struct PayloadReader<R> {
+    inner: R,
+    /// Length of the age header in front of the first chunk.
+    age_header_len: u64,
+    decrypted: u64,
+}
+
+impl<R> PayloadReader<R> {
+    fn new(inner: R, age_header_len: usize) -> Self {
+        PayloadReader {
+            inner,
+            age_header_len: age_header_len as u64,
+            decrypted: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for PayloadReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(read) => {
+                self.decrypted += read as u64;
+                Ok(read)
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                DecryptionError::PayloadCorrupted {
+                    decrypted_bytes: self.decrypted,
+                    // age only fails on whole chunks, the ones before were read completely
+                    encrypted_offset: self.age_header_len
+                        + self.decrypted / AGE_CHUNK_SIZE * (AGE_CHUNK_SIZE + AGE_TAG_SIZE),
+                },
+            )),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Keeps a copy of what is read.
+struct TeeReader<'a, R> {
+    inner: R,
+    copy: &'a mut Vec<u8>,
+}
+
+impl<R: Read> Read for TeeReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.copy.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// When an identity is unlocked, for set_unlock_ttl(). There is no clock on
+/// wasm32-unknown-unknown, identities stay unlocked there until lock().
+fn unlock_time() -> Option<Instant> {
+    if cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+        None
+    } else {
+        Some(Instant::now())
+    }
+}
+
 fn try_decrypt_identity(
-    encrypted: &Vec<u8>,
-    passphrase: String,
+    encrypted: &[u8],
+    passphrase: &SecretString,
+    timeout: Option<Duration>,
+) -> Result<age::x25519::Identity, DecryptIdentityError> {
+    let timeout = match timeout {
+        None => return unwrap_identity(encrypted, passphrase, None),
+        Some(t) => t,
+    };
+    // age refuses to start on work factors above the limit, so a hopeless unlock fails immediately
+    let max_work_factor = (1..=64)
+        .take_while(|&n| estimate_scrypt_duration(n) <= timeout)
+        .last()
+        .unwrap_or(1);
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            let _ = sender.send(unwrap_identity(
+                encrypted,
+                passphrase,
+                Some(max_work_factor),
+            ));
+        });
+        // scrypt can't be interrupted, but the work factor limit keeps the derivation close to
+        // the timeout, so waiting for the thread to finish at the end of the scope is short
+        receiver
+            .recv_timeout(timeout)
+            .unwrap_or(Err(DecryptIdentityError::UnlockTimedOut))
+    })
+}
+
+/// Reports how long deriving the key of `encrypted`, an age file with an scrypt stanza, is
+/// estimated to take.
+fn report_unlock(callback: &mut Option<Box<dyn FnMut(KeyringPhase) + Send>>, encrypted: &[u8]) {
+    if let (Some(callback), Some(work_factor)) = (callback, scrypt_work_factor(encrypted)) {
+        callback(KeyringPhase::UnlockingKey {
+            estimated_ms: estimate_scrypt_duration(work_factor)
+                .as_millis()
+                .min(u64::MAX as u128) as u64,
+        });
+    }
+}
+
+fn unwrap_identity(
+    encrypted: &[u8],
+    passphrase: &SecretString,
+    max_work_factor: Option<u8>,
 ) -> Result<age::x25519::Identity, DecryptIdentityError> {
-    let decryptor = match age::Decryptor::new(encrypted.as_slice()) {
+    let decryptor = match age::Decryptor::new(encrypted) {
         Err(_) => {
             return Err(DecryptIdentityError::Other(anyhow!(
                 "Encrypted identity is not a valid age ciphertext. Your keyfile may be corrupt."
@@ -355,8 +1204,11 @@ fn try_decrypt_identity(
         },
     };
     let mut decrypted = vec![];
-    let mut reader = match decryptor.decrypt(&Secret::new(passphrase), None) {
-        Err(_) => return Err(DecryptIdentityError::WrongPassphrase),
+    let mut reader = match decryptor.decrypt(passphrase, max_work_factor) {
+        Err(age::DecryptError::ExcessiveWork { .. }) => {
+            return Err(DecryptIdentityError::UnlockTimedOut)
+        }
+        Err(_) => return Err(DecryptIdentityError::BadPassphrase),
         Ok(r) => r,
     };
     reader
@@ -367,10 +1219,644 @@ fn try_decrypt_identity(
         .map_err(|_| DecryptIdentityError::Other(anyhow!("Invalid secret key")))
 }
 
-fn compute_digest(public_key: &str) -> KeyDigest {
-    let mut digest = Sha256::default();
-    digest.update(public_key.as_bytes());
-    digest.finalize().to_vec().as_slice()[16..32]
-        .try_into()
+/// Reads log2(N) from the scrypt stanza of an age encrypted identity:
+/// -> scrypt <salt> <log2(N)>
+fn scrypt_work_factor(encrypted: &[u8]) -> Option<u8> {
+    encrypted
+        .split(|&b| b == b'\n')
+        .take_while(|line| !line.starts_with(b"---"))
+        .filter_map(|line| std::str::from_utf8(line).ok())
+        .find_map(|line| {
+            let mut args = line.strip_prefix("-> scrypt ")?.split(' ');
+            args.nth(1)?.parse().ok()
+        })
+}
+
+const CALIBRATION_WORK_FACTOR: u8 = 10;
+
+/// Extrapolates the duration of an scrypt derivation (r = 8, p = 1 as used by age) from a
+/// small calibration run that is measured once, on the first call.
+fn estimate_scrypt_duration(work_factor: u8) -> Duration {
+    static CALIBRATION: OnceLock<Duration> = OnceLock::new();
+    let calibration = *CALIBRATION.get_or_init(|| {
+        let params = scrypt::ScryptParams::new(CALIBRATION_WORK_FACTOR, 8, 1).unwrap();
+        let mut output = [0u8; 32];
+        let start = Instant::now();
+        let _ = scrypt::scrypt(b"calibration", b"calibration salt", &params, &mut output);
+        start.elapsed()
+    });
+    if work_factor <= CALIBRATION_WORK_FACTOR {
+        return calibration;
+    }
+    calibration
+        .checked_mul(1 << (work_factor - CALIBRATION_WORK_FACTOR).min(31))
+        .unwrap_or(Duration::MAX)
+}
+
+pub(crate) fn compute_digest(public_key: &str) -> KeyDigest {
+    RecipientDigest::of_public_key(public_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encrypt::Recipient,
+        fixtures::{test_keyring, test_recipient, FixtureFile, TEST_PUBLIC_KEY, TEST_SECRET_KEY},
+        parser::parse_header,
+        passphrase::StaticPassphrase,
+    };
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    fn recipient(generated: &GeneratedIdentity) -> Recipient {
+        generated.recipient.parse().unwrap()
+    }
+
+    fn file_to(recipients: Vec<Recipient>) -> Vec<u8> {
+        FixtureFile::image("{}", "image")
+            .recipients(recipients)
+            .build()
+    }
+
+    /// Decrypts `file` to the end, returns the identity that was used.
+    fn decrypt_file(
+        keyring: &mut Keyring,
+        file: &[u8],
+    ) -> std::result::Result<Option<IdentityInfo>, DecryptionError> {
+        let mut reader = file;
+        let (header, _) = parse_header(&mut reader).unwrap();
+        let (mut decrypted, identity) = keyring.decrypt(reader, &header.recipient_digests)?;
+        let mut payload = vec![];
+        decrypted.read_to_end(&mut payload).unwrap();
+        assert!(payload.ends_with(b"image"));
+        Ok(identity)
+    }
+
+    #[test]
+    fn reports_the_identity_that_decrypted() {
+        let second = generate_identity(Some("second".to_owned())).unwrap();
+        let mut keyring = test_keyring();
+        keyring
+            .import_key(second.identity.expose_secret(), second.label.clone())
+            .unwrap();
+        let file = file_to(vec![recipient(&second)]);
+        let identity = decrypt_file(&mut keyring, &file).unwrap().unwrap();
+        assert_eq!(identity.digest, second.digest);
+        assert_eq!(identity.label.as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn falls_back_to_an_unlocked_identity() {
+        let locked = generate_identity(None).unwrap();
+        let mut keyring = test_keyring();
+        keyring
+            .import_key_encrypted(locked.identity.expose_secret(), PASSPHRASE, None)
+            .unwrap();
+        // the locked identity comes first in the header
+        let file = file_to(vec![recipient(&locked), test_recipient()]);
+        let identity = decrypt_file(&mut keyring, &file).unwrap().unwrap();
+        assert_eq!(identity.digest, test_recipient().digest());
+        match keyring.can_decrypt(&[locked.digest, test_recipient().digest()]) {
+            MatchResult::Matched(identity) => {
+                assert_eq!(identity.digest, test_recipient().digest())
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn locked_identity_without_provider_is_reported() {
+        let locked = generate_identity(None).unwrap();
+        let mut keyring = Keyring::in_memory();
+        keyring
+            .import_key_encrypted(locked.identity.expose_secret(), PASSPHRASE, None)
+            .unwrap();
+        let file = file_to(vec![recipient(&locked)]);
+        assert!(matches!(
+            decrypt_file(&mut keyring, &file),
+            Err(DecryptionError::IdentityEncrypted(_))
+        ));
+    }
+
+    #[test]
+    fn tries_the_next_identity_when_one_fails() {
+        let other = generate_identity(None).unwrap();
+        let mut keyring = test_keyring();
+        keyring
+            .import_key(other.identity.expose_secret(), None)
+            .unwrap();
+        // a corrupted keyfile: the digest of one key with the secret key of another
+        keyring
+            .identities
+            .get_mut(&other.digest)
+            .unwrap()
+            .secret_key = SecretKey::Unencrypted(age::x25519::Identity::generate());
+        let file = file_to(vec![recipient(&other), test_recipient()]);
+        let identity = decrypt_file(&mut keyring, &file).unwrap().unwrap();
+        assert_eq!(identity.digest, test_recipient().digest());
+
+        let file = file_to(vec![recipient(&other)]);
+        assert!(matches!(
+            decrypt_file(&mut keyring, &file),
+            Err(DecryptionError::UnwrapFailed { identity }) if identity.digest == other.digest
+        ));
+    }
+
+    #[test]
+    fn unknown_recipients_are_no_such_key() {
+        let other = generate_identity(None).unwrap();
+        let file = file_to(vec![recipient(&other)]);
+        match decrypt_file(&mut test_keyring(), &file) {
+            Err(DecryptionError::NoSuchKey { recipients }) => {
+                assert_eq!(recipients, vec![other.digest])
+            }
+            other => panic!("{:?}", other.err()),
+        }
+        assert_eq!(
+            Keyring::in_memory().can_decrypt(&[test_recipient().digest()]),
+            MatchResult::NoMatch(vec![test_recipient().digest()])
+        );
+    }
+
+    #[test]
+    fn corrupted_chunks_fail_while_reading() {
+        let file = FixtureFile::image("{}", vec![0u8; 150_000]).build();
+        let mut reader = &file[..];
+        let (header, _) = parse_header(&mut reader).unwrap();
+        let header_len = file.len() - reader.len();
+        // the age header ends with the "--- <mac>" line
+        let mac_line = reader.windows(4).position(|w| w == b"\n---").unwrap() + 1;
+        let age_header_len =
+            mac_line + reader[mac_line..].iter().position(|&b| b == b'\n').unwrap() + 1;
+        let second_chunk = (age_header_len as u64 + AGE_CHUNK_SIZE + AGE_TAG_SIZE) as usize;
+        let mut corrupted = file.clone();
+        corrupted[header_len + second_chunk + 100] ^= 1;
+
+        let mut keyring = test_keyring();
+        let (mut decrypted, _) = keyring
+            .decrypt(&corrupted[header_len..], &header.recipient_digests)
+            .unwrap();
+        let mut payload = vec![];
+        let err = decrypted.read_to_end(&mut payload).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        match err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<DecryptionError>())
+        {
+            Some(DecryptionError::PayloadCorrupted {
+                decrypted_bytes,
+                encrypted_offset,
+            }) => {
+                assert_eq!(*decrypted_bytes, AGE_CHUNK_SIZE);
+                assert_eq!(*encrypted_offset, second_chunk as u64);
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(payload.len() as u64, AGE_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn decrypting_keeps_key_errors_typed() {
+        let other = generate_identity(None).unwrap();
+        let file = file_to(vec![recipient(&other)]);
+        let err =
+            crate::decrypt::decrypt_to_vec(&file[..], &mut test_keyring(), usize::MAX).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DecryptionError>(),
+            Some(DecryptionError::NoSuchKey { recipients }) if recipients == &vec![other.digest]
+        ));
+        let err = crate::decrypt::decrypt_from_reader(
+            io::Cursor::new(file),
+            None,
+            &mut test_keyring(),
+            std::env::temp_dir(),
+            Default::default(),
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<DecryptionError>(),
+            Some(DecryptionError::NoSuchKey { .. })
+        ));
+
+        let mut keyring = test_keyring();
+        keyring
+            .import_key(other.identity.expose_secret(), None)
+            .unwrap();
+        keyring
+            .identities
+            .get_mut(&other.digest)
+            .unwrap()
+            .secret_key = SecretKey::Unencrypted(age::x25519::Identity::generate());
+        let file = file_to(vec![recipient(&other)]);
+        let err = crate::decrypt::decrypt_to_vec(&file[..], &mut keyring, usize::MAX).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DecryptionError>(),
+            Some(DecryptionError::UnwrapFailed { identity }) if identity.digest == other.digest
+        ));
+    }
+
+    /// `encrypted` with the work factor in its scrypt stanza replaced. The header doesn't
+    /// authenticate any more, but age checks the work factor first.
+    fn with_work_factor(encrypted: &[u8], work_factor: u8) -> Vec<u8> {
+        let start = encrypted
+            .windows(10)
+            .position(|w| w == b"-> scrypt ")
+            .unwrap();
+        let end = start + encrypted[start..].iter().position(|&b| b == b'\n').unwrap();
+        let last_space = start
+            + encrypted[start..end]
+                .iter()
+                .rposition(|&b| b == b' ')
+                .unwrap();
+        [
+            &encrypted[..=last_space],
+            work_factor.to_string().as_bytes(),
+            &encrypted[end..],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn unlock_beyond_the_timeout_fails_early() {
+        let generated = generate_identity(None).unwrap();
+        let mut keyring = Keyring::in_memory();
+        keyring
+            .import_key_encrypted(generated.identity.expose_secret(), PASSPHRASE, None)
+            .unwrap();
+        match &mut keyring
+            .identities
+            .get_mut(&generated.digest)
+            .unwrap()
+            .secret_key
+        {
+            SecretKey::ScryptEncrypted(encrypted) => *encrypted = with_work_factor(encrypted, 40),
+            _ => panic!("the identity is locked"),
+        }
+        assert_eq!(
+            keyring
+                .unlock_estimate(&generated.digest)
+                .unwrap()
+                .work_factor,
+            40
+        );
+        let phases = Arc::new(Mutex::new(vec![]));
+        let reported = phases.clone();
+        keyring.set_phase_callback(Some(Box::new(move |phase| {
+            reported.lock().unwrap().push(phase)
+        })));
+        keyring.set_unlock_timeout(Some(Duration::from_millis(100)));
+
+        let start = Instant::now();
+        let result = keyring.decrypt_identity(&generated.digest, PASSPHRASE.to_owned());
+        assert!(
+            matches!(result, Err(DecryptIdentityError::UnlockTimedOut)),
+            "{:?}",
+            result
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+        match phases.lock().unwrap().as_slice() {
+            [KeyringPhase::UnlockingKey { estimated_ms }] => assert!(*estimated_ms > 100),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn unlock_within_the_timeout_reports_its_estimate() {
+        let generated = generate_identity(None).unwrap();
+        let mut keyring = Keyring::in_memory();
+        keyring
+            .import_key_encrypted(generated.identity.expose_secret(), PASSPHRASE, None)
+            .unwrap();
+        let phases = Arc::new(Mutex::new(vec![]));
+        let reported = phases.clone();
+        keyring.set_phase_callback(Some(Box::new(move |phase| {
+            reported.lock().unwrap().push(phase)
+        })));
+        keyring.set_unlock_timeout(Some(Duration::from_secs(600)));
+        keyring
+            .decrypt_identity(&generated.digest, PASSPHRASE.to_owned())
+            .unwrap();
+        assert!(keyring.unlock_estimate(&generated.digest).is_none());
+        assert_eq!(phases.lock().unwrap().len(), 1);
+    }
+
+    /// Gives the right passphrase and counts how often it was asked.
+    struct CountingProvider(Arc<Mutex<usize>>);
+
+    impl PassphraseProvider for CountingProvider {
+        fn get_passphrase(&mut self, _prompt: &str, _retry: bool) -> Result<SecretString> {
+            *self.0.lock().unwrap() += 1;
+            Ok(SecretString::new(PASSPHRASE.to_owned()))
+        }
+    }
+
+    #[test]
+    fn unlocked_identity_is_reused_until_locked() {
+        let generated = generate_identity(None).unwrap();
+        let mut keyring = Keyring::in_memory();
+        keyring
+            .import_key_encrypted(generated.identity.expose_secret(), PASSPHRASE, None)
+            .unwrap();
+        let prompts = Arc::new(Mutex::new(0));
+        keyring.set_passphrase_provider(Some(Box::new(CountingProvider(prompts.clone()))));
+        let file = file_to(vec![recipient(&generated)]);
+
+        decrypt_file(&mut keyring, &file).unwrap();
+        decrypt_file(&mut keyring, &file).unwrap();
+        assert_eq!(*prompts.lock().unwrap(), 1);
+
+        keyring.lock();
+        decrypt_file(&mut keyring, &file).unwrap();
+        assert_eq!(*prompts.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn encrypted_import_unlocks_with_its_passphrase_only() {
+        let generated = generate_identity(None).unwrap();
+        let mut keyring = Keyring::in_memory();
+        let digest = keyring
+            .import_key_encrypted(generated.identity.expose_secret(), PASSPHRASE, None)
+            .unwrap();
+        assert!(keyring.export_secret_key(&digest).is_err());
+        assert!(matches!(
+            keyring.decrypt_identity(&digest, "wrong".to_owned()),
+            Err(DecryptIdentityError::BadPassphrase)
+        ));
+        keyring
+            .decrypt_identity(&digest, PASSPHRASE.to_owned())
+            .unwrap();
+        assert_eq!(
+            keyring.export_secret_key(&digest).unwrap().expose_secret(),
+            generated.identity.expose_secret()
+        );
+    }
+
+    #[test]
+    fn changed_passphrase_replaces_the_old_one() {
+        let generated = generate_identity(None).unwrap();
+        let mut keyring = Keyring::in_memory();
+        let digest = keyring
+            .import_key_encrypted(generated.identity.expose_secret(), PASSPHRASE, None)
+            .unwrap();
+        assert!(matches!(
+            keyring.change_passphrase(&digest, "wrong".to_owned(), "new".to_owned()),
+            Err(DecryptIdentityError::BadPassphrase)
+        ));
+        keyring
+            .change_passphrase(&digest, PASSPHRASE.to_owned(), "new".to_owned())
+            .unwrap();
+        keyring.lock();
+        assert!(matches!(
+            keyring.decrypt_identity(&digest, PASSPHRASE.to_owned()),
+            Err(DecryptIdentityError::BadPassphrase)
+        ));
+        keyring.decrypt_identity(&digest, "new".to_owned()).unwrap();
+    }
+
+    #[test]
+    fn identities_lists_the_keys_with_their_digests_and_labels() {
+        let second = generate_identity(None).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let mut keyring = Keyring::load(dir.path()).unwrap();
+        keyring
+            .import_key(TEST_SECRET_KEY, Some("phone".to_owned()))
+            .unwrap();
+        keyring
+            .import_key(second.identity.expose_secret(), None)
+            .unwrap();
+
+        let keyring = Keyring::load(dir.path()).unwrap();
+        let identities = keyring.identities();
+        let labels: Vec<_> = identities
+            .iter()
+            .map(|i| i.label.clone().unwrap())
+            .collect();
+        let unlabeled = format!("Imported key {}", &second.recipient[4..12]);
+        assert_eq!(labels, [unlabeled.as_str(), "phone"]);
+        // the digests are those of the file header, and the fingerprints of the public keys
+        let mut reader = &file_to(vec![recipient(&second), test_recipient()])[..];
+        let (header, _) = parse_header(&mut reader).unwrap();
+        let digests: Vec<_> = identities.iter().map(|i| i.digest).collect();
+        assert_eq!(digests, header.recipient_digests);
+        assert_eq!(
+            identities[1].digest,
+            RecipientDigest::of_public_key(TEST_PUBLIC_KEY)
+        );
+        assert_eq!(identities[0].digest, second.digest);
+        for identity in &identities {
+            assert!(keyring.contains(&identity.digest));
+            assert!(identity.created.is_some());
+            assert!(!identity.encrypted);
+        }
+
+        // keyfiles written before the creation time was recorded
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let path = entry.unwrap().path();
+            let keyfile = std::fs::read_to_string(&path).unwrap();
+            let keyfile: Vec<_> = keyfile
+                .lines()
+                .filter(|l| !l.starts_with("created"))
+                .collect();
+            std::fs::write(&path, keyfile.join("\n")).unwrap();
+        }
+        let keyring = Keyring::load(dir.path()).unwrap();
+        assert!(keyring.identities().iter().all(|i| i.created.is_none()));
+        assert_eq!(keyring.identities().len(), 2);
+    }
+
+    #[test]
+    fn saved_keyrings_load_with_the_same_keys() {
+        let generated = generate_identity(None).unwrap();
+        let created = "2021-06-01T12:00:00Z".parse().unwrap();
+        let mut keyring = Keyring::in_memory();
+        let plain = keyring
+            .import_key_created(TEST_SECRET_KEY, Some("plain".to_owned()), Some(created))
+            .unwrap();
+        let encrypted = keyring
+            .import_key_encrypted(
+                generated.identity.expose_secret(),
+                PASSPHRASE,
+                Some("with passphrase".to_owned()),
+            )
+            .unwrap();
+        // unlocked identities are saved as they were stored, encrypted
+        keyring
+            .decrypt_identity(&encrypted, PASSPHRASE.to_owned())
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        keyring.save(dir.path()).unwrap();
+
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let keyfile = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+            assert!(!keyfile.contains(generated.identity.expose_secret()));
+        }
+        let mut loaded = Keyring::load(dir.path()).unwrap();
+        let identities = loaded.identities();
+        assert_eq!(identities.len(), 2);
+        assert_eq!(
+            identities[0],
+            IdentityInfo {
+                digest: plain,
+                label: Some("plain".to_owned()),
+                created: Some(created),
+                encrypted: false,
+            }
+        );
+        assert_eq!(identities[1].digest, encrypted);
+        assert_eq!(identities[1].label.as_deref(), Some("with passphrase"));
+        assert!(identities[1].encrypted);
+        // keyfiles keep the creation time to the second
+        let saved_created = keyring.identities()[1].created.unwrap();
+        assert_eq!(
+            identities[1].created.unwrap().timestamp(),
+            saved_created.timestamp()
+        );
+
+        assert_eq!(
+            loaded.export_secret_key(&plain).unwrap().expose_secret(),
+            TEST_SECRET_KEY
+        );
+        assert!(loaded.export_secret_key(&encrypted).is_err());
+        loaded
+            .decrypt_identity(&encrypted, PASSPHRASE.to_owned())
+            .unwrap();
+        assert_eq!(
+            loaded
+                .export_secret_key(&encrypted)
+                .unwrap()
+                .expose_secret(),
+            generated.identity.expose_secret()
+        );
+    }
+
+    /// The file tests/fixtures/`path`. The identity files there all hold TEST_SECRET_KEY,
+    /// the passphrase protected files use PASSPHRASE.
+    fn fixture(path: &str) -> Vec<u8> {
+        std::fs::read(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures")
+                .join(path),
+        )
         .unwrap()
+    }
+
+    #[test]
+    fn identity_files_with_crlf_are_imported() {
+        let mut keyring = Keyring::in_memory();
+        let digests = keyring
+            .import_age_identities(&fixture("identities/crlf.txt")[..])
+            .unwrap();
+        assert_eq!(digests, vec![test_recipient().digest()]);
+        let file = file_to(vec![test_recipient()]);
+        let identity = decrypt_file(&mut keyring, &file).unwrap().unwrap();
+        assert_eq!(identity.digest, test_recipient().digest());
+    }
+
+    #[test]
+    fn passphrase_protected_identity_files_are_imported() {
+        let mut keyring = Keyring::in_memory();
+        keyring.set_passphrase_provider(Some(Box::new(StaticPassphrase::new(PASSPHRASE))));
+        let digests = keyring
+            .import_age_identities(&fixture("identities/passphrase.age")[..])
+            .unwrap();
+        assert_eq!(digests, vec![test_recipient().digest()]);
+        let file = file_to(vec![test_recipient()]);
+        let identity = decrypt_file(&mut keyring, &file).unwrap().unwrap();
+        assert_eq!(identity.digest, test_recipient().digest());
+    }
+
+    #[test]
+    fn identity_files_need_their_passphrase() {
+        let encrypted = fixture("identities/passphrase.age");
+        let mut keyring = Keyring::in_memory();
+        assert!(keyring.import_age_identities(&encrypted[..]).is_err());
+        keyring.set_passphrase_provider(Some(Box::new(StaticPassphrase::new("wrong"))));
+        assert!(keyring.import_age_identities(&encrypted[..]).is_err());
+        assert!(keyring.identities().is_empty());
+    }
+
+    #[test]
+    fn exported_identities_are_imported_again() {
+        let exported = test_keyring()
+            .export_age_identity(&test_recipient().digest())
+            .unwrap();
+        assert!(exported.contains(&format!("# public key: {}\n", TEST_PUBLIC_KEY)));
+        assert!(exported.ends_with(&format!("{}\n", TEST_SECRET_KEY)));
+
+        let mut keyring = Keyring::in_memory();
+        let digests = keyring.import_age_identities(exported.as_bytes()).unwrap();
+        assert_eq!(digests, vec![test_recipient().digest()]);
+        assert!(matches!(
+            keyring.can_decrypt(&[test_recipient().digest()]),
+            MatchResult::Matched(_)
+        ));
+    }
+
+    /// Answers with `answers` in turn, then with a wrong passphrase, recording the retry flag
+    /// of every request.
+    struct Answers {
+        answers: Vec<&'static str>,
+        retries: Arc<Mutex<Vec<bool>>>,
+    }
+
+    impl PassphraseProvider for Answers {
+        fn get_passphrase(&mut self, _prompt: &str, retry: bool) -> Result<SecretString> {
+            let mut retries = self.retries.lock().unwrap();
+            let answer = self.answers.get(retries.len()).unwrap_or(&"wrong");
+            retries.push(retry);
+            Ok(SecretString::new(answer.to_string()))
+        }
+    }
+
+    /// Decrypts the passphrase protected image fixture, returns the retry flags of the
+    /// passphrase requests.
+    fn decrypt_with_answers(
+        answers: Vec<&'static str>,
+    ) -> (std::result::Result<(), DecryptionError>, Vec<bool>) {
+        let retries = Arc::new(Mutex::new(vec![]));
+        let mut keyring = Keyring::in_memory();
+        keyring.set_passphrase_provider(Some(Box::new(Answers {
+            answers,
+            retries: retries.clone(),
+        })));
+        let result = decrypt_file(&mut keyring, &fixture("files/passphrase_image"))
+            .map(|identity| assert!(identity.is_none()));
+        let retries = retries.lock().unwrap().clone();
+        (result, retries)
+    }
+
+    #[test]
+    fn passphrase_files_decrypt_with_the_right_passphrase() {
+        let (result, retries) = decrypt_with_answers(vec![PASSPHRASE]);
+        result.unwrap();
+        assert_eq!(retries, [false]);
+    }
+
+    #[test]
+    fn passphrase_files_ask_again_after_a_wrong_passphrase() {
+        let (result, retries) = decrypt_with_answers(vec!["wrong", PASSPHRASE]);
+        result.unwrap();
+        assert_eq!(retries, [false, true]);
+    }
+
+    #[test]
+    fn passphrase_files_fail_after_the_last_attempt() {
+        let (result, retries) = decrypt_with_answers(vec![]);
+        assert!(
+            matches!(result, Err(DecryptionError::BadPassphrase)),
+            "{:?}",
+            result.err()
+        );
+        assert_eq!(retries.len(), MAX_PASSPHRASE_ATTEMPTS);
+        assert!(retries[1..].iter().all(|&retry| retry));
+    }
+
+    #[test]
+    fn passphrase_files_need_a_provider() {
+        let mut keyring = Keyring::in_memory();
+        let result = decrypt_file(&mut keyring, &fixture("files/passphrase_image"));
+        assert!(matches!(result, Err(DecryptionError::Other(_))));
+    }
 }
@@ -1,8 +1,65 @@
+pub mod batch;
+pub mod budget;
+#[cfg(feature = "transcode")]
+mod burn_in;
+mod cancel;
+mod capabilities;
 pub mod decrypt;
+#[cfg(feature = "async")]
+mod decrypt_async;
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+pub mod decrypt_audio;
 mod decrypt_image;
-mod decrypt_video;
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+pub mod decrypt_video;
+pub mod encrypt;
+pub mod error;
+mod estimate;
+mod exif;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fingerprint;
+#[cfg(any(test, feature = "test-fixtures"))]
+pub mod fixtures;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+pub mod hash;
+#[cfg(feature = "video")]
+mod import;
+mod jsonl;
 pub mod key_qrcode;
 pub mod keyring;
+mod location;
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+mod mp4;
+mod output_path;
+pub mod packet;
 pub mod parser;
+pub mod passphrase;
+pub mod prelude;
+mod progress;
+mod recover;
+mod reencrypt;
+mod registry;
+mod resume;
+#[cfg(feature = "rust-mp4")]
+mod rust_mp4;
+mod scan;
+#[cfg(feature = "shamir")]
+mod shamir;
+mod sidecar;
+mod signing;
+mod space;
+#[cfg(feature = "thumbnail")]
+mod thumbnail;
+mod timestamp;
+mod transcode;
+mod verify;
+mod warning;
+#[cfg(feature = "watch")]
+mod watch;
 
+#[cfg(feature = "video")]
+pub use ac_ffmpeg;
+pub use capabilities::{capabilities, Capabilities, FileTypeSupport};
 pub use qrcode;
@@ -0,0 +1,82 @@
+//! The GPS location in the metadata, written into output files unless
+//! DecryptOptions::strip_location is set.
+
+use crate::warning::DecryptWarning;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Location {
+    /// Degrees, north is positive.
+    pub latitude: f64,
+    /// Degrees, east is positive.
+    pub longitude: f64,
+    /// Meters above sea level.
+    pub altitude: Option<f64>,
+}
+
+impl Location {
+    /// The location from the metadata's coordinates, None if it has none.
+    /// Coordinates out of range or only one of latitude and longitude give a warning.
+    pub(crate) fn from_metadata(
+        latitude: Option<f64>,
+        longitude: Option<f64>,
+        altitude: Option<f64>,
+    ) -> Result<Option<Location>, DecryptWarning> {
+        let (latitude, longitude) = match (latitude, longitude) {
+            (None, None) => return Ok(None),
+            (Some(latitude), Some(longitude)) => (latitude, longitude),
+            _ => {
+                return Err(DecryptWarning::InvalidLocation(
+                    "only one of latitude and longitude is set".to_owned(),
+                ))
+            }
+        };
+        if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+            return Err(DecryptWarning::InvalidLocation(format!(
+                "latitude {} and longitude {} are out of range",
+                latitude, longitude
+            )));
+        }
+        if let Some(altitude) = altitude.filter(|altitude| !altitude.is_finite()) {
+            return Err(DecryptWarning::InvalidLocation(format!(
+                "altitude {} is out of range",
+                altitude
+            )));
+        }
+        Ok(Some(Location {
+            latitude,
+            longitude,
+            altitude,
+        }))
+    }
+
+    /// The location as written to MP4 files, e.g. "+48.8584+002.2945+035.000/".
+    #[cfg(any(feature = "video", feature = "rust-mp4"))]
+    pub(crate) fn to_iso6709(self) -> String {
+        let mut iso6709 = format!("{:+08.4}{:+09.4}", self.latitude, self.longitude);
+        if let Some(altitude) = self.altitude {
+            iso6709.push_str(&format!("{:+08.3}", altitude));
+        }
+        iso6709.push('/');
+        iso6709
+    }
+}
+
+/// Clears the coordinates if `strip` is set or they aren't a valid location. Returns the
+/// warning about an invalid location.
+pub(crate) fn normalize(
+    latitude: &mut Option<f64>,
+    longitude: &mut Option<f64>,
+    altitude: &mut Option<f64>,
+    strip: bool,
+) -> Option<DecryptWarning> {
+    let warning = match Location::from_metadata(*latitude, *longitude, *altitude) {
+        Err(warning) if !strip => Some(warning),
+        _ => None,
+    };
+    if strip || warning.is_some() {
+        *latitude = None;
+        *longitude = None;
+        *altitude = None;
+    }
+    warning
+}
@@ -0,0 +1,672 @@
+use anyhow::{anyhow, bail, Context, Result};
+#[cfg(feature = "video")]
+use chrono::{DateTime, TimeZone, Utc};
+#[cfg(feature = "video")]
+use std::convert::TryFrom;
+use std::{
+    convert::TryInto,
+    fs::{self, File},
+    io::{copy, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// A top-level box of an ISO base media file.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TopLevelBox {
+    pub kind: [u8; 4],
+    pub offset: u64,
+    pub header_len: u64,
+    pub size: u64,
+}
+
+/// Lists the top-level boxes (ftyp, moov, mdat, ...) of an MP4 file without reading their contents.
+pub(crate) fn top_level_boxes(file: &mut (impl Read + Seek)) -> Result<Vec<TopLevelBox>> {
+    let file_len = file.seek(SeekFrom::End(0))?;
+    let mut boxes = Vec::new();
+    let mut offset = 0;
+    while offset < file_len {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)
+            .context("Truncated box header")?;
+        let mut size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let kind = [header[4], header[5], header[6], header[7]];
+        let mut header_len = 8;
+        if size == 1 {
+            let mut large_size = [0u8; 8];
+            file.read_exact(&mut large_size)
+                .context("Truncated box header")?;
+            size = u64::from_be_bytes(large_size);
+            header_len = 16;
+        } else if size == 0 {
+            size = file_len - offset;
+        }
+        let end = match offset.checked_add(size) {
+            Some(end) if size >= header_len && end <= file_len => end,
+            _ => bail!(
+                "Invalid size {} for box {} at offset {}",
+                size,
+                String::from_utf8_lossy(&kind),
+                offset
+            ),
+        };
+        boxes.push(TopLevelBox {
+            kind,
+            offset,
+            header_len,
+            size,
+        });
+        offset = end;
+    }
+    Ok(boxes)
+}
+
+/// Rewrites the MP4 file at `path` so that the moov box comes before mdat, allowing progressive
+/// playback. The file is rewritten through a temporary file next to it, which replaces the original
+/// once complete. `on_progress(done, total)` is called as the rewrite proceeds.
+/// Returns false without touching the file if moov already precedes mdat or the rewrite was cancelled.
+pub(crate) fn faststart(
+    path: &Path,
+    on_progress: &mut dyn FnMut(u64, u64),
+    cancel: &AtomicBool,
+) -> Result<bool> {
+    let mut input = BufReader::new(File::open(path)?);
+    let boxes = top_level_boxes(&mut input)?;
+    let moov = boxes
+        .iter()
+        .find(|b| &b.kind == b"moov")
+        .copied()
+        .ok_or_else(|| anyhow!("No moov box found"))?;
+    let first_mdat = match boxes.iter().find(|b| &b.kind == b"mdat") {
+        None => return Ok(false),
+        Some(mdat) => *mdat,
+    };
+    if moov.offset < first_mdat.offset {
+        return Ok(false);
+    }
+
+    let mut moov_data = vec![0; moov.size as usize];
+    input.seek(SeekFrom::Start(moov.offset))?;
+    input.read_exact(&mut moov_data)?;
+    // everything from the first mdat on moves back by the size of moov
+    shift_chunk_offsets(&mut moov_data[moov.header_len as usize..], moov.size)?;
+
+    let tmp_path = path.with_extension("faststart.tmp");
+    let result = write_reordered(
+        &mut input,
+        &boxes,
+        &moov,
+        &moov_data,
+        first_mdat.offset,
+        &tmp_path,
+        on_progress,
+        cancel,
+    );
+    match result {
+        Ok(true) => {
+            fs::rename(&tmp_path, path)?;
+            Ok(true)
+        }
+        other => {
+            let _ = fs::remove_file(&tmp_path);
+            other
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_reordered(
+    input: &mut BufReader<File>,
+    boxes: &[TopLevelBox],
+    moov: &TopLevelBox,
+    moov_data: &[u8],
+    insert_at: u64,
+    tmp_path: &Path,
+    on_progress: &mut dyn FnMut(u64, u64),
+    cancel: &AtomicBool,
+) -> Result<bool> {
+    let total: u64 = boxes.iter().map(|b| b.size).sum();
+    let mut done = 0;
+    let mut out = BufWriter::new(File::create(tmp_path)?);
+    for b in boxes {
+        if b.offset == insert_at {
+            out.write_all(moov_data)?;
+            done += moov.size;
+        }
+        if b.offset == moov.offset {
+            continue;
+        }
+        input.seek(SeekFrom::Start(b.offset))?;
+        let mut remaining = b.size;
+        while remaining > 0 {
+            if cancel.load(Ordering::Relaxed) {
+                return Ok(false);
+            }
+            let chunk = remaining.min(1 << 20);
+            copy(&mut input.by_ref().take(chunk), &mut out)?;
+            remaining -= chunk;
+            done += chunk;
+            on_progress(done, total);
+        }
+    }
+    out.flush()?;
+    Ok(true)
+}
+
+const CONTAINER_BOXES: [&[u8; 4]; 5] = [b"trak", b"mdia", b"minf", b"stbl", b"edts"];
+
+/// Adds `shift` to every chunk offset in the stco/co64 boxes found below `data`,
+/// which holds the children of a container box.
+fn shift_chunk_offsets(data: &mut [u8], shift: u64) -> Result<()> {
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let size =
+            u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let kind = [data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]];
+        if size < 8 || pos + size > data.len() {
+            bail!("Invalid box {} in moov", String::from_utf8_lossy(&kind));
+        }
+        let body = &mut data[pos + 8..pos + size];
+        if CONTAINER_BOXES.contains(&&kind) {
+            shift_chunk_offsets(body, shift)?;
+        } else if &kind == b"stco" || &kind == b"co64" {
+            let entry_size = if &kind == b"stco" { 4 } else { 8 };
+            if body.len() < 8 {
+                bail!("Truncated {} box", String::from_utf8_lossy(&kind));
+            }
+            let count = u32::from_be_bytes([body[4], body[5], body[6], body[7]]) as usize;
+            if body.len() < 8 + count * entry_size {
+                bail!("Truncated {} box", String::from_utf8_lossy(&kind));
+            }
+            for entry in body[8..8 + count * entry_size].chunks_exact_mut(entry_size) {
+                if entry_size == 4 {
+                    let offset =
+                        u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]) as u64 + shift;
+                    if offset > u32::MAX as u64 {
+                        bail!("Chunk offset overflows stco box");
+                    }
+                    entry.copy_from_slice(&(offset as u32).to_be_bytes());
+                } else {
+                    let mut bytes = [0u8; 8];
+                    bytes.copy_from_slice(entry);
+                    let offset = u64::from_be_bytes(bytes)
+                        .checked_add(shift)
+                        .ok_or_else(|| anyhow!("Chunk offset overflows co64 box"))?;
+                    entry.copy_from_slice(&offset.to_be_bytes());
+                }
+            }
+        }
+        pos += size;
+    }
+    Ok(())
+}
+
+/// Sets the display matrix in the tkhd box of track `track_id` so players rotate the video
+/// `rotation` degrees clockwise, the same matrix FFmpeg writes for the rotate metadata.
+/// The moov box is patched in place. Other rotations than multiples of 90 are ignored.
+#[cfg(feature = "rust-mp4")]
+pub(crate) fn set_rotation(
+    file: &mut (impl Read + Write + Seek),
+    track_id: u32,
+    rotation: u16,
+    width: u32,
+    height: u32,
+) -> Result<()> {
+    let (a, b, c, d, x, y): (i32, i32, i32, i32, u32, u32) = match rotation % 360 {
+        90 => (0, 1, -1, 0, height, 0),
+        180 => (-1, 0, 0, -1, width, height),
+        270 => (0, -1, 1, 0, 0, width),
+        _ => return Ok(()),
+    };
+    let moov = top_level_boxes(file)?
+        .into_iter()
+        .find(|b| &b.kind == b"moov")
+        .ok_or_else(|| anyhow!("No moov box found"))?;
+    let mut moov_data = vec![0; (moov.size - moov.header_len) as usize];
+    file.seek(SeekFrom::Start(moov.offset + moov.header_len))?;
+    file.read_exact(&mut moov_data)?;
+    let matrix_offset = find_tkhd_matrix(&moov_data, track_id)?
+        .ok_or_else(|| anyhow!("No tkhd box for track {}", track_id))?;
+    let mut matrix = Vec::with_capacity(36);
+    for value in [a << 16, b << 16, 0, c << 16, d << 16, 0] {
+        matrix.extend_from_slice(&value.to_be_bytes());
+    }
+    // the translation is 16.16 fixed point like the rest, w is 2.30
+    matrix.extend_from_slice(&(x << 16).to_be_bytes());
+    matrix.extend_from_slice(&(y << 16).to_be_bytes());
+    matrix.extend_from_slice(&(1u32 << 30).to_be_bytes());
+    file.seek(SeekFrom::Start(
+        moov.offset + moov.header_len + matrix_offset as u64,
+    ))?;
+    file.write_all(&matrix)?;
+    Ok(())
+}
+
+/// Offset of the matrix in the tkhd box of track `track_id`, within `moov_data`, the children
+/// of the moov box.
+#[cfg(feature = "rust-mp4")]
+fn find_tkhd_matrix(moov_data: &[u8], track_id: u32) -> Result<Option<usize>> {
+    let be_u32 = |data: &[u8], pos: usize| {
+        u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+    };
+    let mut pos = 0;
+    while pos + 8 <= moov_data.len() {
+        let size = be_u32(moov_data, pos) as usize;
+        if size < 8 || pos + size > moov_data.len() {
+            bail!("Invalid box in moov");
+        }
+        if &moov_data[pos + 4..pos + 8] == b"trak" {
+            // tkhd comes first in trak
+            let tkhd = pos + 8;
+            if tkhd + 8 <= pos + size && &moov_data[tkhd + 4..tkhd + 8] == b"tkhd" {
+                let tkhd_size = be_u32(moov_data, tkhd) as usize;
+                let version = moov_data.get(tkhd + 8).copied();
+                // full box header, then the times, track ID, reserved and duration
+                let (id_offset, fields_len) = match version {
+                    Some(0) => (12, 20),
+                    Some(1) => (20, 32),
+                    _ => bail!("Invalid tkhd box"),
+                };
+                // reserved, layer, alternate group, volume, reserved, then the matrix
+                let matrix = tkhd + 12 + fields_len + 16;
+                if matrix + 36 > tkhd + tkhd_size {
+                    bail!("Truncated tkhd box");
+                }
+                if be_u32(moov_data, tkhd + 8 + id_offset) == track_id {
+                    return Ok(Some(matrix));
+                }
+            }
+        }
+        pos += size;
+    }
+    Ok(None)
+}
+
+/// Appends a ©xyz box with `iso6709` to the udta box of the moov box, creating udta if needed.
+/// moov has to be the last box of the file, as Mp4Writer writes it.
+#[cfg(feature = "rust-mp4")]
+pub(crate) fn append_location(file: &mut (impl Read + Write + Seek), iso6709: &str) -> Result<()> {
+    let moov = top_level_boxes(file)?
+        .pop()
+        .filter(|b| &b.kind == b"moov" && b.header_len == 8)
+        .ok_or_else(|| anyhow!("moov is not the last box"))?;
+    let mut moov_data = vec![0; (moov.size - moov.header_len) as usize];
+    file.seek(SeekFrom::Start(moov.offset + moov.header_len))?;
+    file.read_exact(&mut moov_data)?;
+    // the string's length and language code, 0x15c7 like Android writes
+    let mut xyz = Vec::with_capacity(12 + iso6709.len());
+    xyz.extend_from_slice(&(12 + iso6709.len() as u32).to_be_bytes());
+    xyz.extend_from_slice(b"\xa9xyz");
+    xyz.extend_from_slice(&(iso6709.len() as u16).to_be_bytes());
+    xyz.extend_from_slice(&0x15c7u16.to_be_bytes());
+    xyz.extend_from_slice(iso6709.as_bytes());
+
+    let mut last_child = None;
+    let mut pos = 0;
+    while pos + 8 <= moov_data.len() {
+        let size = u32::from_be_bytes([
+            moov_data[pos],
+            moov_data[pos + 1],
+            moov_data[pos + 2],
+            moov_data[pos + 3],
+        ]) as usize;
+        if size < 8 || pos + size > moov_data.len() {
+            bail!("Invalid box in moov");
+        }
+        last_child = Some((pos, size));
+        pos += size;
+    }
+    let appended = match last_child {
+        // udta at the end of the file can grow in place
+        Some((udta, size)) if &moov_data[udta + 4..udta + 8] == b"udta" => {
+            file.seek(SeekFrom::Start(moov.offset + moov.header_len + udta as u64))?;
+            file.write_all(&((size + xyz.len()) as u32).to_be_bytes())?;
+            xyz
+        }
+        _ => {
+            let mut udta = Vec::with_capacity(8 + xyz.len());
+            udta.extend_from_slice(&(8 + xyz.len() as u32).to_be_bytes());
+            udta.extend_from_slice(b"udta");
+            udta.extend_from_slice(&xyz);
+            udta
+        }
+    };
+    file.seek(SeekFrom::Start(moov.offset))?;
+    file.write_all(&((moov.size as usize + appended.len()) as u32).to_be_bytes())?;
+    file.seek(SeekFrom::Start(moov.offset + moov.size))?;
+    file.write_all(&appended)?;
+    Ok(())
+}
+
+/// What encrypt::import_mp4() takes from the moov box, besides the streams FFmpeg reports.
+#[cfg(feature = "video")]
+#[derive(Debug, Default)]
+pub(crate) struct MovieInfo {
+    /// From the mvhd box, None if it is 0, as many tools leave it.
+    pub creation_time: Option<DateTime<Utc>>,
+    /// Clockwise, from the display matrix in the tkhd box of the first video track, rounded to
+    /// a multiple of 90 degrees.
+    pub rotation: Option<u16>,
+}
+
+/// Seconds from 1904-01-01, where MP4 times count from, to the Unix epoch.
+#[cfg(feature = "video")]
+const MP4_EPOCH_OFFSET: u64 = 2_082_844_800;
+
+#[cfg(feature = "video")]
+pub(crate) fn movie_info(file: &mut (impl Read + Seek)) -> Result<MovieInfo> {
+    let moov = top_level_boxes(file)?
+        .into_iter()
+        .find(|b| &b.kind == b"moov")
+        .ok_or_else(|| anyhow!("No moov box found"))?;
+    let mut moov_data = vec![0; (moov.size - moov.header_len) as usize];
+    file.seek(SeekFrom::Start(moov.offset + moov.header_len))?;
+    file.read_exact(&mut moov_data)?;
+    let mut info = MovieInfo::default();
+    for (kind, body) in child_boxes(&moov_data)? {
+        match &kind {
+            b"mvhd" => info.creation_time = mvhd_creation_time(body)?,
+            b"trak" if info.rotation.is_none() => info.rotation = video_track_rotation(body)?,
+            _ => {}
+        }
+    }
+    Ok(info)
+}
+
+/// The boxes in `data`, the children of a box, as their type and contents.
+fn child_boxes(data: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    let mut boxes = vec![];
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let mut size =
+            u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let kind = [data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]];
+        let mut header_len = 8;
+        if size == 1 {
+            let large_size = data
+                .get(pos + 8..pos + 16)
+                .ok_or_else(|| anyhow!("Truncated box header"))?;
+            size = u64::from_be_bytes(large_size.try_into().unwrap()) as usize;
+            header_len = 16;
+        } else if size == 0 {
+            size = data.len() - pos;
+        }
+        if size < header_len || size > data.len() - pos {
+            bail!("Invalid box {}", String::from_utf8_lossy(&kind));
+        }
+        boxes.push((kind, &data[pos + header_len..pos + size]));
+        pos += size;
+    }
+    Ok(boxes)
+}
+
+#[cfg(feature = "video")]
+fn mvhd_creation_time(mvhd: &[u8]) -> Result<Option<DateTime<Utc>>> {
+    let seconds = match mvhd.first().copied() {
+        Some(0) if mvhd.len() >= 8 => u32::from_be_bytes(mvhd[4..8].try_into().unwrap()) as u64,
+        Some(1) if mvhd.len() >= 12 => u64::from_be_bytes(mvhd[4..12].try_into().unwrap()),
+        _ => bail!("Invalid mvhd box"),
+    };
+    let unix_seconds = match seconds.checked_sub(MP4_EPOCH_OFFSET) {
+        Some(unix_seconds) if seconds != 0 => unix_seconds,
+        _ => return Ok(None),
+    };
+    Ok(i64::try_from(unix_seconds)
+        .ok()
+        .and_then(|s| Utc.timestamp_opt(s, 0).single()))
+}
+
+/// The rotation in the display matrix of `trak`, None if it isn't a video track.
+#[cfg(feature = "video")]
+fn video_track_rotation(trak: &[u8]) -> Result<Option<u16>> {
+    let children = child_boxes(trak)?;
+    let mut is_video = false;
+    for (kind, body) in &children {
+        if kind == b"mdia" {
+            for (kind, body) in child_boxes(body)? {
+                // full box header and pre_defined, then the handler type
+                if &kind == b"hdlr" && body.get(8..12) == Some(&b"vide"[..]) {
+                    is_video = true;
+                }
+            }
+        }
+    }
+    if !is_video {
+        return Ok(None);
+    }
+    let tkhd = match children.iter().find(|(kind, _)| kind == b"tkhd") {
+        Some((_, body)) => *body,
+        None => bail!("No tkhd box in video track"),
+    };
+    // after the full box header: the times, track ID, reserved and duration, then reserved,
+    // layer, alternate group, volume and reserved
+    let matrix = match tkhd.first().copied() {
+        Some(0) => 4 + 20 + 16,
+        Some(1) => 4 + 32 + 16,
+        _ => bail!("Invalid tkhd box"),
+    };
+    if tkhd.len() < matrix + 36 {
+        bail!("Truncated tkhd box");
+    }
+    let value = |i: usize| {
+        let pos = matrix + 4 * i;
+        i32::from_be_bytes(tkhd[pos..pos + 4].try_into().unwrap()) as f64
+    };
+    // a and b are the cosine and sine of the clockwise rotation, see set_rotation()
+    let degrees = value(1).atan2(value(0)).to_degrees();
+    let rotation = ((degrees / 90.0).round() as i32 * 90).rem_euclid(360);
+    Ok(Some(rotation as u16))
+}
+
+/// Checks the box structure of a finished MP4 without demuxing it, see
+/// DecryptOptions::check_output: it has to start with ftyp, have one moov and media data, and
+/// the moov box needs at least `min_tracks` tracks and a duration. Fragmented files, whose moov
+/// has an mvex box, have their duration in the fragments instead. The error says what is wrong.
+pub(crate) fn check_structure(file: &mut (impl Read + Seek), min_tracks: usize) -> Result<()> {
+    let boxes = top_level_boxes(file)?;
+    match boxes.first() {
+        Some(first) if &first.kind == b"ftyp" => {}
+        Some(first) => bail!(
+            "Starts with a {} box instead of ftyp",
+            String::from_utf8_lossy(&first.kind)
+        ),
+        None => bail!("The file is empty"),
+    }
+    let moovs: Vec<_> = boxes.iter().filter(|b| &b.kind == b"moov").collect();
+    let moov = match moovs[..] {
+        [moov] => *moov,
+        [] => bail!("No moov box"),
+        _ => bail!("More than one moov box"),
+    };
+    if !boxes.iter().any(|b| &b.kind == b"mdat") {
+        bail!("No mdat box");
+    }
+    let mut moov_data = vec![0; (moov.size - moov.header_len) as usize];
+    file.seek(SeekFrom::Start(moov.offset + moov.header_len))?;
+    file.read_exact(&mut moov_data)?;
+    let children = child_boxes(&moov_data)?;
+    let tracks = children.iter().filter(|(kind, _)| kind == b"trak").count();
+    if tracks < min_tracks {
+        bail!("{} tracks instead of {}", tracks, min_tracks);
+    }
+    let fragmented = children.iter().any(|(kind, _)| kind == b"mvex");
+    let mvhd = match children.iter().find(|(kind, _)| kind == b"mvhd") {
+        Some((_, body)) => *body,
+        None => bail!("No mvhd box"),
+    };
+    if !fragmented && mvhd_duration(mvhd)? == 0 {
+        bail!("The movie has no duration");
+    }
+    Ok(())
+}
+
+fn mvhd_duration(mvhd: &[u8]) -> Result<u64> {
+    // after the full box header: the creation and modification time and the timescale
+    match mvhd.first().copied() {
+        Some(0) if mvhd.len() >= 20 => {
+            Ok(u32::from_be_bytes(mvhd[16..20].try_into().unwrap()) as u64)
+        }
+        Some(1) if mvhd.len() >= 32 => Ok(u64::from_be_bytes(mvhd[24..32].try_into().unwrap())),
+        _ => bail!("Invalid mvhd box"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(body);
+        data
+    }
+
+    /// ftyp, then mdat holding `samples`, then a moov whose stco points at the samples.
+    fn moov_at_end(samples: &[u8]) -> Vec<u8> {
+        let ftyp = mp4_box(b"ftyp", b"isom\0\0\x02\0isom");
+        let mdat = mp4_box(b"mdat", samples);
+        let chunk_offset = (ftyp.len() + 8) as u32;
+        let mut stco_body = vec![0; 4];
+        stco_body.extend_from_slice(&1u32.to_be_bytes());
+        stco_body.extend_from_slice(&chunk_offset.to_be_bytes());
+        let stbl = mp4_box(b"stbl", &mp4_box(b"stco", &stco_body));
+        let trak = mp4_box(b"trak", &mp4_box(b"mdia", &mp4_box(b"minf", &stbl)));
+        let moov = mp4_box(b"moov", &trak);
+        [ftyp, mdat, moov].concat()
+    }
+
+    fn chunk_offset(file: &[u8]) -> u64 {
+        let stco = file
+            .windows(4)
+            .position(|w| w == b"stco")
+            .expect("the file has an stco box");
+        u32::from_be_bytes(file[stco + 12..stco + 16].try_into().unwrap()) as u64
+    }
+
+    #[test]
+    fn lists_top_level_boxes() {
+        let file = moov_at_end(b"samples");
+        let boxes = top_level_boxes(&mut Cursor::new(&file)).unwrap();
+        let kinds: Vec<_> = boxes.iter().map(|b| &b.kind).collect();
+        assert_eq!(kinds, [b"ftyp", b"mdat", b"moov"]);
+        assert_eq!(boxes.iter().map(|b| b.size).sum::<u64>(), file.len() as u64);
+    }
+
+    #[test]
+    fn rejects_box_sizes_past_the_end() {
+        let mut file = moov_at_end(b"samples");
+        file[16..20].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(top_level_boxes(&mut Cursor::new(&file)).is_err());
+    }
+
+    #[test]
+    fn rejects_box_sizes_overflowing_the_offset() {
+        let mut file = mp4_box(b"ftyp", b"isom\0\0\x02\0");
+        file.extend_from_slice(&1u32.to_be_bytes());
+        file.extend_from_slice(b"mdat");
+        file.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(top_level_boxes(&mut Cursor::new(&file)).is_err());
+    }
+
+    /// The children of the udta box in the last box of `file`, which has to be moov.
+    #[cfg(feature = "rust-mp4")]
+    fn udta_children(file: &[u8]) -> Vec<Vec<u8>> {
+        let boxes = top_level_boxes(&mut Cursor::new(file)).unwrap();
+        let moov = boxes.last().unwrap();
+        assert_eq!(&moov.kind, b"moov");
+        assert_eq!(moov.offset + moov.size, file.len() as u64);
+        let udta = file
+            .windows(4)
+            .rposition(|w| w == b"udta")
+            .expect("moov has a udta box")
+            - 4;
+        let udta_size = u32::from_be_bytes(file[udta..udta + 4].try_into().unwrap()) as usize;
+        assert_eq!(udta + udta_size, file.len());
+        let mut children = vec![];
+        let mut pos = udta + 8;
+        while pos < file.len() {
+            let size = u32::from_be_bytes(file[pos..pos + 4].try_into().unwrap()) as usize;
+            children.push(file[pos..pos + size].to_vec());
+            pos += size;
+        }
+        children
+    }
+
+    #[cfg(feature = "rust-mp4")]
+    #[test]
+    fn locations_are_appended_as_xyz_boxes() {
+        let location = "+48.8584+002.2945/";
+        let mut xyz = mp4_box(b"\xa9xyz", b"\x00\x12\x15\xc7");
+        xyz.extend_from_slice(location.as_bytes());
+        xyz[..4].copy_from_slice(&(xyz.len() as u32).to_be_bytes());
+
+        // moov without udta gets one
+        let original = moov_at_end(b"samples");
+        let mut file = Cursor::new(original.clone());
+        append_location(&mut file, location).unwrap();
+        let file = file.into_inner();
+        assert_eq!(udta_children(&file), [xyz.clone()]);
+        assert_eq!(file.len(), original.len() + 8 + xyz.len());
+        assert_eq!(chunk_offset(&file), chunk_offset(&original));
+
+        // a udta at the end of moov grows
+        let meta = mp4_box(b"meta", b"other tags");
+        let udta = mp4_box(b"udta", &meta);
+        let mut original = moov_at_end(b"samples");
+        let moov = top_level_boxes(&mut Cursor::new(&original))
+            .unwrap()
+            .pop()
+            .unwrap();
+        let moov_size = (moov.size as usize + udta.len()) as u32;
+        original[moov.offset as usize..][..4].copy_from_slice(&moov_size.to_be_bytes());
+        original.extend_from_slice(&udta);
+        let mut file = Cursor::new(original.clone());
+        append_location(&mut file, location).unwrap();
+        let file = file.into_inner();
+        assert_eq!(file.len(), original.len() + xyz.len());
+        assert_eq!(udta_children(&file), [meta, xyz]);
+    }
+
+    #[test]
+    fn faststart_moves_moov_before_mdat() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("video.mp4");
+        let samples = b"the samples of the only chunk";
+        fs::write(&path, moov_at_end(samples)).unwrap();
+
+        let mut last_progress = None;
+        let moved = faststart(
+            &path,
+            &mut |done, total| last_progress = Some((done, total)),
+            &AtomicBool::new(false),
+        )
+        .unwrap();
+        assert!(moved);
+        let (done, total) = last_progress.unwrap();
+        assert_eq!(done, total);
+
+        let file = fs::read(&path).unwrap();
+        let boxes = top_level_boxes(&mut Cursor::new(&file)).unwrap();
+        let kinds: Vec<_> = boxes.iter().map(|b| &b.kind).collect();
+        assert_eq!(kinds, [b"ftyp", b"moov", b"mdat"]);
+        let offset = chunk_offset(&file) as usize;
+        assert_eq!(&file[offset..offset + samples.len()], samples);
+
+        // already in order, nothing to do
+        assert!(!faststart(&path, &mut |_, _| {}, &AtomicBool::new(false)).unwrap());
+    }
+
+    #[test]
+    fn cancelled_faststart_leaves_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("video.mp4");
+        let original = moov_at_end(b"samples");
+        fs::write(&path, &original).unwrap();
+        assert!(!faststart(&path, &mut |_, _| {}, &AtomicBool::new(true)).unwrap());
+        assert_eq!(fs::read(&path).unwrap(), original);
+        assert!(!path.with_extension("faststart.tmp").exists());
+    }
+}
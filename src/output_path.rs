@@ -0,0 +1,1033 @@
+use crate::{
+    error::Error,
+    hash::{HashAlgo, HashDigest, Hasher, HashingReader},
+    timestamp::parse_timestamp,
+    warning::DecryptWarning,
+};
+use anyhow::{bail, Context, Result};
+use chrono::format::{Item, StrftimeItems};
+use log::{info, warn};
+use serde_json::{Map, Value};
+use std::{
+    collections::{BTreeSet, HashMap},
+    ffi::OsStr,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+#[cfg(windows)]
+use std::{ffi::OsString, path::Prefix};
+use unicode_normalization::UnicodeNormalization;
+
+/// Resolves the caller-provided output directory to an absolute path, so that a job
+/// writes to the same place even if the working directory changes before it runs.
+/// Existing directories are canonicalized, paths that don't exist yet are joined
+/// onto the current working directory as it is right now.
+pub(crate) fn absolutize_output_dir(path: PathBuf) -> Result<PathBuf> {
+    let resolved = match path.canonicalize() {
+        Ok(canonical) => canonical,
+        Err(_) if path.is_absolute() => path.clone(),
+        Err(_) => std::env::current_dir()
+            .context("Could not determine the current working directory")?
+            .join(&path),
+    };
+    if path.is_relative() {
+        info!(
+            "Relative output path {} resolved to {}",
+            path.display(),
+            resolved.display()
+        );
+    }
+    Ok(resolved)
+}
+
+/// Output names for a batch of files decrypted together. Within a batch, names are cut to
+/// second precision, except for recordings of a burst whose timestamps only differ below a
+/// second: just those files get the milliseconds of their timestamp appended. The result only depends on
+/// the set of timestamps in the batch, not on the order the jobs run in, as long as all jobs
+/// of the batch are built before the first one runs. Files with identical timestamps can't be
+/// told apart this way and still get the same name.
+#[derive(Debug, Clone, Default)]
+pub struct BatchNames {
+    // second precision file stem -> timestamps of the batch that have it
+    stems: Arc<Mutex<HashMap<String, BTreeSet<String>>>>,
+}
+
+impl BatchNames {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file to the batch. Building a job with DecryptOptions::batch_names set does this.
+    pub fn register(&self, timestamp: &str) {
+        self.stems
+            .lock()
+            .unwrap()
+            .entry(second_precision_stem(timestamp))
+            .or_default()
+            .insert(timestamp.to_owned());
+    }
+
+    /// Whether the name of the file with this timestamp gets its milliseconds, because other
+    /// files registered so far were recorded in the same second.
+    pub fn appends_milliseconds(&self, timestamp: &str) -> bool {
+        let collides = self
+            .stems
+            .lock()
+            .unwrap()
+            .get(&second_precision_stem(timestamp))
+            .is_some_and(|timestamps| timestamps.len() > 1);
+        collides && parse_timestamp(timestamp).is_ok()
+    }
+
+    /// The name the file with this timestamp will be written to, given the files registered so far.
+    pub fn file_name(&self, timestamp: &str, extension: &str) -> String {
+        let stem = second_precision_stem(timestamp);
+        match parse_timestamp(timestamp) {
+            Ok(t) if self.appends_milliseconds(timestamp) => {
+                format!("{}_{:03}.{}", stem, t.timestamp_subsec_millis(), extension)
+            }
+            _ => format!("{}.{}", stem, extension),
+        }
+    }
+}
+
+/// Where under the output directory files are written.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum SubdirectoryStrategy {
+    #[default]
+    Flat,
+    /// Subdirectories named after the recording date, given as a strftime format like
+    /// "%Y-%m-%d", or "%Y/%m" for nested ones. Files whose timestamp can't be parsed go
+    /// into "unknown-date".
+    ByDate { format: String },
+}
+
+const UNKNOWN_DATE_DIR: &str = "unknown-date";
+
+impl SubdirectoryStrategy {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if let SubdirectoryStrategy::ByDate { format } = self {
+            if StrftimeItems::new(format).any(|item| item == Item::Error) {
+                bail!("Invalid date format {} for subdirectories", format);
+            }
+        }
+        Ok(())
+    }
+
+    /// The subdirectory for a recording, relative to the output directory. Every component is
+    /// sanitized, so it can't point outside of it.
+    pub(crate) fn subdirectory(&self, timestamp: &str) -> PathBuf {
+        let format = match self {
+            SubdirectoryStrategy::Flat => return PathBuf::new(),
+            SubdirectoryStrategy::ByDate { format } => format,
+        };
+        let date = match parse_timestamp(timestamp) {
+            Ok(t) => t.format(format).to_string(),
+            Err(e) => {
+                warn!("Writing to {}: {}", UNKNOWN_DATE_DIR, e);
+                return PathBuf::from(UNKNOWN_DATE_DIR);
+            }
+        };
+        date.split(['/', '\\'])
+            .map(sanitize_file_name)
+            .filter(|component| !component.is_empty())
+            .collect()
+    }
+}
+
+/// Adds the subdirectory for a recording, see SubdirectoryStrategy::subdirectory(), to
+/// `out_path` and creates it.
+pub(crate) fn enter_subdirectory(out_path: &mut PathBuf, subdirectory: &Path) -> io::Result<()> {
+    if subdirectory.as_os_str().is_empty() {
+        return Ok(());
+    }
+    out_path.push(subdirectory);
+    fs::create_dir_all(out_path)
+}
+
+/// What to do when the output file already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overwrite {
+    /// Replace the existing file.
+    Replace,
+    /// Write to "name (1).ext", "name (2).ext", ... instead.
+    Rename,
+    /// Fail the job.
+    Fail,
+}
+
+/// Creates the output file, changing `path` to the name actually used with Overwrite::Rename.
+/// It is opened for reading too, so muxers can patch what they wrote.
+pub(crate) fn create_output_file(path: &mut PathBuf, overwrite: Overwrite) -> io::Result<File> {
+    let options = |create_new| {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        if create_new {
+            options.create_new(true);
+        } else {
+            options.create(true).truncate(true);
+        }
+        options
+    };
+    let create_new = |path: &PathBuf| options(true).open(path);
+    match overwrite {
+        Overwrite::Replace => options(false).open(&path),
+        Overwrite::Fail => create_new(path).map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            ),
+            _ => e,
+        }),
+        Overwrite::Rename => {
+            let mut candidate = path.clone();
+            let mut n = 0;
+            loop {
+                match create_new(&candidate) {
+                    Ok(file) => {
+                        if candidate != *path {
+                            info!(
+                                "{} already exists, writing to {}",
+                                path.display(),
+                                candidate.display()
+                            );
+                            *path = candidate;
+                        }
+                        return Ok(file);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                        n += 1;
+                        candidate = numbered_path(path, n);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+}
+
+/// "name (n).ext" for "name.ext", where Overwrite::Rename writes when the name is taken.
+pub(crate) fn numbered_path(path: &Path, n: usize) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    path.with_file_name(format!("{} ({}){}", stem, n, extension))
+}
+
+/// How output files are named. The extension is added to the name either way.
+#[derive(Clone, Default)]
+pub enum OutputNaming {
+    /// The recording's timestamp, see BatchNames for files with the same timestamp.
+    #[default]
+    Timestamp,
+    Template(NameTemplate),
+    /// Full control over the name. Names containing characters that aren't allowed in file
+    /// names are sanitized.
+    Callback(Arc<dyn Fn(&MediaInfo) -> String + Send + Sync>),
+}
+
+impl fmt::Debug for OutputNaming {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputNaming::Timestamp => write!(f, "Timestamp"),
+            OutputNaming::Template(template) => write!(f, "Template({:?})", template.source),
+            OutputNaming::Callback(_) => write!(f, "Callback"),
+        }
+    }
+}
+
+/// What is known about a file when its output is named.
+#[derive(Debug, Clone)]
+pub struct MediaInfo {
+    pub timestamp: String,
+    /// "video", "image" or "audio"
+    pub media_type: &'static str,
+    /// The video codec, the image format, or the audio codec for audio recordings.
+    pub codec: String,
+    /// Only known for videos.
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    /// See DecryptOptions::source_stem.
+    pub source_stem: Option<String>,
+    /// See Header::device_label().
+    pub device_label: Option<String>,
+    /// Metadata fields unknown to this version, see ImageMetadata::extra.
+    pub extra: Map<String, Value>,
+    pub extension: String,
+}
+
+/// A file name with placeholders, e.g. "VID_{timestamp}_{codec}". Known placeholders are
+/// {timestamp}, {type}, {codec}, {width}, {height}, {source_stem}, {device} and {extra.KEY}
+/// for the field KEY of MediaInfo::extra. Width and height are empty for images, {device} and
+/// {extra.KEY} are empty if the file doesn't have them. Extra fields that aren't strings are
+/// written as JSON.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTemplate {
+    source: String,
+    parts: Vec<TemplatePart>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    Timestamp,
+    Type,
+    Codec,
+    Width,
+    Height,
+    SourceStem,
+    Device,
+    Extra(String),
+}
+
+impl NameTemplate {
+    /// Fails for unknown placeholders and unmatched braces.
+    pub fn parse(template: &str) -> Result<NameTemplate> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(open) = rest.find(['{', '}']) {
+            if rest[open..].starts_with('}') {
+                bail!("Unmatched }} in output name template {}", template);
+            }
+            if open > 0 {
+                parts.push(TemplatePart::Literal(rest[..open].to_owned()));
+            }
+            let close = match rest[open..].find('}') {
+                Some(close) => open + close,
+                None => bail!("Unmatched {{ in output name template {}", template),
+            };
+            parts.push(match &rest[open + 1..close] {
+                "timestamp" => TemplatePart::Timestamp,
+                "type" => TemplatePart::Type,
+                "codec" => TemplatePart::Codec,
+                "width" => TemplatePart::Width,
+                "height" => TemplatePart::Height,
+                "source_stem" => TemplatePart::SourceStem,
+                "device" => TemplatePart::Device,
+                other if other.starts_with("extra.") => {
+                    TemplatePart::Extra(other["extra.".len()..].to_owned())
+                }
+                other => bail!("Unknown placeholder {{{}}} in output name template", other),
+            });
+            rest = &rest[close + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(rest.to_owned()));
+        }
+        Ok(NameTemplate {
+            source: template.to_owned(),
+            parts,
+        })
+    }
+
+    pub(crate) fn uses_source_stem(&self) -> bool {
+        self.parts.contains(&TemplatePart::SourceStem)
+    }
+
+    fn render(&self, info: &MediaInfo) -> String {
+        let optional = |n: Option<usize>| n.map(|n| n.to_string()).unwrap_or_default();
+        self.parts
+            .iter()
+            .map(|part| match part {
+                TemplatePart::Literal(literal) => literal.clone(),
+                TemplatePart::Timestamp => info.timestamp.clone(),
+                TemplatePart::Type => info.media_type.to_owned(),
+                TemplatePart::Codec => info.codec.clone(),
+                TemplatePart::Width => optional(info.width),
+                TemplatePart::Height => optional(info.height),
+                TemplatePart::SourceStem => info.source_stem.clone().unwrap_or_default(),
+                TemplatePart::Device => info.device_label.clone().unwrap_or_default(),
+                TemplatePart::Extra(key) => match info.extra.get(key) {
+                    Some(Value::String(value)) => value.clone(),
+                    Some(value) => value.to_string(),
+                    None => String::new(),
+                },
+            })
+            .collect()
+    }
+}
+
+/// An output file that hashes what is written to it, if a hash algorithm is given. As long as
+/// the file is written front to back, the hash is computed on the way. Writes anywhere else,
+/// like the MP4 muxer going back to fill in box sizes, make digest() read the whole file again.
+/// Writes are buffered as DecryptOptions::write_buffer_size says.
+pub(crate) struct OutputFile {
+    file: BufWriter<File>,
+    hasher: Option<Hasher>,
+    position: u64,
+    /// Length of the prefix of the file that went through the hasher.
+    hashed: u64,
+    rewritten: bool,
+    /// The partial output being continued, see DecryptOptions::resume_from.
+    resume: Option<ResumeCheck>,
+}
+
+/// A partial output whose first `len` bytes are compared with what is written instead of being
+/// overwritten.
+struct ResumeCheck {
+    path: PathBuf,
+    len: u64,
+}
+
+impl OutputFile {
+    pub(crate) fn new(file: File, digest_algo: Option<HashAlgo>, write_buffer_size: usize) -> Self {
+        OutputFile {
+            file: BufWriter::with_capacity(write_buffer_size, file),
+            hasher: digest_algo.map(|algo| algo.hasher()),
+            position: 0,
+            hashed: 0,
+            rewritten: false,
+            resume: None,
+        }
+    }
+
+    /// Continues `file`, the partial output at `path`, see resume::open_output(). Writes
+    /// within its current length only check that the bytes are the same.
+    pub(crate) fn resuming(
+        file: File,
+        path: PathBuf,
+        len: u64,
+        digest_algo: Option<HashAlgo>,
+        write_buffer_size: usize,
+    ) -> Self {
+        OutputFile {
+            resume: Some(ResumeCheck { path, len }),
+            ..OutputFile::new(file, digest_algo, write_buffer_size)
+        }
+    }
+
+    /// The length of the file while it is written front to back.
+    pub(crate) fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// The digest of the file at `path`, which this was written to, if a hash algorithm was
+    /// given. Flushes the file first. Fails if a resumed partial output is longer than what
+    /// was written.
+    pub(crate) fn digest(mut self, path: &Path) -> io::Result<Option<HashDigest>> {
+        self.file.flush()?;
+        if let Some(check) = &self.resume {
+            if self.position < check.len {
+                return Err(check.mismatch(format!(
+                    "it is longer than the decrypted output of {} bytes",
+                    self.position
+                )));
+            }
+        }
+        let hasher = match self.hasher {
+            None => return Ok(None),
+            Some(hasher) => hasher,
+        };
+        if !self.rewritten {
+            return Ok(Some(hasher.finalize()));
+        }
+        hash_file(path, hasher.algo()).map(Some)
+    }
+}
+
+/// Flushes the finished output at `path` to disk, see DecryptOptions::fsync_on_complete.
+pub(crate) fn sync_output(path: &Path) -> io::Result<()> {
+    OpenOptions::new().write(true).open(path)?.sync_all()
+}
+
+/// Removes the output at `path` when dropped after the job was cancelled, unless it can be
+/// resumed, see DecryptOptions::resumable. Created once the output is opened, so every way a
+/// cancelled job returns cleans up.
+pub(crate) struct RemoveIfCancelled<'a> {
+    path: PathBuf,
+    cancel: &'a AtomicBool,
+    keep: bool,
+}
+
+impl<'a> RemoveIfCancelled<'a> {
+    pub(crate) fn new(path: &Path, cancel: &'a AtomicBool, resumable: bool) -> Self {
+        RemoveIfCancelled {
+            path: path.to_path_buf(),
+            cancel,
+            keep: resumable,
+        }
+    }
+
+    /// The job completed, the output stays even if cancelled from now on.
+    pub(crate) fn keep(mut self) {
+        self.keep = true;
+    }
+}
+
+impl Drop for RemoveIfCancelled<'_> {
+    fn drop(&mut self) {
+        if !self.keep && self.cancel.load(Ordering::Relaxed) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                warn!("Error removing {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+impl ResumeCheck {
+    fn mismatch(&self, reason: String) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            crate::error::Error::PartialOutputMismatch {
+                path: self.path.clone(),
+                reason,
+            },
+        )
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = match &self.resume {
+            Some(check) if self.position < check.len => {
+                let n = buf.len().min((check.len - self.position) as usize);
+                let mut existing = vec![0; n];
+                self.file.flush()?;
+                self.file.get_mut().read_exact(&mut existing)?;
+                if let Some(i) = existing.iter().zip(buf).position(|(a, b)| a != b) {
+                    return Err(check.mismatch(format!(
+                        "it differs from the decrypted output at byte {}",
+                        self.position + i as u64
+                    )));
+                }
+                n
+            }
+            _ => self.file.write(buf)?,
+        };
+        if let Some(hasher) = &mut self.hasher {
+            if self.position == self.hashed && !self.rewritten {
+                hasher.update(&buf[..written]);
+                self.hashed += written as u64;
+            } else {
+                self.rewritten = true;
+            }
+        }
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// For patching the file after muxing. Reading doesn't touch the hash.
+impl Read for OutputFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.flush()?;
+        let read = self.file.get_mut().read(buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for OutputFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = self.file.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+/// Hashes a whole file, e.g. the output after faststart rewrote it.
+pub(crate) fn hash_file(path: &Path, algo: HashAlgo) -> io::Result<HashDigest> {
+    let mut reader = HashingReader::new(BufReader::new(File::open(path)?), algo);
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.finalize().1)
+}
+
+/// The output file name for a recording. With the default naming, it is kept apart from the
+/// rest of its batch if there is one.
+pub(crate) fn output_file_name(
+    info: &MediaInfo,
+    naming: &OutputNaming,
+    batch_names: Option<&BatchNames>,
+) -> String {
+    let extension = &sanitize_extension(&info.extension);
+    let stem = match naming {
+        OutputNaming::Timestamp => {
+            return match batch_names {
+                Some(batch_names) => batch_names.file_name(&info.timestamp, extension),
+                None => format!("{}.{}", file_stem(&info.timestamp), extension),
+            }
+        }
+        OutputNaming::Template(template) => template.render(info),
+        OutputNaming::Callback(callback) => callback(info),
+    };
+    let stem = sanitize_file_name(&stem);
+    if stem.is_empty() {
+        warn!(
+            "Output name for {} is empty, using the timestamp",
+            info.timestamp
+        );
+        return format!("{}.{}", file_stem(&info.timestamp), extension);
+    }
+    format!("{}.{}", stem, extension)
+}
+
+/// DecryptWarning::MillisecondsAppended if output_file_name() names the file with the
+/// milliseconds of its timestamp, see BatchNames.
+pub(crate) fn batch_name_warning(
+    info: &MediaInfo,
+    naming: &OutputNaming,
+    batch_names: Option<&BatchNames>,
+) -> Option<DecryptWarning> {
+    match (naming, batch_names) {
+        (OutputNaming::Timestamp, Some(batch_names))
+            if batch_names.appends_milliseconds(&info.timestamp) =>
+        {
+            Some(DecryptWarning::MillisecondsAppended {
+                timestamp: info.timestamp.clone(),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The output file name for a payload written as it is, see
+/// DecryptOptions::recover_raw_on_bad_metadata. Without a source stem, it is "recovered.bin".
+pub(crate) fn recovered_file_name(source_stem: Option<&str>) -> String {
+    let stem = source_stem.map(sanitize_file_name).unwrap_or_default();
+    if stem.is_empty() {
+        return "recovered.bin".to_owned();
+    }
+    format!("{}.bin", stem)
+}
+
+/// Makes sure `path`, the output file about to be created, is directly in `out_dir`, or below
+/// it with `subdirectories`, so metadata can't make a job write anywhere else. Compares the
+/// paths with symlinks and ".." resolved, and fails if they can't be resolved, e.g. because a
+/// directory doesn't exist, rather than letting the path through unchecked.
+pub(crate) fn check_output_path(out_dir: &Path, path: &Path, subdirectories: bool) -> Result<()> {
+    let unsafe_path = || Error::UnsafeOutputPath {
+        path: path.to_path_buf(),
+    };
+    let parent = match (path.parent(), path.components().next_back()) {
+        (Some(parent), Some(Component::Normal(_))) => parent,
+        _ => return Err(unsafe_path().into()),
+    };
+    let canonicalize = |dir: &Path| {
+        fs::canonicalize(dir)
+            .with_context(|| format!("Error resolving output directory {}", dir.display()))
+    };
+    let (parent, out_dir) = (canonicalize(parent)?, canonicalize(out_dir)?);
+    let inside = if subdirectories {
+        parent.starts_with(&out_dir)
+    } else {
+        parent == out_dir
+    };
+    if !inside {
+        return Err(unsafe_path().into());
+    }
+    Ok(())
+}
+
+/// The longest path Windows opens without the `\\?\` prefix, including the terminating null.
+#[cfg(windows)]
+const MAX_PATH: usize = 260;
+/// The longest path that can be opened at all, in UTF-16 units on Windows and bytes elsewhere.
+#[cfg(windows)]
+const PATH_LIMIT: usize = 32_767;
+#[cfg(target_os = "macos")]
+const PATH_LIMIT: usize = 1024;
+#[cfg(not(any(windows, target_os = "macos")))]
+const PATH_LIMIT: usize = 4096;
+/// The longest file or directory name NTFS, APFS and the common Linux filesystems take.
+const NAME_LIMIT: usize = 255;
+
+/// Makes sure the output file `file_name` in `subdirectory` of `out_dir` can be created. On
+/// Windows, `out_dir` is changed to its `\\?\` form if the path would be longer than MAX_PATH,
+/// so the file can still be created, renamed and have its modification time set. Fails with
+/// Error::PathTooLong if the path is too long even then, or one of its names is.
+pub(crate) fn fit_path_length(
+    out_dir: &mut PathBuf,
+    subdirectory: &Path,
+    file_name: &str,
+) -> Result<(), Error> {
+    let path = out_dir.join(subdirectory).join(file_name);
+    let long_name = path.components().any(|component| match component {
+        Component::Normal(name) => os_len(name) > NAME_LIMIT,
+        _ => false,
+    });
+    if long_name {
+        return Err(Error::PathTooLong {
+            path,
+            limit: NAME_LIMIT,
+        });
+    }
+    #[cfg(windows)]
+    if os_len(path.as_os_str()) >= MAX_PATH {
+        *out_dir = extended_length_path(out_dir);
+    }
+    let path = out_dir.join(subdirectory).join(file_name);
+    if os_len(path.as_os_str()) >= PATH_LIMIT {
+        return Err(Error::PathTooLong {
+            path,
+            limit: PATH_LIMIT,
+        });
+    }
+    Ok(())
+}
+
+/// The length the platform's path limits count in.
+#[cfg(windows)]
+fn os_len(s: &OsStr) -> usize {
+    use std::os::windows::ffi::OsStrExt;
+    s.encode_wide().count()
+}
+
+#[cfg(not(windows))]
+fn os_len(s: &OsStr) -> usize {
+    s.len()
+}
+
+/// `path`, which has to be absolute, with the `\\?\` prefix that lifts MAX_PATH. Windows
+/// takes such paths as they are, so "." and ".." are resolved here and "/" becomes "\".
+/// Paths that have the prefix already or aren't on a drive or share are returned unchanged.
+#[cfg(windows)]
+fn extended_length_path(path: &Path) -> PathBuf {
+    let mut components = path.components();
+    let mut extended = OsString::from(r"\\?\");
+    match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(letter) => extended.push(format!("{}:", letter as char)),
+            Prefix::UNC(server, share) => {
+                extended.push(r"UNC\");
+                extended.push(server);
+                extended.push(r"\");
+                extended.push(share);
+            }
+            _ => return path.to_path_buf(),
+        },
+        _ => return path.to_path_buf(),
+    }
+    let mut names: Vec<&OsStr> = vec![];
+    for component in components {
+        match component {
+            Component::Normal(name) => names.push(name),
+            Component::ParentDir => {
+                names.pop();
+            }
+            _ => {}
+        }
+    }
+    for name in names {
+        extended.push(r"\");
+        extended.push(name);
+    }
+    PathBuf::from(extended)
+}
+
+// try not tripping up windows with scary filenames
+fn file_stem(timestamp: &str) -> String {
+    sanitize_file_name(timestamp)
+}
+
+/// Names Windows opens as devices instead of files, in any case and with any extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Replaces path separators, null bytes and the other characters Windows doesn't allow in file
+/// names, and drops trailing dots and spaces, so the name can't be "." or "..". Reserved names
+/// like "NUL" get a "_" in front, on every platform since outputs may be copied to Windows.
+/// Names are normalized to NFC, so the same timestamp with decomposed characters doesn't end
+/// up as a second file that looks the same, which macOS would otherwise allow.
+fn sanitize_file_name(name: &str) -> String {
+    let sanitized: String = name
+        .nfc()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '-',
+            c if c.is_control() => '-',
+            c => c,
+        })
+        .collect();
+    let sanitized = sanitized.trim_end_matches(['.', ' ']);
+    let base = sanitized.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(base))
+    {
+        return format!("_{}", sanitized);
+    }
+    sanitized.to_owned()
+}
+
+/// The extension of images comes from their metadata, it gets sanitized like file names.
+pub(crate) fn sanitize_extension(extension: &str) -> String {
+    match sanitize_file_name(extension) {
+        sanitized if sanitized.is_empty() => "bin".to_owned(),
+        sanitized => sanitized,
+    }
+}
+
+fn second_precision_stem(timestamp: &str) -> String {
+    let stem = file_stem(timestamp);
+    let time_start = stem.find('T').unwrap_or(0);
+    match stem[time_start..].find('.') {
+        None => stem,
+        Some(dot) => {
+            let dot = time_start + dot;
+            let fraction_len = stem[dot + 1..]
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(stem.len() - dot - 1);
+            format!("{}{}", &stem[..dot], &stem[dot + 1 + fraction_len..])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_unsafe(result: Result<()>) -> bool {
+        matches!(
+            result.err().map(|e| e.downcast::<Error>()),
+            Some(Ok(Error::UnsafeOutputPath { .. }))
+        )
+    }
+
+    #[test]
+    fn output_paths_outside_of_the_output_directory_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_dir = dir.path().join("out");
+        fs::create_dir_all(out_dir.join("2021/06")).unwrap();
+        check_output_path(&out_dir, &out_dir.join("a.jpg"), false).unwrap();
+        check_output_path(&out_dir, &out_dir.join("2021/06/a.jpg"), true).unwrap();
+
+        assert!(is_unsafe(check_output_path(
+            &out_dir,
+            &out_dir.join("2021/06/a.jpg"),
+            false
+        )));
+        for path in [
+            "../a.jpg",
+            "2021/../../a.jpg",
+            "2021/06/../../../a.jpg",
+            "..",
+        ] {
+            assert!(
+                is_unsafe(check_output_path(&out_dir, &out_dir.join(path), true)),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_of_the_output_directory_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_dir = dir.path().join("out");
+        fs::create_dir(&out_dir).unwrap();
+        std::os::unix::fs::symlink(dir.path(), out_dir.join("link")).unwrap();
+        assert!(is_unsafe(check_output_path(
+            &out_dir,
+            &out_dir.join("link/a.jpg"),
+            true
+        )));
+    }
+
+    #[test]
+    fn unresolvable_output_paths_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_dir = dir.path().join("missing");
+        assert!(check_output_path(&out_dir, &out_dir.join("a.jpg"), false).is_err());
+        assert!(check_output_path(dir.path(), &dir.path().join("missing/a.jpg"), true).is_err());
+    }
+
+    #[test]
+    fn file_names_have_no_separators() {
+        for (name, sanitized) in [
+            ("../../.bashrc", "..-..-.bashrc"),
+            ("..\\..\\evil", "..-..-evil"),
+            ("2021-06-01T12:00:00", "2021-06-01T12-00-00"),
+            ("a\0b", "a-b"),
+            ("..", ""),
+            ("name. . ", "name"),
+        ] {
+            assert_eq!(sanitize_file_name(name), sanitized);
+        }
+        assert_eq!(sanitize_extension("../../.bashrc"), "..-..-.bashrc");
+        assert_eq!(sanitize_extension("/"), "-");
+        assert_eq!(sanitize_extension("."), "bin");
+    }
+
+    #[test]
+    fn reserved_names_get_a_prefix() {
+        for (name, sanitized) in [
+            ("NUL", "_NUL"),
+            ("con.png", "_con.png"),
+            ("Com1 .tar.gz", "_Com1 .tar.gz"),
+            ("lpt9.", "_lpt9"),
+            ("CONSOLE", "CONSOLE"),
+            ("NULL.png", "NULL.png"),
+            ("COM10", "COM10"),
+        ] {
+            assert_eq!(sanitize_file_name(name), sanitized);
+        }
+        assert_eq!(sanitize_extension("aux"), "_aux");
+    }
+
+    #[test]
+    fn decomposed_names_are_normalized() {
+        assert_eq!(sanitize_file_name("Cafe\u{301}"), "Caf\u{e9}");
+        assert_eq!(sanitize_file_name("Caf\u{e9}"), "Caf\u{e9}");
+    }
+
+    /// A directory in a new tempdir with a path at least `len` long.
+    fn deep_dir(len: usize) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut path = dir.path().to_path_buf();
+        while os_len(path.as_os_str()) < len {
+            path.push("d".repeat(50));
+        }
+        fs::create_dir_all(&path).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn outputs_can_be_written_to_long_output_directories() {
+        let (_dir, mut out_dir) = deep_dir(300);
+        let timestamp = "2021-06-01T12:00:00Z";
+        let file_name = format!("{}.jpg", file_stem(timestamp));
+        fit_path_length(&mut out_dir, Path::new(""), &file_name).unwrap();
+        #[cfg(windows)]
+        assert!(out_dir.as_os_str().to_string_lossy().starts_with(r"\\?\"));
+
+        let temp = out_dir.join("partial");
+        fs::write(&temp, b"jpg").unwrap();
+        let path = out_dir.join(&file_name);
+        fs::rename(&temp, &path).unwrap();
+        crate::timestamp::set_mtime(&path, timestamp);
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        let expected: std::time::SystemTime = parse_timestamp(timestamp).unwrap().into();
+        assert_eq!(modified, expected);
+    }
+
+    #[test]
+    fn paths_too_long_to_open_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut out_dir = dir.path().to_path_buf();
+        let name = "a".repeat(NAME_LIMIT + 1);
+        match fit_path_length(&mut out_dir, Path::new(""), &name) {
+            Err(Error::PathTooLong { limit, .. }) => assert_eq!(limit, NAME_LIMIT),
+            result => panic!("{:?}", result),
+        }
+
+        let subdirectory: PathBuf = (0..PATH_LIMIT / 200 + 1).map(|_| "d".repeat(200)).collect();
+        match fit_path_length(&mut out_dir, &subdirectory, "a.jpg") {
+            Err(Error::PathTooLong { limit, .. }) => assert_eq!(limit, PATH_LIMIT),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    fn video_info() -> MediaInfo {
+        let mut extra = Map::new();
+        extra.insert("scene".to_owned(), Value::from("garden"));
+        extra.insert("take".to_owned(), Value::from(3));
+        MediaInfo {
+            timestamp: "2021-06-01T12:00:00Z".to_owned(),
+            media_type: "video",
+            codec: "h264".to_owned(),
+            width: Some(1920),
+            height: Some(1080),
+            source_stem: Some("VID_0001".to_owned()),
+            device_label: Some("Pixel".to_owned()),
+            extra,
+            extension: "mp4".to_owned(),
+        }
+    }
+
+    fn template_name(template: &str, info: &MediaInfo) -> String {
+        let naming = OutputNaming::Template(NameTemplate::parse(template).unwrap());
+        output_file_name(info, &naming, None)
+    }
+
+    #[test]
+    fn templates_fill_in_the_placeholders() {
+        let info = video_info();
+        for (template, name) in [
+            (
+                "{type}_{codec}_{width}x{height}",
+                "video_h264_1920x1080.mp4",
+            ),
+            ("{source_stem} from {device}", "VID_0001 from Pixel.mp4"),
+            (
+                "{extra.scene}-{extra.take}-{extra.missing}",
+                "garden-3-.mp4",
+            ),
+            ("{timestamp}", "2021-06-01T12-00-00Z.mp4"),
+            ("plain", "plain.mp4"),
+        ] {
+            assert_eq!(template_name(template, &info), name, "{}", template);
+        }
+
+        let image = MediaInfo {
+            media_type: "image",
+            codec: "jpg".to_owned(),
+            width: None,
+            height: None,
+            device_label: None,
+            extension: "jpg".to_owned(),
+            ..video_info()
+        };
+        assert_eq!(template_name("{type}{width}{device}", &image), "image.jpg");
+    }
+
+    #[test]
+    fn invalid_templates_are_refused() {
+        let e = NameTemplate::parse("{timestamp}_{resolution}").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Unknown placeholder {resolution} in output name template"
+        );
+        for template in ["{timestamp", "timestamp}", "{{timestamp}}", "{}"] {
+            assert!(NameTemplate::parse(template).is_err(), "{}", template);
+        }
+        assert!(NameTemplate::parse("{source_stem}")
+            .unwrap()
+            .uses_source_stem());
+        assert!(!NameTemplate::parse("{timestamp}")
+            .unwrap()
+            .uses_source_stem());
+    }
+
+    #[test]
+    fn rendered_names_are_sanitized() {
+        let info = MediaInfo {
+            device_label: Some("../../home/user/.bashrc".to_owned()),
+            ..video_info()
+        };
+        assert_eq!(
+            template_name("{device}", &info),
+            "..-..-home-user-.bashrc.mp4"
+        );
+        assert_eq!(
+            template_name("{device}:{codec}?", &info),
+            "..-..-home-user-.bashrc-h264-.mp4"
+        );
+        // nothing left of the name, so it falls back to the timestamp
+        let info = MediaInfo {
+            device_label: None,
+            ..video_info()
+        };
+        assert_eq!(
+            template_name("{device}..", &info),
+            "2021-06-01T12-00-00Z.mp4"
+        );
+        let naming = OutputNaming::Callback(Arc::new(|_: &MediaInfo| "NUL".to_owned()));
+        assert_eq!(output_file_name(&info, &naming, None), "_NUL.mp4");
+    }
+}
@@ -0,0 +1,552 @@
+/*
+The decrypted payload of a video file is a sequence of packets, each with a 13 byte header:
+
+type    u8      1 = video, 2 = audio
+pts     u64 LE  presentation timestamp in microseconds
+length  u32 LE  length of the packet data that follows
+*/
+
+use bytes::{ByteOrder, LittleEndian};
+use std::io::{self, Read};
+use thiserror::Error;
+
+const PACKET_HEADER_LEN: usize = 13;
+/// Longer than any frame a phone's encoder produces, a longer packet is corrupt.
+/// The default of PacketReader::max_packet_len().
+pub const MAX_PACKET_LEN: usize = 64 << 20;
+/// The most the packet iterator allocates for a packet before its data has been read.
+const PREALLOCATE_LIMIT: usize = 1 << 20;
+/// A packet whose PTS is this far from the previous one is considered corrupt when resyncing.
+const MAX_PTS_JUMP_US: u64 = 3_600_000_000;
+/// While resyncing, a packet header is only accepted with a PTS this close to the last good one.
+const RESYNC_PTS_WINDOW_US: u64 = 10_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketKind {
+    Video,
+    Audio,
+    /// Written by a newer version of the app, skipped by the muxer.
+    Unknown(u8),
+}
+
+/// The header of a packet, see PacketReader::next_header().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketHeader {
+    pub kind: PacketKind,
+    pub pts_us: u64,
+    /// Length of the packet data.
+    pub length: usize,
+}
+
+#[cfg(any(
+    feature = "video",
+    feature = "rust-mp4",
+    feature = "test-fixtures",
+    test
+))]
+impl PacketHeader {
+    /// The header as written in the stream, see the top of this file.
+    pub(crate) fn to_bytes(self) -> [u8; PACKET_HEADER_LEN] {
+        let mut bytes = [0; PACKET_HEADER_LEN];
+        bytes[0] = match self.kind {
+            PacketKind::Video => 1,
+            PacketKind::Audio => 2,
+            PacketKind::Unknown(kind) => kind,
+        };
+        LittleEndian::write_u64(&mut bytes[1..9], self.pts_us);
+        LittleEndian::write_u32(&mut bytes[9..13], self.length as u32);
+        bytes
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CryptocamPacket {
+    pub kind: PacketKind,
+    pub pts_us: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Error)]
+pub enum PacketError {
+    /// The stream ended in the middle of a packet.
+    #[error("Video stream is truncated after {0} bytes")]
+    Truncated(u64),
+    #[error("Corrupt video packet after {offset} bytes: length {length}")]
+    TooLong { offset: u64, length: usize },
+    #[error("Error reading video stream: {0}")]
+    Io(#[from] io::Error),
+    /// The buffer passed to PacketReader::read_data() isn't as long as the packet. The packet
+    /// is still pending, read it again with a buffer of the right length.
+    #[error("Buffer of {got} bytes for a video packet of {expected} bytes")]
+    BufferLength { expected: usize, got: usize },
+}
+
+impl PacketError {
+    /// Whether the stream ended early or could not be read any further. A recording that was cut
+    /// off also fails to decrypt its last, incomplete chunk, which shows up as Io.
+    pub fn is_truncation(&self) -> bool {
+        matches!(self, PacketError::Truncated(_) | PacketError::Io(_))
+    }
+
+    /// For ProgressCallback::on_error(). A typed error the stream failed with, like
+    /// error::Error::IntegrityError when a chunk didn't authenticate, is passed on by itself.
+    #[cfg(any(feature = "video", feature = "rust-mp4"))]
+    pub(crate) fn into_job_error(self) -> Box<dyn std::error::Error + Send + Sync> {
+        match self {
+            PacketError::Io(e) => crate::error::read_error(e),
+            e => Box::new(e),
+        }
+    }
+}
+
+/// Bytes of a damaged stream that were skipped to find the next packet,
+/// see PacketReader::resync_on_error().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SkippedBytes {
+    /// Position in the stream where the damaged data starts.
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Reads the packets of a decrypted video payload. The iterator ends at the end of the stream
+/// or after the first error.
+pub struct PacketReader<R: Read> {
+    inner: R,
+    resync: bool,
+    max_packet_len: usize,
+    position: u64,
+    last_pts: Option<u64>,
+    skipped: Option<SkippedBytes>,
+    /// Header of the packet whose data hasn't been read yet.
+    pending: Option<PacketHeader>,
+    done: bool,
+}
+
+impl<R: Read> PacketReader<R> {
+    pub fn new(inner: R) -> Self {
+        PacketReader {
+            inner,
+            resync: false,
+            max_packet_len: MAX_PACKET_LEN,
+            position: 0,
+            last_pts: None,
+            skipped: None,
+            pending: None,
+            done: false,
+        }
+    }
+
+    /// When a packet header is implausible (unknown type, huge length, PTS far away from the
+    /// previous packet), skip ahead byte by byte to the next plausible header instead of failing.
+    /// The skipped bytes are available from take_skipped() afterwards.
+    pub fn resync_on_error(mut self, resync: bool) -> Self {
+        self.resync = resync;
+        self
+    }
+
+    /// Packets longer than this are corrupt and fail with PacketError::TooLong, or are skipped
+    /// when resyncing.
+    pub fn max_packet_len(mut self, max_packet_len: usize) -> Self {
+        self.max_packet_len = max_packet_len;
+        self
+    }
+
+    /// Number of bytes read from the stream so far.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// The bytes skipped while resyncing before the last packet, or before the end of the
+    /// stream. Cleared by the call.
+    pub fn take_skipped(&mut self) -> Option<SkippedBytes> {
+        self.skipped.take()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Reads the header of the next packet, leaving its data to read_data(). Data that wasn't
+    /// read is skipped. Together with read_data(), this reads packets without allocating a Vec
+    /// for each, e.g. straight into a buffer owned by a decoder. Returns None at the end of the
+    /// stream and after an error.
+    pub fn next_header(&mut self) -> Option<Result<PacketHeader, PacketError>> {
+        if self.done {
+            return None;
+        }
+        let result = self.skip_data().and_then(|()| self.read_header());
+        if !matches!(result, Ok(Some(_))) {
+            self.done = true;
+        }
+        result.transpose()
+    }
+
+    /// Reads the data of the packet whose header next_header() returned last into `buf`,
+    /// which must be exactly as long as the packet, or PacketError::BufferLength is returned.
+    pub fn read_data(&mut self, buf: &mut [u8]) -> Result<(), PacketError> {
+        let header = match self.pending.take() {
+            Some(header) => header,
+            None => return Ok(()),
+        };
+        if buf.len() != header.length {
+            let expected = header.length;
+            self.pending = Some(header);
+            return Err(PacketError::BufferLength {
+                expected,
+                got: buf.len(),
+            });
+        }
+        let read = read_to_fill(&mut self.inner, buf);
+        self.finish_data(header, read)
+    }
+
+    /// Like read_data(), into a Vec that grows with the data actually read, so that a corrupt
+    /// length in a stream that ends soon after doesn't allocate all of it up front.
+    fn read_data_to_vec(&mut self) -> Result<Vec<u8>, PacketError> {
+        let header = match self.pending.take() {
+            Some(header) => header,
+            None => return Ok(Vec::new()),
+        };
+        let mut data = Vec::with_capacity(header.length.min(PREALLOCATE_LIMIT));
+        let read = (&mut self.inner)
+            .take(header.length as u64)
+            .read_to_end(&mut data);
+        self.finish_data(header, read)?;
+        Ok(data)
+    }
+
+    /// Accounts for the `read` bytes of the data of the packet with `header`.
+    fn finish_data(
+        &mut self,
+        header: PacketHeader,
+        read: io::Result<usize>,
+    ) -> Result<(), PacketError> {
+        let read = match read {
+            Ok(read) => read,
+            Err(e) => {
+                self.done = true;
+                return Err(e.into());
+            }
+        };
+        self.position += read as u64;
+        if read < header.length {
+            self.done = true;
+            return Err(PacketError::Truncated(
+                self.position - (read + PACKET_HEADER_LEN) as u64,
+            ));
+        }
+        if !matches!(header.kind, PacketKind::Unknown(_)) {
+            self.last_pts = Some(header.pts_us);
+        }
+        Ok(())
+    }
+
+    fn skip_data(&mut self) -> Result<(), PacketError> {
+        let length = match &self.pending {
+            Some(header) => header.length as u64,
+            None => return Ok(()),
+        };
+        self.pending = None;
+        let skipped = io::copy(&mut (&mut self.inner).take(length), &mut io::sink())?;
+        self.position += skipped;
+        if skipped < length {
+            return Err(PacketError::Truncated(
+                self.position - skipped - PACKET_HEADER_LEN as u64,
+            ));
+        }
+        Ok(())
+    }
+
+    fn read_header(&mut self) -> Result<Option<PacketHeader>, PacketError> {
+        let mut header = [0; PACKET_HEADER_LEN];
+        let header_start = self.position;
+        match read_to_fill(&mut self.inner, &mut header)? {
+            0 => return Ok(None),
+            n if n < header.len() => {
+                self.position += n as u64;
+                return Err(PacketError::Truncated(header_start));
+            }
+            n => self.position += n as u64,
+        }
+        if self.resync
+            && !is_plausible_packet(&header, self.last_pts, MAX_PTS_JUMP_US, self.max_packet_len)
+        {
+            let (skipped, found) = resync(
+                &mut self.inner,
+                &mut header,
+                self.last_pts,
+                self.max_packet_len,
+            );
+            self.position += skipped;
+            self.skipped = Some(SkippedBytes {
+                offset: header_start,
+                // without a packet, the rest of the stream is skipped, header included
+                len: match found {
+                    Ok(false) => self.position - header_start,
+                    _ => skipped,
+                },
+            });
+            if !found? {
+                return Ok(None);
+            }
+        }
+        let kind = match header[0] {
+            1 => PacketKind::Video,
+            2 => PacketKind::Audio,
+            other => PacketKind::Unknown(other),
+        };
+        let pts_us = LittleEndian::read_u64(&header[1..9]);
+        let length = LittleEndian::read_u32(&header[9..13]) as usize;
+        if length > self.max_packet_len {
+            return Err(PacketError::TooLong {
+                offset: self.position - header.len() as u64,
+                length,
+            });
+        }
+        let header = PacketHeader {
+            kind,
+            pts_us,
+            length,
+        };
+        self.pending = Some(header);
+        Ok(Some(header))
+    }
+}
+
+impl<R: Read> Iterator for PacketReader<R> {
+    type Item = Result<CryptocamPacket, PacketError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = match self.next_header()? {
+            Ok(header) => header,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(self.read_data_to_vec().map(|data| CryptocamPacket {
+            kind: header.kind,
+            pts_us: header.pts_us,
+            data,
+        }))
+    }
+}
+
+fn is_plausible_packet(
+    header: &[u8; 13],
+    last_pts: Option<u64>,
+    max_pts_jump: u64,
+    max_packet_len: usize,
+) -> bool {
+    let pts = LittleEndian::read_u64(&header[1..9]);
+    let length = LittleEndian::read_u32(&header[9..13]) as usize;
+    matches!(header[0], 1 | 2)
+        && length <= max_packet_len
+        && last_pts.map_or(true, |last| pts.abs_diff(last) <= max_pts_jump)
+}
+
+/// Moves through the stream one byte at a time until `header` holds a plausible packet header.
+/// Returns the number of bytes skipped, and whether a header was found before the stream ended.
+fn resync(
+    data: &mut dyn Read,
+    header: &mut [u8; 13],
+    last_pts: Option<u64>,
+    max_packet_len: usize,
+) -> (u64, io::Result<bool>) {
+    let mut skipped = 0;
+    loop {
+        header.copy_within(1.., 0);
+        match read_to_fill(data, &mut header[12..]) {
+            Ok(0) => return (skipped, Ok(false)),
+            Ok(_) => skipped += 1,
+            Err(e) => return (skipped, Err(e)),
+        }
+        if is_plausible_packet(header, last_pts, RESYNC_PTS_WINDOW_US, max_packet_len) {
+            return (skipped, Ok(true));
+        }
+    }
+}
+
+/// Reads until `buf` is full or the input ends, returns how much was read.
+fn read_to_fill(data: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match data.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(kind: PacketKind, pts_us: u64, data: &[u8]) -> Vec<u8> {
+        let header = PacketHeader {
+            kind,
+            pts_us,
+            length: data.len(),
+        };
+        let mut packet = header.to_bytes().to_vec();
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    fn stream() -> Vec<u8> {
+        [
+            packet(PacketKind::Video, 0, b"frame 0"),
+            packet(PacketKind::Audio, 10_000, b"audio"),
+            packet(PacketKind::Unknown(7), 20_000, b"from a newer app"),
+            packet(PacketKind::Video, 33_333, b""),
+            packet(PacketKind::Video, 66_666, b"frame 2"),
+        ]
+        .concat()
+    }
+
+    fn summary(packet: &CryptocamPacket) -> (PacketKind, u64, &[u8]) {
+        (packet.kind, packet.pts_us, &packet.data)
+    }
+
+    #[test]
+    fn packets_are_read_in_order() {
+        let stream = stream();
+        let mut reader = PacketReader::new(&stream[..]);
+        let packets: Vec<_> = (&mut reader).map(Result::unwrap).collect();
+        let packets: Vec<_> = packets.iter().map(summary).collect();
+        assert_eq!(
+            packets,
+            [
+                (PacketKind::Video, 0, &b"frame 0"[..]),
+                (PacketKind::Audio, 10_000, &b"audio"[..]),
+                (PacketKind::Unknown(7), 20_000, &b"from a newer app"[..]),
+                (PacketKind::Video, 33_333, &b""[..]),
+                (PacketKind::Video, 66_666, &b"frame 2"[..]),
+            ]
+        );
+        assert_eq!(reader.position(), stream.len() as u64);
+        assert!(reader.next().is_none());
+        assert!(PacketReader::new(&b""[..]).next().is_none());
+    }
+
+    #[test]
+    fn truncation_is_the_last_item() {
+        let stream = stream();
+        let second_packet = 13 + 7;
+        // cut inside the header and inside the data of the second packet
+        for cut in [second_packet + 5, second_packet + 13 + 2] {
+            let mut reader = PacketReader::new(&stream[..cut]);
+            assert_eq!(reader.next().unwrap().unwrap().data, b"frame 0");
+            match reader.next() {
+                Some(Err(PacketError::Truncated(offset))) => {
+                    assert_eq!(offset, second_packet as u64)
+                }
+                item => panic!("{:?}", item),
+            }
+            assert!(reader.next().is_none());
+            assert_eq!(reader.position(), cut as u64);
+        }
+    }
+
+    #[test]
+    fn packets_longer_than_the_limit_are_corrupt() {
+        let stream = stream();
+        let mut reader = PacketReader::new(&stream[..]).max_packet_len(7);
+        assert_eq!(reader.next().unwrap().unwrap().data, b"frame 0");
+        assert_eq!(reader.next().unwrap().unwrap().data, b"audio");
+        match reader.next() {
+            Some(Err(PacketError::TooLong { offset, length })) => {
+                assert_eq!((offset, length), (13 + 7 + 13 + 5, 16))
+            }
+            item => panic!("{:?}", item),
+        }
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn data_is_read_into_buffers_of_its_length() {
+        let stream = stream();
+        let mut reader = PacketReader::new(&stream[..]);
+        let header = reader.next_header().unwrap().unwrap();
+        assert_eq!(header.length, 7);
+        let mut short = [0; 3];
+        match reader.read_data(&mut short) {
+            Err(PacketError::BufferLength { expected, got }) => {
+                assert_eq!((expected, got), (7, 3))
+            }
+            result => panic!("{:?}", result),
+        }
+        let mut buf = [0; 7];
+        reader.read_data(&mut buf).unwrap();
+        assert_eq!(&buf, b"frame 0");
+
+        // the data of packets whose data isn't read is skipped
+        assert_eq!(reader.next_header().unwrap().unwrap().pts_us, 10_000);
+        assert_eq!(reader.next_header().unwrap().unwrap().pts_us, 20_000);
+        assert_eq!(reader.next().unwrap().unwrap().pts_us, 33_333);
+    }
+
+    #[test]
+    fn resyncing_skips_damaged_data() {
+        let stream = [
+            packet(PacketKind::Video, 0, b"frame 0"),
+            vec![0xff; 20],
+            packet(PacketKind::Video, 33_333, b"frame 1"),
+        ]
+        .concat();
+        let mut reader = PacketReader::new(&stream[..]);
+        reader.next().unwrap().unwrap();
+        assert!(matches!(
+            reader.next(),
+            Some(Err(PacketError::TooLong { .. }))
+        ));
+
+        let mut reader = PacketReader::new(&stream[..]).resync_on_error(true);
+        reader.next().unwrap().unwrap();
+        assert_eq!(reader.take_skipped(), None);
+        let packet = reader.next().unwrap().unwrap();
+        assert_eq!(
+            summary(&packet),
+            (PacketKind::Video, 33_333, &b"frame 1"[..])
+        );
+        assert_eq!(
+            reader.take_skipped(),
+            Some(SkippedBytes {
+                offset: 13 + 7,
+                len: 20
+            })
+        );
+        assert!(reader.next().is_none());
+
+        // damage at the end of the stream ends it
+        let stream = [packet(PacketKind::Video, 0, b"frame 0"), vec![0xff; 20]].concat();
+        let mut reader = PacketReader::new(&stream[..]).resync_on_error(true);
+        reader.next().unwrap().unwrap();
+        assert!(reader.next().is_none());
+        assert_eq!(
+            reader.take_skipped(),
+            Some(SkippedBytes {
+                offset: 20,
+                len: 20
+            })
+        );
+    }
+
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "chunk failed"))
+        }
+    }
+
+    #[test]
+    fn read_errors_end_the_stream() {
+        let mut reader = PacketReader::new(FailingReader);
+        match reader.next() {
+            Some(Err(e)) => assert!(matches!(e, PacketError::Io(_)) && e.is_truncation()),
+            item => panic!("{:?}", item),
+        }
+        assert!(reader.next().is_none());
+    }
+}
@@ -1,45 +1,480 @@
+/*
+The unencrypted header at the start of a Cryptocam file:
+
+magic          4 bytes  1c 5a 8e 9f
+version        u16 LE   1 or 2
+recipients     u8       number of recipient digests
+digests        16 bytes each, see fingerprint::RecipientDigest
+-- version 2 only --
+extensions_len u16 LE   length of the extension records that follow
+extensions     records of: tag u8, length u16 LE, length bytes of data
+
+Readers keep extension records they don't know, so new fields can be added to version 2
+headers without breaking older readers. A malformed record ends the extensions, the ones
+before it are kept. The age encrypted data follows the header.
+
+Headers that can't be read fail with one of these error::Error variants:
+
+NotACryptocamFile       the magic is wrong, also for input shorter than the magic that doesn't
+                        start like it
+TruncatedHeader         the input ends inside the header, including input shorter than the
+                        magic that starts like it and empty input
+UnsupportedVersion      a version other than 1 or 2
+MalformedRecipientList  the same digest is listed twice
+
+Known extension tags:
+1  device label, the UTF-8 name the user gave the camera
+*/
+
 use anyhow::{bail, Result};
 use bytes::{ByteOrder, LittleEndian};
-use std::io::Read;
+use log::warn;
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    io::{self, Read, Write},
+};
 
-use crate::keyring::KeyDigest;
+use crate::{
+    budget::{Reservation, Resource, ResourceBudget},
+    error::Error,
+    fingerprint::RecipientDigest,
+};
 
-#[derive(Debug)]
-pub struct CryptocamFileHeader {
+pub(crate) const MAGIC: [u8; 4] = [0x1c, 0x5a, 0x8e, 0x9f];
+
+/// Tag of the header extension holding the device label, see Header::device_label().
+pub const EXTENSION_DEVICE_LABEL: u8 = 1;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Header {
     pub version: u16,
-    pub recipient_digests: Vec<KeyDigest>,
+    pub recipient_digests: Vec<RecipientDigest>,
+    /// Always empty for version 1 headers.
+    pub extensions: Vec<HeaderExtension>,
+    /// Number of bytes the header takes up at the start of the file.
+    pub header_len: u64,
+}
+
+impl Header {
+    /// The data of the first extension record with this tag.
+    pub fn extension(&self, tag: u8) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|extension| extension.tag == tag)
+            .map(|extension| extension.data.as_slice())
+    }
+
+    /// The name the user gave the camera that recorded the file, if the header has one.
+    /// Invalid UTF-8 is replaced, an empty label counts as none.
+    pub fn device_label(&self) -> Option<String> {
+        match self.extension(EXTENSION_DEVICE_LABEL) {
+            Some(data) if !data.is_empty() => Some(String::from_utf8_lossy(data).into_owned()),
+            _ => None,
+        }
+    }
+}
+
+/// The name of Header before version 2 headers were supported.
+pub type CryptocamFileHeader = Header;
+
+/// An extension record of a version 2 header.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HeaderExtension {
+    pub tag: u8,
+    pub data: Vec<u8>,
+}
+
+/// The header layouts this parser understands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderVersion {
+    V1,
+    V2,
+}
+
+impl HeaderVersion {
+    /// Every version parse_header() reads.
+    pub const ALL: [HeaderVersion; 2] = [HeaderVersion::V1, HeaderVersion::V2];
+
+    /// The version as written in the header.
+    pub fn number(self) -> u16 {
+        match self {
+            HeaderVersion::V1 => 1,
+            HeaderVersion::V2 => 2,
+        }
+    }
+}
+
+impl TryFrom<u16> for HeaderVersion {
+    type Error = Error;
+
+    fn try_from(version: u16) -> Result<Self, Error> {
+        match version {
+            1 => Ok(HeaderVersion::V1),
+            2 => Ok(HeaderVersion::V2),
+            other => Err(Error::UnsupportedVersion(other)),
+        }
+    }
 }
 
 /// Parses the first (unencrypted) header of a cryptocam output file,
 /// which contains the public key digests of the file's recipients.
 /// Returns the parsed header and the number of bytes read from the reader
-pub fn parse_header(reader: &mut dyn Read) -> Result<(CryptocamFileHeader, u64)> {
+pub fn parse_header(reader: &mut dyn Read) -> Result<(Header, u64)> {
+    parse_header_within_budget(reader, None).map(|(header, read, _)| (header, read))
+}
+
+/// Like parse_header(), for the start of a file that is already in memory.
+/// Returns the parsed header and its length, the encrypted data starts at that offset.
+pub fn parse_header_from_slice(data: &[u8]) -> Result<(Header, usize)> {
+    let mut reader = data;
+    parse_header_within_budget(&mut reader, None).map(|(header, read, _)| (header, read as usize))
+}
+
+/// Like parse_header(), with the memory for the header reserved from `budget`.
+/// The reservations are released when the returned Reservations are dropped.
+pub(crate) fn parse_header_within_budget(
+    reader: &mut dyn Read,
+    budget: Option<&ResourceBudget>,
+) -> Result<(Header, u64, Vec<Reservation>)> {
     let mut header: [u8; 7] = [0; 7];
-    match reader.read_exact(&mut header) {
-        Err(_) => bail!("Not a Cryptocam file"),
-        _ => (),
-    };
-    if header[0..4] != [0x1c, 0x5a, 0x8e, 0x9f] {
-        bail!("Not a Cryptocam file");
+    let got = read_up_to(reader, &mut header)?;
+    let magic_len = got.min(MAGIC.len());
+    if header[..magic_len] != MAGIC[..magic_len] {
+        let mut found_magic = [0; 4];
+        found_magic.copy_from_slice(&header[..4]);
+        return Err(Error::NotACryptocamFile { found_magic }.into());
     }
+    let mut read: u64 = 0;
+    check_complete(&header, got, &mut read)?;
     let version: u16 = LittleEndian::read_u16(&header[4..6]);
+    let header_version = HeaderVersion::try_from(version)?;
     let num_recipients: u8 = header[6];
 
-    let mut read: u64 = header.len() as u64;
-    let mut recipient_digests: Vec<KeyDigest> = Vec::new();
-    let mut hash_buf: KeyDigest = [0; 16];
-    for _ in 0..num_recipients {
-        match reader.read_exact(&mut hash_buf) {
-            Err(_) => bail!("Not a Cryptocam file"),
-            _ => (),
+    let mut reservations = vec![];
+    if let Some(budget) = budget {
+        reservations.extend(budget.reserve(
+            Resource::HeaderBytes,
+            read + num_recipients as u64 * 16,
+            None,
+        )?);
+    }
+    let mut digests_buf = vec![0; num_recipients as usize * RecipientDigest::LEN];
+    let got = read_up_to(reader, &mut digests_buf)?;
+    check_complete(&digests_buf, got, &mut read)?;
+    let mut recipient_digests: Vec<RecipientDigest> = Vec::new();
+    let mut seen = HashSet::new();
+    for chunk in digests_buf.chunks_exact(RecipientDigest::LEN) {
+        let mut hash_buf = [0; RecipientDigest::LEN];
+        hash_buf.copy_from_slice(chunk);
+        let digest = RecipientDigest::from_bytes(hash_buf);
+        if !seen.insert(digest) {
+            return Err(Error::MalformedRecipientList {
+                reason: format!("{} is listed twice", digest.to_hex()),
+            }
+            .into());
         }
-        read += hash_buf.len() as u64;
-        recipient_digests.push(hash_buf.clone())
+        recipient_digests.push(digest)
     }
 
-    let cfh = CryptocamFileHeader {
+    let extensions = match header_version {
+        HeaderVersion::V1 => vec![],
+        HeaderVersion::V2 => {
+            let mut len_buf = [0; 2];
+            let got = read_up_to(reader, &mut len_buf)?;
+            check_complete(&len_buf, got, &mut read)?;
+            let extensions_len = LittleEndian::read_u16(&len_buf);
+            if let Some(budget) = budget {
+                reservations.extend(budget.reserve(
+                    Resource::HeaderBytes,
+                    extensions_len as u64,
+                    None,
+                )?);
+            }
+            let mut extensions_buf = vec![0; extensions_len as usize];
+            let got = read_up_to(reader, &mut extensions_buf)?;
+            check_complete(&extensions_buf, got, &mut read)?;
+            parse_extensions(&extensions_buf)
+        }
+    };
+
+    let cfh = Header {
         version,
         recipient_digests,
+        extensions,
+        header_len: read,
+    };
+    Ok((cfh, read, reservations))
+}
+
+/// Reads until `buf` is full or the input ends, returns how many bytes were read.
+fn read_up_to(reader: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Adds the `got` bytes read into `buf` to `read`, fails with Error::TruncatedHeader if that is
+/// less than all of `buf`.
+fn check_complete(buf: &[u8], got: usize, read: &mut u64) -> Result<(), Error> {
+    let expected = *read + buf.len() as u64;
+    *read += got as u64;
+    if got < buf.len() {
+        return Err(Error::TruncatedHeader {
+            expected,
+            got: *read,
+        });
+    }
+    Ok(())
+}
+
+/// Writes a version 1 header for the given recipients, the counterpart of parse_header().
+pub(crate) fn write_header(
+    out: &mut dyn Write,
+    recipient_digests: &[RecipientDigest],
+) -> Result<()> {
+    let num_recipients = match u8::try_from(recipient_digests.len()) {
+        Ok(0) | Err(_) => bail!(
+            "A file needs 1 to 255 recipients, not {}",
+            recipient_digests.len()
+        ),
+        Ok(n) => n,
     };
-    Ok((cfh, read))
+    let distinct: HashSet<&RecipientDigest> = recipient_digests.iter().collect();
+    if distinct.len() < recipient_digests.len() {
+        bail!("A file can't have the same recipient twice");
+    }
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.push(num_recipients);
+    for digest in recipient_digests {
+        header.extend_from_slice(digest.as_bytes());
+    }
+    out.write_all(&header)?;
+    Ok(())
+}
+
+fn parse_extensions(mut buf: &[u8]) -> Vec<HeaderExtension> {
+    let mut extensions = vec![];
+    while !buf.is_empty() {
+        let data = match buf.get(1..3) {
+            Some(len) => buf.get(3..3 + LittleEndian::read_u16(len) as usize),
+            None => None,
+        };
+        let data = match data {
+            Some(data) => data,
+            None => {
+                warn!(
+                    "Ignoring {} bytes of malformed header extensions",
+                    buf.len()
+                );
+                break;
+            }
+        };
+        let tag = buf[0];
+        let len = data.len();
+        extensions.push(HeaderExtension {
+            tag,
+            data: data.to_vec(),
+        });
+        buf = &buf[3 + len..];
+    }
+    extensions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::Path};
+
+    fn digest(n: u8) -> RecipientDigest {
+        RecipientDigest::from_bytes([n; 16])
+    }
+
+    fn header(version: u16, digests: &[RecipientDigest]) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&version.to_le_bytes());
+        header.push(digests.len() as u8);
+        for digest in digests {
+            header.extend_from_slice(digest.as_bytes());
+        }
+        header
+    }
+
+    fn v2_header(extensions: &[u8]) -> Vec<u8> {
+        let mut header = header(2, &[digest(1)]);
+        header.extend_from_slice(&(extensions.len() as u16).to_le_bytes());
+        header.extend_from_slice(extensions);
+        header
+    }
+
+    fn parse_error(data: &[u8]) -> Error {
+        parse_header_from_slice(data)
+            .unwrap_err()
+            .downcast()
+            .unwrap()
+    }
+
+    #[test]
+    fn written_headers_are_read_back() {
+        let digests = [digest(1), digest(2)];
+        let mut data = vec![];
+        write_header(&mut data, &digests).unwrap();
+        data.extend_from_slice(b"age-encryption.org/v1\n");
+
+        let (header, len) = parse_header_from_slice(&data).unwrap();
+        assert_eq!(len, 7 + 2 * RecipientDigest::LEN);
+        assert_eq!(header.version, 1);
+        assert_eq!(header.recipient_digests, digests);
+        assert!(header.extensions.is_empty());
+        assert_eq!(header.header_len, len as u64);
+        let (header, read) = parse_header(&mut &data[..]).unwrap();
+        assert_eq!(read, len as u64);
+        assert_eq!(header.recipient_digests, digests);
+    }
+
+    #[test]
+    fn v2_extensions_are_kept_known_or_not() {
+        let mut extensions = vec![EXTENSION_DEVICE_LABEL, 5, 0];
+        extensions.extend_from_slice(b"Phone");
+        extensions.extend_from_slice(&[200, 2, 0, 0xab, 0xcd]);
+        let mut data = v2_header(&extensions);
+        let header_len = data.len();
+        data.extend_from_slice(b"age-encryption.org/v1\n");
+
+        let (header, len) = parse_header_from_slice(&data).unwrap();
+        assert_eq!(len, header_len);
+        assert_eq!(header.version, 2);
+        assert_eq!(header.recipient_digests, [digest(1)]);
+        assert_eq!(header.extensions.len(), 2);
+        assert_eq!(header.device_label().as_deref(), Some("Phone"));
+        assert_eq!(header.extension(200), Some(&[0xab, 0xcd][..]));
+        assert_eq!(header.extension(3), None);
+    }
+
+    #[test]
+    fn malformed_extensions_end_the_list() {
+        // the second record claims more data than there is
+        let data = v2_header(&[7, 1, 0, 0x11, 8, 9, 0, 0x22]);
+        let (header, len) = parse_header_from_slice(&data).unwrap();
+        assert_eq!(len, data.len());
+        assert_eq!(
+            header.extensions,
+            [HeaderExtension {
+                tag: 7,
+                data: vec![0x11]
+            }]
+        );
+        // not even a whole tag and length
+        let (header, _) = parse_header_from_slice(&v2_header(&[7, 1])).unwrap();
+        assert!(header.extensions.is_empty());
+        let (header, _) = parse_header_from_slice(&v2_header(&[])).unwrap();
+        assert!(header.extensions.is_empty());
+        assert_eq!(header.device_label(), None);
+    }
+
+    #[test]
+    fn wrong_magic_is_not_a_cryptocam_file() {
+        for (data, found) in [
+            (&b"not a cryptocam file"[..], *b"not "),
+            (
+                &[0x1c, 0x5a, 0x8e, 0x00, 1, 0, 0][..],
+                [0x1c, 0x5a, 0x8e, 0],
+            ),
+            (&b"ab"[..], [b'a', b'b', 0, 0]),
+        ] {
+            match parse_error(data) {
+                Error::NotACryptocamFile { found_magic } => assert_eq!(found_magic, found),
+                e => panic!("{:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_headers_say_how_far_they_got() {
+        let v1 = header(1, &[digest(1), digest(2)]);
+        let v2 = v2_header(&[7, 1, 0, 0x11]);
+        for (data, expected_len, got_len) in [
+            (&[][..], 7, 0),
+            (&MAGIC[..2], 7, 2),
+            (&v1[..6], 7, 6),
+            (&v1[..30], 39, 30),
+            (&v2[..24], 25, 24),
+            (&v2[..27], 29, 27),
+        ] {
+            match parse_error(data) {
+                Error::TruncatedHeader { expected, got } => {
+                    assert_eq!((expected, got), (expected_len, got_len))
+                }
+                e => panic!("{:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn other_versions_are_unsupported() {
+        for version in [0, 3, u16::MAX] {
+            match parse_error(&header(version, &[digest(1)])) {
+                Error::UnsupportedVersion(v) => assert_eq!(v, version),
+                e => panic!("{:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn duplicate_digests_are_a_malformed_recipient_list() {
+        match parse_error(&header(1, &[digest(1), digest(2), digest(1)])) {
+            Error::MalformedRecipientList { reason } => {
+                assert!(reason.contains(&digest(1).to_hex()), "{}", reason)
+            }
+            e => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn corpus_headers() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus/header");
+        let parse = |name: &str| parse_header_from_slice(&fs::read(dir.join(name)).unwrap());
+        for (name, version, recipients, extensions) in [
+            ("v1_no_recipients", 1, 0, 0),
+            ("v1_one_recipient", 1, 1, 0),
+            ("v1_two_recipients", 1, 2, 0),
+            ("v2_no_extensions", 2, 1, 0),
+            ("v2_extensions", 2, 1, 2),
+            // the malformed record is dropped, the header is fine
+            ("invalid_v2_extension_overrun", 2, 1, 0),
+        ] {
+            let (header, _) = parse(name).unwrap();
+            assert_eq!(
+                (
+                    header.version,
+                    header.recipient_digests.len(),
+                    header.extensions.len()
+                ),
+                (version, recipients, extensions),
+                "{}",
+                name
+            );
+        }
+        for name in [
+            "invalid_duplicate_recipients",
+            "invalid_empty",
+            "invalid_garbage",
+            "invalid_magic",
+            "invalid_truncated_digest",
+            "invalid_truncated_magic",
+            "invalid_v2_truncated_extensions_len",
+            "invalid_version_3",
+        ] {
+            assert!(parse(name).is_err(), "{}", name);
+        }
+    }
 }
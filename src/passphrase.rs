@@ -0,0 +1,133 @@
+use crate::error::Error;
+use anyhow::{anyhow, Result};
+#[cfg(not(target_arch = "wasm32"))]
+use dialoguer::Password;
+use secrecy::{ExposeSecret, SecretString};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+/// Asks for the passphrase of an encrypted identity when Keyring::decrypt() needs to unlock it,
+/// see Keyring::set_passphrase_provider().
+/// `retry` is false on the first request for an identity and true after a wrong passphrase.
+/// To let the user cancel, return error::Error::Cancelled, which aborts the decryption with
+/// DecryptionError::Cancelled. Any other error aborts it with DecryptionError::Other.
+pub trait PassphraseProvider {
+    fn get_passphrase(&mut self, prompt: &str, retry: bool) -> Result<SecretString>;
+}
+
+/// Reads the passphrase from the terminal without echoing it.
+/// An empty passphrase cancels.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Default)]
+pub struct TerminalPassphrase;
+
+#[cfg(not(target_arch = "wasm32"))]
+impl PassphraseProvider for TerminalPassphrase {
+    fn get_passphrase(&mut self, prompt: &str, retry: bool) -> Result<SecretString> {
+        let prompt = if retry {
+            format!("Wrong passphrase. {}", prompt)
+        } else {
+            prompt.to_owned()
+        };
+        let passphrase = Password::new()
+            .with_prompt(prompt)
+            .allow_empty_password(true)
+            .interact()?;
+        if passphrase.is_empty() {
+            return Err(Error::Cancelled.into());
+        }
+        Ok(SecretString::new(passphrase))
+    }
+}
+
+/// Asks for the passphrase through pinentry, for programs without a terminal.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Default)]
+pub struct PinentryPassphrase;
+
+#[cfg(not(target_arch = "wasm32"))]
+impl PassphraseProvider for PinentryPassphrase {
+    fn get_passphrase(&mut self, prompt: &str, retry: bool) -> Result<SecretString> {
+        let mut input = pinentry::PassphraseInput::with_default_binary()
+            .ok_or_else(|| anyhow!("pinentry not found"))?;
+        input
+            .with_description(prompt)
+            .with_prompt("Passphrase:")
+            .required("A passphrase is required");
+        if retry {
+            input.with_error("Wrong passphrase");
+        }
+        match input.interact() {
+            Ok(passphrase) => Ok(passphrase),
+            Err(pinentry::Error::Cancelled) => Err(Error::Cancelled.into()),
+            Err(e) => Err(anyhow!("pinentry failed: {}", e)),
+        }
+    }
+}
+
+/// Always answers with the same passphrase, for tests and automation.
+/// Fails instead of answering again once the passphrase turned out to be wrong.
+pub struct StaticPassphrase(SecretString);
+
+impl StaticPassphrase {
+    pub fn new(passphrase: &str) -> Self {
+        StaticPassphrase(SecretString::new(passphrase.to_owned()))
+    }
+}
+
+impl PassphraseProvider for StaticPassphrase {
+    fn get_passphrase(&mut self, _prompt: &str, retry: bool) -> Result<SecretString> {
+        if retry {
+            return Err(anyhow!("Wrong passphrase"));
+        }
+        Ok(SecretString::new(self.0.expose_secret().clone()))
+    }
+}
+
+/// A request for a passphrase sent by a ChannelPassphrase.
+pub struct PassphraseRequest {
+    pub prompt: String,
+    pub retry: bool,
+    reply: Sender<Option<SecretString>>,
+}
+
+impl PassphraseRequest {
+    pub fn answer(self, passphrase: SecretString) {
+        let _ = self.reply.send(Some(passphrase));
+    }
+
+    /// Dropping the request without answering it cancels as well.
+    pub fn cancel(self) {
+        let _ = self.reply.send(None);
+    }
+}
+
+/// Hands passphrase requests to another thread, e.g. the UI thread of a GUI, which answers
+/// them through the Receiver returned by channel(). The decrypting thread waits for the answer.
+pub struct ChannelPassphrase {
+    requests: Sender<PassphraseRequest>,
+}
+
+impl ChannelPassphrase {
+    pub fn channel() -> (ChannelPassphrase, Receiver<PassphraseRequest>) {
+        let (requests, receiver) = mpsc::channel();
+        (ChannelPassphrase { requests }, receiver)
+    }
+}
+
+impl PassphraseProvider for ChannelPassphrase {
+    fn get_passphrase(&mut self, prompt: &str, retry: bool) -> Result<SecretString> {
+        let (reply, answer) = mpsc::channel();
+        let request = PassphraseRequest {
+            prompt: prompt.to_owned(),
+            retry,
+            reply,
+        };
+        if self.requests.send(request).is_err() {
+            return Err(Error::Cancelled.into());
+        }
+        match answer.recv() {
+            Ok(Some(passphrase)) => Ok(passphrase),
+            Ok(None) | Err(_) => Err(Error::Cancelled.into()),
+        }
+    }
+}
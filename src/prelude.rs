@@ -0,0 +1,58 @@
+//! The types needed for decrypting files and managing keys, for glob importing.
+
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+pub use crate::decrypt::stitch;
+#[cfg(feature = "async")]
+pub use crate::decrypt::{decrypt_async, JobEvent, ProgressStream};
+#[cfg(unix)]
+pub use crate::decrypt::{decrypt_from_fd, decrypt_image_from_fd};
+#[cfg(feature = "watch")]
+pub use crate::decrypt::{watch_dir, WatchEvent, WatchHandle, WatchOptions};
+#[cfg(feature = "transcode")]
+pub use crate::decrypt::{Corner, FrameProcessor, TimestampBurnIn};
+#[cfg(feature = "thumbnail")]
+pub use crate::decrypt::{ThumbnailFormat, ThumbnailSpec};
+#[cfg(feature = "video")]
+pub use crate::encrypt::{import_mp4, ImportOptions};
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+pub use crate::encrypt::{VideoEncryptor, VideoMetadata};
+#[cfg(not(target_arch = "wasm32"))]
+pub use crate::passphrase::TerminalPassphrase;
+pub use crate::{
+    batch::{
+        execute, plan, BatchEntry, BatchExecution, BatchOptions, BatchPlan, BatchReport, Collision,
+        PlanDrift, PlannedAction, PlannedFile, PlannedJob, SkipReason,
+    },
+    budget::{ResourceBudget, ResourceUsage},
+    capabilities::{capabilities, Capabilities, FileTypeSupport},
+    decrypt::{
+        decrypt, decrypt_from_reader, decrypt_stream, decrypt_with_options, decrypt_with_registry,
+        estimate_output_size, reencrypt, scan_dir, scan_dir_all, total_estimated_output_size,
+        AudioStats, BatchNames, CancelAck, CancellationToken, DecryptOptions, DecryptStats,
+        DecryptWarning, DecryptingJob, FrameRate, ImageFormatMismatch, ImageStats, JobBuilder,
+        JobContext, JobResult, JsonlProgress, MediaInfo, NameTemplate, NonMonotonicPts,
+        OutputNaming, Overwrite, ProgressCallback, Registry, ScanEntry, ScanErrorKind,
+        SubdirectoryStrategy, TranscodeSpec, VerificationReport, VideoBackend, VideoContainer,
+        VideoStats,
+    },
+    encrypt::Recipient,
+    fingerprint::RecipientDigest,
+    hash::{HashAlgo, HashDigest},
+    key_qrcode::{
+        assemble_parts, export_keyring, import_keyring_parts, import_uri, make_qr_code,
+        parse_payload, render_backup_sheet, render_svg, split_payload, BackupSheetOptions,
+        KeyQrPayload, KeyringExport, KeyringExportOptions, KeyringImportResult, PaperSize, QrPart,
+        QrRenderOptions,
+    },
+    keyring::{
+        generate_identity, sign_manifest, verify_manifest, DecryptIdentityError, DecryptionError,
+        DisplayIdentity, GeneratedIdentity, IdentityInfo, KeyDigest, Keyring, KeyringPhase,
+        ManifestKey, MatchResult, UnlockEstimate,
+    },
+    parser::{parse_header, Header},
+    passphrase::{ChannelPassphrase, PassphraseProvider, PassphraseRequest, StaticPassphrase},
+};
+pub use crate::{
+    decrypt::{decrypt_image_to_writer, decrypt_to_vec, DecryptedImage},
+    encrypt::{encrypt_image, ImageMetadata},
+};
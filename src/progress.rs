@@ -0,0 +1,46 @@
+//! Progress in bytes of the encrypted input, see InputPosition.
+
+use std::{
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// How many bytes of the encrypted file were read so far, headers and the overhead of the
+/// encryption included. Jobs report it to ProgressCallback::on_progress(), so progress ends at
+/// the total file size once the file was read to the end. Clones share the position.
+#[derive(Debug, Clone, Default)]
+pub struct InputPosition(Arc<AtomicU64>);
+
+impl InputPosition {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts the bytes read from the encrypted file into an InputPosition.
+pub(crate) struct CountingReader<R> {
+    inner: R,
+    position: InputPosition,
+}
+
+impl<R> CountingReader<R> {
+    pub(crate) fn new(inner: R) -> (Self, InputPosition) {
+        let position = InputPosition::default();
+        let reader = CountingReader {
+            inner,
+            position: position.clone(),
+        };
+        (reader, position)
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.position.0.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
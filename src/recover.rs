@@ -0,0 +1,149 @@
+//! The job for DecryptOptions::recover_raw_on_bad_metadata: the payload of a file whose metadata
+//! can't be parsed is written out as it is, together with the metadata bytes.
+
+use crate::{
+    cancel::CancellableReader,
+    decrypt::{DecryptOptions, DecryptingJob, ProgressCallback},
+    error::{job_error, read_error},
+    output_path::{recovered_file_name, sync_output, RemoveIfCancelled},
+    progress::InputPosition,
+    resume::open_output,
+    warning::{self, DecryptWarning},
+};
+use anyhow::anyhow;
+use std::{
+    fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// Written between progress reports.
+const PROGRESS_INTERVAL: usize = 1 << 20;
+
+pub(crate) struct RawPayloadJob {
+    data: Box<dyn Read + Send>,
+    metadata: Vec<u8>,
+    /// Why the metadata couldn't be parsed.
+    reason: String,
+    out_path: PathBuf,
+    total_file_size: u64,
+    input_position: InputPosition,
+    options: DecryptOptions,
+    output: Option<PathBuf>,
+}
+
+impl RawPayloadJob {
+    pub(crate) fn new(
+        data: Box<dyn Read + Send>,
+        metadata: &[u8],
+        reason: String,
+        out_path: PathBuf,
+        total_file_size: u64,
+        input_position: InputPosition,
+        options: DecryptOptions,
+    ) -> Self {
+        RawPayloadJob {
+            data,
+            metadata: metadata.to_vec(),
+            reason,
+            out_path,
+            total_file_size,
+            input_position,
+            options,
+            output: None,
+        }
+    }
+}
+
+impl DecryptingJob for RawPayloadJob {
+    fn run(&mut self, progress_callback: Box<&mut dyn ProgressCallback>, cancel: Arc<AtomicBool>) {
+        progress_callback.set_total_file_size(self.total_file_size);
+        warning::report(
+            &mut **progress_callback,
+            DecryptWarning::MetadataUnreadable {
+                reason: self.reason.clone(),
+            },
+        );
+        let options = &self.options;
+        let filename = recovered_file_name(options.source_stem.as_deref());
+        let out_path = &mut self.out_path;
+        // there's no timestamp to sort by, the subdirectory strategy falls back to unknown-date
+        let (mut out, pending_manifest) =
+            match open_output(out_path, &filename, "", options, None, true) {
+                Err(e) => {
+                    progress_callback.on_error(job_error(e));
+                    return;
+                }
+                Ok(opened) => opened,
+            };
+        let cleanup = RemoveIfCancelled::new(out_path, &cancel, options.resumable);
+        progress_callback.on_output_created(out_path);
+
+        let mut data = CancellableReader::new(&mut self.data, &cancel);
+        let mut buf = vec![0; 64 << 10];
+        let mut since_progress = 0;
+        loop {
+            let len = match data.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) => {
+                    if !cancel.load(Ordering::Relaxed) {
+                        progress_callback.on_error(read_error(e));
+                    }
+                    return;
+                }
+            };
+            if let Err(e) = out.write_all(&buf[..len]) {
+                progress_callback.on_error(e.into());
+                return;
+            }
+            since_progress += len;
+            if since_progress >= PROGRESS_INTERVAL {
+                since_progress = 0;
+                progress_callback.on_progress(self.input_position.get());
+            }
+        }
+        progress_callback.on_progress(self.input_position.get());
+
+        match out.digest(out_path) {
+            Ok(Some(digest)) => progress_callback.on_output_digest(&digest),
+            Ok(None) => {}
+            Err(e) => {
+                progress_callback.on_error(e.into());
+                return;
+            }
+        }
+        if let Err(e) = fs::write(metadata_path(out_path), &self.metadata) {
+            progress_callback.on_error(anyhow!("Error writing raw metadata: {}", e).into());
+            return;
+        }
+        if options.fsync_on_complete {
+            if let Err(e) = sync_output(out_path) {
+                progress_callback.on_error(e.into());
+                return;
+            }
+        }
+        if let Some(pending_manifest) = pending_manifest {
+            if let Err(e) = pending_manifest.complete() {
+                progress_callback.on_error(anyhow!("Error removing job manifest: {}", e).into());
+                return;
+            }
+        }
+        cleanup.keep();
+        self.output = Some(out_path.clone());
+        progress_callback.on_complete();
+    }
+
+    fn output_path(&self) -> Option<&Path> {
+        self.output.as_deref()
+    }
+}
+
+/// `<basename>.metadata.raw` next to the recovered payload.
+fn metadata_path(payload_path: &Path) -> PathBuf {
+    payload_path.with_extension("metadata.raw")
+}
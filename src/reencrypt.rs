@@ -0,0 +1,196 @@
+//! Moving a file to new recipients without touching its contents, see reencrypt().
+
+use crate::{
+    decrypt::{DecryptingJob, MatchedJob, ProgressCallback},
+    encrypt::{encrypt_to, Recipient},
+    error::read_error_anyhow,
+    keyring::Keyring,
+    parser::parse_header,
+    progress::{CountingReader, InputPosition},
+};
+use anyhow::{bail, Result};
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+const COPY_BUFFER_LEN: usize = 256 << 10;
+
+/// Decrypts `input` with a key from `keyring` and encrypts its contents to `new_recipients`
+/// without looking at them, so it works the same for every file type. The job writes to a
+/// temporary file next to `output`, which replaces `output` once complete.
+/// Progress is reported in bytes of `input` like for decryption.
+pub fn reencrypt(
+    input: File,
+    keyring: &mut Keyring,
+    new_recipients: &[Recipient],
+    output: &Path,
+) -> Result<Box<dyn DecryptingJob + Send>> {
+    if new_recipients.is_empty() {
+        bail!("No recipients to encrypt to");
+    }
+    let total_file_size = input.metadata()?.len();
+    let (input, input_position) = CountingReader::new(input);
+    let mut reader = BufReader::new(input);
+    let (header, _) = parse_header(&mut reader)?;
+    let (decrypted, identity) = keyring.decrypt(Box::new(reader), &header.recipient_digests)?;
+    let mut tmp_name = output.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".tmp");
+    let job = Box::new(ReencryptJob {
+        decrypted: Box::new(decrypted),
+        recipients: new_recipients.to_vec(),
+        tmp_path: output.with_file_name(tmp_name),
+        output_path: output.to_path_buf(),
+        total_file_size,
+        input_position,
+        output: None,
+    });
+    Ok(Box::new(MatchedJob {
+        job,
+        identity,
+        // the same contents with other recipients, about as large as the input
+        estimated_output_size: Some(total_file_size),
+        free_space_check: None,
+    }))
+}
+
+struct ReencryptJob {
+    decrypted: Box<dyn Read + Send>,
+    recipients: Vec<Recipient>,
+    tmp_path: PathBuf,
+    output_path: PathBuf,
+    total_file_size: u64,
+    input_position: InputPosition,
+    output: Option<PathBuf>,
+}
+
+impl ReencryptJob {
+    /// Returns false if cancelled.
+    fn copy(
+        &mut self,
+        progress_callback: &mut dyn ProgressCallback,
+        cancel: &AtomicBool,
+    ) -> Result<bool> {
+        let out = BufWriter::new(File::create(&self.tmp_path)?);
+        let mut encrypted = encrypt_to(&self.recipients, out)?;
+        let mut buf = vec![0; COPY_BUFFER_LEN];
+        loop {
+            if cancel.load(Ordering::Relaxed) {
+                return Ok(false);
+            }
+            let n = match self.decrypted.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(read_error_anyhow(e)),
+            };
+            encrypted.write_all(&buf[..n])?;
+            progress_callback.on_progress(self.input_position.get());
+        }
+        progress_callback.on_progress(self.input_position.get());
+        let file = encrypted
+            .finish()?
+            .into_inner()
+            .map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&self.tmp_path, &self.output_path)?;
+        Ok(true)
+    }
+}
+
+impl DecryptingJob for ReencryptJob {
+    fn run(&mut self, progress_callback: Box<&mut dyn ProgressCallback>, cancel: Arc<AtomicBool>) {
+        progress_callback.set_total_file_size(self.total_file_size);
+        progress_callback.on_output_created(&self.output_path);
+        match self.copy(*progress_callback, &cancel) {
+            Ok(true) => {
+                self.output = Some(self.output_path.clone());
+                progress_callback.on_complete();
+            }
+            Ok(false) => {
+                let _ = fs::remove_file(&self.tmp_path);
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&self.tmp_path);
+                progress_callback.on_error(e.into());
+            }
+        }
+    }
+
+    fn output_path(&self) -> Option<&Path> {
+        self.output.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        decrypt::{decrypt_to_vec, CancellationToken, JobResult},
+        fixtures::{test_keyring, FixtureFile},
+        parser::parse_header_from_slice,
+    };
+    use secrecy::ExposeSecret;
+    use std::error::Error;
+
+    struct NoProgress;
+
+    impl ProgressCallback for NoProgress {
+        fn set_total_file_size(&mut self, _n: u64) {}
+        fn on_progress(&mut self, _processed_bytes: u64) {}
+        fn on_complete(&mut self) {}
+        fn on_error(&mut self, error: Box<dyn Error>) {
+            panic!("{}", error);
+        }
+    }
+
+    #[test]
+    fn new_recipients_decrypt_the_same_payload_and_old_ones_dont() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = FixtureFile::image(
+            r#"{"timestamp":"2021-06-01T12:00:00Z","format":"png"}"#,
+            b"\x89PNG\r\n\x1a\n image".to_vec(),
+        )
+        .build();
+        let (input, output) = (dir.path().join("input"), dir.path().join("output"));
+        fs::write(&input, &image).unwrap();
+        let other = age::x25519::Identity::generate();
+        let mut other_keyring = Keyring::in_memory();
+        other_keyring
+            .import_key(other.to_string().expose_secret(), None)
+            .unwrap();
+        let other_recipient: Recipient = other.to_public().to_string().parse().unwrap();
+
+        let mut job = reencrypt(
+            File::open(&input).unwrap(),
+            &mut test_keyring(),
+            &[other_recipient.clone()],
+            &output,
+        )
+        .unwrap();
+        let result = job.run_with_token(Box::new(&mut NoProgress), &CancellationToken::new());
+        assert_eq!(
+            result,
+            JobResult::Complete {
+                output: Some(output.clone())
+            }
+        );
+        assert!(!dir.path().join("output.tmp").exists());
+
+        let reencrypted = fs::read(&output).unwrap();
+        let (header, _) = parse_header_from_slice(&reencrypted).unwrap();
+        assert_eq!(header.recipient_digests, [other_recipient.digest()]);
+        let (original_info, original) =
+            decrypt_to_vec(&image[..], &mut test_keyring(), 1 << 20).unwrap();
+        let (info, payload) =
+            decrypt_to_vec(&reencrypted[..], &mut other_keyring, 1 << 20).unwrap();
+        assert_eq!(payload, original);
+        assert_eq!(info.timestamp, original_info.timestamp);
+        assert!(decrypt_to_vec(&reencrypted[..], &mut test_keyring(), 1 << 20).is_err());
+    }
+}
@@ -0,0 +1,265 @@
+//! Which job decrypts which file type, see Registry.
+
+use crate::{
+    decrypt::{DecryptOptions, DecryptingJob, FILE_TYPE_AUDIO, FILE_TYPE_IMAGE, FILE_TYPE_VIDEO},
+    decrypt_image::{build_image_decryption_job, parse_metadata},
+    error::Error,
+    parser::Header,
+    progress::InputPosition,
+    recover::RawPayloadJob,
+    resume::ResumeManifest,
+    sidecar::Sidecar,
+};
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+use crate::{
+    decrypt_audio::build_audio_decryption_job,
+    decrypt_video::{build_video_decryption_job, parse_video_metadata},
+};
+use anyhow::Result;
+use std::{collections::BTreeMap, io::Read, path::PathBuf, str};
+
+/// Builds the job for one file type once the file is decrypted up to its data, see Registry.
+pub trait JobBuilder: Send + Sync {
+    /// `data` is the decrypted data after the metadata, `metadata` the metadata as the app
+    /// wrote it. `total_file_size` is for ProgressCallback::set_total_file_size(), progress is
+    /// reported from JobContext::input_position. `bytes_before_data` is the length of the
+    /// headers and metadata.
+    fn build(
+        &self,
+        data: Box<dyn Read + Send>,
+        metadata: &[u8],
+        out_path: PathBuf,
+        total_file_size: u64,
+        bytes_before_data: u64,
+        context: JobContext,
+    ) -> Result<Box<dyn DecryptingJob + Send>>;
+}
+
+/// What else a JobBuilder gets about the file.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct JobContext {
+    /// The file's unencrypted header.
+    pub header: Header,
+    pub options: DecryptOptions,
+    /// How far the encrypted file was read, for ProgressCallback::on_progress().
+    pub input_position: InputPosition,
+}
+
+impl JobContext {
+    /// The sidecar for `metadata` if DecryptOptions::write_metadata_sidecar asks for one.
+    fn sidecar(&self, file_type: &'static str, metadata: &[u8]) -> Result<Option<Sidecar>> {
+        if !self.options.write_metadata_sidecar || self.options.verify_only {
+            return Ok(None);
+        }
+        let mut sidecar = Sidecar::new(file_type, &self.header, metadata)?;
+        if self.options.strip_location {
+            sidecar.strip_location();
+        }
+        Ok(Some(sidecar))
+    }
+
+    /// Why `metadata` can't be parsed with `parse`, if DecryptOptions::recover_raw_on_bad_metadata
+    /// asks to write the payload as it is then. None to build the job as usual.
+    fn unreadable_metadata<T>(
+        &self,
+        metadata: &[u8],
+        parse: impl FnOnce(&str) -> Result<T>,
+    ) -> Option<String> {
+        if !self.options.recover_raw_on_bad_metadata || self.options.verify_only {
+            return None;
+        }
+        let parsed = str::from_utf8(metadata)
+            .map_err(anyhow::Error::from)
+            .and_then(parse);
+        parsed.err().map(|e| e.to_string())
+    }
+
+    /// The job writing the payload as it is, see unreadable_metadata().
+    fn raw_payload_job(
+        self,
+        data: Box<dyn Read + Send>,
+        metadata: &[u8],
+        reason: String,
+        out_path: PathBuf,
+        total_file_size: u64,
+    ) -> Box<dyn DecryptingJob + Send> {
+        Box::new(RawPayloadJob::new(
+            data,
+            metadata,
+            reason,
+            out_path,
+            total_file_size,
+            self.input_position,
+            self.options,
+        ))
+    }
+
+    /// The manifest for resuming the job, see DecryptOptions::resumable and resume_from.
+    fn resume_manifest(&self, file_type: &'static str, metadata: &[u8]) -> Option<ResumeManifest> {
+        let options = &self.options;
+        if !(options.resumable || options.resume_from.is_some()) || options.verify_only {
+            return None;
+        }
+        Some(ResumeManifest::new(
+            file_type,
+            &self.header,
+            metadata,
+            options,
+        ))
+    }
+}
+
+/// The JobBuilders by the file type byte in the encrypted header, see
+/// decrypt::decrypt_with_registry(). The default one has videos (1), images (2) and audio
+/// recordings (3), more can be registered for payload types this crate doesn't know.
+pub struct Registry {
+    builders: BTreeMap<u8, Box<dyn JobBuilder>>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Registry {
+            builders: BTreeMap::new(),
+        };
+        registry.register(FILE_TYPE_VIDEO, Box::new(VideoJobBuilder));
+        registry.register(FILE_TYPE_IMAGE, Box::new(ImageJobBuilder));
+        registry.register(FILE_TYPE_AUDIO, Box::new(AudioJobBuilder));
+        registry
+    }
+}
+
+impl Registry {
+    /// Uses `builder` for files of `file_type`, replacing the one registered before.
+    pub fn register(&mut self, file_type: u8, builder: Box<dyn JobBuilder>) {
+        self.builders.insert(file_type, builder);
+    }
+
+    /// The registered file types in ascending order.
+    pub fn file_types(&self) -> Vec<u8> {
+        self.builders.keys().copied().collect()
+    }
+
+    /// The builder for `file_type`, error::Error::UnknownFileType if there is none.
+    pub(crate) fn builder(&self, file_type: u8) -> Result<&dyn JobBuilder, Error> {
+        match self.builders.get(&file_type) {
+            Some(builder) => Ok(builder.as_ref()),
+            None => Err(Error::UnknownFileType {
+                file_type,
+                registered: self.file_types(),
+            }),
+        }
+    }
+}
+
+struct VideoJobBuilder;
+
+impl JobBuilder for VideoJobBuilder {
+    #[cfg(any(feature = "video", feature = "rust-mp4"))]
+    fn build(
+        &self,
+        data: Box<dyn Read + Send>,
+        metadata: &[u8],
+        out_path: PathBuf,
+        total_file_size: u64,
+        _bytes_before_data: u64,
+        context: JobContext,
+    ) -> Result<Box<dyn DecryptingJob + Send>> {
+        if let Some(reason) = context.unreadable_metadata(metadata, parse_video_metadata) {
+            return Ok(context.raw_payload_job(data, metadata, reason, out_path, total_file_size));
+        }
+        build_video_decryption_job(
+            data,
+            metadata,
+            out_path,
+            total_file_size,
+            context.input_position.clone(),
+            context.sidecar("video", metadata)?,
+            context.resume_manifest("video", metadata),
+            context.header.device_label(),
+            context.options,
+        )
+    }
+
+    #[cfg(not(any(feature = "video", feature = "rust-mp4")))]
+    fn build(
+        &self,
+        _data: Box<dyn Read + Send>,
+        _metadata: &[u8],
+        _out_path: PathBuf,
+        _total_file_size: u64,
+        _bytes_before_data: u64,
+        _context: JobContext,
+    ) -> Result<Box<dyn DecryptingJob + Send>> {
+        Err(Error::VideoSupportNotCompiled.into())
+    }
+}
+
+struct ImageJobBuilder;
+
+impl JobBuilder for ImageJobBuilder {
+    fn build(
+        &self,
+        data: Box<dyn Read + Send>,
+        metadata: &[u8],
+        out_path: PathBuf,
+        total_file_size: u64,
+        _bytes_before_data: u64,
+        context: JobContext,
+    ) -> Result<Box<dyn DecryptingJob + Send>> {
+        if let Some(reason) = context.unreadable_metadata(metadata, parse_metadata) {
+            return Ok(context.raw_payload_job(data, metadata, reason, out_path, total_file_size));
+        }
+        build_image_decryption_job(
+            data,
+            metadata,
+            out_path,
+            total_file_size,
+            context.input_position.clone(),
+            context.sidecar("image", metadata)?,
+            context.resume_manifest("image", metadata),
+            context.header.device_label(),
+            context.options,
+        )
+    }
+}
+
+struct AudioJobBuilder;
+
+impl JobBuilder for AudioJobBuilder {
+    #[cfg(any(feature = "video", feature = "rust-mp4"))]
+    fn build(
+        &self,
+        data: Box<dyn Read + Send>,
+        metadata: &[u8],
+        out_path: PathBuf,
+        total_file_size: u64,
+        _bytes_before_data: u64,
+        context: JobContext,
+    ) -> Result<Box<dyn DecryptingJob + Send>> {
+        build_audio_decryption_job(
+            data,
+            metadata,
+            out_path,
+            total_file_size,
+            context.input_position.clone(),
+            context.sidecar("audio", metadata)?,
+            context.resume_manifest("audio", metadata),
+            context.header.device_label(),
+            context.options,
+        )
+    }
+
+    #[cfg(not(any(feature = "video", feature = "rust-mp4")))]
+    fn build(
+        &self,
+        _data: Box<dyn Read + Send>,
+        _metadata: &[u8],
+        _out_path: PathBuf,
+        _total_file_size: u64,
+        _bytes_before_data: u64,
+        _context: JobContext,
+    ) -> Result<Box<dyn DecryptingJob + Send>> {
+        Err(Error::AudioSupportNotCompiled.into())
+    }
+}
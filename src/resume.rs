@@ -0,0 +1,204 @@
+//! Continuing an interrupted job from its partial output, see DecryptOptions::resume_from.
+//!
+//! The decrypted stream can't be seeked to where the partial output ends, since the muxers
+//! have to see every packet before it to get there. Instead the job runs from the start and
+//! OutputFile compares what it would write with what is already in the file, which is only
+//! read, and appends once it is past the end of it.
+
+use crate::{
+    decrypt::DecryptOptions,
+    error::Error,
+    hash::{HashAlgo, HashDigest},
+    output_path::{
+        check_output_path, create_output_file, enter_subdirectory, fit_path_length, OutputFile,
+        SubdirectoryStrategy,
+    },
+    parser::Header,
+};
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    fs::{self, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Written next to the output of a resumable job as `<output name>.resume` while the output is
+/// incomplete, to tell whether a partial output belongs to the file being decrypted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ResumeManifest {
+    file_type: String,
+    /// The hash of the header version, recipient digests and metadata as `<algo>:<hex>`, see
+    /// HashDigest, with DecryptOptions::output_digest or SHA-256.
+    source_digest: String,
+    libcryptocam_version: String,
+    /// The options that change the bytes of the output.
+    output_options: String,
+    /// What source_digest is the hash of, to check a stored manifest hashed with another
+    /// algorithm.
+    #[serde(skip)]
+    source: Vec<u8>,
+}
+
+impl ResumeManifest {
+    pub(crate) fn new(
+        file_type: &'static str,
+        header: &Header,
+        metadata: &[u8],
+        options: &DecryptOptions,
+    ) -> Self {
+        let mut source = header.version.to_le_bytes().to_vec();
+        for digest in &header.recipient_digests {
+            source.extend_from_slice(digest.as_bytes());
+        }
+        source.extend_from_slice(metadata);
+        let algo = options.output_digest.unwrap_or_default();
+        ResumeManifest {
+            file_type: file_type.to_owned(),
+            source_digest: hash(algo, &source).to_string(),
+            libcryptocam_version: env!("CARGO_PKG_VERSION").to_owned(),
+            output_options: format!(
+                "container={:?} backend={:?} fragmented={} write_exif={} strip_location={} \
+                 image_format_mismatch={:?} non_monotonic_pts={:?} resync_on_error={} \
+                 transcode={:?}",
+                options.container,
+                options.video_backend,
+                options.fragmented,
+                options.write_exif,
+                options.strip_location,
+                options.image_format_mismatch,
+                options.non_monotonic_pts,
+                options.resync_on_error,
+                options.transcode,
+            ),
+            source,
+        }
+    }
+
+    /// Why the partial output described by `stored` can't be continued with this manifest.
+    fn mismatch(&self, stored: &ResumeManifest) -> Option<&'static str> {
+        if stored.file_type != self.file_type || !self.same_source(&stored.source_digest) {
+            Some("it was written from another file")
+        } else if stored.libcryptocam_version != self.libcryptocam_version {
+            Some("it was written by another version of libcryptocam")
+        } else if stored.output_options != self.output_options {
+            Some("it was written with other options")
+        } else {
+            None
+        }
+    }
+
+    /// Whether `source_digest` of a stored manifest is the hash of this manifest's source, with
+    /// the algorithm it was hashed with.
+    fn same_source(&self, source_digest: &str) -> bool {
+        match source_digest.parse::<HashDigest>() {
+            Ok(digest) => hash(digest.algo(), &self.source) == digest,
+            Err(_) => false,
+        }
+    }
+}
+
+fn hash(algo: HashAlgo, data: &[u8]) -> HashDigest {
+    let mut hasher = algo.hasher();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+fn manifest_path(output: &Path) -> PathBuf {
+    let mut file_name = output.file_name().map(OsString::from).unwrap_or_default();
+    file_name.push(".resume");
+    output.with_file_name(file_name)
+}
+
+/// The manifest of an output that isn't complete yet. Unlike a pending sidecar, it is left
+/// behind if the job fails, so the job can be resumed.
+pub(crate) struct PendingManifest {
+    path: PathBuf,
+}
+
+impl PendingManifest {
+    /// Removes the manifest once the output is complete.
+    pub(crate) fn complete(self) -> io::Result<()> {
+        fs::remove_file(&self.path)
+    }
+}
+
+/// Opens what a job writes to: a new file named `file_name` in `out_path` and its
+/// subdirectory for `timestamp`, or with DecryptOptions::resume_from the partial output of an
+/// earlier run. `out_path` is changed to the path of the file. `front_to_back` tells whether
+/// the output is written without seeking, only such output can be resumed.
+pub(crate) fn open_output(
+    out_path: &mut PathBuf,
+    file_name: &str,
+    timestamp: &str,
+    options: &DecryptOptions,
+    manifest: Option<&ResumeManifest>,
+    front_to_back: bool,
+) -> Result<(OutputFile, Option<PendingManifest>)> {
+    let partial = match &options.resume_from {
+        Some(partial) => partial,
+        None => {
+            let out_dir = out_path.clone();
+            let subdirectory = options.subdirectory_strategy.subdirectory(timestamp);
+            fit_path_length(out_path, &subdirectory, file_name)?;
+            enter_subdirectory(out_path, &subdirectory)
+                .map_err(|e| anyhow!("Error creating output directory: {}", e))?;
+            out_path.push(file_name);
+            let subdirectories = options.subdirectory_strategy != SubdirectoryStrategy::Flat;
+            check_output_path(&out_dir, out_path, subdirectories)?;
+            let file = create_output_file(out_path, options.overwrite)?;
+            let pending_manifest = match manifest {
+                Some(manifest) if front_to_back && options.resumable => {
+                    let path = manifest_path(out_path);
+                    let mut json = serde_json::to_vec_pretty(manifest)?;
+                    json.push(b'\n');
+                    fs::write(&path, json)
+                        .map_err(|e| anyhow!("Error writing job manifest: {}", e))?;
+                    Some(PendingManifest { path })
+                }
+                _ => None,
+            };
+            return Ok((
+                OutputFile::new(file, options.output_digest, options.write_buffer_size),
+                pending_manifest,
+            ));
+        }
+    };
+    if !front_to_back {
+        bail!("Only output written front to back can be resumed, videos need to be fragmented");
+    }
+    let mismatch = |reason: String| Error::PartialOutputMismatch {
+        path: partial.clone(),
+        reason,
+    };
+    let path = manifest_path(partial);
+    let stored: ResumeManifest = match fs::read(&path) {
+        Ok(json) => serde_json::from_slice(&json)
+            .map_err(|e| mismatch(format!("its job manifest is invalid: {}", e)))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(mismatch(format!(
+                "its job manifest {} is missing, it is only written with DecryptOptions::resumable",
+                path.display()
+            ))
+            .into());
+        }
+        Err(e) => bail!("Error reading job manifest {}: {}", path.display(), e),
+    };
+    if let Some(reason) = manifest.and_then(|manifest| manifest.mismatch(&stored)) {
+        return Err(mismatch(reason.to_owned()).into());
+    }
+    let file = OpenOptions::new().read(true).write(true).open(partial)?;
+    let len = file.metadata()?.len();
+    *out_path = partial.clone();
+    Ok((
+        OutputFile::resuming(
+            file,
+            partial.clone(),
+            len,
+            options.output_digest,
+            options.write_buffer_size,
+        ),
+        Some(PendingManifest { path }),
+    ))
+}
@@ -0,0 +1,471 @@
+//! Writing decrypted videos with the pure Rust mp4 crate, see VideoBackend::RustMp4.
+//! Timing comes from the differences between packet timestamps only, the output has no edit
+//! list, so a stream that starts after the other one is moved to the start of the file.
+
+use crate::{
+    decrypt_video::{AudioCodec, PackerBackend, PacketType, VideoMetadata},
+    location::Location,
+    mp4,
+    output_path::OutputFile,
+};
+use ::mp4::{
+    AacConfig, AudioObjectType, AvcConfig, Bytes, ChannelConfig, FourCC, MediaConfig, Mp4Config,
+    Mp4Sample, Mp4Writer, SampleFreqIndex, TrackConfig, TrackType,
+};
+use anyhow::{anyhow, bail, Result};
+use log::{debug, info, warn};
+use std::{collections::VecDeque, convert::TryFrom};
+
+const VIDEO_TIMESCALE: u32 = 90000;
+/// Audio packets at most, held back while waiting for the SPS and PPS of the video.
+const MAX_QUEUED_AUDIO: usize = 1000;
+/// Used for a last video frame without a successor to take its duration from, 1/30 s.
+const DEFAULT_FRAME_DURATION: u32 = VIDEO_TIMESCALE / 30;
+/// Samples in an AAC frame.
+const AAC_FRAME_SAMPLES: u32 = 1024;
+
+const NAL_IDR: u8 = 5;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+const NAL_AUD: u8 = 9;
+
+pub(crate) struct RustMp4Packer {
+    writer: Mp4Writer<OutputFile>,
+    width: u16,
+    height: u16,
+    rotation: u16,
+    /// ISO 6709, see Location::to_iso6709().
+    location: Option<String>,
+    audio_bitrate: u32,
+    /// From the AudioSpecificConfig in the metadata, preferred over the ADTS header.
+    embedded_audio: Option<AacConfig>,
+    /// From the metadata, for AAC without ADTS headers.
+    fallback_audio: Option<AacConfig>,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    /// Track 1, added once the SPS and PPS are known.
+    video: Option<Track>,
+    /// Track 2, added with the first audio packet after the video track.
+    audio: Option<Track>,
+    queued_audio: VecDeque<(i64, Vec<u8>)>,
+    dropped_frames: u64,
+    /// An audio recording, written without a video track. Video packets are skipped.
+    audio_only: bool,
+}
+
+impl RustMp4Packer {
+    pub(crate) fn new(out: OutputFile, metadata: &VideoMetadata, audio_only: bool) -> Result<Self> {
+        if metadata.audio_codec == AudioCodec::Opus {
+            bail!("The rust-mp4 backend can't write Opus audio");
+        }
+        let width = u16::try_from(metadata.width)
+            .map_err(|_| anyhow!("Video width {} too large for MP4", metadata.width))?;
+        let height = u16::try_from(metadata.height)
+            .map_err(|_| anyhow!("Video height {} too large for MP4", metadata.height))?;
+        // what FFmpeg's ipod muxer writes for .m4a
+        let (major_brand, compatible_brands) = if audio_only {
+            ("M4A ", ["M4A ", "isom", "iso2", "mp41"])
+        } else {
+            ("isom", ["isom", "iso2", "avc1", "mp41"])
+        };
+        let config = Mp4Config {
+            major_brand: major_brand.parse()?,
+            minor_version: 512,
+            compatible_brands: compatible_brands
+                .iter()
+                .map(|brand| brand.parse())
+                .collect::<Result<Vec<FourCC>, _>>()?,
+            timescale: 1000,
+        };
+        let audio_bitrate = u32::try_from(metadata.audio_bitrate).unwrap_or(u32::MAX);
+        // parameter sets from the metadata win over those in the stream, which are then ignored
+        let (mut sps, mut pps) = (None, None);
+        if let Some(extradata) = metadata.video_extradata() {
+            for nal in annex_b_nal_units(&extradata)? {
+                match nal[0] & 0x1f {
+                    NAL_SPS => sps = sps.or_else(|| Some(nal.to_vec())),
+                    NAL_PPS => pps = pps.or_else(|| Some(nal.to_vec())),
+                    _ => {}
+                }
+            }
+        }
+        let embedded_audio = match metadata.audio_extradata() {
+            Some(asc) => match aac_config_from_asc(&asc, audio_bitrate) {
+                Ok(config) => Some(config),
+                Err(e) => {
+                    warn!("{}, using the ADTS headers", e);
+                    None
+                }
+            },
+            None => None,
+        };
+        Ok(RustMp4Packer {
+            writer: Mp4Writer::write_start(out, &config)?,
+            width,
+            height,
+            rotation: metadata.rotation.unwrap_or(0),
+            location: metadata.gps_location().map(Location::to_iso6709),
+            audio_bitrate,
+            embedded_audio,
+            fallback_audio: aac_config_from_metadata(metadata, audio_bitrate),
+            sps,
+            pps,
+            video: None,
+            audio: None,
+            queued_audio: VecDeque::new(),
+            dropped_frames: 0,
+            audio_only,
+        })
+    }
+
+    fn push_video(&mut self, pts_us: i64, data: &[u8]) -> Result<()> {
+        let mut sample = Vec::with_capacity(data.len() + 16);
+        let mut is_sync = false;
+        for nal in annex_b_nal_units(data)? {
+            match nal[0] & 0x1f {
+                NAL_SPS => {
+                    self.sps.get_or_insert_with(|| nal.to_vec());
+                }
+                NAL_PPS => {
+                    self.pps.get_or_insert_with(|| nal.to_vec());
+                }
+                NAL_AUD => {}
+                nal_type => {
+                    is_sync |= nal_type == NAL_IDR;
+                    sample.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+                    sample.extend_from_slice(nal);
+                }
+            }
+        }
+        if self.video.is_none() {
+            self.add_video_track()?;
+        }
+        if sample.is_empty() {
+            // the codec config packet, holding nothing but the SPS and PPS
+            return Ok(());
+        }
+        let video = match &mut self.video {
+            Some(video) => video,
+            None => {
+                self.dropped_frames += 1;
+                return Ok(());
+            }
+        };
+        if self.dropped_frames > 0 {
+            warn!(
+                "Dropped {} video frames before the first SPS and PPS",
+                self.dropped_frames
+            );
+            self.dropped_frames = 0;
+        }
+        let ticks = video.ticks(pts_us);
+        video.push(&mut self.writer, ticks, is_sync, Bytes::from(sample))
+    }
+
+    fn push_audio(&mut self, pts_us: i64, data: Vec<u8>) -> Result<()> {
+        if self.video.is_none() && !self.audio_only {
+            if self.queued_audio.len() >= MAX_QUEUED_AUDIO {
+                bail!(
+                    "No H.264 SPS and PPS before the first {} audio packets",
+                    MAX_QUEUED_AUDIO
+                );
+            }
+            self.queued_audio.push_back((pts_us, data));
+            return Ok(());
+        }
+        if self.audio.is_none() {
+            self.add_audio_track(&data)?;
+        }
+        let audio = self.audio.as_mut().unwrap();
+        let header_len = adts_header_len(&data).unwrap_or(0);
+        let ticks = audio.ticks(pts_us);
+        let bytes = Bytes::from(data).slice(header_len..);
+        audio.push(&mut self.writer, ticks, true, bytes)
+    }
+
+    fn add_video_track(&mut self) -> Result<()> {
+        let (sps, pps) = match (&self.sps, &self.pps) {
+            (Some(sps), Some(pps)) => (sps.clone(), pps.clone()),
+            _ => return Ok(()),
+        };
+        self.writer.add_track(&TrackConfig {
+            track_type: TrackType::Video,
+            timescale: VIDEO_TIMESCALE,
+            language: "und".to_owned(),
+            media_conf: MediaConfig::AvcConfig(AvcConfig {
+                width: self.width,
+                height: self.height,
+                seq_param_set: sps,
+                pic_param_set: pps,
+            }),
+        })?;
+        self.video = Some(Track::new(1, VIDEO_TIMESCALE, DEFAULT_FRAME_DURATION));
+        while let Some((pts_us, data)) = self.queued_audio.pop_front() {
+            self.push_audio(pts_us, data)?;
+        }
+        Ok(())
+    }
+
+    /// The AAC configuration comes from the AudioSpecificConfig in the metadata, the ADTS header
+    /// of the first packet, or the rest of the metadata if it has neither.
+    fn add_audio_track(&mut self, first_packet: &[u8]) -> Result<()> {
+        let aac_config = if let Some(aac_config) = self.embedded_audio.take() {
+            aac_config
+        } else if adts_header_len(first_packet).is_some() {
+            aac_config_from_adts(first_packet, self.audio_bitrate)?
+        } else {
+            info!("Audio packets have no ADTS header, using the metadata for the AAC config");
+            self.fallback_audio
+                .take()
+                .ok_or_else(|| anyhow!("Unsupported audio sample rate or channel count"))?
+        };
+        let sample_rate = aac_config.freq_index.freq();
+        self.writer.add_track(&TrackConfig {
+            track_type: TrackType::Audio,
+            timescale: sample_rate,
+            language: "und".to_owned(),
+            media_conf: MediaConfig::AacConfig(aac_config),
+        })?;
+        // track IDs are handed out in the order tracks are added
+        let id = if self.audio_only { 1 } else { 2 };
+        self.audio = Some(Track::new(id, sample_rate, AAC_FRAME_SAMPLES));
+        Ok(())
+    }
+
+    fn finish_audio_only(mut self) -> Result<OutputFile> {
+        match &mut self.audio {
+            Some(audio) => audio.finish(&mut self.writer)?,
+            None => bail!("No audio packets found in the recording"),
+        }
+        self.writer.write_end()?;
+        let mut out = self.writer.into_writer();
+        if let Some(location) = &self.location {
+            mp4::append_location(&mut out, location)?;
+        }
+        Ok(out)
+    }
+}
+
+impl PackerBackend for RustMp4Packer {
+    type Buffer = Vec<u8>;
+
+    fn alloc(len: usize) -> Vec<u8> {
+        vec![0; len]
+    }
+
+    fn buffer_mut(buffer: &mut Vec<u8>) -> &mut [u8] {
+        buffer
+    }
+
+    fn push(&mut self, packet_type: PacketType, pts_us: i64, data: Vec<u8>) -> Result<()> {
+        match packet_type {
+            PacketType::Video if self.audio_only => {
+                debug!("Skipping video packet of an audio recording");
+                Ok(())
+            }
+            PacketType::Video => self.push_video(pts_us, &data),
+            PacketType::Audio => self.push_audio(pts_us, data),
+        }
+    }
+
+    fn finish(mut self) -> Result<OutputFile> {
+        if self.audio_only {
+            return self.finish_audio_only();
+        }
+        let video = match &mut self.video {
+            Some(video) => video,
+            None => bail!("No H.264 SPS and PPS found in the video stream"),
+        };
+        video.finish(&mut self.writer)?;
+        if let Some(audio) = &mut self.audio {
+            audio.finish(&mut self.writer)?;
+        }
+        self.writer.write_end()?;
+        let mut out = self.writer.into_writer();
+        mp4::set_rotation(
+            &mut out,
+            1,
+            self.rotation,
+            u32::from(self.width),
+            u32::from(self.height),
+        )?;
+        if let Some(location) = &self.location {
+            mp4::append_location(&mut out, location)?;
+        }
+        Ok(out)
+    }
+}
+
+/// A track being written. Each sample is held back until the next one arrives, which gives
+/// its duration.
+struct Track {
+    id: u32,
+    timescale: u32,
+    pending: Option<(u64, bool, Bytes)>,
+    last_duration: u32,
+}
+
+impl Track {
+    fn new(id: u32, timescale: u32, default_duration: u32) -> Self {
+        Track {
+            id,
+            timescale,
+            pending: None,
+            last_duration: default_duration,
+        }
+    }
+
+    /// `pts_us` in the timescale of the track, counting from the first packet of the recording.
+    fn ticks(&self, pts_us: i64) -> u64 {
+        (pts_us.max(0) as u128 * self.timescale as u128 / 1_000_000) as u64
+    }
+
+    fn push(
+        &mut self,
+        writer: &mut Mp4Writer<OutputFile>,
+        ticks: u64,
+        is_sync: bool,
+        bytes: Bytes,
+    ) -> Result<()> {
+        if let Some((start, is_sync, bytes)) = self.pending.take() {
+            // PtsConditioner keeps timestamps increasing, but they may still round to the same tick
+            let duration = u32::try_from(ticks.saturating_sub(start))
+                .map_err(|_| anyhow!("Gap of more than {} ticks in track {}", u32::MAX, self.id))?;
+            self.last_duration = duration;
+            self.write(writer, start, duration, is_sync, bytes)?;
+        }
+        self.pending = Some((ticks, is_sync, bytes));
+        Ok(())
+    }
+
+    fn finish(&mut self, writer: &mut Mp4Writer<OutputFile>) -> Result<()> {
+        match self.pending.take() {
+            Some((start, is_sync, bytes)) => {
+                self.write(writer, start, self.last_duration, is_sync, bytes)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn write(
+        &self,
+        writer: &mut Mp4Writer<OutputFile>,
+        start: u64,
+        duration: u32,
+        is_sync: bool,
+        bytes: Bytes,
+    ) -> Result<()> {
+        writer.write_sample(
+            self.id,
+            &Mp4Sample {
+                start_time: start,
+                duration,
+                rendering_offset: 0,
+                is_sync,
+                bytes,
+            },
+        )?;
+        Ok(())
+    }
+}
+
+/// Splits an H.264 Annex B access unit into its NAL units, without start codes.
+fn annex_b_nal_units(data: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    let leading_garbage = starts
+        .first()
+        .map(|&start| data[..start - 3].iter().any(|&b| b != 0));
+    if leading_garbage != Some(false) {
+        bail!("Video packet is not H.264 in Annex B format");
+    }
+    let mut nal_units = Vec::with_capacity(starts.len());
+    for (n, &start) in starts.iter().enumerate() {
+        let end = starts.get(n + 1).map_or(data.len(), |&next| next - 3);
+        // the zero of a four byte start code, or trailing_zero_8bits
+        let nal = &data[start..end];
+        let len = nal.len() - nal.iter().rev().take_while(|&&b| b == 0).count();
+        if len > 0 {
+            nal_units.push(&nal[..len]);
+        }
+    }
+    Ok(nal_units)
+}
+
+/// Length of the ADTS header `data` starts with, if it starts with one.
+fn adts_header_len(data: &[u8]) -> Option<usize> {
+    if data.len() < 7 || data[0] != 0xff || data[1] & 0xf0 != 0xf0 {
+        return None;
+    }
+    // 9 bytes with a CRC
+    let header_len = if data[1] & 1 == 1 { 7 } else { 9 };
+    Some(header_len).filter(|&len| len <= data.len())
+}
+
+fn aac_config_from_adts(header: &[u8], bitrate: u32) -> Result<AacConfig> {
+    let profile = (header[2] >> 6) + 1;
+    let freq_index = (header[2] >> 2) & 0xf;
+    let channels = ((header[2] & 1) << 2) | (header[3] >> 6);
+    Ok(AacConfig {
+        bitrate,
+        profile: AudioObjectType::try_from(profile)
+            .map_err(|_| anyhow!("Unsupported AAC profile {} in ADTS header", profile))?,
+        freq_index: SampleFreqIndex::try_from(freq_index)
+            .map_err(|_| anyhow!("Invalid sample rate index {} in ADTS header", freq_index))?,
+        chan_conf: ChannelConfig::try_from(channels).map_err(|_| {
+            anyhow!(
+                "Unsupported channel configuration {} in ADTS header",
+                channels
+            )
+        })?,
+    })
+}
+
+/// Reads the start of an AudioSpecificConfig, see ISO 14496-3 section 1.6.2.1. Escaped object
+/// types and explicit sample rates aren't supported.
+fn aac_config_from_asc(asc: &[u8], bitrate: u32) -> Result<AacConfig> {
+    if asc.len() < 2 {
+        bail!("AudioSpecificConfig of {} bytes is too short", asc.len());
+    }
+    let profile = asc[0] >> 3;
+    let freq_index = ((asc[0] & 0x7) << 1) | (asc[1] >> 7);
+    let channels = (asc[1] >> 3) & 0xf;
+    Ok(AacConfig {
+        bitrate,
+        profile: AudioObjectType::try_from(profile)
+            .map_err(|_| anyhow!("Unsupported AAC profile {} in AudioSpecificConfig", profile))?,
+        freq_index: SampleFreqIndex::try_from(freq_index).map_err(|_| {
+            anyhow!(
+                "Invalid sample rate index {} in AudioSpecificConfig",
+                freq_index
+            )
+        })?,
+        chan_conf: ChannelConfig::try_from(channels).map_err(|_| {
+            anyhow!(
+                "Unsupported channel configuration {} in AudioSpecificConfig",
+                channels
+            )
+        })?,
+    })
+}
+
+fn aac_config_from_metadata(metadata: &VideoMetadata, bitrate: u32) -> Option<AacConfig> {
+    let freq_index = (0..13)
+        .filter_map(|index| SampleFreqIndex::try_from(index).ok())
+        .find(|index| index.freq() == metadata.audio_sample_rate)?;
+    Some(AacConfig {
+        bitrate,
+        profile: AudioObjectType::AacLowComplexity,
+        freq_index,
+        chan_conf: ChannelConfig::try_from(u8::try_from(metadata.audio_channel_count).ok()?)
+            .ok()?,
+    })
+}
@@ -0,0 +1,248 @@
+//! Finding Cryptocam files without decrypting them, see scan_dir().
+
+use crate::{
+    error::Error,
+    estimate::estimate_output_size,
+    parser::{parse_header, Header},
+};
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+/// A Cryptocam file found by scan_dir().
+#[derive(Debug, Serialize)]
+pub struct ScanEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    /// The error message if the file or directory couldn't be read, or its header is invalid.
+    pub header: Result<Header, String>,
+    /// What is wrong with the header, set if `header` is an error.
+    pub error_kind: Option<ScanErrorKind>,
+}
+
+/// Why the header of a ScanEntry couldn't be read, for counting the entries of a scan by it.
+/// Serialized in snake_case, like "truncated_header".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanErrorKind {
+    /// error::Error::NotACryptocamFile, only listed by scan_dir_all().
+    NotACryptocamFile,
+    /// error::Error::TruncatedHeader
+    TruncatedHeader,
+    /// error::Error::UnsupportedVersion
+    UnsupportedVersion,
+    /// error::Error::MalformedRecipientList
+    MalformedRecipientList,
+    /// Reading the file or directory failed.
+    Unreadable,
+}
+
+impl ScanErrorKind {
+    fn of(e: &anyhow::Error) -> Self {
+        match e.downcast_ref::<Error>() {
+            Some(Error::NotACryptocamFile { .. }) => ScanErrorKind::NotACryptocamFile,
+            Some(Error::TruncatedHeader { .. }) => ScanErrorKind::TruncatedHeader,
+            Some(Error::UnsupportedVersion(_)) => ScanErrorKind::UnsupportedVersion,
+            Some(Error::MalformedRecipientList { .. }) => ScanErrorKind::MalformedRecipientList,
+            _ => ScanErrorKind::Unreadable,
+        }
+    }
+}
+
+impl ScanEntry {
+    /// How large the file will be decrypted, see estimate_output_size(). None if its header
+    /// couldn't be read.
+    pub fn estimated_output_size(&self) -> Option<u64> {
+        let header = self.header.as_ref().ok()?;
+        Some(estimate_output_size(header, self.size))
+    }
+
+    fn unreadable(path: PathBuf, size: u64, e: std::io::Error) -> Self {
+        ScanEntry {
+            path,
+            size,
+            header: Err(e.to_string()),
+            error_kind: Some(ScanErrorKind::Unreadable),
+        }
+    }
+}
+
+/// The estimated output size of all `entries` together, e.g. to check for enough free space
+/// before decrypting what scan_dir() found. Entries whose header couldn't be read count as 0.
+pub fn total_estimated_output_size(entries: &[ScanEntry]) -> u64 {
+    entries
+        .iter()
+        .filter_map(ScanEntry::estimated_output_size)
+        .sum()
+}
+
+/// Lists the Cryptocam files in `dir`, sorted by path, and in its subdirectories if `recursive`.
+/// Files are recognized by their content, not their extension. Only headers are read, so no
+/// keyring is needed. Files that can't be read are listed with their error instead of failing
+/// the scan, only `dir` itself not being readable is an error. Symlinks to directories aren't
+/// followed. Files that start like a Cryptocam file but end inside the header are listed with
+/// ScanErrorKind::TruncatedHeader, empty files aren't listed.
+pub fn scan_dir(dir: &Path, recursive: bool) -> Result<Vec<ScanEntry>> {
+    scan(dir, recursive, false)
+}
+
+/// Like scan_dir(), also listing the files that aren't Cryptocam files, with
+/// ScanErrorKind::NotACryptocamFile. For checking a directory that should only hold Cryptocam
+/// files, e.g. an archive of them.
+pub fn scan_dir_all(dir: &Path, recursive: bool) -> Result<Vec<ScanEntry>> {
+    scan(dir, recursive, true)
+}
+
+fn scan(dir: &Path, recursive: bool, include_unrecognized: bool) -> Result<Vec<ScanEntry>> {
+    let mut entries = vec![];
+    let mut dirs = vec![(dir.to_path_buf(), fs::read_dir(dir)?)];
+    while let Some((dir, read_dir)) = dirs.pop() {
+        for dir_entry in read_dir {
+            let dir_entry = match dir_entry {
+                Ok(dir_entry) => dir_entry,
+                Err(e) => {
+                    entries.push(ScanEntry::unreadable(dir.clone(), 0, e));
+                    continue;
+                }
+            };
+            let path = dir_entry.path();
+            match dir_entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => {
+                    if recursive {
+                        match fs::read_dir(&path) {
+                            Ok(read_dir) => dirs.push((path, read_dir)),
+                            Err(e) => entries.push(ScanEntry::unreadable(path, 0, e)),
+                        }
+                    }
+                }
+                Ok(_) => entries.extend(scan_file(path, include_unrecognized)),
+                Err(e) => entries.push(ScanEntry::unreadable(path, 0, e)),
+            }
+        }
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// None if the file is empty or isn't a Cryptocam file, unless `include_unrecognized`.
+pub(crate) fn scan_file(path: PathBuf, include_unrecognized: bool) -> Option<ScanEntry> {
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) => return Some(ScanEntry::unreadable(path, 0, e)),
+    };
+    let size = match file.metadata() {
+        // e.g. the .nomedia files Android puts into media folders
+        Ok(metadata) if metadata.is_file() && metadata.len() == 0 => return None,
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        Ok(_) => return None,
+        Err(e) => return Some(ScanEntry::unreadable(path, 0, e)),
+    };
+    let (header, error_kind) = match parse_header(&mut BufReader::new(file)) {
+        Ok((header, _)) => (Ok(header), None),
+        Err(e) => (Err(e.to_string()), Some(ScanErrorKind::of(&e))),
+    };
+    if error_kind == Some(ScanErrorKind::NotACryptocamFile) && !include_unrecognized {
+        return None;
+    }
+    Some(ScanEntry {
+        path,
+        size,
+        header,
+        error_kind,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    /// A directory with the files of the header corpus.
+    fn corpus_dir() -> tempfile::TempDir {
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus/header");
+        let dir = tempfile::tempdir().unwrap();
+        for entry in fs::read_dir(corpus).unwrap() {
+            let path = entry.unwrap().path();
+            fs::copy(&path, dir.path().join(path.file_name().unwrap())).unwrap();
+        }
+        dir
+    }
+
+    fn error_kinds(entries: &[ScanEntry]) -> BTreeMap<String, Option<ScanErrorKind>> {
+        entries
+            .iter()
+            .map(|entry| {
+                let name = entry.path.file_name().unwrap().to_string_lossy();
+                (name.into_owned(), entry.error_kind)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn header_errors_are_categorized() {
+        let dir = corpus_dir();
+        let kinds = error_kinds(&scan_dir_all(dir.path(), false).unwrap());
+        let expected: BTreeMap<String, Option<ScanErrorKind>> = [
+            (
+                "invalid_duplicate_recipients",
+                Some(ScanErrorKind::MalformedRecipientList),
+            ),
+            ("invalid_garbage", Some(ScanErrorKind::NotACryptocamFile)),
+            ("invalid_magic", Some(ScanErrorKind::NotACryptocamFile)),
+            (
+                "invalid_truncated_digest",
+                Some(ScanErrorKind::TruncatedHeader),
+            ),
+            (
+                "invalid_truncated_magic",
+                Some(ScanErrorKind::TruncatedHeader),
+            ),
+            ("invalid_v2_extension_overrun", None),
+            (
+                "invalid_v2_truncated_extensions_len",
+                Some(ScanErrorKind::TruncatedHeader),
+            ),
+            ("invalid_version_3", Some(ScanErrorKind::UnsupportedVersion)),
+            ("v1_no_recipients", None),
+            ("v1_one_recipient", None),
+            ("v1_two_recipients", None),
+            ("v2_extensions", None),
+            ("v2_no_extensions", None),
+        ]
+        .iter()
+        .map(|(name, kind)| (name.to_string(), *kind))
+        .collect();
+        // invalid_empty is left out like any empty file
+        assert_eq!(kinds, expected);
+
+        let mut counts = BTreeMap::new();
+        for kind in kinds.values().flatten() {
+            *counts.entry(*kind).or_insert(0) += 1;
+        }
+        assert_eq!(counts[&ScanErrorKind::TruncatedHeader], 3);
+        assert_eq!(counts[&ScanErrorKind::NotACryptocamFile], 2);
+    }
+
+    #[test]
+    fn scan_dir_leaves_out_other_files() {
+        let dir = corpus_dir();
+        let kinds = error_kinds(&scan_dir(dir.path(), false).unwrap());
+        assert_eq!(kinds.len(), 11);
+        assert!(!kinds.contains_key("invalid_garbage"));
+        assert!(!kinds.contains_key("invalid_magic"));
+        assert_eq!(
+            kinds["invalid_version_3"],
+            Some(ScanErrorKind::UnsupportedVersion)
+        );
+    }
+
+    #[test]
+    fn header_errors_serialize_in_snake_case() {
+        let kind = serde_json::to_value(ScanErrorKind::MalformedRecipientList).unwrap();
+        assert_eq!(kind, "malformed_recipient_list");
+    }
+}
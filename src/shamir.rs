@@ -0,0 +1,83 @@
+//! Shamir secret sharing over GF(256), byte by byte, as used by key_qrcode::split_shamir().
+//! Field arithmetic uses the AES polynomial x^8 + x^4 + x^3 + x + 1 and is written without
+//! lookup tables or secret dependent branches.
+
+use anyhow::{bail, Result};
+use rand::{rngs::OsRng, RngCore};
+
+/// Splits `secret` into `n` shares, any `k` of which recover it. Share i (1-based) is
+/// evaluated at x = i and returned as (i, bytes).
+pub(crate) fn split(secret: &[u8], k: u8, n: u8) -> Result<Vec<(u8, Vec<u8>)>> {
+    if k < 2 || k > n {
+        bail!("Invalid threshold {} of {} shares", k, n);
+    }
+    let mut shares: Vec<(u8, Vec<u8>)> = (1..=n)
+        .map(|x| (x, Vec::with_capacity(secret.len())))
+        .collect();
+    let mut coefficients = vec![0u8; k as usize];
+    for &byte in secret {
+        coefficients[0] = byte;
+        OsRng.fill_bytes(&mut coefficients[1..]);
+        for (x, share) in shares.iter_mut() {
+            share.push(evaluate(&coefficients, *x));
+        }
+    }
+    for c in coefficients.iter_mut() {
+        *c = 0;
+    }
+    Ok(shares)
+}
+
+/// Recovers the secret from shares made by split(), which must have distinct x values
+/// and equal lengths. Exactly the given shares are used, so at least k of them are needed.
+pub(crate) fn combine(shares: &[(u8, &[u8])]) -> Vec<u8> {
+    let len = shares.first().map_or(0, |(_, s)| s.len());
+    // Lagrange basis polynomials evaluated at x = 0
+    let weights: Vec<u8> = shares
+        .iter()
+        .map(|&(xi, _)| {
+            shares
+                .iter()
+                .filter(|&&(xj, _)| xj != xi)
+                .fold(1, |w, &(xj, _)| mul(w, mul(xj, inverse(xj ^ xi))))
+        })
+        .collect();
+    (0..len)
+        .map(|i| {
+            shares
+                .iter()
+                .zip(&weights)
+                .fold(0, |acc, ((_, share), &w)| acc ^ mul(share[i], w))
+        })
+        .collect()
+}
+
+fn evaluate(coefficients: &[u8], x: u8) -> u8 {
+    coefficients.iter().rev().fold(0, |acc, &c| mul(acc, x) ^ c)
+}
+
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & 0u8.wrapping_sub(b & 1);
+        let carry = 0u8.wrapping_sub(a >> 7);
+        a = (a << 1) ^ (0x1b & carry);
+        b >>= 1;
+    }
+    product
+}
+
+/// a^254 = a^-1 for a != 0
+fn inverse(a: u8) -> u8 {
+    let a2 = mul(a, a);
+    let a3 = mul(a2, a);
+    let a6 = mul(a3, a3);
+    let a12 = mul(a6, a6);
+    let a15 = mul(a12, a3);
+    let a30 = mul(a15, a15);
+    let a60 = mul(a30, a30);
+    let a63 = mul(a60, a3);
+    let a126 = mul(a63, a63);
+    let a252 = mul(a126, a126);
+    mul(a252, a2)
+}
@@ -0,0 +1,107 @@
+//! The JSON file written next to a decrypted file, see DecryptOptions::write_metadata_sidecar.
+
+use crate::{fingerprint::RecipientDigest, hash::HashDigest, parser::Header};
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Sidecar {
+    file_type: &'static str,
+    header_version: u16,
+    /// See RecipientDigest::to_hex().
+    recipient_digests: Vec<String>,
+    libcryptocam_version: &'static str,
+    /// The metadata of the file as the app wrote it.
+    metadata: serde_json::Value,
+    /// The digest of the decrypted file as `<algo>:<hex>`, see HashDigest. Only written with
+    /// DecryptOptions::output_digest.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_digest: Option<String>,
+}
+
+impl Sidecar {
+    pub(crate) fn new(file_type: &'static str, header: &Header, metadata: &[u8]) -> Result<Self> {
+        Ok(Sidecar {
+            file_type,
+            header_version: header.version,
+            recipient_digests: header
+                .recipient_digests
+                .iter()
+                .map(RecipientDigest::to_hex)
+                .collect(),
+            libcryptocam_version: env!("CARGO_PKG_VERSION"),
+            metadata: serde_json::from_slice(metadata)?,
+            output_digest: None,
+        })
+    }
+
+    /// Removes the coordinates from the metadata, see DecryptOptions::strip_location.
+    pub(crate) fn strip_location(&mut self) {
+        if let Some(metadata) = self.metadata.as_object_mut() {
+            for key in ["latitude", "longitude", "altitude"] {
+                metadata.remove(key);
+            }
+        }
+    }
+
+    /// Writes the sidecar of `media_path` to a temporary file, which becomes `<basename>.json`
+    /// when committed and is removed if it isn't.
+    pub(crate) fn write_pending(&self, media_path: &Path) -> io::Result<PendingSidecar> {
+        let path = media_path.with_extension("json");
+        let tmp_path = media_path.with_extension("json.tmp");
+        self.write(&tmp_path)?;
+        Ok(PendingSidecar {
+            sidecar: self.clone(),
+            path,
+            tmp_path,
+            committed: false,
+        })
+    }
+
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let mut json = serde_json::to_vec_pretty(self)?;
+        json.push(b'\n');
+        fs::write(path, json)
+    }
+}
+
+pub(crate) struct PendingSidecar {
+    sidecar: Sidecar,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    committed: bool,
+}
+
+impl PendingSidecar {
+    /// Moves the sidecar into place once the media file is complete, with the digest of the
+    /// media file if it was hashed.
+    pub(crate) fn commit(mut self, output_digest: Option<&HashDigest>) -> io::Result<()> {
+        if let Some(digest) = output_digest {
+            self.sidecar.output_digest = Some(digest.to_string());
+            self.sidecar.write(&self.tmp_path)?;
+        }
+        fs::rename(&self.tmp_path, &self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for PendingSidecar {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+/// The digest committed with the sidecar of `media_path`, None if there is no sidecar or it has
+/// no digest.
+pub(crate) fn recorded_digest(media_path: &Path) -> Option<HashDigest> {
+    let json = fs::read(media_path.with_extension("json")).ok()?;
+    let sidecar: serde_json::Value = serde_json::from_slice(&json).ok()?;
+    sidecar.get("output_digest")?.as_str()?.parse().ok()
+}
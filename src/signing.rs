@@ -0,0 +1,334 @@
+/*
+Signatures over decryption manifests, see keyring::sign_manifest(). Every identity in a keyring
+has an Ed25519 signing key derived from its age secret key:
+
+seed = SHA-256("cryptocam-manifest-key-v1" || 0x00 || AGE-SECRET-KEY-1...)
+
+so nothing has to be stored next to the keyfile, and the same key signs on every machine the
+identity is imported on. The signature covers
+
+"cryptocam-manifest-v1" || 0x00 || manifest bytes
+
+and is written as one line of text:
+
+cryptocam-manifest-v1:<digest>:<signature>
+
+with the digest of the signing identity in lowercase hex, see RecipientDigest::to_hex(), and the
+64 byte Ed25519 signature in standard base64 with padding. The public key to verify against,
+ManifestKey, is written the same way:
+
+cryptocam-manifest-key-v1:<digest>:<public key>
+
+with the 32 byte Ed25519 public key in base64.
+*/
+
+use crate::keyring::{KeyDigest, Keyring};
+use anyhow::{anyhow, bail, Result};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer, Verifier};
+use secrecy::ExposeSecret;
+use sha2::{Digest, Sha256};
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+const SIGNATURE_PREFIX: &str = "cryptocam-manifest-v1";
+const KEY_PREFIX: &str = "cryptocam-manifest-key-v1";
+/// Separates the key derivation and the signed messages from other uses of the same keys.
+const KEY_CONTEXT: &[u8] = b"cryptocam-manifest-key-v1\0";
+const MESSAGE_CONTEXT: &[u8] = b"cryptocam-manifest-v1\0";
+
+/// The public key manifests signed by an identity are verified with, see
+/// Keyring::manifest_key(). Give it to whoever needs to check the manifests, it can't be
+/// derived from the identity's age recipient.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ManifestKey {
+    /// The identity whose signatures this key verifies.
+    pub digest: KeyDigest,
+    key: [u8; 32],
+}
+
+/// A signature over a manifest from keyring::sign_manifest().
+#[derive(Clone, PartialEq, Eq)]
+pub struct Signature {
+    /// The identity that signed the manifest.
+    pub digest: KeyDigest,
+    signature: [u8; 64],
+}
+
+impl Signature {
+    pub fn as_bytes(&self) -> &[u8; 64] {
+        &self.signature
+    }
+}
+
+impl ManifestKey {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.key
+    }
+}
+
+impl Keyring {
+    /// The key that verifies the manifests signed with the identity `digest`. Passphrase
+    /// protected identities have to be unlocked first.
+    pub fn manifest_key(&self, digest: &KeyDigest) -> Result<ManifestKey> {
+        let keypair = signing_keypair(self, digest)?;
+        Ok(ManifestKey {
+            digest: *digest,
+            key: keypair.public.to_bytes(),
+        })
+    }
+}
+
+/// Signs `manifest` with the identity `digest` of `keyring`, which has to be unlocked if it is
+/// passphrase protected. The manifest is signed as it is, any byte changed later makes
+/// verify_manifest() return false.
+pub fn sign_manifest(keyring: &Keyring, digest: &KeyDigest, manifest: &[u8]) -> Result<Signature> {
+    let keypair = signing_keypair(keyring, digest)?;
+    Ok(Signature {
+        digest: *digest,
+        signature: keypair.sign(&signed_message(manifest)).to_bytes(),
+    })
+}
+
+/// Whether `signature` over `manifest` was made by the identity `key` belongs to. A signature
+/// by another identity is false. Fails only if `key` isn't a valid Ed25519 public key.
+pub fn verify_manifest(key: &ManifestKey, manifest: &[u8], signature: &Signature) -> Result<bool> {
+    let public_key = PublicKey::from_bytes(&key.key)
+        .map_err(|e| anyhow!("Invalid manifest key {}: {}", key.digest, e))?;
+    if signature.digest != key.digest {
+        return Ok(false);
+    }
+    let signature = match ed25519_dalek::Signature::try_from(&signature.signature[..]) {
+        Ok(signature) => signature,
+        Err(_) => return Ok(false),
+    };
+    Ok(public_key
+        .verify(&signed_message(manifest), &signature)
+        .is_ok())
+}
+
+fn signing_keypair(keyring: &Keyring, digest: &KeyDigest) -> Result<Keypair> {
+    let age_secret_key = keyring.export_secret_key(digest)?;
+    let mut hasher = Sha256::new();
+    hasher.update(KEY_CONTEXT);
+    hasher.update(age_secret_key.expose_secret().as_bytes());
+    let secret = SecretKey::from_bytes(&hasher.finalize())
+        .map_err(|e| anyhow!("Error deriving signing key: {}", e))?;
+    let public = PublicKey::from(&secret);
+    Ok(Keypair { secret, public })
+}
+
+fn signed_message(manifest: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(MESSAGE_CONTEXT.len() + manifest.len());
+    message.extend_from_slice(MESSAGE_CONTEXT);
+    message.extend_from_slice(manifest);
+    message
+}
+
+/// Splits `<prefix>:<digest>:<base64>` into the digest and the decoded bytes.
+fn parse_parts<const N: usize>(s: &str, prefix: &str) -> Result<(KeyDigest, [u8; N])> {
+    let mut parts = s.trim().splitn(3, ':');
+    if parts.next() != Some(prefix) {
+        bail!("Expected {}:...", prefix);
+    }
+    let digest = parts
+        .next()
+        .ok_or_else(|| anyhow!("Missing key digest"))?
+        .parse()?;
+    let encoded = parts
+        .next()
+        .ok_or_else(|| anyhow!("Missing key or signature"))?;
+    let bytes = base64::decode(encoded).map_err(|_| anyhow!("Invalid base64"))?;
+    let bytes = <[u8; N]>::try_from(bytes.as_slice())
+        .map_err(|_| anyhow!("Expected {} bytes, not {}", N, bytes.len()))?;
+    Ok((digest, bytes))
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            SIGNATURE_PREFIX,
+            self.digest.to_hex(),
+            base64::encode(self.signature)
+        )
+    }
+}
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Signature({})", self)
+    }
+}
+
+impl FromStr for Signature {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (digest, signature) = parse_parts(s, SIGNATURE_PREFIX)?;
+        Ok(Signature { digest, signature })
+    }
+}
+
+impl fmt::Display for ManifestKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            KEY_PREFIX,
+            self.digest.to_hex(),
+            base64::encode(self.key)
+        )
+    }
+}
+
+impl fmt::Debug for ManifestKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ManifestKey({})", self)
+    }
+}
+
+impl FromStr for ManifestKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (digest, key) = parse_parts(s, KEY_PREFIX)?;
+        Ok(ManifestKey { digest, key })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{batch::BatchReport, fixtures::TEST_SECRET_KEY};
+
+    const MANIFEST: &[u8] = b"{\"files\":[{\"input\":\"a.cryptocam\",\"output\":\"a.jpg\"}]}\n";
+
+    fn keyring_with(secret_key: &str) -> (Keyring, KeyDigest) {
+        let mut keyring = Keyring::in_memory();
+        let digest = keyring.import_key(secret_key, None).unwrap();
+        (keyring, digest)
+    }
+
+    fn other_identity() -> (Keyring, KeyDigest) {
+        let key = age::x25519::Identity::generate().to_string();
+        keyring_with(key.expose_secret())
+    }
+
+    #[test]
+    fn signed_manifests_verify() {
+        let (keyring, digest) = keyring_with(TEST_SECRET_KEY);
+        let key = keyring.manifest_key(&digest).unwrap();
+        let signature = sign_manifest(&keyring, &digest, MANIFEST).unwrap();
+        assert_eq!(signature.digest, digest);
+        assert!(verify_manifest(&key, MANIFEST, &signature).unwrap());
+
+        // the same identity imported elsewhere signs with the same key
+        let (elsewhere, _) = keyring_with(TEST_SECRET_KEY);
+        assert_eq!(elsewhere.manifest_key(&digest).unwrap(), key);
+        assert_eq!(
+            sign_manifest(&elsewhere, &digest, MANIFEST).unwrap(),
+            signature
+        );
+    }
+
+    #[test]
+    fn signatures_and_keys_round_trip_as_text() {
+        let (keyring, digest) = keyring_with(TEST_SECRET_KEY);
+        let key = keyring.manifest_key(&digest).unwrap();
+        let signature = sign_manifest(&keyring, &digest, MANIFEST).unwrap();
+
+        let hex = digest.to_hex();
+        let signature_text = signature.to_string();
+        assert!(signature_text.starts_with(&format!("cryptocam-manifest-v1:{}:", hex)));
+        let key_text = key.to_string();
+        assert!(key_text.starts_with(&format!("cryptocam-manifest-key-v1:{}:", hex)));
+        let parsed_signature: Signature = signature_text.parse().unwrap();
+        let parsed_key: ManifestKey = key_text.parse().unwrap();
+        assert_eq!((&parsed_signature, &parsed_key), (&signature, &key));
+        assert!(verify_manifest(&parsed_key, MANIFEST, &parsed_signature).unwrap());
+
+        for text in [
+            key_text.as_str(),
+            "cryptocam-manifest-v1",
+            "cryptocam-manifest-v1:00:AAAA",
+            &signature_text[..signature_text.len() - 4],
+        ] {
+            assert!(text.parse::<Signature>().is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn a_flipped_byte_fails_verification() {
+        let (keyring, digest) = keyring_with(TEST_SECRET_KEY);
+        let key = keyring.manifest_key(&digest).unwrap();
+        let signature = sign_manifest(&keyring, &digest, MANIFEST).unwrap();
+        for i in 0..MANIFEST.len() {
+            let mut tampered = MANIFEST.to_vec();
+            tampered[i] ^= 1;
+            assert!(
+                !verify_manifest(&key, &tampered, &signature).unwrap(),
+                "{}",
+                i
+            );
+        }
+        let mut truncated = MANIFEST.to_vec();
+        truncated.pop();
+        assert!(!verify_manifest(&key, &truncated, &signature).unwrap());
+
+        let mut bytes = *signature.as_bytes();
+        bytes[0] ^= 1;
+        let tampered = Signature {
+            digest,
+            signature: bytes,
+        };
+        assert!(!verify_manifest(&key, MANIFEST, &tampered).unwrap());
+    }
+
+    #[test]
+    fn other_identities_fail_verification() {
+        let (keyring, digest) = keyring_with(TEST_SECRET_KEY);
+        let key = keyring.manifest_key(&digest).unwrap();
+        let (other, other_digest) = other_identity();
+        let other_key = other.manifest_key(&other_digest).unwrap();
+        let signature = sign_manifest(&other, &other_digest, MANIFEST).unwrap();
+        assert!(!verify_manifest(&key, MANIFEST, &signature).unwrap());
+
+        // claiming to be the identity doesn't help without its key
+        let forged = Signature {
+            digest,
+            signature: signature.signature,
+        };
+        assert!(!verify_manifest(&key, MANIFEST, &forged).unwrap());
+        // nor does the right signature checked against a key relabeled as the identity
+        let relabeled = ManifestKey {
+            digest,
+            key: other_key.key,
+        };
+        let genuine = sign_manifest(&keyring, &digest, MANIFEST).unwrap();
+        assert!(!verify_manifest(&relabeled, MANIFEST, &genuine).unwrap());
+    }
+
+    #[test]
+    fn locked_and_missing_identities_dont_sign() {
+        let mut keyring = Keyring::in_memory();
+        let key = age::x25519::Identity::generate().to_string();
+        let locked = keyring
+            .import_key_encrypted(key.expose_secret(), "passphrase", None)
+            .unwrap();
+        assert!(sign_manifest(&keyring, &locked, MANIFEST).is_err());
+        let (_, missing) = other_identity();
+        assert!(sign_manifest(&keyring, &missing, MANIFEST).is_err());
+        assert!(keyring.manifest_key(&missing).is_err());
+    }
+
+    #[test]
+    fn signed_batch_reports_verify() {
+        let (keyring, digest) = keyring_with(TEST_SECRET_KEY);
+        let (json, signature) = BatchReport::new()
+            .to_signed_json(&keyring, &digest)
+            .unwrap();
+        let key = keyring.manifest_key(&digest).unwrap();
+        assert!(verify_manifest(&key, &json, &signature).unwrap());
+        assert!(serde_json::from_slice::<serde_json::Value>(&json).is_ok());
+    }
+}
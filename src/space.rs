@@ -0,0 +1,119 @@
+//! The free space check of DecryptOptions::check_free_space.
+
+use crate::error::Error;
+use log::warn;
+use std::{io, path::Path};
+
+/// Added to the estimated output size for the container overhead, sidecars and the
+/// filesystem's own metadata.
+const MARGIN_BYTES: u64 = 16 << 20;
+const MARGIN_PERCENT: u64 = 2;
+
+/// The free space an output estimated at `estimated_size` bytes needs, including the margin.
+/// With faststart, the whole file is written a second time before the first copy is removed.
+pub(crate) fn needed_space(estimated_size: u64, faststart: bool) -> u64 {
+    let copies = if faststart { 2 } else { 1 };
+    let with_margin = estimated_size
+        .saturating_add(estimated_size / 100 * MARGIN_PERCENT)
+        .saturating_add(MARGIN_BYTES);
+    with_margin.saturating_mul(copies)
+}
+
+/// Fails with Error::InsufficientSpace if `available_space` reports less than `needed` bytes
+/// free in `dir`. Passes if it can't tell, with a warning if querying failed.
+pub(crate) fn check_free_space(
+    dir: &Path,
+    needed: u64,
+    available_space: impl Fn(&Path) -> io::Result<Option<u64>>,
+) -> Result<(), Error> {
+    match available_space(dir) {
+        Ok(Some(available)) if available < needed => {
+            Err(Error::InsufficientSpace { needed, available })
+        }
+        Ok(_) => Ok(()),
+        Err(e) => {
+            warn!(
+                "Not checking free space, querying {} failed: {}",
+                dir.display(),
+                e
+            );
+            Ok(())
+        }
+    }
+}
+
+/// The bytes an unprivileged process can still write to the filesystem of `dir`.
+#[cfg(unix)]
+pub(crate) fn available_space(dir: &Path) -> io::Result<Option<u64>> {
+    use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: path is NUL terminated and stat is only read once statvfs() filled it in
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    // the field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    let (blocks, block_size) = (stat.f_bavail as u64, stat.f_frsize as u64);
+    Ok(Some(blocks.saturating_mul(block_size)))
+}
+
+/// Not known on other platforms, the check always passes there.
+#[cfg(not(unix))]
+pub(crate) fn available_space(_dir: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(available: io::Result<Option<u64>>) -> Result<(), Error> {
+        let available = std::cell::RefCell::new(Some(available));
+        check_free_space(Path::new("out"), 1000, |dir| {
+            assert_eq!(dir, Path::new("out"));
+            available.borrow_mut().take().expect("queried once")
+        })
+    }
+
+    #[test]
+    fn too_little_space_fails() {
+        match check(Ok(Some(999))) {
+            Err(Error::InsufficientSpace {
+                needed: 1000,
+                available: 999,
+            }) => {}
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn enough_space_passes() {
+        assert!(check(Ok(Some(1000))).is_ok());
+        assert!(check(Ok(Some(u64::MAX))).is_ok());
+    }
+
+    #[test]
+    fn unknown_space_passes() {
+        assert!(check(Ok(None)).is_ok());
+    }
+
+    #[test]
+    fn failed_queries_pass() {
+        let denied = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+        assert!(check(Err(denied)).is_ok());
+    }
+
+    #[test]
+    fn needed_space_has_a_margin_and_doubles_with_faststart() {
+        assert_eq!(needed_space(0, false), MARGIN_BYTES);
+        assert_eq!(needed_space(100 << 20, false), (102 << 20) + MARGIN_BYTES);
+        assert_eq!(
+            needed_space(100 << 20, true),
+            2 * ((102 << 20) + MARGIN_BYTES)
+        );
+        assert_eq!(needed_space(u64::MAX, true), u64::MAX);
+    }
+}
@@ -0,0 +1,225 @@
+//! Thumbnails of decrypted videos, decoded from the packets while they are muxed.
+//! See DecryptOptions::extract_thumbnail.
+
+use ac_ffmpeg::{
+    codec::{
+        video::{
+            frame::get_pixel_format, scaler::Algorithm, VideoDecoder, VideoEncoder, VideoFrame,
+            VideoFrameMut, VideoFrameScaler,
+        },
+        Decoder, Encoder,
+    },
+    packet::Packet,
+    time::{TimeBase, Timestamp},
+};
+use anyhow::{anyhow, Result};
+use std::{fs, path::Path};
+
+use crate::{
+    decrypt::ProgressCallback,
+    warning::{self, DecryptWarning},
+};
+
+/// Gives up on a thumbnail if this many video packets don't decode to a frame.
+const MAX_PACKETS: usize = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbnailFormat {
+    Jpeg,
+    Png,
+}
+
+impl ThumbnailFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "jpg",
+            ThumbnailFormat::Png => "png",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ThumbnailSpec {
+    /// The longer side of the thumbnail, smaller videos aren't scaled up.
+    pub max_dimension: u32,
+    pub format: ThumbnailFormat,
+    /// Write the thumbnail to `<basename>.thumb.jpg` (or .png) next to the video. It is passed
+    /// to ProgressCallback::on_thumbnail() either way.
+    pub write_file: bool,
+}
+
+impl ThumbnailSpec {
+    pub fn new(max_dimension: u32, format: ThumbnailFormat) -> Self {
+        ThumbnailSpec {
+            max_dimension,
+            format,
+            write_file: true,
+        }
+    }
+}
+
+/// Decodes video packets until the first frame comes out, then encodes it as the thumbnail.
+pub(crate) struct Thumbnailer {
+    spec: ThumbnailSpec,
+    rotation: u16,
+    decoder: Option<VideoDecoder>,
+    packets: usize,
+    result: Option<Result<Vec<u8>>>,
+}
+
+impl Thumbnailer {
+    pub(crate) fn new(codec_name: &str, spec: ThumbnailSpec, rotation: u16) -> Self {
+        let (decoder, result) = match VideoDecoder::new(codec_name) {
+            Ok(decoder) => (Some(decoder), None),
+            Err(e) => (None, Some(Err(anyhow!("Error creating decoder: {}", e)))),
+        };
+        Thumbnailer {
+            spec,
+            rotation,
+            decoder,
+            packets: 0,
+            result,
+        }
+    }
+
+    /// Feeds a video packet to the decoder, until there is a thumbnail or an error.
+    pub(crate) fn push(&mut self, packet: &Packet) {
+        let decoder = match &mut self.decoder {
+            Some(decoder) => decoder,
+            None => return,
+        };
+        self.packets += 1;
+        let frame = decoder
+            .push(packet.clone())
+            .and_then(|()| decoder.take())
+            .map_err(|e| anyhow!("Error decoding video: {}", e));
+        match frame {
+            Ok(None) if self.packets < MAX_PACKETS => return,
+            Ok(None) => {
+                self.result = Some(Err(anyhow!(
+                    "No frame decoded from the first {} video packets",
+                    MAX_PACKETS
+                )))
+            }
+            Ok(Some(frame)) => self.result = Some(self.encode(&frame)),
+            Err(e) => self.result = Some(Err(e)),
+        }
+        self.decoder = None;
+    }
+
+    /// Writes the thumbnail next to the video and passes it to the callback. Not getting a
+    /// thumbnail is only a warning, the video is fine without.
+    pub(crate) fn finish(self, video_path: &Path, progress_callback: &mut dyn ProgressCallback) {
+        let warning = match self.result.transpose() {
+            Ok(Some(data)) => {
+                progress_callback.on_thumbnail(&data, self.spec.format);
+                if !self.spec.write_file {
+                    return;
+                }
+                let path =
+                    video_path.with_extension(format!("thumb.{}", self.spec.format.extension()));
+                match fs::write(&path, &data) {
+                    Ok(()) => return,
+                    Err(e) => format!("Could not write thumbnail {}: {}", path.display(), e),
+                }
+            }
+            Ok(None) => "No video frame to make a thumbnail from".to_owned(),
+            Err(e) => format!("Could not create thumbnail: {}", e),
+        };
+        warning::report(progress_callback, DecryptWarning::ThumbnailFailed(warning));
+    }
+
+    fn encode(&self, frame: &VideoFrame) -> Result<Vec<u8>> {
+        let (width, height) = fit(frame.width(), frame.height(), self.spec.max_dimension);
+        let rgb24 = get_pixel_format("rgb24");
+        let rgb = VideoFrameScaler::builder()
+            .source_pixel_format(frame.pixel_format())
+            .source_width(frame.width())
+            .source_height(frame.height())
+            .target_pixel_format(rgb24)
+            .target_width(width)
+            .target_height(height)
+            .algorithm(Algorithm::Bicubic)
+            .build()?
+            .scale(frame)?;
+        let rgb = rotate(&rgb, self.rotation);
+        let (codec, pixel_format) = match self.spec.format {
+            ThumbnailFormat::Jpeg => ("mjpeg", get_pixel_format("yuvj420p")),
+            ThumbnailFormat::Png => ("png", rgb24),
+        };
+        let time_base = TimeBase::new(1, 25);
+        let frame = if pixel_format == rgb24 {
+            rgb
+        } else {
+            VideoFrameScaler::builder()
+                .source_pixel_format(rgb24)
+                .source_width(rgb.width())
+                .source_height(rgb.height())
+                .target_pixel_format(pixel_format)
+                .target_width(rgb.width())
+                .target_height(rgb.height())
+                .build()?
+                .scale(&rgb)?
+        };
+        let mut encoder = VideoEncoder::builder(codec)?
+            .pixel_format(pixel_format)
+            .width(frame.width())
+            .height(frame.height())
+            .time_base(time_base)
+            .build()?;
+        encoder.push(
+            frame
+                .with_time_base(time_base)
+                .with_pts(Timestamp::new(0, time_base)),
+        )?;
+        encoder.flush()?;
+        let mut data = vec![];
+        while let Some(packet) = encoder.take()? {
+            data.extend_from_slice(packet.data());
+        }
+        Ok(data)
+    }
+}
+
+/// Scales width and height down so neither exceeds max_dimension, keeping the aspect ratio.
+fn fit(width: usize, height: usize, max_dimension: u32) -> (usize, usize) {
+    let max_dimension = (max_dimension as usize).max(1);
+    let longer = width.max(height);
+    if longer <= max_dimension {
+        return (width, height);
+    }
+    let scale = |side: usize| (side * max_dimension / longer).max(1);
+    (scale(width), scale(height))
+}
+
+/// Rotates an rgb24 frame clockwise by the rotation from the metadata.
+fn rotate(frame: &VideoFrame, rotation: u16) -> VideoFrame {
+    let (width, height) = (frame.width(), frame.height());
+    let quarter_turns = (rotation % 360) / 90;
+    if quarter_turns == 0 {
+        return frame.clone();
+    }
+    let (rotated_width, rotated_height) = if quarter_turns % 2 == 1 {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    let mut rotated = VideoFrameMut::black(frame.pixel_format(), rotated_width, rotated_height);
+    let planes = frame.planes();
+    let source = &planes[0];
+    let mut rotated_planes = rotated.planes_mut();
+    let target = &mut rotated_planes[0];
+    for y in 0..height {
+        let line = source.line(y).unwrap();
+        for x in 0..width {
+            let (tx, ty) = match quarter_turns {
+                1 => (height - 1 - y, x),
+                2 => (width - 1 - x, height - 1 - y),
+                _ => (y, width - 1 - x),
+            };
+            target.line_mut(ty).unwrap()[tx * 3..tx * 3 + 3]
+                .copy_from_slice(&line[x * 3..x * 3 + 3]);
+        }
+    }
+    rotated.freeze()
+}
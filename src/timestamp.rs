@@ -0,0 +1,53 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeZone};
+use log::warn;
+use std::{fs::File, path::Path, time::SystemTime};
+
+/// Parses the ISO 8601 recording timestamp stored in the file metadata.
+/// Timestamps without a timezone are assumed to be in the local time of the machine
+/// doing the decryption, which is right as long as it is in the same zone as the phone was.
+pub(crate) fn parse_timestamp(timestamp: &str) -> Result<DateTime<FixedOffset>> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(timestamp) {
+        return Ok(t);
+    }
+    let naive = ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(timestamp, format).ok())
+        .ok_or_else(|| anyhow!("Invalid timestamp {}", timestamp))?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|t| t.fixed_offset())
+        .ok_or_else(|| anyhow!("Timestamp {} does not exist in local time", timestamp))
+}
+
+/// Formats a timestamp the way FFmpeg expects creation_time, e.g. 2021-05-01T13:37:00.000000Z
+#[cfg(feature = "video")]
+pub(crate) fn to_creation_time(timestamp: &DateTime<FixedOffset>) -> String {
+    timestamp
+        .with_timezone(&chrono::Utc)
+        .to_rfc3339_opts(chrono::SecondsFormat::Micros, true)
+}
+
+/// Sets the modification time of a finished output file to the recording timestamp.
+/// Failures only log a warning, the output itself is fine either way.
+pub(crate) fn set_mtime(path: &Path, timestamp: &str) {
+    let time: SystemTime = match parse_timestamp(timestamp) {
+        Ok(t) => t.into(),
+        Err(e) => {
+            warn!("Not setting modification time of {}: {}", path.display(), e);
+            return;
+        }
+    };
+    if let Err(e) = File::options()
+        .write(true)
+        .open(path)
+        .and_then(|f| f.set_modified(time))
+    {
+        warn!(
+            "Could not set modification time of {}: {}",
+            path.display(),
+            e
+        );
+    }
+}
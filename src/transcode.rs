@@ -0,0 +1,314 @@
+//! Re-encoding the video stream while decrypting, see DecryptOptions::transcode.
+
+#[cfg(feature = "transcode")]
+use ac_ffmpeg::{
+    codec::{
+        video::{
+            frame::{get_pixel_format, PixelFormat},
+            VideoDecoder, VideoEncoder, VideoFrame, VideoFrameMut, VideoFrameScaler,
+        },
+        CodecParameters, Decoder, Encoder,
+    },
+    format::muxer::Muxer,
+    packet::Packet,
+    time::TimeBase,
+};
+#[cfg(feature = "transcode")]
+use anyhow::{anyhow, Result};
+use std::fmt;
+#[cfg(feature = "transcode")]
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// The encoder for transcoded video. Audio is copied as it is.
+#[derive(Clone)]
+pub struct TranscodeSpec {
+    /// FFmpeg encoder name, e.g. "libx264".
+    pub codec: String,
+    /// Bits per second, the bitrate of the recording if not set.
+    pub bit_rate: Option<u64>,
+    /// The encoder's preset option, e.g. "fast" for libx264.
+    pub preset: Option<String>,
+    /// Makes the FrameProcessor every decoded frame goes through before it is encoded, one per
+    /// job. None by default.
+    #[cfg(feature = "transcode")]
+    pub frame_processor: Option<Arc<dyn Fn() -> Box<dyn FrameProcessor> + Send + Sync>>,
+}
+
+impl fmt::Debug for TranscodeSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("TranscodeSpec");
+        debug
+            .field("codec", &self.codec)
+            .field("bit_rate", &self.bit_rate)
+            .field("preset", &self.preset);
+        #[cfg(feature = "transcode")]
+        debug.field(
+            "frame_processor",
+            &self.frame_processor.as_ref().map(|_| "Callback"),
+        );
+        debug.finish()
+    }
+}
+
+/// Changes the decoded frames of a transcoded video before they are encoded, e.g. to burn in
+/// the time like TimestampBurnIn. Frames are yuv420p at the size of the recording.
+#[cfg(feature = "transcode")]
+pub trait FrameProcessor: Send {
+    /// Called once before the first frame with the timestamp from the video's metadata, ISO
+    /// 8601 with or without a timezone.
+    fn start(&mut self, _timestamp: &str) {}
+
+    /// Whether process() is called for the frame at `pts_us`, frames it isn't called for are
+    /// encoded as they are, without being copied. True by default.
+    fn wants(&mut self, _pts_us: i64) -> bool {
+        true
+    }
+
+    /// Changes the frame in place. `pts_us` counts from the first packet of the recording. A
+    /// panic fails the job.
+    fn process(&mut self, frame: &mut VideoFrameMut, pts_us: i64);
+}
+
+impl TranscodeSpec {
+    pub fn new(codec: impl Into<String>) -> Self {
+        TranscodeSpec {
+            codec: codec.into(),
+            bit_rate: None,
+            preset: None,
+            #[cfg(feature = "transcode")]
+            frame_processor: None,
+        }
+    }
+
+    pub fn bit_rate(mut self, bit_rate: u64) -> Self {
+        self.bit_rate = Some(bit_rate);
+        self
+    }
+
+    pub fn preset(mut self, preset: impl Into<String>) -> Self {
+        self.preset = Some(preset.into());
+        self
+    }
+
+    /// `make` is called for every video the spec transcodes.
+    #[cfg(feature = "transcode")]
+    pub fn frame_processor(
+        mut self,
+        make: impl Fn() -> Box<dyn FrameProcessor> + Send + Sync + 'static,
+    ) -> Self {
+        self.frame_processor = Some(Arc::new(make));
+        self
+    }
+}
+
+/// Decodes the video packets of the recording and encodes the frames for the muxer.
+#[cfg(feature = "transcode")]
+pub(crate) struct Transcoder {
+    decoder: VideoDecoder,
+    encoder: VideoEncoder,
+    /// What the encoder takes, decoded frames are converted if they don't match.
+    pixel_format: PixelFormat,
+    width: usize,
+    height: usize,
+    scaler: Option<VideoFrameScaler>,
+    frame_processor: Option<Box<dyn FrameProcessor>>,
+    stream_index: usize,
+}
+
+#[cfg(feature = "transcode")]
+impl Transcoder {
+    /// `timestamp` is from the metadata, for the FrameProcessor.
+    pub(crate) fn new(
+        input_codec: &str,
+        spec: &TranscodeSpec,
+        width: usize,
+        height: usize,
+        bit_rate: u64,
+        timestamp: &str,
+    ) -> Result<Self> {
+        let pixel_format = get_pixel_format("yuv420p");
+        let decoder = VideoDecoder::new(input_codec)
+            .map_err(|e| anyhow!("Error creating {} decoder: {}", input_codec, e))?;
+        let mut builder = VideoEncoder::builder(&spec.codec)
+            .map_err(|e| anyhow!("Error creating {} encoder: {}", spec.codec, e))?
+            .width(width)
+            .height(height)
+            .pixel_format(pixel_format)
+            .bit_rate(spec.bit_rate.unwrap_or(bit_rate))
+            .time_base(TimeBase::MICROSECONDS)
+            // MP4 and Matroska keep the parameter sets in the stream header
+            .set_option("flags", "+global_header");
+        if let Some(preset) = &spec.preset {
+            builder = builder.set_option("preset", preset);
+        }
+        let encoder = builder
+            .build()
+            .map_err(|e| anyhow!("Error opening {} encoder: {}", spec.codec, e))?;
+        let frame_processor = match &spec.frame_processor {
+            Some(make) => {
+                let mut processor = make();
+                processor.start(timestamp);
+                Some(processor)
+            }
+            None => None,
+        };
+        Ok(Transcoder {
+            decoder,
+            encoder,
+            pixel_format,
+            width,
+            height,
+            scaler: None,
+            frame_processor,
+            stream_index: 0,
+        })
+    }
+
+    /// Parameters of the encoded stream, for adding it to the muxer.
+    pub(crate) fn codec_parameters(&self) -> CodecParameters {
+        self.encoder.codec_parameters().into()
+    }
+
+    pub(crate) fn set_stream_index(&mut self, stream_index: usize) {
+        self.stream_index = stream_index;
+    }
+
+    /// Decodes a packet and pushes what the encoder makes of its frames to the muxer, or flushes
+    /// both at the end of the stream if `packet` is None. Stops between frames when cancelled.
+    pub(crate) fn push<T>(
+        &mut self,
+        packet: Option<Packet>,
+        muxer: &mut Muxer<T>,
+        cancel: &AtomicBool,
+    ) -> Result<()> {
+        let flush = packet.is_none();
+        match packet {
+            Some(packet) => self.decoder.push(packet),
+            None => self.decoder.flush(),
+        }
+        .map_err(|e| anyhow!("Error decoding video: {}", e))?;
+        while let Some(frame) = self
+            .decoder
+            .take()
+            .map_err(|e| anyhow!("Error decoding video: {}", e))?
+        {
+            if cancel.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            let mut frame = self.convert(frame)?;
+            if let Some(processor) = &mut self.frame_processor {
+                frame = process_frame(&mut **processor, frame)?;
+            }
+            self.encoder
+                .push(frame)
+                .map_err(|e| anyhow!("Error encoding video: {}", e))?;
+            self.drain(muxer)?;
+        }
+        if flush {
+            self.encoder
+                .flush()
+                .map_err(|e| anyhow!("Error encoding video: {}", e))?;
+            self.drain(muxer)?;
+        }
+        Ok(())
+    }
+
+    fn convert(&mut self, frame: VideoFrame) -> Result<VideoFrame> {
+        let (format, width, height) = (self.pixel_format, self.width, self.height);
+        if frame.pixel_format() == format && frame.width() == width && frame.height() == height {
+            return Ok(frame);
+        }
+        if self.scaler.is_none() {
+            self.scaler = Some(
+                VideoFrameScaler::builder()
+                    .source_pixel_format(frame.pixel_format())
+                    .source_width(frame.width())
+                    .source_height(frame.height())
+                    .target_pixel_format(format)
+                    .target_width(width)
+                    .target_height(height)
+                    .build()?,
+            );
+        }
+        let scaled = self.scaler.as_mut().unwrap().scale(&frame)?;
+        Ok(scaled.with_pts(frame.pts()))
+    }
+
+    fn drain<T>(&mut self, muxer: &mut Muxer<T>) -> Result<()> {
+        while let Some(packet) = self
+            .encoder
+            .take()
+            .map_err(|e| anyhow!("Error encoding video: {}", e))?
+        {
+            muxer.push(packet.with_stream_index(self.stream_index))?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs `processor` on a copy of `frame` if it wants it, turning a panic into an error.
+#[cfg(feature = "transcode")]
+fn process_frame(processor: &mut dyn FrameProcessor, frame: VideoFrame) -> Result<VideoFrame> {
+    if frame.pts().is_null() {
+        return Ok(frame);
+    }
+    let pts_us = frame
+        .pts()
+        .with_time_base(TimeBase::MICROSECONDS)
+        .timestamp();
+    let processed = panic::catch_unwind(AssertUnwindSafe(|| {
+        if !processor.wants(pts_us) {
+            return None;
+        }
+        let mut copy = writable_copy(&frame);
+        processor.process(&mut copy, pts_us);
+        Some(copy)
+    }));
+    match processed {
+        Ok(None) => Ok(frame),
+        Ok(Some(copy)) => Ok(copy.freeze().with_pts(frame.pts())),
+        Err(panic) => Err(anyhow!(
+            "The frame processor panicked at {} us: {}",
+            pts_us,
+            panic_message(&*panic)
+        )),
+    }
+}
+
+/// The decoder's frames are shared with it, so they are copied before being changed.
+#[cfg(feature = "transcode")]
+fn writable_copy(frame: &VideoFrame) -> VideoFrameMut {
+    let mut copy = VideoFrameMut::black(frame.pixel_format(), frame.width(), frame.height());
+    let planes = frame.planes();
+    let mut copy_planes = copy.planes_mut();
+    // Y, U and V of the yuv420p frames convert() returns
+    for plane in 0..3 {
+        let mut y = 0;
+        while let (Some(line), Some(copy_line)) =
+            (planes[plane].line(y), copy_planes[plane].line_mut(y))
+        {
+            let len = line.len().min(copy_line.len());
+            copy_line[..len].copy_from_slice(&line[..len]);
+            y += 1;
+        }
+    }
+    copy
+}
+
+#[cfg(feature = "transcode")]
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
@@ -0,0 +1,201 @@
+//! Jobs for DecryptOptions::verify_only: the input is decrypted and checked like for writing
+//! it out, but nothing is written.
+
+use crate::cancel::CancellableReader;
+use crate::decrypt::{DecryptingJob, ProgressCallback};
+use crate::decrypt_image::{normalize_format, sniff_format};
+use crate::error::read_error;
+use crate::progress::InputPosition;
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+use crate::{
+    decrypt::DecryptOptions,
+    packet::{PacketKind, PacketReader},
+    warning::{self, DecryptWarning},
+};
+use std::{
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// What verifying a file found, passed to ProgressCallback::on_verified().
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerificationReport {
+    Video {
+        packets_video: u64,
+        packets_audio: u64,
+        bytes_video: u64,
+        bytes_audio: u64,
+        /// From the first to the last timestamp of all packets.
+        duration_us: u64,
+        /// The stream ended in the middle of a packet, see DecryptOptions::finalize_on_truncation.
+        truncated: bool,
+    },
+    Image {
+        bytes: u64,
+        /// Whether the format in the metadata matches the one recognized from the data.
+        /// Also false for formats that aren't recognized.
+        format_matches_magic: bool,
+    },
+}
+
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+pub(crate) struct VideoVerifyJob {
+    data: Box<dyn Read + Send>,
+    total_file_size: u64,
+    input_position: InputPosition,
+    options: DecryptOptions,
+}
+
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+impl VideoVerifyJob {
+    pub(crate) fn new(
+        data: Box<dyn Read + Send>,
+        total_file_size: u64,
+        input_position: InputPosition,
+        options: DecryptOptions,
+    ) -> Self {
+        VideoVerifyJob {
+            data,
+            total_file_size,
+            input_position,
+            options,
+        }
+    }
+}
+
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+impl DecryptingJob for VideoVerifyJob {
+    fn run(&mut self, progress_callback: Box<&mut dyn ProgressCallback>, cancel: Arc<AtomicBool>) {
+        progress_callback.set_total_file_size(self.total_file_size);
+        let mut data = CancellableReader::new(&mut self.data, &cancel);
+        let mut packets = PacketReader::new(&mut data)
+            .resync_on_error(self.options.resync_on_error)
+            .max_packet_len(self.options.max_packet_len);
+        let (mut packets_video, mut packets_audio) = (0, 0);
+        let (mut bytes_video, mut bytes_audio) = (0, 0);
+        let mut pts_range: Option<(u64, u64)> = None;
+        let mut truncated = false;
+        let mut buf = Vec::new();
+        loop {
+            if cancel.load(Ordering::Relaxed) {
+                return;
+            }
+            let next = packets.next_header();
+            if let Some(skipped) = packets.take_skipped() {
+                warning::report(
+                    &mut **progress_callback,
+                    DecryptWarning::CorruptDataSkipped {
+                        offset: skipped.offset,
+                        len: skipped.len,
+                    },
+                );
+            }
+            let result = match next {
+                None => break,
+                Some(Ok(header)) => {
+                    buf.resize(header.length, 0);
+                    packets.read_data(&mut buf).map(|()| header)
+                }
+                Some(Err(e)) => Err(e),
+            };
+            let header = match result {
+                Ok(header) => header,
+                Err(_) if cancel.load(Ordering::Relaxed) => return,
+                Err(e) if self.options.finalize_on_truncation && e.is_truncation() => {
+                    truncated = true;
+                    break;
+                }
+                Err(e) => {
+                    progress_callback.on_error(e.into_job_error());
+                    return;
+                }
+            };
+            match header.kind {
+                PacketKind::Video => {
+                    packets_video += 1;
+                    bytes_video += header.length as u64;
+                }
+                PacketKind::Audio => {
+                    packets_audio += 1;
+                    bytes_audio += header.length as u64;
+                }
+                PacketKind::Unknown(_) => continue,
+            }
+            pts_range = Some(match pts_range {
+                None => (header.pts_us, header.pts_us),
+                Some((first, last)) => (first.min(header.pts_us), last.max(header.pts_us)),
+            });
+            progress_callback.on_progress(self.input_position.get());
+        }
+        progress_callback.on_progress(self.input_position.get());
+        if truncated {
+            progress_callback.on_truncated(self.input_position.get());
+        }
+        progress_callback.on_verified(&VerificationReport::Video {
+            packets_video,
+            packets_audio,
+            bytes_video,
+            bytes_audio,
+            duration_us: pts_range.map_or(0, |(first, last)| last - first),
+            truncated,
+        });
+        progress_callback.on_complete();
+    }
+}
+
+pub(crate) struct ImageVerifyJob {
+    data: Box<dyn Read>,
+    declared_format: String,
+    total_file_size: u64,
+    input_position: InputPosition,
+}
+
+impl ImageVerifyJob {
+    pub(crate) fn new(
+        data: Box<dyn Read>,
+        declared_format: String,
+        total_file_size: u64,
+        input_position: InputPosition,
+    ) -> Self {
+        ImageVerifyJob {
+            data,
+            declared_format,
+            total_file_size,
+            input_position,
+        }
+    }
+}
+
+unsafe impl Send for ImageVerifyJob {}
+
+impl DecryptingJob for ImageVerifyJob {
+    fn run(&mut self, progress_callback: Box<&mut dyn ProgressCallback>, cancel: Arc<AtomicBool>) {
+        progress_callback.set_total_file_size(self.total_file_size);
+        let mut data = CancellableReader::new(&mut self.data, &cancel);
+        let mut head = Vec::with_capacity(16);
+        let rest = (&mut data)
+            .take(16)
+            .read_to_end(&mut head)
+            .and_then(|_| io::copy(&mut data, &mut io::sink()));
+        let rest = match rest {
+            Ok(rest) => rest,
+            Err(_) if cancel.load(Ordering::Relaxed) => return,
+            Err(e) => {
+                progress_callback.on_error(read_error(e));
+                return;
+            }
+        };
+        progress_callback.on_progress(self.input_position.get());
+        let sniffed = sniff_format(&head);
+        let format_matches_magic =
+            sniffed.is_some() && sniffed == normalize_format(&self.declared_format);
+        progress_callback.on_verified(&VerificationReport::Image {
+            bytes: head.len() as u64 + rest,
+            format_matches_magic,
+        });
+        progress_callback.on_complete();
+    }
+}
@@ -0,0 +1,280 @@
+use crate::{decrypt::ProgressCallback, packet::PacketKind};
+use log::warn;
+use std::fmt;
+
+/// Something that was off with a file while decryption went on, see
+/// ProgressCallback::on_warning(). Displays as a message for the user. PTS are in microseconds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DecryptWarning {
+    /// A packet's PTS wasn't after the previous one of its stream, see NonMonotonicPts::Bump.
+    TimestampAdjusted {
+        stream: PacketKind,
+        pts: i64,
+        previous: i64,
+        adjusted: i64,
+    },
+    /// The PTS of a stream jumped back by `back_us`, like after a clock change. The packet
+    /// and everything after it in the stream was moved to continue from `adjusted`.
+    TimestampJump {
+        stream: PacketKind,
+        pts: i64,
+        back_us: i64,
+        adjusted: i64,
+    },
+    /// A packet's PTS wasn't after the previous one of its stream, see NonMonotonicPts::Drop.
+    PacketDropped {
+        stream: PacketKind,
+        pts: i64,
+        previous: i64,
+    },
+    /// A packet with a PTS too large to be used was skipped.
+    InvalidPtsSkipped { stream: PacketKind, pts: u64 },
+    /// A packet written by a newer version of the app was skipped.
+    UnknownPacketTypeSkipped(u8),
+    /// Damaged video data was skipped to find the next packet,
+    /// see DecryptOptions::resync_on_error.
+    CorruptDataSkipped { offset: u64, len: u64 },
+    /// The video stream ended early, the output has the packets before `processed`,
+    /// see DecryptOptions::finalize_on_truncation.
+    TruncatedStream { processed: u64, reason: String },
+    /// The image contains a different format than declared, see ImageFormatMismatch.
+    FormatMismatch { declared: String, detected: String },
+    /// The image contents aren't recognized, the declared format is kept.
+    UnrecognizedImage { declared: String },
+    /// Neither the declared format nor the contents are recognized, saved as .bin.
+    UnknownImageFormat { declared: String },
+    /// The audio channel layout is unknown or missing, a guess is used. `stereo` if that
+    /// guess is stereo because there's no default layout for the channel count either.
+    ChannelLayoutGuessed {
+        layout: Option<String>,
+        channel_count: u32,
+        stereo: bool,
+    },
+    /// The video's rotation isn't 0, 90, 180 or 270 degrees, 0 is used instead.
+    InvalidRotation(u16),
+    /// The location in the metadata isn't valid and is left out of the output.
+    InvalidLocation(String),
+    /// The recording's timestamp can't be parsed, the output has no creation time.
+    InvalidTimestamp { timestamp: String, reason: String },
+    /// The codec specific data in the metadata isn't valid base64, the codec configuration is
+    /// taken from the stream instead.
+    InvalidCodecConfig { stream: PacketKind, reason: String },
+    /// No thumbnail for the video, see DecryptOptions::extract_thumbnail.
+    ThumbnailFailed(String),
+    /// The metadata can't be parsed, the payload was written as it is, see
+    /// DecryptOptions::recover_raw_on_bad_metadata.
+    MetadataUnreadable { reason: String },
+    /// The output failed DecryptOptions::check_output, reported instead of an error with
+    /// DecryptOptions::lenient.
+    CorruptOutput { reason: String },
+    /// Other files of the batch were recorded in the same second, the output name has the
+    /// milliseconds of the timestamp to tell them apart, see BatchNames.
+    MillisecondsAppended { timestamp: String },
+}
+
+impl DecryptWarning {
+    /// The variant in snake case, e.g. "truncated_stream", for machine readable output like
+    /// JsonlProgress.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DecryptWarning::TimestampAdjusted { .. } => "timestamp_adjusted",
+            DecryptWarning::TimestampJump { .. } => "timestamp_jump",
+            DecryptWarning::PacketDropped { .. } => "packet_dropped",
+            DecryptWarning::InvalidPtsSkipped { .. } => "invalid_pts_skipped",
+            DecryptWarning::UnknownPacketTypeSkipped(_) => "unknown_packet_type_skipped",
+            DecryptWarning::CorruptDataSkipped { .. } => "corrupt_data_skipped",
+            DecryptWarning::TruncatedStream { .. } => "truncated_stream",
+            DecryptWarning::FormatMismatch { .. } => "format_mismatch",
+            DecryptWarning::UnrecognizedImage { .. } => "unrecognized_image",
+            DecryptWarning::UnknownImageFormat { .. } => "unknown_image_format",
+            DecryptWarning::ChannelLayoutGuessed { .. } => "channel_layout_guessed",
+            DecryptWarning::InvalidRotation(_) => "invalid_rotation",
+            DecryptWarning::InvalidLocation(_) => "invalid_location",
+            DecryptWarning::InvalidTimestamp { .. } => "invalid_timestamp",
+            DecryptWarning::InvalidCodecConfig { .. } => "invalid_codec_config",
+            DecryptWarning::ThumbnailFailed(_) => "thumbnail_failed",
+            DecryptWarning::MetadataUnreadable { .. } => "metadata_unreadable",
+            DecryptWarning::CorruptOutput { .. } => "corrupt_output",
+            DecryptWarning::MillisecondsAppended { .. } => "milliseconds_appended",
+        }
+    }
+}
+
+impl fmt::Display for DecryptWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecryptWarning::TimestampAdjusted {
+                stream,
+                pts,
+                previous,
+                adjusted,
+            } => write!(
+                f,
+                "{:?} stream: PTS {} us not after {} us, using {} us",
+                stream, pts, previous, adjusted
+            ),
+            DecryptWarning::TimestampJump {
+                stream,
+                pts,
+                back_us,
+                adjusted,
+            } => write!(
+                f,
+                "{:?} stream: timestamps jump back by {} ms at {} us, continuing from {} us",
+                stream,
+                back_us / 1000,
+                pts,
+                adjusted
+            ),
+            DecryptWarning::PacketDropped {
+                stream,
+                pts,
+                previous,
+            } => write!(
+                f,
+                "{:?} stream: dropped packet with PTS {} us, not after {} us",
+                stream, pts, previous
+            ),
+            DecryptWarning::InvalidPtsSkipped { stream, pts } => write!(
+                f,
+                "{:?} stream: skipped packet with invalid PTS {:#x}",
+                stream, pts
+            ),
+            DecryptWarning::UnknownPacketTypeSkipped(packet_type) => {
+                write!(f, "Skipping packet of unknown type {}", packet_type)
+            }
+            DecryptWarning::CorruptDataSkipped { offset, len } => write!(
+                f,
+                "Skipped {} bytes of corrupt video data after {} bytes",
+                len, offset
+            ),
+            DecryptWarning::TruncatedStream { reason, .. } => {
+                write!(f, "{}, keeping the packets before", reason)
+            }
+            DecryptWarning::FormatMismatch { declared, detected } => write!(
+                f,
+                "Image declared as {} contains {} data",
+                declared, detected
+            ),
+            DecryptWarning::UnrecognizedImage { declared } => write!(
+                f,
+                "Image contents don't look like the declared format {}",
+                declared
+            ),
+            DecryptWarning::UnknownImageFormat { declared } => write!(
+                f,
+                "Unknown image format {} and unrecognized contents, saving as .bin",
+                declared
+            ),
+            DecryptWarning::ChannelLayoutGuessed {
+                layout,
+                channel_count,
+                stereo,
+            } => match (layout, stereo) {
+                (Some(layout), false) => write!(
+                    f,
+                    "Unknown audio channel layout \"{}\", using the default for {} channels",
+                    layout, channel_count
+                ),
+                (Some(layout), true) => write!(
+                    f,
+                    "Unknown audio channel layout \"{}\" with {} channels, using stereo",
+                    layout, channel_count
+                ),
+                (None, _) => write!(
+                    f,
+                    "No audio channel layout for {} channels, using stereo",
+                    channel_count
+                ),
+            },
+            DecryptWarning::InvalidRotation(rotation) => {
+                write!(f, "Invalid rotation of {} degrees, not rotating", rotation)
+            }
+            DecryptWarning::InvalidLocation(reason) => {
+                write!(f, "Leaving out the location, {}", reason)
+            }
+            DecryptWarning::InvalidTimestamp { timestamp, reason } => write!(
+                f,
+                "Not setting creation_time, invalid timestamp {}: {}",
+                timestamp, reason
+            ),
+            DecryptWarning::InvalidCodecConfig { stream, reason } => write!(
+                f,
+                "{:?} stream: invalid codec specific data in metadata, {}",
+                stream, reason
+            ),
+            DecryptWarning::ThumbnailFailed(reason) => write!(f, "{}", reason),
+            DecryptWarning::MetadataUnreadable { reason } => write!(
+                f,
+                "Unreadable metadata, writing the payload without conversion: {}",
+                reason
+            ),
+            DecryptWarning::CorruptOutput { reason } => {
+                write!(f, "The output may not play: {}", reason)
+            }
+            DecryptWarning::MillisecondsAppended { timestamp } => write!(
+                f,
+                "Other files of the batch were recorded in the same second as {}, adding the \
+                 milliseconds to the output name",
+                timestamp
+            ),
+        }
+    }
+}
+
+/// Logs the warning and passes it on to the callback.
+pub(crate) fn report(progress_callback: &mut dyn ProgressCallback, warning: DecryptWarning) {
+    warn!("{}", warning);
+    progress_callback.on_warning(warning);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Warnings(Vec<DecryptWarning>);
+
+    impl ProgressCallback for Warnings {
+        fn set_total_file_size(&mut self, _: u64) {}
+        fn on_progress(&mut self, _: u64) {}
+        fn on_complete(&mut self) {}
+        fn on_error(&mut self, _: Box<dyn std::error::Error>) {}
+        fn on_warning(&mut self, warning: DecryptWarning) {
+            self.0.push(warning);
+        }
+    }
+
+    #[test]
+    fn reported_warnings_reach_the_callback_as_they_are() {
+        let warnings = [
+            DecryptWarning::UnknownPacketTypeSkipped(7),
+            DecryptWarning::CorruptDataSkipped {
+                offset: 1050,
+                len: 100,
+            },
+        ];
+        let mut callback = Warnings::default();
+        for warning in warnings.clone() {
+            report(&mut callback, warning);
+        }
+        assert_eq!(callback.0, warnings);
+    }
+
+    #[test]
+    fn skipped_packets_and_data_are_described() {
+        let unknown = DecryptWarning::UnknownPacketTypeSkipped(7);
+        assert_eq!(unknown.kind(), "unknown_packet_type_skipped");
+        assert_eq!(unknown.to_string(), "Skipping packet of unknown type 7");
+        let corrupt = DecryptWarning::CorruptDataSkipped {
+            offset: 1050,
+            len: 100,
+        };
+        assert_eq!(corrupt.kind(), "corrupt_data_skipped");
+        assert_eq!(
+            corrupt.to_string(),
+            "Skipped 100 bytes of corrupt video data after 1050 bytes"
+        );
+    }
+}
@@ -0,0 +1,710 @@
+//! Decrypting files as they appear in a directory, e.g. one the phone's output is synced to,
+//! see watch_dir(). Built with the watch feature.
+
+#[cfg(feature = "thumbnail")]
+use crate::thumbnail::ThumbnailFormat;
+use crate::{
+    batch::{execute, plan, BatchEntry, BatchOptions, PlannedAction},
+    decrypt::{CancellationToken, DecryptStats, JobResult, ProgressCallback},
+    hash::HashDigest,
+    keyring::{IdentityInfo, Keyring},
+    output_path::absolutize_output_dir,
+    packet::PacketKind,
+    scan::{scan_dir, scan_file},
+    verify::VerificationReport,
+    warning::DecryptWarning,
+};
+use anyhow::Result;
+use log::{debug, warn};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How often waiting files are checked, at most and at least.
+const MAX_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+const MIN_CHECK_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How watch_dir() waits for files and decrypts them. Built from WatchOptions::new() or
+/// default() with the setters below, new options may be added in any release.
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct WatchOptions {
+    /// How the files are decrypted, like with batch::plan() and execute(). Each file is a
+    /// batch of its own, so DecryptOptions::batch_names doesn't apply.
+    pub batch_options: BatchOptions,
+    /// How long the size and modification time of a new file have to stay the same before it
+    /// is decrypted. Phones and sync tools write files in pieces, with pauses in between. 2
+    /// seconds by default.
+    pub settle_time: Duration,
+    /// Files that are still changing this long after they appeared are given up on with
+    /// WatchEvent::Unstable. 10 minutes by default.
+    pub stabilize_timeout: Duration,
+    /// Watch the subdirectories of the directory too. Off by default.
+    pub recursive: bool,
+    /// Also decrypt the files that are in the directory when watching starts, not only new
+    /// ones. Off by default.
+    pub include_existing: bool,
+    /// Called for every file that is decrypted, the ProgressCallback gets the events of its
+    /// job along with the BatchEntry of WatchEvent::Finished.
+    pub progress_callback: Option<Arc<dyn Fn(&Path) -> Box<dyn ProgressCallback> + Send + Sync>>,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        WatchOptions {
+            batch_options: BatchOptions::default(),
+            settle_time: Duration::from_secs(2),
+            stabilize_timeout: Duration::from_secs(10 * 60),
+            recursive: false,
+            include_existing: false,
+            progress_callback: None,
+        }
+    }
+}
+
+impl fmt::Debug for WatchOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WatchOptions")
+            .field("batch_options", &self.batch_options)
+            .field("settle_time", &self.settle_time)
+            .field("stabilize_timeout", &self.stabilize_timeout)
+            .field("recursive", &self.recursive)
+            .field("include_existing", &self.include_existing)
+            .field(
+                "progress_callback",
+                &self.progress_callback.as_ref().map(|_| "Callback"),
+            )
+            .finish()
+    }
+}
+
+// setters for the fields above, see there
+impl WatchOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn batch_options(mut self, batch_options: BatchOptions) -> Self {
+        self.batch_options = batch_options;
+        self
+    }
+
+    pub fn settle_time(mut self, settle_time: Duration) -> Self {
+        self.settle_time = settle_time;
+        self
+    }
+
+    pub fn stabilize_timeout(mut self, stabilize_timeout: Duration) -> Self {
+        self.stabilize_timeout = stabilize_timeout;
+        self
+    }
+
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    pub fn include_existing(mut self, include_existing: bool) -> Self {
+        self.include_existing = include_existing;
+        self
+    }
+
+    pub fn progress_callback(
+        mut self,
+        make: impl Fn(&Path) -> Box<dyn ProgressCallback> + Send + Sync + 'static,
+    ) -> Self {
+        self.progress_callback = Some(Arc::new(make));
+        self
+    }
+}
+
+/// What happens in a watched directory, see WatchHandle::next_event().
+#[derive(Debug, Clone)]
+pub enum WatchEvent {
+    /// A file of the batch ended, the entry has the error if it failed. Files whose output
+    /// exists already, see BatchOptions::skip_existing_by_hash, end up here too, with
+    /// BatchEntry::skipped set.
+    Finished(BatchEntry),
+    /// A Cryptocam file that is not decrypted, e.g. because its key isn't in the keyring.
+    NotDecrypted {
+        input: PathBuf,
+        action: PlannedAction,
+    },
+    /// A file went away before it stopped changing, e.g. the temporary file of a sync tool or
+    /// a conflict copy that was removed again.
+    Disappeared(PathBuf),
+    /// A file kept changing for WatchOptions::stabilize_timeout and was given up on. It is
+    /// waited for again if it changes once more.
+    Unstable(PathBuf),
+    /// Watching the directory or planning a file failed, watching goes on.
+    Error(String),
+}
+
+/// The directory watched by watch_dir(). Dropping the handle stops watching like stop().
+pub struct WatchHandle {
+    events: Receiver<WatchEvent>,
+    cancel: CancellationToken,
+    watcher: Option<RecommendedWatcher>,
+    thread: Option<JoinHandle<Keyring>>,
+}
+
+impl WatchHandle {
+    /// The next event, waiting for it. None once watching has stopped and all events were
+    /// received.
+    pub fn next_event(&self) -> Option<WatchEvent> {
+        self.events.recv().ok()
+    }
+
+    /// Like next_event(), None if nothing happened within `timeout`.
+    pub fn next_event_timeout(&self, timeout: Duration) -> Option<WatchEvent> {
+        self.events.recv_timeout(timeout).ok()
+    }
+
+    /// Stops watching and returns the keyring. The job that is running is cancelled and its
+    /// partial output removed, files that were still changing are left alone. Events that
+    /// weren't received are dropped.
+    pub fn stop(mut self) -> Keyring {
+        self.cancel.cancel();
+        self.watcher = None;
+        let thread = self.thread.take().unwrap();
+        thread
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+}
+
+impl Drop for WatchHandle {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        self.watcher = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Watches `dir` for new files and decrypts the Cryptocam files among them into `out_dir` once
+/// they stop changing, see WatchOptions::settle_time, on a thread of its own. Files are
+/// recognized by their content like with scan_dir(), each is planned and run with
+/// batch::plan() and execute(). A file is decrypted again if it changes after it was
+/// decrypted, a file that is removed and appears again counts as new.
+///
+/// The keyring is used by the watching thread, so locked identities are unlocked through its
+/// PassphraseProvider there. WatchHandle::stop() gives it back. Events pile up until they are
+/// received with WatchHandle::next_event().
+pub fn watch_dir(
+    dir: &Path,
+    keyring: Keyring,
+    out_dir: &Path,
+    options: WatchOptions,
+) -> Result<WatchHandle> {
+    options.batch_options.decrypt_options.validate()?;
+    let dir = dir.canonicalize()?;
+    let out_dir = absolutize_output_dir(out_dir.to_path_buf())?;
+    let (notifications_tx, notifications) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(notifications_tx)?;
+    let mode = if options.recursive {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
+    watcher.watch(&dir, mode)?;
+
+    let (events_tx, events) = mpsc::channel();
+    let cancel = CancellationToken::new();
+    let mut worker = Worker {
+        out_dir,
+        keyring,
+        events: events_tx,
+        cancel: cancel.clone(),
+        pending: HashMap::new(),
+        done: HashMap::new(),
+        outputs: HashSet::new(),
+        options,
+    };
+    if worker.options.include_existing {
+        for entry in scan_dir(&dir, worker.options.recursive)? {
+            worker.notice(entry.path);
+        }
+    }
+    let thread = thread::Builder::new()
+        .name("cryptocam-watch".to_owned())
+        .spawn(move || worker.run(notifications))?;
+    Ok(WatchHandle {
+        events,
+        cancel,
+        watcher: Some(watcher),
+        thread: Some(thread),
+    })
+}
+
+/// What a file looked like when it was last checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileState {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileState {
+    /// None if `path` is gone or isn't a file.
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = path.metadata().ok().filter(|metadata| metadata.is_file())?;
+        Some(FileState {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+/// A file waiting to stop changing.
+struct PendingFile {
+    state: FileState,
+    appeared: Instant,
+    changed: Instant,
+}
+
+/// Runs on the watching thread.
+struct Worker {
+    out_dir: PathBuf,
+    keyring: Keyring,
+    options: WatchOptions,
+    events: Sender<WatchEvent>,
+    cancel: CancellationToken,
+    pending: HashMap<PathBuf, PendingFile>,
+    /// The files that were handled, as they were then, so repeated events for them are
+    /// ignored.
+    done: HashMap<PathBuf, FileState>,
+    /// The outputs written so far, in case they end up in the watched directory.
+    outputs: HashSet<PathBuf>,
+}
+
+impl Worker {
+    fn run(mut self, notifications: Receiver<notify::Result<Event>>) -> Keyring {
+        let check_interval =
+            (self.options.settle_time / 4).clamp(MIN_CHECK_INTERVAL, MAX_CHECK_INTERVAL);
+        while !self.cancel.is_cancelled() {
+            match notifications.recv_timeout(check_interval) {
+                Ok(Ok(event)) => self.on_event(event),
+                Ok(Err(e)) => self.emit(WatchEvent::Error(e.to_string())),
+                Err(RecvTimeoutError::Timeout) => {}
+                // the handle dropped the watcher
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            self.check_pending();
+        }
+        self.keyring
+    }
+
+    fn emit(&self, event: WatchEvent) {
+        // nobody is listening once the handle is gone
+        let _ = self.events.send(event);
+    }
+
+    fn on_event(&mut self, event: Event) {
+        match event.kind {
+            // also what reading the files here causes
+            EventKind::Access(_) => {}
+            EventKind::Remove(_) => {
+                for path in event.paths {
+                    self.done.remove(&path);
+                    if self.pending.remove(&path).is_some() {
+                        self.emit(WatchEvent::Disappeared(path));
+                    }
+                }
+            }
+            // renames have the old path too, check_pending() notices it is gone
+            _ => {
+                for path in event.paths {
+                    self.notice(path);
+                }
+            }
+        }
+    }
+
+    /// Starts waiting for `path` to stop changing, unless it already is waited for or was
+    /// handled as it is now.
+    fn notice(&mut self, path: PathBuf) {
+        if self.pending.contains_key(&path) || self.outputs.contains(&path) {
+            return;
+        }
+        let state = match FileState::of(&path) {
+            Some(state) => state,
+            None => return,
+        };
+        if self.done.get(&path) == Some(&state) {
+            return;
+        }
+        let now = Instant::now();
+        self.pending.insert(
+            path,
+            PendingFile {
+                state,
+                appeared: now,
+                changed: now,
+            },
+        );
+    }
+
+    fn check_pending(&mut self) {
+        let now = Instant::now();
+        let (mut gone, mut unstable, mut ready) = (vec![], vec![], vec![]);
+        for (path, file) in &mut self.pending {
+            match FileState::of(path) {
+                None => {
+                    gone.push(path.clone());
+                    continue;
+                }
+                Some(state) if state != file.state => {
+                    file.state = state;
+                    file.changed = now;
+                }
+                Some(_) => {}
+            }
+            if now.duration_since(file.changed) >= self.options.settle_time {
+                ready.push((path.clone(), file.state));
+            } else if now.duration_since(file.appeared) >= self.options.stabilize_timeout {
+                unstable.push(path.clone());
+            }
+        }
+        for path in gone {
+            self.pending.remove(&path);
+            self.emit(WatchEvent::Disappeared(path));
+        }
+        for path in unstable {
+            self.pending.remove(&path);
+            warn!(
+                "{} is still changing after {:?}, not decrypting it",
+                path.display(),
+                self.options.stabilize_timeout
+            );
+            self.emit(WatchEvent::Unstable(path));
+        }
+        ready.sort_by(|a, b| a.0.cmp(&b.0));
+        for (path, state) in ready {
+            if self.cancel.is_cancelled() {
+                return;
+            }
+            self.pending.remove(&path);
+            self.done.insert(path.clone(), state);
+            self.decrypt(path);
+        }
+    }
+
+    fn decrypt(&mut self, input: PathBuf) {
+        let header = match scan_file(input.clone(), false).map(|entry| entry.header) {
+            None => {
+                debug!("{} isn't a Cryptocam file, ignoring it", input.display());
+                return;
+            }
+            Some(Ok(header)) => header,
+            Some(Err(reason)) => {
+                let action = PlannedAction::Skip { reason };
+                self.emit(WatchEvent::NotDecrypted { input, action });
+                return;
+            }
+        };
+        let options = self.options.batch_options.clone();
+        let planned = plan(
+            std::slice::from_ref(&input),
+            &mut self.keyring,
+            &self.out_dir,
+            &options,
+        );
+        let execution = planned.and_then(|planned| {
+            for file in &planned.files {
+                match &file.action {
+                    PlannedAction::Decrypt { .. } | PlannedAction::AlreadyDecrypted { .. } => {}
+                    action => self.emit(WatchEvent::NotDecrypted {
+                        input: file.input.clone(),
+                        action: action.clone(),
+                    }),
+                }
+            }
+            execute(&planned, &mut self.keyring, &options)
+        });
+        let execution = match execution {
+            Ok(execution) => execution,
+            Err(e) => {
+                let message = format!("Error decrypting {}: {}", input.display(), e);
+                self.emit(WatchEvent::Error(message));
+                return;
+            }
+        };
+        for entry in execution.skipped {
+            self.emit(WatchEvent::Finished(entry));
+        }
+        for planned in execution.jobs {
+            let mut entry = BatchEntry::new(&planned.input, &header);
+            let mut job = match planned.job {
+                Ok(job) => job,
+                Err(e) => {
+                    entry.error = Some(e.to_string());
+                    self.emit(WatchEvent::Finished(entry));
+                    continue;
+                }
+            };
+            let callback = self
+                .options
+                .progress_callback
+                .as_ref()
+                .map(|make| make(&planned.input));
+            let mut tee = Tee {
+                entry: &mut entry,
+                callback,
+            };
+            let result = job.run_with_token(Box::new(&mut tee), &self.cancel);
+            if result == JobResult::Cancelled {
+                return;
+            }
+            if let Some(output) = &entry.output {
+                self.outputs.insert(output.clone());
+            }
+            self.emit(WatchEvent::Finished(entry));
+        }
+    }
+}
+
+/// Passes the events of a job to its BatchEntry and the callback from
+/// WatchOptions::progress_callback.
+struct Tee<'a> {
+    entry: &'a mut BatchEntry,
+    callback: Option<Box<dyn ProgressCallback>>,
+}
+
+impl ProgressCallback for Tee<'_> {
+    fn set_total_file_size(&mut self, n: u64) {
+        self.entry.set_total_file_size(n);
+        if let Some(callback) = &mut self.callback {
+            callback.set_total_file_size(n);
+        }
+    }
+
+    fn on_identity_matched(&mut self, identity: &IdentityInfo) {
+        self.entry.on_identity_matched(identity);
+        if let Some(callback) = &mut self.callback {
+            callback.on_identity_matched(identity);
+        }
+    }
+
+    fn on_progress(&mut self, processed_bytes: u64) {
+        self.entry.on_progress(processed_bytes);
+        if let Some(callback) = &mut self.callback {
+            callback.on_progress(processed_bytes);
+        }
+    }
+
+    fn on_stream_progress(
+        &mut self,
+        stream: PacketKind,
+        packets: u64,
+        bytes: u64,
+        last_pts_us: u64,
+    ) {
+        if let Some(callback) = &mut self.callback {
+            callback.on_stream_progress(stream, packets, bytes, last_pts_us);
+        }
+    }
+
+    fn on_complete(&mut self) {
+        self.entry.on_complete();
+        if let Some(callback) = &mut self.callback {
+            callback.on_complete();
+        }
+    }
+
+    fn on_error(&mut self, error: Box<dyn Error>) {
+        self.entry.error = Some(error.to_string());
+        if let Some(callback) = &mut self.callback {
+            callback.on_error(error);
+        }
+    }
+
+    fn on_warning(&mut self, warning: DecryptWarning) {
+        if let Some(callback) = &mut self.callback {
+            callback.on_warning(warning);
+        }
+    }
+
+    fn on_truncated(&mut self, processed_bytes: u64) {
+        if let Some(callback) = &mut self.callback {
+            callback.on_truncated(processed_bytes);
+        }
+    }
+
+    fn on_output_created(&mut self, path: &Path) {
+        self.entry.on_output_created(path);
+        if let Some(callback) = &mut self.callback {
+            callback.on_output_created(path);
+        }
+    }
+
+    fn on_output_digest(&mut self, digest: &HashDigest) {
+        self.entry.on_output_digest(digest);
+        if let Some(callback) = &mut self.callback {
+            callback.on_output_digest(digest);
+        }
+    }
+
+    fn on_stats(&mut self, stats: &DecryptStats) {
+        self.entry.on_stats(stats);
+        if let Some(callback) = &mut self.callback {
+            callback.on_stats(stats);
+        }
+    }
+
+    fn on_verified(&mut self, report: &VerificationReport) {
+        if let Some(callback) = &mut self.callback {
+            callback.on_verified(report);
+        }
+    }
+
+    #[cfg(feature = "thumbnail")]
+    fn on_thumbnail(&mut self, data: &[u8], format: ThumbnailFormat) {
+        if let Some(callback) = &mut self.callback {
+            callback.on_thumbnail(data, format);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{test_keyring, FixtureFile};
+    use std::{fs, io::Write};
+
+    const IMAGE: &[u8] = b"\x89PNG\r\n\x1a\nimage data";
+
+    fn image(timestamp: &str) -> Vec<u8> {
+        let metadata = format!(r#"{{"timestamp":"{}","format":"png"}}"#, timestamp);
+        FixtureFile::image(metadata, IMAGE).build()
+    }
+
+    fn options() -> WatchOptions {
+        WatchOptions::new().settle_time(Duration::from_millis(100))
+    }
+
+    struct NoProgress;
+
+    impl ProgressCallback for NoProgress {
+        fn set_total_file_size(&mut self, _n: u64) {}
+        fn on_progress(&mut self, _processed_bytes: u64) {}
+        fn on_complete(&mut self) {}
+        fn on_error(&mut self, _error: Box<dyn Error>) {}
+    }
+
+    /// The next WatchEvent::Finished, failing on other events.
+    fn next_finished(handle: &WatchHandle) -> BatchEntry {
+        match handle.next_event_timeout(Duration::from_secs(10)) {
+            Some(WatchEvent::Finished(entry)) => entry,
+            event => panic!("Expected a finished file, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn dropped_files_are_decrypted() {
+        let (dir, out_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let handle = watch_dir(dir.path(), test_keyring(), out_dir.path(), options()).unwrap();
+        fs::write(dir.path().join("notes.txt"), b"not a Cryptocam file").unwrap();
+        let input = dir.path().join("image.cryptocam");
+        fs::write(&input, image("2021-06-01T12:00:00Z")).unwrap();
+
+        let entry = next_finished(&handle);
+        assert_eq!(entry.input.file_name(), input.file_name());
+        assert_eq!(entry.error, None);
+        let output = entry.output.unwrap();
+        assert_eq!(
+            output.parent().unwrap().canonicalize().unwrap(),
+            out_dir.path().canonicalize().unwrap()
+        );
+        assert_eq!(fs::read(&output).unwrap(), IMAGE);
+        // the text file is ignored
+        assert!(handle
+            .next_event_timeout(Duration::from_millis(500))
+            .is_none());
+        handle.stop();
+    }
+
+    #[test]
+    fn files_written_in_pieces_are_decrypted_once_complete() {
+        let (dir, out_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let options = options().settle_time(Duration::from_millis(500));
+        let handle = watch_dir(dir.path(), test_keyring(), out_dir.path(), options).unwrap();
+        let file = image("2021-06-01T12:00:00Z");
+        let input = dir.path().join("image.cryptocam");
+        let (first, second) = file.split_at(file.len() / 2);
+        fs::write(&input, first).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&input)
+            .unwrap()
+            .write_all(second)
+            .unwrap();
+
+        let entry = next_finished(&handle);
+        assert_eq!(entry.error, None);
+        assert_eq!(fs::read(entry.output.unwrap()).unwrap(), IMAGE);
+        assert!(handle
+            .next_event_timeout(Duration::from_millis(500))
+            .is_none());
+    }
+
+    #[test]
+    fn existing_files_are_decrypted_with_include_existing() {
+        let (dir, out_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        fs::write(
+            dir.path().join("a.cryptocam"),
+            image("2021-06-01T12:00:00Z"),
+        )
+        .unwrap();
+        let options = options().include_existing(true);
+        let handle = watch_dir(dir.path(), test_keyring(), out_dir.path(), options).unwrap();
+        assert_eq!(next_finished(&handle).error, None);
+    }
+
+    /// Options whose callback holds `marker`, so it is only released once the watching thread
+    /// is done with the options.
+    fn options_holding(marker: &Arc<()>) -> WatchOptions {
+        let marker = marker.clone();
+        options().progress_callback(move |_| {
+            let _ = &marker;
+            Box::new(NoProgress)
+        })
+    }
+
+    #[test]
+    fn stop_joins_the_thread_and_returns_the_keyring() {
+        let (dir, out_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let marker = Arc::new(());
+        let options = options_holding(&marker);
+        let handle = watch_dir(dir.path(), test_keyring(), out_dir.path(), options).unwrap();
+        assert_eq!(Arc::strong_count(&marker), 2);
+        let keyring = handle.stop();
+        assert_eq!(Arc::strong_count(&marker), 1);
+        assert_eq!(
+            keyring.identities().len(),
+            test_keyring().identities().len()
+        );
+    }
+
+    #[test]
+    fn dropping_the_handle_joins_the_thread() {
+        let (dir, out_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let marker = Arc::new(());
+        let options = options_holding(&marker);
+        let handle = watch_dir(dir.path(), test_keyring(), out_dir.path(), options).unwrap();
+        assert_eq!(Arc::strong_count(&marker), 2);
+        drop(handle);
+        assert_eq!(Arc::strong_count(&marker), 1);
+    }
+}
@@ -0,0 +1,109 @@
+//! The FFmpeg and rust-mp4 backends write the same video for the same file.
+#![cfg(all(feature = "video", feature = "rust-mp4"))]
+
+use libcryptocam::{fixtures::*, prelude::*};
+use std::{fs::File, io::Cursor, path::PathBuf};
+
+const VIDEO_METADATA: &str = r#"{"width":640,"height":480,"video_bitrate":1000000,"audio_sample_rate":44100,"audio_channel_count":1,"audio_bitrate":64000,"timestamp":"2021-06-01T12:00:00Z"}"#;
+
+struct Callback;
+
+impl ProgressCallback for Callback {
+    fn set_total_file_size(&mut self, _: u64) {}
+    fn on_progress(&mut self, _: u64) {}
+    fn on_complete(&mut self) {}
+    fn on_error(&mut self, error: Box<dyn std::error::Error>) {
+        panic!("{}", error);
+    }
+}
+
+fn decrypt(file: &[u8], out_dir: PathBuf, backend: VideoBackend) -> PathBuf {
+    std::fs::create_dir(&out_dir).unwrap();
+    let options = DecryptOptions::new()
+        .video_backend(backend)
+        .container(VideoContainer::Mp4);
+    let mut job = decrypt_from_reader(
+        Cursor::new(file.to_vec()),
+        None,
+        &mut test_keyring(),
+        out_dir,
+        options,
+    )
+    .unwrap();
+    match job.run_with_token(Box::new(&mut Callback), &CancellationToken::new()) {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?}", result),
+    }
+}
+
+/// The NAL units of an MP4 sample, without the parameter sets and access unit delimiters a
+/// muxer may move into or out of the samples.
+fn nal_units(sample: &[u8]) -> Vec<Vec<u8>> {
+    let mut units = vec![];
+    let mut rest = sample;
+    while rest.len() >= 4 {
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        units.push(rest[4..4 + len].to_vec());
+        rest = &rest[4 + len..];
+    }
+    assert!(rest.is_empty(), "trailing bytes in sample");
+    units.retain(|unit| !matches!(unit[0] & 0x1f, 7 | 8 | 9));
+    units
+}
+
+/// The video samples of the MP4 at `path`, as microseconds from the first one and NAL units.
+fn video_samples(path: PathBuf) -> Vec<(i64, Vec<Vec<u8>>)> {
+    let mut mp4 = mp4::read_mp4(File::open(path).unwrap()).unwrap();
+    let (track_id, timescale, sample_count) = mp4
+        .tracks()
+        .values()
+        .find(|track| matches!(track.track_type(), Ok(mp4::TrackType::Video)))
+        .map(|track| (track.track_id(), track.timescale(), track.sample_count()))
+        .expect("the output has a video track");
+    let mut samples = vec![];
+    for sample_id in 1..=sample_count {
+        let sample = mp4.read_sample(track_id, sample_id).unwrap().unwrap();
+        let pts = (sample.start_time as i64 + sample.rendering_offset as i64) * 1_000_000
+            / timescale as i64;
+        samples.push((pts, nal_units(&sample.bytes)));
+    }
+    let first = samples[0].0;
+    for sample in &mut samples {
+        sample.0 -= first;
+    }
+    samples
+}
+
+#[test]
+fn both_backends_write_the_same_samples() {
+    let packets = FixtureVideo::new().h264_frames(30, 4096);
+    let file = FixtureFile::video(VIDEO_METADATA, packets).build();
+    let out_dir = tempfile::tempdir().unwrap();
+    let ffmpeg = video_samples(decrypt(
+        &file,
+        out_dir.path().join("ffmpeg"),
+        VideoBackend::FFmpeg,
+    ));
+    let rust_mp4 = video_samples(decrypt(
+        &file,
+        out_dir.path().join("rust-mp4"),
+        VideoBackend::RustMp4,
+    ));
+
+    assert_eq!(ffmpeg.len(), 30);
+    assert_eq!(ffmpeg.len(), rust_mp4.len());
+    for (i, (ffmpeg, rust_mp4)) in ffmpeg.iter().zip(&rust_mp4).enumerate() {
+        assert_eq!(ffmpeg.1, rust_mp4.1, "payload of sample {}", i);
+        // the timescales of the tracks differ, so the timestamps may round differently
+        assert!(
+            (ffmpeg.0 - rust_mp4.0).abs() <= 1000,
+            "sample {} at {} and {} µs",
+            i,
+            ffmpeg.0,
+            rust_mp4.0
+        );
+        assert!((ffmpeg.0 - i as i64 * 33_333).abs() <= 1000);
+    }
+}
@@ -0,0 +1,458 @@
+//! Batches of fixture files planned and run with batch::plan() and execute().
+
+use libcryptocam::{
+    batch::{execute, plan},
+    fixtures::*,
+    prelude::*,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fs,
+    io::{Cursor, Write},
+    path::{Path, PathBuf},
+};
+use tempfile::TempDir;
+
+struct NoProgress;
+
+impl ProgressCallback for NoProgress {
+    fn set_total_file_size(&mut self, _n: u64) {}
+    fn on_progress(&mut self, _processed_bytes: u64) {}
+    fn on_complete(&mut self) {}
+    fn on_error(&mut self, error: Box<dyn Error>) {
+        panic!("Decryption failed: {}", error);
+    }
+}
+
+/// Writes `files` into a new directory, named in order.
+fn input_dir(files: &[Vec<u8>]) -> (TempDir, Vec<PathBuf>) {
+    let dir = tempfile::tempdir().unwrap();
+    let paths = files
+        .iter()
+        .enumerate()
+        .map(|(i, file)| {
+            let path = dir.path().join(format!("file_{}.cryptocam", i));
+            fs::write(&path, file).unwrap();
+            path
+        })
+        .collect();
+    (dir, paths)
+}
+
+fn image(timestamp: &str) -> Vec<u8> {
+    let metadata = format!(r#"{{"timestamp":"{}","format":"png"}}"#, timestamp);
+    FixtureFile::image(metadata, &b"\x89PNG\r\n\x1a\nimage data"[..]).build()
+}
+
+/// The files in `dir` with their contents.
+fn contents(dir: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let data = fs::read(&path).unwrap();
+            (path, data)
+        })
+        .collect()
+}
+
+/// Plans and runs `files` one after the other.
+fn run_batch(files: &[PathBuf], out_dir: &Path, options: &BatchOptions) -> BatchExecution {
+    let mut keyring = test_keyring();
+    let planned = plan(files, &mut keyring, out_dir, options).unwrap();
+    let mut execution = execute(&planned, &mut keyring, options).unwrap();
+    for planned in &mut execution.jobs {
+        let job = planned.job.as_mut().unwrap();
+        let result = job.run_with_token(Box::new(&mut NoProgress), &CancellationToken::new());
+        assert!(matches!(result, JobResult::Complete { .. }), "{:?}", result);
+    }
+    execution
+}
+
+fn skip_existing_options() -> BatchOptions {
+    BatchOptions::new()
+        .decrypt_options(
+            DecryptOptions::new()
+                .output_digest(HashAlgo::Sha256)
+                .write_metadata_sidecar(true)
+                .overwrite(Overwrite::Rename),
+        )
+        .skip_existing_by_hash(true)
+}
+
+#[test]
+fn second_run_skips_existing_outputs() {
+    let (_inputs, files) =
+        input_dir(&[image("2021-06-01T12:00:00Z"), image("2021-06-01T12:01:00Z")]);
+    let out_dir = tempfile::tempdir().unwrap();
+    let options = skip_existing_options();
+    let first = run_batch(&files, out_dir.path(), &options);
+    assert_eq!(first.jobs.len(), 2);
+    let written = contents(out_dir.path());
+    // the two images and their sidecars
+    assert_eq!(written.len(), 4);
+
+    let mut keyring = test_keyring();
+    let planned = plan(&files, &mut keyring, out_dir.path(), &options).unwrap();
+    for file in &planned.files {
+        assert!(
+            matches!(file.action, PlannedAction::AlreadyDecrypted { .. }),
+            "{:?}",
+            file.action
+        );
+    }
+    assert_eq!(planned.total_estimated_size, 0);
+    let second = execute(&planned, &mut keyring, &options).unwrap();
+    assert!(second.jobs.is_empty());
+    assert_eq!(second.skipped.len(), 2);
+    for entry in &second.skipped {
+        assert_eq!(entry.skipped, Some(SkipReason::AlreadyDecrypted));
+        assert!(written.contains_key(entry.output.as_ref().unwrap()));
+    }
+    assert_eq!(contents(out_dir.path()), written);
+}
+
+#[test]
+fn outputs_are_checked_with_the_algorithm_of_their_digest() {
+    let (_inputs, files) = input_dir(&[image("2021-06-01T12:00:00Z")]);
+    let out_dir = tempfile::tempdir().unwrap();
+    let sha512 = skip_existing_options().decrypt_options(
+        DecryptOptions::new()
+            .output_digest(HashAlgo::Sha512)
+            .write_metadata_sidecar(true)
+            .overwrite(Overwrite::Rename),
+    );
+    let first = run_batch(&files, out_dir.path(), &sha512);
+    let output = first.jobs[0].output.clone();
+    let sidecar: serde_json::Value =
+        serde_json::from_slice(&fs::read(output.with_extension("json")).unwrap()).unwrap();
+    let recorded = sidecar["output_digest"].as_str().unwrap().to_owned();
+    assert!(recorded.starts_with("sha512:"), "{}", recorded);
+
+    // a batch hashing with SHA-256 still recognizes the output
+    let mut keyring = test_keyring();
+    let options = skip_existing_options();
+    let planned = plan(&files, &mut keyring, out_dir.path(), &options).unwrap();
+    assert!(
+        matches!(
+            planned.files[0].action,
+            PlannedAction::AlreadyDecrypted { .. }
+        ),
+        "{:?}",
+        planned.files[0].action
+    );
+
+    // and isn't fooled by a digest that is right for another algorithm
+    let sha256: HashDigest = {
+        let mut hasher = HashAlgo::Sha256.hasher();
+        hasher.update(&fs::read(&output).unwrap());
+        hasher.finalize()
+    };
+    let forged = format!("sha512:{}{}", sha256.to_hex(), sha256.to_hex());
+    let json = sidecar.to_string().replace(&recorded, &forged);
+    fs::write(output.with_extension("json"), json).unwrap();
+    let planned = plan(&files, &mut keyring, out_dir.path(), &options).unwrap();
+    assert!(matches!(
+        planned.files[0].action,
+        PlannedAction::Decrypt { .. }
+    ));
+}
+
+#[test]
+fn partial_output_is_decrypted_again() {
+    let (_inputs, files) = input_dir(&[image("2021-06-01T12:00:00Z")]);
+    let out_dir = tempfile::tempdir().unwrap();
+    let options = skip_existing_options();
+    let first = run_batch(&files, out_dir.path(), &options);
+    let output = first.jobs[0].output.clone();
+    let data = fs::read(&output).unwrap();
+    fs::write(&output, &data[..data.len() / 2]).unwrap();
+
+    let mut keyring = test_keyring();
+    let planned = plan(&files, &mut keyring, out_dir.path(), &options).unwrap();
+    match &planned.files[0].action {
+        PlannedAction::Decrypt { collision, .. } => assert!(collision.is_some()),
+        action => panic!("Planned {:?}", action),
+    }
+}
+
+#[test]
+fn output_without_recorded_digest_is_decrypted_again() {
+    let (_inputs, files) = input_dir(&[image("2021-06-01T12:00:00Z")]);
+    let out_dir = tempfile::tempdir().unwrap();
+    let options = skip_existing_options();
+    let first = run_batch(&files, out_dir.path(), &options);
+    fs::remove_file(first.jobs[0].output.with_extension("json")).unwrap();
+
+    let mut keyring = test_keyring();
+    let planned = plan(&files, &mut keyring, out_dir.path(), &options).unwrap();
+    assert!(matches!(
+        planned.files[0].action,
+        PlannedAction::Decrypt { .. }
+    ));
+}
+
+/// Ten images recorded within the same second.
+fn burst() -> Vec<Vec<u8>> {
+    (0..10)
+        .map(|i| image(&format!("2021-06-01T12:00:00.{:03}Z", i * 100)))
+        .collect()
+}
+
+#[test]
+fn burst_gets_the_same_names_in_any_order() {
+    let (_inputs, files) = input_dir(&burst());
+    let mut reversed = files.clone();
+    reversed.reverse();
+    let options =
+        BatchOptions::new().decrypt_options(DecryptOptions::new().batch_names(BatchNames::new()));
+    let mut runs = vec![];
+    for files in [&files, &reversed] {
+        let out_dir = tempfile::tempdir().unwrap();
+        let mut keyring = test_keyring();
+        let planned = plan(files, &mut keyring, out_dir.path(), &options).unwrap();
+        let mut names = BTreeMap::new();
+        for file in &planned.files {
+            match &file.action {
+                PlannedAction::Decrypt {
+                    output,
+                    collision: None,
+                    milliseconds_appended: true,
+                    ..
+                } => {
+                    names.insert(file.input.clone(), output.file_name().unwrap().to_owned());
+                }
+                action => panic!("Planned {:?}", action),
+            }
+        }
+        run_batch(files, out_dir.path(), &options);
+        let written: BTreeSet<_> = contents(out_dir.path())
+            .keys()
+            .map(|path| path.file_name().unwrap().to_owned())
+            .collect();
+        assert_eq!(written, names.values().cloned().collect());
+        runs.push(names);
+    }
+    assert_eq!(runs[0].len(), 10);
+    assert_eq!(runs[0], runs[1]);
+}
+
+#[derive(Default)]
+struct Warnings(Vec<DecryptWarning>);
+
+impl ProgressCallback for Warnings {
+    fn set_total_file_size(&mut self, _n: u64) {}
+    fn on_progress(&mut self, _processed_bytes: u64) {}
+    fn on_complete(&mut self) {}
+    fn on_error(&mut self, error: Box<dyn Error>) {
+        panic!("Decryption failed: {}", error);
+    }
+    fn on_warning(&mut self, warning: DecryptWarning) {
+        self.0.push(warning);
+    }
+}
+
+#[test]
+fn jobs_warn_about_names_with_milliseconds() {
+    let out_dir = tempfile::tempdir().unwrap();
+    let mut keyring = test_keyring();
+    let options = DecryptOptions::new().batch_names(BatchNames::new());
+    let timestamps = [
+        "2021-06-01T12:00:00.100Z",
+        "2021-06-01T12:00:00.200Z",
+        "2021-06-01T12:00:01.000Z",
+    ];
+    // all jobs of the batch are built before the first one runs
+    let jobs: Vec<_> = timestamps
+        .iter()
+        .map(|timestamp| {
+            let reader = Cursor::new(image(timestamp));
+            let out_dir = out_dir.path().to_path_buf();
+            decrypt_from_reader(reader, None, &mut keyring, out_dir, options.clone()).unwrap()
+        })
+        .collect();
+    for (mut job, timestamp) in jobs.into_iter().zip(timestamps) {
+        let mut warnings = Warnings::default();
+        let result = job.run_with_token(Box::new(&mut warnings), &CancellationToken::new());
+        assert!(matches!(result, JobResult::Complete { .. }), "{:?}", result);
+        let appended = DecryptWarning::MillisecondsAppended {
+            timestamp: timestamp.to_owned(),
+        };
+        assert_eq!(
+            warnings.0.contains(&appended),
+            timestamp.starts_with("2021-06-01T12:00:00."),
+            "{:?}",
+            warnings.0
+        );
+    }
+}
+
+#[test]
+fn plan_shows_what_happens_to_each_file() {
+    let other = generate_identity(None).unwrap();
+    let for_other = FixtureFile::image(
+        r#"{"timestamp":"2021-06-01T12:00:00Z","format":"png"}"#,
+        "x",
+    )
+    .recipients(vec![other.recipient.parse().unwrap()])
+    .build();
+    let (_inputs, files) = input_dir(&[
+        image("2021-06-01T12:00:00Z"),
+        for_other,
+        image("2021-06-01T12:00:00Z"),
+    ]);
+    let out_dir = tempfile::tempdir().unwrap();
+    let options =
+        BatchOptions::new().decrypt_options(DecryptOptions::new().overwrite(Overwrite::Rename));
+    let planned = plan(&files, &mut test_keyring(), out_dir.path(), &options).unwrap();
+    assert_eq!(planned.files.len(), 3);
+    assert!(contents(out_dir.path()).is_empty());
+
+    let first_output = match &planned.files[0].action {
+        PlannedAction::Decrypt {
+            identity,
+            media_type,
+            output,
+            collision: None,
+            ..
+        } => {
+            assert_eq!(identity, &Some(test_recipient().digest().to_hex()));
+            assert_eq!(media_type, "image");
+            output.clone()
+        }
+        action => panic!("{:?}", action),
+    };
+    assert_eq!(
+        planned.files[1].action,
+        PlannedAction::MissingKey {
+            recipients: vec![other.digest.to_hex()]
+        }
+    );
+    match &planned.files[2].action {
+        PlannedAction::Decrypt {
+            output,
+            collision: Some(Collision::Renamed { wanted }),
+            ..
+        } => {
+            assert_eq!(wanted, &first_output);
+            assert_ne!(output, &first_output);
+        }
+        action => panic!("{:?}", action),
+    }
+
+    let json = serde_json::to_value(&planned).unwrap();
+    assert_eq!(json["files"][0]["action"], "decrypt");
+    assert_eq!(json["files"][1]["action"], "missing_key");
+    assert_eq!(json["files"][2]["collision"]["kind"], "renamed");
+    let deserialized: BatchPlan = serde_json::from_value(json).unwrap();
+    assert_eq!(deserialized.out_dir, planned.out_dir);
+    for (file, planned) in deserialized.files.iter().zip(&planned.files) {
+        assert_eq!(file.input, planned.input);
+        assert_eq!(file.size, planned.size);
+        assert_eq!(file.action, planned.action);
+    }
+}
+
+#[test]
+fn execute_reports_what_changed_since_the_plan() {
+    let (_inputs, files) =
+        input_dir(&[image("2021-06-01T12:00:00Z"), image("2021-06-01T12:01:00Z")]);
+    let out_dir = tempfile::tempdir().unwrap();
+    let options = BatchOptions::new();
+    let mut keyring = test_keyring();
+    let planned = plan(&files, &mut keyring, out_dir.path(), &options).unwrap();
+    let second_output = match &planned.files[1].action {
+        PlannedAction::Decrypt { output, .. } => output.clone(),
+        action => panic!("{:?}", action),
+    };
+    fs::OpenOptions::new()
+        .append(true)
+        .open(&files[0])
+        .unwrap()
+        .write_all(b"appended")
+        .unwrap();
+    fs::create_dir_all(second_output.parent().unwrap()).unwrap();
+    fs::write(&second_output, b"someone else's").unwrap();
+
+    let execution = execute(&planned, &mut keyring, &options).unwrap();
+    assert_eq!(execution.jobs.len(), 2);
+    assert_eq!(
+        execution.drift,
+        vec![
+            PlanDrift::InputChanged {
+                input: files[0].clone()
+            },
+            PlanDrift::OutputExists {
+                output: second_output
+            },
+        ]
+    );
+
+    let execution = execute(&planned, &mut Keyring::in_memory(), &options).unwrap();
+    let key_missing: Vec<_> = execution
+        .drift
+        .iter()
+        .filter_map(|drift| match drift {
+            PlanDrift::KeyMissing { input } => Some(input),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(key_missing, vec![&files[0], &files[1]]);
+}
+
+/// A video of `frames` frames of `frame_len` bytes.
+#[cfg(feature = "rust-mp4")]
+fn video(timestamp: &str, frames: u64, frame_len: usize) -> Vec<u8> {
+    let metadata = format!(
+        r#"{{"width":640,"height":480,"video_bitrate":1000000,"audio_sample_rate":44100,"audio_channel_count":1,"audio_bitrate":64000,"timestamp":"{}"}}"#,
+        timestamp
+    );
+    FixtureFile::video(metadata, FixtureVideo::new().h264_frames(frames, frame_len)).build()
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn memory_budget_is_shared_by_concurrent_jobs() {
+    const PACKET_LEN: usize = 16 << 10;
+    const BUDGET: u64 = 40 << 10;
+    let (_inputs, files) = input_dir(&[
+        video("2021-06-01T12:00:00Z", 32, PACKET_LEN),
+        video("2021-06-01T12:01:00Z", 32, PACKET_LEN),
+    ]);
+    let out_dir = tempfile::tempdir().unwrap();
+    let mut keyring = test_keyring();
+    let options = BatchOptions::new()
+        .decrypt_options(
+            DecryptOptions::new()
+                .video_backend(VideoBackend::RustMp4)
+                .pipelined(true),
+        )
+        .memory_budget_bytes(BUDGET);
+
+    let planned = plan(&files, &mut keyring, out_dir.path(), &options).unwrap();
+    let execution = execute(&planned, &mut keyring, &options).unwrap();
+    let budget = execution.resource_budget.clone().unwrap();
+    let runs: Vec<_> = execution
+        .jobs
+        .into_iter()
+        .map(|planned| {
+            let mut job = planned.job.unwrap();
+            std::thread::spawn(move || {
+                job.run_with_token(Box::new(&mut NoProgress), &CancellationToken::new())
+            })
+        })
+        .collect();
+    assert_eq!(runs.len(), 2);
+    for run in runs {
+        match run.join().unwrap() {
+            JobResult::Complete { output } => {
+                assert!(output.unwrap().metadata().unwrap().len() > 0)
+            }
+            result => panic!("Job ended with {:?}", result),
+        }
+    }
+    let peak = budget.peak().buffer_bytes;
+    assert!(peak > 0 && peak <= BUDGET, "peak of {} bytes", peak);
+    assert_eq!(budget.usage().buffer_bytes, 0);
+}
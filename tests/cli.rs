@@ -0,0 +1,243 @@
+//! The cryptocam-cli example, run against fixture files with a keyring in a tempdir.
+
+use libcryptocam::fixtures::*;
+use serde_json::Value;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+use tempfile::TempDir;
+
+const IMAGE: &[u8] = b"\x89PNG\r\n\x1a\nimage data";
+
+/// The example binary. `cargo test` builds it next to the deps directory of the test binaries,
+/// `cargo test --test cli` alone doesn't.
+fn cli_path() -> PathBuf {
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.push("examples");
+    path.push(format!("cryptocam-cli{}", env::consts::EXE_SUFFIX));
+    assert!(
+        path.exists(),
+        "{} is missing, build it with cargo build --examples",
+        path.display()
+    );
+    path
+}
+
+/// Runs the example with the keyring in `dir`/keyring.
+fn cli(dir: &Path, args: &[&str]) -> Output {
+    Command::new(cli_path())
+        .arg(dir.join("keyring"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn assert_success(output: &Output) {
+    assert!(
+        output.status.success(),
+        "{:?}: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+fn assert_failure(output: &Output) {
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("Error: "));
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn path_str(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+fn image(timestamp: &str) -> Vec<u8> {
+    let metadata = format!(r#"{{"timestamp":"{}","format":"png"}}"#, timestamp);
+    FixtureFile::image(metadata, IMAGE).build()
+}
+
+/// A tempdir with a keyring holding TEST_SECRET_KEY, imported through the example, and two
+/// image fixtures in its "in" directory.
+fn setup() -> (TempDir, Vec<String>) {
+    let dir = tempfile::tempdir().unwrap();
+    let identity = dir.path().join("identity.txt");
+    fs::write(&identity, format!("# test key\n{}\n", TEST_SECRET_KEY)).unwrap();
+    assert_success(&cli(dir.path(), &["import", path_str(&identity)]));
+    let in_dir = dir.path().join("in");
+    fs::create_dir(&in_dir).unwrap();
+    let files = ["2021-06-01T12:00:00Z", "2021-06-01T12:00:01Z"]
+        .iter()
+        .enumerate()
+        .map(|(i, timestamp)| {
+            let path = in_dir.join(format!("file_{}.cryptocam", i));
+            fs::write(&path, image(timestamp)).unwrap();
+            path_str(&path).to_owned()
+        })
+        .collect();
+    (dir, files)
+}
+
+/// An empty directory `name` in `dir`.
+fn out_dir(dir: &TempDir, name: &str) -> PathBuf {
+    let path = dir.path().join(name);
+    fs::create_dir(&path).unwrap();
+    path
+}
+
+/// The contents of the files in `dir`.
+fn contents(dir: &Path) -> Vec<Vec<u8>> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| fs::read(entry.unwrap().path()).unwrap())
+        .collect()
+}
+
+#[test]
+fn imported_identities_are_written_to_the_keyring() {
+    let (dir, _) = setup();
+    let identity = dir.path().join("identity.txt");
+
+    assert_success(&cli(dir.path(), &["import", path_str(&identity)]));
+    assert_eq!(fs::read_dir(dir.path().join("keyring")).unwrap().count(), 1);
+    let keys = cli(dir.path(), &["keys"]);
+    assert_success(&keys);
+    assert!(stdout(&keys).contains(TEST_PUBLIC_KEY), "{}", stdout(&keys));
+    let missing = dir.path().join("missing.txt");
+    assert_failure(&cli(dir.path(), &["import", path_str(&missing)]));
+}
+
+#[test]
+fn decrypt_writes_the_images() {
+    let (dir, files) = setup();
+    let out = out_dir(&dir, "out");
+    let junk = dir.path().join("junk");
+    fs::write(&junk, b"not a Cryptocam file").unwrap();
+
+    let mut args = vec!["decrypt", path_str(&out)];
+    args.extend(files.iter().map(String::as_str));
+    assert_success(&cli(dir.path(), &args));
+    assert_eq!(contents(&out), [IMAGE, IMAGE]);
+    let args = ["decrypt", path_str(&out), path_str(&junk)];
+    assert_failure(&cli(dir.path(), &args));
+}
+
+#[test]
+fn batch_dry_runs_write_nothing() {
+    let (dir, files) = setup();
+    let out = out_dir(&dir, "out");
+    let manifest = dir.path().join("manifest.json");
+
+    let mut args = vec!["batch", path_str(&out), "--dry-run"];
+    args.extend(["--manifest", path_str(&manifest)]);
+    args.extend(files.iter().map(String::as_str));
+    let output = cli(dir.path(), &args);
+    assert_success(&output);
+    let plan: Value = serde_json::from_str(&stdout(&output)).unwrap();
+    let actions: Vec<&Value> = plan["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|file| &file["action"])
+        .collect();
+    assert_eq!(actions, ["decrypt", "decrypt"]);
+    assert!(contents(&out).is_empty());
+    assert!(!manifest.exists());
+}
+
+#[test]
+fn batches_write_the_outputs_and_manifest() {
+    let (dir, files) = setup();
+    let out = out_dir(&dir, "out");
+    let manifest = dir.path().join("manifest.json");
+
+    let mut args = vec!["batch", path_str(&out), "--manifest", path_str(&manifest)];
+    args.extend(files.iter().map(String::as_str));
+    assert_success(&cli(dir.path(), &args));
+    assert_eq!(contents(&out), [IMAGE, IMAGE]);
+    let report: Value = serde_json::from_slice(&fs::read(&manifest).unwrap()).unwrap();
+    let entries = report["files"].as_array().unwrap();
+    assert_eq!(entries.len(), 2);
+    for (entry, input) in entries.iter().zip(&files) {
+        assert_eq!(entry["input"], input.as_str());
+        assert_eq!(entry["error"], Value::Null);
+        let output = Path::new(entry["output"].as_str().unwrap());
+        assert_eq!(fs::read(output).unwrap(), IMAGE);
+    }
+}
+
+#[test]
+fn batches_with_unreadable_files_fail() {
+    let (dir, files) = setup();
+    let out = out_dir(&dir, "out");
+    let junk = dir.path().join("junk");
+    fs::write(&junk, b"not a Cryptocam file").unwrap();
+
+    let args = ["batch", path_str(&out), files[0].as_str(), path_str(&junk)];
+    assert_failure(&cli(dir.path(), &args));
+    // the readable file is decrypted anyway
+    assert_eq!(contents(&out), [IMAGE]);
+}
+
+#[test]
+fn identify_lint_and_report_read_the_files() {
+    let (dir, files) = setup();
+    let in_dir = dir.path().join("in");
+    let junk = in_dir.join("junk");
+
+    let output = cli(dir.path(), &["identify", &files[0]]);
+    assert_success(&output);
+    let line = stdout(&output);
+    let prefix = format!("{}\tversion ", files[0]);
+    assert!(line.starts_with(&prefix), "{}", line);
+    assert!(!line.contains("no matching key"), "{}", line);
+    assert_success(&cli(dir.path(), &["lint", path_str(&in_dir)]));
+    let output = cli(dir.path(), &["report", &files[0]]);
+    assert_success(&output);
+    let events: Vec<Value> = stdout(&output)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert!(events.iter().any(|event| event["event"] == "verified"));
+
+    fs::write(&junk, b"not a Cryptocam file").unwrap();
+    let output = cli(dir.path(), &["lint", path_str(&in_dir)]);
+    assert_failure(&output);
+    assert!(stdout(&output).contains("not_a_cryptocam_file"));
+    assert_failure(&cli(dir.path(), &["identify", path_str(&junk)]));
+}
+
+#[test]
+fn qr_codes_import_secret_keys_only() {
+    let dir = tempfile::tempdir().unwrap();
+
+    assert_failure(&cli(dir.path(), &["qr-import", TEST_PUBLIC_KEY]));
+    assert_success(&cli(dir.path(), &["qr-import", TEST_SECRET_KEY]));
+    let keys = stdout(&cli(dir.path(), &["keys"]));
+    assert!(keys.contains(TEST_PUBLIC_KEY), "{}", keys);
+    let name = keys.split('\t').next().unwrap();
+    let qr_code = cli(dir.path(), &["qr", name]);
+    assert_success(&qr_code);
+    assert!(!qr_code.stdout.is_empty());
+}
+
+#[test]
+fn unknown_commands_fail() {
+    let dir = tempfile::tempdir().unwrap();
+
+    assert_failure(&cli(dir.path(), &["frobnicate"]));
+    assert_failure(&cli(dir.path(), &[]));
+}
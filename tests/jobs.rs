@@ -0,0 +1,1833 @@
+//! What jobs report to their ProgressCallback for fixture files. The image tests need no
+//! optional features, run them with `--no-default-features --features test-fixtures` too.
+
+use libcryptocam::{decrypt::InputPosition, error, fixtures::*, prelude::*};
+use sha2::Digest;
+use std::{
+    error::Error,
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc},
+};
+
+/// Size of an age chunk in the encrypted file, with its tag.
+const ENCRYPTED_CHUNK_SIZE: u64 = (64 << 10) + 16;
+
+#[derive(Default)]
+struct Recorder {
+    total_file_size: Option<u64>,
+    progress: Vec<u64>,
+    completed: bool,
+    errors: Vec<Box<dyn Error>>,
+    warnings: Vec<DecryptWarning>,
+    truncated: Option<u64>,
+    stats: Option<DecryptStats>,
+    output_digest: Option<HashDigest>,
+    verified: Option<VerificationReport>,
+    stream_progress: Vec<(libcryptocam::packet::PacketKind, u64, u64, u64)>,
+}
+
+impl ProgressCallback for Recorder {
+    fn set_total_file_size(&mut self, n: u64) {
+        self.total_file_size = Some(n);
+    }
+    fn on_progress(&mut self, processed_bytes: u64) {
+        self.progress.push(processed_bytes);
+    }
+    fn on_complete(&mut self) {
+        self.completed = true;
+    }
+    fn on_error(&mut self, error: Box<dyn Error>) {
+        self.errors.push(error);
+    }
+    fn on_warning(&mut self, warning: DecryptWarning) {
+        self.warnings.push(warning);
+    }
+    fn on_truncated(&mut self, processed_bytes: u64) {
+        self.truncated = Some(processed_bytes);
+    }
+    fn on_stats(&mut self, stats: &DecryptStats) {
+        self.stats = Some(stats.clone());
+    }
+    fn on_output_digest(&mut self, digest: &HashDigest) {
+        self.output_digest = Some(digest.clone());
+    }
+    fn on_verified(&mut self, report: &VerificationReport) {
+        self.verified = Some(report.clone());
+    }
+    fn on_stream_progress(
+        &mut self,
+        stream: libcryptocam::packet::PacketKind,
+        packets: u64,
+        bytes: u64,
+        last_pts_us: u64,
+    ) {
+        self.stream_progress
+            .push((stream, packets, bytes, last_pts_us));
+    }
+}
+
+/// Runs the job for `file` in a temporary directory.
+fn run(file: Vec<u8>, options: DecryptOptions) -> (JobResult, Recorder) {
+    let out_dir = tempfile::tempdir().unwrap();
+    run_in(out_dir.path(), file, options)
+}
+
+fn run_in(out_dir: &Path, file: Vec<u8>, options: DecryptOptions) -> (JobResult, Recorder) {
+    let mut job = decrypt_from_reader(
+        Cursor::new(file),
+        None,
+        &mut test_keyring(),
+        out_dir.to_path_buf(),
+        options,
+    )
+    .unwrap();
+    let mut recorder = Recorder::default();
+    let result = job.run_with_token(Box::new(&mut recorder), &CancellationToken::new());
+    (result, recorder)
+}
+
+/// An image of `len` bytes.
+fn image(len: usize) -> Vec<u8> {
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    png.resize(len, 0);
+    FixtureFile::image(
+        r#"{"timestamp":"2021-06-01T12:00:00Z","format":"png"}"#,
+        png,
+    )
+    .build()
+}
+
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+const VIDEO_METADATA: &str = r#"{"width":640,"height":480,"video_bitrate":1000000,"audio_sample_rate":44100,"audio_channel_count":1,"audio_bitrate":64000,"timestamp":"2021-06-01T12:00:00Z"}"#;
+
+/// A video of `frames` frames of `frame_len` bytes.
+#[cfg(feature = "rust-mp4")]
+fn video(frames: u64, frame_len: usize) -> Vec<u8> {
+    let packets = FixtureVideo::new().h264_frames(frames, frame_len);
+    FixtureFile::video(VIDEO_METADATA, packets).build()
+}
+
+/// How many packets of a video() with `frame_len` bytes per frame are in the whole age
+/// chunks of `file` before `cut`.
+#[cfg(feature = "rust-mp4")]
+fn packets_before(file: &[u8], cut: usize, frame_len: usize) -> u64 {
+    let (_, header_len) = libcryptocam::parser::parse_header_from_slice(file).unwrap();
+    let age = &file[header_len..];
+    // the age header ends with the "--- <mac>" line
+    let mac_line = age.windows(4).position(|w| w == b"\n---").unwrap() + 1;
+    let age_header_len = mac_line + age[mac_line..].iter().position(|&b| b == b'\n').unwrap() + 1;
+    let chunks = (cut - header_len - age_header_len) as u64 / ENCRYPTED_CHUNK_SIZE;
+    let packets_start = 5 + VIDEO_METADATA.len() as u64;
+    let packet_len = FixtureVideo::new()
+        .h264_frames(1, frame_len)
+        .payload()
+        .len() as u64;
+    (chunks * (64 << 10)).saturating_sub(packets_start) / packet_len
+}
+
+/// `file` with a byte of its last chunk flipped, and the offset of that byte.
+fn corrupt_last_chunk(mut file: Vec<u8>) -> (Vec<u8>, u64) {
+    let offset = file.len() - 20;
+    file[offset] ^= 1;
+    (file, offset as u64)
+}
+
+/// Asserts that `reported` is an IntegrityError in the chunk holding `corrupted_offset`.
+fn assert_integrity_error(reported: &(dyn Error + 'static), corrupted_offset: u64) {
+    match reported.downcast_ref::<error::Error>() {
+        Some(&error::Error::IntegrityError {
+            approximate_offset,
+            processed_bytes,
+        }) => {
+            assert!(approximate_offset <= corrupted_offset);
+            assert!(corrupted_offset < approximate_offset + ENCRYPTED_CHUNK_SIZE);
+            assert!(processed_bytes > 0);
+            assert_eq!(processed_bytes % (64 << 10), 0);
+        }
+        _ => panic!("{:?}", reported),
+    }
+}
+
+/// Asserts that the job completed and its progress went up to `file_len`.
+fn assert_progress_to_the_end(file_len: usize, result: JobResult, recorder: &Recorder) {
+    assert!(matches!(result, JobResult::Complete { .. }), "{:?}", result);
+    assert!(recorder.completed);
+    assert_eq!(recorder.total_file_size, Some(file_len as u64));
+    assert!(recorder.progress.windows(2).all(|w| w[0] <= w[1]));
+    assert_eq!(recorder.progress.last(), Some(&(file_len as u64)));
+}
+
+#[test]
+fn image_progress_ends_at_the_file_size() {
+    let file = image(200_000);
+    let file_len = file.len();
+    let (result, recorder) = run(file, DecryptOptions::new());
+    assert_progress_to_the_end(file_len, result, &recorder);
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn video_progress_ends_at_the_file_size() {
+    let file = video(8, 32 << 10);
+    let file_len = file.len();
+    let options = DecryptOptions::new().video_backend(VideoBackend::RustMp4);
+    let (result, recorder) = run(file, options);
+    assert_progress_to_the_end(file_len, result, &recorder);
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn video_stats_are_those_of_the_fixture() {
+    // 45 frames of 33_333 µs each
+    let (frames, fixture_duration) = (45, 45 * 33_333);
+    let mut backends = vec![VideoBackend::RustMp4];
+    if cfg!(feature = "video") {
+        backends.push(VideoBackend::FFmpeg);
+    }
+    for backend in backends {
+        let options = DecryptOptions::new()
+            .video_backend(backend)
+            .container(VideoContainer::Mp4);
+        let out_dir = tempfile::tempdir().unwrap();
+        let (result, recorder) = run_in(out_dir.path(), video(frames, 4096), options);
+        let output = match result {
+            JobResult::Complete {
+                output: Some(output),
+            } => output,
+            result => panic!("{:?} {:?} {:?}", backend, result, recorder.errors),
+        };
+        let stats = match recorder.stats {
+            Some(DecryptStats::Video(stats)) => stats,
+            stats => panic!("{:?} {:?}", backend, stats),
+        };
+        assert_eq!((stats.video_packets, stats.audio_packets), (frames, 0));
+        assert_eq!((stats.video_bytes, stats.audio_bytes), (frames * 4096, 0));
+        assert_eq!(stats.max_video_packet, 4096);
+        assert_eq!((stats.width, stats.height), (640, 480));
+        // the last frame's length isn't in the packets, so the duration is at most a frame
+        // shorter
+        let missing = fixture_duration - stats.duration_us;
+        assert!(missing <= 33_333, "{:?}: {} µs", backend, stats.duration_us);
+
+        // and so is the video track
+        let mp4 = mp4::read_mp4(std::fs::File::open(&output).unwrap()).unwrap();
+        let track = mp4.tracks().values().next().unwrap();
+        let track_duration = track.duration().as_micros() as i64;
+        assert!(
+            (track_duration - fixture_duration as i64).abs() <= 33_333,
+            "{:?}: {} µs",
+            backend,
+            track_duration
+        );
+    }
+}
+
+#[test]
+fn corrupted_images_fail_with_an_integrity_error() {
+    let (file, corrupted_offset) = corrupt_last_chunk(image(200_000));
+    let error = decrypt_to_vec(Cursor::new(file.clone()), &mut test_keyring(), 1 << 20)
+        .err()
+        .expect("decrypting fails");
+    assert_integrity_error(error.root_cause(), corrupted_offset);
+
+    let (result, recorder) = run(file, DecryptOptions::new());
+    assert!(matches!(result, JobResult::Failed(_)), "{:?}", result);
+    assert_eq!(recorder.errors.len(), 1);
+    assert_integrity_error(recorder.errors[0].as_ref(), corrupted_offset);
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn corrupted_videos_fail_with_an_integrity_error() {
+    let (file, corrupted_offset) = corrupt_last_chunk(video(8, 32 << 10));
+    // otherwise the video is finished with the packets before the corrupted chunk
+    let options = DecryptOptions::new()
+        .video_backend(VideoBackend::RustMp4)
+        .finalize_on_truncation(false);
+    let (result, recorder) = run(file, options);
+    assert!(matches!(result, JobResult::Failed(_)), "{:?}", result);
+    assert_eq!(recorder.errors.len(), 1);
+    assert_integrity_error(recorder.errors[0].as_ref(), corrupted_offset);
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn truncated_videos_keep_the_packets_before_the_cut() {
+    const FRAME_LEN: usize = 16 << 10;
+    let file = video(16, FRAME_LEN);
+    let cut = file.len() / 2 + 7;
+    let expected_packets = packets_before(&file, cut, FRAME_LEN);
+    assert!(expected_packets > 0);
+
+    let out_dir = tempfile::tempdir().unwrap();
+    let options = DecryptOptions::new()
+        .video_backend(VideoBackend::RustMp4)
+        .finalize_on_truncation(true);
+    let (result, recorder) = run_in(out_dir.path(), file[..cut].to_vec(), options);
+    let output = match result {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?}", result),
+    };
+    assert!(recorder.completed);
+    assert!(recorder.errors.is_empty());
+    assert!(
+        recorder
+            .warnings
+            .iter()
+            .any(|w| matches!(w, DecryptWarning::TruncatedStream { .. })),
+        "{:?}",
+        recorder.warnings
+    );
+    assert!(recorder.truncated.expect("on_truncated() was called") <= cut as u64);
+    match &recorder.stats {
+        Some(DecryptStats::Video(stats)) => assert_eq!(stats.video_packets, expected_packets),
+        stats => panic!("{:?}", stats),
+    }
+
+    assert_eq!(video_samples(&output) as u64, expected_packets);
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn resyncing_recovers_the_video_after_corrupt_data() {
+    // 30 frames of 512 bytes, the header and start of the eleventh overwritten by bit rot
+    const PACKET_LEN: usize = 13 + 512;
+    let mut payload = FixtureVideo::new().h264_frames(30, 512).payload().to_vec();
+    payload[10 * PACKET_LEN..10 * PACKET_LEN + 100].fill(0xff);
+    let file = FixtureFile::video(VIDEO_METADATA, FixtureVideo::new().raw_bytes(&payload)).build();
+    let options = DecryptOptions::new()
+        .video_backend(VideoBackend::RustMp4)
+        .finalize_on_truncation(false);
+
+    // strictly, the absurd packet length fails the job
+    let (result, _) = run(file.clone(), options.clone());
+    assert!(matches!(result, JobResult::Failed(_)), "{:?}", result);
+
+    let out_dir = tempfile::tempdir().unwrap();
+    let (result, recorder) = run_in(out_dir.path(), file, options.clone().resync_on_error(true));
+    let output = match result {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?} {:?}", result, recorder.errors),
+    };
+    // only the damaged packet is lost, the tail is there
+    assert_eq!(
+        recorder.warnings,
+        [DecryptWarning::CorruptDataSkipped {
+            offset: 10 * PACKET_LEN as u64,
+            len: PACKET_LEN as u64,
+        }]
+    );
+    assert_eq!(video_samples(&output), 29);
+    match &recorder.stats {
+        Some(DecryptStats::Video(stats)) => {
+            assert_eq!(stats.video_packets, 29);
+            assert_eq!(stats.duration_us, 29 * 33_333);
+        }
+        stats => panic!("{:?}", stats),
+    }
+
+    // garbage at the end of the stream ends it, with everything before it in the output
+    let mut payload = FixtureVideo::new().h264_frames(30, 512).payload().to_vec();
+    payload.extend_from_slice(&[0xff; 100]);
+    let file = FixtureFile::video(VIDEO_METADATA, FixtureVideo::new().raw_bytes(&payload)).build();
+    let out_dir = tempfile::tempdir().unwrap();
+    let (result, recorder) = run_in(out_dir.path(), file, options.resync_on_error(true));
+    let output = match result {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?} {:?}", result, recorder.errors),
+    };
+    assert_eq!(
+        recorder.warnings,
+        [DecryptWarning::CorruptDataSkipped {
+            offset: 30 * PACKET_LEN as u64,
+            len: 100,
+        }]
+    );
+    assert_eq!(video_samples(&output), 30);
+}
+
+#[cfg(not(any(feature = "video", feature = "rust-mp4")))]
+#[test]
+fn videos_need_a_video_backend() {
+    let packets = FixtureVideo::new().h264_frames(2, 64);
+    let file = FixtureFile::video(
+        r#"{"width":640,"height":480,"video_bitrate":1000000,"audio_sample_rate":44100,"audio_channel_count":1,"audio_bitrate":64000,"timestamp":"2021-06-01T12:00:00Z"}"#,
+        packets,
+    )
+    .build();
+    let out_dir = tempfile::tempdir().unwrap();
+    let error = decrypt_from_reader(
+        Cursor::new(file),
+        None,
+        &mut test_keyring(),
+        out_dir.path().to_path_buf(),
+        DecryptOptions::new(),
+    )
+    .err()
+    .expect("building the job fails");
+    assert!(
+        matches!(
+            error.downcast_ref::<error::Error>(),
+            Some(error::Error::VideoSupportNotCompiled)
+        ),
+        "{:?}",
+        error
+    );
+    assert_eq!(std::fs::read_dir(out_dir.path()).unwrap().count(), 0);
+}
+
+/// A video whose PTS repeat, go back a little and then jump back like after a clock change.
+#[cfg(feature = "rust-mp4")]
+fn video_with_unordered_pts() -> Vec<u8> {
+    const SLICE: &[u8] = b"\x00\x00\x00\x01\x41\x88\x88\x88";
+    let packets = [33_333, 33_333, 30_000, 10_000_000, 1_000_000, 1_033_333]
+        .iter()
+        .fold(FixtureVideo::new().h264_frames(1, 64), |packets, &pts| {
+            packets.video_packet(pts, SLICE)
+        });
+    FixtureFile::video(VIDEO_METADATA, packets).build()
+}
+
+/// The number of samples in the video track of the MP4 at `path`.
+#[cfg(feature = "rust-mp4")]
+fn video_samples(path: &Path) -> u32 {
+    let mp4 = mp4::read_mp4(std::fs::File::open(path).unwrap()).unwrap();
+    mp4.tracks()
+        .values()
+        .find(|track| matches!(track.track_type(), Ok(mp4::TrackType::Video)))
+        .expect("the output has a video track")
+        .sample_count()
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn unordered_pts_are_fixed_before_muxing() {
+    for (mode, expected_samples) in [(NonMonotonicPts::Bump, 7), (NonMonotonicPts::Drop, 5)] {
+        let out_dir = tempfile::tempdir().unwrap();
+        let options = DecryptOptions::new()
+            .video_backend(VideoBackend::RustMp4)
+            .non_monotonic_pts(mode);
+        let (result, recorder) = run_in(out_dir.path(), video_with_unordered_pts(), options);
+        let output = match result {
+            JobResult::Complete {
+                output: Some(output),
+            } => output,
+            result => panic!("{:?}: {:?}", mode, result),
+        };
+        assert!(recorder.errors.is_empty());
+        assert_eq!(video_samples(&output), expected_samples, "{:?}", mode);
+
+        let count =
+            |f: fn(&DecryptWarning) -> bool| recorder.warnings.iter().filter(|w| f(w)).count();
+        let fixed = count(|w| {
+            matches!(
+                w,
+                DecryptWarning::TimestampAdjusted { .. } | DecryptWarning::PacketDropped { .. }
+            )
+        });
+        assert_eq!(fixed, 2, "{:?}", recorder.warnings);
+        let jumps = count(|w| {
+            matches!(
+                w,
+                DecryptWarning::TimestampJump {
+                    back_us: 9_000_000,
+                    ..
+                }
+            )
+        });
+        assert_eq!(jumps, 1, "{:?}", recorder.warnings);
+    }
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn empty_packets_and_invalid_pts_are_skipped() {
+    const SLICE: &[u8] = b"\x00\x00\x00\x01\x41\x88\x88\x88";
+    const INVALID_PTS: u64 = 1 << 63;
+    let mut packets = FixtureVideo::new().h264_frames(1, 64);
+    for i in 1..=20 {
+        packets = packets
+            .video_packet(i * 33_333, &[])
+            .audio_packet(i * 33_333, &[])
+            .video_packet(i * 33_333 + 1, SLICE);
+    }
+    let packets = packets.video_packet(INVALID_PTS, SLICE);
+    let file = FixtureFile::video(VIDEO_METADATA, packets).build();
+    let file_len = file.len();
+
+    let out_dir = tempfile::tempdir().unwrap();
+    let options = DecryptOptions::new().video_backend(VideoBackend::RustMp4);
+    let (result, recorder) = run_in(out_dir.path(), file, options);
+    let output = match &result {
+        JobResult::Complete {
+            output: Some(output),
+        } => output.clone(),
+        result => panic!("{:?}", result),
+    };
+    assert_progress_to_the_end(file_len, result, &recorder);
+    assert_eq!(video_samples(&output), 21);
+    assert_eq!(
+        recorder.warnings,
+        vec![DecryptWarning::InvalidPtsSkipped {
+            stream: libcryptocam::packet::PacketKind::Video,
+            pts: INVALID_PTS,
+        }]
+    );
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn skipped_packets_and_corrupt_data_are_warned_about() {
+    use libcryptocam::packet::{PacketKind, PacketReader};
+
+    // four frames of 512 bytes with `between` after the second
+    let video = |between: &dyn Fn(FixtureVideo) -> FixtureVideo| {
+        let frames = FixtureVideo::new().h264_frames(4, 512);
+        let mut packets = FixtureVideo::new();
+        for (i, frame) in PacketReader::new(frames.payload()).enumerate() {
+            let frame = frame.unwrap();
+            packets = packets.video_packet(frame.pts_us, &frame.data);
+            if i == 1 {
+                packets = between(packets);
+            }
+        }
+        FixtureFile::video(VIDEO_METADATA, packets).build()
+    };
+    let decrypt = |file, options: DecryptOptions| {
+        let out_dir = tempfile::tempdir().unwrap();
+        let (result, recorder) = run_in(out_dir.path(), file, options);
+        match result {
+            JobResult::Complete {
+                output: Some(output),
+            } => assert_eq!(video_samples(&output), 4),
+            result => panic!("{:?} {:?}", result, recorder.errors),
+        }
+        recorder.warnings
+    };
+    let options = DecryptOptions::new().video_backend(VideoBackend::RustMp4);
+
+    let file = video(&|packets| packets.packet(PacketKind::Unknown(7), 40_000, b"newer app"));
+    assert_eq!(
+        decrypt(file, options.clone()),
+        [DecryptWarning::UnknownPacketTypeSkipped(7)]
+    );
+
+    let file = video(&|packets| packets.raw_bytes(&[0xee; 100]));
+    assert_eq!(
+        decrypt(file, options.resync_on_error(true)),
+        // after two packets with their 13 byte headers
+        [DecryptWarning::CorruptDataSkipped {
+            offset: 2 * (13 + 512),
+            len: 100,
+        }]
+    );
+}
+
+/// Decrypts an image declared as `format`, checks that the payload was written as it is and
+/// returns the extension of the output along with the warnings.
+fn image_as(
+    format: &str,
+    payload: &[u8],
+    options: DecryptOptions,
+) -> (String, Vec<DecryptWarning>) {
+    let metadata = format!(
+        r#"{{"timestamp":"2021-06-01T12:00:00Z","format":"{}"}}"#,
+        format
+    );
+    let out_dir = tempfile::tempdir().unwrap();
+    let file = FixtureFile::image(metadata, payload).build();
+    let (result, recorder) = run_in(out_dir.path(), file, options);
+    let output = match result {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?} {:?}", result, recorder.errors),
+    };
+    assert_eq!(std::fs::read(&output).unwrap(), payload);
+    let extension = output.extension().unwrap().to_string_lossy().into_owned();
+    (extension, recorder.warnings)
+}
+
+#[test]
+fn image_extensions_follow_the_contents() {
+    let png = b"\x89PNG\r\n\x1a\n a png";
+    let (extension, warnings) = image_as("PNG", png, DecryptOptions::new());
+    assert_eq!(extension, "PNG");
+    assert!(warnings.is_empty(), "{:?}", warnings);
+
+    let mismatch = DecryptWarning::FormatMismatch {
+        declared: "jpg".to_owned(),
+        detected: "png".to_owned(),
+    };
+    let (extension, warnings) = image_as("jpg", png, DecryptOptions::new());
+    assert_eq!(extension, "png");
+    assert_eq!(warnings, [mismatch.clone()]);
+    let options = DecryptOptions::new().image_format_mismatch(ImageFormatMismatch::Warn);
+    let (extension, warnings) = image_as("jpg", png, options);
+    assert_eq!(extension, "jpg");
+    assert_eq!(warnings, [mismatch]);
+
+    // contents without a known magic keep a known declared format
+    let unknown = b"\x00\x01 no magic";
+    let (extension, warnings) = image_as("png", unknown, DecryptOptions::new());
+    assert_eq!(extension, "png");
+    assert_eq!(
+        warnings,
+        [DecryptWarning::UnrecognizedImage {
+            declared: "png".to_owned()
+        }]
+    );
+    // and are saved as .bin without one
+    let (extension, warnings) = image_as("tiff", unknown, DecryptOptions::new());
+    assert_eq!(extension, "bin");
+    assert_eq!(
+        warnings,
+        [DecryptWarning::UnknownImageFormat {
+            declared: "tiff".to_owned()
+        }]
+    );
+}
+
+/// Asserts that the job wrote an output and reported `expected(output)` as its digest.
+fn assert_output_digest(
+    (result, recorder): (JobResult, Recorder),
+    algo: HashAlgo,
+    expected: fn(&[u8]) -> Vec<u8>,
+) {
+    let output = match result {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?}", result),
+    };
+    let digest = recorder
+        .output_digest
+        .expect("on_output_digest() was called");
+    assert_eq!(digest.algo(), algo);
+    assert_eq!(
+        digest.as_bytes(),
+        &expected(&std::fs::read(output).unwrap())[..]
+    );
+}
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    sha2::Sha256::digest(data).to_vec()
+}
+
+fn sha512(data: &[u8]) -> Vec<u8> {
+    sha2::Sha512::digest(data).to_vec()
+}
+
+#[test]
+fn image_digests_are_those_of_the_output() {
+    for (algo, expected) in [
+        (HashAlgo::Sha256, sha256 as fn(&[u8]) -> Vec<u8>),
+        (HashAlgo::Sha512, sha512),
+    ] {
+        let options = DecryptOptions::new().output_digest(algo);
+        assert_output_digest(run(image(200_000), options), algo, expected);
+    }
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn video_digests_are_those_of_the_output() {
+    for faststart in [false, true] {
+        let options = DecryptOptions::new()
+            .video_backend(VideoBackend::RustMp4)
+            .container(VideoContainer::Mp4)
+            .faststart(faststart)
+            .output_digest(HashAlgo::Sha256);
+        assert_output_digest(run(video(8, 32 << 10), options), HashAlgo::Sha256, sha256);
+    }
+}
+
+#[cfg(feature = "video")]
+#[test]
+fn streamed_videos_are_written_as_fragmented_mp4() {
+    let packets = FixtureVideo::new().h264_frames(30, 4096);
+    let file = FixtureFile::video(
+        r#"{"width":640,"height":480,"video_bitrate":1000000,"audio_sample_rate":44100,"audio_channel_count":1,"audio_bitrate":64000,"timestamp":"2021-06-01T12:00:00Z"}"#,
+        packets,
+    )
+    .build();
+    let (reader, mut writer) = os_pipe::pipe().unwrap();
+    let feeding = std::thread::spawn(move || std::io::Write::write_all(&mut writer, &file));
+
+    let out_dir = tempfile::tempdir().unwrap();
+    let options = DecryptOptions::new()
+        .video_backend(VideoBackend::FFmpeg)
+        .container(VideoContainer::Mp4)
+        .fragmented(true);
+    let mut job = decrypt_stream(
+        reader,
+        &mut test_keyring(),
+        out_dir.path().to_path_buf(),
+        options,
+    )
+    .unwrap();
+    let mut recorder = Recorder::default();
+    let result = job.run_with_token(Box::new(&mut recorder), &CancellationToken::new());
+    feeding.join().unwrap().unwrap();
+
+    let output = match result {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?} {:?}", result, recorder.errors),
+    };
+    assert!(recorder.completed);
+    // the size of a stream isn't known
+    assert_eq!(recorder.total_file_size, Some(0));
+    match &recorder.stats {
+        Some(DecryptStats::Video(stats)) => assert_eq!(stats.video_packets, 30),
+        stats => panic!("{:?}", stats),
+    }
+    let mp4 = std::fs::read(output).unwrap();
+    assert_eq!(&mp4[4..8], b"ftyp");
+    for box_type in [b"moov", b"mvex", b"moof", b"mdat"] {
+        assert!(
+            mp4.windows(4).any(|w| w == box_type),
+            "no {} box",
+            String::from_utf8_lossy(box_type)
+        );
+    }
+}
+
+/// The time base and average frame rate of the video stream of the file at `path`.
+#[cfg(feature = "video")]
+fn video_stream_rates(path: &Path) -> ((i32, i32), f64) {
+    use ac_ffmpeg::format::{demuxer::Demuxer, io::IO};
+    let io = IO::from_seekable_read_stream(std::fs::File::open(path).unwrap());
+    let demuxer = Demuxer::builder()
+        .build(io)
+        .unwrap()
+        .find_stream_info(None)
+        .map_err(|(_, e)| e)
+        .unwrap();
+    let stream = demuxer
+        .streams()
+        .iter()
+        .find(|stream| stream.codec_parameters().is_video_codec())
+        .expect("the output has a video stream");
+    let (time_base, rate) = (stream.time_base(), stream.avg_frame_rate());
+    (
+        (time_base.num(), time_base.den()),
+        f64::from(rate.num()) / f64::from(rate.den()),
+    )
+}
+
+#[cfg(feature = "video")]
+#[test]
+fn muxed_streams_have_the_metadata_frame_rate() {
+    // frames 33_333 µs apart, but the camera was set to 29.97 frames per second
+    let packets = FixtureVideo::new().h264_frames(30, 4096);
+    let file = FixtureFile::video(
+        r#"{"width":640,"height":480,"video_bitrate":1000000,"audio_sample_rate":44100,"audio_channel_count":1,"audio_bitrate":64000,"timestamp":"2021-06-01T12:00:00Z","frame_rate":29.97}"#,
+        packets.clone(),
+    )
+    .build();
+    let output = |result: JobResult, recorder: &Recorder| match result {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?} {:?}", result, recorder.errors),
+    };
+
+    let options = DecryptOptions::new()
+        .video_backend(VideoBackend::FFmpeg)
+        .container(VideoContainer::Mp4);
+    let out_dir = tempfile::tempdir().unwrap();
+    let (result, recorder) = run_in(out_dir.path(), file.clone(), options);
+    let (time_base, _) = video_stream_rates(&output(result, &recorder));
+    // the PTS are in microseconds, so is the track timescale
+    assert_eq!(time_base, (1, 1_000_000));
+    match &recorder.stats {
+        Some(DecryptStats::Video(stats)) => {
+            let frame_rate = stats.frame_rate.map(|rate| (rate.num, rate.den));
+            assert_eq!(frame_rate, Some((2997, 100)))
+        }
+        stats => panic!("{:?}", stats),
+    }
+
+    // Matroska keeps the average frame rate as the track's default duration, MP4 has no place
+    // for it
+    let options = DecryptOptions::new()
+        .video_backend(VideoBackend::FFmpeg)
+        .container(VideoContainer::Mkv);
+    let out_dir = tempfile::tempdir().unwrap();
+    let (result, recorder) = run_in(out_dir.path(), file, options);
+    let (_, frame_rate) = video_stream_rates(&output(result, &recorder));
+    assert!((frame_rate - 29.97).abs() < 0.001, "{} fps", frame_rate);
+
+    // without one in the metadata it's estimated from the PTS
+    let (result, recorder) = run(
+        FixtureFile::video(
+            r#"{"width":640,"height":480,"video_bitrate":1000000,"audio_sample_rate":44100,"audio_channel_count":1,"audio_bitrate":64000,"timestamp":"2021-06-01T12:00:00Z"}"#,
+            packets,
+        )
+        .build(),
+        DecryptOptions::new().video_backend(VideoBackend::FFmpeg),
+    );
+    assert!(recorder.completed, "{:?} {:?}", result, recorder.errors);
+    match &recorder.stats {
+        Some(DecryptStats::Video(stats)) => {
+            let frame_rate = stats.frame_rate.map(|rate| (rate.num, rate.den));
+            assert_eq!(frame_rate, Some((30, 1)))
+        }
+        stats => panic!("{:?}", stats),
+    }
+}
+
+#[cfg(all(feature = "video", feature = "rust-mp4"))]
+#[test]
+fn muxed_mp4s_are_dated_to_the_recording() {
+    /// 2021-06-01T12:00:00Z in seconds since 1904, where MP4 times count from.
+    const RECORDED: u64 = 1_622_548_800 + 2_082_844_800;
+    // the same time two hours east of UTC
+    let metadata = VIDEO_METADATA.replace("12:00:00Z", "14:00:00+02:00");
+    let file = FixtureFile::video(metadata, FixtureVideo::new().h264_frames(10, 512)).build();
+    let options = DecryptOptions::new()
+        .video_backend(VideoBackend::FFmpeg)
+        .container(VideoContainer::Mp4);
+    let out_dir = tempfile::tempdir().unwrap();
+    let (result, recorder) = run_in(out_dir.path(), file, options);
+    let output = match result {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?} {:?}", result, recorder.errors),
+    };
+
+    let mp4 = mp4::read_mp4(std::fs::File::open(output).unwrap()).unwrap();
+    assert_eq!(mp4.moov.mvhd.creation_time, RECORDED);
+    assert_eq!(mp4.tracks().len(), 1);
+    for track in mp4.tracks().values() {
+        assert_eq!(track.trak.tkhd.creation_time, RECORDED);
+        assert_eq!(track.trak.mdia.mdhd.creation_time, RECORDED);
+    }
+}
+
+/// The decoder names of the streams of the file at `path` with the number of packets of each,
+/// as FFmpeg demuxes them.
+#[cfg(feature = "video")]
+fn demuxed_streams(path: &Path) -> Vec<(&'static str, usize)> {
+    use ac_ffmpeg::format::{demuxer::Demuxer, io::IO};
+    let io = IO::from_seekable_read_stream(std::fs::File::open(path).unwrap());
+    let mut demuxer = Demuxer::builder()
+        .build(io)
+        .unwrap()
+        .find_stream_info(None)
+        .map_err(|(_, e)| e)
+        .unwrap();
+    let mut streams: Vec<_> = demuxer
+        .streams()
+        .iter()
+        .map(|stream| (stream.codec_parameters().decoder_name().unwrap(), 0))
+        .collect();
+    while let Some(packet) = demuxer.take().unwrap() {
+        streams[packet.stream_index()].1 += 1;
+    }
+    streams.sort_unstable();
+    streams
+}
+
+#[cfg(feature = "video")]
+#[test]
+fn opus_and_aac_audio_are_written_to_matroska() {
+    let metadata = |audio_codec: &str, sample_rate: u32| {
+        VIDEO_METADATA
+            .replace("44100", &sample_rate.to_string())
+            .replace('}', &format!(r#","audio_codec":"{}"}}"#, audio_codec))
+    };
+    // ten frames, each with an audio packet
+    let with_audio = |audio: &[u8]| {
+        let frames = FixtureVideo::new().h264_frames(10, 512);
+        libcryptocam::packet::PacketReader::new(frames.payload()).fold(
+            FixtureVideo::new(),
+            |packets, frame| {
+                let frame = frame.unwrap();
+                packets
+                    .video_packet(frame.pts_us, &frame.data)
+                    .audio_packet(frame.pts_us, audio)
+            },
+        )
+    };
+    let output = |(result, recorder): (JobResult, Recorder)| match result {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?} {:?}", result, recorder.errors),
+    };
+    let options = DecryptOptions::new().video_backend(VideoBackend::FFmpeg);
+
+    // Opus goes to Matroska by default, MP4 can't hold it. 0xfc is the TOC byte of a 20 ms
+    // fullband CELT frame.
+    let opus = FixtureFile::video(metadata("opus", 48_000), with_audio(&[0xfc; 40])).build();
+    let out_dir = tempfile::tempdir().unwrap();
+    let mkv = output(run_in(out_dir.path(), opus, options.clone()));
+    assert_eq!(mkv.extension().unwrap(), "mkv");
+    assert_eq!(&std::fs::read(&mkv).unwrap()[..4], b"\x1a\x45\xdf\xa3");
+    assert_eq!(demuxed_streams(&mkv), [("h264", 10), ("opus", 10)]);
+
+    // AAC only if asked for
+    let aac = FixtureFile::video(metadata("aac", 44_100), with_audio(&adts_frame(100))).build();
+    let out_dir = tempfile::tempdir().unwrap();
+    let mkv = output(run_in(
+        out_dir.path(),
+        aac,
+        options.container(VideoContainer::Mkv),
+    ));
+    assert_eq!(mkv.extension().unwrap(), "mkv");
+    assert_eq!(demuxed_streams(&mkv), [("aac", 10), ("h264", 10)]);
+}
+
+#[cfg(feature = "video")]
+#[test]
+fn unknown_channel_layouts_are_warned_about() {
+    let metadata = VIDEO_METADATA.replace('}', r#","audio_channel_layout":"surround-ish"}"#);
+    let packets = FixtureVideo::new()
+        .h264_frames(1, 512)
+        .audio_packet(0, &adts_frame(100));
+    let file = FixtureFile::video(metadata, packets).build();
+    let options = DecryptOptions::new().video_backend(VideoBackend::FFmpeg);
+    let (result, recorder) = run(file, options);
+    assert!(recorder.completed, "{:?} {:?}", result, recorder.errors);
+    // the usual layout for the one channel is used
+    assert_eq!(
+        recorder.warnings,
+        [DecryptWarning::ChannelLayoutGuessed {
+            layout: Some("surround-ish".to_owned()),
+            channel_count: 1,
+            stereo: false,
+        }]
+    );
+}
+
+/// `files` written to `dir` and opened again, for stitch().
+#[cfg(feature = "rust-mp4")]
+fn segment_files(dir: &Path, files: &[Vec<u8>]) -> Vec<std::fs::File> {
+    files
+        .iter()
+        .enumerate()
+        .map(|(i, file)| {
+            let path = dir.join(format!("segment{}", i));
+            std::fs::write(&path, file).unwrap();
+            std::fs::File::open(path).unwrap()
+        })
+        .collect()
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn stitched_segments_continue_each_other() {
+    let in_dir = tempfile::tempdir().unwrap();
+    let out_dir = tempfile::tempdir().unwrap();
+    let files = segment_files(in_dir.path(), &[video(30, 1024), video(30, 1024)]);
+    let options = DecryptOptions::new()
+        .video_backend(VideoBackend::RustMp4)
+        .container(VideoContainer::Mp4);
+    let mut job = stitch(
+        files,
+        &mut test_keyring(),
+        out_dir.path().to_path_buf(),
+        options,
+    )
+    .unwrap();
+    let mut recorder = Recorder::default();
+    let output = match job.run_with_token(Box::new(&mut recorder), &CancellationToken::new()) {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?} {:?}", result, recorder.errors),
+    };
+
+    let mut mp4 = mp4::read_mp4(std::fs::File::open(output).unwrap()).unwrap();
+    let (track_id, timescale, sample_count, duration) = mp4
+        .tracks()
+        .values()
+        .find(|track| matches!(track.track_type(), Ok(mp4::TrackType::Video)))
+        .map(|track| {
+            let duration = track.duration().as_micros() as i64;
+            (
+                track.track_id(),
+                track.timescale(),
+                track.sample_count(),
+                duration,
+            )
+        })
+        .expect("the output has a video track");
+    assert_eq!(sample_count, 60);
+    assert!((duration - 60 * 33_333).abs() <= 33_333, "{} µs", duration);
+    // no gap or overlap where the second segment starts
+    for sample_id in 1..=sample_count {
+        let sample = mp4.read_sample(track_id, sample_id).unwrap().unwrap();
+        let pts = (sample.start_time as i64 + sample.rendering_offset as i64) * 1_000_000
+            / timescale as i64;
+        let expected = (sample_id as i64 - 1) * 33_333;
+        assert!(
+            (pts - expected).abs() <= 1000,
+            "sample {} at {} µs",
+            sample_id,
+            pts
+        );
+    }
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn segments_of_another_size_are_not_stitched() {
+    let in_dir = tempfile::tempdir().unwrap();
+    let out_dir = tempfile::tempdir().unwrap();
+    let larger = FixtureFile::video(
+        VIDEO_METADATA.replace(
+            r#""width":640,"height":480"#,
+            r#""width":1280,"height":720"#,
+        ),
+        FixtureVideo::new().h264_frames(30, 1024),
+    )
+    .build();
+    let files = segment_files(in_dir.path(), &[video(30, 1024), larger]);
+    let options = DecryptOptions::new()
+        .video_backend(VideoBackend::RustMp4)
+        .container(VideoContainer::Mp4);
+    let e = stitch(
+        files,
+        &mut test_keyring(),
+        out_dir.path().to_path_buf(),
+        options,
+    )
+    .err()
+    .expect("the segments are refused");
+
+    match e.downcast_ref::<error::Error>() {
+        Some(error::Error::IncompatibleSegments { index: 1, reason }) => {
+            assert!(reason.contains("1280x720"), "{}", reason)
+        }
+        _ => panic!("{:?}", e),
+    }
+    assert_eq!(std::fs::read_dir(out_dir.path()).unwrap().count(), 0);
+}
+
+/// The NAL units of the samples of the video track of the MP4 at `path`, without the
+/// parameter sets and access unit delimiters the muxer may move in or out of the samples.
+#[cfg(feature = "rust-mp4")]
+fn video_nal_units(path: &Path) -> Vec<Vec<Vec<u8>>> {
+    let mut mp4 = mp4::read_mp4(std::fs::File::open(path).unwrap()).unwrap();
+    let (track_id, sample_count) = mp4
+        .tracks()
+        .values()
+        .find(|track| matches!(track.track_type(), Ok(mp4::TrackType::Video)))
+        .map(|track| (track.track_id(), track.sample_count()))
+        .expect("the output has a video track");
+    (1..=sample_count)
+        .map(|sample_id| {
+            let sample = mp4.read_sample(track_id, sample_id).unwrap().unwrap();
+            let mut units = vec![];
+            let mut rest = &sample.bytes[..];
+            while rest.len() >= 4 {
+                let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+                units.push(rest[4..4 + len].to_vec());
+                rest = &rest[4 + len..];
+            }
+            units.retain(|unit: &Vec<u8>| !matches!(unit[0] & 0x1f, 7 | 8 | 9));
+            units
+        })
+        .collect()
+}
+
+#[cfg(all(feature = "video", feature = "rust-mp4"))]
+#[test]
+fn imported_mp4s_decrypt_to_the_same_video() {
+    let dir = tempfile::tempdir().unwrap();
+    let (first_dir, second_dir) = (dir.path().join("first"), dir.path().join("second"));
+    std::fs::create_dir(&first_dir).unwrap();
+    std::fs::create_dir(&second_dir).unwrap();
+    let options = DecryptOptions::new()
+        .video_backend(VideoBackend::FFmpeg)
+        .container(VideoContainer::Mp4);
+    let mp4 = match run_in(&first_dir, video(30, 1024), options.clone()).0 {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?}", result),
+    };
+
+    let encrypted = dir.path().join("imported");
+    import_mp4(
+        &mp4,
+        &[test_recipient()],
+        &encrypted,
+        ImportOptions::new().timestamp("2021-06-01T12:00:00Z"),
+    )
+    .unwrap();
+    let (result, recorder) = run_in(&second_dir, std::fs::read(&encrypted).unwrap(), options);
+    let reimported = match result {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?} {:?}", result, recorder.errors),
+    };
+
+    // named after the same timestamp
+    assert_eq!(reimported.file_name(), mp4.file_name());
+    let (original, reimported) = (video_nal_units(&mp4), video_nal_units(&reimported));
+    assert_eq!(original.len(), 30);
+    assert_eq!(original, reimported);
+    match &recorder.stats {
+        Some(DecryptStats::Video(stats)) => assert_eq!(stats.video_packets, 30),
+        stats => panic!("{:?}", stats),
+    }
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn encrypted_videos_decrypt_to_the_pushed_packets() {
+    // the same frames as FixtureVideo::h264_frames()
+    const SPS_PPS: &[u8] = b"\x00\x00\x00\x01\x67\x42\x00\x1e\xab\x00\x00\x00\x01\x68\xce\x3c\x80";
+    const START_CODE: &[u8] = b"\x00\x00\x00\x01";
+    let metadata = VideoMetadata::new("2021-06-01T12:00:00Z", 640, 480);
+    let mut encryptor = VideoEncryptor::new(&[test_recipient()], &metadata, Vec::new()).unwrap();
+    let mut slices = vec![];
+    for i in 0..30 {
+        let mut slice = vec![if i == 0 { 0x65 } else { 0x41 }];
+        slice.resize(1024, 0x88);
+        let mut frame = if i == 0 { SPS_PPS.to_vec() } else { vec![] };
+        frame.extend_from_slice(START_CODE);
+        frame.extend_from_slice(&slice);
+        encryptor.push_video(i * 33_333, &frame).unwrap();
+        slices.push(vec![slice]);
+    }
+    let file = encryptor.finish().unwrap();
+
+    let out_dir = tempfile::tempdir().unwrap();
+    let options = DecryptOptions::new().video_backend(VideoBackend::RustMp4);
+    let (result, recorder) = run_in(out_dir.path(), file, options);
+    let output = match result {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?} {:?}", result, recorder.errors),
+    };
+    assert!(recorder.warnings.is_empty(), "{:?}", recorder.warnings);
+    assert_eq!(video_nal_units(&output), slices);
+}
+
+#[test]
+fn verifying_images_writes_nothing() {
+    let out_dir = tempfile::tempdir().unwrap();
+    let options = DecryptOptions::new().verify_only(true);
+    let (result, recorder) = run_in(out_dir.path(), image(200_000), options.clone());
+    assert_eq!(result, JobResult::Complete { output: None });
+    assert_eq!(
+        recorder.verified,
+        Some(VerificationReport::Image {
+            bytes: 200_000,
+            format_matches_magic: true,
+        })
+    );
+
+    let (file, corrupted_offset) = corrupt_last_chunk(image(200_000));
+    let (result, recorder) = run_in(out_dir.path(), file, options);
+    assert!(matches!(result, JobResult::Failed(_)), "{:?}", result);
+    assert_integrity_error(recorder.errors[0].as_ref(), corrupted_offset);
+    assert_eq!(recorder.verified, None);
+    assert_eq!(std::fs::read_dir(out_dir.path()).unwrap().count(), 0);
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn verifying_videos_writes_nothing() {
+    let out_dir = tempfile::tempdir().unwrap();
+    let options = DecryptOptions::new()
+        .video_backend(VideoBackend::RustMp4)
+        .verify_only(true)
+        .finalize_on_truncation(false);
+    let (result, recorder) = run_in(out_dir.path(), video(8, 32 << 10), options.clone());
+    assert_eq!(result, JobResult::Complete { output: None });
+    assert_eq!(
+        recorder.verified,
+        Some(VerificationReport::Video {
+            packets_video: 8,
+            packets_audio: 0,
+            bytes_video: 8 * (32 << 10),
+            bytes_audio: 0,
+            duration_us: 7 * 33_333,
+            truncated: false,
+        })
+    );
+
+    let (file, corrupted_offset) = corrupt_last_chunk(video(8, 32 << 10));
+    let (result, recorder) = run_in(out_dir.path(), file, options);
+    assert!(matches!(result, JobResult::Failed(_)), "{:?}", result);
+    assert_integrity_error(recorder.errors[0].as_ref(), corrupted_offset);
+    assert_eq!(recorder.verified, None);
+    assert_eq!(std::fs::read_dir(out_dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn sidecars_describe_the_output() {
+    let out_dir = tempfile::tempdir().unwrap();
+    let options = DecryptOptions::new()
+        .write_metadata_sidecar(true)
+        .output_digest(HashAlgo::Sha256);
+    let (result, recorder) = run_in(out_dir.path(), image(200_000), options);
+    let output = match result {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?} {:?}", result, recorder.errors),
+    };
+
+    let json = std::fs::read(output.with_extension("json")).unwrap();
+    let sidecar: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(sidecar["file_type"], "image");
+    assert_eq!(sidecar["header_version"], 1);
+    assert_eq!(
+        sidecar["recipient_digests"],
+        serde_json::json!([test_recipient().digest().to_hex()])
+    );
+    assert_eq!(
+        sidecar["metadata"],
+        serde_json::json!({"timestamp": "2021-06-01T12:00:00Z", "format": "png"})
+    );
+    let digest = format!(
+        "sha256:{}",
+        sha256(&std::fs::read(&output).unwrap())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    );
+    assert_eq!(sidecar["output_digest"], digest);
+    assert_eq!(std::fs::read_dir(out_dir.path()).unwrap().count(), 2);
+}
+
+#[test]
+fn sidecars_of_failed_jobs_are_removed() {
+    let out_dir = tempfile::tempdir().unwrap();
+    let (file, _) = corrupt_last_chunk(image(200_000));
+    let options = DecryptOptions::new().write_metadata_sidecar(true);
+    let (result, _) = run_in(out_dir.path(), file, options);
+    assert!(matches!(result, JobResult::Failed(_)), "{:?}", result);
+
+    let sidecars: Vec<_> = std::fs::read_dir(out_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.contains(".json"))
+        .collect();
+    assert!(sidecars.is_empty(), "{:?}", sidecars);
+}
+
+#[test]
+fn unknown_metadata_fields_reach_the_naming_callback_and_the_sidecar() {
+    let extra = serde_json::json!({"lens": "wide", "exposure": {"iso": 100, "ms": 8}});
+    let mut metadata = serde_json::json!({"timestamp": "2021-06-01T12:00:00Z", "format": "png"});
+    for (key, value) in extra.as_object().unwrap() {
+        metadata[key] = value.clone();
+    }
+    let file =
+        FixtureFile::image(metadata.to_string(), b"\x89PNG\r\n\x1a\n a png".to_vec()).build();
+    let named = Arc::new(std::sync::Mutex::new(None));
+    let naming = {
+        let named = named.clone();
+        OutputNaming::Callback(Arc::new(move |info: &MediaInfo| {
+            *named.lock().unwrap() = Some(info.extra.clone());
+            format!(
+                "{}-{}",
+                info.extra["lens"].as_str().unwrap(),
+                info.extra["exposure"]["iso"]
+            )
+        }))
+    };
+    let options = DecryptOptions::new()
+        .naming(naming)
+        .write_metadata_sidecar(true);
+    let out_dir = tempfile::tempdir().unwrap();
+    let (result, recorder) = run_in(out_dir.path(), file.clone(), options);
+    let output = match result {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?} {:?}", result, recorder.errors),
+    };
+
+    assert_eq!(output.file_name().unwrap(), "wide-100.png");
+    let named = named.lock().unwrap().take().map(serde_json::Value::Object);
+    assert_eq!(named, Some(extra));
+    let json = std::fs::read(output.with_extension("json")).unwrap();
+    let sidecar: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(sidecar["metadata"], metadata);
+
+    // and templates can name the output after them, objects as sanitized JSON
+    let template = NameTemplate::parse("{extra.lens}_{extra.exposure}_{extra.flash}").unwrap();
+    let options = DecryptOptions::new().naming(OutputNaming::Template(template));
+    let (result, recorder) = run_in(out_dir.path(), file, options);
+    match result {
+        JobResult::Complete {
+            output: Some(output),
+        } => assert_eq!(output.file_name().unwrap(), "wide_{-iso--100,-ms--8}_.png"),
+        result => panic!("{:?} {:?}", result, recorder.errors),
+    }
+}
+
+/// Runs a job for `file`, whose metadata `metadata` is cut off, and checks that the payload
+/// and the metadata were written as they are to `<name>.bin` and `<name>.metadata.raw`.
+/// Returns the name of the payload file.
+fn assert_recovered(
+    file: Vec<u8>,
+    metadata: &str,
+    payload: &[u8],
+    options: DecryptOptions,
+) -> String {
+    let out_dir = tempfile::tempdir().unwrap();
+    let strict = decrypt_from_reader(
+        Cursor::new(file.clone()),
+        None,
+        &mut test_keyring(),
+        out_dir.path().to_path_buf(),
+        options.clone(),
+    );
+    assert!(strict.is_err(), "the metadata is refused by default");
+
+    let options = options.recover_raw_on_bad_metadata(true);
+    let (result, recorder) = run_in(out_dir.path(), file, options);
+    let output = match result {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?} {:?}", result, recorder.errors),
+    };
+    assert_eq!(std::fs::read(&output).unwrap(), payload);
+    assert_eq!(
+        std::fs::read(output.with_extension("metadata.raw")).unwrap(),
+        metadata.as_bytes()
+    );
+    assert!(
+        matches!(
+            &recorder.warnings[..],
+            [DecryptWarning::MetadataUnreadable { .. }]
+        ),
+        "{:?}",
+        recorder.warnings
+    );
+    assert_eq!(std::fs::read_dir(out_dir.path()).unwrap().count(), 2);
+    output.file_name().unwrap().to_string_lossy().into_owned()
+}
+
+#[test]
+fn images_with_truncated_metadata_are_recovered_raw() {
+    let metadata = r#"{"timestamp":"2021-06-01T12:00:00Z","for"#;
+    let payload = b"\x89PNG\r\n\x1a\n image";
+    let file = FixtureFile::image(metadata, &payload[..]).build();
+    let options = DecryptOptions::new().source_stem("IMG_0001");
+    let name = assert_recovered(file, metadata, payload, options);
+    assert_eq!(name, "IMG_0001.bin");
+}
+
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+#[test]
+fn videos_with_truncated_metadata_are_recovered_as_packet_streams() {
+    let metadata = r#"{"width":640,"height":480,"video_bitrate":1000000,"audio_samp"#;
+    let packets = FixtureVideo::new().h264_frames(8, 1024);
+    let file = FixtureFile::video(metadata, packets.clone()).build();
+    let name = assert_recovered(file, metadata, packets.payload(), DecryptOptions::new());
+    assert_eq!(name, "recovered.bin");
+}
+
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+#[test]
+fn raw_packet_dumps_read_back_as_the_original_stream() {
+    use libcryptocam::packet::{PacketKind, PacketReader};
+
+    let sent = [
+        (PacketKind::Video, 0, vec![0x65; 300]),
+        (PacketKind::Audio, 10_000, vec![0xff; 20]),
+        (PacketKind::Unknown(9), 20_000, vec![1, 2, 3]),
+        (PacketKind::Video, 33_333, vec![0x41; 200]),
+    ];
+    let packets = sent
+        .iter()
+        .fold(FixtureVideo::new(), |packets, (kind, pts, data)| {
+            packets.packet(*kind, *pts, data)
+        });
+    let metadata = r#"{"width":640,"height":480,"video_bitrate":1000000,"audio_sample_rate":44100,"audio_channel_count":1,"audio_bitrate":64000,"timestamp":"2021-06-01T12:00:00Z"}"#;
+    let file = FixtureFile::video(metadata, packets.clone()).build();
+    let out_dir = tempfile::tempdir().unwrap();
+    let options = DecryptOptions::new()
+        .container(VideoContainer::RawPackets)
+        .raw_packet_index(true);
+    let output = match run_in(out_dir.path(), file, options).0 {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?}", result),
+    };
+    assert_eq!(output.extension().unwrap(), "packets");
+    assert_eq!(std::fs::read(&output).unwrap(), packets.payload());
+
+    let read: Vec<_> = PacketReader::new(std::fs::File::open(&output).unwrap())
+        .map(|packet| {
+            let packet = packet.unwrap();
+            (packet.kind, packet.pts_us, packet.data)
+        })
+        .collect();
+    assert_eq!(read, sent);
+
+    let index: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(output.with_extension("index.json")).unwrap())
+            .unwrap();
+    assert_eq!(
+        index,
+        serde_json::json!([
+            {"type": 1, "pts_us": 0, "length": 300, "offset": 0},
+            {"type": 2, "pts_us": 10_000, "length": 20, "offset": 313},
+            {"type": 9, "pts_us": 20_000, "length": 3, "offset": 346},
+            {"type": 1, "pts_us": 33_333, "length": 200, "offset": 362},
+        ])
+    );
+}
+
+/// The job's estimate of the output size, and the size of the output it wrote.
+fn estimated_and_actual_size(file: Vec<u8>, options: DecryptOptions) -> (u64, u64) {
+    let out_dir = tempfile::tempdir().unwrap();
+    let mut job = decrypt_from_reader(
+        Cursor::new(file),
+        None,
+        &mut test_keyring(),
+        out_dir.path().to_path_buf(),
+        options,
+    )
+    .unwrap();
+    let estimate = job
+        .estimated_output_size()
+        .expect("the input size is known");
+    let mut recorder = Recorder::default();
+    match job.run_with_token(Box::new(&mut recorder), &CancellationToken::new()) {
+        JobResult::Complete {
+            output: Some(output),
+        } => (estimate, std::fs::metadata(output).unwrap().len()),
+        result => panic!("{:?} {:?}", result, recorder.errors),
+    }
+}
+
+#[test]
+fn image_size_estimates_are_exact() {
+    for len in [100, 200_000, 1 << 20] {
+        let (estimate, actual) = estimated_and_actual_size(image(len), DecryptOptions::new());
+        assert_eq!(estimate, actual);
+        assert_eq!(actual, len as u64);
+    }
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn video_size_estimates_are_close() {
+    let options = DecryptOptions::new().video_backend(VideoBackend::RustMp4);
+    let (estimate, actual) = estimated_and_actual_size(video(100, 4096), options);
+    // 13 bytes of packet header against 4 bytes of sample length and the MP4 boxes
+    assert!(estimate >= actual, "{} < {}", estimate, actual);
+    assert!(
+        estimate - actual < actual / 20,
+        "{} for {}",
+        estimate,
+        actual
+    );
+}
+
+/// An AAC LC frame of `len` bytes for 44.1 kHz mono, with its ADTS header.
+#[cfg(any(feature = "video", feature = "rust-mp4"))]
+fn adts_frame(len: usize) -> Vec<u8> {
+    let frame_len = 7 + len;
+    let mut frame = vec![
+        0xff,
+        0xf1,
+        0x50,
+        0x40 | (frame_len >> 11) as u8,
+        (frame_len >> 3) as u8,
+        ((frame_len & 7) << 5) as u8 | 0x1f,
+        0xfc,
+    ];
+    frame.resize(frame_len, 0x21);
+    frame
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn stream_progress_shows_audio_that_stops_halfway() {
+    use libcryptocam::packet::PacketKind;
+
+    // two seconds of video, one of audio
+    let mut packets: Vec<(PacketKind, u64)> = (0..60)
+        .map(|i| (PacketKind::Video, i * 33_333))
+        .chain((0..44).map(|i| (PacketKind::Audio, i * 1024 * 1_000_000 / 44_100)))
+        .collect();
+    packets.sort_by_key(|&(kind, pts)| (pts, kind == PacketKind::Audio));
+    let video_frames = FixtureVideo::new().h264_frames(60, 512);
+    let mut frames = libcryptocam::packet::PacketReader::new(video_frames.payload())
+        .map(|packet| packet.unwrap().data);
+    let audio = adts_frame(100);
+    let packets =
+        packets
+            .into_iter()
+            .fold(FixtureVideo::new(), |packets, (kind, pts)| match kind {
+                PacketKind::Video => packets.video_packet(pts, &frames.next().unwrap()),
+                _ => packets.audio_packet(pts, &audio),
+            });
+    let file = FixtureFile::video(VIDEO_METADATA, packets).build();
+    let options = DecryptOptions::new().video_backend(VideoBackend::RustMp4);
+    let (result, recorder) = run(file, options);
+    assert!(
+        matches!(result, JobResult::Complete { .. }),
+        "{:?} {:?}",
+        result,
+        recorder.errors
+    );
+
+    let last = |stream| {
+        recorder
+            .stream_progress
+            .iter()
+            .rev()
+            .find(|progress| progress.0 == stream)
+            .copied()
+            .unwrap()
+    };
+    let (_, video_packets, video_bytes, video_pts) = last(PacketKind::Video);
+    let (_, audio_packets, audio_bytes, audio_pts) = last(PacketKind::Audio);
+    assert_eq!((video_packets, video_bytes), (60, 60 * 512));
+    assert_eq!((audio_packets, audio_bytes), (44, 44 * 107));
+    assert_eq!(video_pts, 59 * 33_333);
+    assert_eq!(audio_pts, 43 * 1024 * 1_000_000 / 44_100);
+    // the audio falls behind at half of the video
+    let ratio = audio_pts as f64 / video_pts as f64;
+    assert!((0.45..0.55).contains(&ratio), "{}", ratio);
+    // and every later report is about the video
+    let last_audio = recorder
+        .stream_progress
+        .iter()
+        .rposition(|progress| progress.0 == PacketKind::Audio)
+        .unwrap();
+    assert!(recorder.stream_progress[last_audio + 1..]
+        .iter()
+        .all(|progress| progress.0 == PacketKind::Video && progress.3 > audio_pts));
+    assert_eq!(recorder.stream_progress.len(), 60 + 44);
+}
+
+/// The SPS and PPS of the first video track and the AAC config of the first audio track of
+/// the MP4 at `path`.
+#[cfg(feature = "rust-mp4")]
+fn codec_private_data(path: &Path) -> (Vec<u8>, Vec<u8>, Option<(u8, u8)>) {
+    let mp4 = mp4::read_mp4(std::fs::File::open(path).unwrap()).unwrap();
+    let tracks = mp4.tracks();
+    let video = tracks
+        .values()
+        .find(|track| matches!(track.track_type(), Ok(mp4::TrackType::Video)))
+        .expect("the output has a video track");
+    let audio = tracks
+        .values()
+        .find(|track| matches!(track.track_type(), Ok(mp4::TrackType::Audio)))
+        .map(|track| {
+            (
+                track.sample_freq_index().unwrap() as u8,
+                track.channel_config().unwrap() as u8,
+            )
+        });
+    (
+        video.sequence_parameter_set().unwrap().to_vec(),
+        video.picture_parameter_set().unwrap().to_vec(),
+        audio,
+    )
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn codec_specific_data_is_the_codec_private_data() {
+    // ten frames, each with an AAC packet for 44.1 kHz mono
+    let video_frames = FixtureVideo::new().h264_frames(10, 512);
+    let frames = libcryptocam::packet::PacketReader::new(video_frames.payload());
+    let packets = frames.fold(FixtureVideo::new(), |packets, frame| {
+        let frame = frame.unwrap();
+        packets
+            .video_packet(frame.pts_us, &frame.data)
+            .audio_packet(frame.pts_us, &adts_frame(100))
+    });
+    // an SPS for level 3.1 instead of the stream's 3.0, and an AudioSpecificConfig for 48 kHz
+    // stereo
+    let with_csd = VIDEO_METADATA.replace(
+        '}',
+        r#","video_csd":["Z0IAH6s=","aM48gA=="],"audio_csd":"EZA="}"#,
+    );
+    let mut backends = vec![VideoBackend::RustMp4];
+    if cfg!(feature = "video") {
+        backends.push(VideoBackend::FFmpeg);
+    }
+    for backend in backends {
+        let options = DecryptOptions::new()
+            .video_backend(backend)
+            .container(VideoContainer::Mp4);
+        let file = FixtureFile::video(with_csd.as_str(), packets.clone()).build();
+        let out_dir = tempfile::tempdir().unwrap();
+        let (result, recorder) = run_in(out_dir.path(), file, options.clone());
+        let output = match result {
+            JobResult::Complete {
+                output: Some(output),
+            } => output,
+            result => panic!("{:?} {:?} {:?}", backend, result, recorder.errors),
+        };
+        assert!(recorder.warnings.is_empty(), "{:?}", recorder.warnings);
+        let (sps, pps, audio) = codec_private_data(&output);
+        assert_eq!(sps, b"\x67\x42\x00\x1f\xab", "{:?}", backend);
+        assert_eq!(pps, b"\x68\xce\x3c\x80", "{:?}", backend);
+        if backend == VideoBackend::RustMp4 {
+            // 48 kHz is sample rate index 3, stereo channel configuration 2
+            assert_eq!(audio, Some((3, 2)));
+        }
+
+        // codec specific data that isn't base64 is ignored, the stream's SPS is used
+        let invalid = VIDEO_METADATA.replace('}', r#","video_csd":["not base64!"]}"#);
+        let file = FixtureFile::video(invalid, packets.clone()).build();
+        let out_dir = tempfile::tempdir().unwrap();
+        let (result, recorder) = run_in(out_dir.path(), file, options);
+        let output = match result {
+            JobResult::Complete {
+                output: Some(output),
+            } => output,
+            result => panic!("{:?} {:?} {:?}", backend, result, recorder.errors),
+        };
+        assert!(
+            matches!(
+                recorder.warnings[..],
+                [DecryptWarning::InvalidCodecConfig {
+                    stream: libcryptocam::packet::PacketKind::Video,
+                    ..
+                }]
+            ),
+            "{:?}",
+            recorder.warnings
+        );
+        let (sps, _, audio) = codec_private_data(&output);
+        assert_eq!(sps, b"\x67\x42\x00\x1e\xab", "{:?}", backend);
+        if backend == VideoBackend::RustMp4 {
+            // and the AAC config is that of the ADTS headers, 44.1 kHz mono
+            assert_eq!(audio, Some((4, 1)));
+        }
+    }
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn raw_aac_is_muxed_with_the_audio_specific_config() {
+    // AAC without ADTS headers, as some beta versions of the app wrote it, described by an
+    // AudioSpecificConfig for 44.1 kHz stereo
+    let video_frames = FixtureVideo::new().h264_frames(10, 512);
+    let frames = libcryptocam::packet::PacketReader::new(video_frames.payload());
+    let packets = frames.fold(FixtureVideo::new(), |packets, frame| {
+        let frame = frame.unwrap();
+        packets
+            .video_packet(frame.pts_us, &frame.data)
+            .audio_packet(frame.pts_us, &[0x21; 100])
+    });
+    let metadata = VIDEO_METADATA.replace('}', r#","audio_csd":"EhA="}"#);
+    let file = FixtureFile::video(metadata, packets).build();
+    let mut backends = vec![VideoBackend::RustMp4];
+    if cfg!(feature = "video") {
+        backends.push(VideoBackend::FFmpeg);
+    }
+    for backend in backends {
+        let options = DecryptOptions::new()
+            .video_backend(backend)
+            .container(VideoContainer::Mp4);
+        let out_dir = tempfile::tempdir().unwrap();
+        let (result, recorder) = run_in(out_dir.path(), file.clone(), options);
+        let output = match result {
+            JobResult::Complete {
+                output: Some(output),
+            } => output,
+            result => panic!("{:?} {:?} {:?}", backend, result, recorder.errors),
+        };
+        assert!(recorder.warnings.is_empty(), "{:?}", recorder.warnings);
+        // sample rate index 4, channel configuration 2
+        let (_, _, audio) = codec_private_data(&output);
+        assert_eq!(audio, Some((4, 2)), "{:?}", backend);
+
+        // the packets are muxed as they are
+        let mut mp4 = mp4::read_mp4(std::fs::File::open(&output).unwrap()).unwrap();
+        let (track_id, sample_count) = mp4
+            .tracks()
+            .values()
+            .find(|track| matches!(track.track_type(), Ok(mp4::TrackType::Audio)))
+            .map(|track| (track.track_id(), track.sample_count()))
+            .unwrap();
+        assert_eq!(sample_count, 10, "{:?}", backend);
+        for sample_id in 1..=sample_count {
+            let sample = mp4.read_sample(track_id, sample_id).unwrap().unwrap();
+            assert_eq!(&sample.bytes[..], &[0x21; 100][..], "{:?}", backend);
+        }
+    }
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn audio_recordings_are_written_as_m4a() {
+    let metadata = r#"{"audio_sample_rate":44100,"audio_channel_count":1,"audio_bitrate":64000,"audio_codec":"aac","timestamp":"2021-06-01T12:00:00Z"}"#;
+    let packets = (0..43).fold(FixtureVideo::new(), |packets, i| {
+        packets.audio_packet(i * 1024 * 1_000_000 / 44_100, &adts_frame(100))
+    });
+    let file = FixtureFile::audio(metadata, packets).build();
+    let out_dir = tempfile::tempdir().unwrap();
+    let options = DecryptOptions::new().video_backend(VideoBackend::RustMp4);
+    let (result, recorder) = run_in(out_dir.path(), file, options.clone());
+    let output = match result {
+        JobResult::Complete {
+            output: Some(output),
+        } => output,
+        result => panic!("{:?} {:?}", result, recorder.errors),
+    };
+    assert_eq!(output.file_name().unwrap(), "2021-06-01T12-00-00Z.m4a");
+    match &recorder.stats {
+        Some(DecryptStats::Audio(stats)) => {
+            assert_eq!((stats.packets, stats.bytes), (43, 43 * 107));
+            assert_eq!(stats.duration_us, 42 * 1024 * 1_000_000 / 44_100);
+        }
+        stats => panic!("{:?}", stats),
+    }
+
+    let mut mp4 = mp4::read_mp4(std::fs::File::open(&output).unwrap()).unwrap();
+    assert_eq!(mp4.tracks().len(), 1);
+    let track = mp4.tracks().values().next().unwrap();
+    assert!(matches!(track.track_type(), Ok(mp4::TrackType::Audio)));
+    assert_eq!(track.timescale(), 44_100);
+    let (track_id, sample_count) = (track.track_id(), track.sample_count());
+    assert_eq!(sample_count, 43);
+    for sample_id in 1..=sample_count {
+        let sample = mp4.read_sample(track_id, sample_id).unwrap().unwrap();
+        // without the ADTS header
+        assert_eq!(&sample.bytes[..], &[0x21; 100][..]);
+    }
+
+    // M4A only holds AAC
+    let opus = FixtureFile::audio(
+        metadata.replace(r#""aac""#, r#""opus""#),
+        FixtureVideo::new().audio_packet(0, &[0xfc; 40]),
+    )
+    .build();
+    let options = options.container(VideoContainer::Mp4);
+    let result = decrypt_from_reader(
+        Cursor::new(opus),
+        None,
+        &mut test_keyring(),
+        out_dir.path().to_path_buf(),
+        options,
+    );
+    assert!(result.is_err());
+}
+
+/// Writes the payload of type 9 files upper-cased to `<out_path>/payload.txt`.
+struct UppercaseBuilder;
+
+struct UppercaseJob {
+    data: Box<dyn Read + Send>,
+    out_path: PathBuf,
+    total_file_size: u64,
+    input_position: InputPosition,
+    output: Option<PathBuf>,
+}
+
+impl JobBuilder for UppercaseBuilder {
+    fn build(
+        &self,
+        data: Box<dyn Read + Send>,
+        metadata: &[u8],
+        out_path: PathBuf,
+        total_file_size: u64,
+        _bytes_before_data: u64,
+        context: JobContext,
+    ) -> anyhow::Result<Box<dyn DecryptingJob + Send>> {
+        assert_eq!(metadata, br#"{"kind":"note"}"#);
+        Ok(Box::new(UppercaseJob {
+            data,
+            out_path,
+            total_file_size,
+            input_position: context.input_position,
+            output: None,
+        }))
+    }
+}
+
+impl DecryptingJob for UppercaseJob {
+    fn run(&mut self, progress_callback: Box<&mut dyn ProgressCallback>, _cancel: Arc<AtomicBool>) {
+        progress_callback.set_total_file_size(self.total_file_size);
+        let mut payload = vec![];
+        if let Err(e) = self.data.read_to_end(&mut payload) {
+            progress_callback.on_error(e.into());
+            return;
+        }
+        let output = self.out_path.join("payload.txt");
+        std::fs::write(&output, payload.to_ascii_uppercase()).unwrap();
+        progress_callback.on_progress(self.input_position.get());
+        self.output = Some(output);
+        progress_callback.on_complete();
+    }
+
+    fn output_path(&self) -> Option<&Path> {
+        self.output.as_deref()
+    }
+}
+
+#[test]
+fn registered_builders_decrypt_their_file_types() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = FixtureFile::raw(9, r#"{"kind":"note"}"#, &b"buy milk"[..]).build();
+    let path = dir.path().join("note");
+    std::fs::write(&path, &file).unwrap();
+    let out_dir = dir.path().join("out");
+    std::fs::create_dir(&out_dir).unwrap();
+    let open = || std::fs::File::open(&path).unwrap();
+
+    let e = decrypt_with_registry(
+        open(),
+        &mut test_keyring(),
+        out_dir.clone(),
+        DecryptOptions::new(),
+        &Registry::default(),
+    )
+    .err()
+    .expect("type 9 is unknown");
+    match e.downcast_ref::<error::Error>() {
+        Some(error::Error::UnknownFileType {
+            file_type: 9,
+            registered,
+        }) => assert_eq!(registered, &[1, 2, 3]),
+        _ => panic!("{:?}", e),
+    }
+
+    let mut registry = Registry::default();
+    registry.register(9, Box::new(UppercaseBuilder));
+    assert_eq!(registry.file_types(), [1, 2, 3, 9]);
+    let mut job = decrypt_with_registry(
+        open(),
+        &mut test_keyring(),
+        out_dir.clone(),
+        DecryptOptions::new(),
+        &registry,
+    )
+    .unwrap();
+    let mut recorder = Recorder::default();
+    let result = job.run_with_token(Box::new(&mut recorder), &CancellationToken::new());
+    let output = out_dir.canonicalize().unwrap().join("payload.txt");
+    assert_eq!(
+        result,
+        JobResult::Complete {
+            output: Some(output.clone())
+        }
+    );
+    assert_eq!(std::fs::read(output).unwrap(), b"BUY MILK");
+    assert_progress_to_the_end(file.len(), result, &recorder);
+}
@@ -0,0 +1,476 @@
+//! What the DecryptOptions change, and the combinations jobs refuse to start with.
+
+use libcryptocam::{fixtures::*, prelude::*};
+use std::{
+    error::Error,
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
+
+#[derive(Default)]
+struct Recorder {
+    errors: Vec<Box<dyn Error>>,
+}
+
+impl ProgressCallback for Recorder {
+    fn set_total_file_size(&mut self, _: u64) {}
+    fn on_progress(&mut self, _: u64) {}
+    fn on_complete(&mut self) {}
+    fn on_error(&mut self, error: Box<dyn Error>) {
+        self.errors.push(error);
+    }
+}
+
+/// How a job that didn't write an output ended, and the errors it reported.
+type Failure = (JobResult, Vec<Box<dyn Error>>);
+
+/// Runs the job for `file` in `out_dir`, returning the output on success.
+fn run_in(out_dir: &Path, file: &[u8], options: DecryptOptions) -> Result<PathBuf, Failure> {
+    let mut job = decrypt_from_reader(
+        Cursor::new(file.to_vec()),
+        None,
+        &mut test_keyring(),
+        out_dir.to_path_buf(),
+        options,
+    )
+    .unwrap();
+    let mut recorder = Recorder::default();
+    match job.run_with_token(Box::new(&mut recorder), &CancellationToken::new()) {
+        JobResult::Complete {
+            output: Some(output),
+        } => Ok(output),
+        result => Err((result, recorder.errors)),
+    }
+}
+
+/// The name and content of the single output of `file` with `options`.
+fn decrypt(file: &[u8], options: DecryptOptions) -> (String, Vec<u8>) {
+    let out_dir = tempfile::tempdir().unwrap();
+    let output = run_in(out_dir.path(), file, options).unwrap();
+    let name = output.file_name().unwrap().to_str().unwrap().to_owned();
+    (name, fs::read(output).unwrap())
+}
+
+const PNG: &[u8] = b"\x89PNG\r\n\x1a\n a png";
+
+/// A JPEG without EXIF: SOI, a JFIF APP0, a scan and EOI.
+const JPEG: &[u8] = b"\xff\xd8\xff\xe0\x00\x10JFIF\x00\x01\x01\x00\x00\x01\x00\x01\x00\x00\
+    \xff\xda\x00\x08\x01\x01\x00\x00\x3f\x00\x12\x34\xff\xd9";
+
+fn image_file(metadata: &ImageMetadata, image: &[u8]) -> Vec<u8> {
+    FixtureFile::image(&serde_json::to_string(metadata).unwrap(), image.to_vec()).build()
+}
+
+fn png_file() -> Vec<u8> {
+    image_file(&ImageMetadata::new("2021-06-01T12:00:00Z", "png"), PNG)
+}
+
+fn jpeg_file() -> Vec<u8> {
+    let metadata = ImageMetadata::new("2021-06-01T12:00:00Z", "jpg").location(48.1, 11.5);
+    image_file(&metadata, JPEG)
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[test]
+fn invalid_combinations_are_refused_before_decrypting() {
+    let stem_template = NameTemplate::parse("{source_stem}").unwrap();
+    let mut cases = vec![
+        (
+            DecryptOptions::new()
+                .faststart(true)
+                .container(VideoContainer::Mkv),
+            "faststart only applies to MP4",
+        ),
+        (
+            DecryptOptions::new()
+                .faststart(true)
+                .container(VideoContainer::RawPackets),
+            "faststart only applies to MP4",
+        ),
+        (
+            DecryptOptions::new().raw_packet_index(true),
+            "packet index is only written",
+        ),
+        // fragmented output is for sinks that can't seek back to the start
+        (
+            DecryptOptions::new().faststart(true).fragmented(true),
+            "faststart can't be combined with fragmented output",
+        ),
+        (
+            DecryptOptions::new().faststart(true).resumable(true),
+            "it can't be resumed",
+        ),
+        (
+            DecryptOptions::new()
+                .resume_from(PathBuf::from("partial.json"))
+                .verify_only(true),
+            "Verifying doesn't write anything",
+        ),
+        (
+            DecryptOptions::new().subdirectory_strategy(SubdirectoryStrategy::ByDate {
+                format: "%Y/%Q".to_owned(),
+            }),
+            "Invalid date format",
+        ),
+        (
+            DecryptOptions::new().naming(OutputNaming::Template(stem_template)),
+            "no source stem is set",
+        ),
+        (DecryptOptions::new().read_buffer_size(0), "must not be 0"),
+        (DecryptOptions::new().input_buffer_size(0), "must not be 0"),
+        (
+            DecryptOptions::new().max_packet_len(0),
+            "maximum packet length must not be 0",
+        ),
+    ];
+    if cfg!(feature = "rust-mp4") {
+        cases.extend(vec![
+            (
+                DecryptOptions::new()
+                    .video_backend(VideoBackend::RustMp4)
+                    .container(VideoContainer::Mkv),
+                "only writes MP4",
+            ),
+            (
+                DecryptOptions::new()
+                    .video_backend(VideoBackend::RustMp4)
+                    .fragmented(true),
+                "can't write fragmented output",
+            ),
+        ]);
+    }
+
+    let file = png_file();
+    for (options, expected) in cases {
+        let out_dir = tempfile::tempdir().unwrap();
+        let description = format!("{:?}", options);
+        let error = decrypt_from_reader(
+            Cursor::new(file.clone()),
+            None,
+            &mut test_keyring(),
+            out_dir.path().to_path_buf(),
+            options,
+        )
+        .err()
+        .unwrap_or_else(|| panic!("{} is accepted", description));
+        assert!(
+            format!("{:#}", error).contains(expected),
+            "{}: {:#}",
+            description,
+            error
+        );
+        assert_eq!(fs::read_dir(out_dir.path()).unwrap().count(), 0);
+    }
+}
+
+#[test]
+fn write_exif_adds_the_recording_time_to_jpegs() {
+    let (name, with_exif) = decrypt(&jpeg_file(), DecryptOptions::new());
+    assert!(name.ends_with(".jpg"), "{}", name);
+    assert!(contains(&with_exif, b"Exif\0\0"));
+    assert!(contains(&with_exif, b"2021:06:01 12:00:00"));
+    assert!(with_exif.ends_with(&JPEG[JPEG.len() - 12..]));
+
+    let (_, without_exif) = decrypt(&jpeg_file(), DecryptOptions::new().write_exif(false));
+    assert_eq!(without_exif, JPEG);
+}
+
+#[test]
+fn strip_location_leaves_out_the_gps_tags() {
+    // the pointer to the GPS IFD, big endian like the EXIF written for bare JPEGs
+    let gps_ifd_tag = [0x88, 0x25];
+    let (_, with_location) = decrypt(&jpeg_file(), DecryptOptions::new());
+    assert!(contains(&with_location, &gps_ifd_tag));
+
+    let (_, stripped) = decrypt(&jpeg_file(), DecryptOptions::new().strip_location(true));
+    assert!(contains(&stripped, b"2021:06:01 12:00:00"));
+    assert!(!contains(&stripped, &gps_ifd_tag));
+}
+
+#[test]
+fn overwrite_decides_what_happens_to_existing_outputs() {
+    let out_dir = tempfile::tempdir().unwrap();
+    let file = png_file();
+    let first = run_in(out_dir.path(), &file, DecryptOptions::new()).unwrap();
+    fs::write(&first, "older output").unwrap();
+
+    let failed = run_in(
+        out_dir.path(),
+        &file,
+        DecryptOptions::new().overwrite(Overwrite::Fail),
+    );
+    assert!(failed.is_err());
+    assert_eq!(fs::read(&first).unwrap(), b"older output");
+
+    let renamed = run_in(
+        out_dir.path(),
+        &file,
+        DecryptOptions::new().overwrite(Overwrite::Rename),
+    )
+    .unwrap();
+    assert_ne!(renamed, first);
+    assert!(
+        renamed.to_str().unwrap().ends_with(" (1).png"),
+        "{:?}",
+        renamed
+    );
+    assert_eq!(fs::read(&renamed).unwrap(), PNG);
+    assert_eq!(fs::read(&first).unwrap(), b"older output");
+
+    let replaced = run_in(
+        out_dir.path(),
+        &file,
+        DecryptOptions::new().overwrite(Overwrite::Replace),
+    )
+    .unwrap();
+    assert_eq!(replaced, first);
+    assert_eq!(fs::read(&first).unwrap(), PNG);
+    assert_eq!(fs::read_dir(out_dir.path()).unwrap().count(), 2);
+}
+
+#[test]
+fn naming_and_source_stem_name_the_output() {
+    let (default_name, _) = decrypt(&png_file(), DecryptOptions::new());
+    assert!(default_name.starts_with("2021"), "{}", default_name);
+
+    let template = NameTemplate::parse("{type}_{source_stem}").unwrap();
+    let options = DecryptOptions::new()
+        .naming(OutputNaming::Template(template))
+        .source_stem("IMG_0001");
+    assert_eq!(decrypt(&png_file(), options).0, "image_IMG_0001.png");
+
+    let callback = OutputNaming::Callback(Arc::new(|info: &MediaInfo| {
+        format!(
+            "{}-{}",
+            info.codec,
+            info.source_stem.as_deref().unwrap_or("none")
+        )
+    }));
+    assert_eq!(
+        decrypt(&png_file(), DecryptOptions::new().naming(callback)).0,
+        "png-none.png"
+    );
+}
+
+#[test]
+fn buffer_sizes_and_fsync_dont_change_the_output() {
+    let file = image_file(
+        &ImageMetadata::new("2021-06-01T12:00:00Z", "png"),
+        &[PNG, &[7; 200_000][..]].concat(),
+    );
+    let expected = decrypt(&file, DecryptOptions::new());
+    let options = DecryptOptions::new()
+        .read_buffer_size(1)
+        .input_buffer_size(1)
+        .write_buffer_size(1);
+    assert_eq!(decrypt(&file, options), expected);
+    let options = DecryptOptions::new()
+        .write_buffer_size(1 << 20)
+        .fsync_on_complete(true);
+    assert_eq!(decrypt(&file, options), expected);
+}
+
+/// The modification time of the output of `file`, in seconds since the epoch.
+fn output_mtime(file: &[u8], options: DecryptOptions) -> u64 {
+    let out_dir = tempfile::tempdir().unwrap();
+    let output = run_in(out_dir.path(), file, options).unwrap();
+    let mtime = fs::metadata(output).unwrap().modified().unwrap();
+    mtime.duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// 2021-06-01T12:00:00Z, the timestamp of the fixtures.
+const RECORDED: u64 = 1_622_548_800;
+
+#[test]
+fn preserve_timestamps_dates_outputs_to_the_recording() {
+    assert_eq!(output_mtime(&png_file(), DecryptOptions::new()), RECORDED);
+    // otherwise the output is dated to when it was written
+    let options = DecryptOptions::new().preserve_timestamps(false);
+    assert!(output_mtime(&png_file(), options) > RECORDED);
+}
+
+#[cfg(feature = "rust-mp4")]
+const VIDEO_METADATA: &str = r#"{"width":640,"height":480,"video_bitrate":1000000,"audio_sample_rate":44100,"audio_channel_count":1,"audio_bitrate":64000,"timestamp":"2021-06-01T12:00:00Z"}"#;
+
+#[cfg(feature = "rust-mp4")]
+fn video_file(frame_len: usize) -> Vec<u8> {
+    let packets = FixtureVideo::new().h264_frames(4, frame_len);
+    FixtureFile::video(VIDEO_METADATA, packets).build()
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn max_packet_len_refuses_longer_packets() {
+    let file = video_file(4096);
+    let options = || {
+        DecryptOptions::new()
+            .video_backend(VideoBackend::RustMp4)
+            .finalize_on_truncation(false)
+    };
+    decrypt(&file, options().max_packet_len(4096));
+
+    let out_dir = tempfile::tempdir().unwrap();
+    assert!(run_in(out_dir.path(), &file, options().max_packet_len(4095)).is_err());
+    assert_eq!(fs::read_dir(out_dir.path()).unwrap().count(), 0);
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn pipelined_writes_the_same_video() {
+    let file = video_file(32 << 10);
+    let options = || DecryptOptions::new().video_backend(VideoBackend::RustMp4);
+    assert_eq!(
+        decrypt(&file, options().pipelined(true)),
+        decrypt(&file, options().pipelined(false))
+    );
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn preserve_timestamps_dates_videos_to_the_recording() {
+    let options = DecryptOptions::new().video_backend(VideoBackend::RustMp4);
+    assert_eq!(output_mtime(&video_file(1024), options), RECORDED);
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn video_locations_are_written_as_iso6709() {
+    let metadata = VIDEO_METADATA.replace(
+        '}',
+        r#","latitude":-33.8568,"longitude":151.2153,"altitude":58.5}"#,
+    );
+    let packets = FixtureVideo::new().h264_frames(4, 4096);
+    let file = FixtureFile::video(metadata, packets).build();
+    let options = || {
+        DecryptOptions::new()
+            .video_backend(VideoBackend::RustMp4)
+            .container(VideoContainer::Mp4)
+    };
+    let (_, video) = decrypt(&file, options());
+    // a ©xyz box with the string's length and language code before it
+    let location = b"-33.8568+151.2153+058.500/";
+    let xyz = [&b"\xa9xyz\x00\x1a\x15\xc7"[..], location].concat();
+    assert!(contains(&video, &xyz));
+    let (_, stripped) = decrypt(&file, options().strip_location(true));
+    assert!(!contains(&stripped, b"\xa9xyz"));
+    assert!(!contains(&stripped, location));
+
+    // FFmpeg writes the location its own way for MP4, but only if it isn't stripped
+    #[cfg(feature = "video")]
+    {
+        let options = || options().video_backend(VideoBackend::FFmpeg);
+        let (_, video) = decrypt(&file, options());
+        let (_, stripped) = decrypt(&file, options().strip_location(true));
+        assert!(video.len() > stripped.len());
+    }
+}
+
+/// A PNG of 300 kB recorded at `timestamp`.
+fn large_png_file(timestamp: &str) -> Vec<u8> {
+    let png = [PNG, &[7; 300_000][..]].concat();
+    image_file(&ImageMetadata::new(timestamp, "png"), &png)
+}
+
+/// The partial output and job manifest a resumable job with `options` leaves behind when its
+/// input ends early, moved to `partial`.
+fn interrupted_output(file: &[u8], partial: &Path, options: DecryptOptions) {
+    let out_dir = tempfile::tempdir().unwrap();
+    let options = options.resumable(true);
+    assert!(run_in(out_dir.path(), &file[..file.len() / 2], options).is_err());
+    for entry in fs::read_dir(out_dir.path()).unwrap() {
+        let path = entry.unwrap().path();
+        let mut name = partial.file_name().unwrap().to_owned();
+        if path.extension().unwrap() == "resume" {
+            name.push(".resume");
+        }
+        fs::rename(path, partial.with_file_name(name)).unwrap();
+    }
+    assert!(fs::metadata(partial).unwrap().len() > 0);
+}
+
+/// Asserts that the job failed with PartialOutputMismatch, giving `reason`.
+fn assert_mismatch(result: Result<PathBuf, Failure>, reason: &str) {
+    let (_, errors) = result.expect_err("resuming fails");
+    match errors[0].downcast_ref::<libcryptocam::error::Error>() {
+        Some(libcryptocam::error::Error::PartialOutputMismatch { reason: r, .. }) => {
+            assert!(r.contains(reason), "{}", r)
+        }
+        _ => panic!("{:?}", errors),
+    }
+}
+
+#[test]
+fn resumed_outputs_are_those_of_a_full_run() {
+    let file = large_png_file("2021-06-01T12:00:00Z");
+    let (_, expected) = decrypt(&file, DecryptOptions::new());
+    let dir = tempfile::tempdir().unwrap();
+    let partial = dir.path().join("partial.png");
+    interrupted_output(&file, &partial, DecryptOptions::new());
+    assert!(fs::metadata(&partial).unwrap().len() < expected.len() as u64);
+
+    let options = DecryptOptions::new().resume_from(partial.clone());
+    let output = run_in(dir.path(), &file, options).unwrap();
+    assert_eq!(output, partial);
+    assert_eq!(fs::read(&partial).unwrap(), expected);
+    assert!(!dir.path().join("partial.png.resume").exists());
+}
+
+#[test]
+fn resuming_checks_the_manifest_with_its_own_algorithm() {
+    let file = large_png_file("2021-06-01T12:00:00Z");
+    let (_, expected) = decrypt(&file, DecryptOptions::new());
+    let dir = tempfile::tempdir().unwrap();
+    let partial = dir.path().join("partial.png");
+    let sha512 = DecryptOptions::new().output_digest(HashAlgo::Sha512);
+    interrupted_output(&file, &partial, sha512);
+    let manifest_path = dir.path().join("partial.png.resume");
+    let manifest = fs::read_to_string(&manifest_path).unwrap();
+    assert!(
+        manifest.contains(r#""source_digest": "sha512:"#),
+        "{}",
+        manifest
+    );
+
+    // a manifest with an unknown algorithm can't be checked
+    let unknown = manifest.replace(r#""sha512:"#, r#""md5:"#);
+    fs::write(&manifest_path, unknown).unwrap();
+    let options = DecryptOptions::new().resume_from(partial.clone());
+    assert_mismatch(run_in(dir.path(), &file, options), "another file");
+
+    // resuming hashes with SHA-256 by default
+    fs::write(&manifest_path, manifest).unwrap();
+    let options = DecryptOptions::new().resume_from(partial.clone());
+    run_in(dir.path(), &file, options).unwrap();
+    assert_eq!(fs::read(&partial).unwrap(), expected);
+}
+
+#[test]
+fn resuming_refuses_outputs_of_other_files_and_options() {
+    let file = large_png_file("2021-06-01T12:00:00Z");
+    let dir = tempfile::tempdir().unwrap();
+    let partial = dir.path().join("partial.png");
+    interrupted_output(&file, &partial, DecryptOptions::new());
+    let written = fs::read(&partial).unwrap();
+
+    let other_file = large_png_file("2021-06-02T12:00:00Z");
+    let options = DecryptOptions::new().resume_from(partial.clone());
+    assert_mismatch(run_in(dir.path(), &other_file, options), "another file");
+    let options = DecryptOptions::new()
+        .resume_from(partial.clone())
+        .image_format_mismatch(ImageFormatMismatch::Warn);
+    assert_mismatch(run_in(dir.path(), &file, options), "other options");
+    assert_eq!(fs::read(&partial).unwrap(), written);
+
+    // the partial output itself was changed since
+    let mut changed = written;
+    changed[10] ^= 1;
+    fs::write(&partial, &changed).unwrap();
+    let options = DecryptOptions::new().resume_from(partial.clone());
+    assert_mismatch(run_in(dir.path(), &file, options), "differs");
+}
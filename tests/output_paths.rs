@@ -0,0 +1,213 @@
+//! Where jobs write the outputs of fixture files whose metadata tries to name them elsewhere.
+
+use libcryptocam::{fixtures::*, prelude::*};
+use std::{
+    env,
+    error::Error,
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+struct NoProgress;
+
+impl ProgressCallback for NoProgress {
+    fn set_total_file_size(&mut self, _n: u64) {}
+    fn on_progress(&mut self, _processed_bytes: u64) {}
+    fn on_complete(&mut self) {}
+    fn on_error(&mut self, error: Box<dyn Error>) {
+        panic!("Decryption failed: {}", error);
+    }
+}
+
+/// Decrypts `file` into `out_dir` and returns the output.
+fn decrypt(file: Vec<u8>, out_dir: &Path, options: DecryptOptions) -> PathBuf {
+    let mut keyring = test_keyring();
+    let mut job = decrypt_from_reader(
+        Cursor::new(file),
+        None,
+        &mut keyring,
+        out_dir.to_path_buf(),
+        options,
+    )
+    .unwrap();
+    match job.run_with_token(Box::new(&mut NoProgress), &CancellationToken::new()) {
+        JobResult::Complete { output } => output.unwrap(),
+        result => panic!("Job ended with {:?}", result),
+    }
+}
+
+/// Asserts that `output` is directly in `out_dir` and nothing else was written next to it.
+fn assert_inside(output: &Path, out_dir: &Path, root: &Path) {
+    assert_eq!(
+        output.parent().unwrap().canonicalize().unwrap(),
+        out_dir.canonicalize().unwrap()
+    );
+    let entries: Vec<_> = fs::read_dir(root).unwrap().collect();
+    assert_eq!(entries.len(), 1, "{:?}", entries);
+}
+
+const HOSTILE_TIMESTAMPS: &[&str] = &["../../evil", "..\\..\\evil", "/etc/passwd", ".."];
+const HOSTILE_FORMATS: &[&str] = &["png", "../../.bashrc", "/tmp/x", ".."];
+
+#[test]
+fn image_metadata_cant_escape_the_output_directory() {
+    for timestamp in HOSTILE_TIMESTAMPS {
+        for format in HOSTILE_FORMATS {
+            let root = tempfile::tempdir().unwrap();
+            let out_dir = root.path().join("out");
+            fs::create_dir(&out_dir).unwrap();
+            let metadata = serde_json::json!({ "timestamp": timestamp, "format": format });
+            let file =
+                FixtureFile::image(metadata.to_string(), &b"\x89PNG\r\n\x1a\nimage data"[..])
+                    .build();
+            // keep the declared format so it ends up in the extension
+            let options = DecryptOptions::new()
+                .image_format_mismatch(ImageFormatMismatch::Warn)
+                .write_metadata_sidecar(true);
+            let output = decrypt(file, &out_dir, options);
+            assert_inside(&output, &out_dir, root.path());
+        }
+    }
+}
+
+#[cfg(feature = "rust-mp4")]
+#[test]
+fn video_metadata_cant_escape_the_output_directory() {
+    for timestamp in HOSTILE_TIMESTAMPS {
+        let root = tempfile::tempdir().unwrap();
+        let out_dir = root.path().join("out");
+        fs::create_dir(&out_dir).unwrap();
+        let metadata = serde_json::json!({
+            "width": 640,
+            "height": 480,
+            "video_bitrate": 1000000,
+            "audio_sample_rate": 44100,
+            "audio_channel_count": 1,
+            "audio_bitrate": 64000,
+            "timestamp": timestamp,
+        });
+        let file = FixtureFile::video(metadata.to_string(), FixtureVideo::new().h264_frames(3, 64))
+            .build();
+        let options = DecryptOptions::new()
+            .video_backend(VideoBackend::RustMp4)
+            .write_metadata_sidecar(true);
+        let output = decrypt(file, &out_dir, options);
+        assert_inside(&output, &out_dir, root.path());
+    }
+}
+
+#[test]
+fn decomposed_timestamps_are_written_in_nfc() {
+    let root = tempfile::tempdir().unwrap();
+    let out_dir = root.path().join("out");
+    fs::create_dir(&out_dir).unwrap();
+    let metadata = serde_json::json!({ "timestamp": "Cafe\u{301}", "format": "png" });
+    let file =
+        FixtureFile::image(metadata.to_string(), &b"\x89PNG\r\n\x1a\nimage data"[..]).build();
+    let output = decrypt(file, &out_dir, DecryptOptions::new());
+    assert_eq!(output.file_name().unwrap(), "Caf\u{e9}.png");
+    assert!(out_dir.join("Caf\u{e9}.png").is_file());
+}
+
+/// Held by tests that change the working directory, which all threads share.
+static WORKING_DIR: Mutex<()> = Mutex::new(());
+
+/// Changes the working directory back when dropped, also when the test fails.
+struct RestoreWorkingDir(PathBuf);
+
+impl Drop for RestoreWorkingDir {
+    fn drop(&mut self) {
+        let _ = env::set_current_dir(&self.0);
+    }
+}
+
+#[test]
+fn relative_output_directories_are_resolved_when_the_job_is_built() {
+    let _lock = WORKING_DIR.lock().unwrap_or_else(|e| e.into_inner());
+    let _restore = RestoreWorkingDir(env::current_dir().unwrap());
+    let root = tempfile::tempdir().unwrap();
+    let (first, second) = (root.path().join("first"), root.path().join("second"));
+    for dir in [&first, &second] {
+        fs::create_dir_all(dir.join("out")).unwrap();
+    }
+
+    env::set_current_dir(&first).unwrap();
+    let metadata = r#"{"timestamp":"2021-06-01T12:00:00Z","format":"png"}"#;
+    let file = FixtureFile::image(metadata, &b"\x89PNG\r\n\x1a\nimage data"[..]).build();
+    let mut job = decrypt_from_reader(
+        Cursor::new(file),
+        None,
+        &mut test_keyring(),
+        PathBuf::from("out"),
+        DecryptOptions::new(),
+    )
+    .unwrap();
+    env::set_current_dir(&second).unwrap();
+    let output = match job.run_with_token(Box::new(&mut NoProgress), &CancellationToken::new()) {
+        JobResult::Complete { output } => output.unwrap(),
+        result => panic!("Job ended with {:?}", result),
+    };
+
+    assert!(output.is_absolute(), "{:?}", output);
+    assert_eq!(
+        output.parent().unwrap().canonicalize().unwrap(),
+        first.join("out").canonicalize().unwrap()
+    );
+    assert_eq!(fs::read_dir(second.join("out")).unwrap().count(), 0);
+}
+
+fn png_at(timestamp: &str) -> Vec<u8> {
+    let metadata = serde_json::json!({ "timestamp": timestamp, "format": "png" });
+    FixtureFile::image(metadata.to_string(), &b"\x89PNG\r\n\x1a\nimage data"[..]).build()
+}
+
+#[test]
+fn outputs_are_sorted_into_date_directories() {
+    let root = tempfile::tempdir().unwrap();
+    // as the job resolves it
+    let out_dir = root.path().canonicalize().unwrap();
+    let by_day = DecryptOptions::new().subdirectory_strategy(SubdirectoryStrategy::ByDate {
+        format: "%Y-%m-%d".to_owned(),
+    });
+    // an hour apart, but on two days in the timezone of the recording
+    let late = decrypt(
+        png_at("2021-06-01T23:30:00+02:00"),
+        &out_dir,
+        by_day.clone(),
+    );
+    let early = decrypt(
+        png_at("2021-06-02T00:30:00+02:00"),
+        &out_dir,
+        by_day.clone(),
+    );
+    assert_eq!(late.parent().unwrap(), out_dir.join("2021-06-01"));
+    assert_eq!(early.parent().unwrap(), out_dir.join("2021-06-02"));
+    let unknown = decrypt(png_at("yesterday"), &out_dir, by_day);
+    assert_eq!(unknown.parent().unwrap(), out_dir.join("unknown-date"));
+
+    let by_month = DecryptOptions::new().subdirectory_strategy(SubdirectoryStrategy::ByDate {
+        format: "%Y/%m".to_owned(),
+    });
+    let nested = decrypt(png_at("2021-07-01T12:00:00Z"), &out_dir, by_month);
+    assert_eq!(nested.parent().unwrap(), out_dir.join("2021").join("07"));
+}
+
+#[test]
+fn invalid_date_formats_are_refused() {
+    let root = tempfile::tempdir().unwrap();
+    let options = DecryptOptions::new().subdirectory_strategy(SubdirectoryStrategy::ByDate {
+        format: "%Y-%!".to_owned(),
+    });
+    let result = decrypt_from_reader(
+        Cursor::new(png_at("2021-06-01T12:00:00Z")),
+        None,
+        &mut test_keyring(),
+        root.path().to_path_buf(),
+        options,
+    );
+    let e = result.err().expect("the format is refused");
+    assert!(e.to_string().contains("Invalid date format"), "{}", e);
+    assert_eq!(fs::read_dir(root.path()).unwrap().count(), 0);
+}
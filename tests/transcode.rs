@@ -0,0 +1,202 @@
+//! Transcoded videos go through the FrameProcessor, checked by decoding the output.
+#![cfg(feature = "transcode")]
+
+use ac_ffmpeg::{
+    codec::{
+        video::{frame::get_pixel_format, VideoDecoder, VideoEncoder, VideoFrame, VideoFrameMut},
+        Decoder, Encoder,
+    },
+    format::{demuxer::Demuxer, io::IO},
+    time::{TimeBase, Timestamp},
+};
+use libcryptocam::{fixtures::*, prelude::*};
+use std::{error::Error, fs::File, io::Cursor, path::Path};
+
+const WIDTH: usize = 640;
+const HEIGHT: usize = 480;
+/// Luma of the frames the fixture is encoded from, so both the white text and the black box
+/// stand out.
+const GRAY: u8 = 128;
+
+#[derive(Default)]
+struct Recorder {
+    errors: Vec<Box<dyn Error>>,
+}
+
+impl ProgressCallback for Recorder {
+    fn set_total_file_size(&mut self, _: u64) {}
+    fn on_progress(&mut self, _: u64) {}
+    fn on_complete(&mut self) {}
+    fn on_error(&mut self, error: Box<dyn Error>) {
+        self.errors.push(error);
+    }
+}
+
+struct Panicking;
+
+impl FrameProcessor for Panicking {
+    fn process(&mut self, _frame: &mut VideoFrameMut, _pts_us: i64) {
+        panic!("boom");
+    }
+}
+
+/// A video of `frames` gray frames, encoded with libx264 so the transcoder can decode it.
+fn gray_video(frames: i64) -> Vec<u8> {
+    let pixel_format = get_pixel_format("yuv420p");
+    let mut encoder = VideoEncoder::builder("libx264")
+        .unwrap()
+        .width(WIDTH)
+        .height(HEIGHT)
+        .pixel_format(pixel_format)
+        .time_base(TimeBase::MICROSECONDS)
+        // no B-frames, the packets are in presentation order like those of a recording
+        .set_option("preset", "ultrafast")
+        .build()
+        .unwrap();
+    let mut video = FixtureVideo::new();
+    for i in 0..=frames {
+        if i < frames {
+            let mut frame = VideoFrameMut::black(pixel_format, WIDTH, HEIGHT);
+            let mut planes = frame.planes_mut();
+            let mut y = 0;
+            while let Some(line) = planes[0].line_mut(y) {
+                line.fill(GRAY);
+                y += 1;
+            }
+            let pts = Timestamp::from_micros(i * 33_333);
+            encoder.push(frame.freeze().with_pts(pts)).unwrap();
+        } else {
+            encoder.flush().unwrap();
+        }
+        while let Some(packet) = encoder.take().unwrap() {
+            let pts = packet.pts().with_time_base(TimeBase::MICROSECONDS);
+            video = video.video_packet(pts.timestamp() as u64, packet.data());
+        }
+    }
+    let metadata = format!(
+        r#"{{"width":{},"height":{},"video_bitrate":1000000,"audio_sample_rate":44100,"audio_channel_count":1,"audio_bitrate":64000,"timestamp":"2021-06-01T12:00:00Z"}}"#,
+        WIDTH, HEIGHT
+    );
+    FixtureFile::video(metadata, video).build()
+}
+
+fn transcode(out_dir: &Path, file: &[u8], spec: TranscodeSpec) -> (JobResult, Recorder) {
+    let options = DecryptOptions::new()
+        .container(VideoContainer::Mp4)
+        .transcode(spec);
+    let mut job = decrypt_from_reader(
+        Cursor::new(file.to_vec()),
+        None,
+        &mut test_keyring(),
+        out_dir.to_path_buf(),
+        options,
+    )
+    .unwrap();
+    let mut recorder = Recorder::default();
+    let result = job.run_with_token(Box::new(&mut recorder), &CancellationToken::new());
+    (result, recorder)
+}
+
+/// The first frame of the video at `path`.
+fn first_frame(path: &Path) -> VideoFrame {
+    let io = IO::from_seekable_read_stream(File::open(path).unwrap());
+    let mut demuxer = Demuxer::builder()
+        .build(io)
+        .unwrap()
+        .find_stream_info(None)
+        .map_err(|(_, e)| e)
+        .unwrap();
+    let (index, mut decoder) = {
+        let (index, stream) = demuxer
+            .streams()
+            .iter()
+            .enumerate()
+            .find(|(_, stream)| stream.codec_parameters().is_video_codec())
+            .expect("the output has a video stream");
+        (
+            index,
+            VideoDecoder::from_stream(stream).unwrap().build().unwrap(),
+        )
+    };
+    while let Some(packet) = demuxer.take().unwrap() {
+        if packet.stream_index() != index {
+            continue;
+        }
+        decoder.push(packet).unwrap();
+        if let Some(frame) = decoder.take().unwrap() {
+            return frame;
+        }
+    }
+    decoder.flush().unwrap();
+    decoder.take().unwrap().expect("the output has a frame")
+}
+
+/// The luma plane of `frame`, row by row.
+fn luma(frame: &VideoFrame) -> Vec<Vec<u8>> {
+    let plane = &frame.planes()[0];
+    (0..frame.height())
+        .map(|y| plane.line(y).unwrap()[..frame.width()].to_vec())
+        .collect()
+}
+
+#[test]
+fn burned_in_time_is_in_the_decoded_output() {
+    let file = gray_video(10);
+    let (plain_dir, stamped_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+    let (result, recorder) = transcode(plain_dir.path(), &file, TranscodeSpec::new("libx264"));
+    let baseline = match result {
+        JobResult::Complete { output: Some(path) } => luma(&first_frame(&path)),
+        result => panic!("{:?} {:?}", result, recorder.errors),
+    };
+    let spec = TranscodeSpec::new("libx264").frame_processor(|| Box::new(TimestampBurnIn::new()));
+    let (result, recorder) = transcode(stamped_dir.path(), &file, spec);
+    let stamped = match result {
+        JobResult::Complete { output: Some(path) } => luma(&first_frame(&path)),
+        result => panic!("{:?} {:?}", result, recorder.errors),
+    };
+
+    // the default scale for 480 lines is 1: a 9 lines high box for the 19 characters, 4
+    // pixels from the bottom left corner
+    let (top, left, box_width, box_height) = (466, 4, 115, 9);
+    let (mut bright, mut dark) = (0, 0);
+    let rows = baseline[top..top + box_height].iter().zip(&stamped[top..]);
+    for (before, after) in rows {
+        for (&before, &after) in before[left..left + box_width].iter().zip(&after[left..]) {
+            if after as i32 - before as i32 > 64 {
+                bright += 1;
+            } else if before as i32 - after as i32 > 64 {
+                dark += 1;
+            }
+        }
+    }
+    assert!(bright > 100, "{} pixels of text", bright);
+    assert!(dark > 300, "{} pixels of box", dark);
+    // the top half is far from the box, lossy encoding aside it is the same
+    let rows = baseline.iter().zip(&stamped).take(HEIGHT / 2);
+    for (y, (before, after)) in rows.enumerate() {
+        for (x, (&before, &after)) in before.iter().zip(after).enumerate() {
+            let diff = (before as i32 - after as i32).abs();
+            assert!(diff <= 8, "pixel {},{} changed by {}", x, y, diff);
+        }
+    }
+}
+
+#[test]
+fn panicking_frame_processors_fail_the_job() {
+    let file = gray_video(10);
+    let out_dir = tempfile::tempdir().unwrap();
+    let spec = TranscodeSpec::new("libx264").frame_processor(|| Box::new(Panicking));
+    let (result, recorder) = transcode(out_dir.path(), &file, spec);
+
+    match result {
+        JobResult::Failed(message) => {
+            assert!(
+                message.contains("The frame processor panicked at 0 us: boom"),
+                "{}",
+                message
+            )
+        }
+        result => panic!("{:?}", result),
+    }
+    assert_eq!(recorder.errors.len(), 1);
+}